@@ -4,6 +4,10 @@ pub type VirtAddr = usize;
 pub type PhysAddr = usize;
 
 pub const PAGE_SIZE: usize = 1 << 12;
+/// Size of a huge page, mapped by a second-level page table entry
+pub const HUGE_PAGE_SIZE: usize = 1 << 21;
+/// Size of a giant page, mapped by a third-level page table entry
+pub const GIANT_PAGE_SIZE: usize = 1 << 30;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
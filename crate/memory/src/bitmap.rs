@@ -0,0 +1,107 @@
+//! Fixed-size bitmap that can be changed without a lock
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const BITS: usize = usize::BITS as usize;
+
+/// A bitmap of `N` words, e.g. one bit of state for every physical frame
+pub struct AtomicBitmap<const N: usize> {
+    words: [AtomicUsize; N],
+}
+
+impl<const N: usize> AtomicBitmap<N> {
+    /// Number of bits in the bitmap
+    pub const CAP: usize = N * BITS;
+
+    /// Create a bitmap with all bits cleared
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        AtomicBitmap { words: [ZERO; N] }
+    }
+
+    pub fn test(&self, index: usize) -> bool {
+        self.words[index / BITS].load(Ordering::Acquire) & (1 << (index % BITS)) != 0
+    }
+
+    /// Set all bits in `range`, return whether all of them were cleared before.
+    /// Bits are set even if some of them were already set.
+    pub fn set_range(&self, range: Range<usize>) -> bool {
+        let mut was_clear = true;
+        self.for_each_word(range, |word, mask| {
+            was_clear &= word.fetch_or(mask, Ordering::AcqRel) & mask == 0;
+        });
+        was_clear
+    }
+
+    /// Clear all bits in `range`, return whether all of them were set before.
+    /// Bits are cleared even if some of them were already cleared.
+    pub fn clear_range(&self, range: Range<usize>) -> bool {
+        let mut was_set = true;
+        self.for_each_word(range, |word, mask| {
+            was_set &= word.fetch_and(!mask, Ordering::AcqRel) & mask == mask;
+        });
+        was_set
+    }
+
+    /// Call `f(word, mask)` for every word covering `range`,
+    /// where `mask` selects the bits of `range` in it
+    fn for_each_word(&self, range: Range<usize>, mut f: impl FnMut(&AtomicUsize, usize)) {
+        let mut start = range.start;
+        while start < range.end {
+            let end = range.end.min((start / BITS + 1) * BITS);
+            let len = end - start;
+            let mask = if len == BITS {
+                !0
+            } else {
+                ((1 << len) - 1) << (start % BITS)
+            };
+            f(&self.words[start / BITS], mask);
+            start = end;
+        }
+    }
+}
+
+impl<const N: usize> Default for AtomicBitmap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_and_clear() {
+        let bitmap = AtomicBitmap::<4>::new();
+        assert_eq!(AtomicBitmap::<4>::CAP, 4 * BITS);
+        assert!(bitmap.set_range(3..4));
+        assert!(bitmap.test(3));
+        assert!(!bitmap.test(2) && !bitmap.test(4));
+        // set twice
+        assert!(!bitmap.set_range(3..4));
+        assert!(bitmap.clear_range(3..4));
+        assert!(!bitmap.test(3));
+        // clear twice
+        assert!(!bitmap.clear_range(3..4));
+    }
+
+    #[test]
+    fn ranges_across_words() {
+        let bitmap = AtomicBitmap::<4>::new();
+        assert!(bitmap.set_range(BITS - 2..3 * BITS + 1));
+        assert!(!bitmap.test(BITS - 3));
+        assert!((BITS - 2..3 * BITS + 1).all(|i| bitmap.test(i)));
+        assert!(!bitmap.test(3 * BITS + 1));
+        // overlapping with set bits
+        assert!(!bitmap.set_range(0..BITS));
+        assert!(bitmap.test(0));
+        // partly cleared
+        assert!(bitmap.clear_range(BITS..2 * BITS));
+        assert!(!bitmap.clear_range(0..2 * BITS));
+        assert!(bitmap.clear_range(2 * BITS..3 * BITS + 1));
+        assert!((0..AtomicBitmap::<4>::CAP).all(|i| !bitmap.test(i)));
+    }
+}
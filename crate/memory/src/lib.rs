@@ -6,6 +6,7 @@ use log::*;
 extern crate alloc;
 
 mod addr;
+pub mod bitmap;
 pub mod cow;
 pub mod memory_set;
 pub mod no_mmu;
@@ -14,8 +15,11 @@ pub mod paging;
 
 pub use crate::addr::*;
 
+#[derive(Debug)]
 pub enum VMError {
     InvalidPtr,
+    InvalidArgument,
+    NoMemory,
 }
 
 pub type VMResult<T> = Result<T, VMError>;
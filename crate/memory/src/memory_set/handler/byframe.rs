@@ -11,14 +11,24 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
     }
 
     fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
-        let target = self.allocator.alloc().expect("failed to allocate frame");
-        let entry = pt.map(addr, target);
-        attr.apply(entry);
+        match self.allocator.alloc() {
+            Some(target) => {
+                let entry = pt.map(addr, target);
+                attr.apply(entry);
+            }
+            // out of memory, retry on page fault
+            None => self.map_lazy(pt, addr, attr),
+        }
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
-        let target = pt.get_entry(addr).expect("fail to get entry").target();
-        self.allocator.dealloc(target);
+        let entry = pt.get_entry(addr).expect("fail to get entry");
+        if entry.present() {
+            self.allocator.dealloc(entry.target());
+        }
+
+        // PageTable::unmap requires page to be present
+        entry.set_present(true);
         pt.unmap(addr);
     }
 
@@ -28,14 +38,65 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
         src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
-        self.map(pt, addr, attr);
+    ) -> bool {
+        let entry = src_pt.get_entry(addr).expect("failed to get entry");
+        if !entry.present() {
+            self.map_lazy(pt, addr, attr);
+            return true;
+        }
+        let target = match self.allocator.alloc() {
+            Some(target) => target,
+            None => return false,
+        };
+        let entry = pt.map(addr, target);
+        attr.apply(entry);
         let data = src_pt.get_page_slice_mut(addr);
         pt.get_page_slice_mut(addr).copy_from_slice(data);
+        true
     }
 
-    fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
-        false
+    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+        // only pages failed to allocate in `map` or discarded are missing
+        let addr = addr & !(PAGE_SIZE - 1);
+        let entry = match pt.get_entry(addr) {
+            Some(entry) if !entry.present() => entry,
+            _ => return false,
+        };
+        let frame = match self.allocator.alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        entry.set_target(frame);
+        entry.set_present(true);
+        entry.update();
+        self.fill_zero(pt, addr);
+        true
+    }
+
+    fn discard(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        // free the frame, a zero page is allocated on the next access
+        let entry = match pt.get_entry(addr) {
+            Some(entry) if entry.present() => entry,
+            _ => return,
+        };
+        self.allocator.dealloc(entry.target());
+        entry.set_present(false);
+        entry.set_target(0);
+        attr.apply(entry);
+    }
+
+    fn set_allocator(&mut self, allocator: &dyn Any) -> bool {
+        match allocator.downcast_ref::<T>() {
+            Some(allocator) => {
+                self.allocator = allocator.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn relocate(&self, _offset: isize) -> Option<Box<dyn MemoryHandler>> {
+        Some(self.box_clone())
     }
 }
 
@@ -43,4 +104,20 @@ impl<T: FrameAllocator> ByFrame<T> {
     pub fn new(allocator: T) -> Self {
         ByFrame { allocator }
     }
+
+    /// Map `addr` without a frame, which is allocated on the first access
+    fn map_lazy(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        let entry = pt.map(addr, 0);
+        entry.set_present(false);
+        attr.apply(entry);
+    }
+
+    fn fill_zero(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let data = pt.get_page_slice_mut(addr);
+        let len = data.len();
+        for x in data {
+            *x = 0;
+        }
+        pt.flush_cache_copy_user(addr, addr + len, false);
+    }
 }
@@ -0,0 +1,172 @@
+use super::*;
+
+/// Eagerly allocate frames, using huge pages when the alignment allows.
+#[derive(Debug, Clone)]
+pub struct ByHugeFrame<T: FrameAllocator> {
+    allocator: T,
+}
+
+impl<T: FrameAllocator> MemoryHandler for ByHugeFrame<T> {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        let entry = match self.allocator.alloc() {
+            Some(target) => pt.map(addr, target),
+            None => {
+                // out of memory, retry on page fault
+                let entry = pt.map(addr, 0);
+                entry.set_present(false);
+                attr.apply(entry);
+                return;
+            }
+        };
+        attr.apply(entry);
+        self.fill_zero(pt, addr, PAGE_SIZE);
+    }
+
+    fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        self.unmap_range(pt, addr, addr + PAGE_SIZE);
+    }
+
+    fn map_range(
+        &self,
+        pt: &mut dyn PageTable,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        let mut addr = start_addr & !(PAGE_SIZE - 1);
+        while addr < end_addr {
+            if addr & (HUGE_PAGE_SIZE - 1) == 0 && end_addr - addr >= HUGE_PAGE_SIZE {
+                if let Some(target) = self.alloc_huge() {
+                    if let Some(entry) = pt.map_huge(addr, target, HUGE_PAGE_SIZE) {
+                        attr.apply(entry);
+                        self.fill_zero(pt, addr, HUGE_PAGE_SIZE);
+                        addr += HUGE_PAGE_SIZE;
+                        continue;
+                    }
+                    self.dealloc_range(target, HUGE_PAGE_SIZE);
+                }
+            }
+            self.map(pt, addr, attr);
+            addr += PAGE_SIZE;
+        }
+    }
+
+    fn unmap_range(&self, pt: &mut dyn PageTable, start_addr: VirtAddr, end_addr: VirtAddr) {
+        unmap_huge_range(pt, start_addr, end_addr, &mut |pt, addr, target, size| {
+            let entry = pt.get_entry(addr).expect("failed to get entry");
+            if entry.present() {
+                self.dealloc_range(target, size);
+            } else {
+                // PageTable::unmap requires page to be present
+                entry.set_present(true);
+            }
+        });
+    }
+
+    fn clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) -> bool {
+        let entry = src_pt.get_entry(addr).expect("failed to get entry");
+        if !entry.present() {
+            self.map(pt, addr, attr);
+            return true;
+        }
+        let target = match self.allocator.alloc() {
+            Some(target) => target,
+            None => return false,
+        };
+        let entry = pt.map(addr, target);
+        attr.apply(entry);
+        let data = src_pt.get_page_slice_mut(addr);
+        pt.get_page_slice_mut(addr).copy_from_slice(data);
+        true
+    }
+
+    fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+        // only pages failed to allocate in `map` or discarded are missing
+        let addr = addr & !(PAGE_SIZE - 1);
+        let entry = match pt.get_entry(addr) {
+            Some(entry) if !entry.present() => entry,
+            _ => return false,
+        };
+        let frame = match self.allocator.alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        entry.set_target(frame);
+        entry.set_present(true);
+        entry.update();
+        self.fill_zero(pt, addr, PAGE_SIZE);
+        true
+    }
+
+    fn discard(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        // free the frame, a zero page is allocated on the next access
+        split_huge_page(pt, addr);
+        let entry = match pt.get_entry(addr) {
+            Some(entry) if entry.present() => entry,
+            _ => return,
+        };
+        self.allocator.dealloc(entry.target());
+        entry.set_present(false);
+        entry.set_target(0);
+        attr.apply(entry);
+    }
+
+    fn set_allocator(&mut self, allocator: &dyn Any) -> bool {
+        match allocator.downcast_ref::<T>() {
+            Some(allocator) => {
+                self.allocator = allocator.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn relocate(&self, _offset: isize) -> Option<Box<dyn MemoryHandler>> {
+        Some(self.box_clone())
+    }
+}
+
+impl<T: FrameAllocator> ByHugeFrame<T> {
+    pub fn new(allocator: T) -> Self {
+        ByHugeFrame { allocator }
+    }
+
+    /// Allocate physical memory for a huge page
+    fn alloc_huge(&self) -> Option<PhysAddr> {
+        const PAGES: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+        let target = self
+            .allocator
+            .alloc_contiguous(PAGES, PAGES.trailing_zeros() as usize)?;
+        if target & (HUGE_PAGE_SIZE - 1) != 0 {
+            // physical memory does not start at an aligned address
+            self.dealloc_range(target, HUGE_PAGE_SIZE);
+            return None;
+        }
+        Some(target)
+    }
+
+    fn dealloc_range(&self, target: PhysAddr, size: usize) {
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            self.allocator.dealloc(target + offset);
+        }
+    }
+
+    fn fill_zero(&self, pt: &mut dyn PageTable, addr: VirtAddr, size: usize) {
+        for page in Page::range_of(addr, addr + size) {
+            for x in pt.get_page_slice_mut(page.start_address()) {
+                *x = 0;
+            }
+        }
+        pt.flush_cache_copy_user(addr, addr + size, false);
+    }
+}
@@ -33,12 +33,15 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         let entry = src_pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
             // eager map and copy data
             let data = src_pt.get_page_slice_mut(addr);
-            let target = self.allocator.alloc().expect("failed to alloc frame");
+            let target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => return false,
+            };
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
@@ -47,6 +50,7 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
             // delay map
             self.map(pt, addr, attr);
         }
+        true
     }
 
     fn handle_page_fault_ext(
@@ -65,7 +69,10 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
             error!("Permission check failed at 0x{:x}.", addr);
             return false;
         }
-        let frame = self.allocator.alloc().expect("failed to alloc frame");
+        let frame = match self.allocator.alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
         entry.set_target(frame);
         entry.set_present(true);
         entry.update();
@@ -78,6 +85,20 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         pt.flush_cache_copy_user(addr, addr + len, false);
         true
     }
+
+    fn set_allocator(&mut self, allocator: &dyn Any) -> bool {
+        match allocator.downcast_ref::<T>() {
+            Some(allocator) => {
+                self.allocator = allocator.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn relocate(&self, _offset: isize) -> Option<Box<dyn MemoryHandler>> {
+        Some(self.box_clone())
+    }
 }
 
 impl<T: FrameAllocator> Delay<T> {
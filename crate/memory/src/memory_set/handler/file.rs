@@ -1,4 +1,5 @@
 use super::*;
+use core::any::Any;
 
 /// Delay mapping a page to an area of a file.
 #[derive(Clone)]
@@ -12,6 +13,10 @@ pub struct File<F, T> {
 
 pub trait Read: Clone + Send + Sync + 'static {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+
+    /// Adjust the content at `offset` just read into executable memory,
+    /// like inserting breakpoints.
+    fn patch_code(&self, _offset: usize, _buf: &mut [u8]) {}
 }
 
 impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
@@ -42,12 +47,15 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
         src_pt: &mut dyn PageTable,
         addr: usize,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         let entry = src_pt.get_entry(addr).expect("failed to get entry");
         if entry.present() && !attr.readonly {
             // eager map and copy data
             let data = src_pt.get_page_slice_mut(addr);
-            let target = self.allocator.alloc().expect("failed to alloc frame");
+            let target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => return false,
+            };
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
@@ -56,6 +64,7 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
             // delay map
             self.map(pt, addr, attr);
         }
+        true
     }
 
     fn handle_page_fault_ext(
@@ -79,25 +88,76 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
             return false;
         }
         let execute = entry.execute();
-        let frame = self.allocator.alloc().expect("failed to alloc frame");
+        let frame = match self.allocator.alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
         entry.set_target(frame);
         entry.set_present(true);
         entry.update();
 
-        let read_size = self.fill_data(pt, addr);
+        let read_size = self.fill_data(pt, addr, execute);
         pt.flush_cache_copy_user(addr, addr + read_size, execute);
         true
     }
+
+    fn set_allocator(&mut self, allocator: &dyn Any) -> bool {
+        match allocator.downcast_ref::<T>() {
+            Some(allocator) => {
+                self.allocator = allocator.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn relocate(&self, offset: isize) -> Option<Box<dyn MemoryHandler>> {
+        let mut handler = self.clone();
+        handler.mem_start = (self.mem_start as isize + offset) as usize;
+        Some(Box::new(handler))
+    }
+
+    fn file_offset(&self, addr: VirtAddr) -> Option<(&dyn Any, usize)> {
+        let offset = addr.checked_sub(self.mem_start)? + self.file_start;
+        if offset < self.file_end {
+            Some((&self.file, offset))
+        } else {
+            None
+        }
+    }
+
+    fn patch_file_page(&self, pt: &mut dyn PageTable, addr: VirtAddr, f: &mut PatchFn) {
+        let addr = addr & !(PAGE_SIZE - 1);
+        match pt.get_entry(addr) {
+            Some(entry) if entry.present() => {}
+            _ => return,
+        }
+        let (file_offset, size) = self.file_range(addr);
+        if size == 0 {
+            return;
+        }
+        let data = pt.get_page_slice_mut(addr);
+        f(&self.file, file_offset, &mut data[..size]);
+    }
 }
 
 impl<F: Read, T: FrameAllocator> File<F, T> {
-    fn fill_data(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> usize {
-        let data = pt.get_page_slice_mut(addr);
+    /// The file offset and the size of the file content in the page at `addr`
+    fn file_range(&self, addr: VirtAddr) -> (usize, usize) {
         let file_offset = addr + self.file_start - self.mem_start;
-        let read_size = (self.file_end as isize - file_offset as isize)
+        let size = (self.file_end as isize - file_offset as isize)
             .min(PAGE_SIZE as isize)
             .max(0) as usize;
+        (file_offset, size)
+    }
+
+    fn fill_data(&self, pt: &mut dyn PageTable, addr: VirtAddr, execute: bool) -> usize {
+        let data = pt.get_page_slice_mut(addr);
+        let (file_offset, read_size) = self.file_range(addr);
         let read_size = self.file.read_at(file_offset, &mut data[..read_size]);
+        if execute {
+            self.file.patch_code(file_offset, &mut data[..read_size]);
+        }
         if read_size != PAGE_SIZE {
             data[read_size..].iter_mut().for_each(|x| *x = 0);
         }
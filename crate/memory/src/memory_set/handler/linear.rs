@@ -17,7 +17,37 @@ impl MemoryHandler for Linear {
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
-        pt.unmap(addr);
+        self.unmap_range(pt, addr, addr + PAGE_SIZE);
+    }
+
+    fn map_range(
+        &self,
+        pt: &mut dyn PageTable,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        let mut addr = start_addr & !(PAGE_SIZE - 1);
+        'next: while addr < end_addr {
+            let target = (addr as isize + self.offset) as PhysAddr;
+            // use the largest page fitting in the range
+            for &size in [GIANT_PAGE_SIZE, HUGE_PAGE_SIZE].iter() {
+                let mask = size - 1;
+                if addr & mask == 0 && target & mask == 0 && end_addr - addr >= size {
+                    if let Some(entry) = pt.map_huge(addr, target, size) {
+                        attr.apply(entry);
+                        addr += size;
+                        continue 'next;
+                    }
+                }
+            }
+            self.map(pt, addr, attr);
+            addr += PAGE_SIZE;
+        }
+    }
+
+    fn unmap_range(&self, pt: &mut dyn PageTable, start_addr: VirtAddr, end_addr: VirtAddr) {
+        unmap_huge_range(pt, start_addr, end_addr, &mut |_, _, _, _| {});
     }
 
     fn clone_map(
@@ -26,13 +56,23 @@ impl MemoryHandler for Linear {
         _src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         self.map(pt, addr, attr);
+        true
     }
 
     fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
         false
     }
+
+    fn discard(&self, _pt: &mut dyn PageTable, _addr: VirtAddr, _attr: &MemoryAttr) {
+        // device memory, nothing to drop
+    }
+
+    fn relocate(&self, offset: isize) -> Option<Box<dyn MemoryHandler>> {
+        // still point to the same physical memory
+        Some(Box::new(Linear::new(self.offset - offset)))
+    }
 }
 
 impl Linear {
@@ -1,4 +1,5 @@
 use super::*;
+use core::any::Any;
 #[derive(Copy, Clone, Debug)]
 pub struct AccessType {
     pub write: bool,
@@ -40,6 +41,10 @@ impl AccessType {
             && ((!self.user) || entry.user())
     }
 }
+/// Called as `f(file, offset, data)` with the file content in a page,
+/// see [`MemoryHandler::patch_file_page`]
+pub type PatchFn<'a> = dyn FnMut(&dyn Any, usize, &mut [u8]) + 'a;
+
 // here may be a interesting part for lab
 pub trait MemoryHandler: Debug + Send + Sync + 'static {
     fn box_clone(&self) -> Box<dyn MemoryHandler>;
@@ -51,14 +56,36 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
     /// Unmap `addr` in the page table
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr);
 
+    /// Map [`start_addr`, `end_addr`) in the page table
+    /// Handlers can override this to map huge pages
+    fn map_range(
+        &self,
+        pt: &mut dyn PageTable,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        for page in Page::range_of(start_addr, end_addr) {
+            self.map(pt, page.start_address(), attr);
+        }
+    }
+
+    /// Unmap [`start_addr`, `end_addr`) in the page table
+    fn unmap_range(&self, pt: &mut dyn PageTable, start_addr: VirtAddr, end_addr: VirtAddr) {
+        for page in Page::range_of(start_addr, end_addr) {
+            self.unmap(pt, page.start_address());
+        }
+    }
+
     /// Clone map `addr` from page table `src_pt` to `pt`.
+    /// Return false if out of memory, in which case `addr` is left unmapped in `pt`.
     fn clone_map(
         &self,
         pt: &mut dyn PageTable,
         src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    );
+    ) -> bool;
 
     /// Handle page fault on `addr`
     /// Return true if success, false if error
@@ -76,6 +103,40 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
     ) -> bool {
         self.handle_page_fault(pt, addr)
     }
+
+    /// Drop the content of `addr`, so that the next access sees a fresh page
+    /// (zero-filled for anonymous memory, reloaded for file-backed memory).
+    /// Used for `MADV_DONTNEED`.
+    fn discard(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        self.unmap(pt, addr);
+        self.map(pt, addr, attr);
+    }
+
+    /// Make frames allocated later come from `allocator` if it has the type of
+    /// the allocator in use. Return whether it was replaced, in which case the
+    /// present frames will be freed to it as well.
+    /// Used when the memory is charged to another owner.
+    fn set_allocator(&mut self, _allocator: &dyn Any) -> bool {
+        false
+    }
+
+    /// Get a handler for the same memory moved by `offset` bytes.
+    /// Return `None` if the memory can not be moved. Used for `mremap`.
+    fn relocate(&self, _offset: isize) -> Option<Box<dyn MemoryHandler>> {
+        None
+    }
+
+    /// Get the file and the offset in it that `addr` is loaded from,
+    /// return `None` if it is not file-backed memory.
+    fn file_offset(&self, _addr: VirtAddr) -> Option<(&dyn Any, usize)> {
+        None
+    }
+
+    /// If the page at `addr` is present and loaded from a file,
+    /// call `f(file, offset, data)` with the file content in it.
+    /// Used to patch code in memory, like inserting breakpoints.
+    /// `f` is responsible for cache maintenance, as the page table may not be active.
+    fn patch_file_page(&self, _pt: &mut dyn PageTable, _addr: VirtAddr, _f: &mut PatchFn) {}
 }
 
 impl Clone for Box<dyn MemoryHandler> {
@@ -84,6 +145,37 @@ impl Clone for Box<dyn MemoryHandler> {
     }
 }
 
+/// Called as `f(pt, addr, target, size)` before unmapping every page in [`unmap_huge_range`]
+type UnmapHook<'a> = dyn FnMut(&mut dyn PageTable, VirtAddr, PhysAddr, usize) + 'a;
+
+/// Unmap [`start_addr`, `end_addr`) which may be mapped by huge pages.
+/// Huge pages crossing the boundary are split first.
+fn unmap_huge_range(
+    pt: &mut dyn PageTable,
+    start_addr: VirtAddr,
+    end_addr: VirtAddr,
+    f: &mut UnmapHook,
+) {
+    if start_addr & (pt.page_size(start_addr) - 1) != 0 {
+        split_huge_page(pt, start_addr);
+    }
+    if end_addr & (pt.page_size(end_addr - 1) - 1) != 0 {
+        split_huge_page(pt, end_addr - 1);
+    }
+    let mut addr = start_addr;
+    while addr < end_addr {
+        let size = pt.page_size(addr);
+        let target = pt.get_entry(addr).expect("failed to get entry").target();
+        f(pt, addr, target, size);
+        if size == PAGE_SIZE {
+            pt.unmap(addr);
+        } else {
+            pt.unmap_huge(addr, size);
+        }
+        addr += size;
+    }
+}
+
 pub trait FrameAllocator: Debug + Clone + Send + Sync + 'static {
     fn alloc(&self) -> Option<PhysAddr>;
     fn alloc_contiguous(&self, size: usize, align_log2: usize) -> Option<PhysAddr>;
@@ -91,6 +183,7 @@ pub trait FrameAllocator: Debug + Clone + Send + Sync + 'static {
 }
 
 mod byframe;
+mod byhugeframe;
 mod delay;
 mod file;
 mod linear;
@@ -98,6 +191,7 @@ mod shared;
 //mod swap;
 
 pub use self::byframe::ByFrame;
+pub use self::byhugeframe::ByHugeFrame;
 pub use self::delay::Delay;
 pub use self::file::{File, Read};
 pub use self::linear::Linear;
@@ -31,7 +31,7 @@ impl<T: FrameAllocator> SharedGuard<T> {
     }
 
     pub fn alloc(&mut self, virt_addr: usize) -> Option<usize> {
-        let phys_addr = self.allocator.alloc().expect("failed to allocate frame");
+        let phys_addr = self.allocator.alloc()?;
         self.target.insert(virt_addr, phys_addr);
         Some(phys_addr)
     }
@@ -113,11 +113,12 @@ impl<T: FrameAllocator> MemoryHandler for Shared<T> {
         _src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         // actual map done when handling page fault, since guard are copied.
         let entry = pt.map(addr, 0);
         entry.set_present(false);
         attr.apply(entry);
+        true
     }
 
     fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
@@ -129,7 +130,10 @@ impl<T: FrameAllocator> MemoryHandler for Shared<T> {
             return false;
         } else if phys_addr_opt.is_none() {
             // physical memory not alloced.
-            let frame = self.guard.lock().alloc(addr_offset).unwrap();
+            let frame = match self.guard.lock().alloc(addr_offset) {
+                Some(frame) => frame,
+                None => return false,
+            };
             entry.set_target(frame);
             entry.set_present(true);
             entry.update();
@@ -150,6 +154,22 @@ impl<T: FrameAllocator> MemoryHandler for Shared<T> {
         }
         true
     }
+
+    fn discard(&self, _pt: &mut dyn PageTable, _addr: VirtAddr, _attr: &MemoryAttr) {
+        // the content is shared with others, keep it
+    }
+
+    fn relocate(&self, offset: isize) -> Option<Box<dyn MemoryHandler>> {
+        let start_virt_addr = self
+            .start_virt_addr
+            .lock()
+            .map(|addr| (addr as isize + offset) as usize);
+        Some(Box::new(Shared {
+            allocator: self.allocator.clone(),
+            start_virt_addr: Arc::new(Mutex::new(start_virt_addr)),
+            guard: self.guard.clone(),
+        }))
+    }
 }
 
 impl<T: FrameAllocator> Shared<T> {
@@ -1,6 +1,7 @@
 //! Memory management structures
 
 use alloc::{boxed::Box, vec::Vec};
+use core::any::Any;
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::size_of;
 
@@ -8,7 +9,7 @@ use crate::paging::*;
 
 use super::*;
 
-use self::handler::MemoryHandler;
+use self::handler::{AccessType, FrameAllocator, MemoryHandler};
 
 pub mod handler;
 
@@ -59,15 +60,12 @@ impl MemoryArea {
     }
     /// Map all pages in the area to page table `pt`
     fn map(&self, pt: &mut dyn PageTable) {
-        for page in Page::range_of(self.start_addr, self.end_addr) {
-            self.handler.map(pt, page.start_address(), &self.attr);
-        }
+        self.handler
+            .map_range(pt, self.start_addr, self.end_addr, &self.attr);
     }
     /// Unmap all pages in the area from page table `pt`
     fn unmap(&self, pt: &mut dyn PageTable) {
-        for page in Page::range_of(self.start_addr, self.end_addr) {
-            self.handler.unmap(pt, page.start_address());
-        }
+        self.handler.unmap_range(pt, self.start_addr, self.end_addr);
     }
 }
 
@@ -217,12 +215,16 @@ impl<T: PageTableExt> MemorySet<T> {
             name,
         };
         area.map(&mut self.page_table);
-        // keep order by start address
+        self.insert(area);
+    }
+
+    /// Insert a mapped area, keeping order by start address
+    fn insert(&mut self, area: MemoryArea) {
         let idx = self
             .areas
             .iter()
             .enumerate()
-            .find(|(_, other)| start_addr < other.start_addr)
+            .find(|(_, other)| area.start_addr < other.start_addr)
             .map(|(i, _)| i)
             .unwrap_or(self.areas.len());
         self.areas.insert(idx, area);
@@ -328,6 +330,239 @@ impl<T: PageTableExt> MemorySet<T> {
         }
     }
 
+    /// Test if [`start_addr`, `end_addr`) is fully covered by areas
+    pub fn test_mapped_area(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
+        Page::range_of(start_addr, end_addr).all(|page| {
+            self.areas
+                .iter()
+                .any(|area| area.contains(page.start_address()))
+        })
+    }
+
+    /// Drop the content of pages in [`start_addr`, `end_addr`),
+    /// the next access will see a fresh page.
+    /// Used for `MADV_DONTNEED`.
+    pub fn discard(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) {
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ..
+        } = self;
+        for area in areas.iter() {
+            if !area.is_overlap_with(start_addr, end_addr) {
+                continue;
+            }
+            let start = start_addr.max(area.start_addr);
+            let end = end_addr.min(area.end_addr);
+            for page in Page::range_of(start, end) {
+                area.handler
+                    .discard(page_table, page.start_address(), &area.attr);
+            }
+        }
+    }
+
+    /// Fault in all pages in [`start_addr`, `end_addr`) ahead of time.
+    /// Return false if some of them can not be faulted in, e.g. out of memory.
+    /// Used for `MADV_WILLNEED` and before the kernel writes to user memory.
+    pub fn populate(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> bool {
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ..
+        } = self;
+        let mut ok = true;
+        for area in areas.iter() {
+            if !area.is_overlap_with(start_addr, end_addr) {
+                continue;
+            }
+            let start = start_addr.max(area.start_addr);
+            let end = end_addr.min(area.end_addr);
+            for page in Page::range_of(start, end) {
+                let addr = page.start_address();
+                let present = match page_table.get_entry(addr) {
+                    Some(entry) => entry.present(),
+                    None => continue,
+                };
+                if !present {
+                    ok &= area.handler.handle_page_fault_ext(
+                        page_table,
+                        addr,
+                        AccessType::read(true),
+                    );
+                }
+            }
+        }
+        ok
+    }
+
+    /// Split the huge pages crossing the boundaries of [`start_addr`, `end_addr`),
+    /// so that the pages inside can be changed without touching those outside.
+    /// Used for `mprotect`.
+    pub fn split_huge_pages(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) {
+        let start_addr = start_addr & !(PAGE_SIZE - 1);
+        let end_addr = (end_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        for area in self.areas.iter() {
+            if !area.is_overlap_with(start_addr, end_addr) {
+                continue;
+            }
+            let start = start_addr.max(area.start_addr);
+            let end = end_addr.min(area.end_addr);
+            if start & (self.page_table.page_size(start) - 1) != 0 {
+                split_huge_page(&mut self.page_table, start);
+            }
+            if end & (self.page_table.page_size(end - 1) - 1) != 0 {
+                split_huge_page(&mut self.page_table, end - 1);
+            }
+        }
+    }
+
+    /// Resize the mapping [`old_addr`, `old_addr + old_size`) to `new_size`.
+    /// If it can not be expanded in place, move it when `may_move` is set,
+    /// or move it to `new_addr` if given.
+    /// Return the new start address. Used for `mremap`.
+    pub fn remap(
+        &mut self,
+        old_addr: VirtAddr,
+        old_size: usize,
+        new_size: usize,
+        may_move: bool,
+        new_addr: Option<VirtAddr>,
+    ) -> VMResult<VirtAddr> {
+        let old_size = (old_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let new_size = (new_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let old_end = old_addr + old_size;
+        let idx = self
+            .areas
+            .iter()
+            .position(|area| area.start_addr <= old_addr && old_end <= area.end_addr)
+            .ok_or(VMError::InvalidPtr)?;
+
+        if let Some(new_addr) = new_addr {
+            // the old mapping would be destroyed by unmapping new_addr
+            if new_addr < old_end && old_addr < new_addr + new_size {
+                return Err(VMError::InvalidArgument);
+            }
+            // we have to move it to new_addr, so remove the old mapping first
+            self.pop_with_split(new_addr, new_addr + new_size);
+            let idx = self
+                .areas
+                .iter()
+                .position(|area| area.start_addr <= old_addr && old_end <= area.end_addr)
+                .ok_or(VMError::InvalidPtr)?;
+            self.move_area(idx, old_addr, old_size, new_addr, new_size)?;
+            return Ok(new_addr);
+        }
+
+        if new_size <= old_size {
+            // shrink
+            if new_size < old_size {
+                self.pop_with_split(old_addr + new_size, old_end);
+            }
+            return Ok(old_addr);
+        }
+
+        // expand in place
+        let new_end = old_addr + new_size;
+        if self.areas[idx].end_addr == old_end && self.test_free_area(old_end, new_end) {
+            let area = &mut self.areas[idx];
+            area.handler
+                .map_range(&mut self.page_table, old_end, new_end, &area.attr);
+            area.end_addr = new_end;
+            return Ok(old_addr);
+        }
+
+        if !may_move {
+            return Err(VMError::NoMemory);
+        }
+        let new_addr = self.find_free_area(old_addr, new_size);
+        self.move_area(idx, old_addr, old_size, new_addr, new_size)?;
+        Ok(new_addr)
+    }
+
+    /// Move [`old_addr`, `old_addr + old_size`) in the `idx`-th area
+    /// to a new area [`new_addr`, `new_addr + new_size`) with its content.
+    fn move_area(
+        &mut self,
+        idx: usize,
+        old_addr: VirtAddr,
+        old_size: usize,
+        new_addr: VirtAddr,
+        new_size: usize,
+    ) -> VMResult<()> {
+        let old_area = &self.areas[idx];
+        let handler = old_area
+            .handler
+            .relocate(new_addr as isize - old_addr as isize)
+            .ok_or(VMError::NoMemory)?;
+        let area = MemoryArea {
+            start_addr: new_addr,
+            end_addr: new_addr + new_size,
+            attr: old_area.attr,
+            handler,
+            name: old_area.name,
+        };
+        area.map(&mut self.page_table);
+        // copy the pages already in memory, others will be filled on demand
+        for offset in (0..old_size.min(new_size)).step_by(PAGE_SIZE) {
+            let src = old_addr + offset;
+            let dst = new_addr + offset;
+            let src_target = match self.page_table.get_entry(src) {
+                Some(entry) if entry.present() => entry.target(),
+                _ => continue,
+            };
+            let present = matches!(self.page_table.get_entry(dst), Some(entry) if entry.present());
+            if !present
+                && !area.handler.handle_page_fault_ext(
+                    &mut self.page_table,
+                    dst,
+                    AccessType::unknown(),
+                )
+            {
+                // out of memory, keep the old area untouched
+                area.unmap(&mut self.page_table);
+                return Err(VMError::NoMemory);
+            }
+            let dst_target = match self.page_table.get_entry(dst) {
+                Some(entry) if entry.present() => entry.target(),
+                _ => continue,
+            };
+            if src_target != dst_target {
+                let data = self.page_table.get_page_slice_mut(src);
+                self.page_table
+                    .get_page_slice_mut(dst)
+                    .copy_from_slice(data);
+                self.page_table
+                    .flush_cache_copy_user(dst, dst + PAGE_SIZE, area.attr.execute);
+            }
+        }
+        self.insert(area);
+        self.pop_with_split(old_addr, old_addr + old_size);
+        Ok(())
+    }
+
+    /// Get the file and the offset in it that `addr` is loaded from
+    pub fn file_offset(&self, addr: VirtAddr) -> Option<(&dyn Any, usize)> {
+        let area = self.areas.iter().find(|area| area.contains(addr))?;
+        area.handler.file_offset(addr)
+    }
+
+    /// Call `f(file, offset, data)` with the file content in present pages of
+    /// executable areas. Used to insert or remove breakpoints in loaded code,
+    /// `f` should make the change visible to instruction fetches.
+    pub fn patch_code(&mut self, mut f: impl FnMut(&dyn Any, usize, &mut [u8])) {
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ..
+        } = self;
+        for area in areas.iter().filter(|area| area.attr.execute) {
+            for page in Page::range_of(area.start_addr, area.end_addr) {
+                area.handler
+                    .patch_file_page(page_table, page.start_address(), &mut f);
+            }
+        }
+    }
+
     /// Get iterator of areas
     pub fn iter(&self) -> impl Iterator<Item = &MemoryArea> {
         self.areas.iter()
@@ -393,27 +628,74 @@ impl<T: PageTableExt> MemorySet<T> {
         }
     }
 
-    pub fn clone(&mut self) -> Self {
+    /// Get the number of pages resident in memory
+    pub fn rss(&mut self) -> usize {
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ..
+        } = self;
+        areas
+            .iter()
+            .flat_map(|area| Page::range_of(area.start_addr, area.end_addr))
+            .filter(|page| {
+                matches!(page_table.get_entry(page.start_address()), Some(entry) if entry.present())
+            })
+            .count()
+    }
+
+    /// Make the areas allocate frames from `allocator` if they use allocators of its type.
+    /// Return the number of frames present in these areas, which will be freed to it.
+    pub fn set_allocator<A: FrameAllocator>(&mut self, allocator: &A) -> usize {
+        let Self {
+            ref mut page_table,
+            ref mut areas,
+            ..
+        } = self;
+        let mut frames = 0;
+        for area in areas.iter_mut() {
+            if !area.handler.set_allocator(allocator) {
+                continue;
+            }
+            frames += Page::range_of(area.start_addr, area.end_addr)
+                .filter(|page| {
+                    matches!(page_table.get_entry(page.start_address()), Some(entry) if entry.present())
+                })
+                .count();
+        }
+        frames
+    }
+
+    /// Clone the memory set, copying the content of present pages.
+    /// Return `VMError::NoMemory` if out of memory, with nothing leaked.
+    pub fn try_clone(&mut self) -> VMResult<Self> {
         let mut new_page_table = T::new();
         let Self {
             ref mut page_table,
             ref areas,
             ..
         } = self;
-        for area in areas.iter() {
+        for (i, area) in areas.iter().enumerate() {
             for page in Page::range_of(area.start_addr, area.end_addr) {
-                area.handler.clone_map(
-                    &mut new_page_table,
-                    page_table,
-                    page.start_address(),
-                    &area.attr,
-                );
+                let addr = page.start_address();
+                if !area
+                    .handler
+                    .clone_map(&mut new_page_table, page_table, addr, &area.attr)
+                {
+                    // release the pages cloned so far
+                    for area in areas[..i].iter() {
+                        area.unmap(&mut new_page_table);
+                    }
+                    area.handler
+                        .unmap_range(&mut new_page_table, area.start_addr, addr);
+                    return Err(VMError::NoMemory);
+                }
             }
         }
-        MemorySet {
+        Ok(MemorySet {
             areas: areas.clone(),
             page_table: new_page_table,
-        }
+        })
     }
 }
 
@@ -428,3 +710,228 @@ impl<T: PageTableExt> Debug for MemorySet<T> {
         f.debug_list().entries(self.areas.iter()).finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::handler::{ByFrame, Delay, File, FrameAllocator, Read};
+    use super::*;
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    #[derive(Debug, Clone)]
+    struct FrameAlloc(Arc<Mutex<Vec<PhysAddr>>>);
+
+    impl FrameAlloc {
+        fn new() -> Self {
+            FrameAlloc(Arc::new(Mutex::new(
+                (8..16).map(|i| i * PAGE_SIZE).collect(),
+            )))
+        }
+        fn free_count(&self) -> usize {
+            self.0.lock().len()
+        }
+    }
+
+    impl FrameAllocator for FrameAlloc {
+        fn alloc(&self) -> Option<PhysAddr> {
+            self.0.lock().pop()
+        }
+        fn alloc_contiguous(&self, _size: usize, _align_log2: usize) -> Option<PhysAddr> {
+            unimplemented!()
+        }
+        fn dealloc(&self, target: PhysAddr) {
+            self.0.lock().push(target);
+        }
+    }
+
+    #[derive(Clone)]
+    struct Code(Arc<Vec<u8>>);
+
+    impl Read for Code {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+            let len = buf.len().min(self.0.len() - offset);
+            buf[..len].copy_from_slice(&self.0[offset..offset + len]);
+            len
+        }
+        // a breakpoint at offset 0x1010
+        fn patch_code(&self, offset: usize, buf: &mut [u8]) {
+            if let Some(byte) = 0x1010usize.checked_sub(offset).and_then(|i| buf.get_mut(i)) {
+                *byte = 0xcc;
+            }
+        }
+    }
+
+    #[test]
+    fn patch_code() {
+        let alloc = FrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user().readonly().execute();
+        let file = File {
+            file: Code(Arc::new(vec![0x90; 0x2000])),
+            mem_start: 0x1000,
+            file_start: 0,
+            file_end: 0x2000,
+            allocator: alloc.clone(),
+        };
+        ms.push(0x1000, 0x3000, attr, file, "code");
+        assert!(ms.file_offset(0x2010).unwrap().0.is::<Code>());
+        assert_eq!(ms.file_offset(0x2010).unwrap().1, 0x1010);
+
+        // patched when loaded
+        ms.populate(0x2000, 0x3000);
+        assert_eq!(ms.get_page_table_mut().read(0x2010), 0xcc);
+        assert_eq!(ms.get_page_table_mut().read(0x2011), 0x90);
+
+        // only present pages are patched
+        let mut patched = Vec::new();
+        ms.patch_code(|file, offset, data| {
+            assert!(file.is::<Code>());
+            patched.push((offset, data.len()));
+            data[0x10] = 0x90;
+        });
+        assert_eq!(patched, vec![(0x1000, 0x1000)]);
+        assert_eq!(ms.get_page_table_mut().read(0x2010), 0x90);
+    }
+
+    #[test]
+    fn discard() {
+        let alloc = FrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x3000, attr, Delay::new(alloc.clone()), "test");
+
+        ms.populate(0x1000, 0x3000);
+        assert_eq!(alloc.free_count(), 6);
+        ms.get_page_table_mut().write(0x1000, 1);
+        ms.get_page_table_mut().write(0x2000, 2);
+
+        ms.discard(0x1000, 0x2000);
+        assert_eq!(alloc.free_count(), 7);
+        assert!(!ms.get_page_table_mut().get_entry(0x1000).unwrap().present());
+
+        ms.populate(0x1000, 0x2000);
+        assert_eq!(ms.get_page_table_mut().read(0x1000), 0);
+        assert_eq!(ms.get_page_table_mut().read(0x2000), 2);
+
+        // frames of eagerly mapped areas are freed as well
+        ms.push(0x3000, 0x5000, attr, ByFrame::new(alloc.clone()), "eager");
+        assert_eq!(alloc.free_count(), 4);
+        ms.get_page_table_mut().write(0x3000, 3);
+        ms.discard(0x3000, 0x5000);
+        assert_eq!(alloc.free_count(), 6);
+        assert!(ms.handle_page_fault(0x3000));
+        assert_eq!(ms.get_page_table_mut().read(0x3000), 0);
+        drop(ms);
+        assert_eq!(alloc.free_count(), 8);
+    }
+
+    #[test]
+    fn remap() {
+        let alloc = FrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x2000, attr, Delay::new(alloc.clone()), "a");
+        ms.populate(0x1000, 0x2000);
+        ms.get_page_table_mut().write(0x1000, 1);
+
+        // expand in place
+        assert!(matches!(
+            ms.remap(0x1000, 0x1000, 0x2000, false, None),
+            Ok(0x1000)
+        ));
+        assert!(ms.test_mapped_area(0x1000, 0x3000));
+
+        // blocked by another area
+        ms.push(0x3000, 0x4000, attr, Delay::new(alloc.clone()), "b");
+        assert!(matches!(
+            ms.remap(0x1000, 0x2000, 0x3000, false, None),
+            Err(VMError::NoMemory)
+        ));
+
+        // move with content
+        assert!(matches!(
+            ms.remap(0x1000, 0x2000, 0x3000, true, None),
+            Ok(0x4000)
+        ));
+        assert!(!ms.test_mapped_area(0x1000, 0x3000));
+        assert_eq!(ms.get_page_table_mut().read(0x4000), 1);
+
+        // shrink
+        assert!(matches!(
+            ms.remap(0x4000, 0x3000, 0x1000, false, None),
+            Ok(0x4000)
+        ));
+        assert!(!ms.test_mapped_area(0x5000, 0x6000));
+
+        // overlapping move is rejected before anything is unmapped
+        assert!(matches!(
+            ms.remap(0x4000, 0x1000, 0x2000, true, Some(0x3000)),
+            Err(VMError::InvalidArgument)
+        ));
+        assert!(ms.test_mapped_area(0x3000, 0x5000));
+        assert_eq!(ms.get_page_table_mut().read(0x4000), 1);
+        drop(ms);
+        assert_eq!(alloc.free_count(), 8);
+    }
+
+    #[test]
+    fn set_allocator() {
+        let alloc = FrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x4000, attr, Delay::new(alloc.clone()), "a");
+        ms.populate(0x1000, 0x3000);
+        assert_eq!(alloc.free_count(), 6);
+
+        let other = FrameAlloc::new();
+        assert_eq!(ms.set_allocator(&other), 2);
+        assert!(ms.handle_page_fault(0x3000));
+        assert_eq!(other.free_count(), 7);
+        drop(ms);
+        assert_eq!(alloc.free_count(), 6);
+        assert_eq!(other.free_count(), 10);
+    }
+
+    #[test]
+    fn map_out_of_memory() {
+        let alloc = FrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x9000, attr, ByFrame::new(alloc.clone()), "a");
+        ms.push(0x9000, 0xa000, attr, ByFrame::new(alloc.clone()), "b");
+        assert_eq!(ms.rss(), 8);
+        assert!(!ms.get_page_table_mut().get_entry(0x9000).unwrap().present());
+        assert!(!ms.handle_page_fault(0x9000));
+        assert!(!ms.populate(0x8000, 0xa000));
+
+        ms.pop(0x1000, 0x9000);
+        assert!(ms.handle_page_fault(0x9000));
+        assert_eq!(ms.get_page_table_mut().read(0x9000), 0);
+        assert_eq!(ms.rss(), 1);
+        drop(ms);
+        assert_eq!(alloc.free_count(), 8);
+    }
+
+    #[test]
+    fn clone_out_of_memory() {
+        let alloc = FrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x3000, attr, Delay::new(alloc.clone()), "a");
+        ms.push(0x3000, 0x6000, attr, ByFrame::new(alloc.clone()), "b");
+        ms.populate(0x1000, 0x3000);
+        assert_eq!(ms.rss(), 5);
+        assert_eq!(alloc.free_count(), 3);
+
+        assert!(matches!(ms.try_clone(), Err(VMError::NoMemory)));
+        assert_eq!(alloc.free_count(), 3);
+
+        ms.pop(0x3000, 0x6000);
+        let mut ms2 = ms.try_clone().unwrap();
+        assert_eq!(ms2.rss(), 2);
+        assert_eq!(alloc.free_count(), 4);
+        drop(ms2);
+        drop(ms);
+        assert_eq!(alloc.free_count(), 8);
+    }
+}
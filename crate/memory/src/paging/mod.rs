@@ -29,6 +29,32 @@ pub trait PageTable {
     /// When copied user data (in page fault handler)，maybe need to flush I/D cache.
     fn flush_cache_copy_user(&mut self, start: VirtAddr, end: VirtAddr, execute: bool);
 
+    /// Map a huge page of `size` bytes at virtual address `addr` to physical address `target`,
+    /// both of which should be aligned to `size`.
+    /// Return `None` if it can not be mapped as a huge page, the page table is not changed then.
+    fn map_huge(
+        &mut self,
+        _addr: VirtAddr,
+        _target: PhysAddr,
+        _size: usize,
+    ) -> Option<&mut dyn Entry> {
+        None
+    }
+
+    /// Unmap the huge page of `size` bytes at virtual address `addr`
+    /// Page tables without huge pages only have normal pages there
+    fn unmap_huge(&mut self, addr: VirtAddr, size: usize) {
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            self.unmap(addr + offset);
+        }
+    }
+
+    /// Get the size of the page containing `addr`,
+    /// which is `PAGE_SIZE` unless it is mapped by a huge page.
+    fn page_size(&mut self, _addr: VirtAddr) -> usize {
+        PAGE_SIZE
+    }
+
     /// Read data from virtual address `addr`
     /// Used for testing with mock
     fn read(&mut self, _addr: VirtAddr) -> u8 {
@@ -42,6 +68,30 @@ pub trait PageTable {
     }
 }
 
+/// Split the huge page containing `addr` (if any) into normal pages with the same attributes.
+pub fn split_huge_page(pt: &mut dyn PageTable, addr: VirtAddr) {
+    let size = pt.page_size(addr);
+    if size == PAGE_SIZE {
+        return;
+    }
+    let base = addr & !(size - 1);
+    let entry = pt.get_entry(base).expect("failed to get entry");
+    let target = entry.target();
+    let writable = entry.writable();
+    let user = entry.user();
+    let execute = entry.execute();
+    let mmio = entry.mmio();
+    pt.unmap_huge(base, size);
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        let entry = pt.map(base + offset, target + offset);
+        entry.set_writable(writable);
+        entry.set_user(user);
+        entry.set_execute(execute);
+        entry.set_mmio(mmio);
+        entry.update();
+    }
+}
+
 /// Page Table Entry
 pub trait Entry {
     /// Make all changes take effect.
@@ -24,4 +24,170 @@ fn main() {
     } else if target.contains("x86_64") {
         println!("cargo:rustc-cfg=x86_64");
     }
+
+    gen_syscall_names(&target).unwrap();
+    gen_btf_structs().unwrap();
+}
+
+/// Build the syscall name table used by syscall tracepoints from the syscall ids of the arch
+fn gen_syscall_names(target: &str) -> std::io::Result<()> {
+    let arch = if target.contains("riscv") {
+        "riscv"
+    } else if target.contains("mipsel") {
+        "mipsel"
+    } else if target.contains("aarch64") {
+        "aarch64"
+    } else {
+        "x86_64"
+    };
+    let path = format!("src/arch/{}/syscall.rs", arch);
+    println!("cargo:rerun-if-changed={}", path);
+    let source = std::fs::read_to_string(&path)?;
+
+    let mut names = Vec::new();
+    for line in source.lines() {
+        // pub const SYS_READ: usize = 0;
+        if let Some(def) = line.strip_prefix("pub const SYS_") {
+            let mut parts = def.trim_end_matches(';').split(": usize = ");
+            if let (Some(name), Some(Ok(id))) =
+                (parts.next(), parts.next().map(str::parse::<usize>))
+            {
+                names.push((name.to_lowercase(), id));
+            }
+        }
+        // define_syscall!(READ, 3); on mipsel, ids start at MIPS_SYSCALL_OFFSET
+        if let Some(def) = line.strip_prefix("define_syscall!(") {
+            let mut parts = def.trim_end_matches(");").split(", ");
+            if let (Some(name), Some(Ok(id))) =
+                (parts.next(), parts.next().map(str::parse::<usize>))
+            {
+                names.push((name.to_lowercase(), 4000 + id));
+            }
+        }
+    }
+
+    let out = std::env::var("OUT_DIR").unwrap();
+    let mut table = String::from("pub static SYSCALL_NAMES: &[(&str, usize)] = &[\n");
+    for (name, id) in names {
+        table += &format!("    (\"{}\", {}),\n", name, id);
+    }
+    table += "];\n";
+    std::fs::write(format!("{}/syscall_names.rs", out), table)
+}
+
+/// Kernel structures described to eBPF programs in BTF, by the files defining them
+const BTF_STRUCTS: &[(&str, &str)] = &[
+    ("src/process/thread.rs", "crate::process::Thread"),
+    ("src/process/proc.rs", "crate::process::Process"),
+    ("src/fs/file.rs", "crate::fs::FileHandle"),
+];
+
+/// Generate the code describing `BTF_STRUCTS` from their definitions.
+/// Only public fields are described. Their layouts are left to the compiler,
+/// which the generated code asks with `addr_of!`.
+fn gen_btf_structs() -> std::io::Result<()> {
+    let mut sources = Vec::new();
+    for (file, _) in BTF_STRUCTS {
+        println!("cargo:rerun-if-changed={}", file);
+        sources.push(std::fs::read_to_string(file)?);
+    }
+
+    // pub type Tid = usize; pub struct Pid(pub usize);
+    let mut aliases = std::collections::BTreeMap::new();
+    for line in sources.iter().flat_map(|source| source.lines()) {
+        let alias = line
+            .strip_prefix("pub type ")
+            .and_then(|def| def.strip_suffix(';'))
+            .and_then(|def| def.split_once(" = "))
+            .or_else(|| {
+                let def = line.strip_prefix("pub struct ")?.strip_suffix(");")?;
+                def.split_once("(pub ")
+            });
+        if let Some((name, ty)) = alias {
+            aliases.insert(name.to_string(), ty.to_string());
+        }
+    }
+    let names: Vec<&str> = BTF_STRUCTS
+        .iter()
+        .map(|(_, path)| path.rsplit("::").next().unwrap())
+        .collect();
+
+    let mut code = String::from("fn kernel_structs() -> Vec<KernelType> {\n    vec![\n");
+    for ((_, path), (name, source)) in BTF_STRUCTS.iter().zip(names.iter().zip(&sources)) {
+        let mut lines = source
+            .lines()
+            .skip_while(|line| *line != format!("pub struct {} {{", name))
+            .skip(1)
+            .take_while(|line| *line != "}");
+        code += &format!(
+            "        btf_struct::<{}>(\"{}\", |base| unsafe {{\n            vec![\n",
+            path, name
+        );
+        for line in &mut lines {
+            // pub tid: Tid, // comment
+            let line = line.split("//").next().unwrap();
+            let field = line.trim().strip_prefix("pub ").and_then(|def| {
+                let (field, ty) = def.strip_suffix(',')?.split_once(": ")?;
+                Some((field, ty))
+            });
+            if let Some((field, ty)) = field {
+                code += &format!(
+                    "                member(\"{}\", base, addr_of!((*base).{}), |ptr| {}),\n",
+                    field,
+                    field,
+                    btf_type(ty, &aliases, &names)
+                );
+            }
+        }
+        code += "            ]\n        }),\n";
+    }
+    code += "    ]\n}\n";
+
+    let out = std::env::var("OUT_DIR").unwrap();
+    std::fs::write(format!("{}/btf_structs.rs", out), code)
+}
+
+/// Code building the BTF type of `ty`, from a pointer `ptr` to it
+fn btf_type(
+    ty: &str,
+    aliases: &std::collections::BTreeMap<String, String>,
+    structs: &[&str],
+) -> String {
+    if let Some(ty) = aliases.get(ty) {
+        return btf_type(ty, aliases, structs);
+    }
+    let inner = |prefix: &str| ty.strip_prefix(prefix)?.strip_suffix('>');
+    match ty {
+        "u8" | "u16" | "u32" | "u64" | "usize" => "int(ptr, false)".into(),
+        "i8" | "i16" | "i32" | "i64" | "isize" => "int(ptr, true)".into(),
+        "bool" => "boolean(ptr)".into(),
+        _ if structs.contains(&ty) => format!("described(ptr, \"{}\")", ty),
+        _ => {
+            let arc = inner("Arc<").map(|data| ("arc", data));
+            if let Some((func, data)) = arc.or_else(|| Some(("weak", inner("Weak<")?))) {
+                format!(
+                    "{}(ptr, \"{}\", |ptr| {})",
+                    func,
+                    c_name(&format!("ArcInner<{}>", data)),
+                    btf_type(data, aliases, structs)
+                )
+            } else if let Some(data) = inner("Mutex<") {
+                format!(
+                    "mutex(ptr, \"{}\", |ptr| {})",
+                    c_name(ty),
+                    btf_type(data, aliases, structs)
+                )
+            } else {
+                format!("opaque(ptr, \"{}\")", c_name(ty))
+            }
+        }
+    }
+}
+
+/// A C identifier naming a Rust type, `Arc<Mutex<Process>>` is `Arc_Mutex_Process`
+fn c_name(ty: &str) -> String {
+    ty.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
 }
@@ -33,6 +33,14 @@ pub fn is_syscall(trap: usize) -> bool {
     trap == Syscall
 }
 
+pub fn is_breakpoint(trap: usize) -> bool {
+    trap == 0x2 && matches!(Syndrome::from(ESR_EL1.get() as u32), Syndrome::Brk(_))
+}
+
+pub fn is_single_step(trap: usize) -> bool {
+    trap == 0x2 && matches!(Syndrome::from(ESR_EL1.get() as u32), Syndrome::Step)
+}
+
 pub fn is_intr(trap: usize) -> bool {
     IrqMin <= trap && trap <= IrqMax
 }
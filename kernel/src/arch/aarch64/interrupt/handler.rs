@@ -87,12 +87,23 @@ pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
                     }
                     _ => panic!(),
                 },
+                Syndrome::Brk(_) => {
+                    if !crate::kprobes::breakpoint_handler(tf) {
+                        panic!("\nEXCEPTION: Breakpoint @ {:#x}", tf.elr);
+                    }
+                }
+                Syndrome::Step => {
+                    if !crate::kprobes::single_step_handler(tf) {
+                        panic!("\nEXCEPTION: Step @ {:#x}", tf.elr);
+                    }
+                }
                 _ => panic!(),
             }
         }
         Kind::Irq => {
             if timer::is_pending() {
                 crate::arch::board::timer::set_next();
+                crate::perf::kernel_tick(tf.elr, tf.general.x29);
                 crate::trap::timer();
             } else {
                 IRQ_MANAGER.read().try_handle_interrupt(Some(tf.trap_num));
@@ -39,6 +39,7 @@ pub extern "C" fn main_start() -> ! {
     board::early_final();
     crate::lkm::manager::ModuleManager::init();
     board::init();
+    crate::net::init();
 
     crate::process::init();
 
@@ -7,7 +7,7 @@ use aarch64::paging::{
     mapper::{MappedPageTable, Mapper},
     memory_attribute::*,
     page_table::{PageTable as Aarch64PageTable, PageTableEntry, PageTableFlags as EF},
-    FrameAllocator, FrameDeallocator, Page as PageAllSizes, Size2MiB, Size4KiB,
+    FrameAllocator, FrameDeallocator, Page as PageAllSizes, PageSize, Size1GiB, Size2MiB, Size4KiB,
 };
 use aarch64::translation::{invalidate_tlb_vaddr, local_invalidate_tlb_all};
 use aarch64::translation::{ttbr_el1_read, ttbr_el1_write};
@@ -15,6 +15,7 @@ use aarch64::{align_down, align_up, PhysAddr, ALIGN_2MIB};
 use core::mem::ManuallyDrop;
 use log::*;
 use rcore_memory::paging::*;
+use rcore_memory::{GIANT_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE};
 
 type Page = PageAllSizes<Size4KiB>;
 
@@ -55,6 +56,12 @@ impl PageTable for PageTableImpl {
 
     fn get_entry(&mut self, vaddr: usize) -> Option<&mut dyn Entry> {
         let page = Page::of_addr(vaddr as u64);
+        if let Some((e, size)) = self.leaf_entry(vaddr) {
+            if size != PAGE_SIZE {
+                self.entry = Some(PageEntry(e, page));
+                return Some(self.entry.as_mut().unwrap());
+            }
+        }
         if let Ok(e) = self.page_table.get_entry_mut(page) {
             let e = unsafe { &mut *(e as *mut PageTableEntry) };
             self.entry = Some(PageEntry(e, page));
@@ -65,6 +72,13 @@ impl PageTable for PageTableImpl {
     }
 
     fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
+        if let Some((e, size)) = self.leaf_entry(addr) {
+            if size != PAGE_SIZE {
+                let paddr = e.addr().as_u64() as usize + (addr & (size - 1) & !(PAGE_SIZE - 1));
+                let vaddr = phys_to_virt(paddr);
+                return unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, 0x1000) };
+            }
+        }
         let frame = self
             .page_table
             .translate_page(Page::of_addr(addr as u64))
@@ -91,6 +105,28 @@ impl PageTable for PageTableImpl {
             }
         }
     }
+
+    fn map_huge(&mut self, addr: usize, target: usize, size: usize) -> Option<&mut dyn Entry> {
+        // 2 MiB blocks at level 2, 1 GiB blocks at level 1 with the 4 KiB granule
+        match size {
+            HUGE_PAGE_SIZE => self.map_block::<Size2MiB>(addr, target)?,
+            GIANT_PAGE_SIZE => self.map_block::<Size1GiB>(addr, target)?,
+            _ => return None,
+        }
+        self.get_entry(addr)
+    }
+
+    fn unmap_huge(&mut self, addr: usize, size: usize) {
+        match size {
+            HUGE_PAGE_SIZE => self.unmap_block::<Size2MiB>(addr),
+            GIANT_PAGE_SIZE => self.unmap_block::<Size1GiB>(addr),
+            _ => panic!("not a huge page of this size"),
+        }
+    }
+
+    fn page_size(&mut self, addr: usize) -> usize {
+        self.leaf_entry(addr).map_or(PAGE_SIZE, |(_, size)| size)
+    }
 }
 
 fn frame_to_page_table(frame: Frame) -> *mut Aarch64PageTable {
@@ -229,6 +265,62 @@ impl PageEntry {
 }
 
 impl PageTableImpl {
+    /// Map a block of size `S` at `addr` to `target`, return `None` if it is in use
+    fn map_block<S: PageSize>(&mut self, addr: usize, target: usize) -> Option<()>
+    where
+        MappedPageTable<'static, fn(Frame) -> *mut Aarch64PageTable>: Mapper<S>,
+    {
+        let flags = EF::default_block() | EF::PXN | EF::UXN;
+        let attr = MairNormal::attr_value();
+        unsafe {
+            self.page_table
+                .map_to(
+                    PageAllSizes::<S>::of_addr(addr as u64),
+                    Frame::<S>::of_addr(target as u64),
+                    flags,
+                    attr,
+                    &mut FrameAllocatorForAarch64,
+                )
+                .ok()?
+                .flush();
+        }
+        Some(())
+    }
+
+    fn unmap_block<S: PageSize>(&mut self, addr: usize)
+    where
+        MappedPageTable<'static, fn(Frame) -> *mut Aarch64PageTable>: Mapper<S>,
+    {
+        self.page_table
+            .unmap(PageAllSizes::<S>::of_addr(addr as u64))
+            .unwrap()
+            .1
+            .flush();
+    }
+
+    /// Walk the page table to the last level entry of `addr`, which may be a block.
+    /// Return the entry and the size of the memory it maps.
+    fn leaf_entry(&self, addr: usize) -> Option<(&'static mut PageTableEntry, usize)> {
+        let mut page_table = frame_to_page_table(self.root_frame);
+        for level in 0..4 {
+            let index = (addr >> (12 + (3 - level) * 9)) & 0o777;
+            let entry = unsafe { &mut (&mut *page_table)[index] };
+            let size = 1 << (12 + (3 - level) * 9);
+            if level == 3 {
+                return Some((entry, size));
+            }
+            if !entry.flags().contains(EF::VALID) {
+                return None;
+            }
+            if level > 0 && !entry.flags().contains(EF::TABLE_OR_PAGE) {
+                // block descriptor
+                return Some((entry, size));
+            }
+            page_table = frame_to_page_table(Frame::of_addr(entry.addr().as_u64()));
+        }
+        unreachable!();
+    }
+
     /// Unsafely get the current active page table.
     /// Using ManuallyDrop to wrap the page table: this is how `core::mem::forget` is implemented now.
     pub unsafe fn active() -> ManuallyDrop<Self> {
@@ -36,6 +36,15 @@ pub fn is_timer_intr(trap: usize) -> bool {
     }
 }
 
+// uprobes are not supported on mips
+pub fn is_breakpoint(_trap: usize) -> bool {
+    false
+}
+
+pub fn is_single_step(_trap: usize) -> bool {
+    false
+}
+
 pub fn is_reserved_inst(trap: usize) -> bool {
     use cp0::cause::Exception as E;
     let cause = cp0::cause::Cause { bits: trap as u32 };
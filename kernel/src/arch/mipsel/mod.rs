@@ -46,6 +46,7 @@ pub extern "C" fn rust_main() -> ! {
     info!("Hello MIPS 32 from CPU {}, dtb @ {:#x}", cpu_id, dtb_start);
 
     //crate::drivers::init(dtb_start);
+    crate::net::init();
     crate::process::init();
 
     // TODO: start other CPU
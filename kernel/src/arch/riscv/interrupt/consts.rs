@@ -1,3 +1,4 @@
+pub const Breakpoint: usize = 3;
 pub const Syscall: usize = 8;
 pub const InstructionPageFault: usize = 12;
 pub const LoadPageFault: usize = 13;
@@ -27,6 +28,15 @@ pub fn is_syscall(trap: usize) -> bool {
     trap == Syscall
 }
 
+pub fn is_breakpoint(trap: usize) -> bool {
+    trap == Breakpoint
+}
+
+// there is no single-step on riscv
+pub fn is_single_step(_trap: usize) -> bool {
+    false
+}
+
 pub fn is_intr(trap: usize) -> bool {
     IrqMin <= trap && trap <= IrqMax
 }
@@ -51,13 +51,20 @@ pub fn trap_handler_no_frame(tf: &mut TrapFrame) {
     match scause.cause() {
         Trap::Interrupt(I::SupervisorExternal) => external(),
         Trap::Interrupt(I::SupervisorSoft) => ipi(),
-        Trap::Interrupt(I::SupervisorTimer) => timer(),
+        Trap::Interrupt(I::SupervisorTimer) => {
+            crate::perf::kernel_tick(tf.sepc, tf.general.s0);
+            timer()
+        }
         Trap::Exception(E::LoadPageFault) => page_fault(stval, &mut tf.sepc, AccessType::read(is_user)),
         Trap::Exception(E::StorePageFault) => page_fault(stval, &mut tf.sepc, AccessType::write(is_user)),
         Trap::Exception(E::InstructionPageFault) => {
             page_fault(stval, &mut tf.sepc, AccessType::execute(is_user))
         },
-        Trap::Exception(E::Breakpoint) => breakpoint_handler(tf),
+        Trap::Exception(E::Breakpoint) => {
+            if !breakpoint_handler(tf) {
+                panic!("unhandled breakpoint @ {:#x}", tf.sepc);
+            }
+        }
         _ => {
             let bits = scause.bits();
             panic!("unhandled trap {:?} ({})", scause.cause(), bits);
@@ -76,6 +76,7 @@ pub extern "C" fn rust_main(hartid: usize, device_tree_paddr: usize) -> ! {
     unsafe {
         board::init_external_interrupt();
     }
+    crate::net::init();
     crate::process::init();
     info!(
         "Hello RISCV! in hart {}, device tree @ {:#x}",
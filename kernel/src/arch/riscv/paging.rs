@@ -3,6 +3,8 @@ use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt};
 use core::mem::ManuallyDrop;
 use log::*;
 use rcore_memory::paging::*;
+#[cfg(target_arch = "riscv64")]
+use rcore_memory::{GIANT_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE};
 use riscv::addr::*;
 use riscv::asm::{sfence_vma, sfence_vma_all};
 use riscv::paging::MapperFlushable;
@@ -55,6 +57,13 @@ impl PageTable for PageTableImpl {
 
     fn get_entry(&mut self, vaddr: usize) -> Option<&mut dyn Entry> {
         let page = Page::of_addr(VirtAddr::new(vaddr));
+        #[cfg(target_arch = "riscv64")]
+        if let Some((e, size)) = self.leaf_entry(vaddr) {
+            if size != PAGE_SIZE {
+                self.entry = Some(PageEntry(e, page));
+                return Some(self.entry.as_mut().unwrap());
+            }
+        }
         if let Ok(e) = self.page_table.ref_entry(page.clone()) {
             let e = unsafe { &mut *(e as *mut PageTableEntry) };
             self.entry = Some(PageEntry(e, page));
@@ -65,6 +74,15 @@ impl PageTable for PageTableImpl {
     }
 
     fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
+        #[cfg(target_arch = "riscv64")]
+        if let Some((e, size)) = self.leaf_entry(addr) {
+            if size != PAGE_SIZE {
+                let paddr =
+                    e.addr::<PhysAddr>().as_usize() + (addr & (size - 1) & !(PAGE_SIZE - 1));
+                let vaddr = paddr + PHYSICAL_MEMORY_OFFSET;
+                return unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, 0x1000) };
+            }
+        }
         let frame = self
             .page_table
             .translate_page(Page::of_addr(VirtAddr::new(addr)))
@@ -74,6 +92,65 @@ impl PageTable for PageTableImpl {
     }
 
     fn flush_cache_copy_user(&mut self, _start: usize, _end: usize, _execute: bool) {}
+
+    #[cfg(target_arch = "riscv64")]
+    fn map_huge(&mut self, addr: usize, target: usize, size: usize) -> Option<&mut dyn Entry> {
+        let depth = match size {
+            GIANT_PAGE_SIZE => 0,
+            HUGE_PAGE_SIZE => 1,
+            _ => return None,
+        };
+        let mut table = self.root_table();
+        for level in 0..depth {
+            let entry = &mut table[(addr >> (30 - level * 9)) & 0o777];
+            if entry.is_unused() {
+                let frame = alloc_frame().expect("failed to allocate frame");
+                unsafe { (&mut *(phys_to_virt(frame) as *mut RvPageTable)).zero() };
+                entry.set(Frame::of_addr(PhysAddr::new_u64(frame as u64)), EF::VALID);
+            } else if is_leaf(entry) {
+                return None;
+            }
+            table = unsafe {
+                &mut *(phys_to_virt(entry.addr::<PhysAddr>().as_usize()) as *mut RvPageTable)
+            };
+        }
+        let entry = &mut table[(addr >> (30 - depth * 9)) & 0o777];
+        if !entry.is_unused() {
+            // already used by a page table of smaller pages
+            return None;
+        }
+        let flags = EF::VALID | EF::READABLE | EF::WRITABLE;
+        entry.set(Frame::of_addr(PhysAddr::new_u64(target as u64)), flags);
+        unsafe {
+            sfence_vma(0, addr);
+        }
+        let e = unsafe { &mut *(entry as *mut PageTableEntry) };
+        self.entry = Some(PageEntry(e, Page::of_addr(VirtAddr::new(addr))));
+        Some(self.entry.as_mut().unwrap())
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn unmap_huge(&mut self, addr: usize, size: usize) {
+        let (entry, entry_size) = self.leaf_entry(addr).unwrap();
+        assert_eq!(size, entry_size, "not a huge page of this size");
+        entry.set_unused();
+        unsafe {
+            sfence_vma(0, addr);
+        }
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn page_size(&mut self, addr: usize) -> usize {
+        self.leaf_entry(addr).map_or(PAGE_SIZE, |(_, size)| size)
+    }
+}
+
+/// Whether the entry maps a page rather than pointing to the next level page table
+#[cfg(target_arch = "riscv64")]
+fn is_leaf(entry: &PageTableEntry) -> bool {
+    entry
+        .flags()
+        .intersects(EF::READABLE | EF::WRITABLE | EF::EXECUTABLE)
 }
 
 /// implementation for the Entry trait in /crate/memory/src/paging/mod.rs
@@ -164,6 +241,37 @@ fn get_kernel_page_table_frame() -> Frame {
 }
 
 impl PageTableImpl {
+    #[cfg(target_arch = "riscv64")]
+    fn root_table(&self) -> &'static mut RvPageTable {
+        unsafe {
+            &mut *(phys_to_virt(self.root_frame.start_address().as_usize()) as *mut RvPageTable)
+        }
+    }
+
+    /// Walk the Sv39 page table to the last level entry of `addr`, which may be a huge page.
+    /// Return the entry and the size of the page it maps.
+    #[cfg(target_arch = "riscv64")]
+    fn leaf_entry(&self, addr: usize) -> Option<(&'static mut PageTableEntry, usize)> {
+        let mut table = self.root_table();
+        for level in 0..3 {
+            let entry = &mut table[(addr >> (30 - level * 9)) & 0o777];
+            let size = 1 << (30 - level * 9);
+            if level == 2 {
+                return Some((entry, size));
+            }
+            if !entry.flags().contains(EF::VALID) {
+                return None;
+            }
+            if is_leaf(entry) {
+                return Some((entry, size));
+            }
+            table = unsafe {
+                &mut *(phys_to_virt(entry.addr::<PhysAddr>().as_usize()) as *mut RvPageTable)
+            };
+        }
+        unreachable!();
+    }
+
     /// Unsafely get the current active page table.
     /// Using ManuallyDrop to wrap the page table: this is how `core::mem::forget` is implemented now.
     pub unsafe fn active() -> ManuallyDrop<Self> {
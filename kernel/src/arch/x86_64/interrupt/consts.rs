@@ -54,6 +54,14 @@ pub fn is_syscall(trap: usize) -> bool {
     trap == Syscall
 }
 
+pub fn is_breakpoint(trap: usize) -> bool {
+    trap == Breakpoint
+}
+
+pub fn is_single_step(trap: usize) -> bool {
+    trap == Debug
+}
+
 pub fn is_intr(trap: usize) -> bool {
     IrqMin <= trap && trap <= IrqMax
 }
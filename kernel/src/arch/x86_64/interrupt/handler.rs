@@ -82,6 +82,8 @@ pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
 
     // Dispatch
     match tf.trap_num {
+        Debug => debug(tf),
+        Breakpoint => breakpoint(tf),
         DoubleFault => double_fault(tf),
         PageFault => page_fault(tf),
         IrqMin..=IrqMax => {
@@ -89,6 +91,7 @@ pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
             super::ack(irq); // must ack before switching
             match tf.trap_num {
                 Timer => {
+                    crate::perf::kernel_tick(tf.rip, tf.rbp);
                     crate::trap::timer();
                 }
                 _ => {
@@ -109,6 +112,20 @@ pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
     }
 }
 
+fn debug(tf: &mut TrapFrame) {
+    if !crate::kprobes::single_step_handler(tf) {
+        panic!("\nEXCEPTION: Debug @ {:#x}", tf.rip);
+    }
+}
+
+fn breakpoint(tf: &mut TrapFrame) {
+    // int3 traps with rip after itself, kprobes want its own address
+    tf.rip -= 1;
+    if !crate::kprobes::breakpoint_handler(tf) {
+        panic!("\nEXCEPTION: Breakpoint @ {:#x}", tf.rip);
+    }
+}
+
 fn double_fault(tf: &TrapFrame) {
     error!("\nEXCEPTION: Double Fault\n{:#x?}", tf);
     loop {}
@@ -1,6 +1,5 @@
 use super::paging::PageTableImpl;
 use crate::memory::FRAME_ALLOCATOR;
-use bitmap_allocator::BitAlloc;
 use rboot::{BootInfo, MemoryType};
 use rcore_memory::paging::*;
 use rcore_memory::PAGE_SIZE;
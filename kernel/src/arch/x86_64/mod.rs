@@ -64,6 +64,8 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     crate::lkm::manager::ModuleManager::init();
     // init board
     board::init(boot_info);
+    // init software network interfaces after NICs
+    crate::net::init();
     // init cpu scheduler and process manager, and add user shell app in process manager
     crate::process::init();
     // load acpi
@@ -2,7 +2,9 @@ use super::consts::*;
 use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt};
 use core::mem::ManuallyDrop;
 use log::*;
+use raw_cpuid::CpuId;
 use rcore_memory::paging::*;
+use rcore_memory::{GIANT_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE};
 use x86_64::instructions::tlb;
 use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::paging::{
@@ -70,30 +72,65 @@ impl PageTable for PageTableImpl {
     }
 
     fn get_entry(&mut self, addr: usize) -> Option<&mut dyn Entry> {
+        let (entry, _) = self.leaf_entry(addr)?;
+        let page = Page::of_addr(addr);
+        self.1 = Some(PageEntry(entry, page, self.2));
+        Some(self.1.as_mut().unwrap())
+    }
+
+    fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
+        let (entry, size) = self.leaf_entry(addr).unwrap();
+        let paddr = entry.addr().as_u64() as usize + (addr & (size - 1) & !(PAGE_SIZE - 1));
+        let vaddr = phys_to_virt(paddr);
+        unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, 0x1000) }
+    }
+
+    fn flush_cache_copy_user(&mut self, _start: usize, _end: usize, _execute: bool) {}
+
+    fn map_huge(&mut self, addr: usize, target: usize, size: usize) -> Option<&mut dyn Entry> {
+        let depth = match size {
+            HUGE_PAGE_SIZE => 2,
+            GIANT_PAGE_SIZE if has_giant_page() => 1,
+            _ => return None,
+        };
         let mut page_table = frame_to_page_table(self.2);
-        for level in 0..4 {
+        for level in 0..depth {
             let index = (addr >> (12 + (3 - level) * 9)) & 0o777;
             let entry = unsafe { &mut (&mut *page_table)[index] };
-            if level == 3 {
-                let page = Page::of_addr(addr);
-                self.1 = Some(PageEntry(entry, page, self.2));
-                return Some(self.1.as_mut().unwrap());
-            }
-            if !entry.flags().contains(EF::PRESENT) {
+            if entry.is_unused() {
+                let frame = alloc_frame().expect("failed to allocate frame");
+                unsafe { (&mut *frame_to_page_table(Frame::of_addr(frame))).zero() };
+                entry.set_addr(PhysAddr::new(frame as u64), EF::PRESENT | EF::WRITABLE);
+            } else if entry.flags().contains(EF::HUGE_PAGE) {
                 return None;
             }
             page_table = frame_to_page_table(entry.frame().unwrap());
         }
-        unreachable!();
+        let index = (addr >> (12 + (3 - depth) * 9)) & 0o777;
+        let entry = unsafe { &mut (&mut *page_table)[index] };
+        if !entry.is_unused() {
+            // already used by a page table of smaller pages
+            return None;
+        }
+        let flags = EF::PRESENT | EF::WRITABLE | EF::NO_EXECUTE | EF::HUGE_PAGE;
+        entry.set_addr(PhysAddr::new(target as u64), flags);
+        tlb::flush(VirtAddr::new(addr as u64));
+        flush_tlb_all(addr);
+        self.1 = Some(PageEntry(entry, Page::of_addr(addr), self.2));
+        Some(self.1.as_mut().unwrap())
     }
 
-    fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
-        let frame = self.0.translate_page(Page::of_addr(addr)).unwrap();
-        let vaddr = phys_to_virt(frame.start_address().as_u64() as usize);
-        unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, 0x1000) }
+    fn unmap_huge(&mut self, addr: usize, size: usize) {
+        let (entry, entry_size) = self.leaf_entry(addr).unwrap();
+        assert_eq!(size, entry_size, "not a huge page of this size");
+        entry.set_unused();
+        tlb::flush(VirtAddr::new(addr as u64));
+        flush_tlb_all(addr);
     }
 
-    fn flush_cache_copy_user(&mut self, _start: usize, _end: usize, _execute: bool) {}
+    fn page_size(&mut self, addr: usize) -> usize {
+        self.leaf_entry(addr).map_or(PAGE_SIZE, |(_, size)| size)
+    }
 }
 
 fn frame_to_page_table(frame: Frame) -> *mut x86PageTable {
@@ -171,7 +208,7 @@ impl Entry for PageEntry {
                     (self.1.start_address().as_u64() as usize >> (12 + (3 - level) * 9)) & 0o777;
                 let entry = unsafe { &mut (&mut *page_table)[index] };
                 entry.set_flags(entry.flags() | EF::USER_ACCESSIBLE);
-                if level == 3 {
+                if level == 3 || entry.flags().contains(EF::HUGE_PAGE) {
                     return;
                 }
                 page_table = frame_to_page_table(entry.frame().unwrap());
@@ -197,6 +234,28 @@ impl PageEntry {
 }
 
 impl PageTableImpl {
+    /// Walk the page table to the last level entry of `addr`, which may be a huge page.
+    /// Return the entry and the size of the page it maps.
+    fn leaf_entry(&self, addr: usize) -> Option<(&'static mut PageTableEntry, usize)> {
+        let mut page_table = frame_to_page_table(self.2);
+        for level in 0..4 {
+            let index = (addr >> (12 + (3 - level) * 9)) & 0o777;
+            let entry = unsafe { &mut (&mut *page_table)[index] };
+            let size = 1 << (12 + (3 - level) * 9);
+            if level == 3 {
+                return Some((entry, size));
+            }
+            if !entry.flags().contains(EF::PRESENT) {
+                return None;
+            }
+            if level > 0 && entry.flags().contains(EF::HUGE_PAGE) {
+                return Some((entry, size));
+            }
+            page_table = frame_to_page_table(entry.frame().unwrap());
+        }
+        unreachable!();
+    }
+
     /// Unsafely get the current active page table.
     /// Using ManuallyDrop to wrap the page table: this is how `core::mem::forget` is implemented now.
     pub unsafe fn active() -> ManuallyDrop<Self> {
@@ -282,6 +341,13 @@ impl FrameDeallocator<Size4KiB> for FrameAllocatorForX86 {
     }
 }
 
+/// Whether 1GiB pages are supported by the CPU
+fn has_giant_page() -> bool {
+    CpuId::new()
+        .get_extended_function_info()
+        .map_or(false, |info| info.has_1gib_pages())
+}
+
 /// Flush TLB for `vaddr` on all CPU
 fn flush_tlb_all(_vaddr: usize) {
     // TODO: too slow, disable now.
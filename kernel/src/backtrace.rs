@@ -59,6 +59,133 @@ pub fn lr() -> usize {
     ptr
 }
 
+/// Returns the return address `depth` frames above the caller,
+/// or 0 if the frame chain ends before that.
+#[inline(always)]
+#[cfg(not(target_arch = "mips"))]
+pub fn caller(depth: usize) -> usize {
+    use crate::consts::PHYSICAL_MEMORY_OFFSET;
+    let mut current_fp = fp();
+    let mut pc = 0;
+    for _ in 0..=depth {
+        if current_fp < PHYSICAL_MEMORY_OFFSET || current_fp % size_of::<usize>() != 0 {
+            return 0;
+        }
+        let (prev_fp, ret) = unsafe { unwind(current_fp) };
+        current_fp = prev_fp;
+        pc = ret;
+    }
+    pc
+}
+
+/// MIPS frames can only be walked by decoding prologues, see `backtrace`
+#[cfg(target_arch = "mips")]
+pub fn caller(_depth: usize) -> usize {
+    0
+}
+
+/// Fills `buf` with the return addresses of the calling frames, innermost first,
+/// after skipping `skip` of them. Returns the number of addresses written.
+#[inline(always)]
+#[cfg(not(target_arch = "mips"))]
+pub fn callers(skip: usize, buf: &mut [usize]) -> usize {
+    walk(fp(), skip, buf, kernel_unwind)
+}
+
+/// Like `callers`, but from the frame pointer `fp` of an interrupted
+/// kernel context, e.g. the one saved in a trap frame.
+#[cfg(not(target_arch = "mips"))]
+pub fn callers_from(fp: usize, buf: &mut [usize]) -> usize {
+    walk(fp, 0, buf, kernel_unwind)
+}
+
+/// Like `callers_from`, but for user frames in the current address space.
+/// The walk stops at the first frame that cannot be read.
+#[cfg(not(target_arch = "mips"))]
+pub fn user_callers(fp: usize, buf: &mut [usize]) -> usize {
+    walk(fp, 0, buf, |fp| {
+        #[cfg(riscv)]
+        let (prev_fp, ret) = (fp.wrapping_sub(16), fp.wrapping_sub(8));
+        #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+        let (prev_fp, ret) = (fp, fp + size_of::<usize>());
+        let prev_fp = crate::memory::copy_from_user(prev_fp as *const usize)?;
+        let ret = crate::memory::copy_from_user(ret as *const usize)?;
+        Some((prev_fp, ret))
+    })
+}
+
+#[cfg(target_arch = "mips")]
+pub fn callers(_skip: usize, _buf: &mut [usize]) -> usize {
+    0
+}
+
+#[cfg(target_arch = "mips")]
+pub fn callers_from(_fp: usize, _buf: &mut [usize]) -> usize {
+    0
+}
+
+#[cfg(target_arch = "mips")]
+pub fn user_callers(_fp: usize, _buf: &mut [usize]) -> usize {
+    0
+}
+
+/// Follow the frame chain from `fp` with `unwind`, see `callers`
+#[cfg(not(target_arch = "mips"))]
+fn walk(
+    mut current_fp: usize,
+    skip: usize,
+    buf: &mut [usize],
+    unwind: impl Fn(usize) -> Option<(usize, usize)>,
+) -> usize {
+    let mut depth = 0;
+    let mut count = 0;
+    while count < buf.len() {
+        if current_fp == 0 || current_fp % size_of::<usize>() != 0 {
+            break;
+        }
+        let (prev_fp, ret) = match unwind(current_fp) {
+            Some((prev_fp, ret)) if ret != 0 => (prev_fp, ret),
+            _ => break,
+        };
+        if depth >= skip {
+            buf[count] = ret;
+            count += 1;
+        }
+        depth += 1;
+        // stacks grow downwards, anything else is a broken chain
+        if prev_fp <= current_fp {
+            break;
+        }
+        current_fp = prev_fp;
+    }
+    count
+}
+
+#[cfg(not(target_arch = "mips"))]
+fn kernel_unwind(fp: usize) -> Option<(usize, usize)> {
+    use crate::consts::PHYSICAL_MEMORY_OFFSET;
+    if fp < PHYSICAL_MEMORY_OFFSET {
+        return None;
+    }
+    Some(unsafe { unwind(fp) })
+}
+
+/// Returns the previous frame pointer and the return address stored in frame `fp`
+#[inline(always)]
+#[cfg(not(target_arch = "mips"))]
+unsafe fn unwind(fp: usize) -> (usize, usize) {
+    #[cfg(riscv)]
+    {
+        let fp = fp as *const usize;
+        (*fp.offset(-2), *fp.offset(-1))
+    }
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+    {
+        let fp = fp as *const usize;
+        (*fp, *fp.offset(1))
+    }
+}
+
 use alloc::vec::Vec;
 use alloc::string::String;
 fn find_symbol(symbols: &Vec<(String, usize)>, pc: usize) -> Option<(&str, usize)> {
@@ -90,6 +217,13 @@ fn find_symbol(symbols: &Vec<(String, usize)>, pc: usize) -> Option<(&str, usize
     Some((&entry.0, pc - entry.1))
 }
 
+/// Returns the kernel symbol containing `pc` and the offset of `pc` in it
+pub fn symbol(pc: usize) -> Option<(String, usize)> {
+    crate::lkm::manager::ModuleManager::with(|mm| {
+        find_symbol(mm.get_kernel_symbols(), pc).map(|(name, offset)| (String::from(name), offset))
+    })
+}
+
 // Print the backtrace starting from the caller
 pub fn backtrace() {
     unsafe {
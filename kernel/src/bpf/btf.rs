@@ -0,0 +1,704 @@
+//! BTF, the type information of kernel structures
+//!
+//! build.rs generates `kernel_structs` from the definitions of the structures
+//! in its `BTF_STRUCTS`, the compiler gives their layouts. The encoded types
+//! are read from /sys/kernel/btf/vmlinux, and programs of BPF_PROG_LOAD_EX
+//! compiled with CO-RE relocations get the field offsets of this kernel.
+//!
+//! See https://www.kernel.org/doc/html/latest/bpf/btf.html
+//! and https://nakryiko.com/posts/bpf-core-reference-guide/
+
+use crate::sync::{Mutex, MutexSupport};
+use crate::syscall::SysError::{self, *};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem::{align_of, discriminant, size_of, MaybeUninit};
+use core::ptr::{addr_of, null};
+use lazy_static::lazy_static;
+
+use super::consts::*;
+use super::insn::BpfInsn;
+
+const BTF_MAGIC: u16 = 0xeb9f;
+const BTF_VERSION: u8 = 1;
+
+// kinds of types
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+// encodings of integers
+const BTF_INT_SIGNED: u32 = 1 << 0;
+const BTF_INT_BOOL: u32 = 1 << 2;
+
+// kinds of CO-RE relocations
+const BPF_CORE_FIELD_BYTE_OFFSET: u32 = 0;
+const BPF_CORE_FIELD_BYTE_SIZE: u32 = 1;
+const BPF_CORE_FIELD_EXISTS: u32 = 2;
+const BPF_CORE_TYPE_EXISTS: u32 = 8;
+const BPF_CORE_TYPE_SIZE: u32 = 9;
+
+/// A type of the kernel, as the generated code describes it
+pub enum KernelType {
+    Int {
+        size: usize,
+        signed: bool,
+    },
+    Bool,
+    Ptr(Box<KernelType>),
+    /// Structures without members are opaque
+    Struct {
+        name: &'static str,
+        size: usize,
+        members: Vec<KernelMember>,
+    },
+    /// One of the structures of `kernel_structs`, by name
+    Described(&'static str),
+}
+
+pub struct KernelMember {
+    name: &'static str,
+    offset: usize,
+    ty: KernelType,
+}
+
+// Helpers of the generated code. Pointers to members only carry their types,
+// they are never read.
+
+fn btf_struct<T>(
+    name: &'static str,
+    members: impl FnOnce(*const T) -> Vec<KernelMember>,
+) -> KernelType {
+    let base = MaybeUninit::<T>::uninit();
+    KernelType::Struct {
+        name,
+        size: size_of::<T>(),
+        members: members(base.as_ptr()),
+    }
+}
+
+fn member<S, T>(
+    name: &'static str,
+    base: *const S,
+    field: *const T,
+    ty: impl FnOnce(*const T) -> KernelType,
+) -> KernelMember {
+    KernelMember {
+        name,
+        offset: field as usize - base as usize,
+        ty: ty(field),
+    }
+}
+
+fn int<T>(_: *const T, signed: bool) -> KernelType {
+    KernelType::Int {
+        size: size_of::<T>(),
+        signed,
+    }
+}
+
+fn boolean(_: *const bool) -> KernelType {
+    KernelType::Bool
+}
+
+fn opaque<T>(_: *const T, name: &'static str) -> KernelType {
+    KernelType::Struct {
+        name,
+        size: size_of::<T>(),
+        members: Vec::new(),
+    }
+}
+
+fn described<T>(_: *const T, name: &'static str) -> KernelType {
+    KernelType::Described(name)
+}
+
+/// Pointer to `ArcInner<T>`, which is `#[repr(C)]` with the counts first
+fn arc_inner<T>(name: &'static str, data: impl FnOnce(*const T) -> KernelType) -> KernelType {
+    let usize_type = || KernelType::Int {
+        size: size_of::<usize>(),
+        signed: false,
+    };
+    let align = |size: usize, align: usize| (size + align - 1) / align * align;
+    let offset = align(2 * size_of::<usize>(), align_of::<T>());
+    let size = align(
+        offset + size_of::<T>(),
+        align_of::<usize>().max(align_of::<T>()),
+    );
+    let members = vec![
+        KernelMember {
+            name: "strong",
+            offset: 0,
+            ty: usize_type(),
+        },
+        KernelMember {
+            name: "weak",
+            offset: size_of::<usize>(),
+            ty: usize_type(),
+        },
+        KernelMember {
+            name: "data",
+            offset,
+            ty: data(null()),
+        },
+    ];
+    KernelType::Ptr(Box::new(KernelType::Struct {
+        name,
+        size,
+        members,
+    }))
+}
+
+fn arc<T>(
+    _: *const Arc<T>,
+    name: &'static str,
+    data: impl FnOnce(*const T) -> KernelType,
+) -> KernelType {
+    arc_inner(name, data)
+}
+
+fn weak<T>(
+    _: *const Weak<T>,
+    name: &'static str,
+    data: impl FnOnce(*const T) -> KernelType,
+) -> KernelType {
+    arc_inner(name, data)
+}
+
+fn mutex<T, S: MutexSupport>(
+    _: *const Mutex<T, S>,
+    name: &'static str,
+    data: impl FnOnce(*const T) -> KernelType,
+) -> KernelType {
+    KernelType::Struct {
+        name,
+        size: size_of::<Mutex<T, S>>(),
+        members: vec![KernelMember {
+            name: "data",
+            offset: Mutex::<T, S>::data_offset(),
+            ty: data(null()),
+        }],
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/btf_structs.rs"));
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Void,
+    /// The word after the type, with the encoding and bits
+    Int(u32),
+    Ptr(u32),
+    Array {
+        elem: u32,
+        nelems: u32,
+    },
+    Struct(Vec<Member>),
+    Union(Vec<Member>),
+    /// Typedefs and modifiers, which relocations look through
+    Alias(u32),
+    /// Types relocations do not handle
+    Other,
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    name: String,
+    ty: u32,
+    /// In bits
+    offset: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Type {
+    name: String,
+    size: u32,
+    kind: Kind,
+}
+
+/// Types by their ids, 0 is void
+pub struct Btf {
+    types: Vec<Type>,
+}
+
+lazy_static! {
+    static ref KERNEL_BTF: Btf = Btf::kernel();
+}
+
+/// The encoded kernel BTF, content of /sys/kernel/btf/vmlinux
+pub fn vmlinux() -> Vec<u8> {
+    KERNEL_BTF.encode()
+}
+
+/// Builds the kernel BTF, structures are named uniquely
+struct Builder {
+    btf: Btf,
+    ids: BTreeMap<&'static str, u32>,
+    /// Described structures with ids but not members yet
+    pending: BTreeSet<&'static str>,
+}
+
+impl Builder {
+    fn push(&mut self, name: &str, size: usize, kind: Kind) -> u32 {
+        self.btf.types.push(Type {
+            name: String::from(name),
+            size: size as u32,
+            kind,
+        });
+        (self.btf.types.len() - 1) as u32
+    }
+
+    fn add(&mut self, ty: KernelType) -> u32 {
+        match ty {
+            KernelType::Int { size, signed } => {
+                let (name, encoding) = match (size, signed) {
+                    (1, false) => ("u8", 0),
+                    (2, false) => ("u16", 0),
+                    (4, false) => ("u32", 0),
+                    (8, false) => ("u64", 0),
+                    (1, true) => ("i8", BTF_INT_SIGNED),
+                    (2, true) => ("i16", BTF_INT_SIGNED),
+                    (4, true) => ("i32", BTF_INT_SIGNED),
+                    _ => ("i64", BTF_INT_SIGNED),
+                };
+                self.add_int(name, size, encoding)
+            }
+            KernelType::Bool => self.add_int("bool", 1, BTF_INT_BOOL),
+            KernelType::Ptr(target) => {
+                let target = self.add(*target);
+                self.push("", size_of::<usize>(), Kind::Ptr(target))
+            }
+            KernelType::Struct {
+                name,
+                size,
+                members,
+            } => {
+                let id = match self.ids.get(name) {
+                    Some(&id) if !self.pending.remove(name) => return id,
+                    Some(&id) => id,
+                    None => {
+                        let id = self.push(name, size, Kind::Struct(Vec::new()));
+                        self.ids.insert(name, id);
+                        id
+                    }
+                };
+                let members = members
+                    .into_iter()
+                    .map(|member| Member {
+                        name: String::from(member.name),
+                        offset: member.offset as u32 * 8,
+                        ty: self.add(member.ty),
+                    })
+                    .collect();
+                self.btf.types[id as usize].kind = Kind::Struct(members);
+                id
+            }
+            KernelType::Described(name) => self.ids[name],
+        }
+    }
+
+    fn add_int(&mut self, name: &'static str, size: usize, encoding: u32) -> u32 {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let bits = size as u32 * 8;
+        let id = self.push(name, size, Kind::Int(encoding << 24 | bits));
+        self.ids.insert(name, id);
+        id
+    }
+}
+
+impl Btf {
+    fn kernel() -> Self {
+        let void = Type {
+            name: String::new(),
+            size: 0,
+            kind: Kind::Void,
+        };
+        let mut builder = Builder {
+            btf: Btf { types: vec![void] },
+            ids: BTreeMap::new(),
+            pending: BTreeSet::new(),
+        };
+        let structs = kernel_structs();
+        // described structures refer to each other, so they come first
+        for ty in structs.iter() {
+            if let KernelType::Struct { name, size, .. } = *ty {
+                let id = builder.push(name, size, Kind::Struct(Vec::new()));
+                builder.ids.insert(name, id);
+                builder.pending.insert(name);
+            }
+        }
+        for ty in structs {
+            builder.add(ty);
+        }
+        builder.btf
+    }
+
+    /// Encode the types in the format of Linux
+    fn encode(&self) -> Vec<u8> {
+        let mut strings = vec![0u8];
+        let mut offsets = BTreeMap::new();
+        let mut name_off = |name: &str| -> u32 {
+            if name.is_empty() {
+                return 0;
+            }
+            *offsets.entry(String::from(name)).or_insert_with(|| {
+                let offset = strings.len() as u32;
+                strings.extend_from_slice(name.as_bytes());
+                strings.push(0);
+                offset
+            })
+        };
+
+        let mut types = Vec::new();
+        let put = |types: &mut Vec<u8>, value: u32| types.extend_from_slice(&value.to_le_bytes());
+        for ty in self.types.iter().skip(1) {
+            let name = name_off(&ty.name);
+            match &ty.kind {
+                Kind::Int(encoding) => {
+                    put(&mut types, name);
+                    put(&mut types, BTF_KIND_INT << 24);
+                    put(&mut types, ty.size);
+                    put(&mut types, *encoding);
+                }
+                Kind::Ptr(target) => {
+                    put(&mut types, name);
+                    put(&mut types, BTF_KIND_PTR << 24);
+                    put(&mut types, *target);
+                }
+                Kind::Struct(members) => {
+                    put(&mut types, name);
+                    put(&mut types, BTF_KIND_STRUCT << 24 | members.len() as u32);
+                    put(&mut types, ty.size);
+                    for member in members {
+                        put(&mut types, name_off(&member.name));
+                        put(&mut types, member.ty);
+                        put(&mut types, member.offset);
+                    }
+                }
+                // the kernel has no other kinds
+                _ => unreachable!(),
+            }
+        }
+
+        let hdr_len = 24;
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+        blob.push(BTF_VERSION);
+        blob.push(0); // flags
+        for value in [
+            hdr_len,
+            0, // type_off
+            types.len() as u32,
+            types.len() as u32, // str_off
+            strings.len() as u32,
+        ] {
+            blob.extend_from_slice(&value.to_le_bytes());
+        }
+        blob.extend_from_slice(&types);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+
+    /// Parse the BTF of a program, its `.BTF` section
+    fn parse(data: &[u8]) -> Option<Self> {
+        if read_u16(data, 0)? != BTF_MAGIC || *data.get(2)? != BTF_VERSION {
+            return None;
+        }
+        let hdr_len = read_u32(data, 4)? as usize;
+        let section = |off: usize| -> Option<&[u8]> {
+            let start = hdr_len.checked_add(read_u32(data, off)? as usize)?;
+            let len = read_u32(data, off + 4)? as usize;
+            data.get(start..start.checked_add(len)?)
+        };
+        let (types, strings) = (section(8)?, section(16)?);
+        let string = |off: u32| -> Option<String> {
+            let rest = strings.get(off as usize..)?;
+            let len = rest.iter().position(|&c| c == 0)?;
+            core::str::from_utf8(&rest[..len]).ok().map(String::from)
+        };
+
+        let void = Type {
+            name: String::new(),
+            size: 0,
+            kind: Kind::Void,
+        };
+        let mut btf = Btf { types: vec![void] };
+        let mut pos = 0;
+        while pos < types.len() {
+            let name = string(read_u32(types, pos)?)?;
+            let info = read_u32(types, pos + 4)?;
+            let size = read_u32(types, pos + 8)?;
+            let (kind, vlen, kind_flag) = ((info >> 24) & 0x1f, info & 0xffff, info >> 31);
+            pos += 12;
+            let members = |pos: usize| -> Option<Vec<Member>> {
+                (0..vlen as usize)
+                    .map(|i| {
+                        let pos = pos + i * 12;
+                        let offset = read_u32(types, pos + 8)?;
+                        Some(Member {
+                            name: string(read_u32(types, pos)?)?,
+                            ty: read_u32(types, pos + 4)?,
+                            // the bitfield size is in the high bits then
+                            offset: if kind_flag != 0 {
+                                offset & 0xffffff
+                            } else {
+                                offset
+                            },
+                        })
+                    })
+                    .collect()
+            };
+            let (kind, extra) = match kind {
+                BTF_KIND_INT => (Kind::Int(read_u32(types, pos)?), 4),
+                BTF_KIND_PTR => (Kind::Ptr(size), 0),
+                BTF_KIND_ARRAY => {
+                    let elem = read_u32(types, pos)?;
+                    let nelems = read_u32(types, pos + 8)?;
+                    (Kind::Array { elem, nelems }, 12)
+                }
+                BTF_KIND_STRUCT => (Kind::Struct(members(pos)?), vlen * 12),
+                BTF_KIND_UNION => (Kind::Union(members(pos)?), vlen * 12),
+                BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT
+                | BTF_KIND_TYPE_TAG => (Kind::Alias(size), 0),
+                BTF_KIND_ENUM | BTF_KIND_FUNC_PROTO => (Kind::Other, vlen * 8),
+                BTF_KIND_VAR | BTF_KIND_DECL_TAG => (Kind::Other, 4),
+                BTF_KIND_DATASEC | BTF_KIND_ENUM64 => (Kind::Other, vlen * 12),
+                _ => (Kind::Other, 0),
+            };
+            pos += extra as usize;
+            let size = match kind {
+                // pointers of eBPF are 64-bit
+                Kind::Ptr(_) => 8,
+                Kind::Alias(_) => 0,
+                _ => size,
+            };
+            btf.types.push(Type { name, size, kind });
+        }
+        Some(btf)
+    }
+
+    /// The type of `id` without typedefs and modifiers
+    fn resolve(&self, mut id: u32) -> Option<(u32, &Type)> {
+        // bound the walk, types may refer to each other
+        for _ in 0..32 {
+            let ty = self.types.get(id as usize)?;
+            match ty.kind {
+                Kind::Alias(target) => id = target,
+                _ => return Some((id, ty)),
+            }
+        }
+        None
+    }
+
+    fn size_of(&self, id: u32) -> Option<u32> {
+        let (_, ty) = self.resolve(id)?;
+        match ty.kind {
+            Kind::Int(_) | Kind::Ptr(_) | Kind::Struct(_) | Kind::Union(_) => Some(ty.size),
+            Kind::Array { elem, nelems } => self.size_of(elem)?.checked_mul(nelems),
+            _ => None,
+        }
+    }
+
+    /// A type of the same name and kind as `ty`,
+    /// names may have a `___flavor` suffix
+    fn find(&self, ty: &Type) -> Option<u32> {
+        let essential = |name: &str| match name.find("___") {
+            Some(pos) => String::from(&name[..pos]),
+            None => String::from(name),
+        };
+        let name = essential(&ty.name);
+        if name.is_empty() {
+            return None;
+        }
+        self.types
+            .iter()
+            .position(|other| {
+                discriminant(&other.kind) == discriminant(&ty.kind) && other.name == name
+            })
+            .map(|id| id as u32)
+    }
+}
+
+/// The value of a CO-RE relocation of `kind` on local type `type_id`,
+/// found in `target`. `access` is like `0:1:2`, the index into the type
+/// followed by indices of members or array elements.
+fn core_value(local: &Btf, target: &Btf, type_id: u32, access: &str, kind: u32) -> Option<u32> {
+    let spec: Vec<u32> = access
+        .split(':')
+        .map(|index| index.parse().ok())
+        .collect::<Option<_>>()?;
+    let (local_id, local_type) = local.resolve(type_id)?;
+    let target_id = target.find(local_type)?;
+    match kind {
+        BPF_CORE_TYPE_EXISTS => return Some(1),
+        BPF_CORE_TYPE_SIZE => return target.size_of(target_id),
+        _ => (),
+    }
+
+    let mut offset = spec.first()?.checked_mul(target.size_of(target_id)?)?;
+    let (mut local_id, mut target_id) = (local_id, target_id);
+    for &index in &spec[1..] {
+        let (_, local_type) = local.resolve(local_id)?;
+        let (_, target_type) = target.resolve(target_id)?;
+        match (&local_type.kind, &target_type.kind) {
+            (
+                Kind::Struct(local_members) | Kind::Union(local_members),
+                Kind::Struct(target_members) | Kind::Union(target_members),
+            ) => {
+                let local_member = local_members.get(index as usize)?;
+                let target_member = target_members
+                    .iter()
+                    .find(|member| !member.name.is_empty() && member.name == local_member.name)?;
+                if target_member.offset % 8 != 0 {
+                    return None;
+                }
+                offset = offset.checked_add(target_member.offset / 8)?;
+                local_id = local_member.ty;
+                target_id = target_member.ty;
+            }
+            (
+                Kind::Array {
+                    elem: local_elem, ..
+                },
+                Kind::Array { elem, nelems },
+            ) => {
+                if index >= *nelems {
+                    return None;
+                }
+                offset = offset.checked_add(index.checked_mul(target.size_of(*elem)?)?)?;
+                local_id = *local_elem;
+                target_id = *elem;
+            }
+            _ => return None,
+        }
+    }
+    match kind {
+        BPF_CORE_FIELD_BYTE_OFFSET => Some(offset),
+        BPF_CORE_FIELD_BYTE_SIZE => target.size_of(target_id),
+        BPF_CORE_FIELD_EXISTS => Some(1),
+        _ => None,
+    }
+}
+
+/// Write `value` to the offset or the immediate of instruction `pc`
+fn patch(insns: &mut [u64], pc: usize, kind: u32, value: u32) -> Option<()> {
+    let insn = BpfInsn::decode(*insns.get(pc)?);
+    let raw = u64::from_le(insns[pc]);
+    let with_imm = |raw: u64, imm: u32| raw & 0xffff_ffff | (imm as u64) << 32;
+    let raw = match insn.class() {
+        BPF_LDX | BPF_ST | BPF_STX
+            if insn.mode() == BPF_MEM && kind == BPF_CORE_FIELD_BYTE_OFFSET =>
+        {
+            let off = i16::try_from(value).ok()?;
+            raw & !(0xffff << 16) | (off as u16 as u64) << 16
+        }
+        BPF_ALU | BPF_ALU64 if insn.source() == BPF_K => with_imm(raw, value),
+        BPF_LD if insn.is_ld_imm64() => {
+            let next = insns.get_mut(pc + 1)?;
+            *next = u64::to_le(with_imm(u64::from_le(*next), 0));
+            with_imm(raw, value)
+        }
+        _ => return None,
+    };
+    insns[pc] = u64::to_le(raw);
+    Some(())
+}
+
+/// Apply the CO-RE relocations of `section` to its `insns`. `btf` and `ext`
+/// are the `.BTF` and `.BTF.ext` sections of the program.
+pub fn relocate(insns: &mut [u64], section: &str, btf: &[u8], ext: &[u8]) -> Result<(), SysError> {
+    let local = Btf::parse(btf).ok_or(EINVAL)?;
+    if read_u16(ext, 0) != Some(BTF_MAGIC) {
+        return Err(EINVAL);
+    }
+    let hdr_len = read_u32(ext, 4).ok_or(EINVAL)? as usize;
+    // older compilers emit no CO-RE relocations
+    if hdr_len < 32 {
+        return Ok(());
+    }
+    let relos = (|| {
+        let start = hdr_len.checked_add(read_u32(ext, 24)? as usize)?;
+        let len = read_u32(ext, 28)? as usize;
+        ext.get(start..start.checked_add(len)?)
+    })()
+    .ok_or(EINVAL)?;
+    if relos.is_empty() {
+        return Ok(());
+    }
+
+    let strings = (|| {
+        let start = read_u32(btf, 4)?.checked_add(read_u32(btf, 16)?)? as usize;
+        let len = read_u32(btf, 20)? as usize;
+        btf.get(start..start.checked_add(len)?)
+    })()
+    .ok_or(EINVAL)?;
+    let string = |off: u32| -> Option<&str> {
+        let rest = strings.get(off as usize..)?;
+        let len = rest.iter().position(|&c| c == 0)?;
+        core::str::from_utf8(&rest[..len]).ok()
+    };
+
+    // bpf_core_relo records of each section
+    let record_size = read_u32(relos, 0).ok_or(EINVAL)? as usize;
+    if record_size < 16 {
+        return Err(EINVAL);
+    }
+    let mut pos = 4;
+    while pos < relos.len() {
+        let sec_name = read_u32(relos, pos).and_then(string).ok_or(EINVAL)?;
+        let num_info = read_u32(relos, pos + 4).ok_or(EINVAL)? as usize;
+        pos += 8;
+        for i in 0..num_info {
+            let record = pos + i * record_size;
+            let field = |off: usize| read_u32(relos, record + off).ok_or(EINVAL);
+            let (insn_off, type_id, access, kind) = (field(0)?, field(4)?, field(8)?, field(12)?);
+            if sec_name != section {
+                continue;
+            }
+            let access = string(access).ok_or(EINVAL)?;
+            let value = match core_value(&local, &KERNEL_BTF, type_id, access, kind) {
+                Some(value) => value,
+                None if kind == BPF_CORE_FIELD_EXISTS || kind == BPF_CORE_TYPE_EXISTS => 0,
+                None => {
+                    warn!(
+                        "eBPF CO-RE relocation of type {} {} failed",
+                        type_id, access
+                    );
+                    return Err(EINVAL);
+                }
+            };
+            patch(insns, insn_off as usize / 8, kind, value).ok_or(EINVAL)?;
+        }
+        pos += num_info * record_size;
+    }
+    Ok(())
+}
+
+fn read_u16(data: &[u8], off: usize) -> Option<u16> {
+    let bytes = data.get(off..off.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    let bytes = data.get(off..off.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
@@ -13,11 +13,23 @@ pub const BPF_PROG_DETACH: usize = 9;
 // custom commands
 pub const BPF_PROG_LOAD_EX: usize = 1000;
 
+// eBPF program types
+pub const BPF_PROG_TYPE_UNSPEC: u32 = 0;
+pub const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+pub const BPF_PROG_TYPE_KPROBE: u32 = 2;
+pub const BPF_PROG_TYPE_TRACEPOINT: u32 = 5;
+
 // eBPF map types
 pub const BPF_MAP_TYPE_UNSPEC: u32 = 0;
 pub const BPF_MAP_TYPE_HASH: u32 = 1;
 pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
 pub const BPF_MAP_TYPE_PROG_ARRAY: u32 = 3;
+pub const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+pub const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+pub const BPF_MAP_TYPE_STACK_TRACE: u32 = 7;
+pub const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+pub const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+pub const BPF_MAP_TYPE_RINGBUF: u32 = 27;
 
 // eBPF LLVM relocations
 // see https://www.kernel.org/doc/html/latest/bpf/llvm_reloc.html
@@ -32,3 +44,110 @@ pub const BPF_ANY: u64 = 0;
 pub const BPF_NOEXIST: u64 = 1;
 pub const BPF_EXIST: u64 = 2;
 pub const BPF_F_LOCK: u64 = 4;
+
+// flags of stack trace maps
+pub const BPF_F_SKIP_FIELD_MASK: u64 = 0xff;
+pub const BPF_F_USER_STACK: u64 = 1 << 8;
+pub const BPF_F_FAST_STACK_CMP: u64 = 1 << 9;
+pub const BPF_F_REUSE_STACKID: u64 = 1 << 10;
+pub const PERF_MAX_STACK_DEPTH: usize = 127;
+
+// flags and record headers of ring buffers
+pub const BPF_RB_NO_WAKEUP: u64 = 1 << 0;
+pub const BPF_RB_FORCE_WAKEUP: u64 = 1 << 1;
+pub const BPF_RINGBUF_HDR_SZ: usize = 8;
+
+// ids of helper functions
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: usize = 1;
+pub const BPF_FUNC_MAP_UPDATE_ELEM: usize = 2;
+pub const BPF_FUNC_MAP_DELETE_ELEM: usize = 3;
+pub const BPF_FUNC_PROBE_READ: usize = 4;
+pub const BPF_FUNC_KTIME_GET_NS: usize = 5;
+pub const BPF_FUNC_TRACE_PRINTK: usize = 6;
+pub const BPF_FUNC_GET_PRANDOM_U32: usize = 7;
+pub const BPF_FUNC_GET_SMP_PROCESSOR_ID: usize = 8;
+pub const BPF_FUNC_SKB_STORE_BYTES: usize = 9;
+pub const BPF_FUNC_L3_CSUM_REPLACE: usize = 10;
+pub const BPF_FUNC_L4_CSUM_REPLACE: usize = 11;
+pub const BPF_FUNC_TAIL_CALL: usize = 12;
+pub const BPF_FUNC_CLONE_REDIRECT: usize = 13;
+pub const BPF_FUNC_GET_CURRENT_PID_TGID: usize = 14;
+pub const BPF_FUNC_GET_CURRENT_UID_GID: usize = 15;
+pub const BPF_FUNC_GET_CURRENT_COMM: usize = 16;
+pub const BPF_FUNC_GET_STACKID: usize = 27;
+pub const BPF_FUNC_GET_CURRENT_TASK: usize = 35;
+pub const BPF_FUNC_PROBE_WRITE_USER: usize = 36;
+pub const BPF_FUNC_PROBE_READ_STR: usize = 45;
+pub const BPF_FUNC_SEND_SIGNAL: usize = 109;
+pub const BPF_FUNC_RINGBUF_OUTPUT: usize = 130;
+/// Tail calls made in a row at most
+pub const MAX_TAIL_CALL_CNT: usize = 33;
+
+// eBPF instruction classes
+pub const BPF_LD: u8 = 0x00;
+pub const BPF_LDX: u8 = 0x01;
+pub const BPF_ST: u8 = 0x02;
+pub const BPF_STX: u8 = 0x03;
+pub const BPF_ALU: u8 = 0x04;
+pub const BPF_JMP: u8 = 0x05;
+pub const BPF_JMP32: u8 = 0x06;
+pub const BPF_ALU64: u8 = 0x07;
+
+// sizes of memory accesses
+pub const BPF_W: u8 = 0x00;
+pub const BPF_H: u8 = 0x08;
+pub const BPF_B: u8 = 0x10;
+pub const BPF_DW: u8 = 0x18;
+
+// modes of memory accesses
+pub const BPF_IMM: u8 = 0x00;
+pub const BPF_ABS: u8 = 0x20;
+pub const BPF_IND: u8 = 0x40;
+pub const BPF_MEM: u8 = 0x60;
+pub const BPF_ATOMIC: u8 = 0xc0;
+
+// ALU operations
+pub const BPF_ADD: u8 = 0x00;
+pub const BPF_SUB: u8 = 0x10;
+pub const BPF_MUL: u8 = 0x20;
+pub const BPF_DIV: u8 = 0x30;
+pub const BPF_OR: u8 = 0x40;
+pub const BPF_AND: u8 = 0x50;
+pub const BPF_LSH: u8 = 0x60;
+pub const BPF_RSH: u8 = 0x70;
+pub const BPF_NEG: u8 = 0x80;
+pub const BPF_MOD: u8 = 0x90;
+pub const BPF_XOR: u8 = 0xa0;
+pub const BPF_MOV: u8 = 0xb0;
+pub const BPF_ARSH: u8 = 0xc0;
+pub const BPF_END: u8 = 0xd0;
+
+// jump operations
+pub const BPF_JA: u8 = 0x00;
+pub const BPF_JEQ: u8 = 0x10;
+pub const BPF_JGT: u8 = 0x20;
+pub const BPF_JGE: u8 = 0x30;
+pub const BPF_JSET: u8 = 0x40;
+pub const BPF_JNE: u8 = 0x50;
+pub const BPF_JSGT: u8 = 0x60;
+pub const BPF_JSGE: u8 = 0x70;
+pub const BPF_CALL: u8 = 0x80;
+pub const BPF_EXIT: u8 = 0x90;
+pub const BPF_JLT: u8 = 0xa0;
+pub const BPF_JLE: u8 = 0xb0;
+pub const BPF_JSLT: u8 = 0xc0;
+pub const BPF_JSLE: u8 = 0xd0;
+
+// operand sources, BPF_END uses them for byte orders
+pub const BPF_K: u8 = 0x00;
+pub const BPF_X: u8 = 0x08;
+pub const BPF_TO_LE: u8 = 0x00;
+pub const BPF_TO_BE: u8 = 0x08;
+
+/// src_reg of ld_imm64 loading a map by its fd
+pub const BPF_PSEUDO_MAP_FD: usize = 1;
+
+/// Stack size of eBPF programs, r10 points to its end
+pub const BPF_STACK_SIZE: usize = 512;
+/// Longest program accepted
+pub const BPF_MAXINSNS: usize = 65536;
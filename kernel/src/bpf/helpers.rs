@@ -1,35 +1,186 @@
+use crate::memory::{copy_from_user, copy_to_user};
 use crate::process::current_thread;
-use crate::syscall::SysResult;
+use crate::signal::{send_signal_locked, Siginfo, Signal, SI_KERNEL};
+use crate::syscall::{SysError::*, SysResult};
+use alloc::sync::Arc;
 use core::ptr::{null, null_mut};
+use num::FromPrimitive;
 
 use super::{
     consts::*,
-    map::{bpf_map_lookup_helper, bpf_map_ops},
+    map::{self, bpf_map_lookup_helper, bpf_map_ops, bpf_stack_map_collect},
 };
 
 pub type BpfHelperFn = fn(u64, u64, u64, u64, u64) -> i64;
 
-pub const HELPER_FN_COUNT: usize = 17;
-pub static HELPER_FN_TABLE: [BpfHelperFn; HELPER_FN_COUNT] = [
-    bpf_nop,
-    bpf_map_lookup_elem,
-    bpf_map_update_elem,
-    bpf_map_delete_elem,
-    bpf_probe_read,
-    bpf_ktime_get_ns,
-    bpf_trace_printk,
-    bpf_get_prandom_u32,
-    bpf_get_smp_processor_id,
-    bpf_nop, // bpf_skb_store_bytes
-    bpf_nop, // bpf_l3_csum_replace
-    bpf_nop, // bpf_l4_csum_replace
-    bpf_nop, // bpf_tail_call
-    bpf_nop, // bpf_clone_redirect
-    bpf_get_current_pid_tgid,
-    bpf_nop, // bpf_get_current_uid_gid
-    bpf_get_current_comm,
+/// Helpers are indexed by their ids, those up to `BPF_FUNC_RINGBUF_OUTPUT`
+pub const HELPER_FN_COUNT: usize = BPF_FUNC_RINGBUF_OUTPUT + 1;
+pub static HELPER_FN_TABLE: [BpfHelperFn; HELPER_FN_COUNT] = {
+    let mut table = [bpf_nop as BpfHelperFn; HELPER_FN_COUNT];
+    table[BPF_FUNC_MAP_LOOKUP_ELEM] = bpf_map_lookup_elem;
+    table[BPF_FUNC_MAP_UPDATE_ELEM] = bpf_map_update_elem;
+    table[BPF_FUNC_MAP_DELETE_ELEM] = bpf_map_delete_elem;
+    table[BPF_FUNC_PROBE_READ] = bpf_probe_read;
+    table[BPF_FUNC_KTIME_GET_NS] = bpf_ktime_get_ns;
+    table[BPF_FUNC_TRACE_PRINTK] = bpf_trace_printk;
+    table[BPF_FUNC_GET_PRANDOM_U32] = bpf_get_prandom_u32;
+    table[BPF_FUNC_GET_SMP_PROCESSOR_ID] = bpf_get_smp_processor_id;
+    // bpf_tail_call does not return, the interpreter handles it
+    table[BPF_FUNC_GET_CURRENT_PID_TGID] = bpf_get_current_pid_tgid;
+    table[BPF_FUNC_GET_CURRENT_UID_GID] = bpf_get_current_uid_gid;
+    table[BPF_FUNC_GET_CURRENT_COMM] = bpf_get_current_comm;
+    table[BPF_FUNC_GET_STACKID] = bpf_get_stackid;
+    table[BPF_FUNC_GET_CURRENT_TASK] = bpf_get_current_task;
+    table[BPF_FUNC_PROBE_WRITE_USER] = bpf_probe_write_user;
+    table[BPF_FUNC_PROBE_READ_STR] = bpf_probe_read_str;
+    table[BPF_FUNC_SEND_SIGNAL] = bpf_send_signal;
+    table[BPF_FUNC_RINGBUF_OUTPUT] = bpf_ringbuf_output;
+    table
+};
+
+/// What a helper expects of an argument
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HelperArg {
+    /// Not used by the helper
+    DontCare,
+    /// Any initialized value
+    Anything,
+    /// A map, as the fd its symbol holds
+    Map,
+    /// Pointer to a key of the map argument
+    MapKey,
+    /// Pointer to a value of the map argument
+    MapValue,
+    /// Pointer to the context of the program
+    Ctx,
+    /// Pointer to memory the helper reads, sized by the next argument
+    Mem,
+    /// Pointer to memory the helper fills, sized by the next argument
+    UninitMem,
+    /// Size of the memory argument before
+    ConstSize,
+}
+
+/// What a helper returns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HelperRet {
+    Scalar,
+    /// Pointer to a value of the map argument, or NULL
+    MapValueOrNull,
+}
+
+/// Prototype of a helper, checked by the verifier
+#[derive(Clone, Copy, Debug)]
+pub struct HelperProto {
+    pub args: [HelperArg; 5],
+    pub ret: HelperRet,
+    /// Only for programs of GPL compatible licenses
+    pub gpl_only: bool,
+    /// Type of the map argument, None for the generic map operations.
+    /// Maps of the special types only go to the helpers naming them.
+    pub map_type: Option<u32>,
+}
+
+impl HelperProto {
+    const fn with_map_type(self, map_type: u32) -> Self {
+        HelperProto {
+            map_type: Some(map_type),
+            ..self
+        }
+    }
+}
+
+use HelperArg::*;
+use HelperRet::*;
+
+const fn proto(args: [HelperArg; 5], ret: HelperRet) -> HelperProto {
+    HelperProto {
+        args,
+        ret,
+        gpl_only: false,
+        map_type: None,
+    }
+}
+
+const fn gpl_proto(args: [HelperArg; 5], ret: HelperRet) -> HelperProto {
+    HelperProto {
+        args,
+        ret,
+        gpl_only: true,
+        map_type: None,
+    }
+}
+
+const NO_ARGS: HelperProto = proto([DontCare; 5], Scalar);
+
+/// Map types only special helpers take
+pub const SPECIAL_MAP_TYPES: &[u32] = &[
+    BPF_MAP_TYPE_PROG_ARRAY,
+    BPF_MAP_TYPE_STACK_TRACE,
+    BPF_MAP_TYPE_RINGBUF,
 ];
 
+/// Prototypes of `HELPER_FN_TABLE`, None for helpers not supported
+pub static HELPER_PROTOS: [Option<HelperProto>; HELPER_FN_COUNT] = {
+    let mut protos = [None; HELPER_FN_COUNT];
+    protos[0] = Some(NO_ARGS);
+    protos[BPF_FUNC_MAP_LOOKUP_ELEM] = Some(proto(
+        [Map, MapKey, DontCare, DontCare, DontCare],
+        MapValueOrNull,
+    ));
+    protos[BPF_FUNC_MAP_UPDATE_ELEM] =
+        Some(proto([Map, MapKey, MapValue, Anything, DontCare], Scalar));
+    protos[BPF_FUNC_MAP_DELETE_ELEM] =
+        Some(proto([Map, MapKey, DontCare, DontCare, DontCare], Scalar));
+    protos[BPF_FUNC_PROBE_READ] = Some(gpl_proto(
+        [UninitMem, ConstSize, Anything, DontCare, DontCare],
+        Scalar,
+    ));
+    protos[BPF_FUNC_KTIME_GET_NS] = Some(NO_ARGS);
+    protos[BPF_FUNC_TRACE_PRINTK] = Some(gpl_proto(
+        [Mem, ConstSize, Anything, Anything, Anything],
+        Scalar,
+    ));
+    protos[BPF_FUNC_GET_PRANDOM_U32] = Some(NO_ARGS);
+    protos[BPF_FUNC_GET_SMP_PROCESSOR_ID] = Some(NO_ARGS);
+    protos[BPF_FUNC_SKB_STORE_BYTES] = Some(NO_ARGS);
+    protos[BPF_FUNC_L3_CSUM_REPLACE] = Some(NO_ARGS);
+    protos[BPF_FUNC_L4_CSUM_REPLACE] = Some(NO_ARGS);
+    protos[BPF_FUNC_TAIL_CALL] = Some(
+        proto([Ctx, Map, Anything, DontCare, DontCare], Scalar)
+            .with_map_type(BPF_MAP_TYPE_PROG_ARRAY),
+    );
+    protos[BPF_FUNC_CLONE_REDIRECT] = Some(NO_ARGS);
+    protos[BPF_FUNC_GET_CURRENT_PID_TGID] = Some(NO_ARGS);
+    protos[BPF_FUNC_GET_CURRENT_UID_GID] = Some(NO_ARGS);
+    protos[BPF_FUNC_GET_CURRENT_COMM] = Some(proto(
+        [UninitMem, ConstSize, DontCare, DontCare, DontCare],
+        Scalar,
+    ));
+    protos[BPF_FUNC_GET_STACKID] = Some(
+        proto([Ctx, Map, Anything, DontCare, DontCare], Scalar)
+            .with_map_type(BPF_MAP_TYPE_STACK_TRACE),
+    );
+    protos[BPF_FUNC_GET_CURRENT_TASK] = Some(NO_ARGS);
+    protos[BPF_FUNC_PROBE_WRITE_USER] = Some(gpl_proto(
+        [Anything, Mem, ConstSize, DontCare, DontCare],
+        Scalar,
+    ));
+    protos[BPF_FUNC_PROBE_READ_STR] = Some(gpl_proto(
+        [UninitMem, ConstSize, Anything, DontCare, DontCare],
+        Scalar,
+    ));
+    protos[BPF_FUNC_SEND_SIGNAL] = Some(proto(
+        [Anything, DontCare, DontCare, DontCare, DontCare],
+        Scalar,
+    ));
+    protos[BPF_FUNC_RINGBUF_OUTPUT] = Some(
+        proto([Map, Mem, ConstSize, Anything, DontCare], Scalar)
+            .with_map_type(BPF_MAP_TYPE_RINGBUF),
+    );
+    protos
+};
+
 // WARNING: be careful to use bpf_probe_read, bpf_get_current_pid_tgid & bpf_get_current_comm
 // in syscall contexts. obtaining current process information may cause deadlock!
 
@@ -128,7 +279,7 @@ fn bpf_trace_printk(fmt: u64, fmt_size: u64, p1: u64, p2: u64, p3: u64) -> i64 {
 }
 
 fn bpf_get_prandom_u32(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> i64 {
-    todo!()
+    crate::arch::rand::rand() as u32 as i64
 }
 
 fn bpf_get_smp_processor_id(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> i64 {
@@ -160,3 +311,103 @@ fn bpf_get_current_comm(dst: u64, buf_size: u64, _1: u64, _2: u64, _3: u64) -> i
     }
     len as i64
 }
+
+fn bpf_get_current_uid_gid(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> i64 {
+    // NOTE: there are no users, everyone is root
+    0
+}
+
+// long bpf_get_stackid(void *ctx, struct bpf_map *map, u64 flags)
+// the trace starts in the helper, skip the frames of the eBPF runtime with flags
+fn bpf_get_stackid(_ctx: u64, map_fd: u64, flags: u64, _1: u64, _2: u64) -> i64 {
+    convert_result(bpf_stack_map_collect(map_fd as u32, flags))
+}
+
+// u64 bpf_get_current_task(void)
+// return the address of the current Thread
+fn bpf_get_current_task(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> i64 {
+    match current_thread() {
+        Some(thread) => Arc::as_ptr(&thread) as usize as i64,
+        None => 0,
+    }
+}
+
+// long bpf_probe_write_user(void *dst, const void *src, u32 len)
+fn bpf_probe_write_user(dst: u64, src: u64, len: u64, _1: u64, _2: u64) -> i64 {
+    let dst = dst as usize as *mut u8;
+    let src = unsafe { core::slice::from_raw_parts(src as usize as *const u8, len as usize) };
+    // faults on user memory are fixed up byte by byte
+    for (i, byte) in src.iter().enumerate() {
+        if !copy_to_user(unsafe { dst.add(i) }, byte) {
+            return convert_result(Err(EFAULT));
+        }
+    }
+    0
+}
+
+// long bpf_probe_read_str(void *dst, u32 size, const void *unsafe_ptr)
+// return the length copied including the trailing NUL
+fn bpf_probe_read_str(dst: u64, size: u64, src: u64, _1: u64, _2: u64) -> i64 {
+    let size = size as usize;
+    if size == 0 {
+        return 0;
+    }
+    let dst = unsafe { core::slice::from_raw_parts_mut(dst as usize as *mut u8, size) };
+    let src = src as usize as *const u8;
+
+    use crate::arch::consts::KERNEL_OFFSET;
+    for i in 0..(size - 1) {
+        let ptr = unsafe { src.add(i) };
+        let byte = if ptr as usize >= KERNEL_OFFSET {
+            // WARNING: this may cause kernel crash!
+            Some(unsafe { *ptr })
+        } else {
+            copy_from_user(ptr)
+        };
+        match byte {
+            Some(0) => {
+                dst[i] = 0;
+                return i as i64 + 1;
+            }
+            Some(byte) => dst[i] = byte,
+            None => {
+                dst.fill(0);
+                return convert_result(Err(EFAULT));
+            }
+        }
+    }
+    dst[size - 1] = 0;
+    size as i64
+}
+
+// long bpf_send_signal(u32 sig)
+// send sig to the current process
+fn bpf_send_signal(sig: u64, _1: u64, _2: u64, _3: u64, _4: u64) -> i64 {
+    let signo = sig as u32 as i32;
+    if <Signal as FromPrimitive>::from_i32(signo).is_none() {
+        return convert_result(Err(EINVAL));
+    }
+    let thread = match current_thread() {
+        Some(thread) => thread,
+        None => return convert_result(Err(EPERM)),
+    };
+    // the probe may have interrupted a holder of the lock
+    let mut process = match thread.proc.try_lock() {
+        Some(process) => process,
+        None => return convert_result(Err(EBUSY)),
+    };
+    let info = Siginfo {
+        signo,
+        errno: 0,
+        code: SI_KERNEL,
+        field: Default::default(),
+    };
+    send_signal_locked(&mut process, -1, info);
+    0
+}
+
+// long bpf_ringbuf_output(void *ringbuf, void *data, u64 size, u64 flags)
+fn bpf_ringbuf_output(map_fd: u64, data: u64, size: u64, flags: u64, _1: u64) -> i64 {
+    let data = unsafe { core::slice::from_raw_parts(data as usize as *const u8, size as usize) };
+    convert_result(map::bpf_ringbuf_output(map_fd as u32, data, flags))
+}
@@ -0,0 +1,64 @@
+use super::consts::*;
+
+/// A decoded eBPF instruction
+#[derive(Clone, Copy, Debug)]
+pub struct BpfInsn {
+    pub opcode: u8,
+    pub dst: usize,
+    pub src: usize,
+    pub off: i16,
+    pub imm: i32,
+}
+
+impl BpfInsn {
+    /// Decode an instruction as it is laid out in memory
+    pub fn decode(insn: u64) -> Self {
+        let insn = u64::from_le(insn);
+        BpfInsn {
+            opcode: insn as u8,
+            dst: (insn >> 8) as usize & 0xf,
+            src: (insn >> 12) as usize & 0xf,
+            off: (insn >> 16) as u16 as i16,
+            imm: (insn >> 32) as u32 as i32,
+        }
+    }
+
+    pub fn class(&self) -> u8 {
+        self.opcode & 0x07
+    }
+
+    /// ALU or jump operation
+    pub fn op(&self) -> u8 {
+        self.opcode & 0xf0
+    }
+
+    /// BPF_K or BPF_X
+    pub fn source(&self) -> u8 {
+        self.opcode & 0x08
+    }
+
+    /// Memory access mode
+    pub fn mode(&self) -> u8 {
+        self.opcode & 0xe0
+    }
+
+    /// Bytes of a memory access
+    pub fn size(&self) -> usize {
+        match self.opcode & 0x18 {
+            BPF_W => 4,
+            BPF_H => 2,
+            BPF_B => 1,
+            _ => 8,
+        }
+    }
+
+    /// BPF_LD | BPF_IMM | BPF_DW takes two slots
+    pub fn is_ld_imm64(&self) -> bool {
+        self.opcode == BPF_LD | BPF_IMM | BPF_DW
+    }
+
+    /// The immediate of a 64-bit load, `next` is its second slot
+    pub fn imm64(&self, next: &BpfInsn) -> u64 {
+        (self.imm as u32 as u64) | (next.imm as u32 as u64) << 32
+    }
+}
@@ -0,0 +1,219 @@
+//! Portable eBPF interpreter
+//!
+//! Programs are checked by the verifier before they get here,
+//! so instructions are neither checked nor bounded again.
+
+use alloc::sync::Arc;
+#[cfg(target_has_atomic = "64")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::consts::*;
+use super::helpers::HELPER_FN_TABLE;
+use super::insn::BpfInsn;
+use super::program::BpfProgram;
+
+/// Compute a 64-bit ALU operation, BPF_NEG and BPF_END excluded
+pub fn alu64(op: u8, dst: u64, src: u64) -> u64 {
+    match op {
+        BPF_ADD => dst.wrapping_add(src),
+        BPF_SUB => dst.wrapping_sub(src),
+        BPF_MUL => dst.wrapping_mul(src),
+        // division by zero gives 0, modulo by zero keeps dst
+        BPF_DIV => dst.checked_div(src).unwrap_or(0),
+        BPF_MOD => dst.checked_rem(src).unwrap_or(dst),
+        BPF_OR => dst | src,
+        BPF_AND => dst & src,
+        BPF_XOR => dst ^ src,
+        BPF_LSH => dst << (src & 63),
+        BPF_RSH => dst >> (src & 63),
+        BPF_ARSH => ((dst as i64) >> (src & 63)) as u64,
+        _ => src, // BPF_MOV
+    }
+}
+
+/// Compute a 32-bit ALU operation, the result is zero extended
+pub fn alu32(op: u8, dst: u64, src: u64) -> u64 {
+    let (dst, src) = (dst as u32, src as u32);
+    let result = match op {
+        BPF_ADD => dst.wrapping_add(src),
+        BPF_SUB => dst.wrapping_sub(src),
+        BPF_MUL => dst.wrapping_mul(src),
+        BPF_DIV => dst.checked_div(src).unwrap_or(0),
+        BPF_MOD => dst.checked_rem(src).unwrap_or(dst),
+        BPF_OR => dst | src,
+        BPF_AND => dst & src,
+        BPF_XOR => dst ^ src,
+        BPF_LSH => dst << (src & 31),
+        BPF_RSH => dst >> (src & 31),
+        BPF_ARSH => ((dst as i32) >> (src & 31)) as u32,
+        _ => src,
+    };
+    result as u64
+}
+
+/// Convert the low `bits` of `value` to or from a byte order
+pub fn byte_order(value: u64, to_be: bool, bits: i32) -> u64 {
+    match (bits, to_be) {
+        (16, false) => u16::to_le(value as u16) as u64,
+        (16, true) => u16::to_be(value as u16) as u64,
+        (32, false) => u32::to_le(value as u32) as u64,
+        (32, true) => u32::to_be(value as u32) as u64,
+        (_, false) => u64::to_le(value),
+        (_, true) => u64::to_be(value),
+    }
+}
+
+/// Decide a conditional jump, BPF_JMP32 compares the low halves
+pub fn jump_taken(op: u8, dst: u64, src: u64, jmp32: bool) -> bool {
+    let (dst, src, sdst, ssrc) = if jmp32 {
+        let (dst, src) = (dst as u32, src as u32);
+        (dst as u64, src as u64, dst as i32 as i64, src as i32 as i64)
+    } else {
+        (dst, src, dst as i64, src as i64)
+    };
+    match op {
+        BPF_JEQ => dst == src,
+        BPF_JNE => dst != src,
+        BPF_JGT => dst > src,
+        BPF_JGE => dst >= src,
+        BPF_JLT => dst < src,
+        BPF_JLE => dst <= src,
+        BPF_JSET => dst & src != 0,
+        BPF_JSGT => sdst > ssrc,
+        BPF_JSGE => sdst >= ssrc,
+        BPF_JSLT => sdst < ssrc,
+        _ => sdst <= ssrc, // BPF_JSLE
+    }
+}
+
+unsafe fn load(addr: u64, size: usize) -> u64 {
+    let addr = addr as usize;
+    match size {
+        1 => *(addr as *const u8) as u64,
+        2 => (addr as *const u16).read_unaligned() as u64,
+        4 => (addr as *const u32).read_unaligned() as u64,
+        _ => (addr as *const u64).read_unaligned(),
+    }
+}
+
+unsafe fn store(addr: u64, size: usize, value: u64) {
+    let addr = addr as usize;
+    match size {
+        1 => *(addr as *mut u8) = value as u8,
+        2 => (addr as *mut u16).write_unaligned(value as u16),
+        4 => (addr as *mut u32).write_unaligned(value as u32),
+        _ => (addr as *mut u64).write_unaligned(value),
+    }
+}
+
+/// BPF_ATOMIC | BPF_ADD. The verifier only lets through aligned
+/// 4-byte additions, and 8-byte ones on targets with 64-bit atomics.
+unsafe fn atomic_add(addr: u64, size: usize, value: u64) {
+    let addr = addr as usize;
+    match size {
+        4 => {
+            (*(addr as *const AtomicU32)).fetch_add(value as u32, Ordering::SeqCst);
+        }
+        #[cfg(target_has_atomic = "64")]
+        8 => {
+            (*(addr as *const AtomicU64)).fetch_add(value, Ordering::SeqCst);
+        }
+        _ => unreachable!("atomic add of {} bytes", size),
+    }
+}
+
+/// How a run of the interpreter ends
+pub enum Exit {
+    /// BPF_EXIT, with r0
+    Return(i64),
+    /// bpf_tail_call succeeded, the target takes over the context
+    TailCall(Arc<BpfProgram>),
+}
+
+/// Run a verified program with `ctx` in r1. `tail_call` finds the target
+/// of bpf_tail_call from its map fd and index, the call falls through if
+/// there is none.
+pub fn interpret<F>(insns: &[u64], ctx: *const u8, tail_call: F) -> Exit
+where
+    F: Fn(u32, u32) -> Option<Arc<BpfProgram>>,
+{
+    let mut stack = [0u64; BPF_STACK_SIZE / 8];
+    let mut reg = [0u64; 11];
+    reg[1] = ctx as usize as u64;
+    reg[10] = stack.as_mut_ptr() as usize as u64 + BPF_STACK_SIZE as u64;
+
+    let mut pc = 0;
+    loop {
+        let insn = BpfInsn::decode(insns[pc]);
+        pc += 1;
+        let (dst, src) = (insn.dst, insn.src);
+        let operand = if insn.source() == BPF_X {
+            reg[src]
+        } else {
+            insn.imm as i64 as u64
+        };
+        match insn.class() {
+            BPF_ALU64 => {
+                reg[dst] = match insn.op() {
+                    BPF_NEG => (reg[dst] as i64).wrapping_neg() as u64,
+                    op => alu64(op, reg[dst], operand),
+                }
+            }
+            BPF_ALU => {
+                reg[dst] = match insn.op() {
+                    BPF_NEG => (reg[dst] as i32).wrapping_neg() as u32 as u64,
+                    BPF_END => byte_order(reg[dst], insn.source() == BPF_TO_BE, insn.imm),
+                    op => alu32(op, reg[dst], operand),
+                }
+            }
+            BPF_LD => {
+                // only BPF_LD | BPF_IMM | BPF_DW passes the verifier
+                reg[dst] = insn.imm64(&BpfInsn::decode(insns[pc]));
+                pc += 1;
+            }
+            BPF_LDX => {
+                let addr = reg[src].wrapping_add(insn.off as i64 as u64);
+                reg[dst] = unsafe { load(addr, insn.size()) };
+            }
+            BPF_ST | BPF_STX => {
+                let addr = reg[dst].wrapping_add(insn.off as i64 as u64);
+                let value = if insn.class() == BPF_ST {
+                    insn.imm as i64 as u64
+                } else {
+                    reg[src]
+                };
+                unsafe {
+                    if insn.mode() == BPF_ATOMIC {
+                        atomic_add(addr, insn.size(), value);
+                    } else {
+                        store(addr, insn.size(), value);
+                    }
+                }
+            }
+            _ => {
+                // BPF_JMP or BPF_JMP32
+                let jump = match insn.op() {
+                    BPF_JA => true,
+                    BPF_CALL if insn.imm as usize == BPF_FUNC_TAIL_CALL => {
+                        if let Some(program) = tail_call(reg[2] as u32, reg[3] as u32) {
+                            return Exit::TailCall(program);
+                        }
+                        reg[0] = -1i64 as u64;
+                        false
+                    }
+                    BPF_CALL => {
+                        let helper = HELPER_FN_TABLE[insn.imm as usize];
+                        reg[0] = helper(reg[1], reg[2], reg[3], reg[4], reg[5]) as u64;
+                        false
+                    }
+                    BPF_EXIT => return Exit::Return(reg[0] as i64),
+                    op => jump_taken(op, reg[dst], operand, insn.class() == BPF_JMP32),
+                };
+                if jump {
+                    pc = (pc as isize + insn.off as isize) as usize;
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,18 @@
+use crate::consts::SMP_CORES;
 use crate::memory;
+use crate::memory::{alloc_frame_contiguous, dealloc_frame_contiguous, phys_to_virt};
 use crate::sync::SpinLock as Mutex;
-use crate::syscall::{SysError::*, SysResult};
+use crate::syscall::{SysError, SysError::*, SysResult};
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::cmp::min;
+use core::convert::TryFrom;
 use core::ptr::null;
 use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
 use core::{mem, slice};
+use rcore_memory::PAGE_SIZE;
 use rlibc::memcmp;
 
 use super::consts::*;
@@ -23,6 +29,7 @@ pub struct MapAttr {
 
 #[derive(Debug, Clone, Copy)]
 pub struct InternalMapAttr {
+    pub map_type: u32,
     pub key_size: usize,
     pub value_size: usize,
     pub max_entries: usize,
@@ -31,6 +38,7 @@ pub struct InternalMapAttr {
 impl From<MapAttr> for InternalMapAttr {
     fn from(attr: MapAttr) -> Self {
         Self {
+            map_type: attr.map_type,
             key_size: attr.key_size as usize,
             value_size: attr.value_size as usize,
             max_entries: attr.max_entries as usize,
@@ -38,6 +46,19 @@ impl From<MapAttr> for InternalMapAttr {
     }
 }
 
+impl InternalMapAttr {
+    /// Bytes of a value as seen by user space. Per-CPU maps hand out
+    /// the values of all CPUs, each one padded to 8 bytes.
+    pub fn user_value_size(&self) -> usize {
+        match self.map_type {
+            BPF_MAP_TYPE_PERCPU_HASH | BPF_MAP_TYPE_PERCPU_ARRAY | BPF_MAP_TYPE_LRU_PERCPU_HASH => {
+                round_up(self.value_size, 8) * num_cpus()
+            }
+            _ => self.value_size,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct MapOpAttr {
@@ -56,11 +77,40 @@ pub trait BpfMap {
 
     // this lookup is intended for the helper function
     fn lookup_helper(&self, key: *const u8) -> SysResult;
+
+    // the following operations only make sense for some map types
+
+    /// Store a stack trace and return its id (BPF_MAP_TYPE_STACK_TRACE)
+    fn get_stackid(&mut self, _ips: &[u64], _flags: u64) -> SysResult {
+        Err(EINVAL)
+    }
+
+    /// Append a record (BPF_MAP_TYPE_RINGBUF)
+    fn output(&mut self, _data: &[u8], _flags: u64) -> SysResult {
+        Err(EINVAL)
+    }
+
+    /// Physical memory to map for `len` bytes at `offset` of the map,
+    /// as runs of (physical address, size) in order
+    fn mmap(
+        &self,
+        _offset: usize,
+        _len: usize,
+        _writable: bool,
+    ) -> Result<Vec<(usize, usize)>, SysError> {
+        Err(EINVAL)
+    }
+
+    /// The program at `index` (BPF_MAP_TYPE_PROG_ARRAY)
+    fn get_program(&self, _index: usize) -> Option<Arc<BpfProgram>> {
+        None
+    }
 }
 
 type HashCode = u32;
 type MapKey = Box<[u8]>;
-type MapValue = Box<[u8]>;
+/// Values are 8-byte aligned, so programs can update them atomically
+type MapValue = Box<[u64]>;
 
 fn copy(dst: *mut u8, src: *const u8, len: usize) {
     let from = unsafe { from_raw_parts(src, len) };
@@ -68,9 +118,41 @@ fn copy(dst: *mut u8, src: *const u8, len: usize) {
     to.copy_from_slice(from);
 }
 
+fn round_up(size: usize, align: usize) -> usize {
+    (size + align - 1) / align * align
+}
+
+fn num_cpus() -> usize {
+    *SMP_CORES
+}
+
+fn read_index(key: *const u8) -> usize {
+    unsafe { *(key as *const u32) as usize }
+}
+
+fn array_next_key(key: *const u8, next_key: *mut u8, max_entries: usize) -> SysResult {
+    let out = next_key as *mut u32;
+    let index = read_index(key);
+    if index >= max_entries {
+        unsafe {
+            *out = 0u32;
+        }
+        return Ok(0);
+    }
+
+    if index < max_entries - 1 {
+        unsafe {
+            *out = (index + 1) as u32;
+        }
+        Ok(0)
+    } else {
+        Err(ENOENT)
+    }
+}
+
 struct ArrayMap {
     attr: InternalMapAttr,
-    storage: Vec<u8>,
+    storage: Vec<u64>,
 }
 
 struct HashMap {
@@ -81,14 +163,13 @@ struct HashMap {
 
 impl ArrayMap {
     fn new(attr: InternalMapAttr) -> Self {
-        let size = attr.max_entries * attr.value_size;
-        let mut storage = Vec::with_capacity(size);
-        storage.resize(size, 0u8);
+        // every element starts at a multiple of 8 bytes
+        let storage = vec![0u64; attr.max_entries * round_up(attr.value_size, 8) / 8];
         Self { attr, storage }
     }
 
     fn get_element_addr(&self, index: usize) -> usize {
-        let offset = self.attr.value_size * index;
+        let offset = round_up(self.attr.value_size, 8) * index;
         self.storage.as_ptr() as usize + offset
     }
 }
@@ -129,6 +210,10 @@ impl HashMap {
         storage.resize(size, 0u8);
         storage.into_boxed_slice()
     }
+
+    fn alloc_value(size: usize) -> MapValue {
+        vec![0u64; round_up(size, 8) / 8].into_boxed_slice()
+    }
 }
 
 impl BpfMap for ArrayMap {
@@ -159,23 +244,7 @@ impl BpfMap for ArrayMap {
     }
 
     fn next_key(&self, key: *const u8, next_key: *mut u8) -> SysResult {
-        let out = next_key as *mut u32;
-        let index = unsafe { *(key as *const u32) } as usize;
-        if index >= self.attr.max_entries {
-            unsafe {
-                *out = 0u32;
-            }
-            return Ok(0);
-        }
-
-        if index < self.attr.max_entries - 1 {
-            unsafe {
-                *out = (index + 1) as u32;
-            }
-            Ok(0)
-        } else {
-            Err(ENOENT)
-        }
+        array_next_key(key, next_key, self.attr.max_entries)
     }
 
     fn get_attr(&self) -> InternalMapAttr {
@@ -195,7 +264,7 @@ impl BpfMap for ArrayMap {
 impl BpfMap for HashMap {
     fn lookup(&self, key: *const u8, value: *mut u8) -> SysResult {
         if let Some(mv) = self.find(key) {
-            copy(value, mv.as_ptr(), self.attr.value_size);
+            copy(value, mv.as_ptr() as *const u8, self.attr.value_size);
             Ok(0)
         } else {
             Err(ENOENT)
@@ -229,9 +298,9 @@ impl BpfMap for HashMap {
                     }
                     // create one, copy key and value into kernel space
                     let mut map_key = HashMap::alloc(key_size);
-                    let mut map_value = HashMap::alloc(value_size);
+                    let mut map_value = HashMap::alloc_value(value_size);
                     copy(map_key.as_mut_ptr(), key, key_size);
-                    copy(map_value.as_mut_ptr(), value, value_size);
+                    copy(map_value.as_mut_ptr() as *mut u8, value, value_size);
 
                     let hashcode = HashMap::hash(key, key_size);
                     if let Some(vec) = self.map.get_mut(&hashcode) {
@@ -328,49 +397,541 @@ impl BpfMap for HashMap {
     }
 }
 
+/// Keeps one value for every CPU in the values of an inner map,
+/// so that programs on different CPUs do not share an element.
+/// Each of them is padded to 8 bytes, as user space sees them.
+struct PerCpuMap<M> {
+    attr: InternalMapAttr,
+    inner: M,
+}
+
+impl<M: BpfMap> PerCpuMap<M> {
+    fn new(attr: InternalMapAttr, new_inner: impl FnOnce(InternalMapAttr) -> M) -> Self {
+        let mut inner_attr = attr;
+        inner_attr.value_size = attr.user_value_size();
+        Self {
+            attr,
+            inner: new_inner(inner_attr),
+        }
+    }
+}
+
+impl<M: BpfMap> BpfMap for PerCpuMap<M> {
+    fn lookup(&self, key: *const u8, value: *mut u8) -> SysResult {
+        self.inner.lookup(key, value)
+    }
+
+    fn update(&mut self, key: *const u8, value: *const u8, flags: u64) -> SysResult {
+        self.inner.update(key, value, flags)
+    }
+
+    fn delete(&mut self, key: *const u8) -> SysResult {
+        self.inner.delete(key)
+    }
+
+    fn next_key(&self, key: *const u8, next_key: *mut u8) -> SysResult {
+        self.inner.next_key(key, next_key)
+    }
+
+    fn get_attr(&self) -> InternalMapAttr {
+        self.attr
+    }
+
+    fn lookup_helper(&self, key: *const u8) -> SysResult {
+        // programs only see the value of the CPU they run on
+        let values = self.inner.lookup_helper(key)?;
+        Ok(values + crate::arch::cpu::id() * round_up(self.attr.value_size, 8))
+    }
+}
+
+/// A hash map that makes room for new elements by evicting
+/// the least recently used one instead of failing
+struct LruHashMap {
+    inner: HashMap,
+    clock: AtomicUsize,
+    // lookups only borrow the map, hence the atomics
+    last_used: BTreeMap<MapKey, AtomicUsize>,
+}
+
+impl LruHashMap {
+    fn new(attr: InternalMapAttr) -> Self {
+        Self {
+            inner: HashMap::new(attr),
+            clock: AtomicUsize::new(0),
+            last_used: BTreeMap::new(),
+        }
+    }
+
+    fn key<'a>(&self, key: *const u8) -> &'a [u8] {
+        unsafe { from_raw_parts(key, self.inner.attr.key_size) }
+    }
+
+    fn touch(&self, key: *const u8) {
+        if let Some(stamp) = self.last_used.get(self.key(key)) {
+            let now = self.clock.fetch_add(1, Ordering::Relaxed);
+            stamp.store(now, Ordering::Relaxed);
+        }
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .last_used
+            .iter()
+            .min_by_key(|(_, stamp)| stamp.load(Ordering::Relaxed))
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            let _ = self.inner.delete(key.as_ptr());
+            self.last_used.remove(&key);
+        }
+    }
+}
+
+impl BpfMap for LruHashMap {
+    fn lookup(&self, key: *const u8, value: *mut u8) -> SysResult {
+        self.inner.lookup(key, value)?;
+        self.touch(key);
+        Ok(0)
+    }
+
+    fn update(&mut self, key: *const u8, value: *const u8, flags: u64) -> SysResult {
+        let inserting = flags == BPF_ANY || flags == BPF_NOEXIST;
+        if inserting
+            && self.inner.total_elems >= self.inner.attr.max_entries
+            && self.inner.find(key).is_none()
+        {
+            self.evict();
+        }
+        self.inner.update(key, value, flags)?;
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        self.last_used
+            .insert(Box::from(self.key(key)), AtomicUsize::new(now));
+        Ok(0)
+    }
+
+    fn delete(&mut self, key: *const u8) -> SysResult {
+        self.inner.delete(key)?;
+        self.last_used.remove(self.key(key));
+        Ok(0)
+    }
+
+    fn next_key(&self, key: *const u8, next_key: *mut u8) -> SysResult {
+        self.inner.next_key(key, next_key)
+    }
+
+    fn get_attr(&self) -> InternalMapAttr {
+        self.inner.get_attr()
+    }
+
+    fn lookup_helper(&self, key: *const u8) -> SysResult {
+        let addr = self.inner.lookup_helper(key)?;
+        self.touch(key);
+        Ok(addr)
+    }
+}
+
+/// Stack traces of up to `value_size / 8` addresses, keyed by u32 ids
+/// which are handed out by `get_stackid`
+struct StackTraceMap {
+    attr: InternalMapAttr,
+    storage: Vec<u64>,
+    depths: Vec<usize>, // 0 for empty buckets
+}
+
+impl StackTraceMap {
+    fn new(attr: InternalMapAttr) -> Self {
+        let storage = vec![0u64; attr.max_entries * attr.value_size / 8];
+        let depths = vec![0usize; attr.max_entries];
+        Self {
+            attr,
+            storage,
+            depths,
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        self.attr.value_size / 8
+    }
+
+    fn bucket(&mut self, id: usize) -> &mut [u64] {
+        let depth = self.max_depth();
+        &mut self.storage[id * depth..(id + 1) * depth]
+    }
+}
+
+impl BpfMap for StackTraceMap {
+    fn lookup(&self, key: *const u8, value: *mut u8) -> SysResult {
+        let id = read_index(key);
+        if id >= self.attr.max_entries || self.depths[id] == 0 {
+            return Err(ENOENT);
+        }
+        let trace = &self.storage[id * self.max_depth()..];
+        copy(value, trace.as_ptr() as *const u8, self.attr.value_size);
+        Ok(0)
+    }
+
+    fn update(&mut self, _key: *const u8, _value: *const u8, _flags: u64) -> SysResult {
+        Err(EINVAL) // traces only come from get_stackid
+    }
+
+    fn delete(&mut self, key: *const u8) -> SysResult {
+        let id = read_index(key);
+        if id >= self.attr.max_entries || self.depths[id] == 0 {
+            return Err(ENOENT);
+        }
+        self.bucket(id).fill(0);
+        self.depths[id] = 0;
+        Ok(0)
+    }
+
+    fn next_key(&self, key: *const u8, next_key: *mut u8) -> SysResult {
+        let id = read_index(key);
+        let start = if id < self.attr.max_entries {
+            id + 1
+        } else {
+            0
+        };
+        match (start..self.attr.max_entries).find(|&i| self.depths[i] != 0) {
+            Some(next) => {
+                unsafe {
+                    *(next_key as *mut u32) = next as u32;
+                }
+                Ok(0)
+            }
+            None => Err(ENOENT),
+        }
+    }
+
+    fn get_attr(&self) -> InternalMapAttr {
+        self.attr
+    }
+
+    fn lookup_helper(&self, _key: *const u8) -> SysResult {
+        Err(EINVAL) // traces are meant for user space
+    }
+
+    fn get_stackid(&mut self, ips: &[u64], flags: u64) -> SysResult {
+        let ips = &ips[..min(ips.len(), self.max_depth())];
+        if ips.is_empty() {
+            return Err(EFAULT);
+        }
+        let hash = HashMap::hash(ips.as_ptr() as *const u8, ips.len() * 8);
+        let id = hash as usize % self.attr.max_entries;
+        let depth = self.depths[id];
+        if depth != 0 {
+            let trace = &self.bucket(id)[..depth];
+            if trace == ips {
+                return Ok(id);
+            }
+            // hash collision with another trace
+            if flags & BPF_F_REUSE_STACKID == 0 {
+                return Err(EEXIST);
+            }
+        }
+        let bucket = self.bucket(id);
+        bucket.fill(0);
+        bucket[..ips.len()].copy_from_slice(ips);
+        self.depths[id] = ips.len();
+        Ok(id)
+    }
+}
+
+/// Ring buffer of variable sized records, written by programs and read
+/// by user space through mmap. It is laid out like in Linux, so libbpf
+/// consumers work as is: the consumer position on the first page,
+/// the producer position on the second one, then `max_entries` bytes
+/// of records, each after a header of its length and page offset.
+/// User space maps the data twice in a row so records never wrap for it.
+struct RingBuf {
+    attr: InternalMapAttr,
+    paddr: usize,
+    pages: usize,
+    mapped: AtomicBool,
+}
+
+impl RingBuf {
+    fn new(attr: InternalMapAttr) -> Result<Self, SysError> {
+        let pages = 2 + attr.max_entries / PAGE_SIZE;
+        let paddr = alloc_frame_contiguous(pages, 0).ok_or(ENOMEM)?;
+        // these pages end up in user space
+        unsafe {
+            core::ptr::write_bytes(phys_to_virt(paddr) as *mut u8, 0, pages * PAGE_SIZE);
+        }
+        Ok(Self {
+            attr,
+            paddr,
+            pages,
+            mapped: AtomicBool::new(false),
+        })
+    }
+
+    fn consumer_pos(&self) -> &AtomicUsize {
+        unsafe { &*(phys_to_virt(self.paddr) as *const AtomicUsize) }
+    }
+
+    fn producer_pos(&self) -> &AtomicUsize {
+        unsafe { &*(phys_to_virt(self.paddr + PAGE_SIZE) as *const AtomicUsize) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        phys_to_virt(self.paddr + 2 * PAGE_SIZE) as *mut u8
+    }
+}
+
+impl Drop for RingBuf {
+    fn drop(&mut self) {
+        // user space may still have the pages mapped, then they are leaked
+        if !self.mapped.load(Ordering::Relaxed) {
+            dealloc_frame_contiguous(self.paddr, self.pages);
+        }
+    }
+}
+
+impl BpfMap for RingBuf {
+    fn lookup(&self, _key: *const u8, _value: *mut u8) -> SysResult {
+        Err(EINVAL)
+    }
+
+    fn update(&mut self, _key: *const u8, _value: *const u8, _flags: u64) -> SysResult {
+        Err(EINVAL)
+    }
+
+    fn delete(&mut self, _key: *const u8) -> SysResult {
+        Err(EINVAL)
+    }
+
+    fn next_key(&self, _key: *const u8, _next_key: *mut u8) -> SysResult {
+        Err(EINVAL)
+    }
+
+    fn get_attr(&self) -> InternalMapAttr {
+        self.attr
+    }
+
+    fn lookup_helper(&self, _key: *const u8) -> SysResult {
+        Err(EINVAL)
+    }
+
+    fn output(&mut self, data: &[u8], flags: u64) -> SysResult {
+        if flags & !(BPF_RB_NO_WAKEUP | BPF_RB_FORCE_WAKEUP) != 0 {
+            return Err(EINVAL);
+        }
+        let size = self.attr.max_entries;
+        let total = round_up(BPF_RINGBUF_HDR_SZ + data.len(), 8);
+        if total > size {
+            return Err(E2BIG);
+        }
+        // there is only one producer as the map is locked
+        let cons = self.consumer_pos().load(Ordering::Acquire);
+        let prod = self.producer_pos().load(Ordering::Relaxed);
+        if prod.wrapping_sub(cons) + total > size {
+            return Err(EAGAIN);
+        }
+
+        // headers are 8-byte aligned and never wrap, the data might
+        let offset = prod & (size - 1);
+        let start = (offset + BPF_RINGBUF_HDR_SZ) & (size - 1);
+        let first = min(data.len(), size - start);
+        unsafe {
+            copy(self.data().add(start), data.as_ptr(), first);
+            copy(self.data(), data[first..].as_ptr(), data.len() - first);
+            let header = self.data().add(offset) as *mut u32;
+            *header.add(1) = (offset / PAGE_SIZE + 2) as u32;
+            (*(header as *const AtomicU32)).store(data.len() as u32, Ordering::Release);
+        }
+        self.producer_pos()
+            .store(prod.wrapping_add(total), Ordering::Release);
+        Ok(0)
+    }
+
+    fn mmap(
+        &self,
+        offset: usize,
+        len: usize,
+        writable: bool,
+    ) -> Result<Vec<(usize, usize)>, SysError> {
+        let size = self.attr.max_entries;
+        let end = offset.checked_add(round_up(len, PAGE_SIZE)).ok_or(EINVAL)?;
+        if offset % PAGE_SIZE != 0 || end > 2 * PAGE_SIZE + 2 * size {
+            return Err(EINVAL);
+        }
+        // only the consumer position is for user space to write
+        if writable && end > PAGE_SIZE {
+            return Err(EPERM);
+        }
+        let mut runs = Vec::new();
+        let second_copy = 2 * PAGE_SIZE + size;
+        if offset < second_copy {
+            runs.push((self.paddr + offset, min(end, second_copy) - offset));
+        }
+        if end > second_copy {
+            let start = offset.max(second_copy);
+            let paddr = self.paddr + 2 * PAGE_SIZE + (start - second_copy);
+            runs.push((paddr, end - start));
+        }
+        self.mapped.store(true, Ordering::Relaxed);
+        Ok(runs)
+    }
+}
+
+/// Programs to tail call into, indexed like an array.
+/// User space stores program fds and reads them back.
+struct ProgArrayMap {
+    attr: InternalMapAttr,
+    progs: Vec<Option<(u32, Arc<BpfProgram>)>>,
+}
+
+impl ProgArrayMap {
+    fn new(attr: InternalMapAttr) -> Self {
+        let mut progs = Vec::with_capacity(attr.max_entries);
+        progs.resize(attr.max_entries, None);
+        Self { attr, progs }
+    }
+}
+
+impl BpfMap for ProgArrayMap {
+    fn lookup(&self, key: *const u8, value: *mut u8) -> SysResult {
+        let index = read_index(key);
+        match self.progs.get(index) {
+            Some(Some((fd, _))) => {
+                unsafe {
+                    *(value as *mut u32) = *fd;
+                }
+                Ok(0)
+            }
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn update(&mut self, key: *const u8, value: *const u8, flags: u64) -> SysResult {
+        if flags > BPF_EXIST {
+            return Err(EINVAL);
+        }
+        let index = read_index(key);
+        if index >= self.attr.max_entries {
+            return Err(ENOENT);
+        }
+        let fd = unsafe { *(value as *const u32) };
+        let prog = match BPF_OBJECTS.lock().get(&fd) {
+            Some(BpfObject::Program(prog)) => prog.clone(),
+            _ => return Err(ENOENT),
+        };
+        // a tail call keeps the context, so all programs take the same one
+        let mismatch = self
+            .progs
+            .iter()
+            .flatten()
+            .any(|(_, other)| other.prog_type() != prog.prog_type());
+        if mismatch {
+            return Err(EINVAL);
+        }
+        self.progs[index] = Some((fd, prog));
+        Ok(0)
+    }
+
+    fn delete(&mut self, key: *const u8) -> SysResult {
+        let index = read_index(key);
+        match self.progs.get_mut(index).and_then(|prog| prog.take()) {
+            Some(_) => Ok(0),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn next_key(&self, key: *const u8, next_key: *mut u8) -> SysResult {
+        array_next_key(key, next_key, self.attr.max_entries)
+    }
+
+    fn get_attr(&self) -> InternalMapAttr {
+        self.attr
+    }
+
+    fn lookup_helper(&self, _key: *const u8) -> SysResult {
+        Err(EINVAL) // programs are only reachable by tail calls
+    }
+
+    fn get_program(&self, index: usize) -> Option<Arc<BpfProgram>> {
+        let (_, prog) = self.progs.get(index)?.as_ref()?;
+        Some(prog.clone())
+    }
+}
+
 pub type SharedBpfMap = Arc<Mutex<dyn BpfMap + Send + Sync>>;
 
 pub fn bpf_map_create(attr: MapAttr) -> SysResult {
-    let internal_attr = InternalMapAttr::from(attr);
-    match attr.map_type {
-        BPF_MAP_TYPE_ARRAY => {
+    let attr = InternalMapAttr::from(attr);
+    let shared_map: SharedBpfMap = match attr.map_type {
+        BPF_MAP_TYPE_ARRAY | BPF_MAP_TYPE_PERCPU_ARRAY => {
             // array index must have size of 4
-            if internal_attr.key_size != 4 {
+            if attr.key_size != 4 {
                 return Err(EINVAL);
             }
-            let map = ArrayMap::new(internal_attr);
-            let shared_map = Arc::new(Mutex::new(map));
-            let fd = bpf_allocate_fd();
-            bpf_object_create_map(fd, shared_map);
-            Ok(fd as usize)
-        }
-        BPF_MAP_TYPE_HASH => {
-            let map = HashMap::new(internal_attr);
-            let shared_map = Arc::new(Mutex::new(map));
-            let fd = bpf_allocate_fd();
-            bpf_object_create_map(fd, shared_map);
-            Ok(fd as usize)
+            if attr.map_type == BPF_MAP_TYPE_ARRAY {
+                Arc::new(Mutex::new(ArrayMap::new(attr)))
+            } else {
+                Arc::new(Mutex::new(PerCpuMap::new(attr, ArrayMap::new)))
+            }
         }
-        _ => Err(EINVAL),
-    }
+        BPF_MAP_TYPE_HASH => Arc::new(Mutex::new(HashMap::new(attr))),
+        BPF_MAP_TYPE_PERCPU_HASH => Arc::new(Mutex::new(PerCpuMap::new(attr, HashMap::new))),
+        BPF_MAP_TYPE_LRU_HASH => Arc::new(Mutex::new(LruHashMap::new(attr))),
+        BPF_MAP_TYPE_LRU_PERCPU_HASH => Arc::new(Mutex::new(PerCpuMap::new(attr, LruHashMap::new))),
+        BPF_MAP_TYPE_PROG_ARRAY => {
+            // keys are indices, values are program fds
+            if attr.key_size != 4 || attr.value_size != 4 {
+                return Err(EINVAL);
+            }
+            Arc::new(Mutex::new(ProgArrayMap::new(attr)))
+        }
+        BPF_MAP_TYPE_STACK_TRACE => {
+            let depth = attr.value_size / 8;
+            if attr.key_size != 4
+                || attr.value_size % 8 != 0
+                || depth == 0
+                || depth > PERF_MAX_STACK_DEPTH
+                || attr.max_entries == 0
+            {
+                return Err(EINVAL);
+            }
+            Arc::new(Mutex::new(StackTraceMap::new(attr)))
+        }
+        BPF_MAP_TYPE_RINGBUF => {
+            // max_entries is the size of the data area
+            let size = attr.max_entries;
+            if attr.key_size != 0
+                || attr.value_size != 0
+                || !size.is_power_of_two()
+                || size % PAGE_SIZE != 0
+            {
+                return Err(EINVAL);
+            }
+            Arc::new(Mutex::new(RingBuf::new(attr)?))
+        }
+        _ => return Err(EINVAL),
+    };
+    let fd = bpf_allocate_fd();
+    bpf_object_create_map(fd, shared_map);
+    Ok(fd as usize)
 }
 
 pub fn bpf_map_close(fd: u32) -> SysResult {
     bpf_object_remove(fd).map_or(Ok(0), |_| Err(ENOENT))
 }
 
-pub fn bpf_map_get_attr(fd: u32) -> Option<InternalMapAttr> {
+// maps are locked after BPF_OBJECTS is released,
+// since updating a prog array takes BPF_OBJECTS again
+fn get_shared_map(fd: u32) -> Option<SharedBpfMap> {
     let bpf_objs = BPF_OBJECTS.lock();
     let obj = bpf_objs.get(&fd)?;
-    let shared_map = obj.is_map()?;
+    obj.is_map().cloned()
+}
+
+pub fn bpf_map_get_attr(fd: u32) -> Option<InternalMapAttr> {
+    let shared_map = get_shared_map(fd)?;
     let attr = shared_map.lock().get_attr();
     Some(attr)
 }
 
 pub fn bpf_map_ops(fd: u32, op: usize, key: *const u8, value: *mut u8, flags: u64) -> SysResult {
-    let bpf_objs = BPF_OBJECTS.lock();
-    let obj = bpf_objs.get(&fd).ok_or(ENOENT)?;
-    let shared_map = obj.is_map().ok_or(ENOENT)?;
+    let shared_map = get_shared_map(fd).ok_or(ENOENT)?;
     let mut map = shared_map.lock();
     match op {
         BPF_MAP_LOOKUP_ELEM => map.lookup(key, value),
@@ -382,9 +943,49 @@ pub fn bpf_map_ops(fd: u32, op: usize, key: *const u8, value: *mut u8, flags: u6
 }
 
 pub fn bpf_map_lookup_helper(fd: u32, key: *const u8) -> SysResult {
-    let bpf_objs = BPF_OBJECTS.lock();
-    let obj = bpf_objs.get(&fd).ok_or(ENOENT)?;
-    let shared_map = obj.is_map().ok_or(ENOENT)?;
+    let shared_map = get_shared_map(fd).ok_or(ENOENT)?;
     let map = shared_map.lock();
     map.lookup_helper(key)
 }
+
+/// Record the kernel stack in stack trace map `fd` and return its id.
+/// The trace starts at the caller of this function, the low bits
+/// of `flags` give how many more frames to skip.
+pub fn bpf_stack_map_collect(fd: u32, flags: u64) -> SysResult {
+    // there is no unwinder for user stacks
+    if flags & !(BPF_F_SKIP_FIELD_MASK | BPF_F_FAST_STACK_CMP | BPF_F_REUSE_STACKID) != 0 {
+        return Err(EINVAL);
+    }
+    let skip = (flags & BPF_F_SKIP_FIELD_MASK) as usize;
+    let mut buf = [0usize; PERF_MAX_STACK_DEPTH];
+    let count = crate::backtrace::callers(skip, &mut buf);
+    let ips: Vec<u64> = buf[..count].iter().map(|&ip| ip as u64).collect();
+
+    let shared_map = get_shared_map(fd).ok_or(ENOENT)?;
+    let mut map = shared_map.lock();
+    map.get_stackid(&ips, flags)
+}
+
+pub fn bpf_ringbuf_output(fd: u32, data: &[u8], flags: u64) -> SysResult {
+    let shared_map = get_shared_map(fd).ok_or(ENOENT)?;
+    let mut map = shared_map.lock();
+    map.output(data, flags)
+}
+
+pub fn bpf_prog_array_get(fd: u32, index: usize) -> Option<Arc<BpfProgram>> {
+    let shared_map = get_shared_map(fd)?;
+    let map = shared_map.lock();
+    map.get_program(index)
+}
+
+/// Memory for mmap(2) on `fd`, or None if it is not an eBPF map
+pub fn bpf_map_mmap(
+    fd: usize,
+    offset: usize,
+    len: usize,
+    writable: bool,
+) -> Option<Result<Vec<(usize, usize)>, SysError>> {
+    let shared_map = get_shared_map(u32::try_from(fd).ok()?)?;
+    let map = shared_map.lock();
+    Some(map.mmap(offset, len, writable))
+}
@@ -1,8 +1,12 @@
+pub mod btf;
 pub mod consts;
 mod helpers;
+mod insn;
+mod interpreter;
 pub mod map;
 pub mod program;
 pub mod tracepoints;
+mod verifier;
 
 use crate::sync::SpinLock as Mutex;
 use alloc::collections::BTreeMap;
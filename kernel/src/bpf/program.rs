@@ -6,11 +6,17 @@ use xmas_elf::header::Machine;
 use xmas_elf::sections::*;
 use xmas_elf::symbol_table::Entry;
 
-#[cfg(target_arch = "riscv64")]
+#[cfg(all(feature = "bpf_jit", target_arch = "riscv64"))]
 use ebpf2rv::compile;
 
+use super::btf;
 use super::consts::*;
+#[cfg(all(feature = "bpf_jit", target_arch = "riscv64"))]
 use super::helpers::*;
+use super::insn::BpfInsn;
+use super::interpreter::{interpret, Exit};
+use super::map::bpf_prog_array_get;
+use super::verifier::{bpf_verify, VerifierEnv};
 use super::*;
 
 #[repr(C)]
@@ -29,29 +35,84 @@ pub struct ProgramLoadExAttr {
     pub map_array: *const MapFdEntry,
 }
 
+/// Attributes of BPF_PROG_LOAD
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramLoadAttr {
+    pub prog_type: u32,
+    pub insn_cnt: u32,
+    pub insns: u64,
+    pub license: u64,
+    pub log_level: u32,
+    pub log_size: u32,
+    pub log_buf: u64,
+    pub kern_version: u32,
+    pub prog_flags: u32,
+    pub prog_name: [u8; 16],
+}
+
+/// Shortest log buffer accepted
+pub const BPF_LOG_MIN_SIZE: usize = 128;
+
 pub struct BpfProgram {
-    bpf_insns: Option<Vec<u64>>,
+    prog_type: u32,
+    bpf_insns: Vec<u64>,
     jited_prog: Option<Vec<u32>>, // TODO: should be something like Vec<u8>
     map_fd_table: Option<Vec<u32>>,
+    ctx_size: usize,
 }
 
 impl BpfProgram {
-    // TODO: run with context
-    pub fn run(&self, ctx: *const u8) -> i64 {
+    /// BPF_PROG_TYPE_UNSPEC for programs of BPF_PROG_LOAD_EX
+    pub fn prog_type(&self) -> u32 {
+        self.prog_type
+    }
+
+    /// Whether the program may attach where `prog_type` programs do
+    pub fn fits(&self, prog_type: u32) -> bool {
+        self.prog_type == BPF_PROG_TYPE_UNSPEC || self.prog_type == prog_type
+    }
+
+    /// Bytes of context the program reads, found by the verifier
+    pub fn ctx_size(&self) -> usize {
+        self.ctx_size
+    }
+
+    /// Run the program and the programs it tail calls on `ctx`,
+    /// of which `ctx_size` bytes can be read
+    pub fn run(&self, ctx: *const u8, ctx_size: usize) -> i64 {
+        let mut exit = self.run_once(ctx, ctx_size, 0);
+        let mut tail_calls = 0;
+        loop {
+            match exit {
+                Exit::Return(result) => return result,
+                Exit::TailCall(program) => {
+                    tail_calls += 1;
+                    exit = program.run_once(ctx, ctx_size, tail_calls);
+                }
+            }
+        }
+    }
+
+    fn run_once(&self, ctx: *const u8, ctx_size: usize, tail_calls: usize) -> Exit {
         if let Some(compiled_code) = &self.jited_prog {
             let result = unsafe {
                 type JitedFn = unsafe fn(*const u8) -> i64;
                 let f = core::mem::transmute::<*const u32, JitedFn>(compiled_code.as_ptr());
                 f(ctx)
             };
-            return result;
+            return Exit::Return(result);
         }
-
-        todo!("eBPF interpreter missing")
+        interpret(&self.bpf_insns, ctx, |fd, index| {
+            if tail_calls >= MAX_TAIL_CALL_CNT {
+                return None;
+            }
+            // the target may read no more of the context than there is
+            bpf_prog_array_get(fd, index as usize).filter(|program| program.ctx_size() <= ctx_size)
+        })
     }
 }
 
-// #[cfg(target_arch = "riscv64")]
 pub fn bpf_program_load_ex(prog: &mut [u8], map_info: &[(String, u32)]) -> SysResult {
     let base = prog.as_ptr();
     let elf = xmas_elf::ElfFile::new(prog).map_err(|_| EINVAL)?;
@@ -128,7 +189,7 @@ pub fn bpf_program_load_ex(prog: &mut [u8], map_info: &[(String, u32)]) -> SysRe
         }
     }
 
-    // compile eBPF code
+    // verify eBPF code
     let sec_hdr = elf.find_section_by_name(".text").ok_or(ENOENT)?;
     let code = sec_hdr.raw_data(&elf);
     let bpf_insns = unsafe {
@@ -137,23 +198,122 @@ pub fn bpf_program_load_ex(prog: &mut [u8], map_info: &[(String, u32)]) -> SysRe
             code.len() / core::mem::size_of::<u64>(),
         )
     };
-    let mut jit_ctx = compile::JitContext::new(bpf_insns);
-    let helper_fn_table =
-        unsafe { core::mem::transmute::<&[BpfHelperFn], &[u64]>(&HELPER_FN_TABLE) };
-    compile::compile(&mut jit_ctx, helper_fn_table, 512);
+    let map_slots: Vec<(u64, u32)> = map_fd_table
+        .iter()
+        .map(|fd| (fd as *const u32 as usize as u64, *fd))
+        .collect();
+    let env = VerifierEnv {
+        map_slots: &map_slots,
+        gpl_compatible: true,
+    };
+    // field offsets of kernel structures, for programs compiled with CO-RE
+    let mut insns = bpf_insns.to_vec();
+    if let (Some(btf), Some(ext)) = (
+        elf.find_section_by_name(".BTF"),
+        elf.find_section_by_name(".BTF.ext"),
+    ) {
+        btf::relocate(&mut insns, ".text", btf.raw_data(&elf), ext.raw_data(&elf))?;
+    }
+    let mut log = String::new();
+    let result = bpf_program_create(
+        BPF_PROG_TYPE_UNSPEC,
+        insns,
+        &env,
+        Some(map_fd_table),
+        &mut log,
+    );
+    if result.is_err() {
+        warn!("eBPF program rejected: {}", log);
+    }
+    result
+}
+
+/// Whether a program of `license` may call GPL only helpers, as Linux decides
+pub fn license_is_gpl_compatible(license: &str) -> bool {
+    [
+        "GPL",
+        "GPL v2",
+        "GPL and additional rights",
+        "Dual BSD/GPL",
+        "Dual MIT/GPL",
+        "Dual MPL/GPL",
+    ]
+    .contains(&license)
+}
+
+/// Load a program of BPF_PROG_LOAD, the verifier writes to `log`
+pub fn bpf_program_load(
+    prog_type: u32,
+    insns: Vec<u64>,
+    license: &str,
+    log: &mut String,
+) -> SysResult {
+    match prog_type {
+        BPF_PROG_TYPE_SOCKET_FILTER | BPF_PROG_TYPE_KPROBE | BPF_PROG_TYPE_TRACEPOINT => (),
+        _ => return Err(EINVAL),
+    }
+    if insns.is_empty() {
+        return Err(EINVAL);
+    }
+    if insns.len() > BPF_MAXINSNS {
+        return Err(E2BIG);
+    }
+    let env = VerifierEnv {
+        map_slots: &[],
+        gpl_compatible: license_is_gpl_compatible(license),
+    };
+    bpf_program_create(prog_type, insns, &env, None, log)
+}
+
+fn bpf_program_create(
+    prog_type: u32,
+    mut insns: Vec<u64>,
+    env: &VerifierEnv,
+    map_fd_table: Option<Vec<u32>>,
+    log: &mut String,
+) -> SysResult {
+    let verified = match bpf_verify(&insns, env) {
+        Ok(verified) => verified,
+        Err(err) => {
+            log.push_str(&format!("{}\n", err));
+            return Err(EACCES);
+        }
+    };
+    log.push_str(&format!("processed {} insns\n", verified.processed));
+
+    // maps are loaded by their fds, which helpers take
+    for insn in insns.iter_mut() {
+        let decoded = BpfInsn::decode(*insn);
+        if decoded.is_ld_imm64() && decoded.src == BPF_PSEUDO_MAP_FD {
+            *insn = u64::to_le(u64::from_le(*insn) & !0xf000);
+        }
+    }
+
+    // the JIT knows nothing of tail calls, those programs are interpreted
+    #[cfg(all(feature = "bpf_jit", target_arch = "riscv64"))]
+    let jited_prog = if insns.iter().any(|&insn| {
+        let insn = BpfInsn::decode(insn);
+        insn.opcode == BPF_JMP | BPF_CALL && insn.imm as usize == BPF_FUNC_TAIL_CALL
+    }) {
+        None
+    } else {
+        let mut jit_ctx = compile::JitContext::new(&insns);
+        let helper_fn_table =
+            unsafe { core::mem::transmute::<&[BpfHelperFn], &[u64]>(&HELPER_FN_TABLE) };
+        compile::compile(&mut jit_ctx, helper_fn_table, 512);
+        Some(jit_ctx.code)
+    };
+    #[cfg(not(all(feature = "bpf_jit", target_arch = "riscv64")))]
+    let jited_prog = None;
 
-    let compiled_code = jit_ctx.code; // partial move
     let program = BpfProgram {
-        bpf_insns: None, // currently we do not store original BPF instructions
-        jited_prog: Some(compiled_code),
-        map_fd_table: Some(map_fd_table),
+        prog_type,
+        bpf_insns: insns,
+        jited_prog,
+        map_fd_table,
+        ctx_size: verified.ctx_size,
     };
     let fd = bpf_allocate_fd();
     bpf_object_create_program(fd, program);
     Ok(fd as usize)
 }
-
-// #[cfg(not(target_arch = "riscv64"))]
-// pub fn bpf_program_load_ex(prog: &mut [u8], map_info: &[(String, u32)]) -> SysResult {
-//     Err(EINVAL) // not supported
-// }
@@ -2,17 +2,28 @@ use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use lazy_static::lazy_static;
-use trapframe::TrapFrame;
+use rcore_fs::vfs::INode;
+use trapframe::{TrapFrame, UserContext};
+use xmas_elf::ElfFile;
 
-use crate::kprobes::{register_kprobe, register_kretprobe, KProbeArgs, KRetProbeArgs};
+use crate::fs::{INodeExt, FOLLOW_MAX_DEPTH, ROOT_INODE};
+use crate::kprobes::uprobes::UProbeHandler;
+use crate::kprobes::{
+    register_kprobe, register_kretprobe, register_uprobe, unregister_kprobe, unregister_kretprobe,
+    unregister_uprobe, KProbeArgs, KRetProbeArgs, UProbeArgs,
+};
 use crate::lkm::manager::ModuleManager;
+use crate::net::{attach_filter_program, detach_filter_program, FilterDirection};
+use crate::process::ElfExt;
 use crate::sync::SpinLock as Mutex;
+use crate::sync::SpinNoIrqLock;
 use crate::syscall::{
     SysError::{self, *},
     SysResult,
 };
+use crate::tracepoint::{find_tracepoint, register_probe, unregister_probe};
 
-use super::{BpfObject::*, *};
+use super::{consts::*, BpfObject::*, *};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -26,20 +37,55 @@ pub enum TracepointType {
     KProbe,
     KRetProbeEntry,
     KRetProbeExit,
+    UProbe,
+    URetProbeEntry,
+    URetProbeExit,
 }
 
 use TracepointType::*;
 
 // Current design is very simple and this is only intended for kprobe/kretprobe
+// and their user space counterparts
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Tracepoint {
     pub tp_type: TracepointType,
+    /// device and inode number of the file a uprobe is in
+    pub file: Option<(usize, usize)>,
+    /// the probed address, or the offset in the file for uprobes
     pub token: usize,
 }
 
 impl Tracepoint {
     pub fn new(tp_type: TracepointType, token: usize) -> Self {
-        Self { tp_type, token }
+        Self {
+            tp_type,
+            file: None,
+            token,
+        }
+    }
+
+    pub fn new_user(tp_type: TracepointType, file: (usize, usize), token: usize) -> Self {
+        Self {
+            tp_type,
+            file: Some(file),
+            token,
+        }
+    }
+
+    /// The other end of a kretprobe or uretprobe
+    fn dual(&self) -> Option<Self> {
+        let tp_type = match self.tp_type {
+            KProbe | UProbe => return None,
+            KRetProbeEntry => KRetProbeExit,
+            KRetProbeExit => KRetProbeEntry,
+            URetProbeEntry => URetProbeExit,
+            URetProbeExit => URetProbeEntry,
+        };
+        Some(Self { tp_type, ..*self })
+    }
+
+    fn is_user(&self) -> bool {
+        self.file.is_some()
     }
 }
 
@@ -48,11 +94,27 @@ lazy_static! {
         Mutex::new(BTreeMap::new());
 }
 
-fn run_attached_programs(tracepoint: &Tracepoint, ctx: *const u8) {
+/// Programs on a static tracepoint, shared with the probe running them
+type ProgramList = Arc<SpinNoIrqLock<Vec<Arc<BpfProgram>>>>;
+
+lazy_static! {
+    // keyed by static tracepoint id and filter, probes do not take this lock
+    static ref STATIC_ATTACHED: Mutex<BTreeMap<(u16, Option<usize>), (usize, ProgramList)>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Run the programs attached to `tracepoint` on `ctx`, which they may read all of
+fn run_attached_programs<T>(tracepoint: &Tracepoint, ctx: &T) {
+    let ctx_size = core::mem::size_of::<T>();
+    let ctx = ctx as *const T as *const u8;
     let map = ATTACHED_PROGS.lock();
-    let programs = map.get(tracepoint).unwrap();
+    // a uprobe can still be hit in other threads after detaching
+    let programs = match map.get(tracepoint) {
+        Some(programs) => programs,
+        None => return,
+    };
     for program in programs {
-        let _result = program.run(ctx);
+        let _result = program.run(ctx, ctx_size);
         // error!("run result: {}", result);
     }
 }
@@ -72,37 +134,93 @@ impl KProbeBPFContext {
             tf: tf.clone()
         }
     }
-
-    pub fn as_ptr(&self) -> *const u8 {
-        unsafe { core::mem::transmute(self) }
-    }
 }
 
 fn kprobe_handler(tf: &mut TrapFrame, probed_addr: usize) -> isize {
     let tracepoint = Tracepoint::new(KProbe, probed_addr);
     let ctx = KProbeBPFContext::new(tf, probed_addr, 0);
-    run_attached_programs(&tracepoint, ctx.as_ptr());
+    run_attached_programs(&tracepoint, &ctx);
     0
 }
 
 fn kretprobe_entry_handler(tf: &mut TrapFrame, probed_addr: usize) -> isize {
     let tracepoint = Tracepoint::new(KRetProbeEntry, probed_addr);
     let ctx = KProbeBPFContext::new(tf, probed_addr, 1);
-    run_attached_programs(&tracepoint, ctx.as_ptr());
+    run_attached_programs(&tracepoint, &ctx);
     0
 }
 
 fn kretprobe_exit_handler(tf: &mut TrapFrame, probed_addr: usize) -> isize {
     let tracepoint = Tracepoint::new(KRetProbeExit, probed_addr);
     let ctx = KProbeBPFContext::new(tf, probed_addr, 2);
-    run_attached_programs(&tracepoint, ctx.as_ptr());
+    run_attached_programs(&tracepoint, &ctx);
     0
 }
 
+#[repr(C)]
+struct UProbeBPFContext {
+    ptype: usize,
+    paddr: usize,
+    cx: UserContext,
+}
+
+impl UProbeBPFContext {
+    pub fn new(cx: &UserContext, offset: usize, t: usize) -> Self {
+        UProbeBPFContext {
+            ptype: t,
+            paddr: offset,
+            cx: cx.clone(),
+        }
+    }
+}
+
+fn uprobe_handler(tracepoint: Tracepoint, t: usize) -> Arc<UProbeHandler> {
+    Arc::new(move |cx: &mut UserContext, offset: usize| {
+        let ctx = UProbeBPFContext::new(cx, offset, t);
+        run_attached_programs(&tracepoint, &ctx);
+        0
+    })
+}
+
 fn resolve_symbol(symbol: &str) -> Option<usize> {
     ModuleManager::with(|mm| mm.resolve_symbol(symbol))
 }
 
+/// Find the file and offset of a uprobe target like `/bin/busybox:main` or `/bin/busybox:0x1000`
+fn resolve_user_symbol(target: &str) -> Result<(Arc<dyn INode>, usize), SysError> {
+    let pos = target.rfind(':').ok_or(EINVAL)?;
+    let path = &target[..pos];
+    let symbol = &target[(pos + 1)..];
+    let inode = ROOT_INODE.lookup_follow(path, FOLLOW_MAX_DEPTH)?;
+    if let Some(hex) = symbol.strip_prefix("0x") {
+        let offset = usize::from_str_radix(hex, 16).map_err(|_| EINVAL)?;
+        return Ok((inode, offset));
+    }
+    let data = inode.read_as_vec()?;
+    let elf = ElfFile::new(&data).map_err(|_| ENOEXEC)?;
+    let offset = elf.symbol_offset(symbol).ok_or(ENOENT)?;
+    Ok((inode, offset))
+}
+
+/// The tracepoint of a target parsed by `parse_tracepoint`, with the file of a uprobe
+fn resolve_tracepoint(
+    tp_type: TracepointType,
+    fn_name: &str,
+) -> Result<(Tracepoint, Option<Arc<dyn INode>>), SysError> {
+    match tp_type {
+        KProbe | KRetProbeEntry | KRetProbeExit => {
+            let addr = resolve_symbol(fn_name).ok_or(ENOENT)?;
+            Ok((Tracepoint::new(tp_type, addr), None))
+        }
+        UProbe | URetProbeEntry | URetProbeExit => {
+            let (inode, offset) = resolve_user_symbol(fn_name)?;
+            let metadata = inode.metadata()?;
+            let file = (metadata.dev, metadata.inode);
+            Ok((Tracepoint::new_user(tp_type, file, offset), Some(inode)))
+        }
+    }
+}
+
 fn parse_tracepoint<'a>(target: &'a str) -> Result<(TracepointType, &'a str), SysError> {
     let pos = target.find(':').ok_or(EINVAL)?;
     let type_str = &target[0..pos];
@@ -116,25 +234,100 @@ fn parse_tracepoint<'a>(target: &'a str) -> Result<(TracepointType, &'a str), Sy
         tp_type = KRetProbeEntry;
     } else if type_str.eq_ignore_ascii_case("kretprobe@exit") {
         tp_type = KRetProbeExit;
+    } else if type_str.eq_ignore_ascii_case("uprobe") {
+        tp_type = UProbe;
+    } else if type_str.eq_ignore_ascii_case("uretprobe@entry") {
+        tp_type = URetProbeEntry;
+    } else if type_str.eq_ignore_ascii_case("uretprobe@exit") {
+        tp_type = URetProbeExit;
     } else {
         return Err(EINVAL);
     }
     Ok((tp_type, fn_name))
 }
 
+/// Attach to a static tracepoint, `name` is like `syscalls:sys_enter_openat`
+fn attach_static(name: &str, program: Arc<BpfProgram>) -> SysResult {
+    let (tp, filter) = find_tracepoint(name).ok_or(ENOENT)?;
+    if !program.fits(BPF_PROG_TYPE_TRACEPOINT) || program.ctx_size() > tp.ctx_size {
+        return Err(EINVAL);
+    }
+
+    let mut attached = STATIC_ATTACHED.lock();
+    if let Some((_, programs)) = attached.get(&(tp.id, filter)) {
+        let mut programs = programs.lock();
+        if programs.iter().any(|other| Arc::ptr_eq(&program, other)) {
+            return Err(EAGAIN);
+        }
+        programs.push(program);
+        return Ok(0);
+    }
+    let programs: ProgramList = Arc::new(SpinNoIrqLock::new(vec![program]));
+    let shared = programs.clone();
+    let ctx_size = tp.ctx_size;
+    let handler = Arc::new(move |ctx: *const u8| {
+        for program in shared.lock().iter() {
+            let _result = program.run(ctx, ctx_size);
+        }
+    });
+    let probe = register_probe(tp, filter, handler);
+    attached.insert((tp.id, filter), (probe, programs));
+    Ok(0)
+}
+
+fn detach_static(name: &str, program: &Arc<BpfProgram>) -> SysResult {
+    let (tp, filter) = find_tracepoint(name).ok_or(ENOENT)?;
+    let mut attached = STATIC_ATTACHED.lock();
+    let (probe, programs) = attached.get(&(tp.id, filter)).ok_or(ENOENT)?;
+    let mut list = programs.lock();
+    let index = list
+        .iter()
+        .position(|other| Arc::ptr_eq(program, other))
+        .ok_or(ENOENT)?;
+    list.remove(index);
+
+    // the probe leaves with the last program
+    if list.is_empty() {
+        drop(list);
+        unregister_probe(tp, *probe);
+        attached.remove(&(tp.id, filter));
+    }
+    Ok(0)
+}
+
+fn get_program(prog_fd: u32) -> Result<Arc<BpfProgram>, SysError> {
+    let objs = BPF_OBJECTS.lock();
+    match objs.get(&prog_fd) {
+        Some(Program(shared_program)) => Ok(shared_program.clone()),
+        _ => Err(ENOENT),
+    }
+}
+
 pub fn bpf_program_attach(target: &str, prog_fd: u32) -> SysResult {
     // check program fd
-    let program = {
-        let objs = BPF_OBJECTS.lock();
-        match objs.get(&prog_fd) {
-            Some(Program(shared_program)) => Ok(shared_program.clone()),
-            _ => Err(ENOENT),
+    let program = get_program(prog_fd)?;
+
+    // packet filter hooks are not tracepoints
+    if let Some(direction) = FilterDirection::from_hook(target) {
+        if !program.fits(BPF_PROG_TYPE_SOCKET_FILTER) {
+            return Err(EINVAL);
         }
-    }?;
+        return attach_filter_program(direction, program);
+    }
+    if let Some(name) = target.strip_prefix("tracepoint:") {
+        return attach_static(name, program);
+    }
 
     let (tp_type, fn_name) = parse_tracepoint(target)?;
-    let addr = resolve_symbol(fn_name).ok_or(ENOENT)?;
-    let tracepoint = Tracepoint::new(tp_type, addr);
+    let (tracepoint, inode) = resolve_tracepoint(tp_type, fn_name)?;
+    let ctx_size = match tracepoint.is_user() {
+        false => core::mem::size_of::<KProbeBPFContext>(),
+        true => core::mem::size_of::<UProbeBPFContext>(),
+    };
+    if !program.fits(BPF_PROG_TYPE_KPROBE) || program.ctx_size() > ctx_size {
+        return Err(EINVAL);
+    }
+    let addr = tracepoint.token;
 
     let mut map = ATTACHED_PROGS.lock();
     if let Some(programs) = map.get_mut(&tracepoint) {
@@ -163,17 +356,75 @@ pub fn bpf_program_attach(target: &str, prog_fd: u32) -> SysResult {
                     user_data: addr,
                 };
                 let _ = register_kretprobe(addr, args).ok_or(EINVAL)?;
-
-                let dual_tp: Tracepoint;
-                if tp_type == KRetProbeEntry {
-                    dual_tp = Tracepoint::new(KRetProbeExit, addr);
-                } else {
-                    dual_tp = Tracepoint::new(KRetProbeEntry, addr);
-                }
                 map.insert(tracepoint, vec![program]);
-                map.insert(dual_tp, vec![]);
+                map.insert(tracepoint.dual().unwrap(), vec![]);
+            }
+            UProbe => {
+                let args = UProbeArgs {
+                    handler: Some(uprobe_handler(tracepoint, 3)),
+                    ret_handler: None,
+                    user_data: addr,
+                };
+                let _ = register_uprobe(&inode.unwrap(), addr, args).ok_or(EINVAL)?;
+                map.insert(tracepoint, vec![program]);
+            }
+            URetProbeEntry | URetProbeExit => {
+                let entry = Tracepoint::new_user(URetProbeEntry, tracepoint.file.unwrap(), addr);
+                let args = UProbeArgs {
+                    handler: Some(uprobe_handler(entry, 4)),
+                    ret_handler: Some(uprobe_handler(entry.dual().unwrap(), 5)),
+                    user_data: addr,
+                };
+                let _ = register_uprobe(&inode.unwrap(), addr, args).ok_or(EINVAL)?;
+                map.insert(tracepoint, vec![program]);
+                map.insert(tracepoint.dual().unwrap(), vec![]);
             }
         }
     }
     Ok(0)
 }
+
+pub fn bpf_program_detach(target: &str, prog_fd: u32) -> SysResult {
+    let program = get_program(prog_fd)?;
+    if let Some(direction) = FilterDirection::from_hook(target) {
+        return detach_filter_program(direction, &program);
+    }
+    if let Some(name) = target.strip_prefix("tracepoint:") {
+        return detach_static(name, &program);
+    }
+
+    let (tp_type, fn_name) = parse_tracepoint(target)?;
+    let (tracepoint, inode) = resolve_tracepoint(tp_type, fn_name)?;
+    let addr = tracepoint.token;
+
+    let mut map = ATTACHED_PROGS.lock();
+    let programs = map.get_mut(&tracepoint).ok_or(ENOENT)?;
+    let index = programs
+        .iter()
+        .position(|other| Arc::ptr_eq(&program, other))
+        .ok_or(ENOENT)?;
+    programs.remove(index);
+
+    // unregister the probe when its last program leaves
+    let dual = tracepoint.dual();
+    let unused = map.get(&tracepoint).unwrap().is_empty()
+        && dual.map_or(true, |dual| map.get(&dual).unwrap().is_empty());
+    if !unused {
+        return Ok(0);
+    }
+    let unregistered = match tp_type {
+        KProbe => unregister_kprobe(addr),
+        KRetProbeEntry | KRetProbeExit => unregister_kretprobe(addr),
+        UProbe | URetProbeEntry | URetProbeExit => unregister_uprobe(&inode.unwrap(), addr),
+    };
+    if unregistered.is_none() {
+        // the probe is being hit, keep the program
+        map.get_mut(&tracepoint).unwrap().insert(index, program);
+        return Err(EBUSY);
+    }
+    map.remove(&tracepoint);
+    if let Some(dual) = dual {
+        map.remove(&dual);
+    }
+    Ok(0)
+}
@@ -0,0 +1,1224 @@
+//! Static verifier of eBPF programs
+//!
+//! Programs are checked when they are loaded, before either backend
+//! accepts them. Like the Linux verifier, every path is walked with
+//! the type of each register and stack slot, and the range of each
+//! scalar. Loops are allowed as long as every path ends within
+//! `COMPLEXITY_LIMIT` instructions; a state repeating itself on a
+//! path is an infinite loop. Paths reaching a state no more general
+//! than one verified before are pruned.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::fmt;
+
+use super::consts::*;
+use super::helpers::{HelperArg, HelperRet, HELPER_PROTOS, SPECIAL_MAP_TYPES};
+use super::insn::BpfInsn;
+use super::interpreter::{alu32, alu64, byte_order, jump_taken};
+use super::map::{bpf_map_get_attr, InternalMapAttr};
+
+/// Instructions walked over all paths before giving up
+const COMPLEXITY_LIMIT: usize = 200_000;
+/// States remembered for pruning, at one instruction and in total
+const MAX_STATES_PER_INSN: usize = 16;
+const MAX_STATES: usize = 1024;
+
+#[derive(Debug)]
+pub struct VerifierError {
+    pub pc: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for VerifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.pc, self.msg)
+    }
+}
+
+type VResult<T> = Result<T, VerifierError>;
+
+macro_rules! reject {
+    ($pc:expr, $($arg:tt)*) => {
+        return Err(VerifierError {
+            pc: $pc,
+            msg: format!($($arg)*),
+        })
+    };
+}
+
+/// What the verifier needs to know besides instructions
+#[derive(Debug, Clone, Copy)]
+pub struct VerifierEnv<'a> {
+    /// Addresses BPF_PROG_LOAD_EX relocates map symbols to, with the fds they hold
+    pub map_slots: &'a [(u64, u32)],
+    /// Whether the license lets the program call GPL only helpers
+    pub gpl_compatible: bool,
+}
+
+/// What the verifier found out about a program
+#[derive(Debug, Clone, Copy)]
+pub struct VerifiedProgram {
+    /// Bytes of the context read, attach points must provide as many
+    pub ctx_size: usize,
+    /// Instructions walked over all paths
+    pub processed: usize,
+}
+
+/// Signed range of a scalar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    min: i64,
+    max: i64,
+}
+
+const UNKNOWN: Range = Range {
+    min: i64::MIN,
+    max: i64::MAX,
+};
+const U32_RANGE: Range = Range {
+    min: 0,
+    max: u32::MAX as i64,
+};
+
+impl Range {
+    fn new(min: i64, max: i64) -> Option<Self> {
+        if min <= max {
+            Some(Range { min, max })
+        } else {
+            None
+        }
+    }
+
+    fn constant(value: i64) -> Self {
+        Range {
+            min: value,
+            max: value,
+        }
+    }
+
+    fn value(&self) -> Option<i64> {
+        if self.min == self.max {
+            Some(self.min)
+        } else {
+            None
+        }
+    }
+
+    fn contains(&self, other: &Range) -> bool {
+        self.min <= other.min && other.max <= self.max
+    }
+
+    fn intersect(&self, min: i64, max: i64) -> Option<Self> {
+        Range::new(core::cmp::max(self.min, min), core::cmp::min(self.max, max))
+    }
+
+    fn non_negative(&self) -> bool {
+        self.min >= 0
+    }
+
+    /// Range of the low 32 bits
+    fn low32(&self) -> Self {
+        if self.min >= 0 && self.max <= u32::MAX as i64 {
+            *self
+        } else {
+            U32_RANGE
+        }
+    }
+
+    /// Range of a value loaded from `size` bytes
+    fn of_size(size: usize) -> Self {
+        match size {
+            8 => UNKNOWN,
+            _ => Range {
+                min: 0,
+                max: (1i64 << (size * 8)) - 1,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Uninit,
+    Scalar(Range),
+    /// Pointer to the context, at a constant offset
+    Ctx(i64),
+    /// Pointer into the stack, at a constant offset from r10
+    Stack(i64),
+    /// Pointer into a value of a map. Values from one lookup share an id,
+    /// so checking one of them for NULL tells about every copy.
+    MapValue {
+        fd: u32,
+        size: usize,
+        off: Range,
+        id: u32,
+        null: bool,
+    },
+    /// Address of a map fd slot of BPF_PROG_LOAD_EX
+    MapFdSlot(u32),
+    /// A map fd loaded from its slot
+    MapFd(u32),
+}
+
+use Value::*;
+
+impl Value {
+    fn is_pointer(&self) -> bool {
+        !matches!(self, Uninit | Scalar(_))
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Uninit => "uninit",
+            Scalar(_) => "scalar",
+            Ctx(_) => "ctx",
+            Stack(_) => "fp",
+            MapValue { null: true, .. } => "map_value_or_null",
+            MapValue { .. } => "map_value",
+            MapFdSlot(_) => "map_fd_slot",
+            MapFd(_) => "map_fd",
+        }
+    }
+
+    /// Whether everything safe with `self` is safe with `other`
+    fn covers(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Uninit, _) => true,
+            (Scalar(range), Scalar(other)) => range.contains(other),
+            (
+                MapValue {
+                    fd,
+                    size,
+                    off,
+                    id,
+                    null,
+                },
+                MapValue {
+                    fd: fd2,
+                    size: size2,
+                    off: off2,
+                    id: id2,
+                    null: null2,
+                },
+            ) => {
+                fd == fd2
+                    && size == size2
+                    && null == null2
+                    && (!null || id == id2)
+                    && off.contains(off2)
+            }
+            _ => self == other,
+        }
+    }
+}
+
+/// 8 bytes of stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Slot {
+    /// A register stored as a whole
+    spill: Option<Value>,
+    /// Initialized bytes, one bit each
+    init: u8,
+}
+
+impl Slot {
+    fn covers(&self, other: &Slot) -> bool {
+        if self.init & !other.init != 0 {
+            return false;
+        }
+        match (&self.spill, &other.spill) {
+            (Some(value), Some(other)) => value.covers(other),
+            (Some(_), None) => self.init == 0,
+            (None, Some(other)) => self.init == 0 || !other.is_pointer(),
+            (None, None) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    regs: [Value; 11],
+    /// Slots from r10 downwards, grown as they are written
+    stack: Vec<Slot>,
+}
+
+impl State {
+    fn new() -> Self {
+        let mut regs = [Uninit; 11];
+        regs[1] = Ctx(0);
+        regs[10] = Stack(0);
+        State {
+            regs,
+            stack: Vec::new(),
+        }
+    }
+
+    fn slot(&self, index: usize) -> Slot {
+        self.stack.get(index).copied().unwrap_or_default()
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut Slot {
+        if self.stack.len() <= index {
+            self.stack.resize(index + 1, Slot::default());
+        }
+        &mut self.stack[index]
+    }
+
+    fn equals(&self, other: &State) -> bool {
+        let slots = max(self.stack.len(), other.stack.len());
+        self.regs == other.regs && (0..slots).all(|i| self.slot(i) == other.slot(i))
+    }
+
+    fn covers(&self, other: &State) -> bool {
+        let slots = max(self.stack.len(), other.stack.len());
+        self.regs
+            .iter()
+            .zip(other.regs.iter())
+            .all(|(a, b)| a.covers(b))
+            && (0..slots).all(|i| self.slot(i).covers(&other.slot(i)))
+    }
+
+    /// Apply `f` to every register and spilled register
+    fn for_each_value(&mut self, mut f: impl FnMut(&mut Value)) {
+        self.regs.iter_mut().for_each(&mut f);
+        for slot in self.stack.iter_mut() {
+            if let Some(value) = &mut slot.spill {
+                f(value);
+            }
+        }
+    }
+
+    /// Values of lookup `id` are known to be NULL or not
+    fn mark_null(&mut self, id: u32, is_null: bool) {
+        self.for_each_value(|value| {
+            if let MapValue {
+                id: other, null, ..
+            } = value
+            {
+                if *other == id && *null {
+                    if is_null {
+                        *value = Scalar(Range::constant(0));
+                    } else {
+                        *null = false;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Stack slot and bit of the byte at `off` from r10
+fn stack_byte(off: i64) -> (usize, u8) {
+    let depth = (-off - 1) as usize;
+    (depth / 8, 1 << (7 - depth % 8))
+}
+
+fn jump_target(pc: usize, insn: &BpfInsn) -> usize {
+    (pc as i64 + 1 + insn.off as i64) as usize
+}
+
+struct Checkpoint {
+    state: State,
+    parent: Option<usize>,
+    /// Paths from here still being walked
+    branches: usize,
+}
+
+struct Path {
+    pc: usize,
+    state: State,
+    /// Closest checkpoint walked through
+    parent: Option<usize>,
+}
+
+enum Access {
+    Read,
+    Write(Value),
+    Atomic,
+}
+
+enum Step {
+    Next,
+    Jump(usize),
+    Exit,
+    /// Continue, and walk the other path later
+    Branch(usize, State),
+}
+
+struct Verifier<'a> {
+    insns: Vec<BpfInsn>,
+    env: &'a VerifierEnv<'a>,
+    prune_points: Vec<bool>,
+    checkpoints: Vec<Checkpoint>,
+    visited: BTreeMap<usize, Vec<usize>>,
+    next_id: u32,
+    ctx_size: usize,
+    processed: usize,
+}
+
+/// Check a program before it is run
+pub fn bpf_verify(raw_insns: &[u64], env: &VerifierEnv) -> VResult<VerifiedProgram> {
+    let insns = raw_insns
+        .iter()
+        .map(|&insn| BpfInsn::decode(insn))
+        .collect();
+    let mut verifier = Verifier {
+        insns,
+        env,
+        prune_points: vec![false; raw_insns.len()],
+        checkpoints: Vec::new(),
+        visited: BTreeMap::new(),
+        next_id: 1,
+        ctx_size: 0,
+        processed: 0,
+    };
+    verifier.check_structure()?;
+    verifier.walk()?;
+    Ok(VerifiedProgram {
+        ctx_size: verifier.ctx_size,
+        processed: verifier.processed,
+    })
+}
+
+impl<'a> Verifier<'a> {
+    /// Check opcodes, registers and jump targets
+    fn check_structure(&mut self) -> VResult<()> {
+        let len = self.insns.len();
+        if len == 0 {
+            reject!(0, "empty program");
+        }
+        if len > BPF_MAXINSNS {
+            reject!(0, "program of {} insns is too large", len);
+        }
+        // second halves of 64-bit loads are not instructions
+        let mut second_half = vec![false; len];
+        let mut pc = 0;
+        while pc < len {
+            if self.insns[pc].is_ld_imm64() {
+                match self.insns.get(pc + 1) {
+                    Some(next) if next.opcode == 0 && next.dst == 0 && next.src == 0 => {
+                        second_half[pc + 1] = true
+                    }
+                    _ => reject!(pc, "invalid BPF_LD_IMM insn"),
+                }
+                pc += 1;
+            }
+            pc += 1;
+        }
+
+        for (pc, insn) in self.insns.iter().enumerate() {
+            if second_half[pc] {
+                continue;
+            }
+            if insn.dst > 10 || insn.src > 10 {
+                reject!(pc, "invalid register");
+            }
+            let valid = match insn.class() {
+                BPF_LD => {
+                    insn.is_ld_imm64()
+                        && (insn.src == 0 || insn.src == BPF_PSEUDO_MAP_FD)
+                        && insn.off == 0
+                }
+                BPF_LDX => insn.mode() == BPF_MEM && insn.imm == 0,
+                BPF_ST => insn.mode() == BPF_MEM && insn.src == 0,
+                BPF_STX => match insn.mode() {
+                    BPF_MEM => insn.imm == 0,
+                    BPF_ATOMIC => {
+                        let size_ok = match insn.size() {
+                            4 => true,
+                            8 => cfg!(target_has_atomic = "64"),
+                            _ => false,
+                        };
+                        size_ok && insn.imm == BPF_ADD as i32
+                    }
+                    _ => false,
+                },
+                BPF_ALU | BPF_ALU64 => match insn.op() {
+                    BPF_NEG => insn.source() == BPF_K && insn.src == 0 && insn.imm == 0,
+                    BPF_END => {
+                        insn.class() == BPF_ALU && [16, 32, 64].contains(&insn.imm) && insn.src == 0
+                    }
+                    BPF_DIV | BPF_MOD if insn.source() == BPF_K => insn.imm != 0,
+                    BPF_LSH | BPF_RSH | BPF_ARSH if insn.source() == BPF_K => {
+                        let bits = if insn.class() == BPF_ALU64 { 64 } else { 32 };
+                        0 <= insn.imm && insn.imm < bits
+                    }
+                    op => op <= BPF_ARSH && (insn.source() == BPF_X || insn.src == 0),
+                },
+                _ => match insn.op() {
+                    BPF_CALL => insn.class() == BPF_JMP && insn.dst == 0 && insn.off == 0,
+                    BPF_EXIT => insn.class() == BPF_JMP && insn.dst == 0 && insn.src == 0,
+                    BPF_JA => insn.class() == BPF_JMP && insn.dst == 0 && insn.src == 0,
+                    op => op <= BPF_JSLE && (insn.source() == BPF_X || insn.src == 0),
+                },
+            };
+            if !valid {
+                reject!(pc, "invalid insn, opcode {:#x}", insn.opcode);
+            }
+
+            let is_jump = matches!(insn.class(), BPF_JMP | BPF_JMP32)
+                && insn.op() != BPF_CALL
+                && insn.op() != BPF_EXIT;
+            if is_jump {
+                let target = pc as i64 + 1 + insn.off as i64;
+                if target < 0 || target >= len as i64 || second_half[target as usize] {
+                    reject!(pc, "jump out of range to {}", target);
+                }
+                self.prune_points[target as usize] = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk every path of the program
+    fn walk(&mut self) -> VResult<()> {
+        let mut paths = vec![Path {
+            pc: 0,
+            state: State::new(),
+            parent: None,
+        }];
+        while let Some(mut path) = paths.pop() {
+            loop {
+                self.processed += 1;
+                if self.processed > COMPLEXITY_LIMIT {
+                    reject!(path.pc, "program is too complex, or loops without bound");
+                }
+                if path.pc >= self.insns.len() {
+                    reject!(path.pc, "runs past the end of the program");
+                }
+                if self.prune_points[path.pc] && self.checkpoint(&mut path)? {
+                    self.finish(path.parent);
+                    break;
+                }
+                match self.step(path.pc, &mut path.state)? {
+                    Step::Next => {
+                        path.pc += if self.insns[path.pc].is_ld_imm64() {
+                            2
+                        } else {
+                            1
+                        };
+                    }
+                    Step::Jump(target) => path.pc = target,
+                    Step::Exit => {
+                        self.finish(path.parent);
+                        break;
+                    }
+                    Step::Branch(pc, state) => {
+                        if let Some(parent) = path.parent {
+                            self.checkpoints[parent].branches += 1;
+                        }
+                        paths.push(Path {
+                            pc,
+                            state,
+                            parent: path.parent,
+                        });
+                        path.pc += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare `path` with the states seen here, return whether to prune it
+    fn checkpoint(&mut self, path: &mut Path) -> VResult<bool> {
+        let seen = self.visited.entry(path.pc).or_insert_with(Vec::new);
+        for &index in seen.iter() {
+            let checkpoint = &self.checkpoints[index];
+            if checkpoint.branches == 0 {
+                // every path from there is safe
+                if checkpoint.state.covers(&path.state) {
+                    return Ok(true);
+                }
+            } else if checkpoint.state.equals(&path.state) {
+                // still walking from there, so it is on this path
+                reject!(path.pc, "infinite loop detected");
+            }
+        }
+        if seen.len() < MAX_STATES_PER_INSN && self.checkpoints.len() < MAX_STATES {
+            seen.push(self.checkpoints.len());
+            self.checkpoints.push(Checkpoint {
+                state: path.state.clone(),
+                parent: path.parent,
+                branches: 1,
+            });
+            path.parent = Some(self.checkpoints.len() - 1);
+        }
+        Ok(false)
+    }
+
+    /// A path from `parent` has ended
+    fn finish(&mut self, mut parent: Option<usize>) {
+        while let Some(index) = parent {
+            let checkpoint = &mut self.checkpoints[index];
+            checkpoint.branches -= 1;
+            if checkpoint.branches > 0 {
+                break;
+            }
+            parent = checkpoint.parent;
+        }
+    }
+
+    fn read(&self, pc: usize, state: &State, reg: usize) -> VResult<Value> {
+        match state.regs[reg] {
+            Uninit => reject!(pc, "R{} !read_ok", reg),
+            value => Ok(value),
+        }
+    }
+
+    fn step(&mut self, pc: usize, state: &mut State) -> VResult<Step> {
+        let insn = self.insns[pc];
+        let writes_dst = matches!(insn.class(), BPF_LD | BPF_LDX | BPF_ALU | BPF_ALU64);
+        if writes_dst && insn.dst == 10 {
+            reject!(pc, "frame pointer is read only");
+        }
+        match insn.class() {
+            BPF_ALU | BPF_ALU64 => self.check_alu(pc, &insn, state)?,
+            BPF_LD => {
+                let imm = insn.imm64(&self.insns[pc + 1]);
+                let slot = self.env.map_slots.iter().find(|slot| slot.0 == imm);
+                state.regs[insn.dst] = match slot {
+                    _ if insn.src == BPF_PSEUDO_MAP_FD => {
+                        let fd = insn.imm as u32;
+                        if imm != fd as u64 || bpf_map_get_attr(fd).is_none() {
+                            reject!(pc, "fd {} is not pointing to valid bpf_map", insn.imm);
+                        }
+                        MapFd(fd)
+                    }
+                    Some(&(_, fd)) => MapFdSlot(fd),
+                    None => Scalar(Range::constant(imm as i64)),
+                };
+            }
+            BPF_LDX => {
+                let ptr = self.read(pc, state, insn.src)?;
+                let value = self.check_mem(pc, state, ptr, insn.off, insn.size(), Access::Read)?;
+                state.regs[insn.dst] = value;
+            }
+            BPF_ST | BPF_STX => {
+                let ptr = self.read(pc, state, insn.dst)?;
+                let access = if insn.class() == BPF_ST {
+                    Access::Write(Scalar(Range::constant(insn.imm as i64)))
+                } else {
+                    let value = self.read(pc, state, insn.src)?;
+                    if insn.mode() == BPF_MEM {
+                        Access::Write(value)
+                    } else if value.is_pointer() {
+                        reject!(pc, "R{} atomic add of a pointer", insn.src);
+                    } else {
+                        Access::Atomic
+                    }
+                };
+                self.check_mem(pc, state, ptr, insn.off, insn.size(), access)?;
+            }
+            _ => match insn.op() {
+                BPF_JA => return Ok(Step::Jump(jump_target(pc, &insn))),
+                BPF_CALL => self.check_call(pc, &insn, state)?,
+                BPF_EXIT => {
+                    self.read(pc, state, 0)?;
+                    return Ok(Step::Exit);
+                }
+                _ => return self.check_cond_jump(pc, &insn, state),
+            },
+        }
+        Ok(Step::Next)
+    }
+
+    fn check_alu(&mut self, pc: usize, insn: &BpfInsn, state: &mut State) -> VResult<()> {
+        let is64 = insn.class() == BPF_ALU64;
+        let op = insn.op();
+        let (dst, src) = (insn.dst, insn.src);
+        let imm = if is64 {
+            insn.imm as i64
+        } else {
+            insn.imm as u32 as i64
+        };
+        let operand = if insn.source() == BPF_X {
+            self.read(pc, state, src)?
+        } else {
+            Scalar(Range::constant(imm))
+        };
+        if op == BPF_MOV {
+            state.regs[dst] = match operand {
+                Scalar(range) if !is64 => Scalar(range.low32()),
+                value if !is64 && value.is_pointer() => Scalar(U32_RANGE),
+                value => value,
+            };
+            return Ok(());
+        }
+
+        let value = self.read(pc, state, dst)?;
+        state.regs[dst] = match (value, operand) {
+            (Scalar(range), _) if op == BPF_NEG || op == BPF_END => Scalar(match range.value() {
+                Some(value) if op == BPF_NEG && is64 => Range::constant(value.wrapping_neg()),
+                Some(value) if op == BPF_NEG => {
+                    Range::constant((value as i32).wrapping_neg() as u32 as i64)
+                }
+                Some(value) => {
+                    Range::constant(
+                        byte_order(value as u64, insn.source() == BPF_TO_BE, insn.imm) as i64,
+                    )
+                }
+                None if op == BPF_END => Range::of_size(insn.imm as usize / 8),
+                None if is64 => UNKNOWN,
+                None => U32_RANGE,
+            }),
+            (Scalar(range), Scalar(operand)) => Scalar(scalar_alu(op, range, operand, is64)),
+            (_, _) if !is64 => reject!(pc, "R{} 32-bit arithmetic on pointers prohibited", dst),
+            (ptr, Scalar(operand)) if op == BPF_ADD || op == BPF_SUB => {
+                self.offset_pointer(pc, dst, ptr, operand, op == BPF_SUB)?
+            }
+            (Scalar(range), ptr) if op == BPF_ADD => {
+                self.offset_pointer(pc, dst, ptr, range, false)?
+            }
+            (Ctx(_), Ctx(_)) | (Stack(_), Stack(_)) if op == BPF_SUB => Scalar(UNKNOWN),
+            (ptr, _) => reject!(
+                pc,
+                "R{} pointer arithmetic on {} prohibited",
+                dst,
+                ptr.type_name()
+            ),
+        };
+        Ok(())
+    }
+
+    /// Add or subtract `range` to a pointer
+    fn offset_pointer(
+        &self,
+        pc: usize,
+        reg: usize,
+        ptr: Value,
+        range: Range,
+        sub: bool,
+    ) -> VResult<Value> {
+        let range = if sub {
+            match (range.max.checked_neg(), range.min.checked_neg()) {
+                (Some(min), Some(max)) => Range { min, max },
+                _ => reject!(pc, "R{} pointer offset out of bounds", reg),
+            }
+        } else {
+            range
+        };
+        let value = match ptr {
+            Ctx(off) | Stack(off) => match range.value().and_then(|value| off.checked_add(value)) {
+                Some(off) if matches!(ptr, Ctx(_)) => Ctx(off),
+                Some(off) => Stack(off),
+                None => reject!(
+                    pc,
+                    "R{} variable offset of {} pointer prohibited",
+                    reg,
+                    ptr.type_name()
+                ),
+            },
+            MapValue {
+                fd,
+                size,
+                off,
+                id,
+                null: false,
+            } => match (
+                off.min.checked_add(range.min),
+                off.max.checked_add(range.max),
+            ) {
+                (Some(min), Some(max)) => MapValue {
+                    fd,
+                    size,
+                    off: Range { min, max },
+                    id,
+                    null: false,
+                },
+                _ => reject!(pc, "R{} map value offset out of bounds", reg),
+            },
+            _ => reject!(
+                pc,
+                "R{} pointer arithmetic on {} prohibited",
+                reg,
+                ptr.type_name()
+            ),
+        };
+        Ok(value)
+    }
+
+    /// Check a memory access of `size` bytes at `ptr` plus `off`,
+    /// return what is loaded
+    fn check_mem(
+        &mut self,
+        pc: usize,
+        state: &mut State,
+        ptr: Value,
+        off: i16,
+        size: usize,
+        access: Access,
+    ) -> VResult<Value> {
+        let off = off as i64;
+        let loaded = Scalar(Range::of_size(size));
+        match ptr {
+            Ctx(base) => {
+                let start = base + off;
+                if start < 0 || start % size as i64 != 0 {
+                    reject!(pc, "invalid ctx access off={} size={}", start, size);
+                }
+                if !matches!(access, Access::Read) {
+                    reject!(pc, "ctx is read only");
+                }
+                self.ctx_size = max(self.ctx_size, start as usize + size);
+                Ok(loaded)
+            }
+            Stack(base) => {
+                let start = base + off;
+                if start < -(BPF_STACK_SIZE as i64) || start + size as i64 > 0 {
+                    reject!(pc, "invalid stack access off={} size={}", start, size);
+                }
+                let (index, _) = stack_byte(start);
+                let whole_slot = size == 8 && start % 8 == 0;
+                match access {
+                    Access::Read => {
+                        if whole_slot {
+                            if let Some(value) = state.slot(index).spill {
+                                return Ok(value);
+                            }
+                        }
+                        self.check_stack_init(pc, state, start, size)?;
+                        Ok(loaded)
+                    }
+                    Access::Write(value) => {
+                        if whole_slot {
+                            *state.slot_mut(index) = Slot {
+                                spill: Some(value),
+                                init: 0xff,
+                            };
+                        } else if value.is_pointer() {
+                            reject!(pc, "partial spill of {} prohibited", value.type_name());
+                        } else {
+                            mark_stack_init(state, start, size);
+                        }
+                        Ok(Uninit)
+                    }
+                    Access::Atomic => {
+                        if start % size as i64 != 0 {
+                            reject!(pc, "misaligned atomic stack access off={}", start);
+                        }
+                        if matches!(state.slot(index).spill, Some(value) if value.is_pointer()) {
+                            reject!(pc, "atomic add to a spilled pointer prohibited");
+                        }
+                        self.check_stack_init(pc, state, start, size)?;
+                        mark_stack_init(state, start, size);
+                        Ok(Uninit)
+                    }
+                }
+            }
+            MapValue {
+                size: value_size,
+                off: range,
+                null: false,
+                ..
+            } => {
+                let start = range.min.checked_add(off);
+                let end = range.max.checked_add(off + size as i64);
+                match (start, end) {
+                    (Some(start), Some(end)) if start >= 0 && end <= value_size as i64 => (),
+                    _ => reject!(
+                        pc,
+                        "invalid access to map value, value_size={} off={}..{} size={}",
+                        value_size,
+                        range.min,
+                        range.max,
+                        size
+                    ),
+                }
+                match access {
+                    Access::Write(value) if value.is_pointer() => {
+                        reject!(pc, "storing {} into maps prohibited", value.type_name());
+                    }
+                    // map values are 8-byte aligned
+                    Access::Atomic => match range.value() {
+                        Some(start) if (start + off) % size as i64 == 0 => (),
+                        _ => reject!(
+                            pc,
+                            "misaligned atomic access to map value off={}..{}",
+                            range.min + off,
+                            range.max + off
+                        ),
+                    },
+                    _ => (),
+                }
+                Ok(loaded)
+            }
+            MapFdSlot(fd) if off == 0 && size == 4 && matches!(access, Access::Read) => {
+                Ok(MapFd(fd))
+            }
+            _ => reject!(pc, "invalid mem access '{}'", ptr.type_name()),
+        }
+    }
+
+    fn check_stack_init(&self, pc: usize, state: &State, start: i64, size: usize) -> VResult<()> {
+        for off in start..start + size as i64 {
+            let (index, bit) = stack_byte(off);
+            if state.slot(index).init & bit == 0 {
+                reject!(pc, "invalid read from uninitialized stack off={}", off);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check memory a helper reads or writes, from the pointer in `reg`
+    fn check_helper_mem(
+        &mut self,
+        pc: usize,
+        state: &mut State,
+        reg: usize,
+        size: usize,
+        write: bool,
+    ) -> VResult<()> {
+        let ptr = self.read(pc, state, reg)?;
+        match ptr {
+            Stack(start) => {
+                if start < -(BPF_STACK_SIZE as i64) || start + size as i64 > 0 {
+                    reject!(
+                        pc,
+                        "R{} invalid indirect access to stack off={} size={}",
+                        reg,
+                        start,
+                        size
+                    );
+                }
+                if write {
+                    mark_stack_init(state, start, size);
+                } else {
+                    self.check_stack_init(pc, state, start, size)?;
+                }
+                Ok(())
+            }
+            MapValue { null: false, .. } if size > 0 => {
+                let access = if write {
+                    Access::Write(Scalar(UNKNOWN))
+                } else {
+                    Access::Read
+                };
+                // the first and the last byte bound the whole access
+                self.check_mem(pc, state, ptr, 0, 1, Access::Read)?;
+                self.check_mem(pc, state, ptr, (size - 1) as i16, 1, access)
+                    .map(|_| ())
+            }
+            MapValue { null: false, .. } => Ok(()),
+            _ => reject!(
+                pc,
+                "R{} type={} expected=fp or map_value",
+                reg,
+                ptr.type_name()
+            ),
+        }
+    }
+
+    fn check_call(&mut self, pc: usize, insn: &BpfInsn, state: &mut State) -> VResult<()> {
+        if insn.src != 0 {
+            reject!(pc, "calls of BPF functions are not supported");
+        }
+        let proto = match HELPER_PROTOS.get(insn.imm as u32 as usize) {
+            Some(Some(proto)) => *proto,
+            _ => reject!(pc, "invalid func {}", insn.imm),
+        };
+        if proto.gpl_only && !self.env.gpl_compatible {
+            reject!(
+                pc,
+                "cannot call GPL-restricted function {} from non-GPL compatible program",
+                insn.imm
+            );
+        }
+        let mut map: Option<(u32, InternalMapAttr)> = None;
+        for (i, &arg) in proto.args.iter().enumerate() {
+            let reg = i + 1;
+            match arg {
+                HelperArg::DontCare | HelperArg::ConstSize => (),
+                HelperArg::Anything => {
+                    self.read(pc, state, reg)?;
+                }
+                HelperArg::Map => {
+                    let fd = match self.read(pc, state, reg)? {
+                        MapFd(fd) => Some(fd),
+                        _ => None,
+                    };
+                    match fd.and_then(|fd| Some((fd, bpf_map_get_attr(fd)?))) {
+                        Some(found) => map = Some(found),
+                        None => reject!(pc, "R{} is not a map", reg),
+                    }
+                    let map_type = map.unwrap().1.map_type;
+                    let compatible = match proto.map_type {
+                        Some(expected) => map_type == expected,
+                        None => !SPECIAL_MAP_TYPES.contains(&map_type),
+                    };
+                    if !compatible {
+                        reject!(
+                            pc,
+                            "cannot pass map_type {} into func {}",
+                            map_type,
+                            insn.imm
+                        );
+                    }
+                }
+                HelperArg::Ctx => match self.read(pc, state, reg)? {
+                    Ctx(0) => (),
+                    value => reject!(pc, "R{} type={} expected=ctx", reg, value.type_name()),
+                },
+                HelperArg::MapKey | HelperArg::MapValue => {
+                    let attr = match map {
+                        Some((_, attr)) => attr,
+                        None => reject!(pc, "R{} has no map to go with", reg),
+                    };
+                    let size = if arg == HelperArg::MapKey {
+                        attr.key_size
+                    } else {
+                        attr.value_size
+                    };
+                    self.check_helper_mem(pc, state, reg, size, false)?;
+                }
+                HelperArg::Mem | HelperArg::UninitMem => {
+                    let size = match self.read(pc, state, reg + 1)? {
+                        Scalar(range) if range.non_negative() && range.max <= i16::MAX as i64 => {
+                            range.max as usize
+                        }
+                        _ => reject!(pc, "R{} is not a bounded size", reg + 1),
+                    };
+                    self.check_helper_mem(pc, state, reg, size, arg == HelperArg::UninitMem)?;
+                }
+            }
+        }
+
+        // caller saved registers are clobbered
+        for reg in 1..=5 {
+            state.regs[reg] = Uninit;
+        }
+        state.regs[0] = match (proto.ret, map) {
+            (HelperRet::MapValueOrNull, Some((fd, attr))) => {
+                self.next_id += 1;
+                MapValue {
+                    fd,
+                    size: attr.value_size,
+                    off: Range::constant(0),
+                    id: self.next_id,
+                    null: true,
+                }
+            }
+            _ => Scalar(UNKNOWN),
+        };
+        Ok(())
+    }
+
+    fn check_cond_jump(&mut self, pc: usize, insn: &BpfInsn, state: &mut State) -> VResult<Step> {
+        let target = jump_target(pc, insn);
+        let jmp32 = insn.class() == BPF_JMP32;
+        let op = insn.op();
+        let dst = self.read(pc, state, insn.dst)?;
+        let src = if insn.source() == BPF_X {
+            self.read(pc, state, insn.src)?
+        } else {
+            Scalar(Range::constant(insn.imm as i64))
+        };
+
+        let mut taken = state.clone();
+        let mut not_taken = state.clone();
+        let (taken_ok, not_taken_ok) = match (dst, src) {
+            (Scalar(a), Scalar(b)) => match (a.value(), b.value()) {
+                (Some(x), Some(y)) => {
+                    let jump = jump_taken(op, x as u64, y as u64, jmp32);
+                    (jump, !jump)
+                }
+                _ if jmp32 => (true, true),
+                (None, Some(k)) => refine_branches(&mut taken, &mut not_taken, insn.dst, a, op, k),
+                (Some(k), None) => {
+                    let op = swap_operands(op);
+                    refine_branches(&mut taken, &mut not_taken, insn.src, b, op, k)
+                }
+                (None, None) => (true, true),
+            },
+            (MapValue { id, null: true, .. }, Scalar(zero))
+                if zero.value() == Some(0) && !jmp32 && (op == BPF_JEQ || op == BPF_JNE) =>
+            {
+                taken.mark_null(id, op == BPF_JEQ);
+                not_taken.mark_null(id, op == BPF_JNE);
+                (true, true)
+            }
+            _ => (true, true),
+        };
+
+        match (taken_ok, not_taken_ok) {
+            (true, true) => {
+                *state = not_taken;
+                Ok(Step::Branch(target, taken))
+            }
+            (true, false) => {
+                *state = taken;
+                Ok(Step::Jump(target))
+            }
+            _ => {
+                *state = not_taken;
+                Ok(Step::Next)
+            }
+        }
+    }
+}
+
+fn mark_stack_init(state: &mut State, start: i64, size: usize) {
+    for off in start..start + size as i64 {
+        let (index, bit) = stack_byte(off);
+        let slot = state.slot_mut(index);
+        slot.spill = None;
+        slot.init |= bit;
+    }
+}
+
+/// Narrow the range of `reg` on both branches of `reg op k`,
+/// return whether each branch can be taken
+fn refine_branches(
+    taken: &mut State,
+    not_taken: &mut State,
+    reg: usize,
+    range: Range,
+    op: u8,
+    k: i64,
+) -> (bool, bool) {
+    let jump = refine(range, op, k, true);
+    let fall = refine(range, op, k, false);
+    if let Some(range) = jump {
+        taken.regs[reg] = Scalar(range);
+    }
+    if let Some(range) = fall {
+        not_taken.regs[reg] = Scalar(range);
+    }
+    (jump.is_some(), fall.is_some())
+}
+
+/// `k op x` is `x swapped op k`
+fn swap_operands(op: u8) -> u8 {
+    match op {
+        BPF_JGT => BPF_JLT,
+        BPF_JLT => BPF_JGT,
+        BPF_JGE => BPF_JLE,
+        BPF_JLE => BPF_JGE,
+        BPF_JSGT => BPF_JSLT,
+        BPF_JSLT => BPF_JSGT,
+        BPF_JSGE => BPF_JSLE,
+        BPF_JSLE => BPF_JSGE,
+        op => op,
+    }
+}
+
+/// Range of `x` where `x op k` is `taken`, None if it cannot be
+fn refine(x: Range, op: u8, k: i64, taken: bool) -> Option<Range> {
+    let op = if taken {
+        op
+    } else {
+        match op {
+            BPF_JEQ => BPF_JNE,
+            BPF_JNE => BPF_JEQ,
+            BPF_JGT => BPF_JLE,
+            BPF_JLE => BPF_JGT,
+            BPF_JGE => BPF_JLT,
+            BPF_JLT => BPF_JGE,
+            BPF_JSGT => BPF_JSLE,
+            BPF_JSLE => BPF_JSGT,
+            BPF_JSGE => BPF_JSLT,
+            BPF_JSLT => BPF_JSGE,
+            // nothing to learn from BPF_JSET
+            _ => return Some(x),
+        }
+    };
+    match op {
+        BPF_JEQ => x.intersect(k, k),
+        BPF_JNE if x.value() == Some(k) => None,
+        BPF_JNE if x.min == k => x.intersect(k + 1, i64::MAX),
+        BPF_JNE if x.max == k => x.intersect(i64::MIN, k - 1),
+        BPF_JSGT => x.intersect(k.checked_add(1)?, i64::MAX),
+        BPF_JSGE => x.intersect(k, i64::MAX),
+        BPF_JSLT => x.intersect(i64::MIN, k.checked_sub(1)?),
+        BPF_JSLE => x.intersect(i64::MIN, k),
+        // unsigned comparisons, as signed ranges when they are contiguous
+        BPF_JLT if k > 0 => x.intersect(0, k - 1),
+        BPF_JLT if k == 0 => None,
+        BPF_JLE if k >= 0 => x.intersect(0, k),
+        BPF_JLT if x.max < 0 => x.intersect(i64::MIN, k.checked_sub(1)?),
+        BPF_JLE if x.max < 0 => x.intersect(i64::MIN, k),
+        BPF_JGT if k < 0 => x.intersect(k.checked_add(1)?, -1),
+        BPF_JGE if k < 0 => x.intersect(k, -1),
+        BPF_JGT if x.non_negative() => x.intersect(k.checked_add(1)?, i64::MAX),
+        BPF_JGE if x.non_negative() => x.intersect(k, i64::MAX),
+        _ => Some(x),
+    }
+}
+
+/// Range of a scalar ALU operation
+fn scalar_alu(op: u8, a: Range, b: Range, is64: bool) -> Range {
+    if let (Some(x), Some(y)) = (a.value(), b.value()) {
+        let result = if is64 {
+            alu64(op, x as u64, y as u64)
+        } else {
+            alu32(op, x as u64, y as u64)
+        };
+        return Range::constant(result as i64);
+    }
+    if is64 {
+        range_alu(op, a, b, 63).unwrap_or(UNKNOWN)
+    } else {
+        range_alu(op, a.low32(), b.low32(), 31)
+            .map(|range| range.low32())
+            .unwrap_or(U32_RANGE)
+    }
+}
+
+fn range_alu(op: u8, a: Range, b: Range, shift_mask: i64) -> Option<Range> {
+    let both_non_negative = a.non_negative() && b.non_negative();
+    match op {
+        BPF_ADD => Range::new(a.min.checked_add(b.min)?, a.max.checked_add(b.max)?),
+        BPF_SUB => Range::new(a.min.checked_sub(b.max)?, a.max.checked_sub(b.min)?),
+        BPF_MUL if both_non_negative => Range::new(a.min * b.min, a.max.checked_mul(b.max)?),
+        BPF_DIV if a.non_negative() => match b.value() {
+            Some(k) if k > 0 => Range::new(a.min / k, a.max / k),
+            _ => Range::new(0, a.max),
+        },
+        // modulo by zero keeps the dividend
+        BPF_MOD if b.min > 0 && a.non_negative() => Range::new(0, min(a.max, b.max - 1)),
+        BPF_MOD if b.min > 0 => Range::new(0, b.max - 1),
+        BPF_MOD if both_non_negative => Range::new(0, a.max),
+        BPF_AND if a.non_negative() || b.non_negative() => {
+            let bound = match (a.non_negative(), b.non_negative()) {
+                (true, true) => min(a.max, b.max),
+                (true, false) => a.max,
+                _ => b.max,
+            };
+            Range::new(0, bound)
+        }
+        BPF_OR | BPF_XOR if both_non_negative => {
+            // no bit above the highest of either operand is set
+            let bits = 64 - (max(a.max, b.max) as u64).leading_zeros();
+            let bound = ((1u64 << bits) - 1) as i64;
+            let low = if op == BPF_OR { max(a.min, b.min) } else { 0 };
+            Range::new(low, bound)
+        }
+        BPF_LSH if a.non_negative() => {
+            let shift = b.value()? & shift_mask;
+            if a.max > i64::MAX >> shift {
+                return None;
+            }
+            Range::new(a.min << shift, a.max << shift)
+        }
+        BPF_RSH => match b.value() {
+            Some(shift) if a.non_negative() => {
+                let shift = shift & shift_mask;
+                Range::new(a.min >> shift, a.max >> shift)
+            }
+            Some(shift) if shift & shift_mask > 0 => {
+                Range::new(0, (u64::MAX >> (shift & shift_mask)) as i64)
+            }
+            _ if a.non_negative() => Range::new(0, a.max),
+            _ => None,
+        },
+        BPF_ARSH if shift_mask == 63 => match b.value() {
+            Some(shift) => Range::new(a.min >> (shift & 63), a.max >> (shift & 63)),
+            None => Range::new(min(a.min, 0), max(a.max, 0)),
+        },
+        _ => None,
+    }
+}
@@ -1,4 +1,6 @@
 use crate::sync::Condvar;
+use crate::syscall::SysError::EIO;
+use crate::tracepoint::trace_block_rq;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -91,14 +93,22 @@ pub struct BlockDriverWrapper(pub Arc<dyn BlockDriver>);
 impl BlockDevice for BlockDriverWrapper {
     const BLOCK_SIZE_LOG2: u8 = 9; // 512
     fn read_at(&self, block_id: usize, buf: &mut [u8]) -> dev::Result<()> {
-        match self.0.read_block(block_id, buf) {
+        trace_block_rq(block_id, buf.len(), false, None);
+        let ok = self.0.read_block(block_id, buf);
+        let error = if ok { 0 } else { -(EIO as i32) };
+        trace_block_rq(block_id, buf.len(), false, Some(error));
+        match ok {
             true => Ok(()),
             false => Err(DevError),
         }
     }
 
     fn write_at(&self, block_id: usize, buf: &[u8]) -> dev::Result<()> {
-        match self.0.write_block(block_id, buf) {
+        trace_block_rq(block_id, buf.len(), true, None);
+        let ok = self.0.write_block(block_id, buf);
+        let error = if ok { 0 } else { -(EIO as i32) };
+        trace_block_rq(block_id, buf.len(), true, Some(error));
+        match ok {
             true => Ok(()),
             false => Err(DevError),
         }
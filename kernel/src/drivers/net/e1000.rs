@@ -5,6 +5,7 @@ use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use smoltcp::iface::*;
 use smoltcp::phy::{self, DeviceCapabilities};
@@ -17,12 +18,12 @@ use isomorphic_drivers::net::ethernet::structs::EthernetAddress as DriverEtherne
 use rcore_memory::PAGE_SIZE;
 
 use crate::drivers::{provider::Provider, BlockDriver};
-use crate::net::SOCKETS;
+use crate::net::{filter_frame, notify_activity, FilterDirection, SOCKETS};
 use crate::sync::SpinNoIrqLock as Mutex;
 
 use super::{
-    super::{DeviceType, Driver, DRIVERS, IRQ_MANAGER, NET_DRIVERS, SOCKET_ACTIVITY},
-    NetDriver,
+    super::{DeviceType, Driver, DRIVERS, IRQ_MANAGER},
+    IfaceFlags, NetDriver,
 };
 
 #[derive(Clone)]
@@ -33,6 +34,7 @@ pub struct E1000Interface {
     driver: E1000Driver,
     name: String,
     irq: Option<usize>,
+    up: AtomicBool,
 }
 
 impl Driver for E1000Interface {
@@ -44,13 +46,14 @@ impl Driver for E1000Interface {
 
         let data = self.driver.0.lock().handle_interrupt();
 
-        if data {
+        if data && self.up.load(Ordering::Relaxed) {
             let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
             let mut sockets = SOCKETS.lock();
             match self.iface.lock().poll(&mut sockets, timestamp) {
-                Ok(_) => {
-                    SOCKET_ACTIVITY.notify_all();
+                Ok(true) => {
+                    notify_activity();
                 }
+                Ok(false) => {}
                 Err(err) => {
                     debug!("poll got err {}", err);
                 }
@@ -91,17 +94,49 @@ impl NetDriver for E1000Interface {
         Vec::from(self.iface.lock().ip_addrs())
     }
 
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) {
+        super::update_ip_addrs(&mut self.iface.lock(), addrs);
+    }
+
     fn ipv4_address(&self) -> Option<Ipv4Address> {
         self.iface.lock().ipv4_address()
     }
 
+    fn get_flags(&self) -> IfaceFlags {
+        if self.up.load(Ordering::Relaxed) {
+            IfaceFlags::BROADCAST | IfaceFlags::MULTICAST | IfaceFlags::UP | IfaceFlags::RUNNING
+        } else {
+            IfaceFlags::BROADCAST | IfaceFlags::MULTICAST
+        }
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_routes(&self) -> Vec<(IpCidr, IpAddress)> {
+        super::get_routes(&mut self.iface.lock())
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> bool {
+        super::add_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> bool {
+        super::del_route(&mut self.iface.lock(), dst)
+    }
+
     fn poll(&self) {
+        if !self.up.load(Ordering::Relaxed) {
+            return;
+        }
         let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
         let mut sockets = SOCKETS.lock();
         match self.iface.lock().poll(&mut sockets, timestamp) {
-            Ok(_) => {
-                SOCKET_ACTIVITY.notify_all();
+            Ok(true) => {
+                notify_activity();
             }
+            Ok(false) => {}
             Err(err) => {
                 debug!("poll got err {}", err);
             }
@@ -109,6 +144,9 @@ impl NetDriver for E1000Interface {
     }
 
     fn send(&self, data: &[u8]) -> Option<usize> {
+        if !self.up.load(Ordering::Relaxed) {
+            return None;
+        }
         use smoltcp::phy::TxToken;
         let token = E1000TxToken(self.driver.clone());
         if token
@@ -139,10 +177,12 @@ impl phy::Device<'_> for E1000Driver {
     type TxToken = E1000TxToken;
 
     fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        self.0
-            .lock()
-            .receive()
-            .map(|vec| (E1000RxToken(vec), E1000TxToken(self.clone())))
+        loop {
+            let frame = self.0.lock().receive()?;
+            if filter_frame(FilterDirection::Ingress, &frame) {
+                return Some((E1000RxToken(frame), E1000TxToken(self.clone())));
+            }
+        }
     }
 
     fn transmit(&mut self) -> Option<Self::TxToken> {
@@ -178,8 +218,10 @@ impl phy::TxToken for E1000TxToken {
         let mut buffer = [0u8; PAGE_SIZE];
         let result = f(&mut buffer[..len]);
 
-        let mut driver = (self.0).0.lock();
-        driver.send(&buffer);
+        if filter_frame(FilterDirection::Egress, &buffer[..len]) {
+            let mut driver = (self.0).0.lock();
+            driver.send(&buffer);
+        }
 
         result
     }
@@ -199,10 +241,12 @@ pub fn init(name: String, irq: Option<usize>, header: usize, size: usize, index:
     let ethernet_addr = EthernetAddress::from_bytes(&mac);
     let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, index as u8, 2), 24)];
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
+    let routes = Routes::new(BTreeMap::new());
     let iface = EthernetInterfaceBuilder::new(net_driver.clone())
         .ethernet_addr(ethernet_addr)
         .ip_addrs(ip_addrs)
         .neighbor_cache(neighbor_cache)
+        .routes(routes)
         .finalize();
 
     info!("e1000 interface {} up with addr 10.0.{}.2/24", name, index);
@@ -211,10 +255,11 @@ pub fn init(name: String, irq: Option<usize>, header: usize, size: usize, index:
         driver: net_driver.clone(),
         name,
         irq,
+        up: AtomicBool::new(true),
     };
 
     let driver = Arc::new(e1000_iface);
     DRIVERS.write().push(driver.clone());
     IRQ_MANAGER.write().register_opt(irq, driver.clone());
-    NET_DRIVERS.write().push(driver);
+    super::register_nic(driver);
 }
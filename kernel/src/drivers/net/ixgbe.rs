@@ -4,6 +4,7 @@
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::collections::BTreeMap;
 use isomorphic_drivers::net::ethernet::intel::ixgbe;
@@ -15,15 +16,13 @@ use smoltcp::wire::EthernetAddress;
 use smoltcp::wire::*;
 use smoltcp::Result;
 
-use crate::net::SOCKETS;
+use crate::net::{filter_frame, notify_activity, FilterDirection, SOCKETS};
 use crate::sync::FlagsGuard;
 use crate::{drivers::BlockDriver, sync::SpinNoIrqLock as Mutex};
 
 use super::{
-    super::{
-        provider::Provider, DeviceType, Driver, DRIVERS, IRQ_MANAGER, NET_DRIVERS, SOCKET_ACTIVITY,
-    },
-    NetDriver,
+    super::{provider::Provider, DeviceType, Driver, DRIVERS, IRQ_MANAGER},
+    IfaceFlags, NetDriver,
 };
 
 #[derive(Clone)]
@@ -40,6 +39,7 @@ pub struct IXGBEInterface {
     ifname: String,
     irq: Option<usize>,
     id: String,
+    up: AtomicBool,
 }
 
 impl Driver for IXGBEInterface {
@@ -54,13 +54,14 @@ impl Driver for IXGBEInterface {
             self.driver.inner.lock().try_handle_interrupt()
         };
 
-        if handled {
+        if handled && self.up.load(Ordering::Relaxed) {
             let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
             let mut sockets = SOCKETS.lock();
             match self.iface.lock().poll(&mut sockets, timestamp) {
-                Ok(_) => {
-                    SOCKET_ACTIVITY.notify_all();
+                Ok(true) => {
+                    notify_activity();
                 }
+                Ok(false) => {}
                 Err(err) => {
                     debug!("poll got err {}", err);
                 }
@@ -101,17 +102,49 @@ impl NetDriver for IXGBEInterface {
         Vec::from(self.iface.lock().ip_addrs())
     }
 
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) {
+        super::update_ip_addrs(&mut self.iface.lock(), addrs);
+    }
+
     fn ipv4_address(&self) -> Option<Ipv4Address> {
         self.iface.lock().ipv4_address()
     }
 
+    fn get_flags(&self) -> IfaceFlags {
+        if self.up.load(Ordering::Relaxed) {
+            IfaceFlags::BROADCAST | IfaceFlags::MULTICAST | IfaceFlags::UP | IfaceFlags::RUNNING
+        } else {
+            IfaceFlags::BROADCAST | IfaceFlags::MULTICAST
+        }
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_routes(&self) -> Vec<(IpCidr, IpAddress)> {
+        super::get_routes(&mut self.iface.lock())
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> bool {
+        super::add_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> bool {
+        super::del_route(&mut self.iface.lock(), dst)
+    }
+
     fn poll(&self) {
+        if !self.up.load(Ordering::Relaxed) {
+            return;
+        }
         let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
         let mut sockets = SOCKETS.lock();
         match self.iface.lock().poll(&mut sockets, timestamp) {
-            Ok(_) => {
-                SOCKET_ACTIVITY.notify_all();
+            Ok(true) => {
+                notify_activity();
             }
+            Ok(false) => {}
             Err(err) => {
                 debug!("poll got err {}", err);
             }
@@ -119,7 +152,12 @@ impl NetDriver for IXGBEInterface {
     }
 
     fn send(&self, data: &[u8]) -> Option<usize> {
-        self.driver.inner.lock().send(&data);
+        if !self.up.load(Ordering::Relaxed) {
+            return None;
+        }
+        if filter_frame(FilterDirection::Egress, data) {
+            self.driver.inner.lock().send(&data);
+        }
         Some(data.len())
     }
 
@@ -139,11 +177,12 @@ impl<'a> phy::Device<'a> for IXGBEDriver {
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         let _ = FlagsGuard::no_irq_region();
         if self.inner.lock().can_send() {
-            if let Some(data) = self.inner.lock().recv() {
-                Some((IXGBERxToken(data), IXGBETxToken(self.clone())))
-            } else {
-                None
+            while let Some(data) = self.inner.lock().recv() {
+                if filter_frame(FilterDirection::Ingress, &data) {
+                    return Some((IXGBERxToken(data), IXGBETxToken(self.clone())));
+                }
             }
+            None
         } else {
             None
         }
@@ -187,7 +226,7 @@ impl phy::TxToken for IXGBETxToken {
         let _ = FlagsGuard::no_irq_region();
         let mut buffer = [0u8; ixgbe::IXGBE::<Provider>::get_mtu()];
         let result = f(&mut buffer[..len]);
-        if result.is_ok() {
+        if result.is_ok() && filter_frame(FilterDirection::Egress, &buffer[..len]) {
             self.0.inner.lock().send(&buffer[..len]);
         }
         result
@@ -232,11 +271,12 @@ pub fn ixgbe_init(
         ifname: name.clone(),
         id: name,
         irq,
+        up: AtomicBool::new(true),
     };
 
     let driver = Arc::new(ixgbe_iface);
     IRQ_MANAGER.write().register_opt(irq, driver.clone());
     DRIVERS.write().push(driver.clone());
-    NET_DRIVERS.write().push(driver.clone());
+    super::register_nic(driver.clone());
     driver
 }
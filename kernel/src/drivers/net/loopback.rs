@@ -0,0 +1,223 @@
+//! Software loopback network interface `lo`
+//!
+//! Frames sent to it are queued and received by the same interface
+//! in the same `poll`, so local clients and servers work without a NIC.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use smoltcp::iface::*;
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::wire::*;
+use smoltcp::Result;
+
+use crate::drivers::BlockDriver;
+use crate::net::{filter_frame, notify_activity, FilterDirection, SOCKETS};
+use crate::sync::SpinNoIrqLock as Mutex;
+
+use super::{
+    super::{DeviceType, Driver, DRIVERS, NET_DRIVERS},
+    IfaceFlags, NetDriver,
+};
+
+/// Same as Linux
+const LOOPBACK_MTU: usize = 65536;
+
+/// Frames sent but not received yet
+#[derive(Clone)]
+pub struct LoopbackDriver(Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+pub struct LoopbackInterface {
+    iface: Mutex<EthernetInterface<'static, 'static, 'static, LoopbackDriver>>,
+    driver: LoopbackDriver,
+    name: String,
+    up: AtomicBool,
+}
+
+impl Driver for LoopbackInterface {
+    fn try_handle_interrupt(&self, _irq: Option<usize>) -> bool {
+        // no hardware behind it
+        false
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Net
+    }
+
+    fn get_id(&self) -> String {
+        String::from("loopback")
+    }
+
+    fn as_net(&self) -> Option<&dyn NetDriver> {
+        Some(self)
+    }
+
+    fn as_block(&self) -> Option<&dyn BlockDriver> {
+        None
+    }
+}
+
+impl NetDriver for LoopbackInterface {
+    fn get_mac(&self) -> EthernetAddress {
+        self.iface.lock().ethernet_addr()
+    }
+
+    fn get_ifname(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_ip_addresses(&self) -> Vec<IpCidr> {
+        Vec::from(self.iface.lock().ip_addrs())
+    }
+
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) {
+        super::update_ip_addrs(&mut self.iface.lock(), addrs);
+    }
+
+    fn ipv4_address(&self) -> Option<Ipv4Address> {
+        self.iface.lock().ipv4_address()
+    }
+
+    fn get_flags(&self) -> IfaceFlags {
+        if self.up.load(Ordering::Relaxed) {
+            IfaceFlags::LOOPBACK | IfaceFlags::UP | IfaceFlags::RUNNING
+        } else {
+            IfaceFlags::LOOPBACK
+        }
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_routes(&self) -> Vec<(IpCidr, IpAddress)> {
+        super::get_routes(&mut self.iface.lock())
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> bool {
+        super::add_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> bool {
+        super::del_route(&mut self.iface.lock(), dst)
+    }
+
+    fn poll(&self) {
+        if !self.up.load(Ordering::Relaxed) {
+            return;
+        }
+        let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
+        let mut sockets = SOCKETS.lock();
+        match self.iface.lock().poll(&mut sockets, timestamp) {
+            Ok(true) => {
+                notify_activity();
+            }
+            Ok(false) => {}
+            Err(err) => {
+                debug!("poll got err {}", err);
+            }
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> Option<usize> {
+        if !self.up.load(Ordering::Relaxed) {
+            return None;
+        }
+        if filter_frame(FilterDirection::Egress, data) {
+            self.driver.0.lock().push_back(Vec::from(data));
+        }
+        Some(data.len())
+    }
+
+    fn get_arp(&self, ip: IpAddress) -> Option<EthernetAddress> {
+        let iface = self.iface.lock();
+        let cache = iface.neighbor_cache();
+        cache.lookup_pure(&ip, Instant::from_millis(0))
+    }
+}
+
+pub struct LoopbackRxToken(Vec<u8>);
+pub struct LoopbackTxToken(LoopbackDriver);
+
+impl phy::Device<'_> for LoopbackDriver {
+    type RxToken = LoopbackRxToken;
+    type TxToken = LoopbackTxToken;
+
+    fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        loop {
+            let frame = self.0.lock().pop_front()?;
+            if filter_frame(FilterDirection::Ingress, &frame) {
+                return Some((LoopbackRxToken(frame), LoopbackTxToken(self.clone())));
+            }
+        }
+    }
+
+    fn transmit(&mut self) -> Option<Self::TxToken> {
+        Some(LoopbackTxToken(self.clone()))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = LOOPBACK_MTU;
+        caps
+    }
+}
+
+impl phy::RxToken for LoopbackRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for LoopbackTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer = vec![0u8; len];
+        let result = f(&mut buffer);
+        if result.is_ok() && filter_frame(FilterDirection::Egress, &buffer) {
+            (self.0).0.lock().push_back(buffer);
+        }
+        result
+    }
+}
+
+/// Register `lo` with 127.0.0.1/8 and ::1/128.
+/// Call it after NIC drivers, so that NICs are polled first
+/// and `lo` only picks up what they can not route.
+pub fn init() {
+    let driver = LoopbackDriver(Arc::new(Mutex::new(VecDeque::new())));
+
+    let ethernet_addr = EthernetAddress([0; 6]);
+    let ip_addrs = [
+        IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8),
+        IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 128),
+    ];
+    let neighbor_cache = NeighborCache::new(BTreeMap::new());
+    let routes = Routes::new(BTreeMap::new());
+    let iface = EthernetInterfaceBuilder::new(driver.clone())
+        .ethernet_addr(ethernet_addr)
+        .ip_addrs(ip_addrs)
+        .neighbor_cache(neighbor_cache)
+        .routes(routes)
+        .finalize();
+
+    info!("loopback interface lo up with addr 127.0.0.1/8 and ::1/128");
+    let lo = Arc::new(LoopbackInterface {
+        iface: Mutex::new(iface),
+        driver,
+        name: String::from("lo"),
+        up: AtomicBool::new(true),
+    });
+
+    DRIVERS.write().push(lo.clone());
+    NET_DRIVERS.write().push(lo);
+}
@@ -1,12 +1,28 @@
-use super::Driver;
+use super::{Driver, NET_DRIVERS};
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use bitflags::bitflags;
+use smoltcp::iface::{EthernetInterface, Route};
+use smoltcp::phy::Device;
 use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
 
 pub mod e1000;
 pub mod ixgbe;
+pub mod loopback;
 pub mod virtio_net;
 
+bitflags! {
+    /// Interface flags, same values as IFF_* in Linux
+    pub struct IfaceFlags: u32 {
+        const UP = 0x1;
+        const BROADCAST = 0x2;
+        const LOOPBACK = 0x8;
+        const RUNNING = 0x40;
+        const MULTICAST = 0x1000;
+    }
+}
+
 pub trait NetDriver: Driver {
     // get mac address for this device
     fn get_mac(&self) -> EthernetAddress {
@@ -23,11 +39,42 @@ pub trait NetDriver: Driver {
         unimplemented!("not a net driver")
     }
 
+    // replace ip addresses, they must be unicast
+    fn set_ip_addresses(&self, _addrs: Vec<IpCidr>) {
+        unimplemented!("not a net driver")
+    }
+
     // get ipv4 address
     fn ipv4_address(&self) -> Option<Ipv4Address> {
         unimplemented!("not a net driver")
     }
 
+    // get interface flags
+    fn get_flags(&self) -> IfaceFlags {
+        unimplemented!("not a net driver")
+    }
+
+    // bring the interface up or down, a down interface is not polled
+    fn set_up(&self, _up: bool) {
+        unimplemented!("not a net driver")
+    }
+
+    // get routes as (destination, gateway)
+    fn get_routes(&self) -> Vec<(IpCidr, IpAddress)> {
+        unimplemented!("not a net driver")
+    }
+
+    // add a route or replace the one to the same destination
+    // return false if the routing table is full
+    fn add_route(&self, _dst: IpCidr, _gateway: IpAddress) -> bool {
+        unimplemented!("not a net driver")
+    }
+
+    // remove the route to destination, return false if not found
+    fn del_route(&self, _dst: IpCidr) -> bool {
+        unimplemented!("not a net driver")
+    }
+
     // manually trigger a poll, use it after sending packets
     fn poll(&self) {
         unimplemented!("not a net driver")
@@ -43,3 +90,63 @@ pub trait NetDriver: Driver {
         unimplemented!("not a net driver")
     }
 }
+
+/// Register a NIC and start configuring its addresses
+pub fn register_nic(driver: Arc<dyn NetDriver>) {
+    NET_DRIVERS.write().push(driver.clone());
+    crate::net::start_slaac(driver.clone());
+    crate::net::start_dhcp(driver);
+}
+
+// Runtime configuration shared by drivers built on smoltcp EthernetInterface
+
+fn update_ip_addrs<D>(iface: &mut EthernetInterface<'_, '_, '_, D>, addrs: Vec<IpCidr>)
+where
+    D: for<'d> Device<'d>,
+{
+    iface.update_ip_addrs(|ip_addrs| *ip_addrs = addrs.into());
+}
+
+fn get_routes<D>(iface: &mut EthernetInterface<'_, '_, '_, D>) -> Vec<(IpCidr, IpAddress)>
+where
+    D: for<'d> Device<'d>,
+{
+    let mut routes = Vec::new();
+    iface.routes_mut().update(|storage| {
+        for (dst, route) in storage.iter() {
+            routes.push((*dst, route.via_router));
+        }
+    });
+    routes
+}
+
+fn add_route<D>(
+    iface: &mut EthernetInterface<'_, '_, '_, D>,
+    dst: IpCidr,
+    gateway: IpAddress,
+) -> bool
+where
+    D: for<'d> Device<'d>,
+{
+    let route = Route {
+        via_router: gateway,
+        preferred_until: None,
+        expires_at: None,
+    };
+    let mut added = false;
+    iface.routes_mut().update(|storage| {
+        added = storage.insert(dst, route).is_ok();
+    });
+    added
+}
+
+fn del_route<D>(iface: &mut EthernetInterface<'_, '_, '_, D>, dst: IpCidr) -> bool
+where
+    D: for<'d> Device<'d>,
+{
+    let mut removed = false;
+    iface.routes_mut().update(|storage| {
+        removed = storage.remove(&dst).is_some();
+    });
+    removed
+}
@@ -1,43 +1,132 @@
+use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use smoltcp::iface::*;
 use smoltcp::phy::{self, DeviceCapabilities};
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, Ipv4Address};
+use smoltcp::wire::*;
 use smoltcp::Result;
 use virtio_drivers::{VirtIOHeader, VirtIONet};
 
 use super::{
     super::{DeviceType, Driver, DRIVERS, IRQ_MANAGER, NET_DRIVERS},
-    NetDriver,
+    IfaceFlags, NetDriver,
 };
+use crate::net::{filter_frame, notify_activity, FilterDirection, SOCKETS};
 use crate::{drivers::BlockDriver, sync::SpinNoIrqLock as Mutex};
 
+/// Large enough for a frame of the MTU
+const BUFFER_SIZE: usize = 2000;
+
 #[derive(Clone)]
 pub struct VirtIONetDriver(Arc<Mutex<VirtIONet<'static>>>);
 
-impl NetDriver for VirtIONetDriver {
+pub struct VirtIONetInterface {
+    iface: Mutex<EthernetInterface<'static, 'static, 'static, VirtIONetDriver>>,
+    driver: VirtIONetDriver,
+    name: String,
+    up: AtomicBool,
+}
+
+impl NetDriver for VirtIONetInterface {
     fn get_mac(&self) -> EthernetAddress {
-        EthernetAddress(self.0.lock().mac())
+        self.iface.lock().ethernet_addr()
     }
 
     fn get_ifname(&self) -> String {
-        format!("virtio{:?}", self.0.lock().mac())
+        self.name.clone()
+    }
+
+    fn get_ip_addresses(&self) -> Vec<IpCidr> {
+        Vec::from(self.iface.lock().ip_addrs())
+    }
+
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) {
+        super::update_ip_addrs(&mut self.iface.lock(), addrs);
     }
 
     fn ipv4_address(&self) -> Option<Ipv4Address> {
-        unimplemented!()
+        self.iface.lock().ipv4_address()
+    }
+
+    fn get_flags(&self) -> IfaceFlags {
+        if self.up.load(Ordering::Relaxed) {
+            IfaceFlags::BROADCAST | IfaceFlags::MULTICAST | IfaceFlags::UP | IfaceFlags::RUNNING
+        } else {
+            IfaceFlags::BROADCAST | IfaceFlags::MULTICAST
+        }
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_routes(&self) -> Vec<(IpCidr, IpAddress)> {
+        super::get_routes(&mut self.iface.lock())
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> bool {
+        super::add_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> bool {
+        super::del_route(&mut self.iface.lock(), dst)
     }
 
     fn poll(&self) {
-        unimplemented!()
+        if !self.up.load(Ordering::Relaxed) {
+            return;
+        }
+        let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
+        let mut sockets = SOCKETS.lock();
+        match self.iface.lock().poll(&mut sockets, timestamp) {
+            Ok(true) => {
+                notify_activity();
+            }
+            Ok(false) => {}
+            Err(err) => {
+                debug!("poll got err {}", err);
+            }
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> Option<usize> {
+        if !self.up.load(Ordering::Relaxed) || data.len() > BUFFER_SIZE {
+            return None;
+        }
+        use smoltcp::phy::TxToken;
+        let token = VirtIONetTxToken(self.driver.clone());
+        if token
+            .consume(Instant::from_millis(0), data.len(), |buffer| {
+                buffer.copy_from_slice(&data);
+                Ok(())
+            })
+            .is_ok()
+        {
+            Some(data.len())
+        } else {
+            None
+        }
+    }
+
+    fn get_arp(&self, ip: IpAddress) -> Option<EthernetAddress> {
+        let iface = self.iface.lock();
+        let cache = iface.neighbor_cache();
+        cache.lookup_pure(&ip, Instant::from_millis(0))
     }
 }
 
-impl Driver for VirtIONetDriver {
+impl Driver for VirtIONetInterface {
     fn try_handle_interrupt(&self, _irq: Option<usize>) -> bool {
-        self.0.lock().ack_interrupt()
+        let data = self.driver.0.lock().ack_interrupt();
+        if data {
+            self.poll();
+        }
+        data
     }
 
     fn device_type(&self) -> DeviceType {
@@ -57,23 +146,33 @@ impl Driver for VirtIONetDriver {
     }
 }
 
+pub struct VirtIONetRxToken(Vec<u8>);
+pub struct VirtIONetTxToken(VirtIONetDriver);
+
 impl phy::Device<'_> for VirtIONetDriver {
-    type RxToken = VirtIONetDriver;
-    type TxToken = VirtIONetDriver;
+    type RxToken = VirtIONetRxToken;
+    type TxToken = VirtIONetTxToken;
 
     fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let net = self.0.lock();
-        if net.can_recv() {
-            Some((self.clone(), self.clone()))
-        } else {
-            None
+        loop {
+            let mut buffer = vec![0u8; BUFFER_SIZE];
+            let mut net = self.0.lock();
+            if !net.can_recv() {
+                return None;
+            }
+            let len = net.recv(&mut buffer).expect("failed to recv packet");
+            drop(net);
+            buffer.truncate(len);
+            if filter_frame(FilterDirection::Ingress, &buffer) {
+                return Some((VirtIONetRxToken(buffer), VirtIONetTxToken(self.clone())));
+            }
         }
     }
 
     fn transmit(&mut self) -> Option<Self::TxToken> {
         let net = self.0.lock();
         if net.can_send() {
-            Some(self.clone())
+            Some(VirtIONetTxToken(self.clone()))
         } else {
             None
         }
@@ -87,36 +186,55 @@ impl phy::Device<'_> for VirtIONetDriver {
     }
 }
 
-impl phy::RxToken for VirtIONetDriver {
-    fn consume<R, F>(self, _timestamp: Instant, f: F) -> Result<R>
+impl phy::RxToken for VirtIONetRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        let mut buffer = [0u8; 2000];
-        let mut driver = self.0.lock();
-        let len = driver.recv(&mut buffer).expect("failed to recv packet");
-        f(&mut buffer[..len])
+        f(&mut self.0)
     }
 }
 
-impl phy::TxToken for VirtIONetDriver {
+impl phy::TxToken for VirtIONetTxToken {
     fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        let mut buffer = [0u8; 2000];
+        let mut buffer = [0u8; BUFFER_SIZE];
         let result = f(&mut buffer[..len]);
-        let mut driver = self.0.lock();
-        driver.send(&buffer).expect("failed to send packet");
+        if filter_frame(FilterDirection::Egress, &buffer[..len]) {
+            let mut driver = (self.0).0.lock();
+            driver.send(&buffer[..len]).expect("failed to send packet");
+        }
         result
     }
 }
 
+/// Register the NIC without a static address, it is configured at runtime
 pub fn init(header: &'static mut VirtIOHeader) {
     let net = VirtIONet::new(header).expect("failed to create net driver");
-    let driver = Arc::new(VirtIONetDriver(Arc::new(Mutex::new(net))));
+    let ethernet_addr = EthernetAddress(net.mac());
+    let net_driver = VirtIONetDriver(Arc::new(Mutex::new(net)));
+
+    let name = format!("eth{}", NET_DRIVERS.read().len());
+    let neighbor_cache = NeighborCache::new(BTreeMap::new());
+    let routes = Routes::new(BTreeMap::new());
+    let iface = EthernetInterfaceBuilder::new(net_driver.clone())
+        .ethernet_addr(ethernet_addr)
+        .ip_addrs(Vec::new())
+        .neighbor_cache(neighbor_cache)
+        .routes(routes)
+        .finalize();
+
+    info!("virtio interface {} up with mac {}", name, ethernet_addr);
+    let driver = Arc::new(VirtIONetInterface {
+        iface: Mutex::new(iface),
+        driver: net_driver,
+        name,
+        up: AtomicBool::new(true),
+    });
 
     DRIVERS.write().push(driver.clone());
     IRQ_MANAGER.write().register_all(driver.clone());
-    NET_DRIVERS.write().push(driver);
+    super::register_nic(driver);
 }
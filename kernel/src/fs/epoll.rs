@@ -2,12 +2,20 @@ use crate::fs::FileLike;
 use crate::process::Process;
 use crate::sync::SpinNoIrqLock;
 use crate::syscall::{SysError, SysResult};
-use alloc::{collections::BTreeMap, collections::BTreeSet};
+use alloc::collections::BTreeMap;
 
 pub struct EpollInstance {
     pub events: BTreeMap<usize, EpollEvent>,
-    pub ready_list: SpinNoIrqLock<BTreeSet<usize>>,
-    pub new_ctl_list: SpinNoIrqLock<BTreeSet<usize>>,
+    /// Events last reported for edge-triggered fds
+    edges: SpinNoIrqLock<BTreeMap<usize, Edge>>,
+}
+
+/// What an edge-triggered fd looked like when last reported
+#[derive(Clone, Copy)]
+struct Edge {
+    events: u32,
+    /// Activity count of the fd, see `crate::net::activity_count`
+    activity: usize,
 }
 
 impl Clone for EpollInstance {
@@ -20,23 +28,44 @@ impl EpollInstance {
     pub fn new(_flags: usize) -> Self {
         return EpollInstance {
             events: BTreeMap::new(),
-            ready_list: Default::default(),
-            new_ctl_list: Default::default(),
+            edges: Default::default(),
         };
     }
 
+    /// Events of `fd` to report, given the `ready` events and activity count.
+    /// Edge-triggered fds only report events newly ready since the last report,
+    /// or all of them again after new activity. Fds without an activity count
+    /// can not tell new data from old, so they are always level-triggered.
+    pub fn ready_events(&self, fd: usize, ready: u32, activity: Option<usize>) -> u32 {
+        let event = match self.events.get(&fd) {
+            Some(event) => event,
+            None => return 0,
+        };
+        let ready = ready & (event.events | EpollEvent::EPOLLERR | EpollEvent::EPOLLHUP);
+        let activity = match activity {
+            Some(activity) if event.contains(EpollEvent::EPOLLET) => activity,
+            _ => return ready,
+        };
+        let edge = Edge {
+            events: ready,
+            activity,
+        };
+        match self.edges.lock().insert(fd, edge) {
+            Some(last) if last.activity == activity => ready & !last.events,
+            _ => ready,
+        }
+    }
+
     pub fn control(&mut self, op: usize, fd: usize, event: &EpollEvent) -> SysResult {
         match op as i32 {
             EPollCtlOp::ADD => {
                 self.events.insert(fd, event.clone());
-                self.new_ctl_list.lock().insert(fd);
             }
 
             EPollCtlOp::MOD => {
                 if self.events.get(&fd).is_some() {
                     self.events.remove(&fd);
                     self.events.insert(fd, event.clone());
-                    self.new_ctl_list.lock().insert(fd);
                 } else {
                     return Err(SysError::EPERM);
                 }
@@ -53,6 +82,7 @@ impl EpollInstance {
                 return Err(SysError::EPERM);
             }
         }
+        self.edges.lock().remove(&fd);
         Ok(0)
     }
 }
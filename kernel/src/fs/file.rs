@@ -1,6 +1,5 @@
 //! File handle for process
 
-use crate::memory::GlobalFrameAlloc;
 use crate::process::{current_thread, INodeForMap};
 use crate::syscall::{MmapProt, SysResult, TimeSpec};
 use alloc::{string::String, sync::Arc};
@@ -231,6 +230,7 @@ impl FileHandle {
             FileType::File => {
                 let prot = MmapProt::from_bits_truncate(area.prot);
                 let thread = current_thread().unwrap();
+                let allocator = thread.proc.lock().frame_allocator();
                 thread.vm.lock().push(
                     area.start_vaddr,
                     area.end_vaddr,
@@ -240,7 +240,7 @@ impl FileHandle {
                         mem_start: area.start_vaddr,
                         file_start: area.offset,
                         file_end: area.offset + area.end_vaddr - area.start_vaddr,
-                        allocator: GlobalFrameAlloc,
+                        allocator,
                     },
                     "mmap_file",
                 );
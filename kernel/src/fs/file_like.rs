@@ -3,9 +3,12 @@ use core::fmt;
 use super::ioctl::*;
 use super::FileHandle;
 use crate::fs::epoll::EpollInstance;
-use crate::net::Socket;
+use crate::net::{MsgFlags, Socket};
+use crate::perf::PerfEvent;
 use crate::syscall::{SysError, SysResult};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cmp::min;
 use rcore_fs::vfs::{MMapArea, PollStatus};
 
 // TODO: merge FileLike to FileHandle ?
@@ -14,6 +17,7 @@ pub enum FileLike {
     File(FileHandle),
     Socket(Box<dyn Socket>),
     EpollInstance(EpollInstance),
+    PerfEvent(Arc<PerfEvent>),
 }
 
 impl FileLike {
@@ -21,26 +25,39 @@ impl FileLike {
         use FileLike::*;
         match self {
             File(file) => File(file.dup(fd_cloexec)),
-            Socket(s) => Socket(s.clone()),
+            Socket(s) => {
+                let mut s = s.clone();
+                s.set_fd_cloexec(fd_cloexec);
+                Socket(s)
+            }
             EpollInstance(e) => EpollInstance(e.clone()),
+            PerfEvent(e) => PerfEvent(e.clone()),
         }
     }
 
     pub async fn read(&mut self, buf: &mut [u8]) -> SysResult {
         let len = match self {
             FileLike::File(file) => file.read(buf).await?,
-            FileLike::Socket(socket) => socket.read(buf).0?,
+            FileLike::Socket(socket) => {
+                let info = socket.recv(buf, MsgFlags::empty()).await?;
+                min(info.len, buf.len())
+            }
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
+            FileLike::PerfEvent(event) => event.read(buf)?,
         };
         Ok(len)
     }
-    pub fn write(&mut self, buf: &[u8]) -> SysResult {
+    pub async fn write(&mut self, buf: &[u8]) -> SysResult {
         let len = match self {
             FileLike::File(file) => file.write(buf)?,
-            FileLike::Socket(socket) => socket.write(buf, None)?,
-            FileLike::EpollInstance(_) => {
+            FileLike::Socket(socket) => {
+                socket
+                    .send(buf, None, Default::default(), MsgFlags::empty())
+                    .await?
+            }
+            FileLike::EpollInstance(_) | FileLike::PerfEvent(_) => {
                 return Err(SysError::ENOSYS);
             }
         };
@@ -50,6 +67,7 @@ impl FileLike {
         match self {
             FileLike::File(file) => file.io_control(request as u32, arg1).map_err(Into::into),
             FileLike::Socket(socket) => socket.ioctl(request, arg1, arg2, arg3),
+            FileLike::PerfEvent(event) => event.ioctl(request, arg1),
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
@@ -58,6 +76,7 @@ impl FileLike {
     pub fn mmap(&mut self, area: MMapArea) -> SysResult {
         match self {
             FileLike::File(file) => file.mmap(area)?,
+            FileLike::PerfEvent(event) => event.mmap(area)?,
             _ => return Err(SysError::ENOSYS),
         };
         Ok(0)
@@ -69,6 +88,7 @@ impl FileLike {
                 let (read, write, error) = socket.poll();
                 PollStatus { read, write, error }
             }
+            FileLike::PerfEvent(event) => event.poll(),
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
@@ -79,9 +99,10 @@ impl FileLike {
         let status = match self {
             FileLike::File(file) => file.async_poll().await?,
             FileLike::Socket(socket) => {
-                let (read, write, error) = socket.poll();
+                let (read, write, error) = socket.ready().await;
                 PollStatus { read, write, error }
             }
+            FileLike::PerfEvent(event) => event.async_poll().await,
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
@@ -96,6 +117,7 @@ impl fmt::Debug for FileLike {
             FileLike::File(file) => write!(f, "File({:?})", file),
             FileLike::Socket(socket) => write!(f, "Socket({:?})", socket),
             FileLike::EpollInstance(_) => write!(f, "EpollInstance()"),
+            FileLike::PerfEvent(_) => write!(f, "PerfEvent()"),
         }
     }
 }
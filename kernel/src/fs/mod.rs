@@ -108,6 +108,31 @@ lazy_static! {
         });
         tmp.mount(ramfs).expect("failed to mount RamFS");
 
+        // mount RamFS at /sys
+        let ramfs = RamFS::new();
+        let sys = root.find(true, "sys").unwrap_or_else(|_| {
+            root.create("sys", FileType::Dir, 0o666).expect("failed to mkdir /sys")
+        });
+        let sys = sys.mount(ramfs).expect("failed to mount RamFS").root_inode();
+
+        // mount tracefs at /sys/kernel/tracing, and the BTF of kernel structures
+        // at /sys/kernel/btf. Both are optional, so a failure is only reported.
+        let mount_sys_kernel = || -> Result<()> {
+            let kernel = sys.create("kernel", FileType::Dir, 0o666)?;
+            let tracing = kernel.create("tracing", FileType::Dir, 0o666)?;
+            tracing.mount(crate::trace::tracefs())?;
+
+            let btffs = DevFS::new();
+            let vmlinux = Pseudo::from_bytes(crate::bpf::btf::vmlinux(), FileType::File);
+            btffs.add("vmlinux", Arc::new(vmlinux))?;
+            let btf = kernel.create("btf", FileType::Dir, 0o666)?;
+            btf.mount(btffs)?;
+            Ok(())
+        };
+        if let Err(err) = mount_sys_kernel() {
+            warn!("failed to set up /sys/kernel: {:?}", err);
+        }
+
         root
     };
 }
@@ -17,6 +17,10 @@ impl Pseudo {
             type_,
         }
     }
+
+    pub fn from_bytes(content: Vec<u8>, type_: FileType) -> Self {
+        Pseudo { content, type_ }
+    }
 }
 
 impl INode for Pseudo {
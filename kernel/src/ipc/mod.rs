@@ -3,7 +3,7 @@ mod shared_mem;
 
 pub use self::semary::*;
 pub use self::shared_mem::*;
-use crate::memory::GlobalFrameAlloc;
+use crate::memory::MemCgFrameAlloc;
 use crate::sync::SpinLock as Mutex;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
@@ -94,7 +94,7 @@ impl Drop for SemProc {
 
 impl ShmProc {
     /// Insert the `SharedGuard` and return its ID
-    pub fn add(&mut self, shared_guard: Arc<spin::Mutex<SharedGuard<GlobalFrameAlloc>>>) -> ShmId {
+    pub fn add(&mut self, shared_guard: Arc<spin::Mutex<SharedGuard<MemCgFrameAlloc>>>) -> ShmId {
         let id = self.get_free_id();
         let shm_identifier = ShmIdentifier {
             addr: 0,
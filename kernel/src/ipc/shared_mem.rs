@@ -1,4 +1,4 @@
-use crate::memory::{FrameAllocator, GlobalFrameAlloc};
+use crate::memory::{FrameAllocator, MemCgFrameAlloc};
 use crate::sync::Semaphore;
 use crate::sync::SpinLock as Mutex;
 use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, sync::Weak, vec::Vec};
@@ -9,14 +9,14 @@ use rcore_memory::{PhysAddr, VirtAddr};
 use spin::RwLock;
 
 lazy_static! {
-    static ref KEY2SHM: RwLock<BTreeMap<usize, Weak<spin::Mutex<SharedGuard<GlobalFrameAlloc>>>>> =
+    static ref KEY2SHM: RwLock<BTreeMap<usize, Weak<spin::Mutex<SharedGuard<MemCgFrameAlloc>>>>> =
         RwLock::new(BTreeMap::new());
 }
 
 #[derive(Clone)]
 pub struct ShmIdentifier {
     pub addr: VirtAddr,
-    pub shared_guard: Arc<spin::Mutex<SharedGuard<GlobalFrameAlloc>>>,
+    pub shared_guard: Arc<spin::Mutex<SharedGuard<MemCgFrameAlloc>>>,
 }
 
 impl ShmIdentifier {
@@ -24,10 +24,12 @@ impl ShmIdentifier {
         self.addr = addr;
     }
 
+    /// Get the segment of `key`, or create it charging its pages to `allocator`
     pub fn new_shared_guard(
         key: usize,
         memsize: usize,
-    ) -> Arc<spin::Mutex<SharedGuard<GlobalFrameAlloc>>> {
+        allocator: MemCgFrameAlloc,
+    ) -> Arc<spin::Mutex<SharedGuard<MemCgFrameAlloc>>> {
         let mut key2shm = KEY2SHM.write();
 
         // found in the map
@@ -37,8 +39,7 @@ impl ShmIdentifier {
             }
         }
         let mut shared_guard = Arc::new(spin::Mutex::new(SharedGuard::new_with_size(
-            GlobalFrameAlloc,
-            memsize,
+            allocator, memsize,
         )));
         // insert to global map
         key2shm.insert(key, Arc::downgrade(&shared_guard));
@@ -0,0 +1,446 @@
+use core::arch::{asm, global_asm};
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::*;
+use rcore_memory::paging::PageTable;
+use rcore_memory::PAGE_SIZE;
+use trapframe::{TrapFrame, UserContext};
+
+use super::code::{code_page_frame, map_code_page, unmap_code_page};
+use super::kprobes::SingleStepType::{self, *};
+use crate::arch::consts::KSEG2_START;
+use crate::arch::cpu;
+use crate::consts::MAX_CPU_NUM;
+use crate::lkm::kernelvm::{MemorySpaceManager, KERNELVM_MANAGER};
+use crate::memory::{alloc_frame, dealloc_frame, kernel_offset};
+
+pub const BREAKPOINT_LENGTH: usize = 4;
+// BRK #4, the immediate Linux uses for kprobes
+const BRK_KPROBES: u32 = 0xd420_0080;
+
+const INSN_LENGTH: usize = 4;
+
+const SPSR_SS: usize = 1 << 21;
+const SPSR_D: usize = 1 << 9;
+const SPSR_I: usize = 1 << 7;
+const MDSCR_SS: usize = 1 << 0;
+const MDSCR_KDE: usize = 1 << 13;
+
+lazy_static! {
+    // a page of kernel virtual memory where text is mapped writable while it is patched
+    static ref TEXT_POKE: usize = KERNELVM_MANAGER.lock().alloc(PAGE_SIZE).unwrap().0;
+}
+
+extern "C" {
+    fn stext();
+    fn etext();
+}
+
+// a whole page of its own, mapped read-only and executable
+fn alloc_insn_buffer() -> usize {
+    map_code_page(alloc_frame().unwrap())
+}
+
+fn free_insn_buffer(addr: usize) {
+    dealloc_frame(unmap_code_page(addr))
+}
+
+// the frame of `addr` if it is in read-only code: kernel text or a page in KSEG2
+fn text_frame(addr: usize) -> Option<usize> {
+    if (stext as usize..etext as usize).contains(&addr) {
+        Some(kernel_offset(addr & !(PAGE_SIZE - 1)))
+    } else if addr >= KSEG2_START {
+        code_page_frame(addr)
+    } else {
+        None
+    }
+}
+
+// code is written through a temporary writable mapping of its frame,
+// then cleaned to the point of unification, see `invalidate_icache`
+pub fn byte_copy(dst_addr: usize, src_addr: usize, len: usize) {
+    let src = unsafe { from_raw_parts(src_addr as *const u8, len) };
+    if let Some(frame) = text_frame(dst_addr) {
+        let offset = dst_addr & (PAGE_SIZE - 1);
+        assert!(offset + len <= PAGE_SIZE, "patch across pages");
+        let poke = *TEXT_POKE;
+        let vmm = KERNELVM_MANAGER.lock();
+        let mut page_table = vmm.kernel_table();
+        page_table.map(poke, frame);
+        let dst = unsafe { from_raw_parts_mut((poke + offset) as *mut u8, len) };
+        dst.copy_from_slice(src);
+        page_table.unmap(poke);
+    } else {
+        let dst = unsafe { from_raw_parts_mut(dst_addr as *mut u8, len) };
+        dst.copy_from_slice(src);
+    }
+    for addr in (dst_addr & !(INSN_LENGTH - 1)..dst_addr + len).step_by(INSN_LENGTH) {
+        unsafe {
+            asm!("dc cvau, {}", in(reg) addr);
+        }
+    }
+    unsafe {
+        asm!("dsb ish");
+    }
+}
+
+pub fn inject_breakpoints(addr: usize, length: Option<usize>) {
+    let brk = BRK_KPROBES;
+    let bp_len = BREAKPOINT_LENGTH;
+
+    let bp_count = match length {
+        Some(len) => {
+            assert!(len % bp_len == 0);
+            len / bp_len
+        }
+        None => 1,
+    };
+    for i in 0..bp_count {
+        byte_copy(addr + i * bp_len, (&brk as *const u32) as usize, bp_len);
+    }
+}
+
+pub struct InstructionBuffer {
+    addr: usize,
+}
+
+impl InstructionBuffer {
+    pub fn new() -> Self {
+        let addr = alloc_insn_buffer();
+        Self { addr }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Copy the probed instruction in to be single-stepped
+    pub fn load(&self, addr: usize, len: usize) {
+        byte_copy(self.addr, addr, len);
+    }
+
+    /// Put the probed instruction back
+    pub fn restore(&self, addr: usize, len: usize) {
+        byte_copy(addr, self.addr, len);
+    }
+}
+
+impl Drop for InstructionBuffer {
+    fn drop(&mut self) {
+        free_insn_buffer(self.addr)
+    }
+}
+
+// arch related helper functions
+pub fn invalidate_icache() {
+    unsafe {
+        asm!("ic ialluis", "dsb ish", "isb");
+    }
+}
+
+pub fn get_insn_length(_addr: usize) -> usize {
+    INSN_LENGTH
+}
+
+fn read_insn(addr: usize) -> u32 {
+    unsafe { *(addr as *const u32) }
+}
+
+fn matches(insn: u32, mask: u32, value: u32) -> bool {
+    insn & mask == value
+}
+
+// instructions whose effect depends on the pc
+fn is_pc_relative(i: u32) -> bool {
+    matches(i, 0x7c00_0000, 0x1400_0000) // b, bl
+        || matches(i, 0xff00_0010, 0x5400_0000) // b.cond
+        || matches(i, 0x7e00_0000, 0x3400_0000) // cbz, cbnz
+        || matches(i, 0x7e00_0000, 0x3600_0000) // tbz, tbnz
+        || matches(i, 0x1f00_0000, 0x1000_0000) // adr, adrp
+        || matches(i, 0xbf00_0000, 0x1800_0000) // ldr (literal)
+        || matches(i, 0xff00_0000, 0x9800_0000) // ldrsw (literal)
+        || matches(i, 0xff00_0000, 0xd800_0000) // prfm (literal)
+        || matches(i, 0xff9f_fc1f, 0xd61f_0000) // br, blr, ret
+}
+
+pub fn get_insn_type(addr: usize) -> SingleStepType {
+    let i = read_insn(addr);
+    if is_pc_relative(i) {
+        return Emulate;
+    }
+    if matches(i, 0xff00_0000, 0xd400_0000) // svc, hvc, smc, brk, hlt
+        || matches(i, 0xffff_ffff, 0xd69f_03e0) // eret
+        || matches(i, 0x3f00_0000, 0x0800_0000) // exclusive loads and stores
+        || matches(i, 0x3f00_0000, 0x1c00_0000) // ldr (literal, simd)
+        || matches(i, 0xfff8_f01f, 0xd500_401f) // msr to pstate fields like daifset
+        || matches(i, 0xffff_ffe0, 0xd51b_4220) // msr daif
+        || i == 0
+    {
+        return Unsupported;
+    }
+    Execute
+}
+
+pub fn get_trapframe_pc(tf: &TrapFrame) -> usize {
+    tf.elr
+}
+
+pub fn set_trapframe_pc(tf: &mut TrapFrame, pc: usize) {
+    tf.elr = pc;
+}
+
+pub fn get_trapframe_ra(tf: &TrapFrame) -> usize {
+    tf.general.x30
+}
+
+pub fn set_trapframe_ra(tf: &mut TrapFrame, ra: usize) {
+    tf.general.x30 = ra;
+}
+
+pub fn get_trapframe_arg(tf: &TrapFrame, n: usize) -> usize {
+    get_reg(regs(&tf.general.x0), n as u32)
+}
+
+pub fn get_trapframe_retval(tf: &TrapFrame) -> usize {
+    tf.general.x0
+}
+
+// x0 to x30 of a trap frame or a user context
+fn regs(x0: &usize) -> &[usize] {
+    unsafe { from_raw_parts(x0 as *const usize, 31) }
+}
+
+fn regs_mut(x0: &mut usize) -> &mut [usize] {
+    unsafe { from_raw_parts_mut(x0 as *mut usize, 31) }
+}
+
+// register 31 reads as zero in the instructions emulated here
+fn get_reg(regs: &[usize], reg: u32) -> usize {
+    let index = reg as usize;
+    if index != 31 {
+        regs[index]
+    } else {
+        0
+    }
+}
+
+fn set_reg(regs: &mut [usize], reg: u32, val: usize) {
+    let index = reg as usize;
+    if index != 31 {
+        regs[index] = val;
+    }
+}
+
+const SPSR_INIT: AtomicUsize = AtomicUsize::new(0);
+// the D and I bits of pstate where each CPU hit a kprobe
+static SAVED_SPSR: [AtomicUsize; MAX_CPU_NUM] = [SPSR_INIT; MAX_CPU_NUM];
+
+// a software step exception follows the copied instruction with SS set
+pub fn setup_single_step(tf: &mut TrapFrame, insn_addr: usize) {
+    SAVED_SPSR[cpu::id()].store(tf.spsr & (SPSR_D | SPSR_I), Ordering::Relaxed);
+    unsafe {
+        // the OS lock blocks debug exceptions, it is set on reset
+        asm!("msr oslar_el1, xzr");
+        let mut mdscr: usize;
+        asm!("mrs {}, mdscr_el1", out(reg) mdscr);
+        mdscr |= MDSCR_SS | MDSCR_KDE;
+        asm!("msr mdscr_el1, {}", "isb", in(reg) mdscr);
+    }
+    // no interrupt may come in before the step exception
+    tf.spsr = (tf.spsr | SPSR_SS | SPSR_I) & !SPSR_D;
+    tf.elr = insn_addr;
+}
+
+pub fn finish_single_step(tf: &mut TrapFrame, insn_addr: usize, pc: usize, len: usize) {
+    unsafe {
+        let mut mdscr: usize;
+        asm!("mrs {}, mdscr_el1", out(reg) mdscr);
+        mdscr &= !MDSCR_SS;
+        asm!("msr mdscr_el1, {}", "isb", in(reg) mdscr);
+    }
+    let saved = SAVED_SPSR[cpu::id()].load(Ordering::Relaxed);
+    tf.spsr = (tf.spsr & !(SPSR_SS | SPSR_D | SPSR_I)) | saved;
+    // branches are all emulated, so execution falls through
+    tf.elr = pc + len;
+}
+
+// user context counterparts for uprobes
+pub fn get_user_pc(cx: &UserContext) -> usize {
+    cx.elr
+}
+
+pub fn set_user_pc(cx: &mut UserContext, pc: usize) {
+    cx.elr = pc;
+}
+
+pub fn get_user_breakpoint_addr(cx: &UserContext) -> usize {
+    cx.elr
+}
+
+pub fn get_user_ra(cx: &UserContext) -> usize {
+    cx.general.x30
+}
+
+pub fn set_user_ra(cx: &mut UserContext, ra: usize) {
+    cx.general.x30 = ra;
+}
+
+pub fn get_user_arg(cx: &UserContext, n: usize) -> usize {
+    get_reg(regs(&cx.general.x0), n as u32)
+}
+
+pub fn get_user_retval(cx: &UserContext) -> usize {
+    cx.general.x0
+}
+
+/// Write the code executing the instruction at `insn_addr` out of line to `dst`,
+/// for the probed instruction at user address `pc`
+pub fn load_user_slot(dst: usize, insn_addr: usize, _pc: usize, len: usize) {
+    byte_copy(dst, insn_addr, len);
+    inject_breakpoints(dst + len, None);
+}
+
+// the step exception would be pending for other threads after a switch,
+// so a breakpoint follows the copied instruction instead
+pub fn setup_user_single_step(cx: &mut UserContext, slot_addr: usize) {
+    cx.elr = slot_addr;
+}
+
+pub fn finish_user_single_step(
+    cx: &mut UserContext,
+    _insn_addr: usize,
+    _slot_addr: usize,
+    pc: usize,
+    len: usize,
+) {
+    cx.elr = pc + len;
+}
+
+pub fn emulate_user_execution(cx: &mut UserContext, insn_addr: usize, pc: usize) {
+    emulate(
+        regs_mut(&mut cx.general.x0),
+        cx.spsr,
+        &mut cx.elr,
+        insn_addr,
+        pc,
+    );
+}
+
+// sign extend the `bits` wide field at `shift`, then scale it by `scale`
+fn offset(i: u32, shift: u32, bits: u32, scale: u32) -> isize {
+    let field = ((i >> shift) & ((1 << bits) - 1)) as isize;
+    let extended = (field << (64 - bits)) >> (64 - bits);
+    extended << scale
+}
+
+// whether condition `cond` holds on the NZCV flags in pstate
+fn condition(spsr: usize, cond: u32) -> bool {
+    let flag = |bit: usize| spsr & (1 << bit) != 0;
+    let (n, z, c, v) = (flag(31), flag(30), flag(29), flag(28));
+    let holds = match cond >> 1 {
+        0 => z,
+        1 => c,
+        2 => n,
+        3 => v,
+        4 => c && !z,
+        5 => n == v,
+        6 => !z && n == v,
+        _ => true,
+    };
+    // odd conditions are the negations, except for 0b1111 which is also "always"
+    if cond & 1 != 0 && cond != 0xf {
+        !holds
+    } else {
+        holds
+    }
+}
+
+pub fn emulate_execution(tf: &mut TrapFrame, insn_addr: usize, pc: usize) {
+    emulate(
+        regs_mut(&mut tf.general.x0),
+        tf.spsr,
+        &mut tf.elr,
+        insn_addr,
+        pc,
+    );
+}
+
+fn emulate(regs: &mut [usize], spsr: usize, elr: &mut usize, insn_addr: usize, pc: usize) {
+    let i = read_insn(insn_addr);
+    let next = pc + INSN_LENGTH;
+    let rt = i & 0x1f;
+    let relative = |offset: isize| (pc as isize + offset) as usize;
+    if matches(i, 0x7c00_0000, 0x1400_0000) {
+        // b, bl
+        if i & (1 << 31) != 0 {
+            set_reg(regs, 30, next);
+        }
+        *elr = relative(offset(i, 0, 26, 2));
+    } else if matches(i, 0xff00_0010, 0x5400_0000) {
+        // b.cond
+        let taken = condition(spsr, i & 0xf);
+        *elr = if taken {
+            relative(offset(i, 5, 19, 2))
+        } else {
+            next
+        };
+    } else if matches(i, 0x7e00_0000, 0x3400_0000) {
+        // cbz, cbnz
+        let mut value = get_reg(regs, rt);
+        if i & (1 << 31) == 0 {
+            value &= 0xffff_ffff;
+        }
+        let taken = (value == 0) != (i & (1 << 24) != 0);
+        *elr = if taken {
+            relative(offset(i, 5, 19, 2))
+        } else {
+            next
+        };
+    } else if matches(i, 0x7e00_0000, 0x3600_0000) {
+        // tbz, tbnz
+        let bit = ((i >> 31) << 5) | ((i >> 19) & 0x1f);
+        let set = get_reg(regs, rt) & (1 << bit) != 0;
+        let taken = set == (i & (1 << 24) != 0);
+        *elr = if taken {
+            relative(offset(i, 5, 14, 2))
+        } else {
+            next
+        };
+    } else if matches(i, 0x1f00_0000, 0x1000_0000) {
+        // adr, adrp
+        let imm = offset(i, 5, 19, 2) | ((i >> 29) & 3) as isize;
+        let value = if i & (1 << 31) != 0 {
+            ((pc & !0xfff) as isize + (imm << 12)) as usize
+        } else {
+            relative(imm)
+        };
+        set_reg(regs, rt, value);
+        *elr = next;
+    } else if matches(i, 0xbf00_0000, 0x1800_0000) || matches(i, 0xff00_0000, 0x9800_0000) {
+        // ldr (literal), ldrsw (literal)
+        let addr = relative(offset(i, 5, 19, 2));
+        let value = unsafe {
+            match i >> 30 {
+                0 => *(addr as *const u32) as usize,
+                1 => *(addr as *const u64) as usize,
+                _ => *(addr as *const i32) as isize as usize,
+            }
+        };
+        set_reg(regs, rt, value);
+        *elr = next;
+    } else if matches(i, 0xff00_0000, 0xd800_0000) {
+        // prfm (literal) is only a hint
+        *elr = next;
+    } else if matches(i, 0xff9f_fc1f, 0xd61f_0000) {
+        // br, blr, ret
+        let target = get_reg(regs, (i >> 5) & 0x1f);
+        if matches(i, 0xffff_fc1f, 0xd63f_0000) {
+            set_reg(regs, 30, next);
+        }
+        *elr = target;
+    } else {
+        panic!("emulation of this instruction is not supported");
+    }
+}
+
+global_asm!(include_str!("test.S"));
@@ -1,7 +1,7 @@
 use core::arch::{asm, global_asm};
 use core::slice::{from_raw_parts, from_raw_parts_mut};
 use riscv_decode::{CompressedInstruction::*, Instruction::*, *};
-use trapframe::TrapFrame;
+use trapframe::{TrapFrame, UserContext};
 
 use super::kprobes::SingleStepType::{self, *};
 use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt, virt_to_phys};
@@ -51,6 +51,17 @@ impl InstructionBuffer {
     pub fn add_breakpoint(&self, offset: usize) {
         inject_breakpoints(self.addr + offset, None);
     }
+
+    /// Copy the probed instruction in, followed by a breakpoint to trap back
+    pub fn load(&self, addr: usize, len: usize) {
+        self.copy_in(0, addr, len);
+        self.add_breakpoint(len);
+    }
+
+    /// Put the probed instruction back
+    pub fn restore(&self, addr: usize, len: usize) {
+        self.copy_out(0, addr, len);
+    }
 }
 
 impl Drop for InstructionBuffer {
@@ -110,8 +121,88 @@ pub fn set_trapframe_ra(tf: &mut TrapFrame, ra: usize) {
     tf.general.ra = ra;
 }
 
-fn get_reg(tf: &TrapFrame, reg: u32) -> usize {
-    let regs = unsafe { from_raw_parts(&tf.general.zero as *const usize, 32) };
+pub fn get_trapframe_arg(tf: &TrapFrame, n: usize) -> usize {
+    get_reg(regs(&tf.general.zero), 10 + n as u32)
+}
+
+pub fn get_trapframe_retval(tf: &TrapFrame) -> usize {
+    tf.general.a0
+}
+
+// the breakpoint after the copied instruction ends the single step
+pub fn setup_single_step(tf: &mut TrapFrame, insn_addr: usize) {
+    tf.sepc = insn_addr;
+}
+
+pub fn finish_single_step(tf: &mut TrapFrame, insn_addr: usize, pc: usize, len: usize) {
+    tf.sepc = pc + len;
+}
+
+// user context counterparts for uprobes
+pub fn get_user_pc(cx: &UserContext) -> usize {
+    cx.sepc
+}
+
+pub fn set_user_pc(cx: &mut UserContext, pc: usize) {
+    cx.sepc = pc;
+}
+
+pub fn get_user_breakpoint_addr(cx: &UserContext) -> usize {
+    cx.sepc
+}
+
+pub fn get_user_ra(cx: &UserContext) -> usize {
+    cx.general.ra
+}
+
+pub fn set_user_ra(cx: &mut UserContext, ra: usize) {
+    cx.general.ra = ra;
+}
+
+pub fn get_user_arg(cx: &UserContext, n: usize) -> usize {
+    get_reg(regs(&cx.general.zero), 10 + n as u32)
+}
+
+pub fn get_user_retval(cx: &UserContext) -> usize {
+    cx.general.a0
+}
+
+/// Write the code executing the instruction at `insn_addr` out of line to `dst`,
+/// for the probed instruction at user address `pc`
+pub fn load_user_slot(dst: usize, insn_addr: usize, _pc: usize, len: usize) {
+    byte_copy(dst, insn_addr, len);
+    inject_breakpoints(dst + len, None);
+}
+
+// as in the kernel, a breakpoint follows the copied instruction
+pub fn setup_user_single_step(cx: &mut UserContext, slot_addr: usize) {
+    cx.sepc = slot_addr;
+}
+
+pub fn finish_user_single_step(
+    cx: &mut UserContext,
+    _insn_addr: usize,
+    _slot_addr: usize,
+    pc: usize,
+    len: usize,
+) {
+    cx.sepc = pc + len;
+}
+
+pub fn emulate_user_execution(cx: &mut UserContext, insn_addr: usize, pc: usize) {
+    emulate(regs_mut(&mut cx.general.zero), &mut cx.sepc, insn_addr, pc);
+}
+
+// the general registers of a trap frame or a user context, starting from zero
+fn regs(zero: &usize) -> &[usize] {
+    unsafe { from_raw_parts(zero as *const usize, 32) }
+}
+
+fn regs_mut(zero: &mut usize) -> &mut [usize] {
+    unsafe { from_raw_parts_mut(zero as *mut usize, 32) }
+}
+
+fn get_reg(regs: &[usize], reg: u32) -> usize {
     let index = reg as usize;
     if index != 0 {
         regs[index]
@@ -120,8 +211,7 @@ fn get_reg(tf: &TrapFrame, reg: u32) -> usize {
     }
 }
 
-fn set_reg(tf: &mut TrapFrame, reg: u32, val: usize) {
-    let regs = unsafe { from_raw_parts_mut(&mut tf.general.zero as *mut usize, 32) };
+fn set_reg(regs: &mut [usize], reg: u32, val: usize) {
     let index = reg as usize;
     if index != 0 {
         regs[index] = val;
@@ -134,50 +224,77 @@ fn set_reg(tf: &mut TrapFrame, reg: u32, val: usize) {
 // }
 
 pub fn emulate_execution(tf: &mut TrapFrame, insn_addr: usize, pc: usize) {
+    emulate(regs_mut(&mut tf.general.zero), &mut tf.sepc, insn_addr, pc);
+}
+
+// the immediate of c.beqz and c.bnez
+fn cb_offset(i: u16) -> isize {
+    let bit = |from: u16, to: u16| (((i >> from) & 1) << to) as isize;
+    let offset = bit(3, 1) | bit(4, 2) | bit(10, 3) | bit(11, 4) | bit(2, 5) | bit(5, 6)
+        | bit(6, 7) | bit(12, 8);
+    (offset << 55) >> 55
+}
+
+fn emulate(regs: &mut [usize], epc: &mut usize, insn_addr: usize, pc: usize) {
     let i = unsafe { *(insn_addr as *const u32) };
     let insn = decode(i).unwrap();
     match insn {
+        Auipc(_) => {
+            let rd = (i >> 7) & 0x1f;
+            let offset = (i & 0xfffff000) as i32 as isize;
+            set_reg(regs, rd, (pc as isize + offset) as usize);
+            *epc = pc + 4;
+        }
         Jal(j_type) => {
-            let offset = j_type.imm() as isize;
-            tf.sepc = pc + offset as usize;
-            set_reg(tf, j_type.rd(), pc + 4);
+            let offset = j_type.imm() as i32 as isize;
+            *epc = (pc as isize + offset) as usize;
+            set_reg(regs, j_type.rd(), pc + 4);
         }
         Jalr(i_type) => {
-            let offset = i_type.imm() as isize;
-            tf.sepc = get_reg(tf, i_type.rs1()) + offset as usize;
-            set_reg(tf, i_type.rd(), pc + 4);
+            let offset = i_type.imm() as i32 as isize;
+            let target = (get_reg(regs, i_type.rs1()) as isize + offset) as usize;
+            set_reg(regs, i_type.rd(), pc + 4);
+            *epc = target & !1;
         }
-        Beq(b_type) => {
-            let offset = b_type.imm() as isize;
-            let rs1 = get_reg(tf, b_type.rs1());
-            let rs2 = get_reg(tf, b_type.rs2());
-            if rs1 == rs2 {
-                tf.sepc = pc + offset as usize;
+        Beq(b_type) | Bne(b_type) | Blt(b_type) | Bge(b_type) | Bltu(b_type) | Bgeu(b_type) => {
+            let rs1 = get_reg(regs, b_type.rs1());
+            let rs2 = get_reg(regs, b_type.rs2());
+            // by funct3
+            let taken = match (i >> 12) & 7 {
+                0 => rs1 == rs2,
+                1 => rs1 != rs2,
+                4 => (rs1 as isize) < rs2 as isize,
+                5 => rs1 as isize >= rs2 as isize,
+                6 => rs1 < rs2,
+                _ => rs1 >= rs2,
+            };
+            *epc = if taken {
+                (pc as isize + b_type.imm() as i32 as isize) as usize
             } else {
-                tf.sepc = pc + 4;
-            }
-        }
-        Bne(b_type) => {
-            let offset = b_type.imm() as isize;
-            let rs1 = get_reg(tf, b_type.rs1());
-            let rs2 = get_reg(tf, b_type.rs2());
-            if rs1 != rs2 {
-                tf.sepc = pc + offset as usize;
-            } else {
-                tf.sepc = pc + 4;
-            }
+                pc + 4
+            };
         }
         Compressed(c_insn) => match c_insn {
             CJ(cj_type) => {
                 let offset = cj_type.imm() as isize;
-                tf.sepc = pc + offset as usize;
+                *epc = pc + offset as usize;
             }
             CJr(cr_type) => {
-                tf.sepc = get_reg(tf, cr_type.rs1());
+                *epc = get_reg(regs, cr_type.rs1());
             }
             CJalr(cr_type) => {
-                tf.sepc = get_reg(tf, cr_type.rs1());
-                set_reg(tf, 1, pc + 2);
+                *epc = get_reg(regs, cr_type.rs1());
+                set_reg(regs, 1, pc + 2);
+            }
+            CBeqz(_) | CBnez(_) => {
+                let i = i as u16;
+                let rs1 = get_reg(regs, ((i >> 7) & 7) as u32 + 8);
+                let taken = (rs1 == 0) == matches!(c_insn, CBeqz(_));
+                *epc = if taken {
+                    (pc as isize + cb_offset(i)) as usize
+                } else {
+                    pc + 2
+                };
             }
             _ => panic!("emulation of this instruction is not supported"),
         },
@@ -185,66 +302,4 @@ pub fn emulate_execution(tf: &mut TrapFrame, insn_addr: usize, pc: usize) {
     }
 }
 
-struct InstructionIterator {
-    cur: usize,
-    end: usize,
-}
-
-impl InstructionIterator {
-    pub fn new(begin: usize, end: usize) -> Self {
-        Self {
-            cur: begin, end,
-        }
-    }
-}
-
-impl Iterator for InstructionIterator {
-    type Item = (usize, Instruction);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.cur < self.end {
-            let addr = self.cur;
-            let i = unsafe { *(addr as *const u32) };
-            match decode(i) {
-                Ok(insn) => {
-                    self.cur += instruction_length(i as u16);
-                    return Some((addr, insn));
-                }
-                Err(err) => {
-                    // TODO: different increment for different error type
-                    self.cur += instruction_length(i as u16);
-                }
-            }
-        }
-        None
-    }
-}
-
-// criteria: jal / jalr instrution whose rd is ra, or compressed jalr 
-fn is_function_call(insn: Instruction) -> bool {
-    match insn {
-        Jal(j_type) if j_type.rd() == 1 => true,
-        Jalr(i_type) if i_type.rd() == 1 => true,
-        Compressed(c_insn) => {
-            match c_insn {
-                CJalr(_) => true,
-                _ => false,
-            }
-        }
-        _ => false,
-    }
-}
-
-pub fn foreach_function_call(code_range: (usize, usize), action: impl FnOnce(usize) + Copy) -> usize {
-    let mut count = 0;
-    let mut iter = InstructionIterator::new(code_range.0, code_range.1);
-    for (addr, insn) in iter {
-        if is_function_call(insn) {
-            action(addr);
-            count = count + 1;
-        }
-    }
-    count
-}
-
 global_asm!(include_str!("test.S"));
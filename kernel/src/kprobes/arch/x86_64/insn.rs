@@ -0,0 +1,205 @@
+//! A length decoder for the x86_64 instructions found in kernel code.
+//! VEX/EVEX/XOP encoded and legacy-only instructions are rejected.
+
+use core::slice::from_raw_parts;
+
+/// Longest legal instruction
+pub const MAX_INSN_LENGTH: usize = 15;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Insn {
+    pub len: usize,
+    /// Opcode map, 1 for 0F xx, 2 for 0F 38 xx and 3 for 0F 3A xx
+    pub map: u8,
+    pub opcode: u8,
+    pub modrm: Option<u8>,
+    /// Offset and size of the displacement, or of the branch offset
+    pub disp: (usize, usize),
+    /// The memory operand is addressed relative to rip
+    pub rip_relative: bool,
+}
+
+impl Insn {
+    /// The reg field of ModRM, the opcode extension of group instructions
+    pub fn reg(&self) -> u8 {
+        self.modrm.map_or(0, |modrm| (modrm >> 3) & 7)
+    }
+}
+
+const PREFIXES: [u8; 11] = [
+    0xf0, 0xf2, 0xf3, 0x2e, 0x36, 0x3e, 0x26, 0x64, 0x65, 0x66, 0x67,
+];
+
+// operands following the opcode, `Z` is 2 or 4 bytes by operand size
+const N: u8 = 0; // none
+const B: u8 = 1; // imm8 or rel8
+const W: u8 = 2; // imm16
+const Z: u8 = 4;
+const M: u8 = 8; // with ModRM
+const X: u8 = 0xff; // invalid or not supported
+
+#[rustfmt::skip]
+const ONE_BYTE: [u8; 256] = [
+    //  0      1      2      3      4    5    6    7    8      9      a      b      c    d    e    f
+    M,     M,     M,     M,     B,   Z,   X,   X,   M,     M,     M,     M,     B,   Z,   X,   X, // 0
+    M,     M,     M,     M,     B,   Z,   X,   X,   M,     M,     M,     M,     B,   Z,   X,   X, // 1
+    M,     M,     M,     M,     B,   Z,   X,   X,   M,     M,     M,     M,     B,   Z,   X,   X, // 2
+    M,     M,     M,     M,     B,   Z,   X,   X,   M,     M,     M,     M,     B,   Z,   X,   X, // 3
+    X,     X,     X,     X,     X,   X,   X,   X,   X,     X,     X,     X,     X,   X,   X,   X, // 4
+    N,     N,     N,     N,     N,   N,   N,   N,   N,     N,     N,     N,     N,   N,   N,   N, // 5
+    X,     X,     X,     M,     X,   X,   X,   X,   Z,     M | Z, B,     M | B, N,   N,   N,   N, // 6
+    B,     B,     B,     B,     B,   B,   B,   B,   B,     B,     B,     B,     B,   B,   B,   B, // 7
+    M | B, M | Z, X,     M | B, M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // 8
+    N,     N,     N,     N,     N,   N,   N,   N,   N,     N,     X,     N,     N,   N,   N,   N, // 9
+    N,     N,     N,     N,     N,   N,   N,   N,   B,     Z,     N,     N,     N,   N,   N,   N, // a
+    B,     B,     B,     B,     B,   B,   B,   B,   Z,     Z,     Z,     Z,     Z,   Z,   Z,   Z, // b
+    M | B, M | B, W,     N,     X,   X,   M | B, M | Z, W | B, N, W,     N,     N,   B,   X,   N, // c
+    M,     M,     M,     M,     X,   X,   X,   N,   M,     M,     M,     M,     M,   M,   M,   M, // d
+    B,     B,     B,     B,     B,   B,   B,   B,   Z,     Z,     X,     B,     N,   N,   N,   N, // e
+    X,     N,     X,     X,     N,   N,   M,   M,   N,     N,     N,     N,     N,   N,   M,   M, // f
+];
+
+#[rustfmt::skip]
+const TWO_BYTE: [u8; 256] = [
+    //  0      1      2      3      4    5    6    7    8      9      a      b      c    d    e    f
+    M,     M,     M,     M,     X,   N,   N,   N,   N,     N,     X,     N,     X,   M,   X,   X, // 0
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // 1
+    M,     M,     M,     M,     X,   X,   X,   X,   M,     M,     M,     M,     M,   M,   M,   M, // 2
+    N,     N,     N,     N,     N,   N,   X,   N,   X,     X,     X,     X,     X,   X,   X,   X, // 3
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // 4
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // 5
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // 6
+    M | B, M | B, M | B, M | B, M,   M,   M,   N,   M,     M,     X,     X,     M,   M,   M,   M, // 7
+    Z,     Z,     Z,     Z,     Z,   Z,   Z,   Z,   Z,     Z,     Z,     Z,     Z,   Z,   Z,   Z, // 8
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // 9
+    N,     N,     N,     M,     M | B, M, X, X,   N,     N,     N,     M,     M | B, M, M,   M, // a
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M | B, M,     M,   M,   M,   M, // b
+    M,     M,     M | B, M,     M | B, M | B, M | B, M, N, N,   N,     N,     N,   N,   N,   N, // c
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // d
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // e
+    M,     M,     M,     M,     M,   M,   M,   M,   M,     M,     M,     M,     M,   M,   M,   M, // f
+];
+
+/// Decode the instruction at `addr`
+pub fn decode(addr: usize) -> Option<Insn> {
+    let bytes = unsafe { from_raw_parts(addr as *const u8, MAX_INSN_LENGTH) };
+    decode_bytes(bytes)
+}
+
+pub fn decode_bytes(bytes: &[u8]) -> Option<Insn> {
+    let mut pos = 0;
+    let mut operand_16 = false;
+    let mut address_32 = false;
+    while pos < bytes.len() && PREFIXES.contains(&bytes[pos]) {
+        match bytes[pos] {
+            0x66 => operand_16 = true,
+            0x67 => address_32 = true,
+            _ => {}
+        }
+        pos += 1;
+    }
+    let mut rex_w = false;
+    if (*bytes.get(pos)? & 0xf0) == 0x40 {
+        rex_w = bytes[pos] & 8 != 0;
+        pos += 1;
+    }
+
+    let mut opcode = *bytes.get(pos)?;
+    pos += 1;
+    let mut map = 0;
+    let flags = if opcode == 0x0f {
+        opcode = *bytes.get(pos)?;
+        pos += 1;
+        map = 1;
+        match opcode {
+            0x38 | 0x3a => {
+                map = if opcode == 0x38 { 2 } else { 3 };
+                opcode = *bytes.get(pos)?;
+                pos += 1;
+                if map == 2 {
+                    M
+                } else {
+                    M | B
+                }
+            }
+            _ => TWO_BYTE[opcode as usize],
+        }
+    } else {
+        ONE_BYTE[opcode as usize]
+    };
+    if flags == X {
+        return None;
+    }
+
+    let mut insn = Insn {
+        len: 0,
+        map,
+        opcode,
+        modrm: None,
+        disp: (0, 0),
+        rip_relative: false,
+    };
+    if flags & M != 0 {
+        let modrm = *bytes.get(pos)?;
+        pos += 1;
+        insn.modrm = Some(modrm);
+        let (mode, rm) = (modrm >> 6, modrm & 7);
+        if mode != 3 && rm == 4 {
+            let sib = *bytes.get(pos)?;
+            pos += 1;
+            if mode == 0 && sib & 7 == 5 {
+                insn.disp = (pos, 4);
+            }
+        }
+        match mode {
+            0 if rm == 5 => {
+                insn.disp = (pos, 4);
+                insn.rip_relative = true;
+            }
+            1 => insn.disp = (pos, 1),
+            2 => insn.disp = (pos, 4),
+            _ => {}
+        }
+        pos += insn.disp.1;
+    }
+
+    let z = if operand_16 { 2 } else { 4 };
+    let mut imm = 0;
+    if flags & B != 0 {
+        imm += 1;
+    }
+    if flags & W != 0 {
+        imm += 2;
+    }
+    if flags & Z != 0 {
+        imm += z;
+    }
+    // the immediates that depend on more than the opcode
+    if map == 0 {
+        match opcode {
+            // test r/m, imm
+            0xf6 if insn.reg() < 2 => imm = 1,
+            0xf7 if insn.reg() < 2 => imm = z,
+            // mov r64, imm64
+            0xb8..=0xbf if rex_w => imm = 8,
+            // mov with a moffs
+            0xa0..=0xa3 => imm = if address_32 { 4 } else { 8 },
+            // branch offsets are rel32 whatever the operand size
+            0xe8 | 0xe9 => imm = 4,
+            _ => {}
+        }
+        if matches!(opcode, 0x70..=0x7f | 0xe0..=0xe3 | 0xe8 | 0xe9 | 0xeb) {
+            insn.disp = (pos, imm);
+        }
+    } else if map == 1 && (0x80..=0x8f).contains(&opcode) {
+        imm = 4;
+        insn.disp = (pos, imm);
+    }
+    pos += imm;
+
+    if pos > MAX_INSN_LENGTH || pos > bytes.len() {
+        return None;
+    }
+    insn.len = pos;
+    Some(insn)
+}
@@ -0,0 +1,311 @@
+use core::arch::global_asm;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use rcore_memory::PAGE_SIZE;
+use trapframe::{TrapFrame, UserContext};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+use super::code::{map_code_page, unmap_code_page};
+use super::kprobes::SingleStepType::{self, *};
+use crate::arch::cpu;
+use crate::arch::interrupt::no_interrupt;
+use crate::consts::MAX_CPU_NUM;
+use crate::memory::{alloc_frame, dealloc_frame};
+
+mod insn;
+use insn::{decode, Insn};
+
+pub const BREAKPOINT_LENGTH: usize = 1;
+const INT3: u8 = 0xcc;
+
+// the original instruction is kept in the second half of an instruction buffer
+const SAVED_OFFSET: usize = PAGE_SIZE / 2;
+
+const RFLAGS_TF: usize = 1 << 8;
+const RFLAGS_IF: usize = 1 << 9;
+
+// a whole page of its own, mapped read-only and executable
+fn alloc_insn_buffer() -> usize {
+    map_code_page(alloc_frame().unwrap())
+}
+
+fn free_insn_buffer(addr: usize) {
+    dealloc_frame(unmap_code_page(addr))
+}
+
+// kernel text and instruction buffers are mapped read-only,
+// so lift write protection while copying
+pub fn byte_copy(dst_addr: usize, src_addr: usize, len: usize) {
+    let src = unsafe { from_raw_parts(src_addr as *const u8, len) };
+    let dst = unsafe { from_raw_parts_mut(dst_addr as *mut u8, len) };
+    no_interrupt(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        dst.copy_from_slice(src);
+        Cr0::write(cr0);
+    });
+}
+
+pub fn inject_breakpoints(addr: usize, length: Option<usize>) {
+    let int3 = INT3;
+    for i in 0..length.unwrap_or(BREAKPOINT_LENGTH) {
+        byte_copy(addr + i, &int3 as *const u8 as usize, BREAKPOINT_LENGTH);
+    }
+}
+
+pub struct InstructionBuffer {
+    addr: usize,
+}
+
+impl InstructionBuffer {
+    pub fn new() -> Self {
+        let addr = alloc_insn_buffer();
+        Self { addr }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Copy the probed instruction in to be single-stepped.
+    /// A relative call is turned into an absolute one, which works from anywhere.
+    pub fn load(&self, addr: usize, len: usize) {
+        byte_copy(self.addr + SAVED_OFFSET, addr, len);
+        copy_insn(self.addr, addr, addr, len);
+    }
+
+    /// Put the probed instruction back
+    pub fn restore(&self, addr: usize, len: usize) {
+        byte_copy(addr, self.addr + SAVED_OFFSET, len);
+    }
+}
+
+impl Drop for InstructionBuffer {
+    fn drop(&mut self) {
+        free_insn_buffer(self.addr)
+    }
+}
+
+// copy the instruction at `insn_addr`, originally at `pc`, to `dst`
+fn copy_insn(dst: usize, insn_addr: usize, pc: usize, len: usize) {
+    let insn = decode(insn_addr).unwrap();
+    if insn.map == 0 && insn.opcode == 0xe8 {
+        let target = (pc + len) as isize + branch_offset(&insn, insn_addr);
+        // call [rip + 2]; 2 bytes of padding; the target
+        let mut code = [0x90u8; 16];
+        code[..6].copy_from_slice(&[0xff, 0x15, 0x02, 0x00, 0x00, 0x00]);
+        code[8..].copy_from_slice(&target.to_le_bytes());
+        byte_copy(dst, code.as_ptr() as usize, code.len());
+    } else {
+        byte_copy(dst, insn_addr, len);
+    }
+}
+
+// arch related helper functions
+pub fn invalidate_icache() {
+    // instruction fetches snoop stores on x86
+}
+
+pub fn get_insn_length(addr: usize) -> usize {
+    decode(addr).map_or(0, |insn| insn.len)
+}
+
+pub fn get_insn_type(addr: usize) -> SingleStepType {
+    let insn = match decode(addr) {
+        Some(insn) => insn,
+        None => return Unsupported,
+    };
+    match (insn.map, insn.opcode) {
+        // jmp and jcc
+        (0, 0xe9) | (0, 0xeb) | (0, 0x70..=0x7f) | (1, 0x80..=0x8f) => Emulate,
+        // see `InstructionBuffer::load`
+        (0, 0xe8) => Execute,
+        // the copy would address something else
+        _ if insn.rip_relative => Unsupported,
+        // loop and jrcxz
+        (0, 0xe0..=0xe3) => Unsupported,
+        // int3, int, into and iret
+        (0, 0xcc..=0xcf) | (0, 0xf1) => Unsupported,
+        // pushf, popf, cli, sti and hlt see or change the flags used for single-stepping
+        (0, 0x9c) | (0, 0x9d) | (0, 0xfa) | (0, 0xfb) | (0, 0xf4) => Unsupported,
+        // mov to ss delays the debug exception by one instruction
+        (0, 0x8e) => Unsupported,
+        // syscall, sysret, sysenter and sysexit
+        (1, 0x05) | (1, 0x07) | (1, 0x34) | (1, 0x35) => Unsupported,
+        _ => Execute,
+    }
+}
+
+pub fn get_trapframe_pc(tf: &TrapFrame) -> usize {
+    tf.rip
+}
+
+pub fn set_trapframe_pc(tf: &mut TrapFrame, pc: usize) {
+    tf.rip = pc;
+}
+
+// the CPU pushes the interrupted rsp right after the trap frame
+fn trapframe_sp(tf: &TrapFrame) -> *mut usize {
+    unsafe { *((tf as *const TrapFrame).add(1) as *const *mut usize) }
+}
+
+// only valid at function entry, where the return address is on top of the stack
+pub fn get_trapframe_ra(tf: &TrapFrame) -> usize {
+    unsafe { *trapframe_sp(tf) }
+}
+
+pub fn set_trapframe_ra(tf: &mut TrapFrame, ra: usize) {
+    unsafe { *trapframe_sp(tf) = ra }
+}
+
+pub fn get_trapframe_arg(tf: &TrapFrame, n: usize) -> usize {
+    [tf.rdi, tf.rsi, tf.rdx, tf.rcx, tf.r8, tf.r9][n]
+}
+
+pub fn get_trapframe_retval(tf: &TrapFrame) -> usize {
+    tf.rax
+}
+
+const IF_INIT: AtomicBool = AtomicBool::new(false);
+// whether interrupts were enabled where each CPU hit a kprobe
+static SAVED_IF: [AtomicBool; MAX_CPU_NUM] = [IF_INIT; MAX_CPU_NUM];
+
+// a debug exception follows the copied instruction with TF set
+pub fn setup_single_step(tf: &mut TrapFrame, insn_addr: usize) {
+    SAVED_IF[cpu::id()].store(tf.rflags & RFLAGS_IF != 0, Ordering::Relaxed);
+    // no interrupt may come in before the debug exception
+    tf.rflags = (tf.rflags | RFLAGS_TF) & !RFLAGS_IF;
+    tf.rip = insn_addr;
+}
+
+pub fn finish_single_step(tf: &mut TrapFrame, insn_addr: usize, pc: usize, len: usize) {
+    tf.rflags &= !RFLAGS_TF;
+    if SAVED_IF[cpu::id()].load(Ordering::Relaxed) {
+        tf.rflags |= RFLAGS_IF;
+    }
+    let insn = decode(insn_addr).unwrap();
+    if is_function_call(&insn) {
+        // the return address points into the buffer
+        set_trapframe_ra(tf, pc + len);
+    } else if tf.rip == insn_addr + insn.len {
+        tf.rip = pc + len;
+    }
+}
+
+fn branch_offset(insn: &Insn, insn_addr: usize) -> isize {
+    let ptr = (insn_addr + insn.disp.0) as *const u8;
+    unsafe {
+        match insn.disp.1 {
+            1 => *(ptr as *const i8) as isize,
+            _ => (ptr as *const i32).read_unaligned() as isize,
+        }
+    }
+}
+
+// whether condition code `cc` of jcc holds
+fn condition(rflags: usize, cc: u8) -> bool {
+    let flag = |bit: usize| rflags & (1 << bit) != 0;
+    let (cf, pf, zf, sf, of) = (flag(0), flag(2), flag(6), flag(7), flag(11));
+    let holds = match cc >> 1 {
+        0 => of,
+        1 => cf,
+        2 => zf,
+        3 => cf || zf,
+        4 => sf,
+        5 => pf,
+        6 => sf != of,
+        _ => zf || sf != of,
+    };
+    // odd codes are the negations
+    holds != (cc & 1 != 0)
+}
+
+pub fn emulate_execution(tf: &mut TrapFrame, insn_addr: usize, pc: usize) {
+    tf.rip = branch_target(tf.rflags, insn_addr, pc);
+}
+
+// where the jump at `insn_addr`, originally at `pc`, goes
+fn branch_target(rflags: usize, insn_addr: usize, pc: usize) -> usize {
+    let insn = decode(insn_addr).unwrap();
+    let next = pc + insn.len;
+    let taken = match (insn.map, insn.opcode) {
+        (0, 0xe9) | (0, 0xeb) => true,
+        (0, cc @ 0x70..=0x7f) | (1, cc @ 0x80..=0x8f) => condition(rflags, cc & 0xf),
+        _ => panic!("emulation of this instruction is not supported"),
+    };
+    if taken {
+        (next as isize + branch_offset(&insn, insn_addr)) as usize
+    } else {
+        next
+    }
+}
+
+// user context counterparts for uprobes
+pub fn get_user_pc(cx: &UserContext) -> usize {
+    cx.general.rip
+}
+
+pub fn set_user_pc(cx: &mut UserContext, pc: usize) {
+    cx.general.rip = pc;
+}
+
+// rip is past the int3
+pub fn get_user_breakpoint_addr(cx: &UserContext) -> usize {
+    cx.general.rip - BREAKPOINT_LENGTH
+}
+
+// the user stack is accessible from the kernel
+pub fn get_user_ra(cx: &UserContext) -> usize {
+    unsafe { *(cx.general.rsp as *const usize) }
+}
+
+pub fn set_user_ra(cx: &mut UserContext, ra: usize) {
+    unsafe { *(cx.general.rsp as *mut usize) = ra }
+}
+
+pub fn get_user_arg(cx: &UserContext, n: usize) -> usize {
+    let regs = &cx.general;
+    [regs.rdi, regs.rsi, regs.rdx, regs.rcx, regs.r8, regs.r9][n]
+}
+
+pub fn get_user_retval(cx: &UserContext) -> usize {
+    cx.general.rax
+}
+
+/// Write the code executing the instruction at `insn_addr` out of line to `dst`,
+/// for the probed instruction at user address `pc`
+pub fn load_user_slot(dst: usize, insn_addr: usize, pc: usize, len: usize) {
+    copy_insn(dst, insn_addr, pc, len);
+}
+
+pub fn setup_user_single_step(cx: &mut UserContext, slot_addr: usize) {
+    cx.general.rflags |= RFLAGS_TF;
+    cx.general.rip = slot_addr;
+}
+
+pub fn finish_user_single_step(
+    cx: &mut UserContext,
+    insn_addr: usize,
+    slot_addr: usize,
+    pc: usize,
+    len: usize,
+) {
+    cx.general.rflags &= !RFLAGS_TF;
+    let insn = decode(insn_addr).unwrap();
+    if is_function_call(&insn) {
+        set_user_ra(cx, pc + len);
+    } else if cx.general.rip == slot_addr + insn.len {
+        cx.general.rip = pc + len;
+    }
+}
+
+pub fn emulate_user_execution(cx: &mut UserContext, insn_addr: usize, pc: usize) {
+    cx.general.rip = branch_target(cx.general.rflags, insn_addr, pc);
+}
+
+// criteria: relative calls and indirect ones (ff /2)
+fn is_function_call(insn: &Insn) -> bool {
+    insn.map == 0 && (insn.opcode == 0xe8 || insn.opcode == 0xff && insn.reg() == 2)
+}
+
+global_asm!(include_str!("test.S"));
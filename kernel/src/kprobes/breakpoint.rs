@@ -0,0 +1,66 @@
+use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt};
+use crate::sync::SpinLock as Mutex;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use lazy_static::*;
+use rcore_memory::PAGE_SIZE;
+
+use super::arch::{inject_breakpoints, invalidate_icache, BREAKPOINT_LENGTH};
+use super::code::{map_code_page, unmap_code_page};
+
+const BREAKPOINTS_PER_PAGE: usize = PAGE_SIZE / BREAKPOINT_LENGTH;
+
+struct BreakpointPage {
+    pub nr_free: usize,
+}
+
+lazy_static! {
+    static ref FREE_BREAKPOINTS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+    static ref BREAKPOINT_PAGES: Mutex<BTreeMap<usize, BreakpointPage>> =
+        Mutex::new(BTreeMap::new());
+}
+
+pub fn alloc_breakpoint() -> usize {
+    let mut free_bps = FREE_BREAKPOINTS.lock();
+    if free_bps.len() != 0 {
+        let addr = free_bps.pop_first().unwrap();
+        let base = addr & !(PAGE_SIZE - 1);
+        let mut pages = BREAKPOINT_PAGES.lock();
+        let page = pages.get_mut(&base).unwrap();
+        page.nr_free -= 1;
+        return addr;
+    }
+
+    // filled through the linear mapping before it is mapped as code
+    let frame = alloc_frame().unwrap();
+    inject_breakpoints(phys_to_virt(frame), Some(PAGE_SIZE));
+    let base = map_code_page(frame);
+    invalidate_icache();
+    for i in 1..BREAKPOINTS_PER_PAGE {
+        free_bps.insert(base + i * BREAKPOINT_LENGTH);
+    }
+
+    let page = BreakpointPage {
+        nr_free: BREAKPOINTS_PER_PAGE - 1,
+    };
+    BREAKPOINT_PAGES.lock().insert(base, page);
+    base
+}
+
+pub fn free_breakpoint(addr: usize) {
+    let mut free_bps = FREE_BREAKPOINTS.lock();
+    free_bps.insert(addr);
+
+    let base = addr & !(PAGE_SIZE - 1);
+    let mut pages = BREAKPOINT_PAGES.lock();
+    let page = pages.get_mut(&base).unwrap();
+    page.nr_free += 1;
+
+    if page.nr_free == BREAKPOINTS_PER_PAGE && pages.len() > 1 {
+        for i in 0..BREAKPOINTS_PER_PAGE {
+            free_bps.remove(&(base + i * BREAKPOINT_LENGTH));
+        }
+        pages.remove(&base);
+        dealloc_frame(unmap_code_page(base))
+    }
+}
@@ -0,0 +1,45 @@
+//! Pages of code written at runtime, for instruction buffers and breakpoints.
+//!
+//! The linear mapping of physical memory is never executable, so the frames
+//! get a second, read-only mapping in the kernel virtual memory (KSEG2).
+
+use crate::lkm::kernelvm::{MemorySpaceManager, KERNELVM_MANAGER};
+use rcore_memory::paging::PageTable;
+use rcore_memory::PAGE_SIZE;
+
+/// Map the frame at `paddr` as kernel code, return its address
+pub fn map_code_page(paddr: usize) -> usize {
+    let mut vmm = KERNELVM_MANAGER.lock();
+    let (addr, _) = vmm.alloc(PAGE_SIZE).expect("out of kernel virtual memory");
+    let mut page_table = vmm.kernel_table();
+    let entry = page_table.map(addr, paddr);
+    entry.set_writable(false);
+    entry.set_execute(true);
+    entry.update();
+    addr
+}
+
+/// Unmap a page of `map_code_page`, return the address of its frame
+pub fn unmap_code_page(addr: usize) -> usize {
+    let mut vmm = KERNELVM_MANAGER.lock();
+    let mut page_table = vmm.kernel_table();
+    let paddr = page_table
+        .get_entry(addr)
+        .expect("code page is not mapped")
+        .target();
+    page_table.unmap(addr);
+    vmm.free((addr, PAGE_SIZE));
+    paddr
+}
+
+/// The frame mapped at `addr` in KSEG2, if any
+pub fn code_page_frame(addr: usize) -> Option<usize> {
+    let vmm = KERNELVM_MANAGER.lock();
+    let mut page_table = vmm.kernel_table();
+    let entry = page_table.get_entry(addr & !(PAGE_SIZE - 1))?;
+    if entry.present() {
+        Some(entry.target())
+    } else {
+        None
+    }
+}
@@ -1,8 +1,11 @@
+use crate::arch::cpu;
+use crate::consts::MAX_CPU_NUM;
 use crate::sync::SpinLock as Mutex;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
 use core::ops::Fn;
 use core::slice::from_raw_parts;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::*;
 
 use super::arch::*;
@@ -34,6 +37,11 @@ lazy_static! {
     static ref ADDR_MAP: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
 }
 
+const NOT_STEPPING: AtomicUsize = AtomicUsize::new(0);
+// the probe whose instruction each CPU is single-stepping, for arches that trap with
+// a single-step exception instead of a breakpoint after the copied instruction
+static STEPPING: [AtomicUsize; MAX_CPU_NUM] = [NOT_STEPPING; MAX_CPU_NUM];
+
 impl KProbe {
     pub fn new(
         addr: usize,
@@ -56,8 +64,7 @@ impl KProbe {
 
     pub fn arm(&self) {
         // write instruction buffer
-        self.insn_buf.copy_in(0, self.addr, self.insn_len);
-        self.insn_buf.add_breakpoint(self.insn_len);
+        self.insn_buf.load(self.addr, self.insn_len);
         // replace original instruction with breakpoints
         inject_breakpoints(self.addr, Some(self.insn_len));
         invalidate_icache();
@@ -65,9 +72,19 @@ impl KProbe {
 
     pub fn disarm(&self) {
         // change to original instruction
-        self.insn_buf.copy_out(0, self.addr, self.insn_len);
+        self.insn_buf.restore(self.addr, self.insn_len);
         invalidate_icache();
     }
+
+    // the copied instruction has been executed
+    fn single_step_done(&mut self, tf: &mut TrapFrame) {
+        STEPPING[cpu::id()].store(0, Ordering::Relaxed);
+        finish_single_step(tf, self.insn_buf.addr(), self.addr, self.insn_len);
+        if let Some(handler) = &self.post_handler {
+            let _ = handler(tf, self.user_data);
+        }
+        self.active_count -= 1;
+    }
 }
 
 // returns whether this event is handled
@@ -91,22 +108,31 @@ pub fn kprobe_trap_handler(tf: &mut TrapFrame) -> bool {
 
         // redirect to instruction buffer (single step type is 'execute')
         // warn!("redirect target {:#x}", probe.insn_buf.addr());
-        set_trapframe_pc(tf, probe.insn_buf.addr());
+        STEPPING[cpu::id()].store(pc, Ordering::Relaxed);
+        setup_single_step(tf, probe.insn_buf.addr());
         return true;
     }
 
     if let Some(orig_addr) = ADDR_MAP.lock().get(&pc) {
         let probe = map.get_mut(orig_addr).unwrap();
-        if let Some(handler) = &probe.post_handler {
-            let _ = handler(tf, probe.user_data);
-        }
-        probe.active_count -= 1;
-        set_trapframe_pc(tf, *orig_addr + probe.insn_len);
+        probe.single_step_done(tf);
         return true;
     }
     false
 }
 
+// returns whether this single-step exception comes from a kprobe
+pub fn kprobe_single_step_handler(tf: &mut TrapFrame) -> bool {
+    let addr = STEPPING[cpu::id()].load(Ordering::Relaxed);
+    if addr == 0 {
+        return false;
+    }
+    let mut map = KPROBES.lock();
+    let probe = map.get_mut(&addr).unwrap();
+    probe.single_step_done(tf);
+    true
+}
+
 pub fn register_kprobe(addr: usize, args: KProbeArgs) -> bool {
     let mut map = KPROBES.lock();
     if map.contains_key(&addr) {
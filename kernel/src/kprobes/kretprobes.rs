@@ -6,9 +6,10 @@ use lazy_static::*;
 use crate::num::FromPrimitive;
 
 use super::arch::{
-    alloc_breakpoint, free_breakpoint, get_trapframe_pc, get_trapframe_ra, set_trapframe_pc,
+    get_trapframe_arg, get_trapframe_pc, get_trapframe_ra, get_trapframe_retval, set_trapframe_pc,
     set_trapframe_ra,
 };
+use super::breakpoint::{alloc_breakpoint, free_breakpoint};
 use super::kprobes::{register_kprobe, unregister_kprobe, Handler};
 use super::{KProbeArgs, KRetProbeArgs, TrapFrame};
 
@@ -92,7 +93,10 @@ pub fn kretprobe_trap_handler(tf: &mut TrapFrame) -> bool {
 
     let pc = get_trapframe_pc(tf);
     let mut instance_map = INSTANCES.lock();
-    let instance = instance_map.get(&pc).unwrap();
+    let instance = match instance_map.get(&pc) {
+        Some(instance) => instance,
+        None => return false,
+    };
 
     let probe = kretprobes.get_mut(&instance.entry_addr).unwrap();
     let _ = (probe.exit_handler)(tf, probe.user_data);
@@ -100,6 +104,8 @@ pub fn kretprobe_trap_handler(tf: &mut TrapFrame) -> bool {
 
     let ra = instance.ret_addr;
     set_trapframe_pc(tf, ra);
+    // x86_64 has popped the return address, its slot may be reused by now
+    #[cfg(not(target_arch = "x86_64"))]
     set_trapframe_ra(tf, ra);
     free_breakpoint(pc);
     instance_map.remove(&pc).unwrap();
@@ -154,12 +160,12 @@ fn recursive_fn(i: isize) -> isize {
 }
 
 fn test_entry_handler(tf: &mut TrapFrame, _data: usize) -> isize {
-    warn!("entering fn, a0 = {}", tf.general.a0);
+    warn!("entering fn, a0 = {}", get_trapframe_arg(tf, 0));
     0
 }
 
 fn test_exit_handler(tf: &mut TrapFrame, _data: usize) -> isize {
-    warn!("exiting fn, a0 = {}", tf.general.a0);
+    warn!("exiting fn, a0 = {}", get_trapframe_retval(tf));
     0
 }
 
@@ -203,7 +209,7 @@ pub fn run_kretprobes_test() {
 
 pub fn kretprobe_recover_sysresult(tf: &TrapFrame) -> Option<SysResult> {
     // recover sysresult from trapfram
-    let general_ret_addr = tf.general.a0 as usize;
+    let general_ret_addr = get_trapframe_retval(tf);
     let enum_flag_addr = general_ret_addr as *const u8;
     let enum_flag = unsafe { *enum_flag_addr };
     if enum_flag == 0 {
@@ -1,15 +1,29 @@
+mod breakpoint;
+mod code;
 pub mod kprobes;
 pub mod kretprobes;
-pub mod trace;
+pub mod uprobes;
 
+use crate::process::Thread;
 use alloc::sync::Arc;
 use kprobes::{Handler, HandlerFn};
-pub use trapframe::TrapFrame;
+use rcore_fs::vfs::INode;
+pub use trapframe::{TrapFrame, UserContext};
+use uprobes::UProbeHandler;
+pub use uprobes::{patch_uprobes, UProbeTask, XolArea};
 
 #[cfg(riscv)]
 #[path = "arch/riscv/mod.rs"]
 mod arch;
 
+#[cfg(target_arch = "x86_64")]
+#[path = "arch/x86_64/mod.rs"]
+mod arch;
+
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64/mod.rs"]
+mod arch;
+
 pub struct KProbeArgs {
     pub pre_handler: Arc<Handler>,
     pub post_handler: Option<Arc<Handler>>,
@@ -24,6 +38,13 @@ pub struct KRetProbeArgs {
     pub user_data: usize,
 }
 
+pub struct UProbeArgs {
+    pub handler: Option<Arc<UProbeHandler>>,
+    // called when the function returns, the probe must be at its entry
+    pub ret_handler: Option<Arc<UProbeHandler>>,
+    pub user_data: usize,
+}
+
 impl KProbeArgs {
     pub fn from(handler: HandlerFn) -> Self {
         Self {
@@ -73,9 +94,53 @@ pub fn unregister_kretprobe(addr: usize) -> Option<()> {
     }
 }
 
-pub fn breakpoint_handler(tf: &mut TrapFrame) {
-    let handled = kprobes::kprobe_trap_handler(tf);
-    if !handled {
-        kretprobes::kretprobe_trap_handler(tf);
+/// Probe the instruction at `offset` of an ELF file in all processes running it
+pub fn register_uprobe(inode: &Arc<dyn INode>, offset: usize, args: UProbeArgs) -> Option<()> {
+    match uprobes::register_uprobe(inode, offset, args) {
+        true => Some(()),
+        false => None,
+    }
+}
+
+pub fn unregister_uprobe(inode: &Arc<dyn INode>, offset: usize) -> Option<()> {
+    match uprobes::unregister_uprobe(inode, offset) {
+        true => Some(()),
+        false => None,
     }
 }
+
+/// The `n`th argument, from 0, of a function probed at its entry
+pub fn get_arg(tf: &TrapFrame, n: usize) -> usize {
+    arch::get_trapframe_arg(tf, n)
+}
+
+/// The return value of a function, in kretprobe exit handlers
+pub fn get_retval(tf: &TrapFrame) -> usize {
+    arch::get_trapframe_retval(tf)
+}
+
+/// The return address of a function probed at its entry
+pub fn get_return_address(tf: &TrapFrame) -> usize {
+    arch::get_trapframe_ra(tf)
+}
+
+/// Returns whether the breakpoint belongs to a kprobe or kretprobe.
+/// The pc in `tf` must point to the breakpoint itself.
+pub fn breakpoint_handler(tf: &mut TrapFrame) -> bool {
+    kprobes::kprobe_trap_handler(tf) || kretprobes::kretprobe_trap_handler(tf)
+}
+
+/// Returns whether the single-step exception belongs to a kprobe
+pub fn single_step_handler(tf: &mut TrapFrame) -> bool {
+    kprobes::kprobe_single_step_handler(tf)
+}
+
+/// Returns whether the breakpoint from user mode belongs to a uprobe or uretprobe.
+pub fn uprobe_breakpoint_handler(thread: &Arc<Thread>, cx: &mut UserContext) -> bool {
+    uprobes::uprobe_breakpoint_handler(thread, cx)
+}
+
+/// Returns whether the single-step exception from user mode belongs to a uprobe
+pub fn uprobe_single_step_handler(thread: &Arc<Thread>, cx: &mut UserContext) -> bool {
+    uprobes::uprobe_single_step_handler(thread, cx)
+}
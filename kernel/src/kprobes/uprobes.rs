@@ -0,0 +1,396 @@
+use crate::memory::{ByFrame, MemCgFrameAlloc, MemoryAttr, MemorySet};
+use crate::process::{INodeForMap, Thread, PROCESSES};
+use crate::sync::SpinLock as Mutex;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Fn;
+use lazy_static::*;
+use rcore_fs::vfs::INode;
+use rcore_memory::paging::PageTable;
+use rcore_memory::PAGE_SIZE;
+use trapframe::UserContext;
+
+use super::arch::*;
+use super::kprobes::SingleStepType;
+use super::UProbeArgs;
+
+pub type UProbeHandler = dyn Fn(&mut UserContext, usize) -> isize + Sync + Send;
+
+// enough for the longest instruction on all arches
+const MAX_INSN_LENGTH: usize = 16;
+// an instruction executed out of line, with what follows it
+const SLOT_SIZE: usize = 32;
+
+// probe points are identified by the file, so they apply to every process mapping it
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct UProbeKey {
+    dev: usize,
+    inode: usize,
+    offset: usize,
+}
+
+struct UProbe {
+    handler: Option<Arc<UProbeHandler>>,
+    ret_handler: Option<Arc<UProbeHandler>>,
+    user_data: usize,
+    // the original instruction, as read from the file
+    insn: [u8; MAX_INSN_LENGTH],
+    insn_len: usize,
+    emulate: bool,
+}
+
+lazy_static! {
+    static ref UPROBES: Mutex<BTreeMap<UProbeKey, Arc<UProbe>>> = Mutex::new(BTreeMap::new());
+}
+
+/// A page in a process to execute probed instructions out of line.
+/// The first slot is where uretprobes return to.
+pub struct XolArea {
+    addr: usize,
+    free: Vec<usize>,
+}
+
+impl XolArea {
+    fn new(vm: &mut MemorySet, allocator: MemCgFrameAlloc) -> Self {
+        use crate::consts::USER_STACK_OFFSET;
+        let addr = vm.find_free_area(USER_STACK_OFFSET - PAGE_SIZE, PAGE_SIZE);
+        vm.push(
+            addr,
+            addr + PAGE_SIZE,
+            MemoryAttr::default().user().execute().readonly(),
+            ByFrame::new(allocator),
+            "uprobes",
+        );
+        let data = vm.get_page_table_mut().get_page_slice_mut(addr);
+        inject_breakpoints(data.as_mut_ptr() as usize, None);
+        invalidate_icache();
+        Self::with_addr(addr)
+    }
+
+    fn with_addr(addr: usize) -> Self {
+        Self {
+            addr,
+            free: (1..PAGE_SIZE / SLOT_SIZE)
+                .rev()
+                .map(|i| addr + i * SLOT_SIZE)
+                .collect(),
+        }
+    }
+
+    /// The area of a forked process, which has a copy of the page
+    pub fn fork(&self) -> Self {
+        Self::with_addr(self.addr)
+    }
+
+    fn trampoline(&self) -> usize {
+        self.addr
+    }
+}
+
+/// Uprobe state of a thread
+#[derive(Default)]
+pub struct UProbeTask {
+    stepping: Option<Stepping>,
+    returns: Vec<ReturnInstance>,
+}
+
+// a probed instruction executing in a slot
+struct Stepping {
+    probe: Arc<UProbe>,
+    slot: usize,
+    pc: usize,
+}
+
+#[derive(Clone)]
+struct ReturnInstance {
+    probe: Arc<UProbe>,
+    ra: usize,
+}
+
+impl UProbeTask {
+    /// The state of the thread in a forked process, which returns to the same functions
+    pub fn fork(&self) -> Self {
+        Self {
+            stepping: None,
+            returns: self.returns.clone(),
+        }
+    }
+}
+
+fn key_of(inode: &Arc<dyn INode>, offset: usize) -> Option<UProbeKey> {
+    let metadata = inode.metadata().ok()?;
+    Some(UProbeKey {
+        dev: metadata.dev,
+        inode: metadata.inode,
+        offset,
+    })
+}
+
+fn breakpoint() -> [u8; BREAKPOINT_LENGTH] {
+    let mut bp = [0u8; BREAKPOINT_LENGTH];
+    inject_breakpoints(bp.as_mut_ptr() as usize, None);
+    bp
+}
+
+// copy the part of `data` at file offset `pos` that falls in `buf` at file offset `offset`,
+// `byte_copy` makes it visible to instruction fetches in any address space
+fn write_at(buf: &mut [u8], offset: usize, data: &[u8], pos: usize) {
+    let start = pos.max(offset);
+    let end = (pos + data.len()).min(offset + buf.len());
+    if start < end {
+        let dst = buf[(start - offset)..].as_mut_ptr() as usize;
+        byte_copy(dst, data[(start - pos)..].as_ptr() as usize, end - start);
+    }
+}
+
+// write `data` at the probe point in every process that has it loaded
+fn patch_processes(key: UProbeKey, data: &[u8]) {
+    let vms: Vec<_> = PROCESSES
+        .read()
+        .values()
+        .map(|proc| proc.lock().vm.clone())
+        .collect();
+    for vm in vms {
+        vm.lock().patch_code(|file, offset, buf| {
+            if key.offset + data.len() <= offset || key.offset >= offset + buf.len() {
+                return;
+            }
+            if let Some(file) = file.downcast_ref::<INodeForMap>() {
+                if key_of(&file.0, key.offset) == Some(key) {
+                    write_at(buf, offset, data, key.offset);
+                }
+            }
+        });
+    }
+    invalidate_icache();
+}
+
+/// Insert breakpoints of uprobes into code at `offset` of `inode` just loaded
+pub fn patch_uprobes(inode: &Arc<dyn INode>, offset: usize, buf: &mut [u8]) {
+    if UPROBES.lock().is_empty() {
+        return;
+    }
+    let start = match key_of(inode, offset.saturating_sub(BREAKPOINT_LENGTH)) {
+        Some(key) => key,
+        None => return,
+    };
+    let end = UProbeKey {
+        offset: offset + buf.len(),
+        ..start
+    };
+    let bp = breakpoint();
+    for key in UPROBES.lock().range(start..end).map(|(key, _)| key) {
+        write_at(buf, offset, &bp, key.offset);
+    }
+}
+
+pub fn register_uprobe(inode: &Arc<dyn INode>, offset: usize, args: UProbeArgs) -> bool {
+    let key = match key_of(inode, offset) {
+        Some(key) => key,
+        None => return false,
+    };
+    let mut insn = [0u8; MAX_INSN_LENGTH];
+    match inode.read_at(offset, &mut insn) {
+        Ok(len) if len > 0 => {}
+        _ => {
+            error!("[uprobe] cannot read instruction at offset {:#x}", offset);
+            return false;
+        }
+    }
+    let insn_addr = insn.as_ptr() as usize;
+    let insn_type = get_insn_type(insn_addr);
+    if insn_type == SingleStepType::Unsupported {
+        error!("[uprobe] target instruction is not supported");
+        return false;
+    }
+    let probe = UProbe {
+        handler: args.handler,
+        ret_handler: args.ret_handler,
+        user_data: args.user_data,
+        insn,
+        insn_len: get_insn_length(insn_addr),
+        emulate: insn_type == SingleStepType::Emulate,
+    };
+
+    // the lock can't be held while patching, page faults take it too
+    {
+        let mut map = UPROBES.lock();
+        if map.contains_key(&key) {
+            error!("uprobe for offset {:#x} already exist", offset);
+            return false;
+        }
+        map.insert(key, Arc::new(probe));
+    }
+    patch_processes(key, &breakpoint());
+    warn!("uprobe for offset {:#x} inserted", offset);
+    true
+}
+
+pub fn unregister_uprobe(inode: &Arc<dyn INode>, offset: usize) -> bool {
+    let key = match key_of(inode, offset) {
+        Some(key) => key,
+        None => return false,
+    };
+    // threads in the middle of it keep their reference
+    let probe = match UPROBES.lock().remove(&key) {
+        Some(probe) => probe,
+        None => return false,
+    };
+    patch_processes(key, &probe.insn[..BREAKPOINT_LENGTH]);
+    true
+}
+
+// the uprobe of the breakpoint at `addr`
+fn find_uprobe(thread: &Thread, addr: usize) -> Option<Arc<UProbe>> {
+    let (inode, offset) = {
+        let vm = thread.vm.lock();
+        let (file, offset) = vm.file_offset(addr)?;
+        (file.downcast_ref::<INodeForMap>()?.0.clone(), offset)
+    };
+    let key = key_of(&inode, offset)?;
+    UPROBES.lock().get(&key).cloned()
+}
+
+// the uprobe at `addr` was removed after the breakpoint was hit
+fn breakpoint_removed(addr: usize) -> bool {
+    let bp = breakpoint();
+    let code = unsafe { core::slice::from_raw_parts(addr as *const u8, BREAKPOINT_LENGTH) };
+    code != bp
+}
+
+// map the area of the process if it has none, returns the trampoline
+// and a free slot if one is needed
+fn alloc_slot(thread: &Thread, need_slot: bool) -> Option<(usize, Option<usize>)> {
+    let mut proc = thread.proc.lock();
+    if proc.xol_area.is_none() {
+        let allocator = proc.frame_allocator();
+        proc.xol_area = Some(XolArea::new(&mut thread.vm.lock(), allocator));
+    }
+    let xol = proc.xol_area.as_mut().unwrap();
+    if !need_slot {
+        return Some((xol.trampoline(), None));
+    }
+    let slot = xol.free.pop()?;
+    Some((xol.trampoline(), Some(slot)))
+}
+
+fn free_slot(thread: &Thread, slot: usize) {
+    if let Some(xol) = thread.proc.lock().xol_area.as_mut() {
+        xol.free.push(slot);
+    }
+}
+
+fn load_slot(thread: &Thread, slot: usize, probe: &UProbe, pc: usize) {
+    let mut vm = thread.vm.lock();
+    let page = slot & !(PAGE_SIZE - 1);
+    let data = vm.get_page_table_mut().get_page_slice_mut(page);
+    let dst = data.as_mut_ptr() as usize + slot - page;
+    load_user_slot(dst, probe.insn.as_ptr() as usize, pc, probe.insn_len);
+    invalidate_icache();
+}
+
+// the instruction in the slot has been executed
+fn single_step_done(thread: &Thread, cx: &mut UserContext, step: Stepping) {
+    let probe = &step.probe;
+    let insn_addr = probe.insn.as_ptr() as usize;
+    finish_user_single_step(cx, insn_addr, step.slot, step.pc, probe.insn_len);
+    free_slot(thread, step.slot);
+}
+
+/// Returns whether the breakpoint belongs to a uprobe or uretprobe
+pub fn uprobe_breakpoint_handler(thread: &Arc<Thread>, cx: &mut UserContext) -> bool {
+    let addr = get_user_breakpoint_addr(cx);
+
+    // the breakpoint after the instruction in a slot
+    let step = {
+        let mut inner = thread.inner.lock();
+        let task = &mut inner.uprobe_task;
+        match &task.stepping {
+            Some(step) if addr == step.slot + step.probe.insn_len => task.stepping.take(),
+            _ => None,
+        }
+    };
+    if let Some(step) = step {
+        single_step_done(thread, cx, step);
+        return true;
+    }
+
+    // returning from a function with a uretprobe
+    let trampoline = thread
+        .proc
+        .lock()
+        .xol_area
+        .as_ref()
+        .map(XolArea::trampoline);
+    if trampoline == Some(addr) {
+        let instance = match thread.inner.lock().uprobe_task.returns.pop() {
+            Some(instance) => instance,
+            None => return false,
+        };
+        let probe = &instance.probe;
+        if let Some(handler) = &probe.ret_handler {
+            let _ = handler(cx, probe.user_data);
+        }
+        set_user_pc(cx, instance.ra);
+        // x86_64 has popped the return address
+        #[cfg(not(target_arch = "x86_64"))]
+        set_user_ra(cx, instance.ra);
+        return true;
+    }
+
+    let probe = match find_uprobe(thread, addr) {
+        Some(probe) => probe,
+        None if breakpoint_removed(addr) => {
+            set_user_pc(cx, addr);
+            return true;
+        }
+        None => return false,
+    };
+    let (trampoline, slot) = match alloc_slot(thread, !probe.emulate) {
+        Some(result) => result,
+        None => {
+            // all slots are in use, try again later
+            set_user_pc(cx, addr);
+            return true;
+        }
+    };
+
+    set_user_pc(cx, addr);
+    if let Some(handler) = &probe.handler {
+        let _ = handler(cx, probe.user_data);
+    }
+    if probe.ret_handler.is_some() {
+        let instance = ReturnInstance {
+            probe: probe.clone(),
+            ra: get_user_ra(cx),
+        };
+        thread.inner.lock().uprobe_task.returns.push(instance);
+        set_user_ra(cx, trampoline);
+    }
+
+    let insn_addr = probe.insn.as_ptr() as usize;
+    match slot {
+        None => emulate_user_execution(cx, insn_addr, addr),
+        Some(slot) => {
+            load_slot(thread, slot, &probe, addr);
+            setup_user_single_step(cx, slot);
+            thread.inner.lock().uprobe_task.stepping = Some(Stepping {
+                probe,
+                slot,
+                pc: addr,
+            });
+        }
+    }
+    true
+}
+
+/// Returns whether the single-step exception comes from a uprobe
+pub fn uprobe_single_step_handler(thread: &Arc<Thread>, cx: &mut UserContext) -> bool {
+    let step = match thread.inner.lock().uprobe_task.stepping.take() {
+        Some(step) => step,
+        None => return false,
+    };
+    single_step_done(thread, cx, step);
+    true
+}
@@ -44,6 +44,7 @@ pub mod lang;
 pub mod lkm;
 pub mod memory;
 pub mod net;
+pub mod perf;
 pub mod process;
 #[cfg(feature = "hypervisor")]
 pub mod rvm;
@@ -51,6 +52,7 @@ pub mod shell;
 pub mod signal;
 pub mod sync;
 pub mod syscall;
+pub mod tracepoint;
 pub mod trap;
 
 #[allow(dead_code)]
@@ -72,13 +74,11 @@ pub mod arch;
 
 pub mod bpf;
 pub mod kprobes;
+pub mod trace;
 
 pub fn kmain() -> ! {
     kprobes::kprobes::run_kprobes_tests();
     kprobes::kretprobes::run_kretprobes_test();
-    if arch::cpu::id() == 0 {
-        kprobes::trace::run_dynamic_trace_test();
-    }
 
     loop {
         executor::run_until_idle();
@@ -89,8 +89,9 @@ pub fn kmain() -> ! {
 /// Global heap allocator
 ///
 /// Available after `memory::init()`.
+/// Small objects come from slab caches, the rest from the buddy heap.
 ///
 /// It should be defined in memory mod, but in Rust `global_allocator` must be in root mod.
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeapWithRescue<32> =
-    LockedHeapWithRescue::new(crate::memory::enlarge_heap);
+static HEAP_ALLOCATOR: memory::SlabAllocator =
+    memory::SlabAllocator::new(crate::memory::enlarge_heap);
@@ -1,6 +1,6 @@
 // Simple kernel memory set for kernel virtual memory
 use crate::arch::paging::PageTableImpl;
-use crate::memory::GlobalFrameAlloc;
+use crate::memory::MemCgFrameAlloc;
 use crate::sync::SpinLock as Mutex;
 use alloc::vec::*;
 use core::mem::ManuallyDrop;
@@ -8,6 +8,7 @@ use core::ops::DerefMut;
 use lazy_static::lazy_static;
 use rcore_memory::memory_set::handler::{ByFrame, MemoryHandler};
 use rcore_memory::memory_set::MemoryAttr;
+use rcore_memory::paging::PageTable;
 use rcore_memory::{Page, PAGE_SIZE};
 
 ///Allocated virtual memory space by pages. returns some vaddr.
@@ -59,11 +60,16 @@ pub struct VirtualSpace {
     size: usize,
     areas: Vec<VirtualArea>,
     allocator: &'static LockedVMM,
-    page_allocator: ByFrame<GlobalFrameAlloc>,
+    page_allocator: ByFrame<MemCgFrameAlloc>,
 }
 
 impl VirtualSpace {
-    pub fn new(allocator: &'static LockedVMM, size: usize) -> Option<VirtualSpace> {
+    /// Pages are charged to the memory cgroup of `page_allocator`
+    pub fn new(
+        allocator: &'static LockedVMM,
+        size: usize,
+        page_allocator: MemCgFrameAlloc,
+    ) -> Option<VirtualSpace> {
         let mut vmm = allocator.lock();
         let (start, rsize) = vmm.alloc(size)?;
         Some(VirtualSpace {
@@ -71,7 +77,7 @@ impl VirtualSpace {
             size: rsize,
             areas: Vec::new(),
             allocator: allocator,
-            page_allocator: ByFrame::new(GlobalFrameAlloc),
+            page_allocator: ByFrame::new(page_allocator),
         })
     }
     pub fn start(&self) -> usize {
@@ -81,15 +87,20 @@ impl VirtualSpace {
         self.size
     }
 
+    /// Map a new area, return `None` if out of memory
     pub fn add_area(
         &mut self,
         start_addr: usize,
         end_addr: usize,
         attr: &MemoryAttr,
-    ) -> &VirtualArea {
-        let area = VirtualArea::new(start_addr, end_addr - start_addr, attr, self);
+    ) -> Option<&VirtualArea> {
+        let (area, mapped) = VirtualArea::new(start_addr, end_addr - start_addr, attr, self);
+        // unmapped on drop even if incomplete
         self.areas.push(area);
-        self.areas.last().unwrap()
+        if !mapped {
+            return None;
+        }
+        self.areas.last()
     }
 }
 
@@ -108,30 +119,37 @@ pub struct VirtualArea {
 }
 
 impl VirtualArea {
+    /// Return the area and whether all of its pages got frames
     pub fn new(
         page_addr: usize,
         size: usize,
         attr: &MemoryAttr,
         parent: &mut VirtualSpace,
-    ) -> VirtualArea {
+    ) -> (VirtualArea, bool) {
         let aligned_start_addr = page_addr - page_addr % PAGE_SIZE;
         let mut aligned_end = page_addr + size + PAGE_SIZE - 1;
         aligned_end = aligned_end - aligned_end % PAGE_SIZE;
         let lock = parent.allocator.lock();
         let mut active_pt = lock.kernel_table();
+        let mut mapped = true;
         for p in Page::range_of(aligned_start_addr, aligned_end) {
             parent
                 .page_allocator
                 .map(active_pt.deref_mut(), p.start_address(), attr);
+            // kernel pages can not be filled on page fault
+            mapped &= active_pt
+                .get_entry(p.start_address())
+                .map_or(false, |entry| entry.present());
         }
 
-        VirtualArea {
+        let area = VirtualArea {
             start: aligned_start_addr,
             end: aligned_end,
             _attr: attr.clone(),
-        }
+        };
+        (area, mapped)
     }
-    pub fn unmap(&mut self, allocator: &LockedVMM, parent: &mut ByFrame<GlobalFrameAlloc>) {
+    pub fn unmap(&mut self, allocator: &LockedVMM, parent: &mut ByFrame<MemCgFrameAlloc>) {
         let lock = allocator.lock();
         let mut active_pt = lock.kernel_table();
         for p in Page::range_of(self.start, self.end) {
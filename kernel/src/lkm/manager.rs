@@ -3,6 +3,7 @@ use super::const_reloc as loader;
 use super::kernelvm::*;
 use super::structs::*;
 use crate::lkm::structs::ModuleState::{Ready, Unloading};
+use crate::memory::MemCgFrameAlloc;
 use crate::sync::SpinLock as Mutex;
 use crate::syscall::SysError::*;
 use crate::syscall::SysResult;
@@ -170,7 +171,13 @@ impl ModuleManager {
             Some(base + (selected_symbol.value() as usize))
         }
     }
-    pub fn init_module(&mut self, module_image: &[u8], _param_values: &str) -> SysResult {
+    /// Load a module, charging its pages to the memory cgroup of `allocator`
+    pub fn init_module(
+        &mut self,
+        module_image: &[u8],
+        _param_values: &str,
+        allocator: MemCgFrameAlloc,
+    ) -> SysResult {
         let elf = ElfFile::new(module_image).expect("[LKM] failed to read elf");
         let is32 = match elf.header.pt2 {
             header::HeaderPt2::Header32(_) => true,
@@ -268,8 +275,8 @@ impl ModuleManager {
             off_start &= neg(PAGE_SIZE);
             let map_len = max_addr - min_addr + off_start;
             // We first map a huge piece. This requires the kernel model to be dense and not abusing vaddr.
-            let mut vspace =
-                { VirtualSpace::new(&KERNELVM_MANAGER, map_len) }.ok_or_else(|| {
+            let mut vspace = { VirtualSpace::new(&KERNELVM_MANAGER, map_len, allocator) }
+                .ok_or_else(|| {
                     error!("[LKM] valloc failed!");
                     ENOMEM
                 })?;
@@ -299,7 +306,12 @@ impl ModuleManager {
                         if flags.is_execute() {
                             attr = attr.execute();
                         }
-                        let _area_ref = vspace_ref.add_area(prog_start_addr, prog_end_addr, &attr);
+                        let _area_ref = vspace_ref
+                            .add_area(prog_start_addr, prog_end_addr, &attr)
+                            .ok_or_else(|| {
+                                error!("[LKM] out of memory!");
+                                ENOMEM
+                            })?;
                         //self.vallocator.map_pages(prog_start_addr, prog_end_addr, &attr);
                         //No need to flush TLB.
                         let target = unsafe {
@@ -0,0 +1,594 @@
+//! Buddy allocator for physical frames
+//!
+//! Physical memory is split into zones by address: `Dma32` holds the frames
+//! reachable by 32-bit devices and `Normal` holds the rest. Every zone keeps
+//! one free list per order, linked through the free frames themselves, so the
+//! allocator never touches the kernel heap.
+//!
+//! Single frames go through a small per-CPU cache first, which keeps the
+//! global lock off the page fault path on SMP.
+
+use super::{phys_to_virt, FrameAlloc, FRAME_ALLOCATOR};
+use crate::arch::cpu;
+use crate::consts::{MAX_CPU_NUM, MEMORY_OFFSET};
+use crate::sync::SpinNoIrqLock;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitmap_allocator::BitAlloc;
+use core::fmt::Write;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use log::*;
+use rcore_memory::bitmap::AtomicBitmap;
+use rcore_memory::{PhysAddr, PAGE_SIZE};
+use spin::RwLock;
+
+/// The largest block holds `1 << MAX_ORDER` frames
+pub const MAX_ORDER: usize = 10;
+
+/// Frames below this physical address are reachable by 32-bit DMA
+const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Maximum number of frames in a per-CPU cache
+const PCP_HIGH: usize = 64;
+
+/// Number of frames moved between a per-CPU cache and the zones at once
+const PCP_BATCH: usize = 16;
+
+/// End of a free list
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Dma32 = 0,
+    Normal = 1,
+}
+
+const ZONE_NUM: usize = 2;
+
+impl Zone {
+    pub const ALL: [Zone; ZONE_NUM] = [Zone::Dma32, Zone::Normal];
+
+    fn of(frame: usize) -> Zone {
+        if (frame_to_phys(frame) as u64) < DMA32_LIMIT {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
+}
+
+/// Zones tried in order for ordinary allocations
+const ZONES_NORMAL: &[Zone] = &[Zone::Normal, Zone::Dma32];
+
+/// Zones tried in order for device buffers
+const ZONES_DMA32: &[Zone] = &[Zone::Dma32];
+
+/// What a frame is allocated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameUsage {
+    /// Memory areas of user processes
+    User = 0,
+    /// Page tables and other kernel structures
+    Kernel = 1,
+    /// The kernel heap
+    Heap = 2,
+    /// Device buffers
+    Dma = 3,
+    /// Slab caches for small kernel objects
+    Slab = 4,
+}
+
+const USAGE_NUM: usize = 5;
+
+/// Set for every frame handed out by `alloc` and `alloc_contiguous`,
+/// so that double frees are caught before reaching the per-CPU caches or the zones
+static ALLOCATED: AtomicBitmap<{ FrameAlloc::CAP / usize::BITS as usize }> = AtomicBitmap::new();
+
+static USAGE: [AtomicUsize; USAGE_NUM] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Min/low/high watermarks of a zone, in frames
+///
+/// Ordinary allocations never take a zone below `min`.
+/// Falling below `low` wakes up reclaim, which runs until `high` is reached.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Watermark {
+    pub min: usize,
+    pub low: usize,
+    pub high: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ZoneState {
+    /// First block of every free list
+    free_list: [usize; MAX_ORDER + 1],
+    /// Number of blocks in every free list
+    nr_free: [usize; MAX_ORDER + 1],
+    /// Number of frames given to this zone
+    managed: usize,
+    /// Number of free frames in this zone
+    free: usize,
+    watermark: Watermark,
+}
+
+impl ZoneState {
+    const EMPTY: Self = ZoneState {
+        free_list: [NIL; MAX_ORDER + 1],
+        nr_free: [0; MAX_ORDER + 1],
+        managed: 0,
+        free: 0,
+        watermark: Watermark {
+            min: 0,
+            low: 0,
+            high: 0,
+        },
+    };
+
+    fn update_watermark(&mut self) {
+        // reserve 1/128 of the zone, but no more than 16 MiB
+        let min = (self.managed / 128).min(4096);
+        self.watermark = Watermark {
+            min,
+            low: min + min / 4,
+            high: min + min / 2,
+        };
+    }
+}
+
+/// Header stored in the first frame of every free block
+#[derive(Debug, Clone, Copy)]
+struct FreeBlock {
+    prev: usize,
+    next: usize,
+    order: usize,
+}
+
+fn frame_to_phys(frame: usize) -> PhysAddr {
+    frame * PAGE_SIZE + MEMORY_OFFSET
+}
+
+fn phys_to_frame(paddr: PhysAddr) -> usize {
+    (paddr - MEMORY_OFFSET) / PAGE_SIZE
+}
+
+fn block(frame: usize) -> &'static mut FreeBlock {
+    unsafe { &mut *(phys_to_virt(frame_to_phys(frame)) as *mut FreeBlock) }
+}
+
+/// The largest order of an aligned block starting at `start` inside `start..end`
+fn block_order(start: usize, end: usize) -> usize {
+    let align = start.trailing_zeros() as usize;
+    let fit = (usize::BITS - 1 - (end - start).leading_zeros()) as usize;
+    align.min(fit).min(MAX_ORDER)
+}
+
+pub struct BuddyFrameAlloc {
+    /// Set for the first frame of every free block
+    heads: FrameAlloc,
+    zones: [ZoneState; ZONE_NUM],
+}
+
+impl BuddyFrameAlloc {
+    pub const DEFAULT: Self = BuddyFrameAlloc {
+        heads: FrameAlloc::DEFAULT,
+        zones: [ZoneState::EMPTY; ZONE_NUM],
+    };
+
+    /// Give frames in `range` to the allocator
+    pub fn insert(&mut self, range: Range<usize>) {
+        let end = range.end.min(FrameAlloc::CAP);
+        let mut start = range.start;
+        while start < end {
+            let order = block_order(start, end);
+            self.zones[Zone::of(start) as usize].managed += 1 << order;
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+        for zone in self.zones.iter_mut() {
+            zone.update_watermark();
+        }
+    }
+
+    /// Allocate `1 << order` frames from the first zone in `zones` that has them.
+    /// Dip into the reserve below the min watermark only if `reserve` is set.
+    pub fn alloc(&mut self, zones: &[Zone], order: usize, reserve: bool) -> Option<usize> {
+        zones
+            .iter()
+            .find_map(|&zone| self.alloc_block(zone, order, reserve))
+    }
+
+    /// Allocate `size` contiguous frames aligned to `1 << align_log2` frames
+    pub fn alloc_contiguous(
+        &mut self,
+        zones: &[Zone],
+        size: usize,
+        align_log2: usize,
+        reserve: bool,
+    ) -> Option<usize> {
+        let order = (size.max(1).next_power_of_two().trailing_zeros() as usize).max(align_log2);
+        if order > MAX_ORDER {
+            return None;
+        }
+        let frame = self.alloc(zones, order, reserve)?;
+        // give back the tail we do not need
+        self.free_range(frame + size, frame + (1 << order));
+        Some(frame)
+    }
+
+    pub fn dealloc(&mut self, frame: usize) {
+        self.free_block(frame, 0);
+    }
+
+    pub fn dealloc_contiguous(&mut self, frame: usize, size: usize) {
+        self.free_range(frame, frame + size);
+    }
+
+    /// Number of frames to reclaim to bring zones below their low watermarks back to high
+    fn reclaim_target(&self) -> usize {
+        self.zones
+            .iter()
+            .filter(|zone| zone.free < zone.watermark.low)
+            .map(|zone| zone.watermark.high - zone.free)
+            .sum()
+    }
+
+    fn alloc_block(&mut self, zone: Zone, order: usize, reserve: bool) -> Option<usize> {
+        let state = &self.zones[zone as usize];
+        if !reserve && state.free < state.watermark.min + (1 << order) {
+            return None;
+        }
+        let mut current = (order..=MAX_ORDER).find(|&o| state.free_list[o] != NIL)?;
+        let frame = state.free_list[current];
+        self.unlink(frame);
+        // split the block, keeping the lower half
+        while current > order {
+            current -= 1;
+            self.push(frame + (1 << current), current);
+        }
+        let state = &mut self.zones[zone as usize];
+        state.free -= 1 << order;
+        if state.free < state.watermark.low {
+            UNDER_PRESSURE.store(true, Ordering::Relaxed);
+        }
+        Some(frame)
+    }
+
+    /// Free the block at `frame` of `order`, merging it with its free buddies
+    fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        self.zones[Zone::of(frame) as usize].free += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy >= FrameAlloc::CAP || !self.heads.test(buddy) || block(buddy).order != order {
+                break;
+            }
+            self.unlink(buddy);
+            frame &= !(1 << order);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let order = block_order(start, end);
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        let zone = &mut self.zones[Zone::of(frame) as usize];
+        let next = zone.free_list[order];
+        *block(frame) = FreeBlock {
+            prev: NIL,
+            next,
+            order,
+        };
+        if next != NIL {
+            block(next).prev = frame;
+        }
+        zone.free_list[order] = frame;
+        zone.nr_free[order] += 1;
+        self.heads.dealloc(frame);
+    }
+
+    fn unlink(&mut self, frame: usize) {
+        let FreeBlock { prev, next, order } = *block(frame);
+        let zone = &mut self.zones[Zone::of(frame) as usize];
+        if prev == NIL {
+            zone.free_list[order] = next;
+        } else {
+            block(prev).next = next;
+        }
+        if next != NIL {
+            block(next).prev = prev;
+        }
+        zone.nr_free[order] -= 1;
+        self.heads.remove(frame..frame + 1);
+    }
+}
+
+struct PerCpuPages {
+    count: usize,
+    frames: [usize; PCP_HIGH],
+}
+
+impl PerCpuPages {
+    fn push(&mut self, frame: usize) {
+        self.frames[self.count] = frame;
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(self.frames[self.count])
+    }
+}
+
+const PCP_EMPTY: SpinNoIrqLock<PerCpuPages> = SpinNoIrqLock::new(PerCpuPages {
+    count: 0,
+    frames: [0; PCP_HIGH],
+});
+
+static PCP: [SpinNoIrqLock<PerCpuPages>; MAX_CPU_NUM] = [PCP_EMPTY; MAX_CPU_NUM];
+
+/// Take a frame from the cache of the current CPU, refilling it if empty
+fn pcp_alloc() -> Option<usize> {
+    let mut pcp = PCP.get(cpu::id())?.lock();
+    if pcp.count == 0 {
+        let mut ba = FRAME_ALLOCATOR.lock();
+        while pcp.count < PCP_BATCH {
+            match ba.alloc(ZONES_NORMAL, 0, false) {
+                Some(frame) => pcp.push(frame),
+                None => break,
+            }
+        }
+    }
+    pcp.pop()
+}
+
+/// Put a frame into the cache of the current CPU, flushing part of it if full
+fn pcp_free(frame: usize) -> bool {
+    let mut pcp = match PCP.get(cpu::id()) {
+        Some(pcp) => pcp.lock(),
+        None => return false,
+    };
+    if pcp.count == PCP_HIGH {
+        let mut ba = FRAME_ALLOCATOR.lock();
+        for _ in 0..PCP_BATCH {
+            ba.dealloc(pcp.pop().unwrap());
+        }
+    }
+    pcp.push(frame);
+    true
+}
+
+/// Return the cached frames of all CPUs to the zones
+fn pcp_drain() {
+    for pcp in PCP.iter() {
+        let mut pcp = pcp.lock();
+        if pcp.count == 0 {
+            continue;
+        }
+        let mut ba = FRAME_ALLOCATOR.lock();
+        while let Some(frame) = pcp.pop() {
+            ba.dealloc(frame);
+        }
+    }
+}
+
+/// Something that can give frames back under memory pressure, like the empty slabs of slab caches
+pub trait Shrinker: Send + Sync {
+    /// Try to free `nr_frames` frames, return the number actually freed
+    fn shrink(&self, nr_frames: usize) -> usize;
+}
+
+static SHRINKERS: RwLock<Vec<Arc<dyn Shrinker>>> = RwLock::new(Vec::new());
+
+/// Set when a zone falls below its low watermark
+static UNDER_PRESSURE: AtomicBool = AtomicBool::new(false);
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+static RECLAIM_RUNS: AtomicUsize = AtomicUsize::new(0);
+static RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn register_shrinker(shrinker: Arc<dyn Shrinker>) {
+    SHRINKERS.write().push(shrinker);
+}
+
+/// Run shrinkers until all zones are back above their high watermarks
+fn reclaim() {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    let mut target = FRAME_ALLOCATOR.lock().reclaim_target();
+    if target != 0 {
+        RECLAIM_RUNS.fetch_add(1, Ordering::Relaxed);
+        for shrinker in SHRINKERS.read().iter() {
+            let freed = shrinker.shrink(target);
+            RECLAIMED.fetch_add(freed, Ordering::Relaxed);
+            if freed >= target {
+                break;
+            }
+            target -= freed;
+        }
+    }
+    RECLAIMING.store(false, Ordering::Release);
+}
+
+/// Allocate with `f`, falling back to reclaim and then to the reserves.
+///
+/// Shrinkers may allocate from the kernel heap, so they only run
+/// on behalf of user memory, never for heap growth or page tables.
+fn alloc_with(
+    usage: FrameUsage,
+    f: impl Fn(&mut BuddyFrameAlloc, bool) -> Option<usize>,
+) -> Option<usize> {
+    let frame = f(&mut FRAME_ALLOCATOR.lock(), false);
+    if frame.is_some() {
+        return frame;
+    }
+    if usage == FrameUsage::User {
+        reclaim();
+    }
+    pcp_drain();
+    f(&mut FRAME_ALLOCATOR.lock(), true)
+}
+
+/// Run reclaim if some zone fell below its low watermark
+fn check_pressure(usage: FrameUsage) {
+    if usage == FrameUsage::User && UNDER_PRESSURE.swap(false, Ordering::Relaxed) {
+        reclaim();
+    }
+}
+
+/// Allocate a single frame for `usage`
+pub fn alloc(usage: FrameUsage) -> Option<PhysAddr> {
+    let frame = match pcp_alloc() {
+        Some(frame) => frame,
+        None => alloc_with(usage, |ba, reserve| ba.alloc(ZONES_NORMAL, 0, reserve))?,
+    };
+    check_pressure(usage);
+    ALLOCATED.set_range(frame..frame + 1);
+    USAGE[usage as usize].fetch_add(1, Ordering::Relaxed);
+    Some(frame_to_phys(frame))
+}
+
+/// Allocate `size` contiguous frames for `usage`, aligned to `1 << align_log2` frames.
+/// Device buffers are taken from the `Dma32` zone.
+pub fn alloc_contiguous(usage: FrameUsage, size: usize, align_log2: usize) -> Option<PhysAddr> {
+    let zones = match usage {
+        FrameUsage::Dma => ZONES_DMA32,
+        _ => ZONES_NORMAL,
+    };
+    let frame = alloc_with(usage, |ba, reserve| {
+        ba.alloc_contiguous(zones, size, align_log2, reserve)
+    })?;
+    check_pressure(usage);
+    ALLOCATED.set_range(frame..frame + size);
+    USAGE[usage as usize].fetch_add(size, Ordering::Relaxed);
+    Some(frame_to_phys(frame))
+}
+
+pub fn dealloc(usage: FrameUsage, target: PhysAddr) {
+    let frame = phys_to_frame(target);
+    if !ALLOCATED.clear_range(frame..frame + 1) {
+        error!("frame {:#x} is already free", target);
+        return;
+    }
+    USAGE[usage as usize].fetch_sub(1, Ordering::Relaxed);
+    if !pcp_free(frame) {
+        FRAME_ALLOCATOR.lock().dealloc(frame);
+    }
+}
+
+pub fn dealloc_contiguous(usage: FrameUsage, target: PhysAddr, size: usize) {
+    let frame = phys_to_frame(target);
+    if !ALLOCATED.clear_range(frame..frame + size) {
+        error!(
+            "frames [{:#x}, {:#x}) are partly or entirely free",
+            target,
+            target + size * PAGE_SIZE
+        );
+        return;
+    }
+    USAGE[usage as usize].fetch_sub(size, Ordering::Relaxed);
+    FRAME_ALLOCATOR.lock().dealloc_contiguous(frame, size);
+}
+
+/// Frame counters, in frames
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    /// Free frames held in per-CPU caches, included in `free`
+    pub cached: usize,
+    pub user: usize,
+    pub kernel: usize,
+    pub heap: usize,
+    pub dma: usize,
+    pub slab: usize,
+    pub reclaim_runs: usize,
+    pub reclaimed: usize,
+}
+
+pub fn stats() -> FrameStats {
+    let cached: usize = PCP.iter().map(|pcp| pcp.lock().count).sum();
+    let ba = FRAME_ALLOCATOR.lock();
+    let usage = |usage: FrameUsage| USAGE[usage as usize].load(Ordering::Relaxed);
+    FrameStats {
+        total: ba.zones.iter().map(|zone| zone.managed).sum(),
+        free: ba.zones.iter().map(|zone| zone.free).sum::<usize>() + cached,
+        cached,
+        user: usage(FrameUsage::User),
+        kernel: usage(FrameUsage::Kernel),
+        heap: usage(FrameUsage::Heap),
+        dma: usage(FrameUsage::Dma),
+        slab: usage(FrameUsage::Slab),
+        reclaim_runs: RECLAIM_RUNS.load(Ordering::Relaxed),
+        reclaimed: RECLAIMED.load(Ordering::Relaxed),
+    }
+}
+
+/// Content of `/proc/meminfo`
+pub fn meminfo() -> String {
+    let stats = stats();
+    let kb = |frames: usize| frames * PAGE_SIZE / 1024;
+    let mut s = String::new();
+    for &(name, frames) in [
+        ("MemTotal", stats.total),
+        ("MemFree", stats.free),
+        ("MemAvailable", stats.free),
+        ("Buffers", 0),
+        ("Cached", 0),
+        ("SwapTotal", 0),
+        ("SwapFree", 0),
+        ("AnonPages", stats.user),
+        ("KernelPages", stats.kernel),
+        ("Slab", stats.slab),
+        ("KernelHeap", stats.heap),
+        ("DmaPages", stats.dma),
+        ("PerCpuCached", stats.cached),
+    ]
+    .iter()
+    {
+        writeln!(s, "{:<16}{:>8} kB", format!("{}:", name), kb(frames)).unwrap();
+    }
+    writeln!(s, "{:<16}{:>8}", "ReclaimRuns:", stats.reclaim_runs).unwrap();
+    writeln!(s, "{:<16}{:>8}", "Reclaimed:", stats.reclaimed).unwrap();
+    s
+}
+
+/// Content of `/proc/buddyinfo`: number of free blocks of every order per zone
+pub fn buddyinfo() -> String {
+    let ba = FRAME_ALLOCATOR.lock();
+    let mut s = String::new();
+    for zone in Zone::ALL.iter() {
+        let state = &ba.zones[*zone as usize];
+        if state.managed == 0 {
+            continue;
+        }
+        write!(s, "Node 0, zone {:>8}", zone.name()).unwrap();
+        for nr_free in state.nr_free.iter() {
+            write!(s, " {:>6}", nr_free).unwrap();
+        }
+        writeln!(s).unwrap();
+    }
+    s
+}
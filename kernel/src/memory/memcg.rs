@@ -0,0 +1,368 @@
+//! Memory cgroups: per process group accounting and limits of user pages
+//!
+//! Every user frame is charged to the cgroup of the process group which
+//! mapped it. When a process moves to another group, the charges of its
+//! private pages move along, while shared memory stays charged to the group
+//! which allocated it until it is freed. When a charge exceeds the limit, or the frame
+//! allocator runs dry, the OOM killer sends SIGKILL to the process with the
+//! largest RSS instead of panicking the kernel. Limits are set through
+//! `/proc/memcg`.
+
+use super::frame::{self, FrameUsage};
+use super::FrameAllocator;
+use crate::process::{Pgid, Process, PROCESSES};
+use crate::signal::{send_signal, Siginfo, Signal, SI_KERNEL};
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use log::*;
+use rcore_fs::vfs::{FileType, FsError, INode, Metadata, PollStatus, Result as FsResult, Timespec};
+use rcore_memory::{PhysAddr, PAGE_SIZE};
+use spin::RwLock;
+
+/// Memory usage and limit of a process group, in pages
+#[derive(Debug)]
+pub struct MemCgroup {
+    pgid: Pgid,
+    usage: AtomicUsize,
+    max_usage: AtomicUsize,
+    /// `usize::MAX` for unlimited
+    limit: AtomicUsize,
+    /// Number of charges rejected by the limit
+    failcnt: AtomicUsize,
+    /// Number of processes killed by the OOM killer
+    oom_kills: AtomicUsize,
+    /// A charge failed and the OOM killer has not run since
+    oom: AtomicBool,
+}
+
+impl MemCgroup {
+    fn new(pgid: Pgid) -> Self {
+        MemCgroup {
+            pgid,
+            usage: AtomicUsize::new(0),
+            max_usage: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+            failcnt: AtomicUsize::new(0),
+            oom_kills: AtomicUsize::new(0),
+            oom: AtomicBool::new(false),
+        }
+    }
+
+    pub fn pgid(&self) -> Pgid {
+        self.pgid
+    }
+
+    /// Pages charged to the group
+    pub fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    /// Limit in pages, `usize::MAX` for unlimited
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Set the limit in pages. Pages already charged are kept,
+    /// the group runs into OOM on its next charge if it is over the limit.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    fn try_charge(&self, pages: usize) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut usage = self.usage.load(Ordering::Relaxed);
+        loop {
+            let new_usage = usage.saturating_add(pages);
+            if new_usage > limit {
+                self.failcnt.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            match self.usage.compare_exchange_weak(
+                usage,
+                new_usage,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.max_usage.fetch_max(new_usage, Ordering::Relaxed);
+                    return true;
+                }
+                Err(current) => usage = current,
+            }
+        }
+    }
+
+    fn uncharge(&self, pages: usize) {
+        self.usage.fetch_sub(pages, Ordering::Relaxed);
+    }
+
+    /// Move the charges of `pages` pages to `other`, even beyond its limit
+    pub fn move_charge(&self, other: &MemCgroup, pages: usize) {
+        self.uncharge(pages);
+        let usage = other.usage.fetch_add(pages, Ordering::Relaxed) + pages;
+        other.max_usage.fetch_max(usage, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    /// Memory cgroups alive, i.e. referenced by processes or charged frames
+    static ref MEMCGS: RwLock<BTreeMap<Pgid, Weak<MemCgroup>>> = RwLock::new(BTreeMap::new());
+}
+
+/// A user frame allocation failed since the last run of the OOM killer
+static GLOBAL_OOM: AtomicBool = AtomicBool::new(false);
+
+/// Some charge or allocation failed since the last run of the OOM killer,
+/// so that checking for it on every return to user mode is cheap
+static OOM_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Get the memory cgroup of process group `pgid`, create it if not exists
+pub fn memcg(pgid: Pgid) -> Arc<MemCgroup> {
+    let mut table = MEMCGS.write();
+    if let Some(memcg) = table.get(&pgid).and_then(Weak::upgrade) {
+        return memcg;
+    }
+    table.retain(|_, memcg| memcg.strong_count() > 0);
+    let memcg = Arc::new(MemCgroup::new(pgid));
+    table.insert(pgid, Arc::downgrade(&memcg));
+    memcg
+}
+
+/// Get the memory cgroup of process group `pgid` if exists
+pub fn find_memcg(pgid: Pgid) -> Option<Arc<MemCgroup>> {
+    MEMCGS.read().get(&pgid).and_then(Weak::upgrade)
+}
+
+/// Allocate user frames, charging them to a memory cgroup
+#[derive(Debug, Clone)]
+pub struct MemCgFrameAlloc {
+    memcg: Arc<MemCgroup>,
+}
+
+impl MemCgFrameAlloc {
+    pub fn new(memcg: Arc<MemCgroup>) -> Self {
+        MemCgFrameAlloc { memcg }
+    }
+}
+
+impl FrameAllocator for MemCgFrameAlloc {
+    fn alloc(&self) -> Option<PhysAddr> {
+        if !self.memcg.try_charge(1) {
+            self.memcg.oom.store(true, Ordering::Relaxed);
+            OOM_PENDING.store(true, Ordering::Relaxed);
+            return None;
+        }
+        let ret = frame::alloc(FrameUsage::User);
+        if ret.is_none() {
+            self.memcg.uncharge(1);
+            GLOBAL_OOM.store(true, Ordering::Relaxed);
+            OOM_PENDING.store(true, Ordering::Relaxed);
+        }
+        trace!("Allocate frame: {:x?}", ret);
+        ret
+    }
+    fn alloc_contiguous(&self, size: usize, align_log2: usize) -> Option<PhysAddr> {
+        // huge pages fall back to normal pages on failure, not an OOM yet
+        if !self.memcg.try_charge(size) {
+            return None;
+        }
+        let ret = frame::alloc_contiguous(FrameUsage::User, size, align_log2);
+        if ret.is_none() {
+            self.memcg.uncharge(size);
+        }
+        trace!("Allocate frame: {:x?}", ret);
+        ret
+    }
+    fn dealloc(&self, target: PhysAddr) {
+        trace!("Deallocate frame: {:x}", target);
+        frame::dealloc(FrameUsage::User, target);
+        self.memcg.uncharge(1);
+    }
+}
+
+/// Run the OOM killer for the failed allocations since its last run.
+/// Return true if a victim has been killed or is being killed,
+/// so that the faulting thread should retry later.
+pub fn out_of_memory() -> bool {
+    if !OOM_PENDING.swap(false, Ordering::Relaxed) {
+        return false;
+    }
+    let mut handled = false;
+    if GLOBAL_OOM.swap(false, Ordering::Relaxed) {
+        handled |= oom_kill(None);
+    }
+    let groups = MEMCGS
+        .read()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    for memcg in groups.iter() {
+        if memcg.oom.swap(false, Ordering::Relaxed) {
+            handled |= oom_kill(Some(memcg));
+        }
+    }
+    handled
+}
+
+/// Kill the process with the largest RSS in `memcg`, or in the system if `None`
+fn oom_kill(memcg: Option<&Arc<MemCgroup>>) -> bool {
+    let procs = PROCESSES.read().values().cloned().collect::<Vec<_>>();
+    let mut victim: Option<(Arc<Mutex<Process>>, usize)> = None;
+    for proc in procs {
+        let vm = {
+            let proc = proc.lock();
+            if let Some(memcg) = memcg {
+                if !Arc::ptr_eq(&proc.memcg, memcg) {
+                    continue;
+                }
+            }
+            if proc.pending_sigset.contains(Signal::SIGKILL) {
+                // wait for it to release memory
+                return true;
+            }
+            if proc.pid.is_init() || proc.exited() {
+                continue;
+            }
+            proc.vm.clone()
+        };
+        let rss = vm.lock().rss();
+        if victim.as_ref().map_or(true, |&(_, max)| rss > max) {
+            victim = Some((proc, rss));
+        }
+    }
+    let (proc, rss) = match victim {
+        Some(victim) => victim,
+        None => return false,
+    };
+    {
+        let proc = proc.lock();
+        warn!(
+            "out of memory in {}: kill process {} ({}) with rss {} KiB",
+            memcg.map_or(String::from("system"), |memcg| format!(
+                "pgid {}",
+                memcg.pgid
+            )),
+            proc.pid,
+            proc.exec_path,
+            rss * PAGE_SIZE / 1024
+        );
+        proc.memcg.oom_kills.fetch_add(1, Ordering::Relaxed);
+    }
+    send_signal(
+        proc,
+        -1,
+        Siginfo {
+            signo: Signal::SIGKILL as i32,
+            errno: 0,
+            code: SI_KERNEL,
+            field: Default::default(),
+        },
+    );
+    true
+}
+
+/// `/proc/memcg`, showing every memory cgroup on read.
+/// Writing `<pgid> <limit>` sets the limit of a group in bytes, or unlimited with `max`.
+pub struct MemCgFile;
+
+impl MemCgFile {
+    fn set_limit(text: &str) -> FsResult<()> {
+        let mut args = text.split_whitespace();
+        let (pgid, limit) = match (args.next(), args.next(), args.next()) {
+            (Some(pgid), Some(limit), None) => (pgid, limit),
+            _ => return Err(FsError::InvalidParam),
+        };
+        let pgid = pgid.parse::<Pgid>().map_err(|_| FsError::InvalidParam)?;
+        let pages = match limit {
+            "max" => usize::MAX,
+            _ => limit.parse::<usize>().map_err(|_| FsError::InvalidParam)? / PAGE_SIZE,
+        };
+        let memcg = find_memcg(pgid).ok_or(FsError::EntryNotFound)?;
+        info!("memcg: set limit of pgid {} to {} pages", pgid, pages);
+        memcg.set_limit(pages);
+        Ok(())
+    }
+}
+
+impl INode for MemCgFile {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize> {
+        let info = memcg_info();
+        let info = info.as_bytes();
+        if offset >= info.len() {
+            return Ok(0);
+        }
+        let len = (info.len() - offset).min(buf.len());
+        buf[..len].copy_from_slice(&info[offset..offset + len]);
+        Ok(len)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> FsResult<usize> {
+        let text = core::str::from_utf8(buf).map_err(|_| FsError::InvalidParam)?;
+        Self::set_limit(text)?;
+        Ok(buf.len())
+    }
+    fn poll(&self) -> FsResult<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+    fn metadata(&self) -> FsResult<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: 0,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            mode: 0o644,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Format memory cgroups for /proc/memcg, sizes in KiB
+pub fn memcg_info() -> String {
+    let groups = MEMCGS
+        .read()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    let mut s = String::new();
+    writeln!(s, "pgid     usage    max_usage    limit  failcnt oom_kill").unwrap();
+    for memcg in groups.iter() {
+        let limit = memcg.limit();
+        let limit = if limit == usize::MAX {
+            String::from("max")
+        } else {
+            format!("{}", limit * PAGE_SIZE / 1024)
+        };
+        writeln!(
+            s,
+            "{:<8} {:>8} {:>12} {:>8} {:>8} {:>8}",
+            memcg.pgid,
+            memcg.usage() * PAGE_SIZE / 1024,
+            memcg.max_usage.load(Ordering::Relaxed) * PAGE_SIZE / 1024,
+            limit,
+            memcg.failcnt.load(Ordering::Relaxed),
+            memcg.oom_kills.load(Ordering::Relaxed)
+        )
+        .unwrap();
+    }
+    s
+}
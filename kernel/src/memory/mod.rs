@@ -4,7 +4,7 @@ use super::HEAP_ALLOCATOR;
 use crate::consts::{KERNEL_OFFSET, MEMORY_OFFSET, PHYSICAL_MEMORY_OFFSET};
 use crate::process::current_thread;
 use crate::sync::SpinNoIrqLock;
-use bitmap_allocator::BitAlloc;
+use alloc::sync::Arc;
 use buddy_system_allocator::Heap;
 use core::alloc::Layout;
 use core::mem;
@@ -16,6 +16,21 @@ pub use crate::arch::paging::*;
 pub use rcore_memory::memory_set::{handler::*, MemoryArea, MemoryAttr};
 pub type MemorySet = rcore_memory::memory_set::MemorySet<PageTableImpl>;
 
+pub use self::frame::{
+    buddyinfo, meminfo, register_shrinker, stats as frame_stats, BuddyFrameAlloc, FrameStats,
+    FrameUsage, Shrinker,
+};
+pub use self::memcg::{
+    find_memcg, memcg, memcg_info, out_of_memory, MemCgFile, MemCgFrameAlloc, MemCgroup,
+};
+pub use self::slab::{
+    slab_alloc_calls, slab_stats, slabinfo, SlabAllocator, SlabShrinker, SlabStats,
+};
+
+mod frame;
+mod memcg;
+mod slab;
+
 // x86_64 support up to 1T memory
 #[cfg(target_arch = "x86_64")]
 pub type FrameAlloc = bitmap_allocator::BitAlloc256M;
@@ -29,7 +44,8 @@ pub type FrameAlloc = bitmap_allocator::BitAlloc256M;
 ))]
 pub type FrameAlloc = bitmap_allocator::BitAlloc1M;
 
-pub static FRAME_ALLOCATOR: SpinNoIrqLock<FrameAlloc> = SpinNoIrqLock::new(FrameAlloc::DEFAULT);
+pub static FRAME_ALLOCATOR: SpinNoIrqLock<BuddyFrameAlloc> =
+    SpinNoIrqLock::new(BuddyFrameAlloc::DEFAULT);
 
 /// Convert physical address to virtual address
 #[inline]
@@ -66,41 +82,33 @@ pub struct GlobalFrameAlloc;
 
 impl FrameAllocator for GlobalFrameAlloc {
     fn alloc(&self) -> Option<usize> {
-        // get the real address of the alloc frame
-        let ret = FRAME_ALLOCATOR
-            .lock()
-            .alloc()
-            .map(|id| id * PAGE_SIZE + MEMORY_OFFSET);
+        let ret = frame::alloc(FrameUsage::User);
         trace!("Allocate frame: {:x?}", ret);
         ret
-        // TODO: try to swap out when alloc failed
     }
     fn alloc_contiguous(&self, size: usize, align_log2: usize) -> Option<PhysAddr> {
-        // get the real address of the alloc frame
-        let ret = FRAME_ALLOCATOR
-            .lock()
-            .alloc_contiguous(size, align_log2)
-            .map(|id| id * PAGE_SIZE + MEMORY_OFFSET);
+        let ret = frame::alloc_contiguous(FrameUsage::User, size, align_log2);
         trace!("Allocate frame: {:x?}", ret);
         ret
-        // TODO: try to swap out when alloc failed
     }
     fn dealloc(&self, target: usize) {
         trace!("Deallocate frame: {:x}", target);
-        FRAME_ALLOCATOR
-            .lock()
-            .dealloc((target - MEMORY_OFFSET) / PAGE_SIZE);
+        frame::dealloc(FrameUsage::User, target);
     }
 }
 
 pub fn alloc_frame() -> Option<usize> {
-    GlobalFrameAlloc.alloc()
+    frame::alloc(FrameUsage::Kernel)
 }
 pub fn dealloc_frame(target: usize) {
-    GlobalFrameAlloc.dealloc(target);
+    frame::dealloc(FrameUsage::Kernel, target);
 }
+/// Allocate frames for device buffers, below 4 GiB
 pub fn alloc_frame_contiguous(size: usize, align_log2: usize) -> Option<usize> {
-    GlobalFrameAlloc.alloc_contiguous(size, align_log2)
+    frame::alloc_contiguous(FrameUsage::Dma, size, align_log2)
+}
+pub fn dealloc_frame_contiguous(target: usize, size: usize) {
+    frame::dealloc_contiguous(FrameUsage::Dma, target, size);
 }
 
 pub struct KernelStack(usize);
@@ -134,6 +142,7 @@ impl Drop for KernelStack {
 /// Return true to continue, false to halt.
 pub fn handle_page_fault(addr: usize) -> bool {
     debug!("page fault from kernel @ {:#x}", addr);
+    crate::tracepoint::trace_page_fault(addr, None, false);
 
     let thread = current_thread().unwrap();
     let mut lock = thread.vm.lock();
@@ -147,6 +156,7 @@ pub fn handle_page_fault_ext(addr: usize, access: crate::memory::AccessType) ->
         "page fault from kernel @ {:#x} with access type {:?}",
         addr, access
     );
+    crate::tracepoint::trace_page_fault(addr, Some(access), false);
 
     let thread = current_thread().unwrap();
     let mut lock = thread.vm.lock();
@@ -160,35 +170,38 @@ pub fn init_heap() {
     static mut HEAP: [usize; HEAP_BLOCK] = [0; HEAP_BLOCK];
     unsafe {
         HEAP_ALLOCATOR
+            .heap()
             .lock()
             .init(HEAP.as_ptr() as usize, HEAP_BLOCK * MACHINE_ALIGN);
     }
+    register_shrinker(Arc::new(SlabShrinker));
 }
 
-pub fn enlarge_heap(heap: &mut Heap<32>, _: &Layout) {
+pub fn enlarge_heap(heap: &mut Heap<32>, layout: &Layout) {
     info!("Enlarging heap to avoid oom");
 
-    let mut addrs = [(0, 0); 32];
-    let mut addr_len = 0;
+    // grow by up to 64 MiB, in the largest blocks the frame allocator can hand out,
+    // but never smaller than the allocation needs
+    let min_pages =
+        ((layout.size().max(layout.align()) + PAGE_SIZE - 1) / PAGE_SIZE).next_power_of_two();
     let va_offset = PHYSICAL_MEMORY_OFFSET;
-    for _ in 0..16384 {
-        let page = alloc_frame().unwrap();
-        let va = va_offset + page;
-        if addr_len > 0 {
-            let (ref mut addr, ref mut len) = addrs[addr_len - 1];
-            if *addr - PAGE_SIZE == va {
-                *len += PAGE_SIZE;
-                *addr -= PAGE_SIZE;
-                continue;
+    let mut order = frame::MAX_ORDER;
+    let mut left = 16384;
+    while left > 0 {
+        let pages = 1 << order;
+        match frame::alloc_contiguous(FrameUsage::Heap, pages, 0) {
+            Some(page) => {
+                let (addr, len) = (va_offset + page, pages * PAGE_SIZE);
+                info!("Adding {:#X} {:#X} to heap", addr, len);
+                unsafe {
+                    heap.init(addr, len);
+                }
+                left -= pages.min(left);
             }
-        }
-        addrs[addr_len] = (va, PAGE_SIZE);
-        addr_len += 1;
-    }
-    for (addr, len) in addrs[..addr_len].into_iter() {
-        info!("Adding {:#X} {:#X} to heap", addr, len);
-        unsafe {
-            heap.init(*addr, *len);
+            // fragmented, try smaller blocks
+            None if order > 0 && pages / 2 >= min_pages => order -= 1,
+            // the allocation fails if nothing was added
+            None => break,
         }
     }
 }
@@ -0,0 +1,518 @@
+//! Slab caches for small kernel objects
+//!
+//! Allocations up to `1 << SLAB_MAX_SHIFT` bytes are served from one cache per
+//! power-of-two size, built on blocks from the frame allocator. Larger ones go
+//! to the buddy heap. The slab header and the allocation site of every object
+//! live at the start of each block, objects fill the rest.
+//!
+//! Before the frame allocator is initialized, or when it runs out of frames,
+//! blocks are carved from the buddy heap instead.
+//!
+//! Debug builds record allocation sites, poison freed objects and red-zone the
+//! unused tail of allocated ones, to catch leaks, use-after-free and small
+//! overflows.
+
+use super::frame::{self, FrameUsage, Shrinker};
+use super::{phys_to_virt, virt_to_phys, HEAP_ALLOCATOR};
+use crate::backtrace;
+use crate::sync::SpinNoIrqLock;
+use alloc::string::String;
+use buddy_system_allocator::{Heap, LockedHeapWithRescue};
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::{null_mut, NonNull};
+use log::*;
+use rcore_memory::PAGE_SIZE;
+
+/// The smallest object holds the free list link
+const SLAB_MIN_SHIFT: usize = 4;
+
+/// The largest object served by slab caches
+const SLAB_MAX_SHIFT: usize = 11;
+
+const CACHE_NUM: usize = SLAB_MAX_SHIFT - SLAB_MIN_SHIFT + 1;
+
+/// Minimum number of objects in a slab
+const SLAB_MIN_OBJECTS: usize = 16;
+
+/// Written over freed objects in debug builds
+const POISON_FREE: u8 = 0x6b;
+
+/// Written over the unused tail of allocated objects in debug builds
+const RED_ZONE: u8 = 0xbb;
+
+/// Distinct allocation sites counted per cache in a leak report
+const MAX_SITES: usize = 64;
+
+/// Header at the start of every slab
+struct Slab {
+    prev: usize,
+    next: usize,
+    /// First free object, 0 if the slab is full
+    free: usize,
+    /// Number of allocated objects
+    inuse: usize,
+    /// Whether the block comes from the buddy heap instead of the frame allocator
+    from_heap: bool,
+}
+
+fn slab(addr: usize) -> &'static mut Slab {
+    unsafe { &mut *(addr as *mut Slab) }
+}
+
+/// Push `addr` to the front of the slab list `head`
+fn push(head: &mut usize, addr: usize) {
+    let s = slab(addr);
+    s.prev = 0;
+    s.next = *head;
+    if *head != 0 {
+        slab(*head).prev = addr;
+    }
+    *head = addr;
+}
+
+fn unlink(head: &mut usize, addr: usize) {
+    let s = slab(addr);
+    if s.prev == 0 {
+        *head = s.next;
+    } else {
+        slab(s.prev).next = s.next;
+    }
+    if s.next != 0 {
+        slab(s.next).prev = s.prev;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SlabStats {
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub pages_per_slab: usize,
+    pub active_objects: usize,
+    pub slabs: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub failed: usize,
+    /// Objects found poisoned or red-zoned wrongly
+    pub corrupted: usize,
+}
+
+struct SlabCache {
+    /// Slabs with free objects
+    partial: usize,
+    /// Slabs without free objects
+    full: usize,
+    /// Number of slabs without allocated objects
+    empty: usize,
+    /// Offset of the first object in a slab
+    offset: usize,
+    stats: SlabStats,
+}
+
+impl SlabCache {
+    const fn new(shift: usize) -> Self {
+        let size = 1 << shift;
+        let mut pages = 1;
+        while pages * PAGE_SIZE < size * SLAB_MIN_OBJECTS {
+            pages *= 2;
+        }
+        let mut count = pages * PAGE_SIZE / size;
+        while Self::objects_offset(size, count) + count * size > pages * PAGE_SIZE {
+            count -= 1;
+        }
+        SlabCache {
+            partial: 0,
+            full: 0,
+            empty: 0,
+            offset: Self::objects_offset(size, count),
+            stats: SlabStats {
+                object_size: size,
+                objects_per_slab: count,
+                pages_per_slab: pages,
+                active_objects: 0,
+                slabs: 0,
+                allocs: 0,
+                frees: 0,
+                failed: 0,
+                corrupted: 0,
+            },
+        }
+    }
+
+    /// Objects come after the header and one allocation site per object
+    const fn objects_offset(size: usize, count: usize) -> usize {
+        let meta = size_of::<Slab>() + count * size_of::<usize>();
+        (meta + size - 1) & !(size - 1)
+    }
+
+    fn slab_of(&self, obj: usize) -> usize {
+        obj & !(self.stats.pages_per_slab * PAGE_SIZE - 1)
+    }
+
+    /// Allocation site of object `idx` in slab `addr`, 0 if free
+    fn site(&self, addr: usize, idx: usize) -> &'static mut usize {
+        unsafe { &mut *((addr + size_of::<Slab>()) as *mut usize).add(idx) }
+    }
+
+    fn index_of(&self, addr: usize, obj: usize) -> usize {
+        (obj - addr - self.offset) / self.stats.object_size
+    }
+
+    /// Layout of a slab taken from the buddy heap
+    fn heap_layout(&self) -> Layout {
+        let size = self.stats.pages_per_slab * PAGE_SIZE;
+        Layout::from_size_align(size, size).unwrap()
+    }
+
+    /// Get a new slab from the frame allocator, or from `heap` if there are no frames
+    fn grow(&mut self, heap: &LockedHeapWithRescue<32>) -> bool {
+        let SlabStats {
+            object_size: size,
+            objects_per_slab: count,
+            pages_per_slab: pages,
+            ..
+        } = self.stats;
+        let align_log2 = pages.trailing_zeros() as usize;
+        let (addr, from_heap) = match frame::alloc_contiguous(FrameUsage::Slab, pages, align_log2) {
+            Some(paddr) => (phys_to_virt(paddr), false),
+            // no rescue here, it is for growing the heap only
+            None => match heap.lock().alloc(self.heap_layout()) {
+                Ok(ptr) => (ptr.as_ptr() as usize, true),
+                Err(_) => return false,
+            },
+        };
+        let first = addr + self.offset;
+        for idx in 0..count {
+            let obj = first + idx * size;
+            if cfg!(debug_assertions) {
+                unsafe { core::ptr::write_bytes(obj as *mut u8, POISON_FREE, size) };
+            }
+            let next = if idx + 1 < count { obj + size } else { 0 };
+            unsafe { *(obj as *mut usize) = next };
+            *self.site(addr, idx) = 0;
+        }
+        let s = slab(addr);
+        s.free = first;
+        s.inuse = 0;
+        s.from_heap = from_heap;
+        push(&mut self.partial, addr);
+        self.empty += 1;
+        self.stats.slabs += 1;
+        true
+    }
+
+    /// Give an empty slab back to where it came from
+    fn shrink(&mut self, addr: usize, heap: &LockedHeapWithRescue<32>) {
+        unlink(&mut self.partial, addr);
+        self.empty -= 1;
+        self.stats.slabs -= 1;
+        if slab(addr).from_heap {
+            let ptr = unsafe { NonNull::new_unchecked(addr as *mut u8) };
+            heap.lock().dealloc(ptr, self.heap_layout());
+        } else {
+            let pages = self.stats.pages_per_slab;
+            frame::dealloc_contiguous(FrameUsage::Slab, virt_to_phys(addr), pages);
+        }
+    }
+
+    /// Give all empty slabs back, return the number of frames freed
+    fn shrink_empty(&mut self, heap: &LockedHeapWithRescue<32>) -> usize {
+        let mut freed = 0;
+        let mut addr = self.partial;
+        while addr != 0 && self.empty != 0 {
+            let s = slab(addr);
+            let next = s.next;
+            if s.inuse == 0 {
+                if !s.from_heap {
+                    freed += self.stats.pages_per_slab;
+                }
+                self.shrink(addr, heap);
+            }
+            addr = next;
+        }
+        freed
+    }
+
+    /// Returns the object and whether it was modified after free
+    fn alloc(
+        &mut self,
+        layout: &Layout,
+        site: usize,
+        heap: &LockedHeapWithRescue<32>,
+    ) -> (*mut u8, bool) {
+        if self.partial == 0 && !self.grow(heap) {
+            self.stats.failed += 1;
+            return (null_mut(), false);
+        }
+        let addr = self.partial;
+        let s = slab(addr);
+        let obj = s.free;
+        s.free = unsafe { *(obj as *const usize) };
+        if s.inuse == 0 {
+            self.empty -= 1;
+        }
+        s.inuse += 1;
+        if s.free == 0 {
+            unlink(&mut self.partial, addr);
+            push(&mut self.full, addr);
+        }
+        // a site of 1 still marks the object as allocated
+        *self.site(addr, self.index_of(addr, obj)) = site.max(1);
+        self.stats.allocs += 1;
+        self.stats.active_objects += 1;
+
+        let size = self.stats.object_size;
+        let mut corrupted = false;
+        if cfg!(debug_assertions) {
+            corrupted = ((obj + size_of::<usize>())..(obj + size))
+                .any(|b| unsafe { *(b as *const u8) } != POISON_FREE);
+            let tail = layout.size().min(size);
+            unsafe { core::ptr::write_bytes((obj + tail) as *mut u8, RED_ZONE, size - tail) };
+        }
+        if corrupted {
+            self.stats.corrupted += 1;
+        }
+        (obj as *mut u8, corrupted)
+    }
+
+    /// Returns whether the red zone of the object was overwritten
+    fn dealloc(&mut self, obj: usize, layout: &Layout, heap: &LockedHeapWithRescue<32>) -> bool {
+        let size = self.stats.object_size;
+        let mut corrupted = false;
+        if cfg!(debug_assertions) {
+            corrupted = ((obj + layout.size().min(size))..(obj + size))
+                .any(|b| unsafe { *(b as *const u8) } != RED_ZONE);
+            unsafe { core::ptr::write_bytes(obj as *mut u8, POISON_FREE, size) };
+        }
+        if corrupted {
+            self.stats.corrupted += 1;
+        }
+
+        let addr = self.slab_of(obj);
+        *self.site(addr, self.index_of(addr, obj)) = 0;
+        let s = slab(addr);
+        if s.free == 0 {
+            unlink(&mut self.full, addr);
+            push(&mut self.partial, addr);
+        }
+        unsafe { *(obj as *mut usize) = s.free };
+        s.free = obj;
+        s.inuse -= 1;
+        self.stats.frees += 1;
+        self.stats.active_objects -= 1;
+        if s.inuse == 0 {
+            // keep one empty slab around to avoid bouncing frames
+            self.empty += 1;
+            if self.empty > 1 {
+                self.shrink(addr, heap);
+            }
+        }
+        corrupted
+    }
+
+    /// Count allocated objects by allocation site, without touching the heap.
+    /// Returns the number of sites filled and the number of objects left over.
+    fn count_sites(&self, sites: &mut [(usize, usize); MAX_SITES]) -> (usize, usize) {
+        let mut len = 0;
+        let mut other = 0;
+        for &head in [self.partial, self.full].iter() {
+            let mut addr = head;
+            while addr != 0 {
+                for idx in 0..self.stats.objects_per_slab {
+                    let site = *self.site(addr, idx);
+                    if site == 0 {
+                        continue;
+                    }
+                    match sites[..len].iter_mut().find(|(s, _)| *s == site) {
+                        Some((_, count)) => *count += 1,
+                        None if len < MAX_SITES => {
+                            sites[len] = (site, 1);
+                            len += 1;
+                        }
+                        None => other += 1,
+                    }
+                }
+                addr = slab(addr).next;
+            }
+        }
+        (len, other)
+    }
+}
+
+const fn cache(shift: usize) -> SpinNoIrqLock<SlabCache> {
+    SpinNoIrqLock::new(SlabCache::new(shift))
+}
+
+static SLAB_CACHES: [SpinNoIrqLock<SlabCache>; CACHE_NUM] = [
+    cache(4),
+    cache(5),
+    cache(6),
+    cache(7),
+    cache(8),
+    cache(9),
+    cache(10),
+    cache(11),
+];
+
+/// Index of the slab cache serving `layout`, if any
+fn cache_index(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    if size > 1 << SLAB_MAX_SHIFT {
+        return None;
+    }
+    let shift = size.next_power_of_two().trailing_zeros() as usize;
+    Some(shift.max(SLAB_MIN_SHIFT) - SLAB_MIN_SHIFT)
+}
+
+fn cache_name(idx: usize) -> String {
+    format!("kmalloc-{}", 1 << (idx + SLAB_MIN_SHIFT))
+}
+
+/// The kernel allocator: slab caches for small objects, the buddy heap for the rest
+pub struct SlabAllocator {
+    heap: LockedHeapWithRescue<32>,
+}
+
+impl SlabAllocator {
+    pub const fn new(rescue: fn(&mut Heap<32>, &Layout)) -> Self {
+        SlabAllocator {
+            heap: LockedHeapWithRescue::new(rescue),
+        }
+    }
+
+    /// The buddy heap serving large allocations
+    pub fn heap(&self) -> &LockedHeapWithRescue<32> {
+        &self.heap
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let idx = match cache_index(&layout) {
+            Some(idx) => idx,
+            None => return self.heap.alloc(layout),
+        };
+        // skip `__rust_alloc` and the wrapper in `alloc`
+        let site = if cfg!(debug_assertions) {
+            backtrace::caller(2)
+        } else {
+            0
+        };
+        // report after the cache is unlocked, logging may allocate
+        let (ptr, corrupted) = {
+            let mut cache = SLAB_CACHES[idx].lock();
+            cache.alloc(&layout, site, &self.heap)
+        };
+        if corrupted {
+            error!(
+                "{}: object {:?} was modified after free",
+                cache_name(idx),
+                ptr
+            );
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let idx = match cache_index(&layout) {
+            Some(idx) => idx,
+            None => return self.heap.dealloc(ptr, layout),
+        };
+        let overwritten = {
+            let mut cache = SLAB_CACHES[idx].lock();
+            cache.dealloc(ptr as usize, &layout, &self.heap)
+        };
+        if overwritten {
+            error!(
+                "{}: red zone of object {:?} was overwritten",
+                cache_name(idx),
+                ptr
+            );
+        }
+    }
+}
+
+/// Gives the empty slabs kept by every cache back under memory pressure
+pub struct SlabShrinker;
+
+impl Shrinker for SlabShrinker {
+    fn shrink(&self, nr_frames: usize) -> usize {
+        let mut freed = 0;
+        for cache in SLAB_CACHES.iter() {
+            if freed >= nr_frames {
+                break;
+            }
+            // a cache that is busy may be the one allocating right now
+            if let Some(mut cache) = cache.try_lock() {
+                freed += cache.shrink_empty(HEAP_ALLOCATOR.heap());
+            }
+        }
+        freed
+    }
+}
+
+/// Statistics of every slab cache, by object size
+pub fn slab_stats() -> [SlabStats; CACHE_NUM] {
+    let mut stats = [SlabStats::default(); CACHE_NUM];
+    for (stat, cache) in stats.iter_mut().zip(SLAB_CACHES.iter()) {
+        *stat = cache.lock().stats;
+    }
+    stats
+}
+
+/// Content of `/proc/slabinfo`
+pub fn slabinfo() -> String {
+    let mut s = String::from(
+        "# name            <active_objs> <num_objs> <objsize> <objperslab> <pagesperslab> \
+         : stats <allocs> <frees> <failed> <corrupted> : slabdata <num_slabs>\n",
+    );
+    for (idx, stat) in slab_stats().iter().enumerate() {
+        writeln!(
+            s,
+            "{:<17} {:>13} {:>10} {:>9} {:>11} {:>13} : stats {:>8} {:>7} {:>8} {:>11} : slabdata {:>11}",
+            cache_name(idx),
+            stat.active_objects,
+            stat.slabs * stat.objects_per_slab,
+            stat.object_size,
+            stat.objects_per_slab,
+            stat.pages_per_slab,
+            stat.allocs,
+            stat.frees,
+            stat.failed,
+            stat.corrupted,
+            stat.slabs,
+        )
+        .unwrap();
+    }
+    s
+}
+
+/// Content of `/proc/slab_alloc_calls`: live objects of every cache by allocation site.
+///
+/// A site whose count keeps growing is likely leaking.
+/// Sites are only recorded in debug builds, release builds show all objects as unknown.
+pub fn slab_alloc_calls() -> String {
+    let mut s = String::new();
+    for (idx, cache) in SLAB_CACHES.iter().enumerate() {
+        let mut sites = [(0, 0); MAX_SITES];
+        let (len, other) = cache.lock().count_sites(&mut sites);
+        let sites = &mut sites[..len];
+        sites.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        writeln!(s, "{}:", cache_name(idx)).unwrap();
+        for &(site, count) in sites.iter() {
+            if site == 1 {
+                writeln!(s, "{:>8} <unknown>", count).unwrap();
+            } else if let Some((name, offset)) = backtrace::symbol(site) {
+                writeln!(s, "{:>8} {}+{:#x}", count, name, offset).unwrap();
+            } else {
+                writeln!(s, "{:>8} {:#x}", count, site).unwrap();
+            }
+        }
+        if other != 0 {
+            writeln!(s, "{:>8} <other>", other).unwrap();
+        }
+    }
+    s
+}
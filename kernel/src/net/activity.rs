@@ -0,0 +1,226 @@
+//! Waiting for sockets to become ready
+//!
+//! Socket operations never block: they fail with EAGAIN instead. Blocking
+//! calls retry them as futures on the executor, woken up when NIC drivers
+//! report activity after polling an interface, so no CPU spins meanwhile.
+
+use super::structs::*;
+use crate::arch::timer::timer_now;
+use crate::drivers::SOCKET_ACTIVITY;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::{SysError, SysResult};
+use crate::trap::NAIVE_TIMER;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::cmp::min;
+use core::future::Future;
+use core::mem::take;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+lazy_static! {
+    /// Wakers of the futures waiting for activity, by waiter id.
+    /// A waiter has one waker at most, removed once it is woken up.
+    static ref WAITERS: Mutex<BTreeMap<usize, Waker>> = Mutex::new(BTreeMap::new());
+}
+
+/// Id of the next waiter
+static NEXT_WAITER: AtomicUsize = AtomicUsize::new(0);
+
+/// Count of activity so far, to catch the activity before a waiter subscribes
+static ACTIVITY: AtomicUsize = AtomicUsize::new(0);
+
+/// smoltcp timers such as TCP retransmission only run when interfaces are polled,
+/// so waiters also wake up at this interval without activity
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Report that sockets may have changed readiness
+pub fn notify_activity() {
+    ACTIVITY.fetch_add(1, Ordering::SeqCst);
+    SOCKET_ACTIVITY.notify_all();
+    let waiters = take(&mut *WAITERS.lock());
+    for (_, waker) in waiters {
+        waker.wake();
+    }
+}
+
+/// Count of activity so far, changed whenever sockets may have new data
+pub fn activity_count() -> usize {
+    ACTIVITY.load(Ordering::SeqCst)
+}
+
+/// Set the waker of waiter `id`, unless there has been activity after `count`
+fn subscribe(id: usize, count: usize, waker: &Waker) -> bool {
+    let mut waiters = WAITERS.lock();
+    // activity might come before subscribing
+    if activity_count() != count {
+        return false;
+    }
+    waiters.insert(id, waker.clone());
+    true
+}
+
+/// Wake up waiter `id` at `deadline`, if it is still waiting
+fn add_timer(id: usize, deadline: Duration) {
+    NAIVE_TIMER.lock().add(
+        deadline,
+        Box::new(move |_| {
+            let waker = WAITERS.lock().remove(&id);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }),
+    );
+}
+
+/// Resolve on activity after `count`, or at `wake_at` of the timer
+#[must_use = "future does nothing unless polled/`await`-ed"]
+pub(super) struct Activity {
+    id: usize,
+    count: usize,
+    wake_at: Duration,
+    timer_added: bool,
+}
+
+impl Future for Activity {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if activity_count() != self.count || timer_now() >= self.wake_at {
+            return Poll::Ready(());
+        }
+        if !subscribe(self.id, self.count, cx.waker()) {
+            return Poll::Ready(());
+        }
+        if !self.timer_added {
+            add_timer(self.id, self.wake_at);
+            self.timer_added = true;
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        WAITERS.lock().remove(&self.id);
+    }
+}
+
+/// Poll status of a socket, resolved once anything is ready.
+/// Every poll checks the socket once, so it can be polled by a new future
+/// each time, like files in `poll`. The task is woken up on activity or
+/// after the poll interval, subscribed once however many sockets it polls.
+#[must_use = "future does nothing unless polled/`await`-ed"]
+pub struct SocketReady<'a> {
+    socket: &'a dyn Socket,
+}
+
+impl Future for SocketReady<'_> {
+    type Output = (bool, bool, bool);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        poll_ifaces();
+        let count = activity_count();
+        match self.socket.poll() {
+            (false, false, false) => {}
+            ready => return Poll::Ready(ready),
+        }
+        let waker = cx.waker();
+        if WAITERS.lock().values().any(|other| other.will_wake(waker)) {
+            return Poll::Pending;
+        }
+        let id = NEXT_WAITER.fetch_add(1, Ordering::SeqCst);
+        if !subscribe(id, count, waker) {
+            // check again at once
+            waker.wake_by_ref();
+            return Poll::Pending;
+        }
+        add_timer(id, timer_now() + POLL_INTERVAL);
+        Poll::Pending
+    }
+}
+
+/// Wait for activity after `count`, which is taken before checking readiness,
+/// or until `wake_at` of the timer
+pub(super) fn wait_activity(count: usize, wake_at: Duration) -> Activity {
+    Activity {
+        id: NEXT_WAITER.fetch_add(1, Ordering::SeqCst),
+        count,
+        wake_at,
+        timer_added: false,
+    }
+}
+
+/// Retry `op` on activity while it fails with EAGAIN.
+/// Fails with EAGAIN once `timeout` is over, or at once if `nonblock`.
+pub async fn retry_on_activity<T>(
+    nonblock: bool,
+    timeout: Option<Duration>,
+    mut op: impl FnMut() -> Result<T, SysError>,
+) -> Result<T, SysError> {
+    let deadline = timeout.map(|timeout| timer_now() + timeout);
+    loop {
+        poll_ifaces();
+        let count = activity_count();
+        match op() {
+            Err(SysError::EAGAIN) => {}
+            result => return result,
+        }
+        let now = timer_now();
+        if nonblock || deadline.map_or(false, |deadline| now >= deadline) {
+            return Err(SysError::EAGAIN);
+        }
+        let wake_at = now + POLL_INTERVAL;
+        wait_activity(
+            count,
+            deadline.map_or(wake_at, |deadline| min(deadline, wake_at)),
+        )
+        .await;
+    }
+}
+
+impl dyn Socket {
+    /// Poll status of the socket once anything is ready, see `SocketReady`
+    pub fn ready(&self) -> SocketReady<'_> {
+        SocketReady { socket: self }
+    }
+
+    /// Receive a message, waiting for one unless the socket is non-blocking
+    /// or `MSG_DONTWAIT` is given
+    pub async fn recv(&self, data: &mut [u8], flags: MsgFlags) -> Result<RecvInfo, SysError> {
+        let options = *self.options().lock();
+        let nonblock = options.nonblock || flags.contains(MsgFlags::DONTWAIT);
+        let timeout = options.recv_timeout;
+        let mut info = retry_on_activity(nonblock, timeout, || self.recvmsg(data, flags)).await?;
+        if flags.contains(MsgFlags::WAITALL) && !flags.contains(MsgFlags::PEEK) {
+            // the part received so far is returned on errors
+            while info.len < data.len() {
+                let rest = &mut data[info.len..];
+                match retry_on_activity(nonblock, timeout, || self.recv_more(rest)).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => info.len += len,
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    /// Send a message, waiting for buffer space unless the socket is non-blocking
+    /// or `MSG_DONTWAIT` is given
+    pub async fn send(
+        &self,
+        data: &[u8],
+        sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+        flags: MsgFlags,
+    ) -> SysResult {
+        let options = *self.options().lock();
+        let nonblock = options.nonblock || flags.contains(MsgFlags::DONTWAIT);
+        retry_on_activity(nonblock, options.send_timeout, || {
+            self.sendmsg(data, sendto_endpoint.clone(), ancillary.clone(), flags)
+        })
+        .await
+    }
+}
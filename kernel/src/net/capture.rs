@@ -0,0 +1,142 @@
+//! Frames captured by packet sockets
+//!
+//! Every packet socket listening to a protocol registers a queue here,
+//! and `filter_frame` offers it each frame an interface receives or sends.
+//! The socket filter, if any, decides how much of a frame is queued.
+
+use super::filter::FilterDirection;
+use super::notify_activity;
+use super::sockfilter::SocketFilter;
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+/// Protocol of packet sockets capturing every frame, in host byte order
+pub const ETH_P_ALL: u16 = 0x0003;
+
+// sll_pkttype
+const PACKET_HOST: u8 = 0;
+const PACKET_BROADCAST: u8 = 1;
+const PACKET_MULTICAST: u8 = 2;
+const PACKET_OUTGOING: u8 = 4;
+
+/// Frames queued for a socket, more are dropped
+const MAX_CAPTURED_FRAMES: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    /// What the socket filter kept of the frame
+    pub data: Vec<u8>,
+    /// Ethertype in host byte order
+    pub protocol: u16,
+    /// PACKET_HOST, PACKET_OUTGOING and so on
+    pub packet_type: u8,
+}
+
+#[derive(Debug)]
+pub struct CaptureQueue {
+    /// ETH_P_ALL or an ethertype, 0 for nothing
+    protocol: u16,
+    frames: Mutex<VecDeque<CapturedFrame>>,
+    filter: Mutex<Option<SocketFilter>>,
+}
+
+lazy_static! {
+    static ref CAPTURES: Mutex<Vec<Weak<CaptureQueue>>> = Mutex::new(Vec::new());
+}
+
+impl CaptureQueue {
+    /// Start capturing frames of `protocol`, in host byte order
+    pub fn new(protocol: u16) -> Arc<Self> {
+        let queue = Arc::new(CaptureQueue {
+            protocol,
+            frames: Mutex::new(VecDeque::new()),
+            filter: Mutex::new(None),
+        });
+        if protocol != 0 {
+            CAPTURES.lock().push(Arc::downgrade(&queue));
+        }
+        queue
+    }
+
+    /// Replace the socket filter, return the old one
+    pub fn set_filter(&self, filter: Option<SocketFilter>) -> Option<SocketFilter> {
+        core::mem::replace(&mut *self.filter.lock(), filter)
+    }
+
+    pub fn pop(&self) -> Option<CapturedFrame> {
+        self.frames.lock().pop_front()
+    }
+
+    pub fn peek(&self) -> Option<CapturedFrame> {
+        self.frames.lock().front().cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.lock().is_empty()
+    }
+
+    /// Queue what the filter keeps of `frame`, return whether anything is kept
+    fn offer(
+        &self,
+        direction: FilterDirection,
+        frame: &[u8],
+        protocol: u16,
+        packet_type: u8,
+    ) -> bool {
+        if self.protocol != ETH_P_ALL && self.protocol != protocol {
+            return false;
+        }
+        let keep = match &*self.filter.lock() {
+            Some(filter) => filter.run(direction, frame),
+            None => frame.len(),
+        };
+        let mut frames = self.frames.lock();
+        if keep == 0 || frames.len() >= MAX_CAPTURED_FRAMES {
+            return false;
+        }
+        frames.push_back(CapturedFrame {
+            data: frame[..keep].to_vec(),
+            protocol,
+            packet_type,
+        });
+        true
+    }
+}
+
+fn packet_type(direction: FilterDirection, frame: &[u8]) -> u8 {
+    if direction == FilterDirection::Egress {
+        return PACKET_OUTGOING;
+    }
+    match frame.get(..6) {
+        Some(dst) if dst.iter().all(|&byte| byte == 0xff) => PACKET_BROADCAST,
+        Some(dst) if dst[0] & 1 != 0 => PACKET_MULTICAST,
+        _ => PACKET_HOST,
+    }
+}
+
+/// Offer `frame` to every packet socket
+pub(super) fn capture_frame(direction: FilterDirection, frame: &[u8]) {
+    let mut captures = CAPTURES.lock();
+    if captures.is_empty() {
+        return;
+    }
+    let protocol = match frame.get(12..14) {
+        Some(ethertype) => u16::from_be_bytes([ethertype[0], ethertype[1]]),
+        None => 0,
+    };
+    let packet_type = packet_type(direction, frame);
+    let mut captured = false;
+    // forget the queues of closed sockets meanwhile
+    captures.retain(|queue| match queue.upgrade() {
+        Some(queue) => {
+            captured |= queue.offer(direction, frame, protocol, packet_type);
+            true
+        }
+        None => false,
+    });
+    if captured {
+        notify_activity();
+    }
+}
@@ -0,0 +1,478 @@
+//! DHCPv4 client
+//!
+//! One client task runs on the executor for every NIC. The address set by
+//! the driver is kept until a lease is acquired, and is restored when the
+//! lease is lost. All messages are broadcast and carry the BROADCAST flag,
+//! so replies are received even before the interface has the leased address.
+
+use super::activity::{activity_count, wait_activity};
+use super::{sleep, sleep_until};
+use crate::arch::rand;
+use crate::arch::timer::timer_now;
+use crate::drivers::NetDriver;
+use crate::net::SOCKETS;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt::Write;
+use core::time::Duration;
+use smoltcp::socket::*;
+use smoltcp::wire::*;
+use spin::RwLock;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC_COOKIE: u32 = 0x63825363;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+/// Fixed part of a message before options, including the magic cookie
+const DHCP_HEADER_LEN: usize = 240;
+
+// message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+// options
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVER: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_END: u8 = 255;
+
+/// Wait for a reply before sending again
+const REPLY_TIMEOUT: Duration = Duration::from_secs(4);
+/// Messages sent before giving up discovery
+const DISCOVER_TRIES: usize = 4;
+/// Wait before discovering again, with the static address in use
+const DISCOVER_BACKOFF: Duration = Duration::from_secs(60);
+/// Wait before sending another renewal request
+const RENEW_RETRY: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// DNS servers from leases, by interface name
+    static ref DNS_SERVERS: RwLock<BTreeMap<String, Vec<Ipv4Address>>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// Content of /etc/resolv.conf, if any lease has DNS servers
+pub fn resolv_conf() -> Option<String> {
+    let servers = DNS_SERVERS.read();
+    if servers.values().all(Vec::is_empty) {
+        return None;
+    }
+    let mut s = String::new();
+    for addr in servers.values().flatten() {
+        writeln!(s, "nameserver {}", addr).unwrap();
+    }
+    Some(s)
+}
+
+/// Start the DHCP client of a newly registered NIC
+pub fn start_dhcp(iface: Arc<dyn NetDriver>) {
+    executor::spawn(run(iface));
+}
+
+#[derive(Debug)]
+struct DhcpMessage {
+    message_type: u8,
+    your_addr: Ipv4Address,
+    server: Option<Ipv4Address>,
+    subnet_mask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns_servers: Vec<Ipv4Address>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Lease {
+    addr: Ipv4Cidr,
+    server: Ipv4Address,
+    router: Option<Ipv4Address>,
+    dns_servers: Vec<Ipv4Address>,
+    renew_at: Duration,
+    expires_at: Duration,
+}
+
+impl Lease {
+    fn from_ack(ack: DhcpMessage, server: Ipv4Address) -> Option<Self> {
+        if !ack.your_addr.is_unicast() {
+            return None;
+        }
+        let prefix_len = match ack.subnet_mask {
+            Some(mask) => {
+                let mask = u32::from_be_bytes(mask.0);
+                if mask.leading_ones() + mask.trailing_zeros() != 32 {
+                    return None;
+                }
+                mask.leading_ones() as u8
+            }
+            None => 24,
+        };
+        let now = timer_now();
+        // no lease time means infinite
+        let lease_time = Duration::from_secs(ack.lease_time.unwrap_or(u32::MAX) as u64);
+        let renewal_time = match ack.renewal_time {
+            Some(time) => Duration::from_secs(time as u64).min(lease_time),
+            None => lease_time / 2,
+        };
+        Some(Lease {
+            addr: Ipv4Cidr::new(ack.your_addr, prefix_len),
+            server: ack.server.unwrap_or(server),
+            router: ack.router,
+            dns_servers: ack.dns_servers,
+            renew_at: now + renewal_time,
+            expires_at: now + lease_time,
+        })
+    }
+}
+
+/// A raw socket receiving UDP, removed from `SOCKETS` on drop
+struct DhcpSocket(SocketHandle);
+
+impl DhcpSocket {
+    fn new() -> Self {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 16], vec![0; 8192]);
+        let tx_buffer = RawSocketBuffer::new(vec![], vec![]);
+        let socket = RawSocket::new(IpVersion::Ipv4, IpProtocol::Udp, rx_buffer, tx_buffer);
+        DhcpSocket(SOCKETS.lock().add(socket))
+    }
+
+    /// Take the first reply to transaction `xid` of `mac` received
+    fn recv(&self, xid: u32, mac: EthernetAddress) -> Option<DhcpMessage> {
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<RawSocket>(self.0);
+        while let Ok(packet) = socket.recv() {
+            if let Some(message) = parse_reply(packet, xid, mac) {
+                return Some(message);
+            }
+        }
+        None
+    }
+}
+
+impl Drop for DhcpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(self.0);
+    }
+}
+
+async fn run(iface: Arc<dyn NetDriver>) {
+    let ifname = iface.get_ifname();
+    let fallback = iface.get_ip_addresses();
+    loop {
+        let mut lease = match discover(&iface).await {
+            Some(lease) => lease,
+            None => {
+                warn!("dhcp: no lease for {}, keep the static address", ifname);
+                sleep(DISCOVER_BACKOFF).await;
+                continue;
+            }
+        };
+        bind(&iface, None, &lease, &fallback);
+        loop {
+            sleep_until(lease.renew_at).await;
+            match renew(&iface, &lease).await {
+                Some(new_lease) => {
+                    bind(&iface, Some(&lease), &new_lease, &fallback);
+                    lease = new_lease;
+                }
+                None => break,
+            }
+        }
+        warn!("dhcp: lease of {} on {} lost", lease.addr, ifname);
+        unbind(&iface, &lease, &fallback);
+    }
+}
+
+/// Send `message` until a reply comes, or `tries` timeouts
+async fn transact(
+    iface: &Arc<dyn NetDriver>,
+    socket: &DhcpSocket,
+    message: &[u8],
+    xid: u32,
+    tries: usize,
+) -> Option<DhcpMessage> {
+    let mac = iface.get_mac();
+    for _ in 0..tries {
+        send(iface, message);
+        let deadline = timer_now() + REPLY_TIMEOUT;
+        loop {
+            iface.poll();
+            let count = activity_count();
+            if let Some(reply) = socket.recv(xid, mac) {
+                return Some(reply);
+            }
+            if timer_now() >= deadline {
+                break;
+            }
+            // replies are received when the driver polls the interface
+            wait_activity(count, deadline).await;
+        }
+    }
+    None
+}
+
+/// DISCOVER, then REQUEST the first offer
+async fn discover(iface: &Arc<dyn NetDriver>) -> Option<Lease> {
+    let socket = DhcpSocket::new();
+    let mac = iface.get_mac();
+    let xid = rand::rand() as u32;
+
+    let message = build_message(DHCPDISCOVER, xid, mac, None, None, None);
+    let offer = transact(iface, &socket, &message, xid, DISCOVER_TRIES).await?;
+    if offer.message_type != DHCPOFFER {
+        return None;
+    }
+    let server = offer.server?;
+    debug!("dhcp: offer {} from {}", offer.your_addr, server);
+
+    let message = build_message(
+        DHCPREQUEST,
+        xid,
+        mac,
+        None,
+        Some(offer.your_addr),
+        Some(server),
+    );
+    let ack = transact(iface, &socket, &message, xid, DISCOVER_TRIES).await?;
+    if ack.message_type != DHCPACK {
+        return None;
+    }
+    Lease::from_ack(ack, server)
+}
+
+/// REQUEST to extend `lease` until it expires
+async fn renew(iface: &Arc<dyn NetDriver>, lease: &Lease) -> Option<Lease> {
+    let socket = DhcpSocket::new();
+    let mac = iface.get_mac();
+    while timer_now() < lease.expires_at {
+        let xid = rand::rand() as u32;
+        let message = build_message(
+            DHCPREQUEST,
+            xid,
+            mac,
+            Some(lease.addr.address()),
+            None,
+            None,
+        );
+        match transact(iface, &socket, &message, xid, 1).await {
+            Some(ack) if ack.message_type == DHCPACK => {
+                return Lease::from_ack(ack, lease.server);
+            }
+            Some(ack) if ack.message_type == DHCPNAK => return None,
+            _ => {}
+        }
+        let retry_at = (timer_now() + RENEW_RETRY).min(lease.expires_at);
+        sleep_until(retry_at).await;
+    }
+    None
+}
+
+/// Replace the static address or the old lease with `lease`
+fn bind(iface: &Arc<dyn NetDriver>, old: Option<&Lease>, lease: &Lease, fallback: &[IpCidr]) {
+    let ifname = iface.get_ifname();
+    let mut addrs: Vec<IpCidr> = iface
+        .get_ip_addresses()
+        .into_iter()
+        .filter(|cidr| match cidr {
+            IpCidr::Ipv4(cidr) => {
+                !fallback.contains(&IpCidr::Ipv4(*cidr))
+                    && old.map_or(true, |old| old.addr != *cidr)
+            }
+            _ => true,
+        })
+        .collect();
+    addrs.insert(0, IpCidr::Ipv4(lease.addr));
+    iface.set_ip_addresses(addrs);
+
+    let default_route = IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0);
+    if let Some(router) = old.and_then(|old| old.router) {
+        if lease.router != Some(router) {
+            iface.del_route(default_route);
+        }
+    }
+    if let Some(router) = lease.router {
+        iface.add_route(default_route, router.into());
+    }
+    DNS_SERVERS
+        .write()
+        .insert(ifname.clone(), lease.dns_servers.clone());
+
+    info!(
+        "dhcp: {} bound to {} via {:?} from {}",
+        ifname, lease.addr, lease.router, lease.server
+    );
+}
+
+/// Restore the static address
+fn unbind(iface: &Arc<dyn NetDriver>, lease: &Lease, fallback: &[IpCidr]) {
+    let mut addrs: Vec<IpCidr> = iface
+        .get_ip_addresses()
+        .into_iter()
+        .filter(|cidr| *cidr != IpCidr::Ipv4(lease.addr))
+        .collect();
+    for (i, cidr) in fallback.iter().enumerate() {
+        if !addrs.contains(cidr) {
+            addrs.insert(min(i, addrs.len()), *cidr);
+        }
+    }
+    iface.set_ip_addresses(addrs);
+    if lease.router.is_some() {
+        iface.del_route(IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0));
+    }
+    DNS_SERVERS.write().remove(&iface.get_ifname());
+}
+
+fn build_message(
+    message_type: u8,
+    xid: u32,
+    mac: EthernetAddress,
+    client_addr: Option<Ipv4Address>,
+    requested_addr: Option<Ipv4Address>,
+    server: Option<Ipv4Address>,
+) -> Vec<u8> {
+    let mut msg = vec![0u8; DHCP_HEADER_LEN];
+    msg[0] = BOOTREQUEST;
+    msg[1] = HTYPE_ETHERNET;
+    msg[2] = 6; // hardware address length
+    msg[4..8].copy_from_slice(&xid.to_be_bytes());
+    msg[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    if let Some(addr) = client_addr {
+        msg[12..16].copy_from_slice(addr.as_bytes());
+    }
+    msg[28..34].copy_from_slice(mac.as_bytes());
+    msg[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE.to_be_bytes());
+
+    msg.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, message_type]);
+    if let Some(addr) = requested_addr {
+        msg.extend_from_slice(&[OPT_REQUESTED_IP, 4]);
+        msg.extend_from_slice(addr.as_bytes());
+    }
+    if let Some(addr) = server {
+        msg.extend_from_slice(&[OPT_SERVER_ID, 4]);
+        msg.extend_from_slice(addr.as_bytes());
+    }
+    msg.extend_from_slice(&[
+        OPT_PARAMETER_LIST,
+        5,
+        OPT_SUBNET_MASK,
+        OPT_ROUTER,
+        OPT_DNS_SERVER,
+        OPT_LEASE_TIME,
+        OPT_RENEWAL_TIME,
+    ]);
+    msg.push(OPT_END);
+    msg
+}
+
+/// Parse an IPv4 packet from the raw socket as a reply to transaction `xid` of `mac`
+fn parse_reply(packet: &[u8], xid: u32, mac: EthernetAddress) -> Option<DhcpMessage> {
+    let packet = Ipv4Packet::new_checked(packet).ok()?;
+    let udp = UdpPacket::new_checked(packet.payload()).ok()?;
+    if udp.src_port() != DHCP_SERVER_PORT || udp.dst_port() != DHCP_CLIENT_PORT {
+        return None;
+    }
+    let data = udp.payload();
+    if data.len() < DHCP_HEADER_LEN
+        || data[0] != BOOTREPLY
+        || data[4..8] != xid.to_be_bytes()
+        || &data[28..34] != mac.as_bytes()
+        || data[236..240] != DHCP_MAGIC_COOKIE.to_be_bytes()
+    {
+        return None;
+    }
+
+    let mut message = DhcpMessage {
+        message_type: 0,
+        your_addr: Ipv4Address::from_bytes(&data[16..20]),
+        server: None,
+        subnet_mask: None,
+        router: None,
+        dns_servers: Vec::new(),
+        lease_time: None,
+        renewal_time: None,
+    };
+    let mut options = &data[DHCP_HEADER_LEN..];
+    while let Some(&code) = options.first() {
+        if code == OPT_END {
+            break;
+        }
+        if code == OPT_PAD {
+            options = &options[1..];
+            continue;
+        }
+        let len = *options.get(1)? as usize;
+        let value = options.get(2..2 + len)?;
+        let addr = || {
+            if len >= 4 {
+                Some(Ipv4Address::from_bytes(&value[..4]))
+            } else {
+                None
+            }
+        };
+        let secs = || addr().map(|addr| u32::from_be_bytes(addr.0));
+        match code {
+            OPT_MESSAGE_TYPE if len == 1 => message.message_type = value[0],
+            OPT_SERVER_ID => message.server = addr(),
+            OPT_SUBNET_MASK => message.subnet_mask = addr(),
+            OPT_ROUTER => message.router = addr(),
+            OPT_DNS_SERVER => {
+                message.dns_servers = value.chunks_exact(4).map(Ipv4Address::from_bytes).collect();
+            }
+            OPT_LEASE_TIME => message.lease_time = secs(),
+            OPT_RENEWAL_TIME => message.renewal_time = secs(),
+            _ => {}
+        }
+        options = &options[2 + len..];
+    }
+    Some(message)
+}
+
+/// Broadcast a DHCP message from 0.0.0.0, bypassing smoltcp routing
+fn send(iface: &Arc<dyn NetDriver>, message: &[u8]) {
+    let udp_len = 8 + message.len();
+    let ip_len = 20 + udp_len;
+    let mut buffer = vec![0u8; 14 + ip_len];
+
+    let mut frame = EthernetFrame::new_unchecked(&mut buffer);
+    frame.set_dst_addr(EthernetAddress::BROADCAST);
+    frame.set_src_addr(iface.get_mac());
+    frame.set_ethertype(EthernetProtocol::Ipv4);
+
+    let mut packet = Ipv4Packet::new_unchecked(frame.payload_mut());
+    packet.set_version(4);
+    packet.set_header_len(20);
+    packet.set_total_len(ip_len as u16);
+    packet.set_hop_limit(64);
+    packet.set_protocol(IpProtocol::Udp);
+    packet.set_src_addr(Ipv4Address::UNSPECIFIED);
+    packet.set_dst_addr(Ipv4Address::BROADCAST);
+    {
+        let mut udp = UdpPacket::new_unchecked(packet.payload_mut());
+        udp.set_src_port(DHCP_CLIENT_PORT);
+        udp.set_dst_port(DHCP_SERVER_PORT);
+        udp.set_len(udp_len as u16);
+        // checksum is optional for UDP over IPv4
+        udp.set_checksum(0);
+        udp.payload_mut().copy_from_slice(message);
+    }
+    packet.fill_checksum();
+
+    if iface.send(&buffer).is_none() {
+        debug!("dhcp: failed to send on {}", iface.get_ifname());
+    }
+}
@@ -0,0 +1,559 @@
+//! Packet filter in the receive and transmit path of interfaces
+//!
+//! Every ethernet frame received or sent by an interface goes through
+//! `filter_frame`. eBPF programs attached to `filter:ingress` or
+//! `filter:egress` run first, a program returning 0 drops the frame.
+//! Then the first matching rule of the table decides, or the default
+//! policy of the direction if no rule matches.
+//!
+//! The rule table is managed by ioctls on sockets, see `filter_ioctl`.
+
+use super::capture::capture_frame;
+use crate::bpf::program::BpfProgram;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::*;
+use crate::tracepoint::trace_net;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use smoltcp::wire::*;
+
+// private ioctls, in the SIOCDEVPRIVATE range
+/// Insert a rule at `index`, or append it if `index` is past the end
+pub const SIOCADDFILTER: usize = 0x89f0;
+/// Remove the rule at `index`
+pub const SIOCDELFILTER: usize = 0x89f1;
+/// Read the rule at `index` with its counters
+pub const SIOCGETFILTER: usize = 0x89f2;
+/// Remove all rules, `arg` is ignored
+pub const SIOCFLUSHFILTER: usize = 0x89f3;
+/// Set the default policy of the directions in `rule.direction`
+/// to `rule.action`, counters are ignored
+pub const SIOCSFILTERPOLICY: usize = 0x89f4;
+/// Read the default policy of the direction in `rule.direction`,
+/// counters are of frames no rule matched
+pub const SIOCGFILTERPOLICY: usize = 0x89f5;
+
+// directions, bits of `FilterRule::direction`
+pub const FILTER_INGRESS: u8 = 1;
+pub const FILTER_EGRESS: u8 = 2;
+
+// actions
+pub const FILTER_ACCEPT: u8 = 0;
+pub const FILTER_DROP: u8 = 1;
+
+/// Rules after this many are refused
+const MAX_FILTER_RULES: usize = 256;
+
+/// Direction of a frame through an interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterDirection {
+    Ingress,
+    Egress,
+}
+
+impl FilterDirection {
+    /// Parse the hook name used by `BPF_PROG_ATTACH`, e.g. `filter:ingress`
+    pub fn from_hook(target: &str) -> Option<Self> {
+        match target {
+            "filter:ingress" => Some(FilterDirection::Ingress),
+            "filter:egress" => Some(FilterDirection::Egress),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        match self {
+            FilterDirection::Ingress => FILTER_INGRESS,
+            FilterDirection::Egress => FILTER_EGRESS,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A rule as seen by userspace, addresses in network byte order
+/// and ports in host byte order
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FilterRule {
+    /// FILTER_INGRESS and/or FILTER_EGRESS
+    direction: u8,
+    /// FILTER_ACCEPT or FILTER_DROP
+    action: u8,
+    /// IP protocol number, 0 for any
+    protocol: u8,
+    _pad1: u8,
+    /// AF_INET or AF_INET6, AF_UNSPEC matches non-IP frames too
+    family: u16,
+    /// Prefix lengths of the addresses, 0 for any
+    src_prefix: u8,
+    dst_prefix: u8,
+    src_addr: [u8; 16],
+    dst_addr: [u8; 16],
+    /// Inclusive port ranges of TCP and UDP, [0, 0] for any
+    src_ports: [u16; 2],
+    dst_ports: [u16; 2],
+    _pad2: u32,
+    /// Frames and bytes matched, ignored when adding a rule
+    packets: u64,
+    bytes: u64,
+}
+
+/// `struct ifreq` of the filter ioctls
+#[repr(C)]
+struct FilterReq {
+    /// Position in the table
+    index: u32,
+    _pad: u32,
+    rule: FilterRule,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counters {
+    packets: u64,
+    bytes: u64,
+}
+
+impl Counters {
+    fn count(&mut self, len: usize) {
+        self.packets += 1;
+        self.bytes += len as u64;
+    }
+}
+
+struct Rule {
+    directions: u8,
+    drop: bool,
+    version: Option<IpVersion>,
+    protocol: Option<IpProtocol>,
+    src: Option<IpCidr>,
+    dst: Option<IpCidr>,
+    src_ports: Option<(u16, u16)>,
+    dst_ports: Option<(u16, u16)>,
+    counters: Counters,
+}
+
+/// What a rule can match in a frame
+struct FrameInfo {
+    src: Option<IpAddress>,
+    dst: Option<IpAddress>,
+    protocol: Option<IpProtocol>,
+    /// Source and destination ports, only for unfragmented TCP and UDP
+    ports: Option<(u16, u16)>,
+}
+
+#[derive(Default)]
+struct Policy {
+    drop: bool,
+    counters: Counters,
+}
+
+struct FilterTable {
+    rules: Vec<Rule>,
+    /// Indexed by `FilterDirection`
+    policies: [Policy; 2],
+}
+
+lazy_static! {
+    static ref FILTER: Mutex<FilterTable> = Mutex::new(FilterTable {
+        rules: Vec::new(),
+        policies: Default::default(),
+    });
+    /// Indexed by `FilterDirection`
+    static ref FILTER_PROGRAMS: Mutex<[Vec<Arc<BpfProgram>>; 2]> =
+        Mutex::new([Vec::new(), Vec::new()]);
+}
+
+/// Context of attached eBPF programs, and of socket filters
+#[repr(C)]
+pub(super) struct FilterContext {
+    data: *const u8,
+    data_end: *const u8,
+    /// FILTER_INGRESS or FILTER_EGRESS
+    direction: u32,
+}
+
+impl FilterContext {
+    pub(super) fn new(direction: FilterDirection, frame: &[u8]) -> Self {
+        let range = frame.as_ptr_range();
+        FilterContext {
+            data: range.start,
+            data_end: range.end,
+            direction: direction.bit() as u32,
+        }
+    }
+}
+
+fn parse_addr(
+    family: AddressFamily,
+    addr: &[u8; 16],
+    prefix: u8,
+) -> Result<Option<IpCidr>, SysError> {
+    if prefix == 0 {
+        return Ok(None);
+    }
+    let cidr = match family {
+        AddressFamily::Internet if prefix <= 32 => {
+            IpCidr::new(IpAddress::Ipv4(Ipv4Address::from_bytes(&addr[..4])), prefix)
+        }
+        AddressFamily::Internet6 if prefix <= 128 => {
+            IpCidr::new(IpAddress::Ipv6(Ipv6Address::from_bytes(addr)), prefix)
+        }
+        _ => return Err(SysError::EINVAL),
+    };
+    Ok(Some(cidr))
+}
+
+fn parse_ports(ports: [u16; 2]) -> Result<Option<(u16, u16)>, SysError> {
+    match ports {
+        [0, 0] => Ok(None),
+        [min, max] if min <= max => Ok(Some((min, max))),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+fn parse_action(action: u8) -> Result<bool, SysError> {
+    match action {
+        FILTER_ACCEPT => Ok(false),
+        FILTER_DROP => Ok(true),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+impl Rule {
+    fn from_user(rule: &FilterRule) -> Result<Self, SysError> {
+        if rule.direction == 0 || rule.direction & !(FILTER_INGRESS | FILTER_EGRESS) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let family = AddressFamily::from(rule.family);
+        let version = match family {
+            AddressFamily::Unspecified => None,
+            AddressFamily::Internet => Some(IpVersion::Ipv4),
+            AddressFamily::Internet6 => Some(IpVersion::Ipv6),
+            _ => return Err(SysError::EAFNOSUPPORT),
+        };
+        let protocol = match rule.protocol {
+            0 => None,
+            protocol => Some(IpProtocol::from(protocol)),
+        };
+        let src_ports = parse_ports(rule.src_ports)?;
+        let dst_ports = parse_ports(rule.dst_ports)?;
+        if (src_ports.is_some() || dst_ports.is_some())
+            && protocol != Some(IpProtocol::Tcp)
+            && protocol != Some(IpProtocol::Udp)
+        {
+            // ports only make sense for a given protocol
+            return Err(SysError::EINVAL);
+        }
+        Ok(Rule {
+            directions: rule.direction,
+            drop: parse_action(rule.action)?,
+            version,
+            protocol,
+            src: parse_addr(family, &rule.src_addr, rule.src_prefix)?,
+            dst: parse_addr(family, &rule.dst_addr, rule.dst_prefix)?,
+            src_ports,
+            dst_ports,
+            counters: Counters::default(),
+        })
+    }
+
+    fn to_user(&self) -> FilterRule {
+        fn addr_bytes(cidr: Option<IpCidr>) -> ([u8; 16], u8) {
+            let mut bytes = [0u8; 16];
+            match cidr {
+                Some(IpCidr::Ipv4(cidr)) => {
+                    bytes[..4].copy_from_slice(cidr.address().as_bytes());
+                    (bytes, cidr.prefix_len())
+                }
+                Some(IpCidr::Ipv6(cidr)) => {
+                    bytes.copy_from_slice(cidr.address().as_bytes());
+                    (bytes, cidr.prefix_len())
+                }
+                _ => (bytes, 0),
+            }
+        }
+        let (src_addr, src_prefix) = addr_bytes(self.src);
+        let (dst_addr, dst_prefix) = addr_bytes(self.dst);
+        let family = match self.version {
+            Some(IpVersion::Ipv4) => AddressFamily::Internet,
+            Some(IpVersion::Ipv6) => AddressFamily::Internet6,
+            _ => AddressFamily::Unspecified,
+        };
+        let (src_min, src_max) = self.src_ports.unwrap_or((0, 0));
+        let (dst_min, dst_max) = self.dst_ports.unwrap_or((0, 0));
+        FilterRule {
+            direction: self.directions,
+            action: if self.drop {
+                FILTER_DROP
+            } else {
+                FILTER_ACCEPT
+            },
+            protocol: self.protocol.map_or(0, u8::from),
+            _pad1: 0,
+            family: family.into(),
+            src_prefix,
+            dst_prefix,
+            src_addr,
+            dst_addr,
+            src_ports: [src_min, src_max],
+            dst_ports: [dst_min, dst_max],
+            _pad2: 0,
+            packets: self.counters.packets,
+            bytes: self.counters.bytes,
+        }
+    }
+
+    fn matches(&self, direction: FilterDirection, frame: &FrameInfo) -> bool {
+        fn port_in(range: Option<(u16, u16)>, port: Option<u16>) -> bool {
+            match (range, port) {
+                (None, _) => true,
+                (Some((min, max)), Some(port)) => min <= port && port <= max,
+                (Some(_), None) => false,
+            }
+        }
+        fn addr_in(cidr: Option<IpCidr>, addr: Option<IpAddress>) -> bool {
+            match (cidr, addr) {
+                (None, _) => true,
+                (Some(cidr), Some(addr)) => cidr.contains_addr(&addr),
+                (Some(_), None) => false,
+            }
+        }
+
+        if self.directions & direction.bit() == 0 {
+            return false;
+        }
+        if let Some(version) = self.version {
+            match frame.src {
+                Some(IpAddress::Ipv4(_)) if version == IpVersion::Ipv4 => {}
+                Some(IpAddress::Ipv6(_)) if version == IpVersion::Ipv6 => {}
+                _ => return false,
+            }
+        }
+        if self.protocol.is_some() && self.protocol != frame.protocol {
+            return false;
+        }
+        addr_in(self.src, frame.src)
+            && addr_in(self.dst, frame.dst)
+            && port_in(self.src_ports, frame.ports.map(|(src, _)| src))
+            && port_in(self.dst_ports, frame.ports.map(|(_, dst)| dst))
+    }
+}
+
+impl FrameInfo {
+    fn parse(frame: &[u8]) -> Self {
+        let mut info = FrameInfo {
+            src: None,
+            dst: None,
+            protocol: None,
+            ports: None,
+        };
+        let frame = match EthernetFrame::new_checked(frame) {
+            Ok(frame) => frame,
+            Err(_) => return info,
+        };
+        let payload = match frame.ethertype() {
+            EthernetProtocol::Ipv4 => match Ipv4Packet::new_checked(frame.payload()) {
+                Ok(packet) => {
+                    info.src = Some(IpAddress::Ipv4(packet.src_addr()));
+                    info.dst = Some(IpAddress::Ipv4(packet.dst_addr()));
+                    info.protocol = Some(packet.protocol());
+                    if packet.frag_offset() != 0 {
+                        // no transport header in later fragments
+                        return info;
+                    }
+                    packet.payload()
+                }
+                Err(_) => return info,
+            },
+            EthernetProtocol::Ipv6 => match Ipv6Packet::new_checked(frame.payload()) {
+                Ok(packet) => {
+                    info.src = Some(IpAddress::Ipv6(packet.src_addr()));
+                    info.dst = Some(IpAddress::Ipv6(packet.dst_addr()));
+                    // extension headers are not followed
+                    info.protocol = Some(packet.next_header());
+                    packet.payload()
+                }
+                Err(_) => return info,
+            },
+            _ => return info,
+        };
+        match info.protocol {
+            // source and destination ports come first in both headers
+            Some(IpProtocol::Tcp) | Some(IpProtocol::Udp) if payload.len() >= 4 => {
+                let src = u16::from_be_bytes([payload[0], payload[1]]);
+                let dst = u16::from_be_bytes([payload[2], payload[3]]);
+                info.ports = Some((src, dst));
+            }
+            _ => {}
+        }
+        info
+    }
+}
+
+fn run_programs(direction: FilterDirection, frame: &[u8]) -> bool {
+    let programs = FILTER_PROGRAMS.lock();
+    let programs = &programs[direction.index()];
+    if programs.is_empty() {
+        return true;
+    }
+    let ctx = FilterContext::new(direction, frame);
+    let ctx_size = core::mem::size_of::<FilterContext>();
+    let ctx = &ctx as *const FilterContext as *const u8;
+    programs
+        .iter()
+        .all(|program| program.run(ctx, ctx_size) != 0)
+}
+
+/// Decide whether to let `frame` through, and count it.
+/// Drivers call it on every frame they receive or send.
+///
+/// Packet sockets capture frames here, before filtering
+/// when they are received and after it when they are sent.
+pub fn filter_frame(direction: FilterDirection, frame: &[u8]) -> bool {
+    if direction == FilterDirection::Ingress {
+        trace_net(frame, false, 0);
+        capture_frame(direction, frame);
+    }
+    let accepted = verdict(direction, frame);
+    if direction == FilterDirection::Egress {
+        // a dropped frame is not sent
+        let rc = if accepted {
+            0
+        } else {
+            -(SysError::EPERM as i32)
+        };
+        trace_net(frame, true, rc);
+        if accepted {
+            capture_frame(direction, frame);
+        }
+    }
+    accepted
+}
+
+fn verdict(direction: FilterDirection, frame: &[u8]) -> bool {
+    if !run_programs(direction, frame) {
+        return false;
+    }
+    let mut table = FILTER.lock();
+    if table.rules.is_empty() && !table.policies[direction.index()].drop {
+        // fast path, nothing to count
+        return true;
+    }
+    let info = FrameInfo::parse(frame);
+    for rule in table.rules.iter_mut() {
+        if rule.matches(direction, &info) {
+            rule.counters.count(frame.len());
+            return !rule.drop;
+        }
+    }
+    let policy = &mut table.policies[direction.index()];
+    policy.counters.count(frame.len());
+    !policy.drop
+}
+
+/// Attach an eBPF program to a direction, the same program
+/// can only be attached once
+pub fn attach_filter_program(direction: FilterDirection, program: Arc<BpfProgram>) -> SysResult {
+    if program.ctx_size() > core::mem::size_of::<FilterContext>() {
+        return Err(SysError::EINVAL);
+    }
+    let mut programs = FILTER_PROGRAMS.lock();
+    let programs = &mut programs[direction.index()];
+    if programs.iter().any(|other| Arc::ptr_eq(&program, other)) {
+        return Err(SysError::EAGAIN);
+    }
+    programs.push(program);
+    Ok(0)
+}
+
+pub fn detach_filter_program(direction: FilterDirection, program: &Arc<BpfProgram>) -> SysResult {
+    let mut programs = FILTER_PROGRAMS.lock();
+    let programs = &mut programs[direction.index()];
+    let index = programs
+        .iter()
+        .position(|other| Arc::ptr_eq(program, other))
+        .ok_or(SysError::ENOENT)?;
+    programs.remove(index);
+    Ok(0)
+}
+
+fn set_policy(req: &FilterReq) -> SysResult {
+    let directions = req.rule.direction;
+    if directions == 0 || directions & !(FILTER_INGRESS | FILTER_EGRESS) != 0 {
+        return Err(SysError::EINVAL);
+    }
+    let drop = parse_action(req.rule.action)?;
+    let mut table = FILTER.lock();
+    for direction in [FilterDirection::Ingress, FilterDirection::Egress].iter() {
+        if directions & direction.bit() != 0 {
+            table.policies[direction.index()].drop = drop;
+        }
+    }
+    Ok(0)
+}
+
+fn get_policy(req: &mut FilterReq) -> SysResult {
+    let direction = match req.rule.direction {
+        FILTER_INGRESS => FilterDirection::Ingress,
+        FILTER_EGRESS => FilterDirection::Egress,
+        _ => return Err(SysError::EINVAL),
+    };
+    let table = FILTER.lock();
+    let policy = &table.policies[direction.index()];
+    req.rule.action = if policy.drop {
+        FILTER_DROP
+    } else {
+        FILTER_ACCEPT
+    };
+    req.rule.packets = policy.counters.packets;
+    req.rule.bytes = policy.counters.bytes;
+    Ok(0)
+}
+
+/// Packet filter ioctls on a socket, `arg` points to `struct FilterReq`
+/// except for SIOCFLUSHFILTER, which takes no argument
+pub fn filter_ioctl(request: usize, arg: usize) -> SysResult {
+    if request == SIOCFLUSHFILTER {
+        FILTER.lock().rules.clear();
+        return Ok(0);
+    }
+    let mut req_ptr = UserInOutPtr::<FilterReq>::from(arg);
+    let mut req = req_ptr.read()?;
+    match request {
+        SIOCADDFILTER => {
+            let rule = Rule::from_user(&req.rule)?;
+            let mut table = FILTER.lock();
+            if table.rules.len() >= MAX_FILTER_RULES {
+                return Err(SysError::ENOSPC);
+            }
+            let index = (req.index as usize).min(table.rules.len());
+            table.rules.insert(index, rule);
+            req.index = index as u32;
+        }
+        SIOCDELFILTER => {
+            let mut table = FILTER.lock();
+            if (req.index as usize) < table.rules.len() {
+                table.rules.remove(req.index as usize);
+                return Ok(0);
+            } else {
+                return Err(SysError::ENOENT);
+            }
+        }
+        SIOCGETFILTER => match FILTER.lock().rules.get(req.index as usize) {
+            Some(rule) => req.rule = rule.to_user(),
+            None => return Err(SysError::ENOENT),
+        },
+        SIOCSFILTERPOLICY => return set_policy(&req),
+        SIOCGFILTERPOLICY => {
+            get_policy(&mut req)?;
+        }
+        _ => return Err(SysError::EINVAL),
+    }
+    // the index or the rule is returned
+    req_ptr.write(req)?;
+    Ok(0)
+}
@@ -0,0 +1,477 @@
+//! Runtime configuration of network interfaces
+//!
+//! Interfaces are identified by their index in `NET_DRIVERS`, the same as
+//! `ifi_index` in netlink messages. `ioctl` on sockets (busybox ifconfig
+//! and route) is handled here, netlink messages (busybox ip) share the
+//! helpers below.
+
+use super::filter::*;
+use crate::drivers::net::IfaceFlags;
+use crate::drivers::{NetDriver, NET_DRIVERS};
+use crate::syscall::*;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::mem::{discriminant, size_of};
+use smoltcp::wire::*;
+
+const IFNAMSIZ: usize = 16;
+
+const SIOCADDRT: usize = 0x890b;
+const SIOCDELRT: usize = 0x890c;
+const SIOCGIFCONF: usize = 0x8912;
+const SIOCGIFFLAGS: usize = 0x8913;
+const SIOCSIFFLAGS: usize = 0x8914;
+const SIOCGIFADDR: usize = 0x8915;
+const SIOCSIFADDR: usize = 0x8916;
+const SIOCGIFBRDADDR: usize = 0x8919;
+const SIOCGIFNETMASK: usize = 0x891b;
+const SIOCSIFNETMASK: usize = 0x891c;
+const SIOCGIFHWADDR: usize = 0x8927;
+const SIOCGIFINDEX: usize = 0x8933;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+
+/// `rt_flags` in `struct rtentry`: the route has a gateway
+const RTF_GATEWAY: u16 = 0x2;
+
+/// struct ifmap, only to get the size of `struct ifreq` right
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct IfMap {
+    _mem_start: usize,
+    _mem_end: usize,
+    _base_addr: u16,
+    _irq: u8,
+    _dma: u8,
+    _port: u8,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+union IfReqData {
+    addr: SockAddrIn,
+    hwaddr: SockAddrPlaceholder,
+    flags: u16,
+    ifindex: i32,
+    _map: IfMap,
+}
+
+#[repr(C)]
+struct IfReq {
+    ifr_name: [u8; IFNAMSIZ],
+    ifr_ifru: IfReqData,
+}
+
+#[repr(C)]
+struct IfConf {
+    ifc_len: i32,
+    ifc_buf: usize,
+}
+
+#[repr(C)]
+struct RtEntry {
+    _rt_pad1: usize,
+    rt_dst: SockAddrIn,
+    rt_gateway: SockAddrIn,
+    rt_genmask: SockAddrIn,
+    rt_flags: u16,
+    _rt_pad2: i16,
+    _rt_pad3: usize,
+    _rt_pad4: usize,
+    _rt_metric: i16,
+    rt_dev: *const u8,
+    _rt_mtu: usize,
+    _rt_window: usize,
+    _rt_irtt: u16,
+}
+
+impl IfReq {
+    fn new(name: &str) -> Self {
+        // zeroed, so that no kernel data is copied to the user in the union
+        let mut req: IfReq = unsafe { core::mem::zeroed() };
+        req.set_name(name);
+        req
+    }
+
+    fn name(&self) -> &str {
+        let len = self
+            .ifr_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(IFNAMSIZ);
+        core::str::from_utf8(&self.ifr_name[..len]).unwrap_or("")
+    }
+
+    fn set_name(&mut self, name: &str) {
+        let len = min(name.len(), IFNAMSIZ - 1);
+        self.ifr_name = [0; IFNAMSIZ];
+        self.ifr_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+}
+
+fn sockaddr_in(addr: Ipv4Address) -> SockAddrIn {
+    SockAddrIn {
+        sin_family: AddressFamily::Internet.into(),
+        sin_port: 0,
+        sin_addr: u32::to_be(u32::from_be_bytes(addr.0)),
+        sin_zero: [0; 8],
+    }
+}
+
+fn ipv4_from_sockaddr(addr: &SockAddrIn) -> Result<Ipv4Address, SysError> {
+    match AddressFamily::from(addr.sin_family) {
+        AddressFamily::Internet => Ok(Ipv4Address::from_bytes(
+            &u32::from_be(addr.sin_addr).to_be_bytes()[..],
+        )),
+        _ => Err(SysError::EAFNOSUPPORT),
+    }
+}
+
+fn prefix_to_netmask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+fn ipv4_network(cidr: Ipv4Cidr) -> Ipv4Cidr {
+    let mask = prefix_to_netmask(cidr.prefix_len());
+    let addr = u32::from_be_bytes(cidr.address().0) & mask;
+    Ipv4Cidr::new(
+        Ipv4Address::from_bytes(&addr.to_be_bytes()),
+        cidr.prefix_len(),
+    )
+}
+
+fn ipv6_network(cidr: Ipv6Cidr) -> Ipv6Cidr {
+    let mut addr = cidr.address().0;
+    for (i, byte) in addr.iter_mut().enumerate() {
+        let bits = (cidr.prefix_len() as u32)
+            .saturating_sub(i as u32 * 8)
+            .min(8);
+        *byte &= 0xffu8.checked_shl(8 - bits).unwrap_or(0);
+    }
+    Ipv6Cidr::new(Ipv6Address(addr), cidr.prefix_len())
+}
+
+/// Clear the host part of `cidr`
+pub fn network(cidr: IpCidr) -> IpCidr {
+    match cidr {
+        IpCidr::Ipv4(cidr) => IpCidr::Ipv4(ipv4_network(cidr)),
+        IpCidr::Ipv6(cidr) => IpCidr::Ipv6(ipv6_network(cidr)),
+        cidr => cidr,
+    }
+}
+
+fn netmask_to_prefix(netmask: Ipv4Address) -> Result<u8, SysError> {
+    let mask = u32::from_be_bytes(netmask.0);
+    if mask.leading_ones() + mask.trailing_zeros() != 32 {
+        return Err(SysError::EINVAL);
+    }
+    Ok(mask.leading_ones() as u8)
+}
+
+/// Default prefix length when an address is set without a netmask, as Linux does
+fn classful_prefix(addr: Ipv4Address) -> u8 {
+    match addr.0[0] {
+        0..=127 => 8,
+        128..=191 => 16,
+        _ => 24,
+    }
+}
+
+fn get_iface(index: usize) -> Result<Arc<dyn NetDriver>, SysError> {
+    NET_DRIVERS
+        .read()
+        .get(index)
+        .cloned()
+        .ok_or(SysError::ENODEV)
+}
+
+fn find_iface(name: &str) -> Result<(usize, Arc<dyn NetDriver>), SysError> {
+    NET_DRIVERS
+        .read()
+        .iter()
+        .enumerate()
+        .find(|(_, iface)| iface.get_ifname() == name)
+        .map(|(index, iface)| (index, iface.clone()))
+        .ok_or(SysError::ENODEV)
+}
+
+/// The first IPv4 address, which ifconfig shows and changes
+fn primary_ipv4(addrs: &[IpCidr]) -> Option<(usize, Ipv4Cidr)> {
+    addrs.iter().enumerate().find_map(|(i, cidr)| match cidr {
+        IpCidr::Ipv4(cidr) => Some((i, *cidr)),
+        _ => None,
+    })
+}
+
+fn is_on_link(iface: &Arc<dyn NetDriver>, addr: IpAddress) -> bool {
+    iface
+        .get_ip_addresses()
+        .iter()
+        .any(|cidr| cidr.contains_addr(&addr))
+}
+
+/// Interface index and local address of messages between `local` and `remote`.
+/// smoltcp does not tell the destination of received messages, so for a
+/// wildcard `local` it is the address on the link of `remote`, or else the
+/// first address of its family.
+pub fn local_address(local: IpAddress, remote: IpAddress) -> Option<(usize, IpAddress)> {
+    let mut fallback = None;
+    for (index, iface) in NET_DRIVERS.read().iter().enumerate() {
+        for cidr in iface.get_ip_addresses() {
+            let addr = cidr.address();
+            if !local.is_unspecified() {
+                if addr == local {
+                    return Some((index, addr));
+                }
+            } else if cidr.contains_addr(&remote) {
+                return Some((index, addr));
+            } else if fallback.is_none() && discriminant(&addr) == discriminant(&remote) {
+                fallback = Some((index, addr));
+            }
+        }
+    }
+    fallback
+}
+
+/// Add `cidr` to interface `index`
+pub fn add_address(index: usize, cidr: IpCidr) -> SysResult {
+    let iface = get_iface(index)?;
+    if !cidr.address().is_unicast() {
+        return Err(SysError::EINVAL);
+    }
+    let mut addrs = iface.get_ip_addresses();
+    if addrs.iter().any(|old| old.address() == cidr.address()) {
+        return Err(SysError::EEXIST);
+    }
+    addrs.push(cidr);
+    iface.set_ip_addresses(addrs);
+    Ok(0)
+}
+
+/// Remove `addr` from interface `index`
+pub fn del_address(index: usize, addr: IpAddress) -> SysResult {
+    let iface = get_iface(index)?;
+    let mut addrs = iface.get_ip_addresses();
+    let len = addrs.len();
+    addrs.retain(|cidr| cidr.address() != addr);
+    if addrs.len() == len {
+        return Err(SysError::EADDRNOTAVAIL);
+    }
+    iface.set_ip_addresses(addrs);
+    Ok(0)
+}
+
+/// Add a route to `dst` via `gateway` on interface `index`.
+/// The interface is chosen by the gateway if not given.
+///
+/// smoltcp only keeps routes through a gateway, routes without one
+/// are accepted only if `dst` is already reachable by an address of the interface.
+pub fn add_route(index: Option<usize>, dst: IpCidr, gateway: Option<IpAddress>) -> SysResult {
+    if network(dst) != dst {
+        return Err(SysError::EINVAL);
+    }
+    let target = gateway.unwrap_or_else(|| dst.address());
+    let iface = match index {
+        Some(index) => get_iface(index)?,
+        None => NET_DRIVERS
+            .read()
+            .iter()
+            .find(|iface| is_on_link(iface, target))
+            .cloned()
+            .ok_or(SysError::ENETUNREACH)?,
+    };
+    if !is_on_link(&iface, target) {
+        return Err(SysError::ENETUNREACH);
+    }
+    match gateway {
+        Some(gateway) => {
+            if iface.add_route(dst, gateway) {
+                Ok(0)
+            } else {
+                Err(SysError::ENOBUFS)
+            }
+        }
+        None => Ok(0),
+    }
+}
+
+/// Remove the route to `dst` on interface `index`, or on any interface if not given
+pub fn del_route(index: Option<usize>, dst: IpCidr) -> SysResult {
+    let ifaces = match index {
+        Some(index) => vec![get_iface(index)?],
+        None => NET_DRIVERS.read().clone(),
+    };
+    let mut removed = false;
+    for iface in ifaces.iter() {
+        removed |= iface.del_route(dst);
+    }
+    if removed {
+        Ok(0)
+    } else {
+        Err(SysError::ESRCH)
+    }
+}
+
+/// Replace the primary IPv4 address, keeping its prefix length.
+/// 0.0.0.0 removes it.
+fn set_ipv4_address(iface: &dyn NetDriver, addr: Ipv4Address) -> SysResult {
+    let mut addrs = iface.get_ip_addresses();
+    let primary = primary_ipv4(&addrs);
+    if addr.is_unspecified() {
+        if let Some((i, _)) = primary {
+            addrs.remove(i);
+        }
+    } else if !addr.is_unicast() {
+        return Err(SysError::EINVAL);
+    } else if let Some((i, cidr)) = primary {
+        addrs[i] = IpCidr::new(addr.into(), cidr.prefix_len());
+    } else {
+        addrs.insert(0, IpCidr::new(addr.into(), classful_prefix(addr)));
+    }
+    iface.set_ip_addresses(addrs);
+    Ok(0)
+}
+
+fn set_ipv4_netmask(iface: &dyn NetDriver, netmask: Ipv4Address) -> SysResult {
+    let prefix_len = netmask_to_prefix(netmask)?;
+    let mut addrs = iface.get_ip_addresses();
+    let (i, cidr) = primary_ipv4(&addrs).ok_or(SysError::EADDRNOTAVAIL)?;
+    addrs[i] = IpCidr::new(cidr.address().into(), prefix_len);
+    iface.set_ip_addresses(addrs);
+    Ok(0)
+}
+
+fn get_iface_conf(conf: &mut IfConf) -> SysResult {
+    let entries: Vec<(String, Ipv4Address)> = NET_DRIVERS
+        .read()
+        .iter()
+        .filter_map(|iface| Some((iface.get_ifname(), iface.ipv4_address()?)))
+        .collect();
+    if conf.ifc_buf == 0 {
+        // query the buffer size
+        conf.ifc_len = (entries.len() * size_of::<IfReq>()) as i32;
+        return Ok(0);
+    }
+    let count = min(
+        entries.len(),
+        conf.ifc_len.max(0) as usize / size_of::<IfReq>(),
+    );
+    let reqs: Vec<IfReq> = entries[..count]
+        .iter()
+        .map(|(name, addr)| {
+            let mut req = IfReq::new(name);
+            req.ifr_ifru.addr = sockaddr_in(*addr);
+            req
+        })
+        .collect();
+    UserOutPtr::<IfReq>::from(conf.ifc_buf).write_array(&reqs)?;
+    conf.ifc_len = (count * size_of::<IfReq>()) as i32;
+    Ok(0)
+}
+
+fn iface_route(request: usize, entry: &RtEntry) -> SysResult {
+    let dst = ipv4_from_sockaddr(&entry.rt_dst)?;
+    let netmask = Ipv4Address::from_bytes(&u32::from_be(entry.rt_genmask.sin_addr).to_be_bytes());
+    let dst = IpCidr::new(dst.into(), netmask_to_prefix(netmask)?);
+    let index = if entry.rt_dev.is_null() {
+        None
+    } else {
+        let name = UserInPtr::<u8>::from(entry.rt_dev as usize).read_cstring()?;
+        Some(find_iface(&name)?.0)
+    };
+    if request == SIOCADDRT {
+        let gateway = if entry.rt_flags & RTF_GATEWAY != 0 {
+            Some(ipv4_from_sockaddr(&entry.rt_gateway)?.into())
+        } else {
+            None
+        };
+        add_route(index, dst, gateway)
+    } else {
+        del_route(index, dst)
+    }
+}
+
+/// Interface ioctls on a socket, `arg` points to `struct ifreq`,
+/// `struct ifconf` or `struct rtentry`, or a filter request.
+/// Unknown requests are ignored.
+pub fn iface_ioctl(request: usize, arg: usize) -> SysResult {
+    match request {
+        SIOCGIFCONF => {
+            let mut conf_ptr = UserInOutPtr::<IfConf>::from(arg);
+            let mut conf = conf_ptr.read()?;
+            get_iface_conf(&mut conf)?;
+            conf_ptr.write(conf)?;
+            Ok(0)
+        }
+        SIOCADDRT | SIOCDELRT => iface_route(request, &UserInPtr::<RtEntry>::from(arg).read()?),
+        SIOCGIFFLAGS | SIOCSIFFLAGS | SIOCGIFADDR | SIOCSIFADDR | SIOCGIFBRDADDR
+        | SIOCGIFNETMASK | SIOCSIFNETMASK | SIOCGIFHWADDR | SIOCGIFINDEX => {
+            let mut req_ptr = UserInOutPtr::<IfReq>::from(arg);
+            let mut req = req_ptr.read()?;
+            let (index, iface) = find_iface(req.name())?;
+            debug!("iface ioctl {:#x} on {}", request, req.name());
+            let primary = primary_ipv4(&iface.get_ip_addresses()).map(|(_, cidr)| cidr);
+            match request {
+                SIOCGIFFLAGS => {
+                    req.ifr_ifru.flags = iface.get_flags().bits() as u16;
+                }
+                SIOCSIFFLAGS => {
+                    let flags =
+                        IfaceFlags::from_bits_truncate(unsafe { req.ifr_ifru.flags } as u32);
+                    iface.set_up(flags.contains(IfaceFlags::UP));
+                    return Ok(0);
+                }
+                SIOCGIFADDR => {
+                    let cidr = primary.ok_or(SysError::EADDRNOTAVAIL)?;
+                    req.ifr_ifru.addr = sockaddr_in(cidr.address());
+                }
+                SIOCSIFADDR => {
+                    let addr = ipv4_from_sockaddr(unsafe { &req.ifr_ifru.addr })?;
+                    return set_ipv4_address(&*iface, addr);
+                }
+                SIOCGIFBRDADDR => {
+                    let cidr = primary.ok_or(SysError::EADDRNOTAVAIL)?;
+                    let mask = prefix_to_netmask(cidr.prefix_len());
+                    let broadcast = u32::from_be_bytes(cidr.address().0) | !mask;
+                    req.ifr_ifru.addr =
+                        sockaddr_in(Ipv4Address::from_bytes(&broadcast.to_be_bytes()));
+                }
+                SIOCGIFNETMASK => {
+                    let cidr = primary.ok_or(SysError::EADDRNOTAVAIL)?;
+                    let mask = prefix_to_netmask(cidr.prefix_len());
+                    req.ifr_ifru.addr = sockaddr_in(Ipv4Address::from_bytes(&mask.to_be_bytes()));
+                }
+                SIOCSIFNETMASK => {
+                    // the family of a netmask is not always set
+                    let mask = unsafe { req.ifr_ifru.addr.sin_addr };
+                    let mask = Ipv4Address::from_bytes(&u32::from_be(mask).to_be_bytes());
+                    return set_ipv4_netmask(&*iface, mask);
+                }
+                SIOCGIFHWADDR => {
+                    let mut hwaddr = SockAddrPlaceholder {
+                        family: ARPHRD_ETHER,
+                        data: [0; 14],
+                    };
+                    if iface.get_flags().contains(IfaceFlags::LOOPBACK) {
+                        hwaddr.family = ARPHRD_LOOPBACK;
+                    }
+                    hwaddr.data[..6].copy_from_slice(iface.get_mac().as_bytes());
+                    req.ifr_ifru.hwaddr = hwaddr;
+                }
+                SIOCGIFINDEX => {
+                    req.ifr_ifru.ifindex = index as i32;
+                }
+                _ => unreachable!(),
+            }
+            // only get requests write the result back
+            req_ptr.write(req)?;
+            Ok(0)
+        }
+        SIOCADDFILTER | SIOCDELFILTER | SIOCGETFILTER | SIOCFLUSHFILTER | SIOCSFILTERPOLICY
+        | SIOCGFILTERPOLICY => filter_ioctl(request, arg),
+        _ => Ok(0),
+    }
+}
@@ -1,5 +1,62 @@
+use crate::arch::timer::timer_now;
+use crate::trap::NAIVE_TIMER;
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+mod activity;
+mod capture;
+mod dhcp;
+mod filter;
+mod iface;
+mod slaac;
+mod sockfilter;
 mod structs;
 mod test;
+mod unix;
 
+pub use self::activity::{activity_count, notify_activity, retry_on_activity};
+pub use self::dhcp::{resolv_conf, start_dhcp};
+pub use self::filter::{
+    attach_filter_program, detach_filter_program, filter_frame, FilterDirection,
+};
+pub use self::slaac::start_slaac;
+pub use self::sockfilter::{SockFilter, SockFprog, SocketFilter};
 pub use self::structs::*;
 pub use self::test::server;
+pub use self::unix::UnixSocketState;
+
+/// Register software network interfaces, after NIC drivers are probed
+pub fn init() {
+    crate::drivers::net::loopback::init();
+}
+
+/// Resolve at `deadline` of the timer
+struct Sleep {
+    deadline: Duration,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if timer_now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let waker = cx.waker().clone();
+        NAIVE_TIMER
+            .lock()
+            .add(self.deadline, Box::new(move |_| waker.wake()));
+        Poll::Pending
+    }
+}
+
+fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep { deadline }
+}
+
+fn sleep(duration: Duration) -> Sleep {
+    sleep_until(timer_now() + duration)
+}
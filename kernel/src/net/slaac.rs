@@ -0,0 +1,237 @@
+//! IPv6 stateless address autoconfiguration
+//!
+//! Every NIC gets a link-local address derived from its MAC when registered.
+//! A task on the executor then solicits routers, adds an address for every
+//! autonomous /64 prefix of the first advertisement, and a default route via
+//! the advertising router. Prefix and router lifetimes are not tracked,
+//! and duplicate address detection is not performed.
+
+use super::sleep;
+use crate::arch::timer::timer_now;
+use crate::drivers::NetDriver;
+use crate::net::SOCKETS;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use smoltcp::socket::*;
+use smoltcp::wire::*;
+
+const ALL_ROUTERS: Ipv6Address =
+    Ipv6Address([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+/// Ethernet multicast address of `ALL_ROUTERS`
+const ALL_ROUTERS_MAC: EthernetAddress = EthernetAddress([0x33, 0x33, 0, 0, 0, 2]);
+const LINK_LOCAL_PREFIX: [u8; 8] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0];
+/// Neighbor discovery messages from off-link are dropped by this hop limit
+const NDP_HOP_LIMIT: u8 = 255;
+
+// ICMPv6 types
+const ROUTER_SOLICIT: u8 = 133;
+const ROUTER_ADVERT: u8 = 134;
+/// Fixed part of a router advertisement before options
+const ROUTER_ADVERT_LEN: usize = 16;
+
+// neighbor discovery options
+const OPT_SOURCE_LL_ADDR: u8 = 1;
+const OPT_PREFIX_INFO: u8 = 3;
+const PREFIX_INFO_LEN: usize = 32;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// Solicitations sent before giving up
+const SOLICIT_TRIES: usize = 3;
+/// Wait for an advertisement before soliciting again
+const SOLICIT_INTERVAL: Duration = Duration::from_secs(4);
+/// How often the raw socket is checked for advertisements
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Add the link-local address of a newly registered NIC and start autoconfiguration
+pub fn start_slaac(iface: Arc<dyn NetDriver>) {
+    let link_local = eui64_address(&LINK_LOCAL_PREFIX, iface.get_mac());
+    add_address(&iface, Ipv6Cidr::new(link_local, 64));
+    executor::spawn(run(iface));
+}
+
+/// Address of a /64 `prefix` with the modified EUI-64 interface identifier of `mac`
+fn eui64_address(prefix: &[u8], mac: EthernetAddress) -> Ipv6Address {
+    let mac = mac.0;
+    let mut addr = [0u8; 16];
+    addr[..8].copy_from_slice(&prefix[..8]);
+    addr[8..].copy_from_slice(&[
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]);
+    Ipv6Address(addr)
+}
+
+fn add_address(iface: &Arc<dyn NetDriver>, cidr: Ipv6Cidr) {
+    let mut addrs = iface.get_ip_addresses();
+    if addrs.iter().any(|old| *old == IpCidr::Ipv6(cidr)) {
+        return;
+    }
+    addrs.push(IpCidr::Ipv6(cidr));
+    iface.set_ip_addresses(addrs);
+    info!("slaac: {} got {}", iface.get_ifname(), cidr);
+}
+
+#[derive(Debug)]
+struct RouterAdvert {
+    router: Ipv6Address,
+    /// In seconds, 0 if it is not a default router
+    router_lifetime: u16,
+    prefixes: Vec<Ipv6Address>,
+}
+
+/// A raw socket receiving ICMPv6, removed from `SOCKETS` on drop
+struct NdiscSocket(SocketHandle);
+
+impl NdiscSocket {
+    fn new() -> Self {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 16], vec![0; 8192]);
+        let tx_buffer = RawSocketBuffer::new(vec![], vec![]);
+        let socket = RawSocket::new(IpVersion::Ipv6, IpProtocol::Icmpv6, rx_buffer, tx_buffer);
+        NdiscSocket(SOCKETS.lock().add(socket))
+    }
+
+    /// Take the first router advertisement received
+    fn recv(&self) -> Option<RouterAdvert> {
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<RawSocket>(self.0);
+        while let Ok(packet) = socket.recv() {
+            if let Some(advert) = parse_advert(packet) {
+                return Some(advert);
+            }
+        }
+        None
+    }
+}
+
+impl Drop for NdiscSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(self.0);
+    }
+}
+
+async fn run(iface: Arc<dyn NetDriver>) {
+    let socket = NdiscSocket::new();
+    let link_local = eui64_address(&LINK_LOCAL_PREFIX, iface.get_mac());
+    for _ in 0..SOLICIT_TRIES {
+        send_solicit(&iface, link_local);
+        let deadline = timer_now() + SOLICIT_INTERVAL;
+        while timer_now() < deadline {
+            iface.poll();
+            if let Some(advert) = socket.recv() {
+                configure(&iface, &advert);
+                return;
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+    debug!("slaac: no router on {}", iface.get_ifname());
+}
+
+/// Add addresses and the default route from `advert`
+fn configure(iface: &Arc<dyn NetDriver>, advert: &RouterAdvert) {
+    let mac = iface.get_mac();
+    for prefix in advert.prefixes.iter() {
+        add_address(
+            iface,
+            Ipv6Cidr::new(eui64_address(prefix.as_bytes(), mac), 64),
+        );
+    }
+    if advert.router_lifetime > 0 {
+        let default_route = IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 0);
+        iface.add_route(default_route, advert.router.into());
+        info!(
+            "slaac: {} default router {}",
+            iface.get_ifname(),
+            advert.router
+        );
+    }
+}
+
+/// Send a router solicitation from `src` with our link-layer address
+fn send_solicit(iface: &Arc<dyn NetDriver>, src: Ipv6Address) {
+    let mac = iface.get_mac();
+    // header, then the source link-layer address option
+    let icmp_len = 8 + 8;
+    let mut buffer = vec![0u8; 14 + 40 + icmp_len];
+
+    let mut frame = EthernetFrame::new_unchecked(&mut buffer);
+    frame.set_dst_addr(ALL_ROUTERS_MAC);
+    frame.set_src_addr(mac);
+    frame.set_ethertype(EthernetProtocol::Ipv6);
+
+    let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+    packet.set_version(6);
+    packet.set_payload_len(icmp_len as u16);
+    packet.set_next_header(IpProtocol::Icmpv6);
+    packet.set_hop_limit(NDP_HOP_LIMIT);
+    packet.set_src_addr(src);
+    packet.set_dst_addr(ALL_ROUTERS);
+
+    let payload = packet.payload_mut();
+    // code, checksum and reserved are left zero
+    payload[0] = ROUTER_SOLICIT;
+    payload[8] = OPT_SOURCE_LL_ADDR;
+    // in units of 8 bytes
+    payload[9] = 1;
+    payload[10..16].copy_from_slice(mac.as_bytes());
+    Icmpv6Packet::new_unchecked(payload).fill_checksum(&src.into(), &ALL_ROUTERS.into());
+
+    if iface.send(&buffer).is_none() {
+        debug!("slaac: failed to send on {}", iface.get_ifname());
+    }
+}
+
+/// Parse a router advertisement, including the IPv6 header
+fn parse_advert(packet: &[u8]) -> Option<RouterAdvert> {
+    let packet = Ipv6Packet::new_checked(packet).ok()?;
+    let router = packet.src_addr();
+    if packet.next_header() != IpProtocol::Icmpv6
+        || packet.hop_limit() != NDP_HOP_LIMIT
+        || !router.is_link_local()
+    {
+        return None;
+    }
+    let data = packet.payload();
+    if data.len() < ROUTER_ADVERT_LEN || data[0] != ROUTER_ADVERT || data[1] != 0 {
+        return None;
+    }
+
+    let mut advert = RouterAdvert {
+        router,
+        router_lifetime: u16::from_be_bytes([data[6], data[7]]),
+        prefixes: Vec::new(),
+    };
+    let mut options = &data[ROUTER_ADVERT_LEN..];
+    while options.len() >= 2 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        let option = &options[..len];
+        if option[0] == OPT_PREFIX_INFO && len == PREFIX_INFO_LEN {
+            let prefix_len = option[2];
+            let flags = option[3];
+            let valid = u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+            let preferred = u32::from_be_bytes([option[8], option[9], option[10], option[11]]);
+            let prefix = Ipv6Address::from_bytes(&option[16..32]);
+            // only /64 prefixes fit an EUI-64 interface identifier
+            if prefix_len == 64
+                && flags & PREFIX_FLAG_AUTONOMOUS != 0
+                && valid > 0
+                && preferred <= valid
+                && !prefix.is_link_local()
+            {
+                advert.prefixes.push(prefix);
+            }
+        }
+        options = &options[len..];
+    }
+    Some(advert)
+}
@@ -0,0 +1,300 @@
+//! Socket filters of SO_ATTACH_FILTER and SO_ATTACH_BPF
+//!
+//! A filter returns how many bytes of a frame to keep, 0 drops the frame.
+//! Classic BPF programs are checked when attached and interpreted here,
+//! eBPF programs from `BPF_OBJECTS` are run by `crate::bpf`.
+
+use super::filter::{FilterContext, FilterDirection};
+use crate::bpf::consts::BPF_PROG_TYPE_SOCKET_FILTER;
+use crate::bpf::program::BpfProgram;
+use crate::syscall::{SysError, SysResult};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt;
+
+/// struct sock_filter, a classic BPF instruction
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// struct sock_fprog, the option value of SO_ATTACH_FILTER
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SockFprog {
+    pub len: u16,
+    pub filter: *const SockFilter,
+}
+
+// instruction classes
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+
+// sizes of loads
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+
+// modes of loads
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+
+// ALU operations
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+
+// jumps
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+// operands
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+/// Return A, for BPF_RET
+const BPF_A: u16 = 0x10;
+
+// register transfers
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// Scratch memory words
+const BPF_MEMWORDS: usize = 16;
+/// Longest program accepted
+const BPF_MAXINSNS: usize = 4096;
+
+/// Offsets from here on are Linux ancillary data, not supported
+const SKF_AD_OFF: u32 = -0x1000i32 as u32;
+
+#[derive(Clone)]
+pub enum SocketFilter {
+    /// Checked classic BPF program
+    Classic(Arc<Vec<SockFilter>>),
+    /// eBPF program of SO_ATTACH_BPF
+    Extended(Arc<BpfProgram>),
+}
+
+impl fmt::Debug for SocketFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketFilter::Classic(insns) => write!(f, "Classic({} insns)", insns.len()),
+            SocketFilter::Extended(_) => write!(f, "Extended"),
+        }
+    }
+}
+
+impl SocketFilter {
+    /// Check a classic BPF program as Linux does, and make a filter of it
+    pub fn classic(insns: &[SockFilter]) -> Result<Self, SysError> {
+        check_classic(insns)?;
+        Ok(SocketFilter::Classic(Arc::new(insns.to_vec())))
+    }
+
+    /// Make a filter of an eBPF program, if it reads no more than `FilterContext`
+    pub fn extended(program: Arc<BpfProgram>) -> Result<Self, SysError> {
+        if !program.fits(BPF_PROG_TYPE_SOCKET_FILTER)
+            || program.ctx_size() > core::mem::size_of::<FilterContext>()
+        {
+            return Err(SysError::EINVAL);
+        }
+        Ok(SocketFilter::Extended(program))
+    }
+
+    /// Bytes of `frame` to keep
+    pub fn run(&self, direction: FilterDirection, frame: &[u8]) -> usize {
+        let keep = match self {
+            SocketFilter::Classic(insns) => run_classic(insns, frame).unwrap_or(0),
+            SocketFilter::Extended(program) => {
+                let ctx = FilterContext::new(direction, frame);
+                let ctx_size = core::mem::size_of::<FilterContext>();
+                program.run(&ctx as *const FilterContext as *const u8, ctx_size) as u32
+            }
+        };
+        min(keep as usize, frame.len())
+    }
+}
+
+/// Check an instruction at `pc` of a program of `len` instructions
+fn valid_insn(insn: &SockFilter, pc: usize, len: usize) -> bool {
+    let code = insn.code;
+    let k = insn.k;
+    // targets of jumps are relative to the next instruction,
+    // compared without adding to the offset, which overflows on 32-bit
+    let in_program = |offset: u32| (offset as usize) < len - pc - 1;
+    if code > 0xff {
+        return false;
+    }
+    match code & 0x07 {
+        BPF_LD | BPF_LDX => match (code & 0x07, code & 0xe0, code & 0x18) {
+            (_, BPF_IMM, BPF_W) | (_, BPF_LEN, BPF_W) => true,
+            (_, BPF_MEM, BPF_W) => (k as usize) < BPF_MEMWORDS,
+            (BPF_LD, BPF_ABS, BPF_W) | (BPF_LD, BPF_ABS, BPF_H) | (BPF_LD, BPF_ABS, BPF_B) => {
+                k < SKF_AD_OFF
+            }
+            (BPF_LD, BPF_IND, BPF_W) | (BPF_LD, BPF_IND, BPF_H) | (BPF_LD, BPF_IND, BPF_B) => true,
+            (BPF_LDX, BPF_MSH, BPF_B) => k < SKF_AD_OFF,
+            _ => false,
+        },
+        BPF_ST | BPF_STX => code & !0x07 == 0 && (k as usize) < BPF_MEMWORDS,
+        BPF_ALU => match (code & 0xf0, code & 0x08) {
+            (BPF_DIV, BPF_K) | (BPF_MOD, BPF_K) => k != 0,
+            (BPF_LSH, BPF_K) | (BPF_RSH, BPF_K) => k < 32,
+            (BPF_NEG, _) => code == BPF_ALU | BPF_NEG,
+            (op, _) => [
+                BPF_ADD, BPF_SUB, BPF_MUL, BPF_DIV, BPF_OR, BPF_AND, BPF_LSH, BPF_RSH, BPF_MOD,
+                BPF_XOR,
+            ]
+            .contains(&op),
+        },
+        BPF_JMP => match code & 0xf0 {
+            BPF_JA => code == BPF_JMP | BPF_JA && in_program(k),
+            BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET => {
+                in_program(insn.jt as u32) && in_program(insn.jf as u32)
+            }
+            _ => false,
+        },
+        BPF_RET => code == BPF_RET | BPF_K || code == BPF_RET | BPF_A,
+        _ => code == BPF_MISC | BPF_TAX || code == BPF_MISC | BPF_TXA,
+    }
+}
+
+fn check_classic(insns: &[SockFilter]) -> SysResult {
+    if insns.is_empty() || insns.len() > BPF_MAXINSNS {
+        return Err(SysError::EINVAL);
+    }
+    for (pc, insn) in insns.iter().enumerate() {
+        if !valid_insn(insn, pc, insns.len()) {
+            warn!("invalid classic BPF instruction {:?} at {}", insn, pc);
+            return Err(SysError::EINVAL);
+        }
+    }
+    // the program must not run past its end
+    match insns.last() {
+        Some(insn) if insn.code & 0x07 == BPF_RET => Ok(0),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+/// Load a big endian value of `size` at `offset` of `frame`
+fn load(frame: &[u8], offset: u32, size: u16) -> Option<u32> {
+    let len = match size {
+        BPF_W => 4,
+        BPF_H => 2,
+        _ => 1,
+    };
+    let start = offset as usize;
+    let bytes = frame.get(start..start.checked_add(len)?)?;
+    Some(
+        bytes
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as u32),
+    )
+}
+
+/// Run a checked program, `None` if it aborts by loading
+/// out of the frame or dividing by zero
+fn run_classic(insns: &[SockFilter], frame: &[u8]) -> Option<u32> {
+    let mut a: u32 = 0;
+    let mut x: u32 = 0;
+    let mut mem = [0u32; BPF_MEMWORDS];
+    let mut pc = 0;
+    loop {
+        let insn = insns[pc];
+        let code = insn.code;
+        let k = insn.k;
+        pc += 1;
+        match code & 0x07 {
+            BPF_LD => {
+                a = match code & 0xe0 {
+                    BPF_IMM => k,
+                    BPF_ABS => load(frame, k, code & 0x18)?,
+                    BPF_IND => load(frame, x.wrapping_add(k), code & 0x18)?,
+                    BPF_MEM => mem[k as usize],
+                    BPF_LEN => frame.len() as u32,
+                    _ => return None,
+                }
+            }
+            BPF_LDX => {
+                x = match code & 0xe0 {
+                    BPF_IMM => k,
+                    BPF_MEM => mem[k as usize],
+                    BPF_LEN => frame.len() as u32,
+                    // IPv4 header length
+                    BPF_MSH => (load(frame, k, BPF_B)? & 0xf) << 2,
+                    _ => return None,
+                }
+            }
+            BPF_ST => mem[k as usize] = a,
+            BPF_STX => mem[k as usize] = x,
+            BPF_ALU => {
+                let operand = if code & 0x08 == BPF_X { x } else { k };
+                a = match code & 0xf0 {
+                    BPF_ADD => a.wrapping_add(operand),
+                    BPF_SUB => a.wrapping_sub(operand),
+                    BPF_MUL => a.wrapping_mul(operand),
+                    BPF_DIV => a.checked_div(operand)?,
+                    BPF_MOD => a.checked_rem(operand)?,
+                    BPF_OR => a | operand,
+                    BPF_AND => a & operand,
+                    BPF_XOR => a ^ operand,
+                    BPF_LSH => a.checked_shl(operand).unwrap_or(0),
+                    BPF_RSH => a.checked_shr(operand).unwrap_or(0),
+                    BPF_NEG => a.wrapping_neg(),
+                    _ => return None,
+                }
+            }
+            BPF_JMP => {
+                let operand = if code & 0x08 == BPF_X { x } else { k };
+                let taken = match code & 0xf0 {
+                    BPF_JA => {
+                        pc += k as usize;
+                        continue;
+                    }
+                    BPF_JEQ => a == operand,
+                    BPF_JGT => a > operand,
+                    BPF_JGE => a >= operand,
+                    BPF_JSET => a & operand != 0,
+                    _ => return None,
+                };
+                pc += if taken { insn.jt } else { insn.jf } as usize;
+            }
+            BPF_RET => {
+                return Some(if code & 0x18 == BPF_A { a } else { k });
+            }
+            BPF_MISC => {
+                if code & 0xf8 == BPF_TAX {
+                    x = a;
+                } else {
+                    a = x;
+                }
+            }
+            _ => return None,
+        }
+    }
+}
@@ -1,5 +1,9 @@
+use super::capture::CaptureQueue;
+use super::iface::*;
+use super::sockfilter::SocketFilter;
 use crate::arch::rand;
-use crate::drivers::{NET_DRIVERS, SOCKET_ACTIVITY};
+use crate::drivers::NET_DRIVERS;
+use crate::fs::FileLike;
 use crate::sync::SpinNoIrqLock as Mutex;
 use crate::syscall::*;
 use crate::util;
@@ -11,6 +15,7 @@ use bitflags::*;
 use core::cmp::min;
 use core::mem::size_of;
 use core::slice;
+use core::time::Duration;
 
 use smoltcp::socket::*;
 use smoltcp::wire::*;
@@ -18,12 +23,18 @@ use smoltcp::wire::*;
 #[derive(Clone, Debug)]
 pub struct LinkLevelEndpoint {
     pub interface_index: usize,
+    /// Ethertype in host byte order
+    pub protocol: u16,
+    /// sll_pkttype of received frames
+    pub packet_type: u8,
 }
 
 impl LinkLevelEndpoint {
     pub fn new(ifindex: usize) -> Self {
         LinkLevelEndpoint {
             interface_index: ifindex,
+            protocol: 0,
+            packet_type: 0,
         }
     }
 }
@@ -50,16 +61,324 @@ pub enum Endpoint {
     Netlink(NetlinkEndpoint),
 }
 
-/// Common methods that a socket must have
+bitflags! {
+    /// Flags of send and receive calls
+    pub struct MsgFlags: usize {
+        const OOB = 0x1;
+        const PEEK = 0x2;
+        const CTRUNC = 0x8;
+        const TRUNC = 0x20;
+        const DONTWAIT = 0x40;
+        const EOR = 0x80;
+        const WAITALL = 0x100;
+        const NOSIGNAL = 0x4000;
+        /// recvmmsg: do not block after the first message
+        const WAITFORONE = 0x10000;
+        const CMSG_CLOEXEC = 0x4000_0000;
+    }
+}
+
+/// Credentials of a local socket peer, as `struct ucred`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UCred {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Ancillary data passed along with data on local sockets
+#[derive(Debug, Clone, Default)]
+pub struct Ancillary {
+    /// SCM_RIGHTS
+    pub files: Vec<FileLike>,
+    /// SCM_CREDENTIALS
+    pub creds: Option<UCred>,
+}
+
+impl Ancillary {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.creds.is_none()
+    }
+}
+
+/// Control messages asked for by setsockopt
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CmsgOptions {
+    /// IP_PKTINFO
+    pub pktinfo: bool,
+    /// SO_TIMESTAMP
+    pub timestamp: bool,
+}
+
+impl CmsgOptions {
+    /// Handle the setsockopt of an option, return false if it is not one of them
+    pub fn set(&mut self, level: usize, opt: usize, data: &[u8]) -> bool {
+        let value = data.first().map_or(false, |&value| value != 0);
+        match (level, opt) {
+            (IPPROTO_IP, IP_PKTINFO) => self.pktinfo = value,
+            (SOL_SOCKET, SO_TIMESTAMP) => self.timestamp = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Options of an open socket, shared by its duplicated fds
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// O_NONBLOCK
+    pub nonblock: bool,
+    /// SO_RCVTIMEO, `None` to wait forever
+    pub recv_timeout: Option<Duration>,
+    /// SO_SNDTIMEO, `None` to wait forever
+    pub send_timeout: Option<Duration>,
+    /// SO_REUSEADDR, ports in TIME-WAIT never block binding here anyway
+    pub reuse_addr: bool,
+    /// SO_KEEPALIVE
+    pub keepalive: bool,
+    /// TCP_KEEPIDLE, idle time before the first keep-alive probe
+    pub keep_idle: Duration,
+    /// TCP_KEEPINTVL, time between keep-alive probes
+    pub keep_interval: Duration,
+    /// TCP_KEEPCNT, probes unanswered before the connection is dropped
+    pub keep_count: u32,
+    /// TCP_USER_TIMEOUT, `None` for no timeout
+    pub user_timeout: Option<Duration>,
+    /// SO_LINGER in seconds, `Some(0)` resets the connection on close
+    pub linger: Option<u32>,
+    /// TCP_NODELAY, smoltcp never delays segments to coalesce them anyway
+    pub nodelay: bool,
+    /// IP_TTL, `None` for the default
+    pub ttl: Option<u8>,
+    /// IP_MULTICAST_TTL
+    pub multicast_ttl: u8,
+    /// IP_MULTICAST_LOOP, smoltcp never loops multicast back anyway
+    pub multicast_loop: bool,
+    /// IP_MULTICAST_IF, smoltcp picks the interface by route anyway
+    pub multicast_if: Ipv4Address,
+    /// SO_RCVBUF and SO_SNDBUF, `None` for the default of the protocol
+    pub recv_buf: Option<usize>,
+    pub send_buf: Option<usize>,
+    /// A connect is in progress, its result is reported by SO_ERROR
+    pub connecting: bool,
+}
+
+impl Default for SocketOptions {
+    /// Defaults of Linux
+    fn default() -> Self {
+        SocketOptions {
+            nonblock: false,
+            recv_timeout: None,
+            send_timeout: None,
+            reuse_addr: false,
+            keepalive: false,
+            keep_idle: Duration::from_secs(7200),
+            keep_interval: Duration::from_secs(75),
+            keep_count: 9,
+            user_timeout: None,
+            linger: None,
+            nodelay: false,
+            ttl: None,
+            multicast_ttl: 1,
+            multicast_loop: true,
+            multicast_if: Ipv4Address::UNSPECIFIED,
+            recv_buf: None,
+            send_buf: None,
+            connecting: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Handle the setsockopt of an option kept here,
+    /// return false if it is not one of them
+    pub fn set(&mut self, level: usize, opt: usize, data: &[u8]) -> Result<bool, SysError> {
+        match (level, opt) {
+            (SOL_SOCKET, SO_REUSEADDR) => self.reuse_addr = parse_int(data)? != 0,
+            (SOL_SOCKET, SO_KEEPALIVE) => self.keepalive = parse_int(data)? != 0,
+            (SOL_SOCKET, SO_LINGER) => {
+                if data.len() < size_of::<Linger>() {
+                    return Err(SysError::EINVAL);
+                }
+                let linger = unsafe { (data.as_ptr() as *const Linger).read_unaligned() };
+                self.linger = if linger.l_onoff != 0 {
+                    Some(linger.l_linger.max(0) as u32)
+                } else {
+                    None
+                };
+            }
+            (IPPROTO_TCP, TCP_NODELAY) => self.nodelay = parse_int(data)? != 0,
+            (IPPROTO_TCP, TCP_KEEPIDLE) => self.keep_idle = parse_secs(data)?,
+            (IPPROTO_TCP, TCP_KEEPINTVL) => self.keep_interval = parse_secs(data)?,
+            (IPPROTO_TCP, TCP_KEEPCNT) => match parse_int(data)? {
+                count @ 1..=MAX_TCP_KEEPCNT => self.keep_count = count as u32,
+                _ => return Err(SysError::EINVAL),
+            },
+            (IPPROTO_TCP, TCP_USER_TIMEOUT) => match parse_int(data)? {
+                0 => self.user_timeout = None,
+                msecs if msecs > 0 => self.user_timeout = Some(Duration::from_millis(msecs as u64)),
+                _ => return Err(SysError::EINVAL),
+            },
+            (IPPROTO_IP, IP_TTL) => match parse_int(data)? {
+                -1 => self.ttl = None,
+                ttl @ 1..=255 => self.ttl = Some(ttl as u8),
+                _ => return Err(SysError::EINVAL),
+            },
+            (IPPROTO_IP, IP_MULTICAST_TTL) => match parse_int(data)? {
+                -1 => self.multicast_ttl = 1,
+                ttl @ 0..=255 => self.multicast_ttl = ttl as u8,
+                _ => return Err(SysError::EINVAL),
+            },
+            (IPPROTO_IP, IP_MULTICAST_LOOP) => self.multicast_loop = parse_int(data)? != 0,
+            (IPPROTO_IP, IP_MULTICAST_IF) => {
+                // struct in_addr, or the address in struct ip_mreq or ip_mreqn
+                self.multicast_if = match data.len() {
+                    4..=7 => Ipv4Address::from_bytes(&data[..4]),
+                    len if len >= 8 => Ipv4Address::from_bytes(&data[4..8]),
+                    _ => return Err(SysError::EINVAL),
+                };
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Handle the getsockopt of an option kept here,
+    /// return `None` if it is not one of them
+    pub fn get(&self, level: usize, opt: usize) -> Option<Vec<u8>> {
+        let value = match (level, opt) {
+            (SOL_SOCKET, SO_REUSEADDR) => self.reuse_addr as i32,
+            (SOL_SOCKET, SO_KEEPALIVE) => self.keepalive as i32,
+            // no errors are kept pending unless the socket reports them
+            (SOL_SOCKET, SO_ERROR) => 0,
+            (SOL_SOCKET, SO_LINGER) => {
+                let linger = Linger {
+                    l_onoff: self.linger.is_some() as i32,
+                    l_linger: self.linger.unwrap_or(0) as i32,
+                };
+                return Some(as_bytes(&linger).to_vec());
+            }
+            (IPPROTO_TCP, TCP_NODELAY) => self.nodelay as i32,
+            (IPPROTO_TCP, TCP_KEEPIDLE) => self.keep_idle.as_secs() as i32,
+            (IPPROTO_TCP, TCP_KEEPINTVL) => self.keep_interval.as_secs() as i32,
+            (IPPROTO_TCP, TCP_KEEPCNT) => self.keep_count as i32,
+            (IPPROTO_TCP, TCP_USER_TIMEOUT) => self
+                .user_timeout
+                .map_or(0, |timeout| timeout.as_millis() as i32),
+            (IPPROTO_IP, IP_TTL) => self.ttl.unwrap_or(DEFAULT_TTL) as i32,
+            (IPPROTO_IP, IP_MULTICAST_TTL) => self.multicast_ttl as i32,
+            (IPPROTO_IP, IP_MULTICAST_LOOP) => self.multicast_loop as i32,
+            (IPPROTO_IP, IP_MULTICAST_IF) => return Some(self.multicast_if.as_bytes().to_vec()),
+            _ => return None,
+        };
+        Some(value.to_ne_bytes().to_vec())
+    }
+}
+
+/// struct linger of SO_LINGER
+#[repr(C)]
+struct Linger {
+    l_onoff: i32,
+    l_linger: i32,
+}
+
+/// Parse an int option. As Linux, a shorter one is taken as a single byte.
+fn parse_int(data: &[u8]) -> Result<i32, SysError> {
+    match data.len() {
+        0 => Err(SysError::EINVAL),
+        1..=3 => Ok(data[0] as i32),
+        _ => Ok(i32::from_ne_bytes([data[0], data[1], data[2], data[3]])),
+    }
+}
+
+/// Parse an int option in seconds for TCP keep-alive
+fn parse_secs(data: &[u8]) -> Result<Duration, SysError> {
+    match parse_int(data)? {
+        secs @ 1..=MAX_TCP_KEEPALIVE_SECS => Ok(Duration::from_secs(secs as u64)),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+/// Bytes of a plain old data struct
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Int option of getsockopt
+fn int_opt(value: i32) -> Result<Vec<u8>, SysError> {
+    Ok(value.to_ne_bytes().to_vec())
+}
+
+pub type SharedOptions = Arc<Mutex<SocketOptions>>;
+
+/// A message received by `Socket::recvmsg`
+#[derive(Debug)]
+pub struct RecvInfo {
+    /// Length of the message, larger than the buffer if it is truncated
+    pub len: usize,
+    pub endpoint: Endpoint,
+    /// Interface index and local address of the message, for IP_PKTINFO
+    pub pktinfo: Option<(usize, IpAddress)>,
+    pub ancillary: Ancillary,
+}
+
+impl RecvInfo {
+    pub fn new(len: usize, endpoint: Endpoint) -> Self {
+        RecvInfo {
+            len,
+            endpoint,
+            pktinfo: None,
+            ancillary: Ancillary::default(),
+        }
+    }
+}
+
+/// Common methods that a socket must have.
+///
+/// None of them blocks: they fail with EAGAIN if they would block,
+/// and blocking calls retry them on activity, see `retry_on_activity`.
 pub trait Socket: Send + Sync + Debug {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint);
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult;
+    /// Receive a message with `MSG_*` flags, the default ignores them.
+    /// `MSG_DONTWAIT` and `MSG_WAITALL` are handled by the caller.
+    fn recvmsg(&self, data: &mut [u8], _flags: MsgFlags) -> Result<RecvInfo, SysError> {
+        let (result, endpoint) = self.read(data);
+        Ok(RecvInfo::new(result?, endpoint))
+    }
+    /// Receive more of a stream for `MSG_WAITALL`, 0 if nothing more belongs
+    /// to the message. The default receives nothing more, as for datagrams.
+    fn recv_more(&self, _data: &mut [u8]) -> SysResult {
+        Ok(0)
+    }
+    /// Send a message with `MSG_*` flags and ancillary data,
+    /// the default ignores the flags and takes no ancillary data
+    fn sendmsg(
+        &self,
+        data: &[u8],
+        sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+        _flags: MsgFlags,
+    ) -> SysResult {
+        if !ancillary.is_empty() {
+            return Err(SysError::EINVAL);
+        }
+        self.write(data, sendto_endpoint)
+    }
+    /// Control messages to be received along with messages
+    fn cmsg_options(&self) -> CmsgOptions {
+        CmsgOptions::default()
+    }
     fn poll(&self) -> (bool, bool, bool); // (in, out, err)
+    /// Connect to `endpoint`, stream sockets fail with EINPROGRESS
+    /// until `poll` reports them writable
     fn connect(&mut self, endpoint: Endpoint) -> SysResult;
     fn bind(&mut self, _endpoint: Endpoint) -> SysResult {
         Err(SysError::EINVAL)
     }
-    fn listen(&mut self) -> SysResult {
+    /// Listen with up to `backlog` pending connections
+    fn listen(&mut self, _backlog: usize) -> SysResult {
         Err(SysError::EINVAL)
     }
     fn shutdown(&self) -> SysResult {
@@ -74,14 +393,31 @@ pub trait Socket: Send + Sync + Debug {
     fn remote_endpoint(&self) -> Option<Endpoint> {
         None
     }
-    fn setsockopt(&mut self, _level: usize, _opt: usize, _data: &[u8]) -> SysResult {
-        warn!("setsockopt is unimplemented");
+    fn setsockopt(&mut self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        if !self.options().lock().set(level, opt, data)? {
+            warn!("setsockopt is unimplemented");
+        }
         Ok(0)
     }
+    /// Get the value of an option, the default only has the options kept in `options`
+    fn getsockopt(&self, level: usize, opt: usize) -> Result<Vec<u8>, SysError> {
+        self.options()
+            .lock()
+            .get(level, opt)
+            .ok_or(SysError::ENOPROTOOPT)
+    }
     fn ioctl(&mut self, _request: usize, _arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
         warn!("ioctl is unimplemented for this socket");
         Ok(0)
     }
+    /// Attach a socket filter to what the socket receives, or detach it with `None`
+    fn attach_filter(&mut self, _filter: Option<SocketFilter>) -> SysResult {
+        Err(SysError::ENOPROTOOPT)
+    }
+    fn options(&self) -> &SharedOptions;
+    /// FD_CLOEXEC of the fd of the socket
+    fn fd_cloexec(&self) -> bool;
+    fn set_fd_cloexec(&mut self, cloexec: bool);
     fn box_clone(&self) -> Box<dyn Socket>;
 }
 
@@ -104,29 +440,62 @@ lazy_static! {
 pub struct TcpSocketState {
     handle: GlobalSocketHandle,
     local_endpoint: Option<IpEndpoint>, // save local endpoint for bind()
-    is_listening: bool,
+    /// Listening sockets wait for connections in this instead of `handle`
+    backlog: Option<Backlog>,
+    ipv6: bool,
+    v6only: bool,
+    cmsg: CmsgOptions,
+    options: SharedOptions,
+    /// FD_CLOEXEC, every fd has its own state
+    fd_cloexec: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct UdpSocketState {
     handle: GlobalSocketHandle,
     remote_endpoint: Option<IpEndpoint>, // remember remote endpoint for connect()
+    ipv6: bool,
+    v6only: bool,
+    cmsg: CmsgOptions,
+    peeked: PeekedDatagram,
+    options: SharedOptions,
+    /// FD_CLOEXEC, every fd has its own state
+    fd_cloexec: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct RawSocketState {
     handle: GlobalSocketHandle,
     header_included: bool,
+    cmsg: CmsgOptions,
+    peeked: PeekedDatagram,
+    options: SharedOptions,
+    /// FD_CLOEXEC, every fd has its own state
+    fd_cloexec: bool,
 }
 
+/// smoltcp sockets listening on the same port, one for each pending connection.
+/// Shared by the fds of a listening socket.
+type Backlog = Arc<Mutex<Vec<GlobalSocketHandle>>>;
+
+/// A datagram received with MSG_PEEK, to be received again
+type PeekedDatagram = Arc<Mutex<Option<(Vec<u8>, IpEndpoint)>>>;
+
 #[derive(Debug, Clone)]
 pub struct PacketSocketState {
-    // no state, only ethernet egress
+    /// Frames received and sent by interfaces
+    capture: Arc<CaptureQueue>,
+    options: SharedOptions,
+    /// FD_CLOEXEC, every fd has its own state
+    fd_cloexec: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct NetlinkSocketState {
     data: Arc<Mutex<Vec<Vec<u8>>>>,
+    options: SharedOptions,
+    /// FD_CLOEXEC, every fd has its own state
+    fd_cloexec: bool,
 }
 
 /// A wrapper for `SocketHandle`.
@@ -153,48 +522,218 @@ impl Drop for GlobalSocketHandle {
     }
 }
 
+/// Check that a socket of the given family can talk to `addr`.
+/// IPv6 sockets reach IPv4 peers via v4-mapped addresses unless `IPV6_V6ONLY` is set.
+fn check_family(ipv6: bool, v6only: bool, addr: IpAddress) -> Result<(), SysError> {
+    match addr {
+        IpAddress::Ipv6(_) if !ipv6 => Err(SysError::EAFNOSUPPORT),
+        IpAddress::Ipv4(_) if ipv6 && v6only => Err(SysError::ENETUNREACH),
+        _ => Ok(()),
+    }
+}
+
+/// Local address to bind to, the wildcard of IPv6 sockets also covers IPv4
+fn bind_endpoint(ipv6: bool, endpoint: IpEndpoint) -> IpEndpoint {
+    if ipv6 && endpoint.addr.is_unspecified() {
+        IpEndpoint::new(IpAddress::Unspecified, endpoint.port)
+    } else {
+        endpoint
+    }
+}
+
+/// Endpoint as seen by the user, IPv6 sockets see IPv4 peers as v4-mapped addresses
+fn user_endpoint(ipv6: bool, endpoint: IpEndpoint) -> Endpoint {
+    if !ipv6 {
+        return Endpoint::Ip(endpoint);
+    }
+    let addr = match endpoint.addr {
+        IpAddress::Ipv4(addr) => {
+            let mut bytes = [0u8; 16];
+            bytes[10] = 0xff;
+            bytes[11] = 0xff;
+            bytes[12..].copy_from_slice(addr.as_bytes());
+            IpAddress::Ipv6(Ipv6Address(bytes))
+        }
+        IpAddress::Ipv6(addr) => IpAddress::Ipv6(addr),
+        _ => IpAddress::Ipv6(Ipv6Address::UNSPECIFIED),
+    };
+    Endpoint::Ip(IpEndpoint::new(addr, endpoint.port))
+}
+
+/// Parse `IPV6_V6ONLY` for sockets of the given family
+fn parse_v6only(ipv6: bool, data: &[u8]) -> Result<bool, SysError> {
+    if !ipv6 {
+        return Err(SysError::ENOPROTOOPT);
+    }
+    if data.len() < size_of::<i32>() {
+        return Err(SysError::EINVAL);
+    }
+    Ok(i32::from_ne_bytes([data[0], data[1], data[2], data[3]]) != 0)
+}
+
+/// Parse SO_RCVBUF or SO_SNDBUF, doubled as Linux does for its bookkeeping
+fn parse_buffer_size(data: &[u8]) -> Result<usize, SysError> {
+    let size = parse_int(data)?.max(0) as usize;
+    Ok((size * 2).max(MIN_SOCKBUF).min(MAX_SOCKBUF))
+}
+
+fn to_smoltcp_duration(duration: Duration) -> smoltcp::time::Duration {
+    smoltcp::time::Duration::from_millis(duration.as_millis() as u64)
+}
+
+/// Create a smoltcp TCP socket with the buffer sizes of `options`
+fn new_tcp_socket(options: &SocketOptions) -> TcpSocket<'static> {
+    let rx_buffer = TcpSocketBuffer::new(vec![0; options.recv_buf.unwrap_or(TCP_RECVBUF)]);
+    let tx_buffer = TcpSocketBuffer::new(vec![0; options.send_buf.unwrap_or(TCP_SENDBUF)]);
+    TcpSocket::new(rx_buffer, tx_buffer)
+}
+
+/// Create a smoltcp TCP socket listening on `endpoint`
+fn new_listener(
+    endpoint: IpEndpoint,
+    options: &SocketOptions,
+) -> Result<TcpSocket<'static>, SysError> {
+    let mut socket = new_tcp_socket(options);
+    socket.listen(endpoint).map_err(|_| SysError::EINVAL)?;
+    configure_tcp(&mut socket, options);
+    Ok(socket)
+}
+
+/// A connection of a listener is ready to be accepted once established.
+/// The peer might have closed its side already.
+fn is_established(socket: &TcpSocket) -> bool {
+    match socket.state() {
+        TcpState::Established | TcpState::CloseWait => true,
+        _ => false,
+    }
+}
+
+/// Apply the TTL, keep-alive and timeout options to a smoltcp TCP socket.
+/// smoltcp resets them on connect and listen.
+fn configure_tcp(socket: &mut TcpSocket, options: &SocketOptions) {
+    socket.set_hop_limit(options.ttl);
+    // smoltcp probes after every interval of idleness,
+    // and drops the connection once nothing is received for the timeout
+    let keepalive_timeout = if options.keepalive {
+        socket.set_keep_alive(Some(to_smoltcp_duration(options.keep_idle)));
+        Some(options.keep_idle + options.keep_interval * options.keep_count)
+    } else {
+        socket.set_keep_alive(None);
+        None
+    };
+    socket.set_timeout(
+        options
+            .user_timeout
+            .or(keepalive_timeout)
+            .map(to_smoltcp_duration),
+    );
+}
+
 impl TcpSocketState {
-    pub fn new() -> Self {
-        let rx_buffer = TcpSocketBuffer::new(vec![0; TCP_RECVBUF]);
-        let tx_buffer = TcpSocketBuffer::new(vec![0; TCP_SENDBUF]);
-        let socket = TcpSocket::new(rx_buffer, tx_buffer);
+    pub fn new(ipv6: bool) -> Self {
+        let socket = new_tcp_socket(&SocketOptions::default());
         let handle = GlobalSocketHandle(SOCKETS.lock().add(socket));
 
         TcpSocketState {
             handle,
             local_endpoint: None,
-            is_listening: false,
+            backlog: None,
+            ipv6,
+            v6only: false,
+            cmsg: CmsgOptions::default(),
+            options: SharedOptions::default(),
+            fd_cloexec: false,
+        }
+    }
+
+    /// Apply the options to the smoltcp socket, or to the backlog of a listening one
+    fn configure(&self) {
+        let options = *self.options.lock();
+        let backlog = self.backlog.as_ref().map(|backlog| backlog.lock());
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+        configure_tcp(&mut socket, &options);
+        drop(socket);
+        for handle in backlog.iter().flat_map(|backlog| backlog.iter()) {
+            let mut socket = sockets.get::<TcpSocket>(handle.0);
+            configure_tcp(&mut socket, &options);
+        }
+    }
+
+    /// Recreate the smoltcp socket with the buffer sizes of the options.
+    /// Connections keep their buffers, as smoltcp cannot resize them,
+    /// and listening sockets use them for the listeners created from now on.
+    fn resize_buffers(&mut self) {
+        if self.backlog.is_some() {
+            return;
+        }
+        let options = *self.options.lock();
+        let mut sockets = SOCKETS.lock();
+        if sockets.get::<TcpSocket>(self.handle.0).state() != TcpState::Closed {
+            return;
+        }
+        let socket = new_tcp_socket(&options);
+        let new_handle = GlobalSocketHandle(sockets.add(socket));
+        let old_handle = ::core::mem::replace(&mut self.handle, new_handle);
+
+        // avoid deadlock
+        drop(sockets);
+        drop(old_handle);
+    }
+}
+
+impl Drop for TcpSocketState {
+    fn drop(&mut self) {
+        // the last fd of the socket is closed with a zero linger time
+        if Arc::strong_count(&self.options) == 1 && self.options.lock().linger == Some(0) {
+            let mut sockets = SOCKETS.lock();
+            sockets.get::<TcpSocket>(self.handle.0).abort();
         }
     }
 }
 
 impl Socket for TcpSocketState {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
-        spin_and_wait(&[&SOCKET_ACTIVITY], move || {
-            poll_ifaces();
-            let mut sockets = SOCKETS.lock();
-            let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+        match self.recvmsg(data, MsgFlags::empty()) {
+            Ok(info) => (Ok(info.len), info.endpoint),
+            Err(err) => (Err(err), Endpoint::Ip(IpEndpoint::UNSPECIFIED)),
+        }
+    }
 
-            if socket.may_recv() {
-                if let Ok(size) = socket.recv_slice(data) {
-                    if size > 0 {
-                        let endpoint = socket.remote_endpoint();
-                        // avoid deadlock
-                        drop(socket);
-                        drop(sockets);
+    fn recvmsg(&self, data: &mut [u8], flags: MsgFlags) -> Result<RecvInfo, SysError> {
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+        let endpoint = user_endpoint(self.ipv6, socket.remote_endpoint());
+        if data.is_empty() {
+            return Ok(RecvInfo::new(0, endpoint));
+        }
+        let len = match socket.state() {
+            // not connected yet
+            TcpState::SynSent | TcpState::SynReceived => return Err(SysError::EAGAIN),
+            _ if !socket.may_recv() => return Err(SysError::ENOTCONN),
+            _ if flags.contains(MsgFlags::PEEK) => socket.peek_slice(data),
+            _ => socket.recv_slice(data),
+        };
+        match len {
+            Ok(0) | Err(_) => Err(SysError::EAGAIN),
+            Ok(len) => {
+                // avoid deadlock
+                drop(socket);
+                drop(sockets);
 
-                        poll_ifaces();
-                        return Some((Ok(size), Endpoint::Ip(endpoint)));
-                    }
-                }
-            } else {
-                return Some((
-                    Err(SysError::ENOTCONN),
-                    Endpoint::Ip(IpEndpoint::UNSPECIFIED),
-                ));
+                poll_ifaces();
+                Ok(RecvInfo::new(len, endpoint))
             }
-            None
-        })
+        }
+    }
+
+    fn recv_more(&self, data: &mut [u8]) -> SysResult {
+        match self.recvmsg(data, MsgFlags::empty()) {
+            Ok(info) => Ok(info.len),
+            // the end of the stream
+            Err(SysError::ENOTCONN) => Ok(0),
+            Err(err) => Err(err),
+        }
     }
 
     fn write(&self, data: &[u8], _sendto_endpoint: Option<Endpoint>) -> SysResult {
@@ -214,8 +753,11 @@ impl Socket for TcpSocketState {
                     }
                     Err(_) => Err(SysError::ENOBUFS),
                 }
+            } else if socket.may_send() || socket.state() == TcpState::SynSent {
+                // the send buffer is full, or not connected yet
+                Err(SysError::EAGAIN)
             } else {
-                Err(SysError::ENOBUFS)
+                Err(SysError::EPIPE)
             }
         } else {
             Err(SysError::ENOTCONN)
@@ -227,9 +769,15 @@ impl Socket for TcpSocketState {
         let socket = sockets.get::<TcpSocket>(self.handle.0);
 
         let (mut input, mut output, mut err) = (false, false, false);
-        if self.is_listening && socket.is_active() {
+        if let Some(backlog) = &self.backlog {
             // a new connection
-            input = true;
+            drop(socket);
+            drop(sockets);
+            let backlog = backlog.lock();
+            let mut sockets = SOCKETS.lock();
+            input = backlog
+                .iter()
+                .any(|handle| is_established(&sockets.get::<TcpSocket>(handle.0)));
         } else if !socket.is_open() {
             err = true;
         } else {
@@ -248,35 +796,29 @@ impl Socket for TcpSocketState {
         let mut socket = sockets.get::<TcpSocket>(self.handle.0);
 
         if let Endpoint::Ip(ip) = endpoint {
+            check_family(self.ipv6, self.v6only, ip.addr)?;
+            match socket.state() {
+                TcpState::Closed => {}
+                TcpState::SynSent | TcpState::SynReceived => return Err(SysError::EALREADY),
+                TcpState::Listen => return Err(SysError::EINVAL),
+                _ => return Err(SysError::EISCONN),
+            }
             let temp_port = get_ephemeral_port();
 
             match socket.connect(ip, temp_port) {
                 Ok(()) => {
+                    let mut options = self.options.lock();
+                    configure_tcp(&mut socket, &options);
+                    options.connecting = true;
+                    drop(options);
+
                     // avoid deadlock
                     drop(socket);
                     drop(sockets);
 
-                    // wait for connection result
-                    loop {
-                        poll_ifaces();
-
-                        let mut sockets = SOCKETS.lock();
-                        let socket = sockets.get::<TcpSocket>(self.handle.0);
-                        match socket.state() {
-                            TcpState::SynSent => {
-                                // still connecting
-                                drop(socket);
-                                debug!("poll for connection wait");
-                                SOCKET_ACTIVITY.wait(sockets);
-                            }
-                            TcpState::Established => {
-                                break Ok(0);
-                            }
-                            _ => {
-                                break Err(SysError::ECONNREFUSED);
-                            }
-                        }
-                    }
+                    // send SYN now, the result is seen from poll
+                    poll_ifaces();
+                    Err(SysError::EINPROGRESS)
                 }
                 Err(_) => Err(SysError::ENOBUFS),
             }
@@ -286,38 +828,45 @@ impl Socket for TcpSocketState {
     }
 
     fn bind(&mut self, endpoint: Endpoint) -> SysResult {
-        if let Endpoint::Ip(mut ip) = endpoint {
+        if self.backlog.is_some() {
+            return Err(SysError::EINVAL);
+        }
+        if let Endpoint::Ip(ip) = endpoint {
+            check_family(self.ipv6, self.v6only, ip.addr)?;
+            let mut ip = bind_endpoint(self.ipv6, ip);
             if ip.port == 0 {
                 ip.port = get_ephemeral_port();
             }
             self.local_endpoint = Some(ip);
-            self.is_listening = false;
             Ok(0)
         } else {
             Err(SysError::EINVAL)
         }
     }
 
-    fn listen(&mut self) -> SysResult {
-        if self.is_listening {
+    fn listen(&mut self, backlog: usize) -> SysResult {
+        if self.backlog.is_some() {
             // it is ok to listen twice
             return Ok(0);
         }
         let local_endpoint = self.local_endpoint.ok_or(SysError::EINVAL)?;
+        let options = *self.options.lock();
         let mut sockets = SOCKETS.lock();
-        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
-
-        info!("socket listening on {:?}", local_endpoint);
-        if socket.is_listening() {
-            return Ok(0);
+        if sockets.get::<TcpSocket>(self.handle.0).state() != TcpState::Closed {
+            return Err(SysError::EINVAL);
         }
-        match socket.listen(local_endpoint) {
-            Ok(()) => {
-                self.is_listening = true;
-                Ok(0)
-            }
-            Err(_) => Err(SysError::EINVAL),
+
+        info!(
+            "socket listening on {:?}, backlog {}",
+            local_endpoint, backlog
+        );
+        let mut listeners = Vec::new();
+        for _ in 0..backlog.max(1).min(MAX_BACKLOG) {
+            let listener = new_listener(local_endpoint, &options)?;
+            listeners.push(GlobalSocketHandle(sockets.add(listener)));
         }
+        self.backlog = Some(Arc::new(Mutex::new(listeners)));
+        Ok(0)
     }
 
     fn shutdown(&self) -> SysResult {
@@ -329,49 +878,72 @@ impl Socket for TcpSocketState {
 
     fn accept(&mut self) -> Result<(Box<dyn Socket>, Endpoint), SysError> {
         let endpoint = self.local_endpoint.ok_or(SysError::EINVAL)?;
-        loop {
+        let backlog = self.backlog.as_ref().ok_or(SysError::EINVAL)?;
+        let options = *self.options.lock();
+        let ready = |backlog: &[GlobalSocketHandle]| {
             let mut sockets = SOCKETS.lock();
-            let socket = sockets.get::<TcpSocket>(self.handle.0);
-
-            if socket.is_active() {
-                let remote_endpoint = socket.remote_endpoint();
-                drop(socket);
-
-                let new_socket = {
-                    let rx_buffer = TcpSocketBuffer::new(vec![0; TCP_RECVBUF]);
-                    let tx_buffer = TcpSocketBuffer::new(vec![0; TCP_SENDBUF]);
-                    let mut socket = TcpSocket::new(rx_buffer, tx_buffer);
-                    socket.listen(endpoint).unwrap();
-                    let new_handle = GlobalSocketHandle(sockets.add(socket));
-                    let old_handle = ::core::mem::replace(&mut self.handle, new_handle);
-
-                    Box::new(TcpSocketState {
-                        handle: old_handle,
-                        local_endpoint: self.local_endpoint,
-                        is_listening: false,
-                    })
-                };
-
-                drop(sockets);
-                poll_ifaces();
-                return Ok((new_socket, Endpoint::Ip(remote_endpoint)));
+            backlog
+                .iter()
+                .position(|handle| is_established(&sockets.get::<TcpSocket>(handle.0)))
+        };
+        // connections from peers of the wrong family are reset and skipped
+        let (handle, remote_endpoint) = loop {
+            if ready(&backlog.lock()[..]).is_none() {
+                return Err(SysError::EAGAIN);
             }
-
+            // another socket listens in place of the connection taken,
+            // create it before taking the locks
+            let listener = new_listener(endpoint, &options)?;
+            let mut backlog = backlog.lock();
+            // the connection may have been taken by another thread meanwhile
+            let index = ready(&backlog[..]).ok_or(SysError::EAGAIN)?;
+            let mut sockets = SOCKETS.lock();
+            let new_handle = GlobalSocketHandle(sockets.add(listener));
+            let handle = ::core::mem::replace(&mut backlog[index], new_handle);
+            drop(backlog);
+
+            let mut socket = sockets.get::<TcpSocket>(handle.0);
+            let remote_endpoint = socket.remote_endpoint();
+            if check_family(self.ipv6, self.v6only, remote_endpoint.addr).is_ok() {
+                break (handle, remote_endpoint);
+            }
+            // e.g. an IPv4 peer of an IPV6_V6ONLY listener, reset it
+            socket.abort();
             drop(socket);
-            SOCKET_ACTIVITY.wait(sockets);
-        }
+            drop(sockets);
+            poll_ifaces();
+            drop(handle);
+        };
+
+        // O_NONBLOCK is not inherited
+        let options = SocketOptions {
+            nonblock: false,
+            ..options
+        };
+        let new_socket = Box::new(TcpSocketState {
+            handle,
+            local_endpoint: self.local_endpoint,
+            backlog: None,
+            ipv6: self.ipv6,
+            v6only: self.v6only,
+            cmsg: self.cmsg,
+            options: Arc::new(Mutex::new(options)),
+            fd_cloexec: false,
+        });
+        poll_ifaces();
+        Ok((new_socket, user_endpoint(self.ipv6, remote_endpoint)))
     }
 
     fn endpoint(&self) -> Option<Endpoint> {
         self.local_endpoint
             .clone()
-            .map(|e| Endpoint::Ip(e))
+            .map(|e| user_endpoint(self.ipv6, e))
             .or_else(|| {
                 let mut sockets = SOCKETS.lock();
                 let socket = sockets.get::<TcpSocket>(self.handle.0);
                 let endpoint = socket.local_endpoint();
                 if endpoint.port != 0 {
-                    Some(Endpoint::Ip(endpoint))
+                    Some(user_endpoint(self.ipv6, endpoint))
                 } else {
                     None
                 }
@@ -382,19 +954,103 @@ impl Socket for TcpSocketState {
         let mut sockets = SOCKETS.lock();
         let socket = sockets.get::<TcpSocket>(self.handle.0);
         if socket.is_open() {
-            Some(Endpoint::Ip(socket.remote_endpoint()))
+            Some(user_endpoint(self.ipv6, socket.remote_endpoint()))
         } else {
             None
         }
     }
 
+    fn setsockopt(&mut self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        match (level, opt) {
+            (IPPROTO_IPV6, IPV6_V6ONLY) => {
+                self.v6only = parse_v6only(self.ipv6, data)?;
+                Ok(0)
+            }
+            (SOL_SOCKET, SO_RCVBUF) | (SOL_SOCKET, SO_SNDBUF) => {
+                let size = parse_buffer_size(data)?;
+                let mut options = self.options.lock();
+                if opt == SO_RCVBUF {
+                    options.recv_buf = Some(size);
+                } else {
+                    options.send_buf = Some(size);
+                }
+                drop(options);
+                self.resize_buffers();
+                Ok(0)
+            }
+            _ => {
+                if self.cmsg.set(level, opt, data) {
+                    return Ok(0);
+                }
+                if self.options.lock().set(level, opt, data)? {
+                    self.configure();
+                } else {
+                    warn!("setsockopt is unimplemented");
+                }
+                Ok(0)
+            }
+        }
+    }
+
+    fn getsockopt(&self, level: usize, opt: usize) -> Result<Vec<u8>, SysError> {
+        match (level, opt) {
+            (IPPROTO_IPV6, IPV6_V6ONLY) if self.ipv6 => int_opt(self.v6only as i32),
+            (SOL_SOCKET, SO_ERROR) => {
+                let state = {
+                    let mut sockets = SOCKETS.lock();
+                    let socket = sockets.get::<TcpSocket>(self.handle.0);
+                    socket.state()
+                };
+                let mut options = self.options.lock();
+                let error = match state {
+                    _ if !options.connecting => 0,
+                    TcpState::SynSent | TcpState::SynReceived => return int_opt(0),
+                    // reset or timed out while connecting
+                    TcpState::Closed => SysError::ECONNREFUSED as i32,
+                    _ => 0,
+                };
+                // reported only once
+                options.connecting = false;
+                int_opt(error)
+            }
+            _ => {
+                let options = self.options.lock();
+                match (level, opt) {
+                    (SOL_SOCKET, SO_RCVBUF) => {
+                        int_opt(options.recv_buf.unwrap_or(TCP_RECVBUF) as i32)
+                    }
+                    (SOL_SOCKET, SO_SNDBUF) => {
+                        int_opt(options.send_buf.unwrap_or(TCP_SENDBUF) as i32)
+                    }
+                    _ => options.get(level, opt).ok_or(SysError::ENOPROTOOPT),
+                }
+            }
+        }
+    }
+
+    fn cmsg_options(&self) -> CmsgOptions {
+        self.cmsg
+    }
+
+    fn options(&self) -> &SharedOptions {
+        &self.options
+    }
+
+    fn fd_cloexec(&self) -> bool {
+        self.fd_cloexec
+    }
+
+    fn set_fd_cloexec(&mut self, cloexec: bool) {
+        self.fd_cloexec = cloexec;
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
 }
 
 impl UdpSocketState {
-    pub fn new() -> Self {
+    pub fn new(ipv6: bool) -> Self {
         let rx_buffer = UdpSocketBuffer::new(
             vec![UdpPacketMetadata::EMPTY; UDP_METADATA_BUF],
             vec![0; UDP_RECVBUF],
@@ -409,6 +1065,12 @@ impl UdpSocketState {
         UdpSocketState {
             handle,
             remote_endpoint: None,
+            ipv6,
+            v6only: false,
+            cmsg: CmsgOptions::default(),
+            peeked: PeekedDatagram::default(),
+            options: SharedOptions::default(),
+            fd_cloexec: false,
         }
     }
 }
@@ -424,29 +1086,50 @@ struct ArpReq {
 
 impl Socket for UdpSocketState {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
-        loop {
-            let mut sockets = SOCKETS.lock();
-            let mut socket = sockets.get::<UdpSocket>(self.handle.0);
-
-            if socket.can_recv() {
-                if let Ok((size, remote_endpoint)) = socket.recv_slice(data) {
-                    let endpoint = remote_endpoint;
-                    // avoid deadlock
-                    drop(socket);
-                    drop(sockets);
+        match self.recvmsg(data, MsgFlags::empty()) {
+            Ok(info) => (Ok(min(info.len, data.len())), info.endpoint),
+            Err(err) => (Err(err), Endpoint::Ip(IpEndpoint::UNSPECIFIED)),
+        }
+    }
 
-                    poll_ifaces();
-                    return (Ok(size), Endpoint::Ip(endpoint));
+    fn recvmsg(&self, data: &mut [u8], flags: MsgFlags) -> Result<RecvInfo, SysError> {
+        let mut peeked = self.peeked.lock();
+        loop {
+            let (payload, remote_endpoint) = match peeked.take() {
+                Some(datagram) => datagram,
+                None => {
+                    let mut sockets = SOCKETS.lock();
+                    let mut socket = sockets.get::<UdpSocket>(self.handle.0);
+                    match socket.recv() {
+                        Ok((payload, remote_endpoint)) => (Vec::from(payload), remote_endpoint),
+                        Err(_) => return Err(SysError::EAGAIN),
+                    }
                 }
-            } else {
-                return (
-                    Err(SysError::ENOTCONN),
-                    Endpoint::Ip(IpEndpoint::UNSPECIFIED),
-                );
+            };
+            if check_family(self.ipv6, self.v6only, remote_endpoint.addr).is_err() {
+                // not for this socket family, drop it
+                continue;
             }
 
-            drop(socket);
-            SOCKET_ACTIVITY.wait(sockets);
+            let len = min(data.len(), payload.len());
+            data[..len].copy_from_slice(&payload[..len]);
+            let mut info = RecvInfo::new(payload.len(), user_endpoint(self.ipv6, remote_endpoint));
+            if self.cmsg.pktinfo {
+                let local_endpoint = {
+                    let mut sockets = SOCKETS.lock();
+                    let socket = sockets.get::<UdpSocket>(self.handle.0);
+                    socket.endpoint()
+                };
+                info.pktinfo = local_address(local_endpoint.addr, remote_endpoint.addr);
+            }
+            if flags.contains(MsgFlags::PEEK) {
+                *peeked = Some((payload, remote_endpoint));
+            } else {
+                // avoid deadlock
+                drop(peeked);
+                poll_ifaces();
+            }
+            return Ok(info);
         }
     }
 
@@ -460,6 +1143,8 @@ impl Socket for UdpSocketState {
                 return Err(SysError::ENOTCONN);
             }
         };
+        check_family(self.ipv6, self.v6only, remote_endpoint.addr)?;
+        let options = *self.options.lock();
         let mut sockets = SOCKETS.lock();
         let mut socket = sockets.get::<UdpSocket>(self.handle.0);
 
@@ -469,30 +1154,37 @@ impl Socket for UdpSocketState {
                 .bind(IpEndpoint::new(IpAddress::Unspecified, temp_port))
                 .unwrap();
         }
+        // smoltcp reads the hop limit when dispatching, right below by poll_ifaces
+        if remote_endpoint.addr.is_multicast() {
+            // smoltcp cannot send with a hop limit of 0
+            socket.set_hop_limit(Some(options.multicast_ttl.max(1)));
+        } else {
+            socket.set_hop_limit(options.ttl);
+        }
 
-        if socket.can_send() {
-            match socket.send_slice(&data, *remote_endpoint) {
-                Ok(()) => {
-                    // avoid deadlock
-                    drop(socket);
-                    drop(sockets);
+        match socket.send_slice(&data, *remote_endpoint) {
+            Ok(()) => {
+                // avoid deadlock
+                drop(socket);
+                drop(sockets);
 
-                    poll_ifaces();
-                    Ok(data.len())
-                }
-                Err(_) => Err(SysError::ENOBUFS),
+                poll_ifaces();
+                Ok(data.len())
             }
-        } else {
-            Err(SysError::ENOBUFS)
+            // the send buffer is full
+            Err(smoltcp::Error::Exhausted) => Err(SysError::EAGAIN),
+            Err(smoltcp::Error::Truncated) => Err(SysError::EMSGSIZE),
+            Err(_) => Err(SysError::ENOBUFS),
         }
     }
 
     fn poll(&self) -> (bool, bool, bool) {
+        let peeked = self.peeked.lock().is_some();
         let mut sockets = SOCKETS.lock();
         let socket = sockets.get::<UdpSocket>(self.handle.0);
 
         let (mut input, mut output, err) = (false, false, false);
-        if socket.can_recv() {
+        if socket.can_recv() || peeked {
             input = true;
         }
         if socket.can_send() {
@@ -503,6 +1195,7 @@ impl Socket for UdpSocketState {
 
     fn connect(&mut self, endpoint: Endpoint) -> SysResult {
         if let Endpoint::Ip(ip) = endpoint {
+            check_family(self.ipv6, self.v6only, ip.addr)?;
             self.remote_endpoint = Some(ip);
             Ok(0)
         } else {
@@ -514,7 +1207,8 @@ impl Socket for UdpSocketState {
         let mut sockets = SOCKETS.lock();
         let mut socket = sockets.get::<UdpSocket>(self.handle.0);
         if let Endpoint::Ip(ip) = endpoint {
-            match socket.bind(ip) {
+            check_family(self.ipv6, self.v6only, ip.addr)?;
+            match socket.bind(bind_endpoint(self.ipv6, ip)) {
                 Ok(()) => Ok(0),
                 Err(_) => Err(SysError::EINVAL),
             }
@@ -556,7 +1250,7 @@ impl Socket for UdpSocketState {
                     Err(SysError::EINVAL)
                 }
             }
-            _ => Ok(0),
+            _ => iface_ioctl(request, arg1),
         }
     }
 
@@ -565,14 +1259,60 @@ impl Socket for UdpSocketState {
         let socket = sockets.get::<UdpSocket>(self.handle.0);
         let endpoint = socket.endpoint();
         if endpoint.port != 0 {
-            Some(Endpoint::Ip(endpoint))
+            Some(user_endpoint(self.ipv6, endpoint))
         } else {
             None
         }
     }
 
     fn remote_endpoint(&self) -> Option<Endpoint> {
-        self.remote_endpoint.clone().map(|e| Endpoint::Ip(e))
+        self.remote_endpoint
+            .clone()
+            .map(|e| user_endpoint(self.ipv6, e))
+    }
+
+    fn setsockopt(&mut self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        match (level, opt) {
+            (IPPROTO_IPV6, IPV6_V6ONLY) => {
+                self.v6only = parse_v6only(self.ipv6, data)?;
+                Ok(0)
+            }
+            _ => {
+                if !self.cmsg.set(level, opt, data) && !self.options.lock().set(level, opt, data)? {
+                    warn!("setsockopt is unimplemented");
+                }
+                Ok(0)
+            }
+        }
+    }
+
+    fn getsockopt(&self, level: usize, opt: usize) -> Result<Vec<u8>, SysError> {
+        match (level, opt) {
+            (IPPROTO_IPV6, IPV6_V6ONLY) if self.ipv6 => int_opt(self.v6only as i32),
+            (SOL_SOCKET, SO_RCVBUF) => int_opt(UDP_RECVBUF as i32),
+            (SOL_SOCKET, SO_SNDBUF) => int_opt(UDP_SENDBUF as i32),
+            _ => self
+                .options
+                .lock()
+                .get(level, opt)
+                .ok_or(SysError::ENOPROTOOPT),
+        }
+    }
+
+    fn cmsg_options(&self) -> CmsgOptions {
+        self.cmsg
+    }
+
+    fn options(&self) -> &SharedOptions {
+        &self.options
+    }
+
+    fn fd_cloexec(&self) -> bool {
+        self.fd_cloexec
+    }
+
+    fn set_fd_cloexec(&mut self, cloexec: bool) {
+        self.fd_cloexec = cloexec;
     }
 
     fn box_clone(&self) -> Box<dyn Socket> {
@@ -581,7 +1321,7 @@ impl Socket for UdpSocketState {
 }
 
 impl RawSocketState {
-    pub fn new(protocol: u8) -> Self {
+    pub fn new(protocol: u8, ipv6: bool) -> Self {
         let rx_buffer = RawSocketBuffer::new(
             vec![RawPacketMetadata::EMPTY; RAW_METADATA_BUF],
             vec![0; RAW_RECVBUF],
@@ -590,42 +1330,66 @@ impl RawSocketState {
             vec![RawPacketMetadata::EMPTY; RAW_METADATA_BUF],
             vec![0; RAW_SENDBUF],
         );
-        let socket = RawSocket::new(
-            IpVersion::Ipv4,
-            IpProtocol::from(protocol),
-            rx_buffer,
-            tx_buffer,
-        );
+        let version = if ipv6 {
+            IpVersion::Ipv6
+        } else {
+            IpVersion::Ipv4
+        };
+        let socket = RawSocket::new(version, IpProtocol::from(protocol), rx_buffer, tx_buffer);
         let handle = GlobalSocketHandle(SOCKETS.lock().add(socket));
 
         RawSocketState {
             handle,
             header_included: false,
+            cmsg: CmsgOptions::default(),
+            peeked: PeekedDatagram::default(),
+            options: SharedOptions::default(),
+            fd_cloexec: false,
         }
     }
 }
 
 impl Socket for RawSocketState {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
-        loop {
-            let mut sockets = SOCKETS.lock();
-            let mut socket = sockets.get::<RawSocket>(self.handle.0);
+        match self.recvmsg(data, MsgFlags::empty()) {
+            Ok(info) => (Ok(min(info.len, data.len())), info.endpoint),
+            Err(err) => (Err(err), Endpoint::Ip(IpEndpoint::UNSPECIFIED)),
+        }
+    }
 
-            if let Ok(size) = socket.recv_slice(data) {
-                let packet = Ipv4Packet::new_unchecked(data);
+    fn recvmsg(&self, data: &mut [u8], flags: MsgFlags) -> Result<RecvInfo, SysError> {
+        let mut peeked = self.peeked.lock();
+        let (packet, endpoint) = match peeked.take() {
+            Some(datagram) => datagram,
+            None => loop {
+                let mut sockets = SOCKETS.lock();
+                let mut socket = sockets.get::<RawSocket>(self.handle.0);
 
-                return (
-                    Ok(size),
-                    Endpoint::Ip(IpEndpoint {
-                        addr: IpAddress::Ipv4(packet.src_addr()),
-                        port: 0,
-                    }),
-                );
-            }
+                if let Ok(packet) = socket.recv() {
+                    if let IpVersion::Ipv6 = socket.ip_version() {
+                        // IPv6 raw sockets never see the IP header
+                        let packet = Ipv6Packet::new_unchecked(packet);
+                        let endpoint = IpEndpoint::new(IpAddress::Ipv6(packet.src_addr()), 0);
+                        break (Vec::from(packet.payload()), endpoint);
+                    }
+                    let src_addr = Ipv4Packet::new_unchecked(packet).src_addr();
+                    let endpoint = IpEndpoint::new(IpAddress::Ipv4(src_addr), 0);
+                    break (Vec::from(packet), endpoint);
+                }
+                return Err(SysError::EAGAIN);
+            },
+        };
 
-            drop(socket);
-            SOCKET_ACTIVITY.wait(sockets);
+        let len = min(data.len(), packet.len());
+        data[..len].copy_from_slice(&packet[..len]);
+        let mut info = RecvInfo::new(packet.len(), Endpoint::Ip(endpoint));
+        if self.cmsg.pktinfo {
+            info.pktinfo = local_address(IpAddress::Unspecified, endpoint.addr);
+        }
+        if flags.contains(MsgFlags::PEEK) {
+            *peeked = Some((packet, endpoint));
         }
+        Ok(info)
     }
 
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
@@ -641,11 +1405,11 @@ impl Socket for RawSocketState {
             if let Some(Endpoint::Ip(endpoint)) = sendto_endpoint {
                 // temporary solution
                 let iface = &*(NET_DRIVERS.read()[0]);
-                let v4_src = iface.ipv4_address().unwrap();
                 let mut sockets = SOCKETS.lock();
                 let mut socket = sockets.get::<RawSocket>(self.handle.0);
 
                 if let IpAddress::Ipv4(v4_dst) = endpoint.addr {
+                    let v4_src = iface.ipv4_address().ok_or(SysError::EADDRNOTAVAIL)?;
                     let len = data.len();
                     // using 20-byte IPv4 header
                     let mut buffer = vec![0u8; len + 20];
@@ -667,9 +1431,51 @@ impl Socket for RawSocketState {
                     drop(sockets);
                     iface.poll();
 
+                    Ok(len)
+                } else if let IpAddress::Ipv6(v6_dst) = endpoint.addr {
+                    // prefer a source address of the same scope
+                    let v6_addrs = iface
+                        .get_ip_addresses()
+                        .into_iter()
+                        .filter_map(|cidr| match cidr.address() {
+                            IpAddress::Ipv6(addr) => Some(addr),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    let v6_src = v6_addrs
+                        .iter()
+                        .find(|addr| addr.is_link_local() == v6_dst.is_link_local())
+                        .or_else(|| v6_addrs.first())
+                        .cloned()
+                        .ok_or(SysError::EADDRNOTAVAIL)?;
+
+                    let len = data.len();
+                    let mut buffer = vec![0u8; len + IPV6_HEADER_LEN];
+                    let mut packet = Ipv6Packet::new_unchecked(&mut buffer);
+                    packet.set_version(6);
+                    packet.set_payload_len(len as u16);
+                    packet.set_next_header(socket.ip_protocol());
+                    packet.set_hop_limit(64);
+                    packet.set_src_addr(v6_src);
+                    packet.set_dst_addr(v6_dst);
+                    let payload = packet.payload_mut();
+                    payload.copy_from_slice(data);
+                    if socket.ip_protocol() == IpProtocol::Icmpv6 {
+                        // the kernel computes ICMPv6 checksums, as in Linux
+                        Icmpv6Packet::new_unchecked(payload)
+                            .fill_checksum(&v6_src.into(), &v6_dst.into());
+                    }
+
+                    socket.send_slice(&buffer).unwrap();
+
+                    // avoid deadlock
+                    drop(socket);
+                    drop(sockets);
+                    iface.poll();
+
                     Ok(len)
                 } else {
-                    unimplemented!("ip type")
+                    Err(SysError::EINVAL)
                 }
             } else {
                 Err(SysError::ENOTCONN)
@@ -678,13 +1484,28 @@ impl Socket for RawSocketState {
     }
 
     fn poll(&self) -> (bool, bool, bool) {
-        unimplemented!()
+        let peeked = self.peeked.lock().is_some();
+        let mut sockets = SOCKETS.lock();
+        let socket = sockets.get::<RawSocket>(self.handle.0);
+        (socket.can_recv() || peeked, socket.can_send(), false)
     }
 
     fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
         unimplemented!()
     }
 
+    fn options(&self) -> &SharedOptions {
+        &self.options
+    }
+
+    fn fd_cloexec(&self) -> bool {
+        self.fd_cloexec
+    }
+
+    fn set_fd_cloexec(&mut self, cloexec: bool) {
+        self.fd_cloexec = cloexec;
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
@@ -697,21 +1518,58 @@ impl Socket for RawSocketState {
                     debug!("hdrincl set to {}", self.header_included);
                 }
             }
-            _ => {}
+            _ => {
+                if !self.cmsg.set(level, opt, data) {
+                    self.options.lock().set(level, opt, data)?;
+                }
+            }
         }
         Ok(0)
     }
+
+    fn cmsg_options(&self) -> CmsgOptions {
+        self.cmsg
+    }
 }
 
 impl PacketSocketState {
-    pub fn new() -> Self {
-        PacketSocketState {}
+    /// Make a packet socket capturing `protocol`, in host byte order
+    pub fn new(protocol: u16) -> Self {
+        PacketSocketState {
+            capture: CaptureQueue::new(protocol),
+            options: SharedOptions::default(),
+            fd_cloexec: false,
+        }
     }
 }
 
 impl Socket for PacketSocketState {
-    fn read(&self, _data: &mut [u8]) -> (SysResult, Endpoint) {
-        unimplemented!()
+    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        match self.recvmsg(data, MsgFlags::empty()) {
+            Ok(info) => (Ok(min(info.len, data.len())), info.endpoint),
+            Err(err) => (Err(err), Endpoint::LinkLevel(LinkLevelEndpoint::new(0))),
+        }
+    }
+
+    fn recvmsg(&self, data: &mut [u8], flags: MsgFlags) -> Result<RecvInfo, SysError> {
+        let frame = if flags.contains(MsgFlags::PEEK) {
+            self.capture.peek()
+        } else {
+            self.capture.pop()
+        };
+        let frame = frame.ok_or(SysError::EAGAIN)?;
+        let len = min(data.len(), frame.data.len());
+        data[..len].copy_from_slice(&frame.data[..len]);
+        // frames are captured without knowing their interface
+        let endpoint = LinkLevelEndpoint {
+            interface_index: 0,
+            protocol: frame.protocol,
+            packet_type: frame.packet_type,
+        };
+        Ok(RecvInfo::new(
+            frame.data.len(),
+            Endpoint::LinkLevel(endpoint),
+        ))
     }
 
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
@@ -727,13 +1585,34 @@ impl Socket for PacketSocketState {
     }
 
     fn poll(&self) -> (bool, bool, bool) {
-        unimplemented!()
+        // sent at once
+        (!self.capture.is_empty(), true, false)
     }
 
     fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
         unimplemented!()
     }
 
+    fn attach_filter(&mut self, filter: Option<SocketFilter>) -> SysResult {
+        let detach = filter.is_none();
+        match self.capture.set_filter(filter) {
+            None if detach => Err(SysError::ENOENT),
+            _ => Ok(0),
+        }
+    }
+
+    fn options(&self) -> &SharedOptions {
+        &self.options
+    }
+
+    fn fd_cloexec(&self) -> bool {
+        self.fd_cloexec
+    }
+
+    fn set_fd_cloexec(&mut self, cloexec: bool) {
+        self.fd_cloexec = cloexec;
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
@@ -773,6 +1652,20 @@ struct IfaceAddrMsg {
     ifa_index: u32,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct RouteMsg {
+    rtm_family: u8,
+    rtm_dst_len: u8,
+    rtm_src_len: u8,
+    rtm_tos: u8,
+    rtm_table: u8,
+    rtm_protocol: u8,
+    rtm_scope: u8,
+    rtm_type: u8,
+    rtm_flags: u32,
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 struct RouteAttr {
@@ -831,6 +1724,12 @@ enum_with_unknown! {
         DelAddr = 21,
         /// Get addr
         GetAddr = 22,
+        /// New route
+        NewRoute = 24,
+        /// Delete route
+        DelRoute = 25,
+        /// Get route
+        GetRoute = 26,
     }
 }
 
@@ -852,10 +1751,61 @@ enum_with_unknown! {
     }
 }
 
+enum_with_unknown! {
+    /// Attr types of address messages
+    pub doc enum AddrAttrTypes(u16) {
+        /// Unspecified
+        Unspecified = 0,
+        /// Peer address, the same as local one if not point-to-point
+        Address = 1,
+        /// Local address
+        Local = 2,
+        /// Interface name
+        Label = 3,
+        /// Broadcast
+        Broadcast = 4,
+    }
+}
+
+enum_with_unknown! {
+    /// Attr types of route messages
+    pub doc enum RouteMsgAttrTypes(u16) {
+        /// Unspecified
+        Unspecified = 0,
+        /// Destination
+        Dst = 1,
+        /// Source
+        Src = 2,
+        /// Input interface
+        Iif = 3,
+        /// Output interface
+        Oif = 4,
+        /// Gateway
+        Gateway = 5,
+        /// Priority
+        Priority = 6,
+        /// Preferred source address
+        PrefSrc = 7,
+        /// Routing table
+        Table = 15,
+    }
+}
+
+// values in RouteMsg
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_KERNEL: u8 = 2;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_HOST: u8 = 254;
+const RTN_UNICAST: u8 = 1;
+
 impl NetlinkSocketState {
     pub fn new() -> Self {
         NetlinkSocketState {
             data: Arc::new(Mutex::new(Vec::new())),
+            options: SharedOptions::default(),
+            fd_cloexec: false,
         }
     }
 }
@@ -894,6 +1844,232 @@ impl VecExt for Vec<u8> {
     }
 }
 
+/// Read a `T` at `offset` of a netlink message
+fn read_msg<T: Copy>(data: &[u8], offset: usize) -> Result<T, SysError> {
+    if data.len() < offset + size_of::<T>() {
+        return Err(SysError::EINVAL);
+    }
+    Ok(unsafe { (data[offset..].as_ptr() as *const T).read_unaligned() })
+}
+
+/// Split route attributes into (type, payload)
+fn parse_attrs(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while let Ok(attr) = read_msg::<RouteAttr>(data, 0) {
+        let len = attr.rta_len as usize;
+        if len < size_of::<RouteAttr>() || len > data.len() {
+            break;
+        }
+        attrs.push((attr.rta_type, &data[size_of::<RouteAttr>()..len]));
+        data = &data[min((len + 3) & !3, data.len())..];
+    }
+    attrs
+}
+
+fn push_attr(attrs: &mut Vec<u8>, rta_type: u16, payload: &[u8]) {
+    let attr = RouteAttr {
+        rta_len: (payload.len() + size_of::<RouteAttr>()) as u16,
+        rta_type,
+    };
+    attrs.align4();
+    attrs.push_ext(attr);
+    attrs.extend_from_slice(payload);
+}
+
+/// Parse an address of `family`
+fn parse_ip(family: AddressFamily, payload: &[u8]) -> Result<IpAddress, SysError> {
+    match (family, payload.len()) {
+        (AddressFamily::Internet, 4) => Ok(Ipv4Address::from_bytes(payload).into()),
+        (AddressFamily::Internet6, 16) => Ok(Ipv6Address::from_bytes(payload).into()),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+/// Maximum prefix length of `family`
+fn max_prefix_len(family: AddressFamily) -> Result<u8, SysError> {
+    match family {
+        AddressFamily::Internet => Ok(32),
+        AddressFamily::Internet6 => Ok(128),
+        _ => Err(SysError::EAFNOSUPPORT),
+    }
+}
+
+fn ip_family(addr: IpAddress) -> AddressFamily {
+    match addr {
+        IpAddress::Ipv4(_) => AddressFamily::Internet,
+        IpAddress::Ipv6(_) => AddressFamily::Internet6,
+        _ => AddressFamily::Unspecified,
+    }
+}
+
+/// Handle NewAddr and DelAddr
+fn netlink_set_addr(message_type: NetlinkMessageType, data: &[u8]) -> SysResult {
+    let offset = size_of::<NetlinkMessageHeader>();
+    let if_addr = read_msg::<IfaceAddrMsg>(data, offset)?;
+    let family = AddressFamily::from(if_addr.ifa_family as u16);
+    if if_addr.ifa_prefixlen > max_prefix_len(family)? {
+        return Err(SysError::EINVAL);
+    }
+    let mut addr = None;
+    for (attr_type, payload) in parse_attrs(&data[offset + size_of::<IfaceAddrMsg>()..]) {
+        match AddrAttrTypes::from(attr_type) {
+            AddrAttrTypes::Local => addr = Some(parse_ip(family, payload)?),
+            AddrAttrTypes::Address if addr.is_none() => addr = Some(parse_ip(family, payload)?),
+            _ => {}
+        }
+    }
+    let addr = addr.ok_or(SysError::EINVAL)?;
+    let index = if_addr.ifa_index as usize;
+    if message_type == NetlinkMessageType::NewAddr {
+        add_address(index, IpCidr::new(addr, if_addr.ifa_prefixlen))
+    } else {
+        del_address(index, addr)
+    }
+}
+
+/// Handle NewRoute and DelRoute
+fn netlink_set_route(message_type: NetlinkMessageType, data: &[u8]) -> SysResult {
+    let offset = size_of::<NetlinkMessageHeader>();
+    let route = read_msg::<RouteMsg>(data, offset)?;
+    let family = AddressFamily::from(route.rtm_family as u16);
+    if route.rtm_dst_len > max_prefix_len(family)? {
+        return Err(SysError::EINVAL);
+    }
+    let mut dst = match family {
+        AddressFamily::Internet => Ipv4Address::UNSPECIFIED.into(),
+        _ => Ipv6Address::UNSPECIFIED.into(),
+    };
+    let mut gateway = None;
+    let mut index = None;
+    for (attr_type, payload) in parse_attrs(&data[offset + size_of::<RouteMsg>()..]) {
+        match RouteMsgAttrTypes::from(attr_type) {
+            RouteMsgAttrTypes::Dst => dst = parse_ip(family, payload)?,
+            RouteMsgAttrTypes::Gateway => gateway = Some(parse_ip(family, payload)?),
+            RouteMsgAttrTypes::Oif => index = Some(read_msg::<u32>(payload, 0)? as usize),
+            _ => {}
+        }
+    }
+    let dst = IpCidr::new(dst, route.rtm_dst_len);
+    if message_type == NetlinkMessageType::NewRoute {
+        add_route(index, dst, gateway)
+    } else {
+        del_route(index, dst)
+    }
+}
+
+/// Scope of an address in NewAddr messages
+fn addr_scope(addr: IpAddress) -> u8 {
+    match addr {
+        IpAddress::Ipv4(addr) if addr.is_loopback() => RT_SCOPE_HOST,
+        IpAddress::Ipv6(addr) if addr.is_loopback() => RT_SCOPE_HOST,
+        IpAddress::Ipv6(addr) if addr.is_link_local() => RT_SCOPE_LINK,
+        _ => RT_SCOPE_UNIVERSE,
+    }
+}
+
+/// Build a NewRoute message for a route dump
+fn route_message(
+    header: &NetlinkMessageHeader,
+    index: usize,
+    dst: IpCidr,
+    gateway: Option<IpAddress>,
+    src: Option<IpAddress>,
+) -> Vec<u8> {
+    let mut msg = Vec::new();
+    let new_header = NetlinkMessageHeader {
+        nlmsg_len: 0, // to be determined later
+        nlmsg_type: NetlinkMessageType::NewRoute.into(),
+        nlmsg_flags: NetlinkMessageFlags::MULTI,
+        nlmsg_seq: header.nlmsg_seq,
+        nlmsg_pid: header.nlmsg_pid,
+    };
+    msg.push_ext(new_header);
+
+    let family: u16 = ip_family(dst.address()).into();
+    let route = RouteMsg {
+        rtm_family: family as u8,
+        rtm_dst_len: dst.prefix_len(),
+        rtm_src_len: 0,
+        rtm_tos: 0,
+        rtm_table: RT_TABLE_MAIN,
+        // routes without gateway come from addresses
+        rtm_protocol: if gateway.is_some() {
+            RTPROT_BOOT
+        } else {
+            RTPROT_KERNEL
+        },
+        rtm_scope: if gateway.is_some() {
+            RT_SCOPE_UNIVERSE
+        } else {
+            RT_SCOPE_LINK
+        },
+        rtm_type: RTN_UNICAST,
+        rtm_flags: 0,
+    };
+    msg.align4();
+    msg.push_ext(route);
+
+    let mut attrs = Vec::new();
+    if dst.prefix_len() > 0 {
+        push_attr(
+            &mut attrs,
+            RouteMsgAttrTypes::Dst.into(),
+            dst.address().as_bytes(),
+        );
+    }
+    if let Some(gateway) = gateway {
+        push_attr(
+            &mut attrs,
+            RouteMsgAttrTypes::Gateway.into(),
+            gateway.as_bytes(),
+        );
+    }
+    if let Some(src) = src {
+        push_attr(
+            &mut attrs,
+            RouteMsgAttrTypes::PrefSrc.into(),
+            src.as_bytes(),
+        );
+    }
+    push_attr(
+        &mut attrs,
+        RouteMsgAttrTypes::Oif.into(),
+        &(index as u32).to_ne_bytes(),
+    );
+
+    msg.align4();
+    msg.append(&mut attrs);
+
+    msg.align4();
+    msg.set_ext(0, msg.len() as u32);
+    msg
+}
+
+/// Reply an Error message, which is an ACK if `result` is Ok.
+/// ACK is only sent on request.
+fn push_ack(buffer: &mut Vec<Vec<u8>>, header: &NetlinkMessageHeader, result: SysResult) {
+    let error = match result {
+        Ok(_) if !header.nlmsg_flags.contains(NetlinkMessageFlags::ACK) => return,
+        Ok(_) => 0,
+        Err(err) => -(err as i32),
+    };
+    let mut msg = Vec::new();
+    let new_header = NetlinkMessageHeader {
+        nlmsg_len: 0, // to be determined later
+        nlmsg_type: NetlinkMessageType::Error.into(),
+        // only the header of the request is returned
+        nlmsg_flags: NetlinkMessageFlags::CAPPED,
+        nlmsg_seq: header.nlmsg_seq,
+        nlmsg_pid: header.nlmsg_pid,
+    };
+    msg.push_ext(new_header);
+    msg.push_ext(error);
+    msg.push_ext(*header);
+    msg.align4();
+    msg.set_ext(0, msg.len() as u32);
+    buffer.push(msg);
+}
+
 impl Socket for NetlinkSocketState {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
         let mut buffer = self.data.lock();
@@ -927,11 +2103,34 @@ impl Socket for NetlinkSocketState {
         if header.nlmsg_len as usize > data.len() {
             return Err(SysError::EINVAL);
         }
+        let message = &data[..header.nlmsg_len as usize];
         let message_type = NetlinkMessageType::from(header.nlmsg_type);
         debug!("type: {:?}", message_type);
         let mut buffer = self.data.lock();
         buffer.clear();
         match message_type {
+            NetlinkMessageType::NewAddr | NetlinkMessageType::DelAddr => {
+                let result = netlink_set_addr(message_type, message);
+                push_ack(&mut buffer, header, result);
+                return Ok(data.len());
+            }
+            NetlinkMessageType::NewRoute | NetlinkMessageType::DelRoute => {
+                let result = netlink_set_route(message_type, message);
+                push_ack(&mut buffer, header, result);
+                return Ok(data.len());
+            }
+            NetlinkMessageType::GetRoute => {
+                let ifaces = NET_DRIVERS.read();
+                for i in 0..ifaces.len() {
+                    for cidr in ifaces[i].get_ip_addresses() {
+                        let dst = network(cidr);
+                        buffer.push(route_message(header, i, dst, None, Some(cidr.address())));
+                    }
+                    for (dst, gateway) in ifaces[i].get_routes() {
+                        buffer.push(route_message(header, i, dst, Some(gateway), None));
+                    }
+                }
+            }
             NetlinkMessageType::GetLink => {
                 let ifaces = NET_DRIVERS.read();
                 for i in 0..ifaces.len() {
@@ -1003,12 +2202,12 @@ impl Socket for NetlinkSocketState {
                         };
                         msg.push_ext(new_header);
 
-                        let family: u16 = AddressFamily::Internet.into();
+                        let family: u16 = ip_family(ip_addrs[j].address()).into();
                         let if_addr = IfaceAddrMsg {
                             ifa_family: family as u8,
                             ifa_prefixlen: ip_addrs[j].prefix_len(),
                             ifa_flags: 0,
-                            ifa_scope: 0,
+                            ifa_scope: addr_scope(ip_addrs[j].address()),
                             ifa_index: i as u32,
                         };
                         msg.align4();
@@ -1055,7 +2254,8 @@ impl Socket for NetlinkSocketState {
     }
 
     fn poll(&self) -> (bool, bool, bool) {
-        unimplemented!()
+        // requests are answered at once
+        (!self.data.lock().is_empty(), true, false)
     }
 
     fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
@@ -1066,6 +2266,18 @@ impl Socket for NetlinkSocketState {
         Ok(0)
     }
 
+    fn options(&self) -> &SharedOptions {
+        &self.options
+    }
+
+    fn fd_cloexec(&self) -> bool {
+        self.fd_cloexec
+    }
+
+    fn set_fd_cloexec(&mut self, cloexec: bool) {
+        self.fd_cloexec = cloexec;
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
@@ -1088,7 +2300,7 @@ fn get_ephemeral_port() -> u16 {
 }
 
 /// Safety: call this without SOCKETS locked
-fn poll_ifaces() {
+pub(super) fn poll_ifaces() {
     for iface in NET_DRIVERS.read().iter() {
         iface.poll();
     }
@@ -1096,6 +2308,18 @@ fn poll_ifaces() {
 
 pub const TCP_SENDBUF: usize = 512 * 1024; // 512K
 pub const TCP_RECVBUF: usize = 512 * 1024; // 512K
+/// Most pending connections of a listening socket,
+/// each of them takes a socket with the full buffers
+const MAX_BACKLOG: usize = 8;
+/// Limits of SO_RCVBUF and SO_SNDBUF
+const MIN_SOCKBUF: usize = 4 * 1024; // 4K
+const MAX_SOCKBUF: usize = 4 * 1024 * 1024; // 4M
+
+/// Limits of Linux
+const MAX_TCP_KEEPALIVE_SECS: i32 = 32767;
+const MAX_TCP_KEEPCNT: i32 = 127;
+/// Hop limit of smoltcp by default
+const DEFAULT_TTL: u8 = 64;
 
 const UDP_METADATA_BUF: usize = 1024;
 const UDP_SENDBUF: usize = 64 * 1024; // 64K
@@ -1104,3 +2328,5 @@ const UDP_RECVBUF: usize = 64 * 1024; // 64K
 const RAW_METADATA_BUF: usize = 1024;
 const RAW_SENDBUF: usize = 64 * 1024; // 64K
 const RAW_RECVBUF: usize = 64 * 1024; // 64K
+/// IPv6 header without extension headers
+const IPV6_HEADER_LEN: usize = 40;
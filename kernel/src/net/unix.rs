@@ -0,0 +1,240 @@
+//! Local stream sockets, as connected pairs from socketpair
+//!
+//! Each direction is a byte stream. Ancillary data, i.e. files and
+//! credentials, goes with the first byte sent along with it: it is received
+//! by the receive returning that byte, and a receive never returns bytes
+//! from both sides of it.
+
+use super::activity::notify_activity;
+use super::structs::*;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::*;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp::min;
+use smoltcp::wire::IpEndpoint;
+
+/// Data in one direction
+#[derive(Debug, Default)]
+struct Channel {
+    buf: VecDeque<u8>,
+    /// Ancillary data with the position of the first byte sent along
+    ancillary: VecDeque<(usize, Ancillary)>,
+    /// Position of `buf[0]` in the stream
+    read_pos: usize,
+    /// No more data is to be sent
+    write_closed: bool,
+    /// No more data is to be received
+    read_closed: bool,
+}
+
+impl Channel {
+    /// Receive bytes up to the next ancillary data.
+    /// Ancillary data at the current position is taken if `with_ancillary`,
+    /// otherwise nothing is received.
+    fn recv(&mut self, data: &mut [u8], peek: bool, with_ancillary: bool) -> (usize, Ancillary) {
+        let read_pos = self.read_pos;
+        let mut ancillary = Ancillary::default();
+        if self.ancillary_pending() {
+            if !with_ancillary {
+                return (0, ancillary);
+            }
+            ancillary = if peek {
+                self.ancillary[0].1.clone()
+            } else {
+                self.ancillary.pop_front().unwrap().1
+            };
+        }
+
+        let mut len = min(data.len(), self.buf.len());
+        if let Some((pos, _)) = self.ancillary.iter().find(|(pos, _)| *pos > read_pos) {
+            len = min(len, pos - read_pos);
+        }
+        for (i, byte) in self.buf.iter().take(len).enumerate() {
+            data[i] = *byte;
+        }
+        if !peek {
+            self.buf.drain(..len);
+            self.read_pos += len;
+        }
+        (len, ancillary)
+    }
+
+    /// Ancillary data goes with the next byte to receive
+    fn ancillary_pending(&self) -> bool {
+        let read_pos = self.read_pos;
+        self.ancillary
+            .front()
+            .map_or(false, |(pos, _)| *pos == read_pos)
+    }
+}
+
+/// One end of a pair, closes both directions when dropped
+#[derive(Debug)]
+struct UnixEnd {
+    /// Data to this end
+    rx: Arc<Mutex<Channel>>,
+    /// Data to the peer
+    tx: Arc<Mutex<Channel>>,
+}
+
+impl Drop for UnixEnd {
+    fn drop(&mut self) {
+        self.rx.lock().read_closed = true;
+        self.tx.lock().write_closed = true;
+        notify_activity();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnixSocketState {
+    end: Arc<UnixEnd>,
+    cmsg: CmsgOptions,
+    options: SharedOptions,
+    /// FD_CLOEXEC, every fd has its own state
+    fd_cloexec: bool,
+}
+
+impl UnixSocketState {
+    /// Create a pair of connected sockets
+    pub fn new_pair() -> (Self, Self) {
+        let a_to_b = Arc::new(Mutex::new(Channel::default()));
+        let b_to_a = Arc::new(Mutex::new(Channel::default()));
+        let a = UnixSocketState {
+            end: Arc::new(UnixEnd {
+                rx: b_to_a.clone(),
+                tx: a_to_b.clone(),
+            }),
+            cmsg: CmsgOptions::default(),
+            options: SharedOptions::default(),
+            fd_cloexec: false,
+        };
+        let b = UnixSocketState {
+            end: Arc::new(UnixEnd {
+                rx: a_to_b,
+                tx: b_to_a,
+            }),
+            cmsg: CmsgOptions::default(),
+            options: SharedOptions::default(),
+            fd_cloexec: false,
+        };
+        (a, b)
+    }
+}
+
+/// Unnamed, as the address of both ends of a pair
+fn unnamed() -> Endpoint {
+    Endpoint::Ip(IpEndpoint::UNSPECIFIED)
+}
+
+impl Socket for UnixSocketState {
+    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        (
+            self.recvmsg(data, MsgFlags::empty()).map(|info| info.len),
+            unnamed(),
+        )
+    }
+
+    fn write(&self, data: &[u8], _sendto_endpoint: Option<Endpoint>) -> SysResult {
+        self.sendmsg(data, None, Ancillary::default(), MsgFlags::empty())
+    }
+
+    fn recvmsg(&self, data: &mut [u8], flags: MsgFlags) -> Result<RecvInfo, SysError> {
+        let mut info = RecvInfo::new(0, unnamed());
+        if data.is_empty() {
+            return Ok(info);
+        }
+        let mut rx = self.end.rx.lock();
+        let (len, ancillary) = rx.recv(data, flags.contains(MsgFlags::PEEK), true);
+        if len == 0 && !rx.write_closed {
+            return Err(SysError::EAGAIN);
+        }
+        info.len = len;
+        info.ancillary = ancillary;
+        Ok(info)
+    }
+
+    fn recv_more(&self, data: &mut [u8]) -> SysResult {
+        let mut rx = self.end.rx.lock();
+        let (len, _) = rx.recv(data, false, false);
+        // MSG_WAITALL stops at ancillary data as well
+        if len == 0 && !rx.write_closed && !rx.ancillary_pending() {
+            return Err(SysError::EAGAIN);
+        }
+        Ok(len)
+    }
+
+    fn sendmsg(
+        &self,
+        data: &[u8],
+        _sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+        _flags: MsgFlags,
+    ) -> SysResult {
+        let mut tx = self.end.tx.lock();
+        if tx.read_closed || tx.write_closed {
+            return Err(SysError::EPIPE);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if !ancillary.is_empty() {
+            let pos = tx.read_pos + tx.buf.len();
+            tx.ancillary.push_back((pos, ancillary));
+        }
+        tx.buf.extend(data.iter());
+        drop(tx);
+        notify_activity();
+        Ok(data.len())
+    }
+
+    fn poll(&self) -> (bool, bool, bool) {
+        let input = {
+            let rx = self.end.rx.lock();
+            !rx.buf.is_empty() || rx.write_closed
+        };
+        let output = {
+            let tx = self.end.tx.lock();
+            !tx.read_closed && !tx.write_closed
+        };
+        (input, output, false)
+    }
+
+    fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
+        Err(SysError::EISCONN)
+    }
+
+    fn shutdown(&self) -> SysResult {
+        self.end.tx.lock().write_closed = true;
+        notify_activity();
+        Ok(0)
+    }
+
+    fn setsockopt(&mut self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        if !self.cmsg.set(level, opt, data) && !self.options.lock().set(level, opt, data)? {
+            warn!("setsockopt is unimplemented");
+        }
+        Ok(0)
+    }
+
+    fn cmsg_options(&self) -> CmsgOptions {
+        self.cmsg
+    }
+
+    fn options(&self) -> &SharedOptions {
+        &self.options
+    }
+
+    fn fd_cloexec(&self) -> bool {
+        self.fd_cloexec
+    }
+
+    fn set_fd_cloexec(&mut self, cloexec: bool) {
+        self.fd_cloexec = cloexec;
+    }
+
+    fn box_clone(&self) -> Box<dyn Socket> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,109 @@
+// see Linux kernel source /include/uapi/linux/perf_event.h
+
+// event types
+pub const PERF_TYPE_SOFTWARE: u32 = 1;
+
+// software events
+pub const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+pub const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+pub const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+pub const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+pub const PERF_COUNT_SW_DUMMY: u64 = 9;
+
+// sample_type
+pub const PERF_SAMPLE_IP: u64 = 1 << 0;
+pub const PERF_SAMPLE_TID: u64 = 1 << 1;
+pub const PERF_SAMPLE_TIME: u64 = 1 << 2;
+pub const PERF_SAMPLE_ADDR: u64 = 1 << 3;
+pub const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+pub const PERF_SAMPLE_ID: u64 = 1 << 6;
+pub const PERF_SAMPLE_CPU: u64 = 1 << 7;
+pub const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+pub const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+pub const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+pub const PERF_SAMPLE_SUPPORTED: u64 = PERF_SAMPLE_IP
+    | PERF_SAMPLE_TID
+    | PERF_SAMPLE_TIME
+    | PERF_SAMPLE_ADDR
+    | PERF_SAMPLE_CALLCHAIN
+    | PERF_SAMPLE_ID
+    | PERF_SAMPLE_CPU
+    | PERF_SAMPLE_PERIOD
+    | PERF_SAMPLE_STREAM_ID
+    | PERF_SAMPLE_IDENTIFIER;
+
+// read_format
+pub const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+pub const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+pub const PERF_FORMAT_ID: u64 = 1 << 2;
+pub const PERF_FORMAT_LOST: u64 = 1 << 4;
+pub const PERF_FORMAT_SUPPORTED: u64 = PERF_FORMAT_TOTAL_TIME_ENABLED
+    | PERF_FORMAT_TOTAL_TIME_RUNNING
+    | PERF_FORMAT_ID
+    | PERF_FORMAT_LOST;
+
+// bits of the flags in perf_event_attr
+pub const ATTR_DISABLED: u64 = 1 << 0;
+pub const ATTR_INHERIT: u64 = 1 << 1;
+pub const ATTR_EXCLUDE_USER: u64 = 1 << 4;
+pub const ATTR_EXCLUDE_KERNEL: u64 = 1 << 5;
+pub const ATTR_EXCLUDE_IDLE: u64 = 1 << 7;
+pub const ATTR_MMAP: u64 = 1 << 8;
+pub const ATTR_COMM: u64 = 1 << 9;
+pub const ATTR_FREQ: u64 = 1 << 10;
+pub const ATTR_ENABLE_ON_EXEC: u64 = 1 << 12;
+pub const ATTR_TASK: u64 = 1 << 13;
+pub const ATTR_WATERMARK: u64 = 1 << 14;
+pub const ATTR_SAMPLE_ID_ALL: u64 = 1 << 18;
+pub const ATTR_EXCLUDE_CALLCHAIN_KERNEL: u64 = 1 << 21;
+pub const ATTR_EXCLUDE_CALLCHAIN_USER: u64 = 1 << 22;
+pub const ATTR_MMAP2: u64 = 1 << 23;
+pub const ATTR_COMM_EXEC: u64 = 1 << 24;
+pub const ATTR_WRITE_BACKWARD: u64 = 1 << 27;
+/// Flags up to `sigtrap`, later ones are reserved
+pub const ATTR_KNOWN: u64 = (1 << 38) - 1;
+
+// perf_event_open flags
+pub const PERF_FLAG_FD_NO_GROUP: usize = 1 << 0;
+pub const PERF_FLAG_FD_OUTPUT: usize = 1 << 1;
+pub const PERF_FLAG_PID_CGROUP: usize = 1 << 2;
+pub const PERF_FLAG_FD_CLOEXEC: usize = 1 << 3;
+
+// sizes of published versions of perf_event_attr
+pub const PERF_ATTR_SIZE_VER0: usize = 64;
+
+// ioctl requests
+pub const PERF_EVENT_IOC_ENABLE: usize = 0x2400;
+pub const PERF_EVENT_IOC_DISABLE: usize = 0x2401;
+pub const PERF_EVENT_IOC_RESET: usize = 0x2403;
+// _IOW('$', 4, u64)
+#[cfg(not(target_arch = "mips"))]
+pub const PERF_EVENT_IOC_PERIOD: usize = 0x4008_2404;
+#[cfg(target_arch = "mips")]
+pub const PERF_EVENT_IOC_PERIOD: usize = 0x8008_2404;
+// _IOR('$', 7, u64 *)
+#[cfg(not(target_arch = "mips"))]
+pub const PERF_EVENT_IOC_ID: usize = 0x8008_2407;
+#[cfg(target_arch = "mips")]
+pub const PERF_EVENT_IOC_ID: usize = 0x4008_2407;
+
+// record types in the ring buffer
+pub const PERF_RECORD_MMAP: u32 = 1;
+pub const PERF_RECORD_LOST: u32 = 2;
+pub const PERF_RECORD_COMM: u32 = 3;
+pub const PERF_RECORD_EXIT: u32 = 4;
+pub const PERF_RECORD_FORK: u32 = 7;
+pub const PERF_RECORD_SAMPLE: u32 = 9;
+
+// misc of record headers
+pub const PERF_RECORD_MISC_KERNEL: u16 = 1;
+pub const PERF_RECORD_MISC_USER: u16 = 2;
+pub const PERF_RECORD_MISC_COMM_EXEC: u16 = 1 << 13;
+
+// markers in callchains
+pub const PERF_CONTEXT_KERNEL: u64 = -128i64 as u64;
+pub const PERF_CONTEXT_USER: u64 = -512i64 as u64;
+
+/// Deepest callchain recorded in samples, like the default of
+/// `kernel.perf_event_max_stack` but smaller to keep records on the stack
+pub const PERF_MAX_STACK_DEPTH: usize = 64;
@@ -0,0 +1,498 @@
+//! Software performance events for `perf_event_open`
+//!
+//! Events count clock time, page faults or context switches of some threads
+//! or CPUs. Sampling events also write records to a ring buffer mapped by
+//! user space, in the format of Linux, so `perf record` works and the host
+//! `perf report` reads its `perf.data`. Clocks are sampled on timer ticks,
+//! the other events are driven by tracepoints.
+
+pub mod consts;
+mod ring_buffer;
+mod sample;
+mod task;
+
+pub use sample::{kernel_tick, user_tick};
+pub use task::{task_exec, task_exit, task_fork, task_mmap};
+
+use self::consts::*;
+use self::ring_buffer::{Record, RingBuffer};
+use self::sample::SampleId;
+use crate::arch::timer::timer_now;
+use crate::memory::{copy_from_user, copy_to_user};
+use crate::process::current_thread;
+use crate::sync::{wait_for_event, Event, EventBus, SpinNoIrqLock as Mutex};
+use crate::syscall::{MmapProt, SysError, SysError::*, SysResult};
+use crate::tracepoint::{self, Tracepoint};
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rcore_fs::vfs::{MMapArea, PollStatus};
+use rcore_memory::memory_set::handler::Linear;
+use rcore_memory::PAGE_SIZE;
+
+/// `struct perf_event_attr` up to `PERF_ATTR_SIZE_VER5`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PerfEventAttr {
+    pub type_: u32,
+    pub size: u32,
+    pub config: u64,
+    /// `sample_freq` with `ATTR_FREQ`
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    /// The bit fields, see `ATTR_*`
+    pub flags: u64,
+    /// `wakeup_watermark` with `ATTR_WATERMARK`
+    pub wakeup_events: u32,
+    pub bp_type: u32,
+    pub config1: u64,
+    pub config2: u64,
+    pub branch_sample_type: u64,
+    pub sample_regs_user: u64,
+    pub sample_stack_user: u32,
+    pub clockid: i32,
+    pub sample_regs_intr: u64,
+    pub aux_watermark: u32,
+    pub sample_max_stack: u16,
+    pub reserved_2: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Time on a CPU, including idle time when counting all threads
+    CpuClock,
+    /// Time the counted threads run
+    TaskClock,
+    PageFaults,
+    ContextSwitches,
+    /// Counts nothing, only for the records of `task`
+    Dummy,
+}
+
+impl Kind {
+    fn is_clock(self) -> bool {
+        self == Kind::CpuClock || self == Kind::TaskClock
+    }
+}
+
+/// Nanoseconds between timer ticks, the resolution of clock events
+const TICK_NS: u64 = crate::consts::USEC_PER_TICK as u64 * 1000;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+static EVENT_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    /// Open events, in the order they were opened
+    static ref EVENTS: Mutex<Vec<Arc<PerfEventInner>>> = Mutex::new(Vec::new());
+}
+
+/// An event shared by its file descriptors and what drives it
+struct PerfEventInner {
+    attr: PerfEventAttr,
+    kind: Kind,
+    id: u64,
+    cpu: Option<usize>,
+    state: Mutex<EventState>,
+    eventbus: Arc<Mutex<EventBus>>,
+}
+
+struct EventState {
+    /// Threads counted, `None` for every thread
+    tids: Option<BTreeSet<usize>>,
+    enabled: bool,
+    count: u64,
+    /// Nanoseconds enabled, up to `enabled_at` if it is enabled now
+    time_enabled: u64,
+    enabled_at: u64,
+    /// Count since the last sample, and between samples
+    pending: u64,
+    period: u64,
+    ring: Option<RingBuffer>,
+    /// Samples since user space was last woken up
+    unwoken: u32,
+    woken: bool,
+    lost: u64,
+    /// The counted threads are all gone
+    hup: bool,
+}
+
+fn now_ns() -> u64 {
+    timer_now().as_nanos() as u64
+}
+
+impl PerfEventInner {
+    fn has(&self, flag: u64) -> bool {
+        self.attr.flags & flag != 0
+    }
+
+    fn sample_type(&self, bits: u64) -> bool {
+        self.attr.sample_type & bits != 0
+    }
+
+    fn is_sampling(&self) -> bool {
+        self.attr.sample_period != 0
+    }
+
+    /// Period of samples for `sample_period` or `sample_freq`
+    fn period(&self, value: u64) -> u64 {
+        if !self.has(ATTR_FREQ) {
+            value
+        } else if self.kind.is_clock() {
+            (NSEC_PER_SEC / value).max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Whether the event counts what `tid` does in user or kernel mode
+    /// on this CPU, `tid` is `None` when the CPU is idle
+    fn counts(&self, state: &EventState, tid: Option<usize>, user: bool) -> bool {
+        if !state.enabled
+            || self.cpu.map_or(false, |cpu| cpu != crate::arch::cpu::id())
+            || self.has(if user {
+                ATTR_EXCLUDE_USER
+            } else {
+                ATTR_EXCLUDE_KERNEL
+            })
+        {
+            return false;
+        }
+        match (&state.tids, tid) {
+            (Some(tids), Some(tid)) => tids.contains(&tid),
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (None, None) => self.kind == Kind::CpuClock && !self.has(ATTR_EXCLUDE_IDLE),
+        }
+    }
+
+    fn follows(&self, state: &EventState, tid: usize) -> bool {
+        state.tids.as_ref().map_or(true, |tids| tids.contains(&tid))
+    }
+
+    fn enable(&self, state: &mut EventState) {
+        if !state.enabled {
+            state.enabled = true;
+            state.enabled_at = now_ns();
+        }
+    }
+
+    fn disable(&self, state: &mut EventState) {
+        if state.enabled {
+            state.time_enabled += now_ns() - state.enabled_at;
+            state.enabled = false;
+        }
+    }
+
+    fn time_enabled(&self, state: &EventState) -> u64 {
+        if state.enabled {
+            state.time_enabled + (now_ns() - state.enabled_at)
+        } else {
+            state.time_enabled
+        }
+    }
+
+    /// Add `n` to the count, and take a sample once per period.
+    /// `sample` fills the sample with `misc` in the header for the period.
+    fn add(
+        &self,
+        state: &mut EventState,
+        n: u64,
+        id: &SampleId,
+        misc: u16,
+        sample: impl FnOnce(&mut Record, u64),
+    ) {
+        state.count += n;
+        if !self.is_sampling() || state.ring.is_none() {
+            return;
+        }
+        state.pending += n;
+        if state.pending < state.period {
+            return;
+        }
+        let period = state.pending;
+        state.pending = 0;
+        let mut record = Record::new(PERF_RECORD_SAMPLE, misc);
+        sample(&mut record, period);
+        self.output(state, &record, id, true);
+    }
+
+    /// The fields of `sample_id_all` after records other than samples
+    fn push_sample_id(&self, record: &mut Record, id: &SampleId) {
+        if !self.has(ATTR_SAMPLE_ID_ALL) {
+            return;
+        }
+        if self.sample_type(PERF_SAMPLE_TID) {
+            record.push_pair(id.pid, id.tid);
+        }
+        if self.sample_type(PERF_SAMPLE_TIME) {
+            record.push(id.time);
+        }
+        if self.sample_type(PERF_SAMPLE_ID) {
+            record.push(self.id);
+        }
+        if self.sample_type(PERF_SAMPLE_STREAM_ID) {
+            record.push(self.id);
+        }
+        if self.sample_type(PERF_SAMPLE_CPU) {
+            record.push_pair(id.cpu, 0);
+        }
+        if self.sample_type(PERF_SAMPLE_IDENTIFIER) {
+            record.push(self.id);
+        }
+    }
+
+    /// Write `record` to the ring buffer and wake up user space if it is time to
+    fn output(&self, state: &mut EventState, record: &Record, id: &SampleId, sample: bool) {
+        let time = self.time_enabled(state);
+        let ring = match &mut state.ring {
+            Some(ring) => ring,
+            None => return,
+        };
+        let written = ring.output(record, |lost| {
+            let mut record = Record::new(PERF_RECORD_LOST, 0);
+            record.push(self.id);
+            record.push(lost);
+            self.push_sample_id(&mut record, id);
+            record
+        });
+        if !written {
+            state.lost += 1;
+            return;
+        }
+        ring.set_times(time, time);
+        if sample && !self.has(ATTR_WATERMARK) && self.attr.wakeup_events != 0 {
+            state.unwoken += 1;
+            if state.unwoken >= self.attr.wakeup_events {
+                state.unwoken = 0;
+                state.woken = true;
+            }
+        }
+        if state.woken || ring.pending() >= self.watermark(ring) {
+            self.eventbus.lock().set(Event::READABLE);
+        }
+    }
+
+    /// Bytes of records to wake up user space, half the buffer by default
+    fn watermark(&self, ring: &RingBuffer) -> usize {
+        if self.has(ATTR_WATERMARK) && self.attr.wakeup_events != 0 {
+            self.attr.wakeup_events as usize
+        } else {
+            (ring.pages() - 1) * PAGE_SIZE / 2
+        }
+    }
+
+    /// Whether there are records for user space to read
+    fn readable(&self, state: &mut EventState) -> bool {
+        let ring = match &state.ring {
+            Some(ring) => ring,
+            None => return false,
+        };
+        let pending = ring.pending();
+        if pending == 0 {
+            state.woken = false;
+        }
+        state.woken || pending >= self.watermark(ring)
+    }
+}
+
+/// An event opened by `perf_event_open`, a file descriptor refers to it
+pub struct PerfEvent {
+    inner: Arc<PerfEventInner>,
+    /// Tracepoints driving the event, and the ids of the probes on them
+    probes: Vec<(&'static Tracepoint, usize)>,
+}
+
+/// Check `attr` and open an event counting `tids`, or all threads if `None`,
+/// on `cpu`, or all CPUs if `None`
+pub fn perf_event_open(
+    attr: PerfEventAttr,
+    tids: Option<BTreeSet<usize>>,
+    cpu: Option<usize>,
+) -> Result<PerfEvent, SysError> {
+    if attr.type_ != PERF_TYPE_SOFTWARE {
+        return Err(ENOENT);
+    }
+    let kind = match attr.config {
+        PERF_COUNT_SW_CPU_CLOCK => Kind::CpuClock,
+        PERF_COUNT_SW_TASK_CLOCK => Kind::TaskClock,
+        PERF_COUNT_SW_PAGE_FAULTS => Kind::PageFaults,
+        PERF_COUNT_SW_CONTEXT_SWITCHES => Kind::ContextSwitches,
+        PERF_COUNT_SW_DUMMY => Kind::Dummy,
+        _ => return Err(ENOENT),
+    };
+    // mmap2 is left to the fallback of perf to mmap
+    if attr.flags & !ATTR_KNOWN != 0
+        || attr.flags & (ATTR_MMAP2 | ATTR_WRITE_BACKWARD) != 0
+        || attr.sample_type & !PERF_SAMPLE_SUPPORTED != 0
+        || attr.read_format & !PERF_FORMAT_SUPPORTED != 0
+        || attr.flags & ATTR_FREQ != 0 && attr.sample_period == 0
+        || attr.branch_sample_type != 0
+        || attr.sample_regs_user != 0
+        || attr.sample_stack_user != 0
+        || attr.sample_regs_intr != 0
+    {
+        return Err(EINVAL);
+    }
+    let enabled = attr.flags & (ATTR_DISABLED | ATTR_ENABLE_ON_EXEC) == 0;
+    let inner = Arc::new(PerfEventInner {
+        attr,
+        kind,
+        id: EVENT_ID.fetch_add(1, Ordering::Relaxed),
+        cpu,
+        state: Mutex::new(EventState {
+            tids,
+            enabled: false,
+            count: 0,
+            time_enabled: 0,
+            enabled_at: 0,
+            pending: 0,
+            period: 0,
+            ring: None,
+            unwoken: 0,
+            woken: false,
+            lost: 0,
+            hup: false,
+        }),
+        eventbus: EventBus::new(),
+    });
+    {
+        let mut state = inner.state.lock();
+        state.period = inner.period(attr.sample_period);
+        if enabled {
+            inner.enable(&mut state);
+        }
+    }
+    let probes = sample::attach(&inner);
+    EVENTS.lock().push(inner.clone());
+    Ok(PerfEvent { inner, probes })
+}
+
+impl PerfEvent {
+    /// The count, followed by what `read_format` asks for
+    pub fn read(&self, buf: &mut [u8]) -> SysResult {
+        let inner = &self.inner;
+        let state = inner.state.lock();
+        let format = inner.attr.read_format;
+        let time = inner.time_enabled(&state);
+        let mut values = [0u64; 5];
+        let mut len = 0;
+        let mut push = |value| {
+            values[len] = value;
+            len += 1;
+        };
+        push(state.count);
+        if format & PERF_FORMAT_TOTAL_TIME_ENABLED != 0 {
+            push(time);
+        }
+        // software events run whenever they are enabled
+        if format & PERF_FORMAT_TOTAL_TIME_RUNNING != 0 {
+            push(time);
+        }
+        if format & PERF_FORMAT_ID != 0 {
+            push(inner.id);
+        }
+        if format & PERF_FORMAT_LOST != 0 {
+            push(state.lost);
+        }
+        drop(state);
+        if buf.len() < len * 8 {
+            return Err(ENOSPC);
+        }
+        for (i, value) in values[..len].iter().enumerate() {
+            buf[i * 8..(i + 1) * 8].copy_from_slice(&value.to_ne_bytes());
+        }
+        Ok(len * 8)
+    }
+
+    pub fn ioctl(&self, request: usize, arg: usize) -> SysResult {
+        let inner = &self.inner;
+        // user memory is accessed without the state locked,
+        // as page faults may be counted by the event
+        match request {
+            PERF_EVENT_IOC_ENABLE => inner.enable(&mut inner.state.lock()),
+            PERF_EVENT_IOC_DISABLE => inner.disable(&mut inner.state.lock()),
+            PERF_EVENT_IOC_RESET => inner.state.lock().count = 0,
+            PERF_EVENT_IOC_PERIOD => {
+                let value = copy_from_user(arg as *const u64).ok_or(EFAULT)?;
+                if value == 0 || !inner.is_sampling() {
+                    return Err(EINVAL);
+                }
+                inner.state.lock().period = inner.period(value);
+            }
+            PERF_EVENT_IOC_ID => {
+                if !copy_to_user(arg as *mut u64, &inner.id) {
+                    return Err(EFAULT);
+                }
+            }
+            _ => return Err(ENOTTY),
+        }
+        Ok(0)
+    }
+
+    /// Map the ring buffer, `area` covers its first page and 2^n data pages.
+    /// Pages of a mapped buffer are not freed, user space may still have them.
+    pub fn mmap(&self, area: MMapArea) -> SysResult {
+        let len = area.end_vaddr - area.start_vaddr;
+        let prot = MmapProt::from_bits_truncate(area.prot);
+        if area.offset != 0 || len % PAGE_SIZE != 0 {
+            return Err(EINVAL);
+        }
+        let mut state = self.inner.state.lock();
+        if state.ring.is_some() {
+            return Err(EINVAL);
+        }
+        let mut ring = RingBuffer::new(len / PAGE_SIZE, prot.contains(MmapProt::WRITE))?;
+        let time = self.inner.time_enabled(&state);
+        ring.set_times(time, time);
+        let paddr = ring.paddr();
+        state.ring = Some(ring);
+        drop(state);
+        let thread = current_thread().unwrap();
+        thread.vm.lock().push(
+            area.start_vaddr,
+            area.end_vaddr,
+            prot.to_attr(),
+            Linear::new(paddr as isize - area.start_vaddr as isize),
+            "mmap_perf",
+        );
+        Ok(0)
+    }
+
+    pub fn poll(&self) -> PollStatus {
+        let mut state = self.inner.state.lock();
+        PollStatus {
+            read: self.inner.readable(&mut state),
+            write: false,
+            error: state.hup,
+        }
+    }
+
+    pub async fn async_poll(&self) -> PollStatus {
+        loop {
+            {
+                let mut state = self.inner.state.lock();
+                if self.inner.readable(&mut state) || state.hup {
+                    break;
+                }
+                // records written from now on wake us up
+                self.inner.eventbus.lock().clear(Event::READABLE);
+            }
+            wait_for_event(self.inner.eventbus.clone(), Event::READABLE).await;
+        }
+        self.poll()
+    }
+}
+
+impl Drop for PerfEvent {
+    fn drop(&mut self) {
+        for &(tp, id) in self.probes.iter() {
+            tracepoint::unregister_probe(tp, id);
+        }
+        EVENTS
+            .lock()
+            .retain(|inner| !Arc::ptr_eq(inner, &self.inner));
+    }
+}
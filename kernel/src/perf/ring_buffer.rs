@@ -0,0 +1,223 @@
+use super::consts::*;
+use crate::memory::{alloc_frame_contiguous, phys_to_virt};
+use crate::syscall::{SysError, SysError::*};
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use rcore_memory::PAGE_SIZE;
+
+/// `struct perf_event_mmap_page`, the first page of a ring buffer.
+/// Only the times and the data fields are maintained, there are no
+/// hardware counters for user space to read directly.
+#[repr(C)]
+struct MmapPage {
+    version: u32,
+    compat_version: u32,
+    lock: u32,
+    index: u32,
+    offset: i64,
+    time_enabled: u64,
+    time_running: u64,
+    capabilities: u64,
+    pmc_width: u16,
+    time_shift: u16,
+    time_mult: u32,
+    time_offset: u64,
+    time_zero: u64,
+    size: u32,
+    reserved_1: u32,
+    time_cycles: u64,
+    time_mask: u64,
+    reserved: [u8; 116 * 8],
+    data_head: u64,
+    data_tail: u64,
+    data_offset: u64,
+    data_size: u64,
+}
+
+/// `cap_bit0_is_deprecated`, so that no capability is read from bit 0
+const CAP_BIT0_IS_DEPRECATED: u64 = 1 << 1;
+
+/// Records of an event for user space, laid out like in Linux:
+/// a metadata page followed by 2^n pages of records, which wrap around.
+/// User space moves `data_tail` after reading if it maps the buffer
+/// writable, otherwise new records overwrite old ones.
+pub struct RingBuffer {
+    paddr: usize,
+    pages: usize,
+    writable: bool,
+    /// Records dropped since the last `PERF_RECORD_LOST`
+    lost: u64,
+}
+
+impl RingBuffer {
+    pub fn new(pages: usize, writable: bool) -> Result<Self, SysError> {
+        if pages < 2 || !(pages - 1).is_power_of_two() {
+            return Err(EINVAL);
+        }
+        let paddr = alloc_frame_contiguous(pages, 0).ok_or(ENOMEM)?;
+        // these pages end up in user space
+        unsafe {
+            core::ptr::write_bytes(phys_to_virt(paddr) as *mut u8, 0, pages * PAGE_SIZE);
+        }
+        let buf = Self {
+            paddr,
+            pages,
+            writable,
+            lost: 0,
+        };
+        let page = buf.page();
+        unsafe {
+            (*page).capabilities = CAP_BIT0_IS_DEPRECATED;
+            (*page).data_offset = PAGE_SIZE as u64;
+            (*page).data_size = buf.size() as u64;
+        }
+        Ok(buf)
+    }
+
+    pub fn paddr(&self) -> usize {
+        self.paddr
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn page(&self) -> *mut MmapPage {
+        phys_to_virt(self.paddr) as *mut MmapPage
+    }
+
+    fn data(&self) -> *mut u8 {
+        phys_to_virt(self.paddr + PAGE_SIZE) as *mut u8
+    }
+
+    fn size(&self) -> usize {
+        (self.pages - 1) * PAGE_SIZE
+    }
+
+    fn head(&self) -> u64 {
+        unsafe { read_volatile(addr_of!((*self.page()).data_head)) }
+    }
+
+    fn tail(&self) -> u64 {
+        let tail = unsafe { read_volatile(addr_of!((*self.page()).data_tail)) };
+        // read the tail before overwriting what user space has read
+        fence(Ordering::Acquire);
+        tail
+    }
+
+    /// Bytes of records not read by user space yet.
+    /// Always 0 in overwrite mode, user space reads when it likes then.
+    pub fn pending(&self) -> usize {
+        if self.writable {
+            self.head().wrapping_sub(self.tail()) as usize
+        } else {
+            0
+        }
+    }
+
+    /// Publish the times of the event in the metadata page
+    pub fn set_times(&mut self, enabled: u64, running: u64) {
+        let page = self.page();
+        unsafe {
+            write_volatile(addr_of_mut!((*page).time_enabled), enabled);
+            write_volatile(addr_of_mut!((*page).time_running), running);
+        }
+    }
+
+    /// Append a record, it is dropped if user space has not made room for it.
+    /// `lost_record` builds the `PERF_RECORD_LOST` for records dropped before.
+    pub fn output(&mut self, record: &Record, lost_record: impl FnOnce(u64) -> Record) -> bool {
+        if self.lost != 0 {
+            let lost = lost_record(self.lost);
+            if !self.write(lost.as_slice()) {
+                self.lost += 1;
+                return false;
+            }
+            self.lost = 0;
+        }
+        if !self.write(record.as_slice()) {
+            self.lost += 1;
+            return false;
+        }
+        true
+    }
+
+    fn write(&mut self, record: &[u64]) -> bool {
+        let size = self.size();
+        let len = record.len() * 8;
+        let head = self.head();
+        if self.writable && head.wrapping_sub(self.tail()) as usize + len > size {
+            return false;
+        }
+        // records are 8-byte aligned, so they wrap between two words
+        let data = self.data() as *mut u64;
+        for (i, &word) in record.iter().enumerate() {
+            let offset = (head as usize + i * 8) & (size - 1);
+            unsafe {
+                write_volatile(data.add(offset / 8), word);
+            }
+        }
+        // the record must be visible before the new head
+        fence(Ordering::Release);
+        unsafe {
+            write_volatile(addr_of_mut!((*self.page()).data_head), head + len as u64);
+        }
+        true
+    }
+}
+
+/// Longest path in records, longer ones are truncated
+const MAX_PATH_LEN: usize = 255;
+
+/// Words of the largest record: a sample with the deepest callchain,
+/// or a `PERF_RECORD_MMAP` with the longest path
+const RECORD_WORDS: usize = 16 + PERF_MAX_STACK_DEPTH + (MAX_PATH_LEN + 1) / 8;
+
+/// A record being built in place, interrupt handlers should not allocate
+pub struct Record {
+    words: [u64; RECORD_WORDS],
+    len: usize,
+}
+
+impl Record {
+    pub fn new(type_: u32, misc: u16) -> Self {
+        let mut record = Self {
+            words: [0; RECORD_WORDS],
+            len: 0,
+        };
+        // the size in the header is updated on every push
+        record.push(type_ as u64 | (misc as u64) << 32);
+        record
+    }
+
+    pub fn push(&mut self, word: u64) {
+        if self.len < RECORD_WORDS {
+            self.words[self.len] = word;
+            self.len += 1;
+            let size = (self.len * 8) as u64;
+            self.words[0] = self.words[0] & !(0xffff << 48) | size << 48;
+        }
+    }
+
+    /// Two 32-bit fields packed in a word, `lo` first in memory
+    pub fn push_pair(&mut self, lo: u32, hi: u32) {
+        self.push(lo as u64 | (hi as u64) << 32);
+    }
+
+    /// A NUL terminated string, padded to whole words
+    pub fn push_str(&mut self, s: &str) {
+        let bytes = &s.as_bytes()[..s.len().min(MAX_PATH_LEN)];
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.push(u64::from_le_bytes(word));
+        }
+        if bytes.len() % 8 == 0 {
+            self.push(0);
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u64] {
+        &self.words[..self.len]
+    }
+}
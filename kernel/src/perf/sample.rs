@@ -0,0 +1,249 @@
+//! Counting and sampling on timer ticks and tracepoints
+
+use super::*;
+use crate::arch::cpu;
+use crate::backtrace;
+use crate::process::Thread;
+use crate::tracepoint::{
+    PageFaultContext, SchedSwitchContext, PAGE_FAULT_KERNEL, PAGE_FAULT_USER, SCHED_SWITCH,
+};
+use trapframe::UserContext;
+
+/// Where and when a record is written
+pub struct SampleId {
+    pub pid: u32,
+    pub tid: u32,
+    pub time: u64,
+    pub cpu: u32,
+}
+
+impl SampleId {
+    pub fn current() -> Self {
+        let (pid, tid) = match current_thread() {
+            Some(thread) => (pid_of(&thread), thread.tid),
+            None => (0, 0),
+        };
+        SampleId {
+            pid: pid as u32,
+            tid: tid as u32,
+            time: now_ns(),
+            cpu: cpu::id() as u32,
+        }
+    }
+}
+
+/// Process id of `thread`, or its thread id if the process is locked,
+/// as interrupts may come in while it is
+pub fn pid_of(thread: &Thread) -> usize {
+    thread
+        .proc
+        .try_lock()
+        .map_or(thread.tid, |proc| proc.pid.get())
+}
+
+/// Return addresses of a sample, innermost first, from the sampled `pc`
+struct Chain {
+    ips: [usize; PERF_MAX_STACK_DEPTH],
+    len: usize,
+    user: bool,
+}
+
+impl Chain {
+    fn user(pc: usize, fp: usize) -> Self {
+        let mut ips = [0; PERF_MAX_STACK_DEPTH];
+        ips[0] = pc;
+        let len = 1 + backtrace::user_callers(fp, &mut ips[1..]);
+        Chain {
+            ips,
+            len,
+            user: true,
+        }
+    }
+
+    fn kernel(pc: usize, fp: usize) -> Self {
+        let mut ips = [0; PERF_MAX_STACK_DEPTH];
+        ips[0] = pc;
+        let len = 1 + backtrace::callers_from(fp, &mut ips[1..]);
+        Chain {
+            ips,
+            len,
+            user: false,
+        }
+    }
+
+    /// The callers of the code reporting an event to a tracepoint
+    #[inline(always)]
+    fn here() -> Self {
+        let mut ips = [0; PERF_MAX_STACK_DEPTH];
+        let len = backtrace::callers(0, &mut ips);
+        Chain {
+            ips,
+            len,
+            user: false,
+        }
+    }
+
+    fn ip(&self) -> u64 {
+        self.ips[0] as u64
+    }
+}
+
+impl PerfEventInner {
+    /// The fields `sample_type` asks for, in the order of Linux
+    fn push_sample(
+        &self,
+        record: &mut Record,
+        id: &SampleId,
+        chain: &Chain,
+        addr: u64,
+        period: u64,
+    ) {
+        if self.sample_type(PERF_SAMPLE_IDENTIFIER) {
+            record.push(self.id);
+        }
+        if self.sample_type(PERF_SAMPLE_IP) {
+            record.push(chain.ip());
+        }
+        if self.sample_type(PERF_SAMPLE_TID) {
+            record.push_pair(id.pid, id.tid);
+        }
+        if self.sample_type(PERF_SAMPLE_TIME) {
+            record.push(id.time);
+        }
+        if self.sample_type(PERF_SAMPLE_ADDR) {
+            record.push(addr);
+        }
+        if self.sample_type(PERF_SAMPLE_ID) {
+            record.push(self.id);
+        }
+        if self.sample_type(PERF_SAMPLE_STREAM_ID) {
+            record.push(self.id);
+        }
+        if self.sample_type(PERF_SAMPLE_CPU) {
+            record.push_pair(id.cpu, 0);
+        }
+        if self.sample_type(PERF_SAMPLE_PERIOD) {
+            record.push(period);
+        }
+        if self.sample_type(PERF_SAMPLE_CALLCHAIN) {
+            let excluded = self.has(if chain.user {
+                ATTR_EXCLUDE_CALLCHAIN_USER
+            } else {
+                ATTR_EXCLUDE_CALLCHAIN_KERNEL
+            });
+            let max_stack = match self.attr.sample_max_stack as usize {
+                0 => PERF_MAX_STACK_DEPTH,
+                max_stack => max_stack,
+            };
+            let len = if excluded {
+                0
+            } else {
+                chain.len.min(max_stack).min(PERF_MAX_STACK_DEPTH - 1)
+            };
+            if len == 0 {
+                record.push(0);
+            } else {
+                record.push(1 + len as u64);
+                record.push(if chain.user {
+                    PERF_CONTEXT_USER
+                } else {
+                    PERF_CONTEXT_KERNEL
+                });
+                for &ip in chain.ips[..len].iter() {
+                    record.push(ip as u64);
+                }
+            }
+        }
+    }
+}
+
+/// A timer tick interrupted user code of `thread` with context `cx`
+pub fn user_tick(thread: &Thread, cx: &UserContext) {
+    #[cfg(target_arch = "x86_64")]
+    let (pc, fp) = (cx.general.rip, cx.general.rbp);
+    #[cfg(riscv)]
+    let (pc, fp) = (cx.sepc, cx.general.s0);
+    #[cfg(target_arch = "aarch64")]
+    let (pc, fp) = (cx.elr, cx.general.x29);
+    #[cfg(target_arch = "mips")]
+    let (pc, fp) = (cx.epc, 0);
+    tick(Some(thread.tid), true, || Chain::user(pc, fp));
+}
+
+/// A timer tick interrupted the kernel at `pc`, with frame pointer `fp`
+pub fn kernel_tick(pc: usize, fp: usize) {
+    let tid = current_thread().map(|thread| thread.tid);
+    tick(tid, false, || Chain::kernel(pc, fp));
+}
+
+/// Account a tick to the clocks, `chain` is only unwound for samples
+fn tick(tid: Option<usize>, user: bool, chain: impl Fn() -> Chain) {
+    let events = EVENTS.lock();
+    if events.is_empty() {
+        return;
+    }
+    let id = SampleId::current();
+    let misc = if user {
+        PERF_RECORD_MISC_USER
+    } else {
+        PERF_RECORD_MISC_KERNEL
+    };
+    let mut unwound = None;
+    for inner in events.iter().filter(|inner| inner.kind.is_clock()) {
+        let mut state = inner.state.lock();
+        if !inner.counts(&state, tid, user) {
+            continue;
+        }
+        inner.add(&mut state, TICK_NS, &id, misc, |record, period| {
+            let chain = unwound.get_or_insert_with(&chain);
+            inner.push_sample(record, &id, chain, 0, period);
+        });
+    }
+}
+
+/// Register probes on the tracepoints driving the event
+pub fn attach(inner: &Arc<PerfEventInner>) -> Vec<(&'static Tracepoint, usize)> {
+    let tracepoints: Vec<&'static Tracepoint> = match inner.kind {
+        Kind::PageFaults => vec![&PAGE_FAULT_USER, &PAGE_FAULT_KERNEL],
+        Kind::ContextSwitches => vec![&SCHED_SWITCH],
+        _ => Vec::new(),
+    };
+    tracepoints
+        .into_iter()
+        .map(|tp| {
+            let inner = inner.clone();
+            let handler = Arc::new(move |ctx: *const u8| on_tracepoint(&inner, tp, ctx));
+            (tp, tracepoint::register_probe(tp, None, handler))
+        })
+        .collect()
+}
+
+/// Count an event reported to `tp`. Samples point at the kernel code
+/// reporting it, with the faulting address for page faults.
+fn on_tracepoint(inner: &PerfEventInner, tp: &Tracepoint, ctx: *const u8) {
+    // context switches are counted for the thread switched out
+    let (tid, user, addr) = if core::ptr::eq(tp, &SCHED_SWITCH) {
+        let ctx = unsafe { &*(ctx as *const SchedSwitchContext) };
+        (ctx.prev_pid as usize, false, 0)
+    } else {
+        let ctx = unsafe { &*(ctx as *const PageFaultContext) };
+        let user = core::ptr::eq(tp, &PAGE_FAULT_USER);
+        (ctx.common.common_pid as usize, user, ctx.address)
+    };
+    let tid = Some(tid).filter(|&tid| tid != 0);
+    let mut state = inner.state.lock();
+    if !inner.counts(&state, tid, user) {
+        return;
+    }
+    let id = SampleId::current();
+    inner.add(
+        &mut state,
+        1,
+        &id,
+        PERF_RECORD_MISC_KERNEL,
+        |record, period| {
+            let chain = Chain::here();
+            inner.push_sample(record, &id, &chain, addr, period);
+        },
+    );
+}
@@ -0,0 +1,238 @@
+//! Records of what threads do for `ATTR_TASK`, `ATTR_COMM` and `ATTR_MMAP`,
+//! they tell `perf report` which program and which symbols a sample is in
+
+use super::sample::pid_of;
+use super::*;
+use crate::arch::cpu;
+use crate::fs::{FOLLOW_MAX_DEPTH, ROOT_INODE};
+use crate::process::{ElfExt, Thread};
+use alloc::string::String;
+use rcore_fs::vfs::INode;
+use xmas_elf::program::Type;
+use xmas_elf::ElfFile;
+
+/// `child` is forked or cloned from `parent`.
+/// Events inherited by `parent` count `child` from now on.
+pub fn task_fork(parent: &Thread, child: &Thread) {
+    if EVENTS.lock().is_empty() {
+        return;
+    }
+    let ppid = parent.proc.lock().pid.get();
+    let pid = child.proc.lock().pid.get();
+    let id = SampleId::current();
+    let events = EVENTS.lock();
+    for inner in events.iter() {
+        let mut state = inner.state.lock();
+        if !inner.follows(&state, parent.tid) {
+            continue;
+        }
+        if inner.has(ATTR_INHERIT) {
+            if let Some(tids) = &mut state.tids {
+                tids.insert(child.tid);
+            }
+        }
+        if inner.has(ATTR_TASK) && state.enabled {
+            let mut record = Record::new(PERF_RECORD_FORK, 0);
+            record.push_pair(pid as u32, ppid as u32);
+            record.push_pair(child.tid as u32, parent.tid as u32);
+            record.push(id.time);
+            inner.push_sample_id(&mut record, &id);
+            inner.output(&mut state, &record, &id, false);
+        }
+    }
+}
+
+/// `thread` of process `pid` runs the program at `path` from now on
+pub fn task_exec(thread: &Thread, pid: usize, path: &str, inode: &Arc<dyn INode>) {
+    let wants_mmap = EVENTS
+        .lock()
+        .iter()
+        .any(|inner| inner.has(ATTR_MMAP) && inner.follows(&inner.state.lock(), thread.tid));
+    // files are read before taking any lock
+    let mappings = if wants_mmap {
+        exec_mappings(path, inode)
+    } else {
+        Vec::new()
+    };
+    let id = SampleId {
+        pid: pid as u32,
+        tid: thread.tid as u32,
+        time: now_ns(),
+        cpu: cpu::id() as u32,
+    };
+    let comm = path.rsplit('/').next().unwrap_or("");
+    // like the comm of Linux threads, at most 15 bytes
+    let comm = &comm[..comm.len().min(15)];
+    let events = EVENTS.lock();
+    for inner in events.iter() {
+        let mut state = inner.state.lock();
+        if !inner.follows(&state, thread.tid) {
+            continue;
+        }
+        if inner.has(ATTR_ENABLE_ON_EXEC) {
+            inner.enable(&mut state);
+        }
+        if !state.enabled {
+            continue;
+        }
+        if inner.has(ATTR_COMM) {
+            let misc = if inner.has(ATTR_COMM_EXEC) {
+                PERF_RECORD_MISC_COMM_EXEC
+            } else {
+                0
+            };
+            let mut record = Record::new(PERF_RECORD_COMM, misc);
+            record.push_pair(id.pid, id.tid);
+            record.push_str(comm);
+            inner.push_sample_id(&mut record, &id);
+            inner.output(&mut state, &record, &id, false);
+        }
+        if inner.has(ATTR_MMAP) {
+            for mapping in mappings.iter() {
+                output_mmap(inner, &mut state, &id, mapping);
+            }
+        }
+    }
+}
+
+/// `thread` maps `len` bytes of the file at `path` from `offset` to `addr`
+/// as code. Data mappings are not recorded, there is no `ATTR_MMAP_DATA`.
+pub fn task_mmap(thread: &Thread, pid: usize, addr: usize, len: usize, offset: usize, path: &str) {
+    let events = EVENTS.lock();
+    if events.is_empty() {
+        return;
+    }
+    let id = SampleId {
+        pid: pid as u32,
+        tid: thread.tid as u32,
+        time: now_ns(),
+        cpu: cpu::id() as u32,
+    };
+    let mapping = Mapping {
+        path: String::from(path),
+        addr,
+        len,
+        offset,
+    };
+    for inner in events.iter() {
+        let mut state = inner.state.lock();
+        if inner.has(ATTR_MMAP) && state.enabled && inner.follows(&state, thread.tid) {
+            output_mmap(inner, &mut state, &id, &mapping);
+        }
+    }
+}
+
+/// `thread` exits. Events counting threads hang up once they have all exited.
+pub fn task_exit(thread: &Thread) {
+    if EVENTS.lock().is_empty() {
+        return;
+    }
+    let (pid, ppid) = {
+        let proc = thread.proc.lock();
+        (proc.pid.get(), proc.parent.0.get())
+    };
+    let id = SampleId {
+        pid: pid as u32,
+        tid: thread.tid as u32,
+        time: now_ns(),
+        cpu: cpu::id() as u32,
+    };
+    let events = EVENTS.lock();
+    for inner in events.iter() {
+        let mut state = inner.state.lock();
+        if !inner.follows(&state, thread.tid) {
+            continue;
+        }
+        if inner.has(ATTR_TASK) && state.enabled {
+            let mut record = Record::new(PERF_RECORD_EXIT, 0);
+            record.push_pair(id.pid, ppid as u32);
+            record.push_pair(id.tid, id.tid);
+            record.push(id.time);
+            inner.push_sample_id(&mut record, &id);
+            inner.output(&mut state, &record, &id, false);
+        }
+        if let Some(tids) = &mut state.tids {
+            tids.remove(&thread.tid);
+            if tids.is_empty() {
+                state.hup = true;
+                inner.eventbus.lock().set(Event::READABLE);
+            }
+        }
+    }
+}
+
+/// Code mapped from a file
+struct Mapping {
+    path: String,
+    addr: usize,
+    len: usize,
+    offset: usize,
+}
+
+fn output_mmap(inner: &PerfEventInner, state: &mut EventState, id: &SampleId, mapping: &Mapping) {
+    let mut record = Record::new(PERF_RECORD_MMAP, PERF_RECORD_MISC_USER);
+    record.push_pair(id.pid, id.tid);
+    record.push(mapping.addr as u64);
+    record.push(mapping.len as u64);
+    record.push(mapping.offset as u64);
+    record.push_str(&mapping.path);
+    inner.push_sample_id(&mut record, id);
+    inner.output(state, &record, id, false);
+}
+
+/// Code segments of the program at `path` and of its interpreter,
+/// where `Thread::new_user_vm` loads them
+fn exec_mappings(path: &str, inode: &Arc<dyn INode>) -> Vec<Mapping> {
+    let mut mappings = Vec::new();
+    // the headers fit in as many bytes as `new_user_vm` reads
+    let mut data = [0u8; 0x3c0];
+    let elf = match inode.read_at(0, &mut data) {
+        Ok(_) => ElfFile::new(&data),
+        Err(_) => return mappings,
+    };
+    let elf = match elf {
+        Ok(elf) => elf,
+        Err(_) => return mappings,
+    };
+    let bias = push_code_segments(&elf, path, 0, &mut mappings);
+    if let Ok(interp) = elf.get_interpreter() {
+        let mut interp_data = [0u8; 0x3c0];
+        if let Ok(interp_inode) = ROOT_INODE.lookup_follow(interp, FOLLOW_MAX_DEPTH) {
+            if interp_inode.read_at(0, &mut interp_data).is_ok() {
+                if let Ok(interp_elf) = ElfFile::new(&interp_data) {
+                    push_code_segments(&interp_elf, interp, bias, &mut mappings);
+                }
+            }
+        }
+    }
+    mappings
+}
+
+/// Push the executable segments of `elf` loaded at `bias`,
+/// and return the page after all of its segments
+fn push_code_segments(
+    elf: &ElfFile,
+    path: &str,
+    bias: usize,
+    mappings: &mut Vec<Mapping>,
+) -> usize {
+    let mut end = 0;
+    for ph in elf.program_iter() {
+        if ph.get_type() != Ok(Type::Load) {
+            continue;
+        }
+        let start = ph.virtual_addr() as usize + bias;
+        end = end.max(start + ph.mem_size() as usize);
+        if !ph.flags().is_execute() {
+            continue;
+        }
+        let addr = start & !(PAGE_SIZE - 1);
+        mappings.push(Mapping {
+            path: String::from(path),
+            addr,
+            len: start + ph.mem_size() as usize - addr,
+            offset: ph.offset() as usize & !(PAGE_SIZE - 1),
+        });
+    }
+    (end + PAGE_SIZE) & !(PAGE_SIZE - 1)
+}
@@ -5,8 +5,10 @@ use super::{
 use crate::arch::paging::*;
 use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::{SemProc, ShmProc};
+use crate::kprobes::XolArea;
 use crate::memory::{
-    phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
+    phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemCgFrameAlloc, MemCgroup,
+    MemoryAttr, MemorySet, Read,
 };
 use crate::process::thread::THREADS;
 use crate::sync::{Event, EventBus, SpinLock, SpinNoIrqLock as Mutex};
@@ -95,6 +97,9 @@ pub struct Process {
     //// Process group id
     pub pgid: Pgid,
 
+    /// Memory cgroup of the process group, charged for new user pages
+    pub memcg: Arc<MemCgroup>,
+
     /// Parent process
     /// Avoid deadlock, put pid out
     pub parent: (Pid, Weak<Mutex<Process>>),
@@ -122,6 +127,9 @@ pub struct Process {
 
     /// shared memory
     pub shm_identifiers: ShmProc,
+
+    /// Where uprobes execute probed instructions, mapped on the first hit
+    pub xol_area: Option<XolArea>,
 }
 
 lazy_static! {
@@ -183,6 +191,22 @@ impl Process {
         fd
     }
 
+    /// Get the allocator for user pages, charged to the memory cgroup
+    pub fn frame_allocator(&self) -> MemCgFrameAlloc {
+        MemCgFrameAlloc::new(self.memcg.clone())
+    }
+
+    /// Move the process to another memory cgroup, along with its private pages
+    pub fn set_memcg(&mut self, memcg: Arc<MemCgroup>) {
+        if Arc::ptr_eq(&self.memcg, &memcg) {
+            return;
+        }
+        let allocator = MemCgFrameAlloc::new(memcg.clone());
+        let pages = self.vm.lock().set_allocator(&allocator);
+        self.memcg.move_charge(&memcg, pages);
+        self.memcg = memcg;
+    }
+
     /// Get futex by addr
     pub fn get_futex(&mut self, uaddr: usize) -> Arc<Futex> {
         if !self.futexes.contains_key(&uaddr) {
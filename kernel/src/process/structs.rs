@@ -3,7 +3,8 @@ use crate::arch::paging::*;
 use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::SemProc;
 use crate::memory::{
-    phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
+    phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemCgFrameAlloc, MemoryAttr,
+    MemorySet, Read,
 };
 use crate::sync::{SpinLock, SpinNoIrqLock as Mutex};
 use crate::{
@@ -33,6 +34,8 @@ use trapframe::UserContext;
 use xmas_elf::{
     header,
     program::{Flags, SegmentData, Type},
+    sections::SectionData,
+    symbol_table::Entry,
     ElfFile,
 };
 
@@ -56,7 +59,12 @@ impl ToMemoryAttr for Flags {
 /// Helper functions to process ELF file
 pub trait ElfExt {
     /// Setup MemorySet according to the ELF file.
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize;
+    fn make_memory_set(
+        &self,
+        ms: &mut MemorySet,
+        inode: &Arc<dyn INode>,
+        allocator: &MemCgFrameAlloc,
+    ) -> usize;
 
     /// Get interpreter string if it has.
     fn get_interpreter(&self) -> Result<&str, &str>;
@@ -68,14 +76,30 @@ pub trait ElfExt {
         inode: &Arc<dyn INode>,
         memory_set: &mut MemorySet,
         bias: usize,
+        allocator: &MemCgFrameAlloc,
     );
 
     /// Get virtual address of PHDR section if it has.
     fn get_phdr_vaddr(&self) -> Option<u64>;
+
+    /// Get the file offset of the code of symbol `name`, for uprobes.
+    fn symbol_offset(&self, name: &str) -> Option<usize>;
+}
+
+fn find_symbol<E: Entry>(elf: &ElfFile, entries: &[E], name: &str) -> Option<u64> {
+    entries
+        .iter()
+        .find(|sym| sym.value() != 0 && sym.get_name(elf) == Ok(name))
+        .map(|sym| sym.value())
 }
 
 impl ElfExt for ElfFile<'_> {
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize {
+    fn make_memory_set(
+        &self,
+        ms: &mut MemorySet,
+        inode: &Arc<dyn INode>,
+        allocator: &MemCgFrameAlloc,
+    ) -> usize {
         debug!("creating MemorySet from ELF");
         let mut farthest_memory: usize = 0;
         for ph in self.program_iter() {
@@ -91,7 +115,7 @@ impl ElfExt for ElfFile<'_> {
                     mem_start: ph.virtual_addr() as usize,
                     file_start: ph.offset() as usize,
                     file_end: ph.offset() as usize + ph.file_size() as usize,
-                    allocator: GlobalFrameAlloc,
+                    allocator: allocator.clone(),
                 },
                 "elf",
             );
@@ -102,7 +126,13 @@ impl ElfExt for ElfFile<'_> {
 
         Page::of_addr(farthest_memory + PAGE_SIZE).start_address()
     }
-    fn append_as_interpreter(&self, inode: &Arc<dyn INode>, ms: &mut MemorySet, bias: usize) {
+    fn append_as_interpreter(
+        &self,
+        inode: &Arc<dyn INode>,
+        ms: &mut MemorySet,
+        bias: usize,
+        allocator: &MemCgFrameAlloc,
+    ) {
         debug!("inserting interpreter from ELF");
 
         for ph in self.program_iter() {
@@ -118,7 +148,7 @@ impl ElfExt for ElfFile<'_> {
                     mem_start: ph.virtual_addr() as usize + bias,
                     file_start: ph.offset() as usize,
                     file_end: ph.offset() as usize + ph.file_size() as usize,
-                    allocator: GlobalFrameAlloc,
+                    allocator: allocator.clone(),
                 },
                 "elf-interp",
             )
@@ -160,6 +190,24 @@ impl ElfExt for ElfFile<'_> {
             None
         }
     }
+
+    fn symbol_offset(&self, name: &str) -> Option<usize> {
+        // stripped binaries only have the dynamic symbols
+        let vaddr = [".symtab", ".dynsym"].iter().find_map(|section| {
+            match self.find_section_by_name(section)?.get_data(self) {
+                Ok(SectionData::SymbolTable64(entries)) => find_symbol(self, entries, name),
+                Ok(SectionData::DynSymbolTable64(entries)) => find_symbol(self, entries, name),
+                _ => None,
+            }
+        })?;
+        let ph = self.program_iter().find(|ph| {
+            ph.get_type() == Ok(Type::Load)
+                && ph.flags().is_execute()
+                && ph.virtual_addr() <= vaddr
+                && vaddr < ph.virtual_addr() + ph.file_size()
+        })?;
+        Some((vaddr - ph.virtual_addr() + ph.offset()) as usize)
+    }
 }
 
 #[derive(Clone)]
@@ -169,4 +217,8 @@ impl Read for INodeForMap {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
         self.0.read_at(offset, buf).unwrap()
     }
+
+    fn patch_code(&self, offset: usize, buf: &mut [u8]) {
+        crate::kprobes::patch_uprobes(&self.0, offset, buf);
+    }
 }
@@ -3,7 +3,8 @@ use super::{
     add_to_process_table, Pid, Process, PROCESSORS,
 };
 use crate::arch::interrupt::consts::{
-    is_intr, is_page_fault, is_reserved_inst, is_syscall, is_timer_intr,
+    is_breakpoint, is_intr, is_page_fault, is_reserved_inst, is_single_step, is_syscall,
+    is_timer_intr,
 };
 use crate::arch::interrupt::{get_trap_num, handle_reserved_inst};
 use crate::arch::{
@@ -15,8 +16,10 @@ use crate::arch::{
 use crate::drivers::IRQ_MANAGER;
 use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::{SemProc, ShmProc};
+use crate::kprobes::{UProbeTask, XolArea};
 use crate::memory::{
-    phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
+    memcg, phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemCgFrameAlloc,
+    MemoryAttr, MemorySet, Read,
 };
 use crate::process::structs::ElfExt;
 use crate::sync::{EventBus, SpinLock, SpinNoIrqLock as Mutex};
@@ -41,7 +44,7 @@ use log::*;
 use num::FromPrimitive;
 use pc_keyboard::KeyCode::BackTick;
 use rcore_fs::vfs::INode;
-use rcore_memory::{Page, PAGE_SIZE};
+use rcore_memory::{Page, VMResult, PAGE_SIZE};
 use spin::RwLock;
 use trapframe::TrapFrame;
 use trapframe::UserContext;
@@ -54,6 +57,9 @@ use xmas_elf::{
 /// Tid type
 pub type Tid = usize;
 
+/// Error of `Thread::new_user_vm` when user memory can not be allocated
+pub const OUT_OF_MEMORY: &str = "out of memory";
+
 pub struct ThreadContext {
     user: Box<UserContext>,
     /// TODO: lazy fp
@@ -73,6 +79,8 @@ pub struct ThreadInner {
     pub sig_mask: Sigset,
     /// signal alternate stack
     pub signal_alternate_stack: SignalStack,
+    /// uprobes being executed and returned from
+    pub uprobe_task: UProbeTask,
 }
 
 #[allow(dead_code)]
@@ -112,12 +120,14 @@ impl Thread {
     }
 
     /// Construct virtual memory of a new user process from ELF at `inode`.
+    /// User pages are allocated by `allocator`.
     /// Return `(MemorySet, entry_point, ustack_top)`
     pub fn new_user_vm(
         inode: &Arc<dyn INode>,
         args: Vec<String>,
         envs: Vec<String>,
         vm: &mut MemorySet,
+        allocator: &MemCgFrameAlloc,
     ) -> Result<(usize, usize), &'static str> {
         // Read ELF header
         // 0x3c0: magic number from ld-musl.so
@@ -165,7 +175,7 @@ impl Thread {
         let mut entry_addr = elf.header.pt2.entry_point() as usize;
         // Make page table
         vm.clear();
-        let bias = elf.make_memory_set(vm, inode);
+        let bias = elf.make_memory_set(vm, inode, allocator);
 
         // Check interpreter (for dynamic link)
         // When interpreter is used, map both dynamic linker and executable
@@ -181,7 +191,7 @@ impl Thread {
                 .read_at(0, &mut interp_data)
                 .map_err(|_| "failed to read from INode")?;
             let elf_interp = ElfFile::new(&interp_data)?;
-            elf_interp.append_as_interpreter(&interp_inode, vm, bias);
+            elf_interp.append_as_interpreter(&interp_inode, vm, bias, allocator);
 
             // update auxiliary vector
             auxv.insert(abi::AT_ENTRY, elf.header.pt2.entry_point() as usize);
@@ -203,7 +213,7 @@ impl Thread {
                 ustack_buttom,
                 ustack_top - PAGE_SIZE * 4,
                 MemoryAttr::default().user().execute(),
-                Delay::new(GlobalFrameAlloc),
+                Delay::new(allocator.clone()),
                 "user_stack_delay",
             );
 
@@ -212,9 +222,12 @@ impl Thread {
                 ustack_top - PAGE_SIZE * 4,
                 ustack_top,
                 MemoryAttr::default().user().execute(), // feature
-                ByFrame::new(GlobalFrameAlloc),
+                ByFrame::new(allocator.clone()),
                 "user_stack",
             );
+            if !vm.populate(ustack_top - PAGE_SIZE * 4, ustack_top) {
+                return Err(OUT_OF_MEMORY);
+            }
             ustack_top
         };
 
@@ -236,7 +249,10 @@ impl Thread {
     ) -> Arc<Thread> {
         // get virtual memory info
         let mut vm = MemorySet::new();
-        let (entry_addr, ustack_top) = Self::new_user_vm(inode, args, envs, &mut vm).unwrap();
+        let memcg = memcg(0);
+        let allocator = MemCgFrameAlloc::new(memcg.clone());
+        let (entry_addr, ustack_top) =
+            Self::new_user_vm(inode, args, envs, &mut vm, &allocator).unwrap();
 
         let vm_token = vm.token();
         let vm = Arc::new(Mutex::new(vm));
@@ -329,6 +345,7 @@ impl Thread {
                 clear_child_tid: 0,
                 sig_mask: Sigset::default(),
                 signal_alternate_stack: SignalStack::default(),
+                uprobe_task: UProbeTask::default(),
             }),
             vm: vm.clone(),
             proc: Arc::new(Mutex::new(Process {
@@ -340,6 +357,7 @@ impl Thread {
                 semaphores: SemProc::default(),
                 pid: Pid::new(), // allocated later
                 pgid: 0,
+                memcg,
                 parent: (Pid::new(), Weak::new()),
                 children: Vec::new(),
                 threads: Vec::new(),
@@ -349,6 +367,7 @@ impl Thread {
                 dispositions: [SignalAction::default(); Signal::RTMAX + 1],
                 eventbus: EventBus::new(),
                 shm_identifiers: ShmProc::default(),
+                xol_area: None,
             })),
         };
 
@@ -362,9 +381,9 @@ impl Thread {
 
     /// Fork a new process from current one
     /// Only current process is persisted
-    pub fn fork(&self, tf: &UserContext) -> Arc<Thread> {
+    pub fn fork(&self, tf: &UserContext) -> VMResult<Arc<Thread>> {
         // clone virtual memory
-        let vm = self.vm.lock().clone();
+        let vm = self.vm.lock().try_clone()?;
         let vm_token = vm.token();
         let vm = Arc::new(Mutex::new(vm));
 
@@ -383,6 +402,7 @@ impl Thread {
             semaphores: proc.semaphores.clone(),
             pid: Pid::new(), // assigned later
             pgid: proc.pgid,
+            memcg: proc.memcg.clone(),
             parent: (proc.pid.clone(), Arc::downgrade(&self.proc)),
             children: Vec::new(),
             threads: Vec::new(),
@@ -392,6 +412,7 @@ impl Thread {
             dispositions: proc.dispositions.clone(),
             eventbus: EventBus::new(),
             shm_identifiers: proc.shm_identifiers.clone(),
+            xol_area: proc.xol_area.as_ref().map(XolArea::fork),
         }));
 
         // new thread
@@ -401,6 +422,7 @@ impl Thread {
         // mask; the signal mask is preserved across execve(2).
         let sig_mask = self.inner.lock().sig_mask;
         let sigaltstack = self.inner.lock().signal_alternate_stack;
+        let uprobe_task = self.inner.lock().uprobe_task.fork();
         let new_thread = Thread {
             tid: 0, // allocated below
             inner: Mutex::new(ThreadInner {
@@ -411,6 +433,7 @@ impl Thread {
                 clear_child_tid: 0,
                 sig_mask,
                 signal_alternate_stack: sigaltstack,
+                uprobe_task,
             }),
             vm,
             proc: new_proc,
@@ -426,7 +449,7 @@ impl Thread {
         proc.children
             .push((child_pid, Arc::downgrade(&new_thread.proc)));
 
-        new_thread
+        Ok(new_thread)
     }
 
     /// Create a new thread in the same process.
@@ -456,6 +479,7 @@ impl Thread {
                 context: Some(thread_context),
                 sig_mask,
                 signal_alternate_stack: sigaltstack,
+                uprobe_task: UProbeTask::default(),
             }),
             vm: self.vm.clone(),
             proc: self.proc.clone(),
@@ -515,6 +539,7 @@ pub fn spawn(thread: Arc<Thread>) {
                     // page fault
                     let addr = get_page_fault_addr();
                     info!("page fault from user @ {:#x}", addr);
+                    let handled;
                     #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
                     {
                         use crate::arch::interrupt::consts::{
@@ -533,26 +558,44 @@ pub fn spawn(thread: Arc<Thread>) {
                             }
                             _ => unreachable!(),
                         };
-                        if !handle_user_page_fault_ext(&thread, addr, access_type) {
-                            // TODO: SIGSEGV
-                            panic!("page fault handle failed");
-                        }
+                        crate::tracepoint::trace_page_fault(addr, Some(access_type), true);
+                        handled = handle_user_page_fault_ext(&thread, addr, access_type);
                     }
                     #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
                     {
                         use crate::arch::interrupt::handle_user_page_fault;
-                        if !handle_user_page_fault(&thread, addr) {
+                        crate::tracepoint::trace_page_fault(addr, None, true);
+                        handled = handle_user_page_fault(&thread, addr);
+                    }
+                    if !handled {
+                        if crate::memory::out_of_memory() {
+                            // retry after the victim releases its memory
+                            do_yield = true;
+                        } else {
                             // TODO: SIGSEGV
                             panic!("page fault handle failed");
                         }
                     }
                 }
-                _ if is_syscall(trap_num) => exit = handle_syscall(&thread, cx).await,
+                // before syscalls, they share the trap number on aarch64
+                _ if is_breakpoint(trap_num)
+                    && crate::kprobes::uprobe_breakpoint_handler(&thread, cx) => {}
+                _ if is_single_step(trap_num)
+                    && crate::kprobes::uprobe_single_step_handler(&thread, cx) => {}
+                _ if is_syscall(trap_num) => {
+                    exit = handle_syscall(&thread, cx).await;
+                    // charges failed in the kernel on behalf of the syscall,
+                    // like faulting in user memory or copying it for fork
+                    if crate::memory::out_of_memory() {
+                        do_yield = true;
+                    }
+                }
                 _ if is_intr(trap_num) => {
                     crate::arch::interrupt::ack(trap_num);
                     trace!("handle irq {:#x}", trap_num);
                     if is_timer_intr(trap_num) {
                         do_yield = true;
+                        crate::perf::user_tick(&thread, cx);
                         crate::arch::interrupt::timer();
                     }
                     IRQ_MANAGER.read().try_handle_interrupt(Some(trap_num));
@@ -581,6 +624,7 @@ pub fn spawn(thread: Arc<Thread>) {
             thread.end_running(thread_context);
             if exit {
                 info!("thread {} stopped", thread.tid);
+                crate::perf::task_exit(&thread);
                 break;
             } else if do_yield {
                 yield_now().await;
@@ -619,6 +663,7 @@ impl Future for PageTableSwitchWrapper {
         unsafe {
             PROCESSORS[cpu_id] = Some(self.thread.clone());
         }
+        crate::tracepoint::trace_sched_switch(&self.thread);
         // vmtoken won't change
         set_page_table(self.vmtoken);
         let res = self.inner.lock().as_mut().poll(cx);
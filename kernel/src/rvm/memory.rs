@@ -1,6 +1,7 @@
 //! Used for delay mapping host's virtual memory to guest's physical memory
 
 use alloc::{boxed::Box, sync::Arc};
+use core::any::Any;
 
 use rvm::RvmPageTable;
 use rvm::{DefaultGuestPhysMemorySet, GuestMemoryAttr, GuestPhysAddr, HostVirtAddr};
@@ -57,12 +58,15 @@ impl<T: FrameAllocator> MemoryHandler for RvmPageTableHandlerDelay<T> {
         src_pt: &mut dyn PageTable,
         addr: HostVirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         let entry = src_pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
             // eager map and copy data
             let data = src_pt.get_page_slice_mut(addr);
-            let target = self.allocator.alloc().expect("failed to alloc frame");
+            let target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => return false,
+            };
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
@@ -70,6 +74,7 @@ impl<T: FrameAllocator> MemoryHandler for RvmPageTableHandlerDelay<T> {
             // delay map
             self.map(pt, addr, attr);
         }
+        true
     }
 
     fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: HostVirtAddr) -> bool {
@@ -83,7 +88,10 @@ impl<T: FrameAllocator> MemoryHandler for RvmPageTableHandlerDelay<T> {
         let mut rvm_pt = self.gpm.rvm_page_table.lock();
         let mut target = rvm_pt.query(guest_paddr).unwrap_or(0);
         if target == 0 {
-            target = self.allocator.alloc().expect("failed to alloc frame");
+            target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => return false,
+            };
         }
         info!("guest_paddr={}, target={}", guest_paddr, target);
         rvm_pt
@@ -95,4 +103,14 @@ impl<T: FrameAllocator> MemoryHandler for RvmPageTableHandlerDelay<T> {
         entry.update();
         true
     }
+
+    fn set_allocator(&mut self, allocator: &dyn Any) -> bool {
+        match allocator.downcast_ref::<T>() {
+            Some(allocator) => {
+                self.allocator = allocator.clone();
+                true
+            }
+            None => false,
+        }
+    }
 }
@@ -28,8 +28,7 @@ fn into_fs_error(e: RvmError) -> FsError {
 }
 
 mod rvm_extern_fn {
-    use crate::memory::{alloc_frame_contiguous, dealloc_frame, phys_to_virt};
-    use rvm::PAGE_SIZE;
+    use crate::memory::{alloc_frame_contiguous, dealloc_frame_contiguous, phys_to_virt};
     #[rvm::extern_fn(alloc_frames)]
     fn rvm_alloc_frames(n: usize, align_log2: usize) -> Option<usize> {
         alloc_frame_contiguous(n, align_log2)
@@ -37,11 +36,7 @@ mod rvm_extern_fn {
 
     #[rvm::extern_fn(dealloc_frames)]
     fn rvm_dealloc_frames(paddr: usize, n: usize, _align_log2: usize) {
-        for i in 0..n {
-            dealloc_frame(paddr + i * PAGE_SIZE)
-        }
-        //use crate::memory::dealloc_frame_contiguous;
-        //dealloc_frame_contiguous(paddr, n, align_log2)
+        dealloc_frame_contiguous(paddr, n)
     }
 
     #[rvm::extern_fn(phys_to_virt)]
@@ -8,7 +8,6 @@ use rvm::{DefaultGuestPhysMemorySet, GuestPhysAddr, HostVirtAddr, RvmResult};
 use rvm::{Guest as GuestInner, Vcpu as VcpuInner};
 
 use super::memory::RvmPageTableHandlerDelay;
-use crate::memory::GlobalFrameAlloc;
 
 pub(super) struct Guest {
     gpm: Arc<DefaultGuestPhysMemorySet>,
@@ -36,8 +35,8 @@ impl Guest {
         self.inner.add_memory_region(gpaddr, size, None)?;
         let thread = crate::process::current_thread().unwrap();
         let hvaddr = thread.vm.lock().find_free_area(PAGE_SIZE, size);
-        let handler =
-            RvmPageTableHandlerDelay::new(gpaddr, hvaddr, self.gpm.clone(), GlobalFrameAlloc);
+        let allocator = thread.proc.lock().frame_allocator();
+        let handler = RvmPageTableHandlerDelay::new(gpaddr, hvaddr, self.gpm.clone(), allocator);
         thread.vm.lock().push(
             hvaddr,
             hvaddr + size,
@@ -93,18 +93,24 @@ impl Signal {
 
 // process and tid must be checked
 pub fn send_signal(process: Arc<Mutex<Process>>, tid: isize, info: Siginfo) {
-    let signal: Signal = <Signal as FromPrimitive>::from_i32(info.signo).unwrap();
     let mut process = process.lock();
+    send_signal_locked(&mut process, tid, info);
+    info!(
+        "send signal {} to pid {} tid {}",
+        info.signo, process.pid, tid
+    )
+}
+
+/// `send_signal` to a process already locked, without logging,
+/// so that probes can call it
+pub fn send_signal_locked(process: &mut Process, tid: isize, info: Siginfo) {
+    let signal: Signal = <Signal as FromPrimitive>::from_i32(info.signo).unwrap();
     if signal.is_standard() && process.pending_sigset.contains(signal) {
         return;
     }
     process.sig_queue.push_back((info, tid));
     process.pending_sigset.add(signal);
     process.eventbus.lock().set(Event::RECEIVE_SIGNAL);
-    info!(
-        "send signal {} to pid {} tid {}",
-        info.signo, process.pid, tid
-    )
 }
 
 /// See musl struct __ucontext
@@ -1,22 +1,14 @@
 use super::*;
 use crate::consts::{INFORM_PER_MSEC, USEC_PER_TICK};
-use crate::process::{Process, Thread};
+use crate::process::Thread;
 use crate::syscall::TimeSpec;
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-pub struct RegisteredProcess {
-    proc: Arc<SpinNoIrqLock<Process>>,
-    tid: usize,
-    epfd: usize,
-    fd: usize,
-}
-
 #[derive(Default)]
 pub struct Condvar {
     wait_queue: SpinNoIrqLock<VecDeque<Arc<Thread>>>,
-    pub epoll_queue: SpinNoIrqLock<VecDeque<RegisteredProcess>>,
 }
 
 impl Condvar {
@@ -138,8 +130,7 @@ impl Condvar {
 
     pub fn notify_one(&self) {
         let mut queue = self.wait_queue.lock();
-        if let Some(t) = queue.front() {
-            self.epoll_callback(t);
+        if let Some(_t) = queue.front() {
             // info!("nofity thread: {}", t.id());
             //t.unpark();
             queue.pop_front();
@@ -148,8 +139,7 @@ impl Condvar {
 
     pub fn notify_all(&self) {
         let mut queue = self.wait_queue.lock();
-        for t in queue.iter() {
-            self.epoll_callback(t);
+        for _t in queue.iter() {
             //t.unpark();
         }
         queue.clear();
@@ -160,11 +150,10 @@ impl Condvar {
     pub fn notify_n(&self, n: usize) -> usize {
         let mut count = 0;
         let mut queue = self.wait_queue.lock();
-        for t in queue.iter() {
+        for _t in queue.iter() {
             if count >= n {
                 break;
             }
-            self.epoll_callback(t);
             //t.unpark();
             count += 1;
         }
@@ -173,52 +162,4 @@ impl Condvar {
         }
         count
     }
-
-    pub fn register_epoll_list(
-        &self,
-        proc: Arc<SpinNoIrqLock<Process>>,
-        tid: usize,
-        epfd: usize,
-        fd: usize,
-    ) {
-        self.epoll_queue.lock().push_back(RegisteredProcess {
-            proc: proc,
-            tid: tid,
-            epfd: epfd,
-            fd: fd,
-        });
-    }
-
-    pub fn unregister_epoll_list(&self, tid: usize, epfd: usize, fd: usize) -> bool {
-        let mut epoll_list = self.epoll_queue.lock();
-        for idx in 0..epoll_list.len() {
-            if epoll_list[idx].tid == tid
-                && epoll_list[idx].epfd == epfd
-                && epoll_list[idx].fd == fd
-            {
-                epoll_list.remove(idx);
-                return true;
-            }
-        }
-        return false;
-    }
-
-    fn epoll_callback(&self, thread: &Arc<Thread>) {
-        let epoll_list = self.epoll_queue.lock();
-        for ist in epoll_list.iter() {
-            //if thread.id() == ist.tid {
-            if true {
-                let proc = ist.proc.lock();
-                match proc.get_epoll_instance(ist.epfd) {
-                    Ok(instacne) => {
-                        let mut ready_list = instacne.ready_list.lock();
-                        ready_list.insert(ist.fd);
-                    }
-                    Err(_) => {
-                        panic!("epoll instance not exist");
-                    }
-                }
-            }
-        }
-    }
 }
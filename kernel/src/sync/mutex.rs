@@ -93,6 +93,13 @@ impl<T, S: MutexSupport> Mutex<T, S> {
         let Mutex { data, .. } = self;
         data.into_inner()
     }
+
+    /// Offset of the data in the mutex, for the BTF of kernel structures
+    pub fn data_offset() -> usize {
+        let mutex = MaybeUninit::<Self>::uninit();
+        let base = mutex.as_ptr();
+        unsafe { core::ptr::addr_of!((*base).data) as usize - base as usize }
+    }
 }
 
 impl<T: ?Sized, S: MutexSupport> Mutex<T, S> {
@@ -5,7 +5,9 @@ use crate::bpf::map::*;
 use crate::bpf::program::*;
 use crate::bpf::tracepoints::*;
 
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::cmp::min;
 
 impl Syscall<'_> {
     pub fn sys_bpf(&self, cmd: usize, attr_ptr: usize, _size: usize) -> SysResult {
@@ -30,6 +32,36 @@ impl Syscall<'_> {
                 let target = check_and_clone_cstr(attach_attr.target)?;
                 bpf_program_attach(&target, attach_attr.prog_fd)
             }
+            BPF_PROG_DETACH => {
+                let ptr = UserInPtr::<AttachTarget>::from(attr_ptr);
+                let detach_attr = ptr.read()?;
+                let target = check_and_clone_cstr(detach_attr.target)?;
+                bpf_program_detach(&target, detach_attr.prog_fd)
+            }
+            BPF_PROG_LOAD => {
+                let ptr = UserInPtr::<ProgramLoadAttr>::from(attr_ptr);
+                let attr = ptr.read()?;
+                let insns = unsafe {
+                    self.vm()
+                        .check_read_array(attr.insns as *const u64, attr.insn_cnt as usize)?
+                };
+                let license = check_and_clone_cstr(attr.license as *const u8)?;
+                let log_size = attr.log_size as usize;
+                if attr.log_level != 0 && (attr.log_buf == 0 || log_size < BPF_LOG_MIN_SIZE) {
+                    return Err(EINVAL);
+                }
+                let mut log = String::new();
+                let result = bpf_program_load(attr.prog_type, insns.to_vec(), &license, &mut log);
+                if attr.log_level != 0 {
+                    let log_buf = attr.log_buf as *mut u8;
+                    let buf = unsafe { self.vm().check_write_array(log_buf, log_size)? };
+                    // truncated and NUL terminated
+                    let len = min(log.len(), log_size - 1);
+                    buf[..len].copy_from_slice(&log.as_bytes()[..len]);
+                    buf[len] = 0;
+                }
+                result
+            }
             // NOTE: non-standard command
             BPF_PROG_LOAD_EX => {
                 let ptr = UserInPtr::<ProgramLoadExAttr>::from(attr_ptr);
@@ -60,7 +92,7 @@ impl Syscall<'_> {
         let value = op_attr.value as *mut u8;
         // sizes
         let key_sz = map_attr.key_size;
-        let val_sz = map_attr.value_size;
+        let val_sz = map_attr.user_value_size();
         // we always need to read the key
         let _ = unsafe { vm.check_read_array(key, key_sz)? };
         match op {
@@ -24,9 +24,10 @@ use super::*;
 use crate::fs::epoll::EpollInstance;
 use crate::fs::fcntl::{FD_CLOEXEC, F_SETFD, O_CLOEXEC, O_NONBLOCK};
 use crate::fs::FileLike;
+use crate::net::retry_on_activity;
 use crate::process::Process;
 use crate::syscall::SysError::{EINTR, EINVAL, ESPIPE};
-use rcore_fs::vfs::PollStatus;
+use core::time::Duration;
 
 impl Syscall<'_> {
     pub async fn sys_read(&mut self, fd: usize, base: UserOutPtr<u8>, len: usize) -> SysResult {
@@ -38,19 +39,26 @@ impl Syscall<'_> {
         let slice = unsafe { self.vm().check_write_array(base.ptr(), len)? };
 
         let file_like = proc.get_file_like(fd)?;
+        if let FileLike::Socket(_) = file_like {
+            // wait for sockets without holding the process
+            let mut socket = file_like.clone();
+            drop(proc);
+            return socket.read(slice).await;
+        }
         let len = file_like.read(slice).await?;
         Ok(len)
     }
 
-    pub fn sys_write(&mut self, fd: usize, base: *const u8, len: usize) -> SysResult {
+    pub async fn sys_write(&mut self, fd: usize, base: *const u8, len: usize) -> SysResult {
         let mut proc = self.process();
         if !proc.pid.is_init() {
             //we trust pid 0 process
             info!("write: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
         }
         let slice = unsafe { self.vm().check_read_array(base, len)? };
-        let file_like = proc.get_file_like(fd)?;
-        let len = file_like.write(slice)?;
+        let mut file_like = proc.get_file_like(fd)?.clone();
+        drop(proc);
+        let len = file_like.write(slice).await?;
         Ok(len)
     }
 
@@ -345,7 +353,7 @@ impl Syscall<'_> {
         return Ok(ret);
     }
 
-    pub fn sys_epoll_wait(
+    pub async fn sys_epoll_wait(
         &mut self,
         epfd: usize,
         events: *mut EpollEvent,
@@ -353,9 +361,10 @@ impl Syscall<'_> {
         timeout: usize,
     ) -> SysResult {
         self.sys_epoll_pwait(epfd, events, maxevents, timeout, 0)
+            .await
     }
 
-    pub fn sys_epoll_pwait(
+    pub async fn sys_epoll_pwait(
         &mut self,
         epfd: usize,
         events: *mut EpollEvent,
@@ -365,166 +374,65 @@ impl Syscall<'_> {
     ) -> SysResult {
         info!("epoll_pwait: epfd: {}, timeout: {:?}", epfd, timeout_msecs);
 
-        let proc = self.process();
-        let events = unsafe { self.vm().check_write_array(events, maxevents)? };
-        let epoll_instance = proc.get_epoll_instance(epfd)?;
-
-        // add new fds which are registered by epoll_ctl after latest epoll_pwait
-        epoll_instance.ready_list.lock().clear();
-        epoll_instance
-            .ready_list
-            .lock()
-            .extend(epoll_instance.new_ctl_list.lock().clone());
-        epoll_instance.new_ctl_list.lock().clear();
-
-        // if registered fd has data to handle and its mode isn't epollet, we need
-        // to add it to the list.
-        let keys: Vec<_> = epoll_instance.events.keys().cloned().collect();
-        for (k, v) in epoll_instance.events.iter() {
-            if !v.contains(EpollEvent::EPOLLET) {
-                match &proc.files.get(k) {
-                    None => {
-                        //      return Err(SysError::EINVAL);
-                    }
-                    Some(file_like) => {
-                        let status = file_like.poll()?;
-                        if status.write || status.read || status.error {
-                            let mut ready_list = epoll_instance.ready_list.lock();
-                            ready_list.insert(*k);
-                        }
-                    }
-                }
-            }
+        if maxevents as i32 <= 0 {
+            return Err(SysError::EINVAL);
         }
-        drop(proc);
+        let events = unsafe { self.vm().check_write_array(events, maxevents)? };
+        self.process().get_epoll_instance(epfd)?;
 
-        let mut callbacks = alloc::vec![];
-        for fd in &keys {
+        // negative to wait forever, zero to return at once
+        let timeout_msecs = timeout_msecs as i32;
+        let timeout = if timeout_msecs < 0 {
+            None
+        } else {
+            Some(Duration::from_millis(timeout_msecs as u64))
+        };
+        let scan = || {
             let proc = self.process();
-            match proc.files.get(&fd) {
-                Some(file_like) => {
-                    match file_like {
-                        FileLike::File(_file) => {
-                            callbacks.push((
-                                0, 0, // thread::current().id(),
-                                epfd, *fd,
-                            ));
-                        }
-                        FileLike::Socket(_socket) => {
-                            let _ = &(*crate::drivers::SOCKET_ACTIVITY).register_epoll_list(
-                                self.thread.proc.clone(),
-                                0,
-                                //thread::current().id(),
-                                epfd,
-                                *fd,
-                            );
-                            callbacks.push((
-                                1, 0, //thread::current().id(),
-                                epfd, *fd,
-                            ));
-                        }
-                        FileLike::EpollInstance(_) => {
-                            return Err(SysError::EINVAL);
-                        }
-                    };
+            let epoll_instance = proc.get_epoll_instance(epfd)?;
+            let mut events_num = 0;
+            for (&fd, event) in epoll_instance.events.iter() {
+                if events_num == events.len() {
+                    break;
                 }
-                None => {}
-            }
-            drop(proc);
-        }
-
-        let condvars = alloc::vec![&(*TICK_ACTIVITY), &(*SOCKET_ACTIVITY)];
-
-        let begin_time_ms = crate::trap::uptime_msec();
-        let condition = move || {
-            let mut proc = self.process();
-
-            let epoll_instance = match proc.get_epoll_instance_mut(epfd) {
-                Ok(ins) => ins,
-                Err(err) => {
-                    return Some(Err(err));
+                let file_like = match proc.files.get(&fd) {
+                    Some(FileLike::EpollInstance(_)) => return Err(SysError::EINVAL),
+                    Some(file_like) => file_like,
+                    None => continue,
+                };
+                let status = file_like.poll()?;
+                let mut ready = 0;
+                if status.read {
+                    ready |= EpollEvent::EPOLLIN;
                 }
-            };
-            let ready_list = epoll_instance.ready_list.lock().clone();
-            let mut events_num = 0;
-
-            for infd in ready_list.iter() {
-                let mut status: PollStatus = Default::default();
-                {
-                    if let Some(file_like) = proc.files.get(&infd) {
-                        let _status = match file_like.poll() {
-                            Ok(ret) => ret,
-                            Err(err) => return Some(Err(err)),
-                        };
-                        status.write = _status.write;
-                        status.read = _status.read;
-                        status.error = _status.error;
-                    }
+                if status.write {
+                    ready |= EpollEvent::EPOLLOUT;
                 }
-
-                {
-                    let epoll_instance = match proc.get_epoll_instance_mut(epfd) {
-                        Ok(ins) => ins,
-                        Err(err) => {
-                            return Some(Err(err));
-                        }
-                    };
-                    let epollevent = epoll_instance.events.get_mut(&infd)?;
-
-                    if status.error {
-                        events[events_num].events = EpollEvent::EPOLLERR;
-                        events[events_num].data = epollevent.data;
-
-                        events_num += 1;
-                    }
-                    if status.read && epollevent.contains(EpollEvent::EPOLLIN) {
-                        events[events_num].events = EpollEvent::EPOLLIN;
-                        events[events_num].data = epollevent.data;
-                        events_num += 1;
-                    }
-                    if status.write && epollevent.contains(EpollEvent::EPOLLOUT) {
-                        events[events_num].events = EpollEvent::EPOLLOUT;
-                        events[events_num].data = epollevent.data;
-
-                        events_num += 1;
-                    }
+                if status.error {
+                    ready |= EpollEvent::EPOLLERR;
                 }
-            }
-
-            {
-                let epoll_instance = match proc.get_epoll_instance_mut(epfd) {
-                    Ok(ins) => ins,
-                    Err(err) => {
-                        return Some(Err(err));
-                    }
+                // only sockets report activity, so edge-triggered ones fire on new data
+                let activity = match file_like {
+                    FileLike::Socket(_) => Some(crate::net::activity_count()),
+                    _ => None,
                 };
-                epoll_instance.ready_list.lock().clear();
-            }
-
-            drop(proc);
-
-            // some event happens, so evoke the process
-            if events_num > 0 {
-                return Some(Ok(events_num));
+                let ready = epoll_instance.ready_events(fd, ready, activity);
+                if ready != 0 {
+                    events[events_num].events = ready;
+                    events[events_num].data = event.data;
+                    events_num += 1;
+                }
             }
-
-            let current_time_ms = crate::trap::uptime_msec();
-            // time runs out, so the evoke the process
-            if timeout_msecs < (1 << 31) && current_time_ms - begin_time_ms > timeout_msecs {
-                return Some(Ok(0));
+            if events_num == 0 {
+                return Err(SysError::EAGAIN);
             }
-            return None;
+            Ok(events_num)
         };
-
-        let num = Condvar::wait_events(condvars.as_slice(), condition).unwrap();
-
-        for cb in callbacks.iter() {
-            match cb.0 {
-                1 => &(*crate::drivers::SOCKET_ACTIVITY).unregister_epoll_list(cb.1, cb.2, cb.3),
-                _ => panic!("cb error"),
-            };
+        match retry_on_activity(timeout_msecs == 0, timeout, scan).await {
+            // time runs out
+            Err(SysError::EAGAIN) => Ok(0),
+            result => result,
         }
-        Ok(num)
     }
 
     pub async fn sys_readv(
@@ -544,13 +452,25 @@ impl Syscall<'_> {
         // read all data to a buf
         let file_like = proc.get_file_like(fd)?;
         let mut buf = iovs.new_buf(true);
-        let len = file_like.read(buf.as_mut_slice()).await?;
+        let len = if let FileLike::Socket(_) = file_like {
+            // wait for sockets without holding the process
+            let mut socket = file_like.clone();
+            drop(proc);
+            socket.read(buf.as_mut_slice()).await?
+        } else {
+            file_like.read(buf.as_mut_slice()).await?
+        };
         // copy data to user
         iovs.write_all_from_slice(&buf[..len]);
         Ok(len)
     }
 
-    pub fn sys_writev(&mut self, fd: usize, iov_ptr: *const IoVec, iov_count: usize) -> SysResult {
+    pub async fn sys_writev(
+        &mut self,
+        fd: usize,
+        iov_ptr: *const IoVec,
+        iov_count: usize,
+    ) -> SysResult {
         let mut proc = self.process();
         if !proc.pid.is_init() {
             // we trust pid 0 process
@@ -567,8 +487,9 @@ impl Syscall<'_> {
         let iovs = unsafe { IoVecs::check_and_new(iov_ptr, iov_count, &self.vm(), false)? };
 
         let buf = iovs.read_all_to_vec();
-        let file_like = proc.get_file_like(fd)?;
-        let len = file_like.write(buf.as_slice())?;
+        let mut file_like = proc.get_file_like(fd)?.clone();
+        drop(proc);
+        let len = file_like.write(buf.as_slice()).await?;
         Ok(len)
     }
 
@@ -905,8 +826,8 @@ impl Syscall<'_> {
                 }
             }
             _ => {
-                let mut proc = self.process();
-                let file_like = proc.get_file_like(fd)?;
+                // the request may block or need the process, like running a vcpu
+                let mut file_like = self.process().get_file_like(fd)?.clone();
                 file_like.ioctl(request, arg1, arg2, arg3)
             }
         }
@@ -1342,11 +1263,29 @@ impl Syscall<'_> {
                     _ => Ok(0),
                 }
             }
-            FileLike::Socket(_) => {
-                Ok(0)
-                //TODO
+            FileLike::Socket(socket) => {
+                use crate::fs::fcntl::*;
+                match cmd {
+                    F_SETFD => {
+                        socket.set_fd_cloexec((arg & 1) != 0);
+                        Ok(0)
+                    }
+                    F_GETFD => Ok(socket.fd_cloexec() as usize),
+                    F_SETFL => {
+                        socket.options().lock().nonblock = arg & O_NONBLOCK != 0;
+                        Ok(0)
+                    }
+                    F_GETFL => {
+                        if socket.options().lock().nonblock {
+                            Ok(O_RDWR | O_NONBLOCK)
+                        } else {
+                            Ok(O_RDWR)
+                        }
+                    }
+                    _ => Ok(0),
+                }
             }
-            FileLike::EpollInstance(_) => Ok(0),
+            FileLike::EpollInstance(_) | FileLike::PerfEvent(_) => Ok(0),
         }
     }
 }
@@ -1393,6 +1332,37 @@ impl Process {
             "/proc/self/exe" => {
                 return Ok(Arc::new(Pseudo::new(&self.exec_path, FileType::SymLink)));
             }
+            "/proc/meminfo" => {
+                return Ok(Arc::new(Pseudo::new(
+                    &crate::memory::meminfo(),
+                    FileType::File,
+                )));
+            }
+            "/proc/buddyinfo" => {
+                return Ok(Arc::new(Pseudo::new(
+                    &crate::memory::buddyinfo(),
+                    FileType::File,
+                )));
+            }
+            "/proc/slabinfo" => {
+                return Ok(Arc::new(Pseudo::new(
+                    &crate::memory::slabinfo(),
+                    FileType::File,
+                )));
+            }
+            "/proc/slab_alloc_calls" => {
+                let calls = crate::memory::slab_alloc_calls();
+                return Ok(Arc::new(Pseudo::new(&calls, FileType::File)));
+            }
+            "/proc/memcg" => {
+                return Ok(Arc::new(crate::memory::MemCgFile));
+            }
+            // DNS servers from DHCP override the file system
+            "/etc/resolv.conf" => {
+                if let Some(conf) = crate::net::resolv_conf() {
+                    return Ok(Arc::new(Pseudo::new(&conf, FileType::File)));
+                }
+            }
             _ => {}
         }
         let (fd_dir_path, fd_name) = split_path(&path);
@@ -4,7 +4,6 @@ use bitflags::*;
 
 pub use crate::ipc::*;
 
-use crate::memory::GlobalFrameAlloc;
 use rcore_memory::memory_set::handler::{Shared, SharedGuard};
 use rcore_memory::memory_set::MemoryAttr;
 use rcore_memory::{PhysAddr, VirtAddr, PAGE_SIZE};
@@ -113,7 +112,8 @@ impl Syscall<'_> {
     pub fn sys_shmget(&self, key: usize, size: usize, shmflg: usize) -> SysResult {
         info!("shmget: key: {}", key);
 
-        let shared_guard = ShmIdentifier::new_shared_guard(key, size);
+        let allocator = self.process().frame_allocator();
+        let shared_guard = ShmIdentifier::new_shared_guard(key, size, allocator);
         let id = self.process().shm_identifiers.add(shared_guard);
         Ok(id)
     }
@@ -139,7 +139,7 @@ impl Syscall<'_> {
             addr,
             addr + size,
             MemoryAttr::default().user().execute().writable(),
-            Shared::new_with_guard(GlobalFrameAlloc, shm_identifier.shared_guard.clone()),
+            Shared::new_with_guard(proc.frame_allocator(), shm_identifier.shared_guard.clone()),
             "shmat",
         );
         shm_identifier.addr = addr;
@@ -8,11 +8,12 @@ impl Syscall<'_> {
         len: usize,
         param_values: *const u8,
     ) -> SysResult {
-        let _proc = self.process();
+        let proc = self.process();
         let modimg = unsafe { self.vm().check_read_array(module_image, len)? };
         let copied_param_values = check_and_clone_cstr(param_values)?;
 
-        ModuleManager::with(|kmm| kmm.init_module(modimg, &copied_param_values))
+        let allocator = proc.frame_allocator();
+        ModuleManager::with(|kmm| kmm.init_module(modimg, &copied_param_values, allocator))
     }
 
     pub fn sys_delete_module(&mut self, module_name: *const u8, flags: u32) -> SysResult {
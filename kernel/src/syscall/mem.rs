@@ -1,10 +1,12 @@
+use crate::bpf::map::bpf_map_mmap;
+use crate::fs::FileLike;
 use rcore_fs::vfs::MMapArea;
-use rcore_memory::memory_set::handler::{Delay, File, Linear, Shared};
+use rcore_memory::memory_set::handler::{ByHugeFrame, Delay, File, Linear, Shared};
 use rcore_memory::memory_set::MemoryAttr;
-use rcore_memory::PAGE_SIZE;
+use rcore_memory::paging::PageTable;
+use rcore_memory::{Page, HUGE_PAGE_SIZE, PAGE_SIZE};
 
 use super::*;
-use crate::memory::GlobalFrameAlloc;
 
 impl Syscall<'_> {
     pub fn sys_mmap(
@@ -32,20 +34,44 @@ impl Syscall<'_> {
             addr = PAGE_SIZE;
         }
 
+        let huge = flags.contains(MmapFlags::ANONYMOUS | MmapFlags::HUGETLB);
+        let len = if huge {
+            (len + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1)
+        } else {
+            len
+        };
+
         if flags.contains(MmapFlags::FIXED) {
+            if huge && addr % HUGE_PAGE_SIZE != 0 {
+                return Err(SysError::EINVAL);
+            }
             // we have to map it to addr, so remove the old mapping first
             self.vm().pop_with_split(addr, addr + len);
+        } else if huge {
+            // leave space for aligning to huge page
+            addr = self.vm().find_free_area(addr, len + HUGE_PAGE_SIZE);
+            addr = (addr + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1);
         } else {
             addr = self.vm().find_free_area(addr, len);
         }
 
         if flags.contains(MmapFlags::ANONYMOUS) {
-            if flags.contains(MmapFlags::SHARED) {
+            let allocator = proc.frame_allocator();
+            if huge && !flags.contains(MmapFlags::SHARED) {
+                self.vm().push(
+                    addr,
+                    addr + len,
+                    prot.to_attr(),
+                    ByHugeFrame::new(allocator),
+                    "mmap_anon_huge",
+                );
+                return Ok(addr);
+            } else if flags.contains(MmapFlags::SHARED) {
                 self.vm().push(
                     addr,
                     addr + len,
                     prot.to_attr(),
-                    Shared::new(GlobalFrameAlloc),
+                    Shared::new(allocator),
                     "mmap_anon_shared",
                 );
                 return Ok(addr);
@@ -54,13 +80,30 @@ impl Syscall<'_> {
                     addr,
                     addr + len,
                     prot.to_attr(),
-                    Delay::new(GlobalFrameAlloc),
+                    Delay::new(allocator),
                     "mmap_anon",
                 );
                 return Ok(addr);
             }
+        } else if let Some(runs) = bpf_map_mmap(fd, offset, len, prot.contains(MmapProt::WRITE)) {
+            // eBPF maps are not in the file table, they share their pages
+            let mut vaddr = addr;
+            for (paddr, size) in runs? {
+                self.vm().push(
+                    vaddr,
+                    vaddr + size,
+                    prot.to_attr(),
+                    Linear::new(paddr as isize - vaddr as isize),
+                    "mmap_bpf_map",
+                );
+                vaddr += size;
+            }
+            Ok(addr)
         } else {
-            let file_like = proc.get_file_like(fd)?;
+            // the file maps itself with the allocator of current process
+            let mut file_like = proc.get_file_like(fd)?.clone();
+            let pid = proc.pid.get();
+            drop(proc);
             let area = MMapArea {
                 start_vaddr: addr,
                 end_vaddr: addr + len,
@@ -69,6 +112,11 @@ impl Syscall<'_> {
                 offset,
             };
             file_like.mmap(area)?;
+            if let FileLike::File(file) = &file_like {
+                if prot.contains(MmapProt::EXEC) {
+                    crate::perf::task_mmap(&self.thread, pid, addr, len, offset, &file.path);
+                }
+            }
             Ok(addr)
         }
     }
@@ -83,13 +131,14 @@ impl Syscall<'_> {
 
         // TODO: properly set the attribute of the area
         //        now some mut ptr check is fault
-        let vm = self.vm();
+        let mut vm = self.vm();
         let memory_area = vm
             .iter()
             .find(|area| area.is_overlap_with(addr, addr + len));
         if memory_area.is_none() {
             return Err(SysError::ENOMEM);
         }
+        vm.split_huge_pages(addr, addr + len);
         Ok(0)
     }
 
@@ -98,6 +147,109 @@ impl Syscall<'_> {
         self.vm().pop_with_split(addr, addr + len);
         Ok(0)
     }
+
+    pub fn sys_madvise(&mut self, addr: usize, len: usize, advice: usize) -> SysResult {
+        info!(
+            "madvise: addr={:#x}, size={:#x}, advice={}",
+            addr, len, advice
+        );
+        if addr % PAGE_SIZE != 0 {
+            return Err(SysError::EINVAL);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut vm = self.vm();
+        if !vm.test_mapped_area(addr, addr + len) {
+            return Err(SysError::ENOMEM);
+        }
+        match advice {
+            MADV_DONTNEED | MADV_FREE => vm.discard(addr, addr + len),
+            // only a hint, pages failing to be faulted in now are left for later
+            MADV_WILLNEED => {
+                vm.populate(addr, addr + len);
+            }
+            // only hints, safe to ignore
+            MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL => {}
+            _ => return self.unimplemented("madvise advice", Ok(0)),
+        }
+        Ok(0)
+    }
+
+    pub fn sys_mremap(
+        &mut self,
+        old_addr: usize,
+        old_size: usize,
+        new_size: usize,
+        flags: usize,
+        new_addr: usize,
+    ) -> SysResult {
+        let flags = MremapFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        info!(
+            "mremap: old_addr={:#x}, old_size={:#x}, new_size={:#x}, flags={:?}, new_addr={:#x}",
+            old_addr, old_size, new_size, flags, new_addr
+        );
+        if old_addr % PAGE_SIZE != 0 || new_size == 0 {
+            return Err(SysError::EINVAL);
+        }
+        let may_move = flags.contains(MremapFlags::MAYMOVE);
+        let new_addr = if flags.contains(MremapFlags::FIXED) {
+            // the new area must not overlap with the old one
+            if !may_move
+                || new_addr % PAGE_SIZE != 0
+                || (new_addr < old_addr + old_size && old_addr < new_addr + new_size)
+            {
+                return Err(SysError::EINVAL);
+            }
+            Some(new_addr)
+        } else {
+            None
+        };
+        let addr = self
+            .vm()
+            .remap(old_addr, old_size, new_size, may_move, new_addr)?;
+        Ok(addr)
+    }
+
+    pub fn sys_mincore(&mut self, addr: usize, len: usize, mut vec: UserOutPtr<u8>) -> SysResult {
+        info!("mincore: addr={:#x}, size={:#x}, vec={:?}", addr, len, vec);
+        if addr % PAGE_SIZE != 0 {
+            return Err(SysError::EINVAL);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut vm = self.vm();
+        if !vm.test_mapped_area(addr, addr + len) {
+            return Err(SysError::ENOMEM);
+        }
+        let pt = vm.get_page_table_mut();
+        let residency = Page::range_of(addr, addr + len)
+            .map(|page| match pt.get_entry(page.start_address()) {
+                Some(entry) if entry.present() => 1,
+                _ => 0,
+            })
+            .collect::<Vec<u8>>();
+        drop(vm);
+        vec.write_array(&residency)?;
+        Ok(0)
+    }
+}
+
+const MADV_NORMAL: usize = 0;
+const MADV_RANDOM: usize = 1;
+const MADV_SEQUENTIAL: usize = 2;
+const MADV_WILLNEED: usize = 3;
+const MADV_DONTNEED: usize = 4;
+const MADV_FREE: usize = 8;
+
+bitflags! {
+    pub struct MremapFlags: usize {
+        /// The mapping can be moved to a new address
+        const MAYMOVE = 1 << 0;
+        /// Move the mapping to the given new address
+        const FIXED = 1 << 1;
+    }
 }
 
 bitflags! {
@@ -124,6 +276,8 @@ bitflags! {
         const FIXED = 1 << 4;
         /// The mapping is not backed by any file. (non-POSIX)
         const ANONYMOUS = 0x800;
+        /// Allocate the mapping using huge pages.
+        const HUGETLB = 0x80000;
     }
 }
 
@@ -138,6 +292,8 @@ bitflags! {
         const FIXED = 1 << 4;
         /// The mapping is not backed by any file. (non-POSIX)
         const ANONYMOUS = 1 << 5;
+        /// Allocate the mapping using huge pages.
+        const HUGETLB = 0x40000;
     }
 }
 
@@ -7,6 +7,7 @@ use crate::syscall::SysError::ETIMEDOUT;
 use crate::trap::TICK_ACTIVITY;
 use core::mem::size_of;
 use core::sync::atomic::{AtomicI32, Ordering};
+use rcore_memory::PAGE_SIZE;
 
 impl Syscall<'_> {
     #[cfg(target_arch = "x86_64")]
@@ -56,7 +57,13 @@ impl Syscall<'_> {
     pub fn sys_sysinfo(&mut self, sys_info: *mut SysInfo) -> SysResult {
         let sys_info = unsafe { self.vm().check_write_ptr(sys_info)? };
 
-        let sysinfo = SysInfo::default();
+        let stats = crate::memory::frame_stats();
+        let sysinfo = SysInfo {
+            totalram: (stats.total * PAGE_SIZE) as u64,
+            freeram: (stats.free * PAGE_SIZE) as u64,
+            mem_unit: 1,
+            ..SysInfo::default()
+        };
         *sys_info = sysinfo;
         Ok(0)
     }
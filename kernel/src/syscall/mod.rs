@@ -4,9 +4,11 @@ use crate::arch::cpu;
 use crate::arch::syscall::*;
 use crate::fs::epoll::EpollEvent;
 use crate::memory::{copy_from_user, MemorySet};
+use crate::perf::PerfEventAttr;
 use crate::process::*;
 use crate::signal::{Signal, SignalAction, SignalFrame, SignalStack, SignalUserContext, Sigset};
 use crate::sync::{Condvar, MutexGuard, SpinNoIrq};
+use crate::tracepoint;
 use crate::util;
 use alloc::{string::String, sync::Arc, vec::Vec};
 use bitflags::bitflags;
@@ -25,6 +27,7 @@ pub use self::lkm::*;
 pub use self::mem::*;
 pub use self::misc::*;
 pub use self::net::*;
+pub use self::perf::*;
 pub use self::proc::*;
 pub use self::signal::*;
 pub use self::time::*;
@@ -38,21 +41,12 @@ mod lkm;
 mod mem;
 mod misc;
 mod net;
+mod perf;
 mod proc;
 mod signal;
 mod time;
 mod user;
 
-#[cfg(feature = "profile")]
-use crate::sync::SpinNoIrqLock as Mutex;
-#[cfg(feature = "profile")]
-use alloc::collections::BTreeMap;
-
-#[cfg(feature = "profile")]
-lazy_static! {
-    static ref SYSCALL_TIMING: Mutex<BTreeMap<usize, i64>> = Mutex::new(BTreeMap::new());
-}
-
 /// System call dispatcher
 pub async fn handle_syscall(thread: &Arc<Thread>, context: &mut UserContext) -> bool {
     let regs = &context.general;
@@ -74,7 +68,9 @@ pub async fn handle_syscall(thread: &Arc<Thread>, context: &mut UserContext) ->
         context,
         exit: false,
     };
+    tracepoint::trace_sys_enter(num, &args);
     let ret = syscall.syscall(num, args).await;
+    tracepoint::trace_sys_exit(num, ret);
     let exit = syscall.exit;
     context.set_syscall_ret(ret as usize);
     exit
@@ -105,8 +101,6 @@ impl Syscall<'_> {
     // See discussion in https://github.com/oscourse-tsinghua/rcore_plus/commit/17e644e54e494835f1a49b34b80c2c4f15ed0dbe.
     #[deny(unreachable_patterns)]
     async fn syscall(&mut self, id: usize, args: [usize; 6]) -> isize {
-        #[cfg(feature = "profile")]
-        let begin_time = unsafe { core::arch::x86_64::_rdtsc() };
         let cid = cpu::id();
         let pid = self.process().pid.clone();
         let tid = self.thread.tid;
@@ -124,7 +118,7 @@ impl Syscall<'_> {
                 self.sys_read(args[0], UserOutPtr::from(args[1]), args[2])
                     .await
             }
-            SYS_WRITE => self.sys_write(args[0], args[1] as *const u8, args[2]),
+            SYS_WRITE => self.sys_write(args[0], args[1] as *const u8, args[2]).await,
             SYS_OPENAT => self.sys_openat(args[0], args[1] as *const u8, args[2], args[3]),
             SYS_CLOSE => self.sys_close(args[0]),
             SYS_FSTAT => self.sys_fstat(args[0], args[1] as *mut Stat),
@@ -142,7 +136,10 @@ impl Syscall<'_> {
                 self.sys_readv(args[0], UserInPtr::from(args[1]), args[2])
                     .await
             }
-            SYS_WRITEV => self.sys_writev(args[0], args[1] as *const IoVec, args[2]),
+            SYS_WRITEV => {
+                self.sys_writev(args[0], args[1] as *const IoVec, args[2])
+                    .await
+            }
             SYS_SENDFILE => {
                 self.sys_sendfile(args[0], args[1], UserInOutPtr::from(args[2]), args[3])
                     .await
@@ -222,16 +219,21 @@ impl Syscall<'_> {
             SYS_EPOLL_CTL => {
                 self.sys_epoll_ctl(args[0], args[1], args[2], args[3] as *mut EpollEvent)
             }
-            SYS_EPOLL_PWAIT => self.sys_epoll_pwait(
-                args[0],
-                args[1] as *mut EpollEvent,
-                args[2],
-                args[3],
-                args[4],
-            ),
+            SYS_EPOLL_PWAIT => {
+                self.sys_epoll_pwait(
+                    args[0],
+                    args[1] as *mut EpollEvent,
+                    args[2],
+                    args[3],
+                    args[4],
+                )
+                .await
+            }
             SYS_EVENTFD2 => self.unimplemented("eventfd2", Err(SysError::EACCES)),
 
-            SYS_SOCKETPAIR => self.unimplemented("socketpair", Err(SysError::EACCES)),
+            SYS_SOCKETPAIR => {
+                self.sys_socketpair(args[0], args[1], args[2], args[3] as *mut [i32; 2])
+            }
             // file system
             SYS_STATFS => self.unimplemented("statfs", Err(SysError::EACCES)),
             SYS_FSTATFS => self.unimplemented("fstatfs", Err(SysError::EACCES)),
@@ -244,7 +246,9 @@ impl Syscall<'_> {
             SYS_MMAP => self.sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
             SYS_MPROTECT => self.sys_mprotect(args[0], args[1], args[2]),
             SYS_MUNMAP => self.sys_munmap(args[0], args[1]),
-            SYS_MADVISE => self.unimplemented("madvise", Ok(0)),
+            SYS_MADVISE => self.sys_madvise(args[0], args[1], args[2]),
+            SYS_MREMAP => self.sys_mremap(args[0], args[1], args[2], args[3], args[4]),
+            SYS_MINCORE => self.sys_mincore(args[0], args[1], UserOutPtr::from(args[2])),
 
             // signal
             SYS_RT_SIGACTION => self.sys_rt_sigaction(
@@ -273,27 +277,67 @@ impl Syscall<'_> {
 
             // socket
             SYS_SOCKET => self.sys_socket(args[0], args[1], args[2]),
-            SYS_CONNECT => self.sys_connect(args[0], args[1] as *const SockAddr, args[2]),
-            SYS_ACCEPT => self.sys_accept(args[0], args[1] as *mut SockAddr, args[2] as *mut u32),
-            SYS_ACCEPT4 => self.sys_accept(args[0], args[1] as *mut SockAddr, args[2] as *mut u32), // use accept for accept4
-            SYS_SENDTO => self.sys_sendto(
-                args[0],
-                args[1] as *const u8,
-                args[2],
-                args[3],
-                args[4] as *const SockAddr,
-                args[5],
-            ),
-            SYS_RECVFROM => self.sys_recvfrom(
-                args[0],
-                args[1] as *mut u8,
-                args[2],
-                args[3],
-                args[4] as *mut SockAddr,
-                args[5] as *mut u32,
-            ),
-            //        SYS_SENDMSG => self.sys_sendmsg(),
-            SYS_RECVMSG => self.sys_recvmsg(args[0], args[1] as *mut MsgHdr, args[2]),
+            SYS_CONNECT => {
+                self.sys_connect(args[0], args[1] as *const SockAddr, args[2])
+                    .await
+            }
+            SYS_ACCEPT => {
+                self.sys_accept(args[0], args[1] as *mut SockAddr, args[2] as *mut u32, 0)
+                    .await
+            }
+            SYS_ACCEPT4 => {
+                self.sys_accept(
+                    args[0],
+                    args[1] as *mut SockAddr,
+                    args[2] as *mut u32,
+                    args[3],
+                )
+                .await
+            }
+            SYS_SENDTO => {
+                self.sys_sendto(
+                    args[0],
+                    args[1] as *const u8,
+                    args[2],
+                    args[3],
+                    args[4] as *const SockAddr,
+                    args[5],
+                )
+                .await
+            }
+            SYS_RECVFROM => {
+                self.sys_recvfrom(
+                    args[0],
+                    args[1] as *mut u8,
+                    args[2],
+                    args[3],
+                    args[4] as *mut SockAddr,
+                    args[5] as *mut u32,
+                )
+                .await
+            }
+            SYS_SENDMSG => {
+                self.sys_sendmsg(args[0], args[1] as *const MsgHdr, args[2])
+                    .await
+            }
+            SYS_RECVMSG => {
+                self.sys_recvmsg(args[0], args[1] as *mut MsgHdr, args[2])
+                    .await
+            }
+            SYS_SENDMMSG => {
+                self.sys_sendmmsg(args[0], args[1] as *mut MMsgHdr, args[2], args[3])
+                    .await
+            }
+            SYS_RECVMMSG => {
+                self.sys_recvmmsg(
+                    args[0],
+                    args[1] as *mut MMsgHdr,
+                    args[2],
+                    args[3],
+                    args[4] as *const TimeSpec,
+                )
+                .await
+            }
             SYS_SHUTDOWN => self.sys_shutdown(args[0], args[1]),
             SYS_BIND => self.sys_bind(args[0], args[1] as *const SockAddr, args[2]),
             SYS_LISTEN => self.sys_listen(args[0], args[1]),
@@ -435,6 +479,15 @@ impl Syscall<'_> {
             // bpf
             SYS_BPF => self.sys_bpf(args[0], args[1], args[2]),
 
+            // perf
+            SYS_PERF_EVENT_OPEN => self.sys_perf_event_open(
+                args[0] as *const PerfEventAttr,
+                args[1] as isize,
+                args[2] as isize,
+                args[3] as isize,
+                args[4],
+            ),
+
             // custom
             SYS_MAP_PCI_DEVICE => self.sys_map_pci_device(args[0], args[1]),
             SYS_GET_PADDR => {
@@ -462,19 +515,6 @@ impl Syscall<'_> {
             // we trust pid 0 process
             info!("=> {:x?}", ret);
         }
-        #[cfg(feature = "profile")]
-        {
-            let end_time = unsafe { core::arch::x86_64::_rdtsc() };
-            *SYSCALL_TIMING.lock().entry(id).or_insert(0) += end_time - begin_time;
-            if end_time % 1000 == 0 {
-                let timing = SYSCALL_TIMING.lock();
-                let mut count_vec: Vec<(&usize, &i64)> = timing.iter().collect();
-                count_vec.sort_by(|a, b| b.1.cmp(a.1));
-                for (id, time) in count_vec.iter().take(5) {
-                    warn!("timing {:03} time {:012}", id, time);
-                }
-            }
-        }
         match ret {
             Ok(code) => code as isize,
             Err(err) => -(err as isize),
@@ -531,6 +571,7 @@ impl Syscall<'_> {
             SYS_EPOLL_CREATE => self.sys_epoll_create(args[0]),
             SYS_EPOLL_WAIT => {
                 self.sys_epoll_wait(args[0], args[1] as *mut EpollEvent, args[2], args[3])
+                    .await
             }
 
             _ => return None,
@@ -575,6 +616,7 @@ impl Syscall<'_> {
             SYS_EPOLL_CREATE => self.sys_epoll_create(args[0]),
             SYS_EPOLL_WAIT => {
                 self.sys_epoll_wait(args[0], args[1] as *mut EpollEvent, args[2], args[3])
+                    .await
             }
             _ => return None,
         };
@@ -631,14 +673,20 @@ pub enum SysError {
     ELOOP = 40,
     EIDRM = 43,
     ENOTSOCK = 80,
+    EMSGSIZE = 90,
     ENOPROTOOPT = 92,
+    EOPNOTSUPP = 95,
     EPFNOSUPPORT = 96,
     EAFNOSUPPORT = 97,
+    EADDRNOTAVAIL = 99,
+    ENETUNREACH = 101,
     ENOBUFS = 105,
     EISCONN = 106,
     ENOTCONN = 107,
     ETIMEDOUT = 110,
     ECONNREFUSED = 111,
+    EALREADY = 114,
+    EINPROGRESS = 115,
 }
 
 #[allow(non_snake_case)]
@@ -690,13 +738,19 @@ impl fmt::Display for SysError {
                 ENOTEMPTY => "Directory not empty",
                 ELOOP => "Too many symbolic links encountered",
                 ENOTSOCK => "Socket operation on non-socket",
+                EMSGSIZE => "Message too long",
                 ENOPROTOOPT => "Protocol not available",
+                EOPNOTSUPP => "Operation not supported on transport endpoint",
                 EPFNOSUPPORT => "Protocol family not supported",
                 EAFNOSUPPORT => "Address family not supported by protocol",
+                EADDRNOTAVAIL => "Cannot assign requested address",
+                ENETUNREACH => "Network is unreachable",
                 ENOBUFS => "No buffer space available",
                 EISCONN => "Transport endpoint is already connected",
                 ENOTCONN => "Transport endpoint is not connected",
                 ECONNREFUSED => "Connection refused",
+                EALREADY => "Operation already in progress",
+                EINPROGRESS => "Operation now in progress",
                 _ => "Unknown error",
             },
         )
@@ -704,8 +758,12 @@ impl fmt::Display for SysError {
 }
 
 impl From<VMError> for SysError {
-    fn from(_: VMError) -> Self {
-        SysError::EFAULT
+    fn from(err: VMError) -> Self {
+        match err {
+            VMError::InvalidPtr => SysError::EFAULT,
+            VMError::InvalidArgument => SysError::EINVAL,
+            VMError::NoMemory => SysError::ENOMEM,
+        }
     }
 }
 
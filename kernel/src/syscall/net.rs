@@ -2,35 +2,47 @@
 
 use super::fs::IoVecs;
 use super::*;
+use crate::bpf::{BpfObject, BPF_OBJECTS};
 use crate::fs::FileLike;
 use crate::memory::MemorySet;
 use crate::net::{
-    Endpoint, LinkLevelEndpoint, NetlinkEndpoint, NetlinkSocketState, PacketSocketState,
-    RawSocketState, Socket, TcpSocketState, UdpSocketState,
+    retry_on_activity, Ancillary, Endpoint, LinkLevelEndpoint, MsgFlags, NetlinkEndpoint,
+    NetlinkSocketState, PacketSocketState, RawSocketState, SockFprog, Socket, SocketFilter,
+    TcpSocketState, UCred, UdpSocketState, UnixSocketState,
 };
 use alloc::boxed::Box;
 use core::cmp::min;
+use core::convert::TryInto;
 use core::mem::size_of;
+use core::time::Duration;
 use smoltcp::wire::*;
 
 impl Syscall<'_> {
     pub fn sys_socket(&mut self, domain: usize, socket_type: usize, protocol: usize) -> SysResult {
         let domain = AddressFamily::from(domain as u16);
+        let nonblock = socket_type & SOCK_NONBLOCK != 0;
+        let cloexec = socket_type & SOCK_CLOEXEC != 0;
         let socket_type = SocketType::from(socket_type as u8 & SOCK_TYPE_MASK);
         info!(
             "socket: domain: {:?}, socket_type: {:?}, protocol: {}",
             domain, socket_type, protocol
         );
         let mut proc = self.process();
-        let socket: Box<dyn Socket> = match domain {
+        let mut socket: Box<dyn Socket> = match domain {
             AddressFamily::Internet | AddressFamily::Unix => match socket_type {
-                SocketType::Stream => Box::new(TcpSocketState::new()),
-                SocketType::Datagram => Box::new(UdpSocketState::new()),
-                SocketType::Raw => Box::new(RawSocketState::new(protocol as u8)),
+                SocketType::Stream => Box::new(TcpSocketState::new(false)),
+                SocketType::Datagram => Box::new(UdpSocketState::new(false)),
+                SocketType::Raw => Box::new(RawSocketState::new(protocol as u8, false)),
+                _ => return Err(SysError::EINVAL),
+            },
+            AddressFamily::Internet6 => match socket_type {
+                SocketType::Stream => Box::new(TcpSocketState::new(true)),
+                SocketType::Datagram => Box::new(UdpSocketState::new(true)),
+                SocketType::Raw => Box::new(RawSocketState::new(protocol as u8, true)),
                 _ => return Err(SysError::EINVAL),
             },
             AddressFamily::Packet => match socket_type {
-                SocketType::Raw => Box::new(PacketSocketState::new()),
+                SocketType::Raw => Box::new(PacketSocketState::new(u16::from_be(protocol as u16))),
                 _ => return Err(SysError::EINVAL),
             },
             AddressFamily::Netlink => match socket_type {
@@ -39,10 +51,43 @@ impl Syscall<'_> {
             },
             _ => return Err(SysError::EAFNOSUPPORT),
         };
+        socket.options().lock().nonblock = nonblock;
+        socket.set_fd_cloexec(cloexec);
         let fd = proc.add_file(FileLike::Socket(socket));
         Ok(fd)
     }
 
+    pub fn sys_socketpair(
+        &mut self,
+        domain: usize,
+        socket_type: usize,
+        protocol: usize,
+        sv: *mut [i32; 2],
+    ) -> SysResult {
+        let domain = AddressFamily::from(domain as u16);
+        let nonblock = socket_type & SOCK_NONBLOCK != 0;
+        let cloexec = socket_type & SOCK_CLOEXEC != 0;
+        let socket_type = SocketType::from(socket_type as u8 & SOCK_TYPE_MASK);
+        info!(
+            "socketpair: domain: {:?}, socket_type: {:?}, protocol: {}",
+            domain, socket_type, protocol
+        );
+        let sv = unsafe { self.vm().check_write_ptr(sv)? };
+        let (a, b) = match (domain, socket_type) {
+            (AddressFamily::Unix, SocketType::Stream) => UnixSocketState::new_pair(),
+            (AddressFamily::Unknown(_), _) => return Err(SysError::EAFNOSUPPORT),
+            _ => return Err(SysError::EOPNOTSUPP),
+        };
+        let mut proc = self.process();
+        for (fd, socket) in sv.iter_mut().zip([a, b].iter()) {
+            let mut socket = Box::new(socket.clone());
+            socket.options().lock().nonblock = nonblock;
+            socket.set_fd_cloexec(cloexec);
+            *fd = proc.add_file(FileLike::Socket(socket)) as i32;
+        }
+        Ok(0)
+    }
+
     pub fn sys_setsockopt(
         &mut self,
         fd: usize,
@@ -58,7 +103,36 @@ impl Syscall<'_> {
         let mut proc = self.process();
         let data = unsafe { self.vm().check_read_array(optval, optlen)? };
         let socket = proc.get_socket(fd)?;
-        socket.setsockopt(level, optname, data)
+        match (level, optname) {
+            (SOL_SOCKET, SO_RCVTIMEO) => {
+                socket.options().lock().recv_timeout = parse_timeout(data)?;
+                Ok(0)
+            }
+            (SOL_SOCKET, SO_SNDTIMEO) => {
+                socket.options().lock().send_timeout = parse_timeout(data)?;
+                Ok(0)
+            }
+            (SOL_SOCKET, SO_ATTACH_FILTER) => {
+                if data.len() < size_of::<SockFprog>() {
+                    return Err(SysError::EINVAL);
+                }
+                let prog = unsafe { (data.as_ptr() as *const SockFprog).read_unaligned() };
+                let insns = unsafe { self.vm().check_read_array(prog.filter, prog.len as usize)? };
+                socket.attach_filter(Some(SocketFilter::classic(insns)?))
+            }
+            (SOL_SOCKET, SO_ATTACH_BPF) => {
+                let prog_fd = data.get(..4).ok_or(SysError::EINVAL)?;
+                let prog_fd = u32::from_ne_bytes(prog_fd.try_into().unwrap());
+                let program = match BPF_OBJECTS.lock().get(&prog_fd) {
+                    Some(BpfObject::Program(program)) => program.clone(),
+                    Some(_) => return Err(SysError::EINVAL),
+                    None => return Err(SysError::EBADF),
+                };
+                socket.attach_filter(Some(SocketFilter::extended(program)?))
+            }
+            (SOL_SOCKET, SO_DETACH_FILTER) => socket.attach_filter(None),
+            _ => socket.setsockopt(level, optname, data),
+        }
     }
 
     pub fn sys_getsockopt(
@@ -76,29 +150,34 @@ impl Syscall<'_> {
         let optlen = unsafe { self.vm().check_write_ptr(optlen)? };
         match level {
             SOL_SOCKET => match optname {
-                SO_SNDBUF => {
-                    let optval = unsafe { self.vm().check_write_ptr(optval as *mut u32)? };
-                    *optval = crate::net::TCP_SENDBUF as u32;
-                    *optlen = 4;
-                    Ok(0)
-                }
-                SO_RCVBUF => {
-                    let optval = unsafe { self.vm().check_write_ptr(optval as *mut u32)? };
-                    *optval = crate::net::TCP_RECVBUF as u32;
-                    *optlen = 4;
+                SO_RCVTIMEO | SO_SNDTIMEO => {
+                    let optval = unsafe { self.vm().check_write_ptr(optval as *mut TimeVal)? };
+                    let options = *self.process().get_socket(fd)?.options().lock();
+                    let timeout = if optname == SO_RCVTIMEO {
+                        options.recv_timeout
+                    } else {
+                        options.send_timeout
+                    };
+                    *optval = TimeVal::from(timeout.unwrap_or_default());
+                    *optlen = size_of::<TimeVal>() as u32;
                     Ok(0)
                 }
-                _ => Err(SysError::ENOPROTOOPT),
+                _ => self.getsockopt(fd, level, optname, optval, optlen),
             },
             IPPROTO_TCP => match optname {
                 TCP_CONGESTION => Ok(0),
-                _ => Err(SysError::ENOPROTOOPT),
+                _ => self.getsockopt(fd, level, optname, optval, optlen),
             },
-            _ => Err(SysError::ENOPROTOOPT),
+            _ => self.getsockopt(fd, level, optname, optval, optlen),
         }
     }
 
-    pub fn sys_connect(&mut self, fd: usize, addr: *const SockAddr, addr_len: usize) -> SysResult {
+    pub async fn sys_connect(
+        &mut self,
+        fd: usize,
+        addr: *const SockAddr,
+        addr_len: usize,
+    ) -> SysResult {
         info!(
             "sys_connect: fd: {}, addr: {:?}, addr_len: {}",
             fd, addr, addr_len
@@ -107,22 +186,47 @@ impl Syscall<'_> {
         let mut proc = self.process();
         let endpoint = sockaddr_to_endpoint(&mut self.vm(), addr, addr_len)?;
         let socket = proc.get_socket(fd)?;
-        socket.connect(endpoint)?;
-        Ok(0)
+        match socket.connect(endpoint) {
+            Err(SysError::EINPROGRESS) => {}
+            result => return result.map(|_| 0),
+        }
+        let options = *socket.options().lock();
+        if options.nonblock {
+            return Err(SysError::EINPROGRESS);
+        }
+
+        // wait for the handshake without holding the process
+        let socket = socket.clone();
+        drop(proc);
+        let result = retry_on_activity(false, options.send_timeout, || match socket.poll() {
+            (_, _, true) => Err(SysError::ECONNREFUSED),
+            (_, true, _) => Ok(0),
+            _ => Err(SysError::EAGAIN),
+        })
+        .await;
+        match result {
+            // still connecting in the background
+            Err(SysError::EAGAIN) => Err(SysError::EINPROGRESS),
+            result => {
+                // the result is reported here instead of SO_ERROR
+                socket.options().lock().connecting = false;
+                result
+            }
+        }
     }
 
-    pub fn sys_sendto(
+    pub async fn sys_sendto(
         &mut self,
         fd: usize,
         base: *const u8,
         len: usize,
-        _flags: usize,
+        flags: usize,
         addr: *const SockAddr,
         addr_len: usize,
     ) -> SysResult {
         info!(
-            "sys_sendto: fd: {} base: {:?} len: {} addr: {:?} addr_len: {}",
-            fd, base, len, addr, addr_len
+            "sys_sendto: fd: {} base: {:?} len: {} flags: {} addr: {:?} addr_len: {}",
+            fd, base, len, flags, addr, addr_len
         );
 
         let mut proc = self.process();
@@ -135,11 +239,15 @@ impl Syscall<'_> {
             info!("sys_sendto: sending to endpoint {:?}", endpoint);
             Some(endpoint)
         };
-        let socket = proc.get_socket(fd)?;
-        socket.write(&slice, endpoint)
+        let socket = proc.get_socket(fd)?.clone();
+        drop(proc);
+        let flags = MsgFlags::from_bits_truncate(flags);
+        socket
+            .send(&slice, endpoint, Ancillary::default(), flags)
+            .await
     }
 
-    pub fn sys_recvfrom(
+    pub async fn sys_recvfrom(
         &mut self,
         fd: usize,
         base: *mut u8,
@@ -156,43 +264,108 @@ impl Syscall<'_> {
         let mut proc = self.process();
 
         let mut slice = unsafe { self.vm().check_write_array(base, len)? };
-        let socket = proc.get_socket(fd)?;
-        let (result, endpoint) = socket.read(&mut slice);
+        let socket = proc.get_socket(fd)?.clone();
+        drop(proc);
+        let flags = MsgFlags::from_bits_truncate(flags);
+        let info = socket.recv(&mut slice, flags).await?;
 
-        if result.is_ok() && !addr.is_null() {
-            let sockaddr_in = SockAddr::from(endpoint);
+        if !addr.is_null() {
+            let sockaddr_in = SockAddr::from(info.endpoint);
             unsafe {
                 sockaddr_in.write_to(&mut self.vm(), addr, addr_len)?;
             }
         }
 
-        result
+        if flags.contains(MsgFlags::TRUNC) {
+            Ok(info.len)
+        } else {
+            Ok(min(info.len, len))
+        }
+    }
+
+    pub async fn sys_sendmsg(&mut self, fd: usize, msg: *const MsgHdr, flags: usize) -> SysResult {
+        info!("sendmsg: fd: {}, msg: {:?}, flags: {}", fd, msg, flags);
+        let hdr = unsafe { self.vm().check_read_ptr(msg)? };
+        self.sendmsg(fd, hdr, MsgFlags::from_bits_truncate(flags))
+            .await
     }
 
-    pub fn sys_recvmsg(&mut self, fd: usize, msg: *mut MsgHdr, flags: usize) -> SysResult {
+    pub async fn sys_recvmsg(&mut self, fd: usize, msg: *mut MsgHdr, flags: usize) -> SysResult {
         info!("recvmsg: fd: {}, msg: {:?}, flags: {}", fd, msg, flags);
-        let mut proc = self.process();
         let hdr = unsafe { self.vm().check_write_ptr(msg)? };
-        let mut iovs =
-            unsafe { IoVecs::check_and_new(hdr.msg_iov, hdr.msg_iovlen, &self.vm(), true)? };
+        self.recvmsg(fd, hdr, MsgFlags::from_bits_truncate(flags))
+            .await
+    }
 
-        let mut buf = iovs.new_buf(true);
-        let socket = proc.get_socket(fd)?;
-        let (result, endpoint) = socket.read(&mut buf);
+    /// Send messages until one fails, return the number sent
+    pub async fn sys_sendmmsg(
+        &mut self,
+        fd: usize,
+        msgvec: *mut MMsgHdr,
+        vlen: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "sendmmsg: fd: {}, msgvec: {:?}, vlen: {}, flags: {}",
+            fd, msgvec, vlen, flags
+        );
+        let msgs = unsafe { self.vm().check_write_array(msgvec, min(vlen, UIO_MAXIOV))? };
+        let flags = MsgFlags::from_bits_truncate(flags);
+        let mut sent = 0;
+        for msg in msgs.iter_mut() {
+            match self.sendmsg(fd, &msg.msg_hdr, flags).await {
+                Ok(len) => msg.msg_len = len as u32,
+                // the error is reported by the next call
+                Err(_) if sent > 0 => break,
+                Err(err) => return Err(err),
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
 
-        if let Ok(len) = result {
-            // copy data to user
-            iovs.write_all_from_slice(&buf[..len]);
-            let sockaddr_in = SockAddr::from(endpoint);
-            unsafe {
-                sockaddr_in.write_to(
-                    &mut self.vm(),
-                    hdr.msg_name,
-                    &mut hdr.msg_namelen as *mut u32,
-                )?;
+    /// Receive messages until one fails or the timeout is over,
+    /// return the number received.
+    /// The timeout is only checked after each message.
+    pub async fn sys_recvmmsg(
+        &mut self,
+        fd: usize,
+        msgvec: *mut MMsgHdr,
+        vlen: usize,
+        flags: usize,
+        timeout: *const TimeSpec,
+    ) -> SysResult {
+        info!(
+            "recvmmsg: fd: {}, msgvec: {:?}, vlen: {}, flags: {}, timeout: {:?}",
+            fd, msgvec, vlen, flags, timeout
+        );
+        let msgs = unsafe { self.vm().check_write_array(msgvec, min(vlen, UIO_MAXIOV))? };
+        let deadline = if timeout.is_null() {
+            None
+        } else {
+            let timeout = unsafe { self.vm().check_read_ptr(timeout)? };
+            Some(crate::trap::uptime_msec() as u64 + timeout.to_msec())
+        };
+        let mut flags = MsgFlags::from_bits_truncate(flags);
+        let mut received = 0;
+        for msg in msgs.iter_mut() {
+            match self.recvmsg(fd, &mut msg.msg_hdr, flags).await {
+                Ok(len) => msg.msg_len = len as u32,
+                // the error is reported by the next call
+                Err(_) if received > 0 => break,
+                Err(err) => return Err(err),
+            }
+            received += 1;
+            if flags.contains(MsgFlags::WAITFORONE) {
+                flags |= MsgFlags::DONTWAIT;
+            }
+            if deadline.map_or(false, |deadline| {
+                crate::trap::uptime_msec() as u64 >= deadline
+            }) {
+                break;
             }
         }
-        result
+        Ok(received)
     }
 
     pub fn sys_bind(&mut self, fd: usize, addr: *const SockAddr, addr_len: usize) -> SysResult {
@@ -208,12 +381,10 @@ impl Syscall<'_> {
 
     pub fn sys_listen(&mut self, fd: usize, backlog: usize) -> SysResult {
         info!("sys_listen: fd: {} backlog: {}", fd, backlog);
-        // smoltcp tcp sockets do not support backlog
-        // open multiple sockets for each connection
         let mut proc = self.process();
 
         let socket = proc.get_socket(fd)?;
-        socket.listen()
+        socket.listen(backlog)
     }
 
     pub fn sys_shutdown(&mut self, fd: usize, how: usize) -> SysResult {
@@ -224,19 +395,30 @@ impl Syscall<'_> {
         socket.shutdown()
     }
 
-    pub fn sys_accept(&mut self, fd: usize, addr: *mut SockAddr, addr_len: *mut u32) -> SysResult {
+    pub async fn sys_accept(
+        &mut self,
+        fd: usize,
+        addr: *mut SockAddr,
+        addr_len: *mut u32,
+        flags: usize,
+    ) -> SysResult {
         info!(
-            "sys_accept: fd: {} addr: {:?} addr_len: {:?}",
-            fd, addr, addr_len
+            "sys_accept: fd: {} addr: {:?} addr_len: {:?} flags: {:#x}",
+            fd, addr, addr_len, flags
         );
-        // smoltcp tcp sockets do not support backlog
-        // open multiple sockets for each connection
-        let mut proc = self.process();
-
-        let socket = proc.get_socket(fd)?;
-        let (new_socket, remote_endpoint) = socket.accept()?;
+        if flags & !(SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let options = *self.process().get_socket(fd)?.options().lock();
+        let (mut new_socket, remote_endpoint) =
+            retry_on_activity(options.nonblock, options.recv_timeout, || {
+                self.process().get_socket(fd)?.accept()
+            })
+            .await?;
+        new_socket.options().lock().nonblock = flags & SOCK_NONBLOCK != 0;
+        new_socket.set_fd_cloexec(flags & SOCK_CLOEXEC != 0);
 
-        let new_fd = proc.add_file(FileLike::Socket(new_socket));
+        let new_fd = self.process().add_file(FileLike::Socket(new_socket));
 
         if !addr.is_null() {
             let sockaddr_in = SockAddr::from(remote_endpoint);
@@ -302,6 +484,246 @@ impl Syscall<'_> {
     }
 }
 
+impl Syscall<'_> {
+    /// Get an option of the socket, truncated to `optlen`
+    fn getsockopt(
+        &mut self,
+        fd: usize,
+        level: usize,
+        optname: usize,
+        optval: *mut u8,
+        optlen: &mut u32,
+    ) -> SysResult {
+        let value = self.process().get_socket(fd)?.getsockopt(level, optname)?;
+        let len = min(value.len(), *optlen as usize);
+        let optval = unsafe { self.vm().check_write_array(optval, len)? };
+        optval.copy_from_slice(&value[..len]);
+        *optlen = len as u32;
+        Ok(0)
+    }
+
+    async fn sendmsg(&mut self, fd: usize, hdr: &MsgHdr, flags: MsgFlags) -> SysResult {
+        if hdr.msg_iovlen > UIO_MAXIOV {
+            return Err(SysError::EMSGSIZE);
+        }
+        let iovs =
+            unsafe { IoVecs::check_and_new(hdr.msg_iov, hdr.msg_iovlen, &self.vm(), false)? };
+        let buf = iovs.read_all_to_vec();
+        let endpoint = if hdr.msg_name.is_null() {
+            None
+        } else {
+            let addr = hdr.msg_name as *const SockAddr;
+            Some(sockaddr_to_endpoint(
+                &self.vm(),
+                addr,
+                hdr.msg_namelen as usize,
+            )?)
+        };
+        let control: &[u8] = if hdr.msg_control.is_null() {
+            &[]
+        } else {
+            unsafe {
+                self.vm()
+                    .check_read_array(hdr.msg_control, hdr.msg_controllen)?
+            }
+        };
+
+        let mut proc = self.process();
+        let ancillary = parse_control(&mut proc, control)?;
+        let socket = proc.get_socket(fd)?.clone();
+        drop(proc);
+        socket.send(&buf, endpoint, ancillary, flags).await
+    }
+
+    async fn recvmsg(&mut self, fd: usize, hdr: &mut MsgHdr, flags: MsgFlags) -> SysResult {
+        if hdr.msg_iovlen > UIO_MAXIOV {
+            return Err(SysError::EMSGSIZE);
+        }
+        let mut iovs =
+            unsafe { IoVecs::check_and_new(hdr.msg_iov, hdr.msg_iovlen, &self.vm(), true)? };
+        let control: &mut [u8] = if hdr.msg_control.is_null() {
+            &mut []
+        } else {
+            unsafe {
+                self.vm()
+                    .check_write_array(hdr.msg_control, hdr.msg_controllen)?
+            }
+        };
+
+        let mut buf = iovs.new_buf(true);
+        let socket = self.process().get_socket(fd)?.clone();
+        let options = socket.cmsg_options();
+        let info = socket.recv(&mut buf, flags).await?;
+
+        // copy data to user
+        let len = min(info.len, buf.len());
+        iovs.write_all_from_slice(&buf[..len]);
+        let sockaddr_in = SockAddr::from(info.endpoint);
+        unsafe {
+            sockaddr_in.write_to(
+                &mut self.vm(),
+                hdr.msg_name,
+                &mut hdr.msg_namelen as *mut u32,
+            )?;
+        }
+
+        let mut cmsgs = CmsgWriter::new(control);
+        if options.timestamp {
+            cmsgs.push(SOL_SOCKET, SO_TIMESTAMP, &TimeVal::get_epoch());
+        }
+        if let (true, Some((ifindex, IpAddress::Ipv4(addr)))) = (options.pktinfo, info.pktinfo) {
+            let pktinfo = InPktInfo {
+                ipi_ifindex: ifindex as i32,
+                ipi_spec_dst: addr.0,
+                ipi_addr: addr.0,
+            };
+            cmsgs.push(IPPROTO_IP, IP_PKTINFO, &pktinfo);
+        }
+        if let Some(creds) = info.ancillary.creds {
+            cmsgs.push(SOL_SOCKET, SCM_CREDENTIALS, &creds);
+        }
+        if !info.ancillary.files.is_empty() {
+            // install the files that fit, the others are closed
+            let count = min(info.ancillary.files.len(), cmsgs.space() / size_of::<i32>());
+            let cloexec = flags.contains(MsgFlags::CMSG_CLOEXEC);
+            let mut proc = self.process();
+            let fds: Vec<i32> = info.ancillary.files[..count]
+                .iter()
+                .map(|file| proc.add_file(file.dup(cloexec)) as i32)
+                .collect();
+            if count > 0 {
+                cmsgs.push_slice(SOL_SOCKET, SCM_RIGHTS, &fds);
+            }
+            if count < info.ancillary.files.len() {
+                cmsgs.truncated = true;
+            }
+        }
+
+        let mut msg_flags = MsgFlags::empty();
+        if info.len > len {
+            msg_flags |= MsgFlags::TRUNC;
+        }
+        if cmsgs.truncated {
+            msg_flags |= MsgFlags::CTRUNC;
+        }
+        hdr.msg_controllen = cmsgs.len;
+        hdr.msg_flags = msg_flags.bits() as i32;
+
+        if flags.contains(MsgFlags::TRUNC) {
+            Ok(info.len)
+        } else {
+            Ok(len)
+        }
+    }
+}
+
+/// Parse control messages of sendmsg
+fn parse_control(proc: &mut Process, mut control: &[u8]) -> Result<Ancillary, SysError> {
+    let mut ancillary = Ancillary::default();
+    while control.len() >= size_of::<CmsgHdr>() {
+        let cmsg = unsafe { (control.as_ptr() as *const CmsgHdr).read_unaligned() };
+        if cmsg.cmsg_len < size_of::<CmsgHdr>() || cmsg.cmsg_len > control.len() {
+            return Err(SysError::EINVAL);
+        }
+        let data = &control[size_of::<CmsgHdr>()..cmsg.cmsg_len];
+        match (cmsg.cmsg_level as usize, cmsg.cmsg_type as usize) {
+            (SOL_SOCKET, SCM_RIGHTS) => {
+                for fd in data.chunks_exact(size_of::<i32>()) {
+                    let fd = i32::from_ne_bytes(fd.try_into().unwrap());
+                    let file = proc.get_file_like(fd as usize)?.dup(false);
+                    ancillary.files.push(file);
+                }
+            }
+            (SOL_SOCKET, SCM_CREDENTIALS) => {
+                if data.len() < size_of::<UCred>() {
+                    return Err(SysError::EINVAL);
+                }
+                // every process runs as root, so any credentials may be sent
+                let creds = unsafe { (data.as_ptr() as *const UCred).read_unaligned() };
+                ancillary.creds = Some(creds);
+            }
+            // the source address is chosen by the routing table
+            (IPPROTO_IP, IP_PKTINFO) => {}
+            _ => return Err(SysError::EINVAL),
+        }
+        let next = cmsg_align(cmsg.cmsg_len);
+        if next >= control.len() {
+            break;
+        }
+        control = &control[next..];
+    }
+    Ok(ancillary)
+}
+
+/// Parse a `struct timeval` of SO_RCVTIMEO or SO_SNDTIMEO, zero to wait forever
+fn parse_timeout(data: &[u8]) -> Result<Option<Duration>, SysError> {
+    if data.len() < size_of::<TimeVal>() {
+        return Err(SysError::EINVAL);
+    }
+    let timeout = unsafe { (data.as_ptr() as *const TimeVal).read_unaligned() };
+    let timeout = timeout.to_duration();
+    if timeout == Duration::default() {
+        Ok(None)
+    } else {
+        Ok(Some(timeout))
+    }
+}
+
+/// Round up to the alignment of control messages
+fn cmsg_align(len: usize) -> usize {
+    (len + size_of::<usize>() - 1) & !(size_of::<usize>() - 1)
+}
+
+/// Fill the control buffer of recvmsg
+struct CmsgWriter<'a> {
+    buf: &'a mut [u8],
+    /// Length written so far
+    len: usize,
+    /// Some control messages did not fit
+    truncated: bool,
+}
+
+impl<'a> CmsgWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        CmsgWriter {
+            buf,
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// Data bytes left for the next control message
+    fn space(&self) -> usize {
+        self.buf
+            .len()
+            .saturating_sub(self.len + size_of::<CmsgHdr>())
+    }
+
+    fn push<T>(&mut self, level: usize, cmsg_type: usize, data: &T) {
+        self.push_slice(level, cmsg_type, core::slice::from_ref(data));
+    }
+
+    fn push_slice<T>(&mut self, level: usize, cmsg_type: usize, data: &[T]) {
+        let data_len = data.len() * size_of::<T>();
+        if data_len > self.space() {
+            self.truncated = true;
+            return;
+        }
+        let cmsg = CmsgHdr {
+            cmsg_len: size_of::<CmsgHdr>() + data_len,
+            cmsg_level: level as i32,
+            cmsg_type: cmsg_type as i32,
+        };
+        unsafe {
+            let ptr = self.buf[self.len..].as_mut_ptr();
+            (ptr as *mut CmsgHdr).write_unaligned(cmsg);
+            let data = slice::from_raw_parts(data.as_ptr() as *const u8, data_len);
+            self.buf[self.len + size_of::<CmsgHdr>()..][..data_len].copy_from_slice(data);
+        }
+        self.len = min(self.len + cmsg_align(cmsg.cmsg_len), self.buf.len());
+    }
+}
+
 impl Process {
     fn get_socket(&mut self, fd: usize) -> Result<&mut Box<dyn Socket>, SysError> {
         match self.get_file_like(fd)? {
@@ -320,6 +742,16 @@ pub struct SockAddrIn {
     pub sin_zero: [u8; 8],
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SockAddrIn6 {
+    pub sin6_family: u16,
+    pub sin6_port: u16,
+    pub sin6_flowinfo: u32,
+    pub sin6_addr: [u8; 16],
+    pub sin6_scope_id: u32,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct SockAddrUn {
@@ -352,6 +784,7 @@ pub struct SockAddrNl {
 pub union SockAddr {
     pub family: u16,
     pub addr_in: SockAddrIn,
+    pub addr_in6: SockAddrIn6,
     pub addr_un: SockAddrUn,
     pub addr_ll: SockAddrLl,
     pub addr_nl: SockAddrNl,
@@ -367,8 +800,8 @@ pub struct SockAddrPlaceholder {
 
 impl From<Endpoint> for SockAddr {
     fn from(endpoint: Endpoint) -> Self {
-        if let Endpoint::Ip(ip) = endpoint {
-            match ip.addr {
+        match endpoint {
+            Endpoint::Ip(ip) => match ip.addr {
                 IpAddress::Ipv4(ipv4) => SockAddr {
                     addr_in: SockAddrIn {
                         sin_family: AddressFamily::Internet.into(),
@@ -377,37 +810,41 @@ impl From<Endpoint> for SockAddr {
                         sin_zero: [0; 8],
                     },
                 },
-                IpAddress::Unspecified => SockAddr {
+                IpAddress::Ipv6(ipv6) => SockAddr {
+                    addr_in6: SockAddrIn6 {
+                        sin6_family: AddressFamily::Internet6.into(),
+                        sin6_port: u16::to_be(ip.port),
+                        sin6_flowinfo: 0,
+                        sin6_addr: ipv6.0,
+                        sin6_scope_id: 0,
+                    },
+                },
+                _ => SockAddr {
                     addr_ph: SockAddrPlaceholder {
                         family: AddressFamily::Unspecified.into(),
                         data: [0; 14],
                     },
                 },
-                _ => unimplemented!("only ipv4"),
-            }
-        } else if let Endpoint::LinkLevel(link_level) = endpoint {
-            SockAddr {
+            },
+            Endpoint::LinkLevel(link_level) => SockAddr {
                 addr_ll: SockAddrLl {
                     sll_family: AddressFamily::Packet.into(),
-                    sll_protocol: 0,
+                    sll_protocol: u16::to_be(link_level.protocol),
                     sll_ifindex: link_level.interface_index as u32,
                     sll_hatype: 0,
-                    sll_pkttype: 0,
+                    sll_pkttype: link_level.packet_type,
                     sll_halen: 0,
                     sll_addr: [0; 8],
                 },
-            }
-        } else if let Endpoint::Netlink(netlink) = endpoint {
-            SockAddr {
+            },
+            Endpoint::Netlink(netlink) => SockAddr {
                 addr_nl: SockAddrNl {
                     nl_family: AddressFamily::Netlink.into(),
                     nl_pad: 0,
                     nl_pid: netlink.port_id,
                     nl_groups: netlink.multicast_groups_mask,
                 },
-            }
-        } else {
-            unimplemented!("only ip");
+            },
         }
     }
 }
@@ -435,6 +872,17 @@ fn sockaddr_to_endpoint(
                 ));
                 Ok(Endpoint::Ip((addr, port).into()))
             }
+            AddressFamily::Internet6 => {
+                let port = u16::from_be(addr.addr_in6.sin6_port);
+                let addr = Ipv6Address(addr.addr_in6.sin6_addr);
+                // IPv4-mapped addresses reach IPv4 peers from dual-stack sockets
+                let addr = if addr.0[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] {
+                    IpAddress::from(Ipv4Address::from_bytes(&addr.0[12..]))
+                } else {
+                    IpAddress::from(addr)
+                };
+                Ok(Endpoint::Ip((addr, port).into()))
+            }
             AddressFamily::Unix => Err(SysError::EINVAL),
             AddressFamily::Packet => Ok(Endpoint::LinkLevel(LinkLevelEndpoint::new(
                 addr.addr_ll.sll_ifindex as usize,
@@ -443,7 +891,7 @@ fn sockaddr_to_endpoint(
                 addr.addr_nl.nl_pid,
                 addr.addr_nl.nl_groups,
             ))),
-            _ => Err(SysError::EINVAL),
+            _ => Err(SysError::EAFNOSUPPORT),
         }
     }
 }
@@ -452,10 +900,13 @@ impl SockAddr {
     fn len(&self) -> Result<usize, SysError> {
         match AddressFamily::from(unsafe { self.family }) {
             AddressFamily::Internet => Ok(size_of::<SockAddrIn>()),
+            AddressFamily::Internet6 => Ok(size_of::<SockAddrIn6>()),
             AddressFamily::Packet => Ok(size_of::<SockAddrLl>()),
             AddressFamily::Netlink => Ok(size_of::<SockAddrNl>()),
+            // unnamed, as local socket pairs
+            AddressFamily::Unspecified => Ok(0),
             AddressFamily::Unix => Err(SysError::EINVAL),
-            _ => Err(SysError::EINVAL),
+            _ => Err(SysError::EAFNOSUPPORT),
         }
     }
 
@@ -489,11 +940,40 @@ pub struct MsgHdr {
     msg_namelen: u32,
     msg_iov: *mut IoVec,
     msg_iovlen: usize,
-    msg_control: usize,
+    msg_control: *mut u8,
     msg_controllen: usize,
-    msg_flags: usize,
+    msg_flags: i32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct MMsgHdr {
+    msg_hdr: MsgHdr,
+    /// Bytes sent or received
+    msg_len: u32,
+}
+
+/// Header of a control message, followed by its data
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct CmsgHdr {
+    cmsg_len: usize,
+    cmsg_level: i32,
+    cmsg_type: i32,
 }
 
+/// Data of IP_PKTINFO control messages
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct InPktInfo {
+    ipi_ifindex: i32,
+    ipi_spec_dst: [u8; 4],
+    ipi_addr: [u8; 4],
+}
+
+/// Max messages of sendmmsg/recvmmsg and iovecs of a message
+pub const UIO_MAXIOV: usize = 1024;
+
 enum_with_unknown! {
     /// Address families
     pub doc enum AddressFamily(u16) {
@@ -503,6 +983,8 @@ enum_with_unknown! {
         Unix = 1,
         /// Internet IP Protocol
         Internet = 2,
+        /// IP version 6
+        Internet6 = 10,
         /// Netlink
         Netlink = 16,
         /// Packet family
@@ -511,6 +993,9 @@ enum_with_unknown! {
 }
 
 const SOCK_TYPE_MASK: u8 = 0xf;
+/// Flags in the socket type, and of accept4
+const SOCK_NONBLOCK: usize = 0o4000;
+const SOCK_CLOEXEC: usize = 0o2000000;
 
 enum_with_unknown! {
     /// Socket types
@@ -527,12 +1012,38 @@ enum_with_unknown! {
 pub const IPPROTO_IP: usize = 0;
 pub const IPPROTO_ICMP: usize = 1;
 pub const IPPROTO_TCP: usize = 6;
+pub const IPPROTO_IPV6: usize = 41;
+pub const IPPROTO_ICMPV6: usize = 58;
 
 pub const SOL_SOCKET: usize = 1;
+pub const SO_REUSEADDR: usize = 2;
+pub const SO_ERROR: usize = 4;
 pub const SO_SNDBUF: usize = 7;
 pub const SO_RCVBUF: usize = 8;
+pub const SO_KEEPALIVE: usize = 9;
 pub const SO_LINGER: usize = 13;
+pub const SO_RCVTIMEO: usize = 20;
+pub const SO_SNDTIMEO: usize = 21;
+pub const SO_ATTACH_FILTER: usize = 26;
+pub const SO_DETACH_FILTER: usize = 27;
+pub const SO_TIMESTAMP: usize = 29;
+pub const SO_ATTACH_BPF: usize = 50;
+
+pub const SCM_RIGHTS: usize = 1;
+pub const SCM_CREDENTIALS: usize = 2;
 
+pub const TCP_NODELAY: usize = 1;
+pub const TCP_KEEPIDLE: usize = 4;
+pub const TCP_KEEPINTVL: usize = 5;
+pub const TCP_KEEPCNT: usize = 6;
 pub const TCP_CONGESTION: usize = 13;
+pub const TCP_USER_TIMEOUT: usize = 18;
 
+pub const IP_TTL: usize = 2;
 pub const IP_HDRINCL: usize = 3;
+pub const IP_PKTINFO: usize = 8;
+pub const IP_MULTICAST_IF: usize = 32;
+pub const IP_MULTICAST_TTL: usize = 33;
+pub const IP_MULTICAST_LOOP: usize = 34;
+
+pub const IPV6_V6ONLY: usize = 26;
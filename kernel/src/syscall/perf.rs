@@ -0,0 +1,82 @@
+use super::{SysError::*, *};
+
+use crate::consts::MAX_CPU_NUM;
+use crate::fs::FileLike;
+use crate::perf::consts::*;
+use crate::perf::{perf_event_open, PerfEventAttr};
+use alloc::collections::BTreeSet;
+use core::mem::size_of;
+
+impl Syscall<'_> {
+    pub fn sys_perf_event_open(
+        &mut self,
+        attr: *const PerfEventAttr,
+        pid: isize,
+        cpu: isize,
+        group_fd: isize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "perf_event_open: attr: {:?}, pid: {}, cpu: {}, group_fd: {}, flags: {:#x}",
+            attr, pid, cpu, group_fd, flags
+        );
+        let attr = self.read_perf_event_attr(attr)?;
+        // events are not grouped, each one has its own ring buffer
+        if group_fd != -1 || flags & !(PERF_FLAG_FD_NO_GROUP | PERF_FLAG_FD_CLOEXEC) != 0 {
+            return Err(EINVAL);
+        }
+        let cpu = match cpu {
+            -1 => None,
+            cpu if cpu >= 0 && (cpu as usize) < MAX_CPU_NUM => Some(cpu as usize),
+            _ => return Err(EINVAL),
+        };
+        let tids = match pid {
+            -1 if cpu.is_none() => return Err(EINVAL),
+            -1 => None,
+            0 => Some(core::iter::once(self.thread.tid).collect()),
+            pid if pid > 0 => Some(self.perf_event_tids(pid as usize)?),
+            _ => return Err(ESRCH),
+        };
+        let event = perf_event_open(attr, tids, cpu)?;
+        // TODO: close on exec with PERF_FLAG_FD_CLOEXEC
+        let fd = self
+            .process()
+            .add_file(FileLike::PerfEvent(Arc::new(event)));
+        Ok(fd)
+    }
+
+    /// Copy an attr of any published size, newer fields must be left zero
+    fn read_perf_event_attr(&self, ptr: *const PerfEventAttr) -> Result<PerfEventAttr, SysError> {
+        let header = unsafe { self.vm().check_read_array(ptr as *const u32, 2)? };
+        let size = match header[1] as usize {
+            0 => PERF_ATTR_SIZE_VER0,
+            size if size < PERF_ATTR_SIZE_VER0 => return Err(EINVAL),
+            size => size,
+        };
+        let bytes = unsafe { self.vm().check_read_array(ptr as *const u8, size)? };
+        let known = size.min(size_of::<PerfEventAttr>());
+        if bytes[known..].iter().any(|&b| b != 0) {
+            return Err(E2BIG);
+        }
+        let mut attr = PerfEventAttr::default();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                &mut attr as *mut PerfEventAttr as *mut u8,
+                known,
+            );
+        }
+        Ok(attr)
+    }
+
+    /// Threads of process `pid`, or thread `pid` alone
+    fn perf_event_tids(&self, pid: usize) -> Result<BTreeSet<usize>, SysError> {
+        if let Some(process) = process(pid) {
+            return Ok(process.lock().threads.iter().cloned().collect());
+        }
+        if THREADS.read().contains_key(&pid) {
+            return Ok(core::iter::once(pid).collect());
+        }
+        Err(ESRCH)
+    }
+}
@@ -3,6 +3,7 @@
 use super::*;
 use crate::arch::timer::timer_now;
 use crate::fs::FileLike;
+use crate::memory::memcg;
 use crate::signal::{send_signal, Signal};
 use crate::{
     sync::{wait_for_event, Event, EventBus, SpinNoIrqLock as Mutex},
@@ -21,9 +22,10 @@ use core::{
 impl Syscall<'_> {
     /// Fork the current process. Return the child's PID.
     pub fn sys_fork(&mut self) -> SysResult {
-        let new_thread = self.thread.fork(self.context);
+        let new_thread = self.thread.fork(self.context)?;
         let pid = new_thread.proc.lock().pid.get();
         info!("fork: {} -> {}", self.process().pid, pid);
+        crate::perf::task_fork(&self.thread, &new_thread);
         spawn(new_thread);
         Ok(pid)
     }
@@ -76,6 +78,7 @@ impl Syscall<'_> {
         info!("clone: {} -> {}", self.thread.tid, tid);
         *parent_tid_ref = tid as u32;
         *child_tid_ref = tid as u32;
+        crate::perf::task_fork(&self.thread, &new_thread);
         spawn(new_thread);
         Ok(tid)
     }
@@ -223,8 +226,12 @@ impl Syscall<'_> {
         // Make new Thread
         // Re-create vm
         let mut vm = self.vm();
-        let (entry_addr, ustack_top) =
-            Thread::new_user_vm(&inode, args, envs, &mut vm).map_err(|_| SysError::EINVAL)?;
+        let allocator = proc.frame_allocator();
+        let (entry_addr, ustack_top) = Thread::new_user_vm(&inode, args, envs, &mut vm, &allocator)
+            .map_err(|err| match err {
+                OUT_OF_MEMORY => SysError::ENOMEM,
+                _ => SysError::EINVAL,
+            })?;
 
         // Kill other threads
         // TODO: stop and wait until they are finished
@@ -234,16 +241,10 @@ impl Syscall<'_> {
         let close_fds = proc
             .files
             .iter()
-            .filter_map(|(fd, file_like)| {
-                if let FileLike::File(file) = file_like {
-                    if file.fd_cloexec {
-                        Some(*fd)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
+            .filter_map(|(fd, file_like)| match file_like {
+                FileLike::File(file) if file.fd_cloexec => Some(*fd),
+                FileLike::Socket(socket) if socket.fd_cloexec() => Some(*fd),
+                _ => None,
             })
             .collect::<Vec<_>>();
         for fd in close_fds {
@@ -259,11 +260,23 @@ impl Syscall<'_> {
         // Modify exec path
         proc.exec_path = path.clone();
 
+        // the old area is gone with the vm
+        proc.xol_area = None;
+        self.thread.inner.lock().uprobe_task = Default::default();
+
         // reset disposition (man signal(7))
         for d in proc.dispositions.iter_mut() {
             *d = SignalAction::default();
         }
+        let pid = proc.pid.get();
+        // profilers look the program up by its full path
+        let full_path = if path.starts_with('/') {
+            path.clone()
+        } else {
+            format!("{}/{}", proc.cwd.trim_end_matches('/'), path)
+        };
         drop(proc);
+        crate::perf::task_exec(&self.thread, pid, &full_path, &inode);
 
         // Modify the TrapFrame
         self.context.set_ip(entry_addr);
@@ -312,6 +325,8 @@ impl Syscall<'_> {
             // TODO: check process pid is the child of calling process
             let mut proc = proc.lock();
             proc.pgid = pgid as Pgid;
+            let memcg = memcg(proc.pgid);
+            proc.set_memcg(memcg);
             Ok(0)
         } else {
             Err(ESRCH)
@@ -119,6 +119,10 @@ impl TimeVal {
         (self.sec as u64) * MSEC_PER_SEC + (self.usec as u64) / USEC_PER_MSEC
     }
 
+    pub fn to_duration(&self) -> Duration {
+        Duration::new(self.sec as u64, (self.usec as u64 * NSEC_PER_USEC) as u32)
+    }
+
     pub fn get_epoch() -> Self {
         let usec = get_epoch_usec();
         TimeVal {
@@ -128,6 +132,15 @@ impl TimeVal {
     }
 }
 
+impl From<Duration> for TimeVal {
+    fn from(duration: Duration) -> Self {
+        TimeVal {
+            sec: duration.as_secs() as usize,
+            usec: duration.subsec_micros() as usize,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct TimeSpec {
@@ -0,0 +1,200 @@
+//! Per-CPU ring buffers of trace entries
+
+use super::event::TraceEvent;
+use super::{comm_str, function};
+use crate::arch::cpu;
+use crate::arch::timer::timer_now;
+use crate::consts::MAX_CPU_NUM;
+use crate::sync::{Event, EventBus, SpinNoIrqLock as Mutex};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Words of data in an event entry, enough for any tracepoint context
+pub const EVENT_WORDS: usize = 8;
+
+#[derive(Clone)]
+pub enum Record {
+    /// The function at `ip` was called from `parent_ip`
+    Function { ip: usize, parent_ip: usize },
+    /// `event` happened, with its raw fields in `data`.
+    /// Bit n of `faults` is set if field n could not be fetched.
+    Event {
+        event: Arc<TraceEvent>,
+        data: [u64; EVENT_WORDS],
+        faults: u8,
+    },
+}
+
+#[derive(Clone)]
+pub struct Entry {
+    /// Nanoseconds since boot
+    pub time: u64,
+    pub cpu: usize,
+    pub pid: usize,
+    pub comm: [u8; 16],
+    pub record: Record,
+}
+
+impl Entry {
+    /// Print the entry as a line of `trace`
+    pub fn print(&self, out: &mut String) {
+        let usecs = self.time / 1000;
+        write!(
+            out,
+            "{:>16}-{:<7} [{:03}] {:>5}.{:06}: ",
+            comm_str(&self.comm),
+            self.pid,
+            self.cpu,
+            usecs / 1_000_000,
+            usecs % 1_000_000
+        )
+        .unwrap();
+        match &self.record {
+            Record::Function { ip, parent_ip } => function::print(*ip, *parent_ip, out),
+            Record::Event {
+                event,
+                data,
+                faults,
+            } => event.print(data, *faults, out),
+        }
+        out.push('\n');
+    }
+}
+
+struct CpuBuffer {
+    entries: VecDeque<Entry>,
+    /// Entries written since the last clear, including overwritten ones
+    written: u64,
+}
+
+const DEFAULT_SIZE_KB: usize = 64;
+
+/// Size of each buffer in KB, `buffer_size_kb`
+static SIZE_KB: AtomicUsize = AtomicUsize::new(DEFAULT_SIZE_KB);
+
+lazy_static! {
+    /// Buffers are allocated on the first entry of each CPU
+    static ref BUFFERS: Vec<Mutex<CpuBuffer>> = (0..MAX_CPU_NUM)
+        .map(|_| {
+            Mutex::new(CpuBuffer {
+                entries: VecDeque::new(),
+                written: 0,
+            })
+        })
+        .collect();
+    /// READABLE while there may be entries for `trace_pipe`
+    pub static ref EVENTBUS: Arc<Mutex<EventBus>> = EventBus::new();
+}
+
+/// Entries in each buffer
+fn capacity() -> usize {
+    (SIZE_KB.load(Ordering::Relaxed) * 1024 / size_of::<Entry>()).max(1)
+}
+
+pub fn size_kb() -> usize {
+    SIZE_KB.load(Ordering::Relaxed)
+}
+
+/// Resize the buffers, dropping the oldest entries that no longer fit
+pub fn set_size_kb(size_kb: usize) {
+    SIZE_KB.store(size_kb, Ordering::Relaxed);
+    let capacity = capacity();
+    for buffer in BUFFERS.iter() {
+        let mut buffer = buffer.lock();
+        while buffer.entries.len() > capacity {
+            buffer.entries.pop_front();
+        }
+        buffer.entries.shrink_to_fit();
+    }
+}
+
+/// Record an entry for the current thread `pid` on this CPU, the oldest
+/// entry is overwritten when the buffer is full
+pub fn record(pid: usize, comm: [u8; 16], record: Record) {
+    let cpu = cpu::id();
+    let entry = Entry {
+        time: timer_now().as_nanos() as u64,
+        cpu,
+        pid,
+        comm,
+        record,
+    };
+    {
+        // the buffer is locked if the probe fired while this CPU reads it
+        let mut buffer = match BUFFERS[cpu].try_lock() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let capacity = capacity();
+        if buffer.entries.capacity() < capacity {
+            let additional = capacity - buffer.entries.len();
+            buffer.entries.reserve_exact(additional);
+        }
+        while buffer.entries.len() >= capacity {
+            buffer.entries.pop_front();
+        }
+        buffer.entries.push_back(entry);
+        buffer.written += 1;
+    }
+    EVENTBUS.lock().set(Event::READABLE);
+}
+
+/// All entries, oldest first
+pub fn snapshot() -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for buffer in BUFFERS.iter() {
+        entries.extend(buffer.lock().entries.iter().cloned());
+    }
+    entries.sort_by_key(|entry| entry.time);
+    entries
+}
+
+/// Remove and return the oldest entry
+pub fn consume() -> Option<Entry> {
+    let mut oldest: Option<(usize, u64)> = None;
+    for (cpu, buffer) in BUFFERS.iter().enumerate() {
+        if let Some(entry) = buffer.lock().entries.front() {
+            if oldest.map_or(true, |(_, time)| entry.time < time) {
+                oldest = Some((cpu, entry.time));
+            }
+        }
+    }
+    match oldest {
+        Some((cpu, _)) => BUFFERS[cpu].lock().entries.pop_front(),
+        None => {
+            // entries recorded after the check set it again
+            let mut eventbus = EVENTBUS.lock();
+            if is_empty() {
+                eventbus.clear(Event::READABLE);
+            }
+            None
+        }
+    }
+}
+
+pub fn is_empty() -> bool {
+    BUFFERS
+        .iter()
+        .all(|buffer| buffer.lock().entries.is_empty())
+}
+
+pub fn clear() {
+    for buffer in BUFFERS.iter() {
+        let mut buffer = buffer.lock();
+        buffer.entries.clear();
+        buffer.written = 0;
+    }
+}
+
+/// Entries in the buffers and entries written to them
+pub fn stats() -> (usize, u64) {
+    BUFFERS.iter().fold((0, 0), |(entries, written), buffer| {
+        let buffer = buffer.lock();
+        (entries + buffer.entries.len(), written + buffer.written)
+    })
+}
@@ -0,0 +1,263 @@
+//! Events of the tracer: the static tracepoints and kprobe events
+
+use super::buffer::{self, Record, EVENT_WORDS};
+use super::filter::Filter;
+use super::kprobe_event::KProbeEvent;
+use super::{comm_str, current_task, pid_allowed, tracing_on, EVENT_PIDS};
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::tracepoint::*;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs::vfs::{FsError, Result};
+use spin::RwLock;
+
+pub enum EventKind {
+    Tracepoint(&'static Tracepoint),
+    KProbe(KProbeEvent),
+}
+
+struct EventState {
+    enabled: bool,
+    /// Id of the probe registered on a tracepoint
+    probe: Option<usize>,
+    filter: Option<Filter>,
+}
+
+pub struct TraceEvent {
+    pub system: String,
+    pub name: String,
+    pub id: usize,
+    pub kind: EventKind,
+    state: Mutex<EventState>,
+}
+
+lazy_static! {
+    /// All events, the static tracepoints first
+    static ref EVENTS: RwLock<Vec<Arc<TraceEvent>>> = RwLock::new(
+        TRACEPOINTS
+            .iter()
+            .map(|&tp| {
+                Arc::new(TraceEvent::new(
+                    String::from(tp.system),
+                    String::from(tp.name),
+                    tp.id as usize,
+                    EventKind::Tracepoint(tp),
+                ))
+            })
+            .collect()
+    );
+}
+
+/// Kprobe events created so far, their ids follow the tracepoints
+static KPROBE_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn events() -> Vec<Arc<TraceEvent>> {
+    EVENTS.read().clone()
+}
+
+/// Find an event by `system:name`, or by `name` alone
+pub fn find_event(target: &str) -> Option<Arc<TraceEvent>> {
+    let (system, name) = match target.find(':') {
+        Some(pos) => (Some(&target[..pos]), &target[(pos + 1)..]),
+        None => (None, target),
+    };
+    EVENTS
+        .read()
+        .iter()
+        .find(|event| event.name == name && system.map_or(true, |system| event.system == system))
+        .cloned()
+}
+
+/// Add a kprobe event, its name must be unique in `system`
+pub fn add_event(system: String, name: String, kind: EventKind) -> Result<Arc<TraceEvent>> {
+    let mut events = EVENTS.write();
+    if events
+        .iter()
+        .any(|event| event.system == system && event.name == name)
+    {
+        return Err(FsError::EntryExist);
+    }
+    let id = TRACEPOINTS.len() + KPROBE_EVENTS.fetch_add(1, Ordering::Relaxed);
+    let event = Arc::new(TraceEvent::new(system, name, id, kind));
+    events.push(event.clone());
+    Ok(event)
+}
+
+/// Remove a kprobe event, it must be disabled
+pub fn remove_event(event: &Arc<TraceEvent>) -> Result<()> {
+    if event.enabled() {
+        return Err(FsError::Busy);
+    }
+    EVENTS.write().retain(|other| !Arc::ptr_eq(other, event));
+    Ok(())
+}
+
+impl TraceEvent {
+    fn new(system: String, name: String, id: usize, kind: EventKind) -> Self {
+        TraceEvent {
+            system,
+            name,
+            id,
+            kind,
+            state: Mutex::new(EventState {
+                enabled: false,
+                probe: None,
+                filter: None,
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.state.lock().enabled
+    }
+
+    pub fn set_enabled(self: &Arc<Self>, enabled: bool) -> Result<()> {
+        let mut state = self.state.lock();
+        if state.enabled == enabled {
+            return Ok(());
+        }
+        match &self.kind {
+            EventKind::Tracepoint(tp) => {
+                if enabled {
+                    let event = self.clone();
+                    let ctx_size = tp.ctx_size.min(EVENT_WORDS * 8);
+                    let handler = Arc::new(move |ctx: *const u8| {
+                        let mut data = [0u64; EVENT_WORDS];
+                        unsafe {
+                            core::ptr::copy_nonoverlapping(
+                                ctx,
+                                data.as_mut_ptr() as *mut u8,
+                                ctx_size,
+                            );
+                        }
+                        event.record(data, 0);
+                    });
+                    state.probe = Some(register_probe(tp, None, handler));
+                } else if let Some(id) = state.probe.take() {
+                    unregister_probe(tp, id);
+                }
+            }
+            EventKind::KProbe(kprobe) => {
+                if enabled {
+                    kprobe.register(self)?;
+                } else {
+                    kprobe.unregister();
+                }
+            }
+        }
+        state.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn filter(&self) -> String {
+        match &self.state.lock().filter {
+            Some(filter) => String::from(filter.text()),
+            None => String::from("none"),
+        }
+    }
+
+    /// Set the filter, `None` lets everything through
+    pub fn set_filter(&self, filter: Option<Filter>) {
+        self.state.lock().filter = filter;
+    }
+
+    /// Record the event for the current thread if it passes the filters
+    pub fn record(self: &Arc<Self>, data: [u64; EVENT_WORDS], faults: u8) {
+        if !tracing_on() {
+            return;
+        }
+        let (pid, comm) = current_task();
+        if !pid_allowed(&EVENT_PIDS, pid) {
+            return;
+        }
+        // the event is being changed if it is locked
+        match self.state.try_lock() {
+            Some(state) => match &state.filter {
+                Some(filter) if !filter.matches(pid, &comm) => return,
+                _ => {}
+            },
+            None => return,
+        }
+        let record = Record::Event {
+            event: self.clone(),
+            data,
+            faults,
+        };
+        buffer::record(pid, comm, record);
+    }
+
+    /// Print the name and fields of an entry
+    pub fn print(&self, data: &[u64; EVENT_WORDS], faults: u8, out: &mut String) {
+        write!(out, "{}: ", self.name).unwrap();
+        match &self.kind {
+            EventKind::Tracepoint(tp) => print_tracepoint(tp, data, out),
+            EventKind::KProbe(kprobe) => kprobe.print(data, faults, out),
+        }
+    }
+}
+
+/// Read the context of type `T` saved in `data`
+fn context<T: Copy>(data: &[u64; EVENT_WORDS]) -> T {
+    assert!(size_of::<T>() <= size_of::<[u64; EVENT_WORDS]>());
+    unsafe { *(data.as_ptr() as *const T) }
+}
+
+/// Fields of tracepoints printed like Linux does
+fn print_tracepoint(tp: &Tracepoint, data: &[u64; EVENT_WORDS], out: &mut String) {
+    let is = |other: &Tracepoint| core::ptr::eq(tp, other);
+    if is(&SYS_ENTER) {
+        let ctx: SysEnterContext = context(data);
+        let args = ctx.args;
+        write!(
+            out,
+            "NR {} ({:x}, {:x}, {:x}, {:x}, {:x}, {:x})",
+            ctx.id, args[0], args[1], args[2], args[3], args[4], args[5]
+        )
+        .unwrap();
+    } else if is(&SYS_EXIT) {
+        let ctx: SysExitContext = context(data);
+        write!(out, "NR {} = {}", ctx.id, ctx.ret).unwrap();
+    } else if is(&SCHED_SWITCH) {
+        let ctx: SchedSwitchContext = context(data);
+        write!(
+            out,
+            "prev_comm={} prev_pid={} prev_prio={} prev_state={} ==> next_comm={} next_pid={} next_prio={}",
+            comm_str(&ctx.prev_comm),
+            ctx.prev_pid,
+            ctx.prev_prio,
+            if ctx.prev_state == 0 { "R" } else { "S" },
+            comm_str(&ctx.next_comm),
+            ctx.next_pid,
+            ctx.next_prio
+        )
+        .unwrap();
+    } else if is(&PAGE_FAULT_USER) || is(&PAGE_FAULT_KERNEL) {
+        let ctx: PageFaultContext = context(data);
+        write!(
+            out,
+            "address={:#x} error_code={:#x}",
+            ctx.address, ctx.error_code
+        )
+        .unwrap();
+    } else if is(&BLOCK_RQ_ISSUE) || is(&BLOCK_RQ_COMPLETE) {
+        let ctx: BlockRqContext = context(data);
+        let len = ctx.rwbs.iter().position(|&c| c == 0).unwrap_or(8);
+        let rwbs = core::str::from_utf8(&ctx.rwbs[..len]).unwrap_or("");
+        write!(out, "{} {} + {} ", rwbs, ctx.sector, ctx.nr_sector).unwrap();
+        if is(&BLOCK_RQ_ISSUE) {
+            write!(out, "[{}]", comm_str(&ctx.comm)).unwrap();
+        } else {
+            write!(out, "[{}]", ctx.error).unwrap();
+        }
+    } else if is(&NETIF_RECEIVE_SKB) || is(&NET_DEV_XMIT) {
+        let ctx: NetContext = context(data);
+        write!(out, "skbaddr={:#x} len={}", ctx.skbaddr, ctx.len).unwrap();
+        if is(&NET_DEV_XMIT) {
+            write!(out, " rc={}", ctx.rc).unwrap();
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Event filters on the current thread, written to `events/*/*/filter`
+//! like `common_pid == 42 || comm == "sh" && common_pid != 1`
+
+use super::comm_str;
+use alloc::string::String;
+use alloc::vec::Vec;
+use rcore_fs::vfs::{FsError, Result};
+
+enum Value {
+    Pid(usize),
+    Comm(String),
+}
+
+struct Predicate {
+    value: Value,
+    equal: bool,
+}
+
+impl Predicate {
+    fn parse(text: &str) -> Result<Self> {
+        let (pos, equal) = match (text.find("=="), text.find("!=")) {
+            (Some(pos), None) => (pos, true),
+            (None, Some(pos)) => (pos, false),
+            _ => return Err(FsError::InvalidParam),
+        };
+        let field = text[..pos].trim();
+        let value = text[(pos + 2)..].trim();
+        let value = match field {
+            "common_pid" => Value::Pid(value.parse().map_err(|_| FsError::InvalidParam)?),
+            "comm" => {
+                let value = value.trim_matches('"');
+                Value::Comm(String::from(value))
+            }
+            _ => return Err(FsError::InvalidParam),
+        };
+        Ok(Predicate { value, equal })
+    }
+
+    fn matches(&self, pid: usize, comm: &[u8; 16]) -> bool {
+        let equal = match &self.value {
+            Value::Pid(value) => pid == *value,
+            Value::Comm(value) => comm_str(comm) == value,
+        };
+        equal == self.equal
+    }
+}
+
+/// Predicates joined by `&&` and `||`, `&&` binds tighter
+pub struct Filter {
+    text: String,
+    /// Any of the groups matches if all of its predicates do
+    groups: Vec<Vec<Predicate>>,
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let groups = text
+            .split("||")
+            .map(|group| group.split("&&").map(Predicate::parse).collect())
+            .collect::<Result<_>>()?;
+        Ok(Filter {
+            text: String::from(text),
+            groups,
+        })
+    }
+
+    pub fn matches(&self, pid: usize, comm: &[u8; 16]) -> bool {
+        self.groups
+            .iter()
+            .any(|group| group.iter().all(|predicate| predicate.matches(pid, comm)))
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
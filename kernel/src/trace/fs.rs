@@ -0,0 +1,606 @@
+//! tracefs, the control files of the tracer at /sys/kernel/tracing
+//!
+//! ```text
+//! trace                       entries in the buffers, truncate to clear
+//! trace_pipe                  entries consumed as they are read, blocking
+//! tracing_on                  1 to record entries, 0 to stop
+//! current_tracer              nop or function
+//! available_tracers
+//! set_ftrace_filter           functions traced by the function tracer
+//! available_filter_functions
+//! set_ftrace_pid              threads traced by the function tracer
+//! set_event                   enabled events, `!` disables one
+//! available_events
+//! set_event_pid               threads events are recorded for
+//! buffer_size_kb              size of the buffer of each CPU
+//! kprobe_events               definitions of kprobe events
+//! events/enable               events/SYSTEM/enable
+//! events/SYSTEM/EVENT/{enable,filter,id}
+//! ```
+
+use super::buffer;
+use super::event::{events, TraceEvent};
+use super::filter::Filter;
+use super::{function, kprobe_event, show_pids, store_pids, TRACING_ON};
+use super::{EVENT_PIDS, FTRACE_PIDS};
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use rcore_fs::vfs::*;
+use rcore_fs_devfs::DevFS;
+
+type Show = Box<dyn Fn() -> String + Send + Sync>;
+type Store = Box<dyn Fn(&str) -> Result<()> + Send + Sync>;
+type Truncate = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// Inode numbers of files with fixed ones, events have theirs after
+static NEXT_INODE: AtomicUsize = AtomicUsize::new(2);
+const EVENTS_INODE: usize = 0x1000;
+
+fn metadata(inode: usize, type_: FileType, mode: u16) -> Metadata {
+    Metadata {
+        dev: 0,
+        inode,
+        size: 0,
+        blk_size: 0,
+        blocks: 0,
+        atime: Timespec { sec: 0, nsec: 0 },
+        mtime: Timespec { sec: 0, nsec: 0 },
+        ctime: Timespec { sec: 0, nsec: 0 },
+        type_,
+        mode,
+        nlinks: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+    }
+}
+
+/// A file whose content is shown from the state of the tracer on each
+/// read, and written lines are stored to it
+struct ControlFile {
+    inode: usize,
+    show: Show,
+    store: Option<Store>,
+    /// On open with `O_TRUNC`, as `>` in shells
+    truncate: Option<Truncate>,
+}
+
+impl ControlFile {
+    fn new(inode: usize, show: Show, store: Option<Store>, truncate: Option<Truncate>) -> Self {
+        ControlFile {
+            inode,
+            show,
+            store,
+            truncate,
+        }
+    }
+
+    fn read_only(show: Show) -> Self {
+        Self::new(NEXT_INODE.fetch_add(1, Ordering::Relaxed), show, None, None)
+    }
+
+    fn writable(show: Show, store: Store, truncate: Option<Truncate>) -> Self {
+        let inode = NEXT_INODE.fetch_add(1, Ordering::Relaxed);
+        Self::new(inode, show, Some(store), truncate)
+    }
+}
+
+impl INode for ControlFile {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let text = (self.show)();
+        let text = text.as_bytes();
+        if offset >= text.len() {
+            return Ok(0);
+        }
+        let len = (text.len() - offset).min(buf.len());
+        buf[..len].copy_from_slice(&text[offset..(offset + len)]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        let store = self.store.as_ref().ok_or(FsError::NotSupported)?;
+        let text = core::str::from_utf8(buf).map_err(|_| FsError::InvalidParam)?;
+        store(text)?;
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: self.store.is_some(),
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let mode = if self.store.is_some() { 0o644 } else { 0o444 };
+        Ok(metadata(self.inode, FileType::File, mode))
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        match &self.truncate {
+            Some(truncate) if len == 0 => truncate(),
+            _ => Ok(()),
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// `trace_pipe`, which consumes entries as it prints them
+struct TracePipe {
+    inode: usize,
+    /// Printed entries not read yet
+    pending: Mutex<Vec<u8>>,
+}
+
+impl TracePipe {
+    fn can_read(&self) -> bool {
+        !self.pending.lock().is_empty() || !buffer::is_empty()
+    }
+}
+
+impl INode for TracePipe {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut pending = self.pending.lock();
+        if pending.is_empty() {
+            let mut text = String::new();
+            while text.len() < buf.len() {
+                match buffer::consume() {
+                    Some(entry) => entry.print(&mut text),
+                    None => break,
+                }
+            }
+            pending.extend_from_slice(text.as_bytes());
+        }
+        if pending.is_empty() {
+            return Err(FsError::Again);
+        }
+        let len = pending.len().min(buf.len());
+        buf[..len].copy_from_slice(&pending[..len]);
+        pending.drain(..len);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: self.can_read(),
+            write: false,
+            error: false,
+        })
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct TracePipeFuture<'a> {
+            pipe: &'a TracePipe,
+        }
+
+        impl<'a> Future for TracePipeFuture<'a> {
+            type Output = Result<PollStatus>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                if self.pipe.can_read() {
+                    return Poll::Ready(self.pipe.poll());
+                }
+                let waker = cx.waker().clone();
+                buffer::EVENTBUS.lock().subscribe(Box::new({
+                    move |_| {
+                        waker.wake_by_ref();
+                        true
+                    }
+                }));
+                Poll::Pending
+            }
+        }
+
+        Box::pin(TracePipeFuture { pipe: self })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(metadata(self.inode, FileType::File, 0o444))
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Directories under `events`, generated from the events there are
+#[derive(Clone)]
+enum TraceDir {
+    /// `events`, in the root of `fs`
+    Events {
+        fs: Weak<DevFS>,
+    },
+    System {
+        fs: Weak<DevFS>,
+        system: String,
+    },
+    Event {
+        fs: Weak<DevFS>,
+        event: Arc<TraceEvent>,
+    },
+}
+
+impl TraceDir {
+    /// Events in the directory
+    fn events(&self) -> Vec<Arc<TraceEvent>> {
+        match self {
+            TraceDir::Events { .. } => events(),
+            TraceDir::System { system, .. } => events()
+                .into_iter()
+                .filter(|event| &event.system == system)
+                .collect(),
+            TraceDir::Event { event, .. } => vec![event.clone()],
+        }
+    }
+
+    fn entries(&self) -> Vec<String> {
+        let mut entries = vec![String::from("enable")];
+        match self {
+            TraceDir::Events { .. } => {
+                let systems: BTreeSet<_> = events()
+                    .into_iter()
+                    .map(|event| event.system.clone())
+                    .collect();
+                entries.extend(systems);
+            }
+            TraceDir::System { .. } => {
+                entries.extend(self.events().into_iter().map(|event| event.name.clone()));
+            }
+            TraceDir::Event { .. } => {
+                entries.push(String::from("filter"));
+                entries.push(String::from("id"));
+            }
+        }
+        entries
+    }
+
+    /// Inode numbers for the events follow `EVENTS_INODE`, from the
+    /// events themselves or the first event of a system
+    fn inode(&self, file: usize) -> usize {
+        match self {
+            TraceDir::Events { .. } => EVENTS_INODE + file,
+            TraceDir::System { .. } => {
+                let id = self.events().first().map_or(0, |event| event.id);
+                EVENTS_INODE + 8 + id * 8 + 4 + file
+            }
+            TraceDir::Event { event, .. } => EVENTS_INODE + 8 + event.id * 8 + file,
+        }
+    }
+
+    fn enable_file(&self) -> ControlFile {
+        let dir = self.clone();
+        let show = Box::new(move || {
+            let events = dir.events();
+            let enabled = events.iter().filter(|event| event.enabled()).count();
+            let state = match enabled {
+                0 => "0",
+                n if n == events.len() => "1",
+                _ => "X",
+            };
+            format!("{}\n", state)
+        });
+        let dir = self.clone();
+        let store = Box::new(move |text: &str| -> Result<()> {
+            let enabled = parse_bool(text)?;
+            for event in dir.events() {
+                event.set_enabled(enabled)?;
+            }
+            Ok(())
+        });
+        ControlFile::new(self.inode(1), show, Some(store), None)
+    }
+}
+
+impl INode for TraceDir {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(metadata(self.inode(0), FileType::Dir, 0o755))
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match (self, name) {
+            (_, ".") => Ok(Arc::new(self.clone())),
+            (TraceDir::Events { fs }, "..") => {
+                let fs = fs.upgrade().ok_or(FsError::EntryNotFound)?;
+                Ok(fs.root_inode())
+            }
+            (TraceDir::System { fs, .. }, "..") => {
+                Ok(Arc::new(TraceDir::Events { fs: fs.clone() }))
+            }
+            (TraceDir::Event { fs, event }, "..") => Ok(Arc::new(TraceDir::System {
+                fs: fs.clone(),
+                system: event.system.clone(),
+            })),
+            (_, "enable") => Ok(Arc::new(self.enable_file())),
+            (TraceDir::Events { fs }, system) => {
+                if events().iter().all(|event| event.system != system) {
+                    return Err(FsError::EntryNotFound);
+                }
+                Ok(Arc::new(TraceDir::System {
+                    fs: fs.clone(),
+                    system: String::from(system),
+                }))
+            }
+            (TraceDir::System { fs, .. }, name) => {
+                let event = self
+                    .events()
+                    .into_iter()
+                    .find(|event| event.name == name)
+                    .ok_or(FsError::EntryNotFound)?;
+                Ok(Arc::new(TraceDir::Event {
+                    fs: fs.clone(),
+                    event,
+                }))
+            }
+            (TraceDir::Event { event, .. }, "filter") => {
+                let shown = event.clone();
+                let show = Box::new(move || format!("{}\n", shown.filter()));
+                let stored = event.clone();
+                let store = Box::new(move |text: &str| -> Result<()> {
+                    let text = text.trim();
+                    // `0` removes the filter, like in Linux
+                    let filter = match text {
+                        "" | "0" => None,
+                        _ => Some(Filter::parse(text)?),
+                    };
+                    stored.set_filter(filter);
+                    Ok(())
+                });
+                Ok(Arc::new(ControlFile::new(
+                    self.inode(2),
+                    show,
+                    Some(store),
+                    None,
+                )))
+            }
+            (TraceDir::Event { event, .. }, "id") => {
+                let id = event.id;
+                let show = Box::new(move || format!("{}\n", id));
+                Ok(Arc::new(ControlFile::new(self.inode(3), show, None, None)))
+            }
+            _ => Err(FsError::EntryNotFound),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            i => self
+                .entries()
+                .into_iter()
+                .nth(i - 2)
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn parse_bool(text: &str) -> Result<bool> {
+    match text.trim() {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => Err(FsError::InvalidParam),
+    }
+}
+
+fn show_trace() -> String {
+    let entries = buffer::snapshot();
+    let (_, written) = buffer::stats();
+    let mut text = String::new();
+    writeln!(text, "# tracer: {}", function::current_tracer()).unwrap();
+    writeln!(text, "#").unwrap();
+    writeln!(
+        text,
+        "# entries-in-buffer/entries-written: {}/{}",
+        entries.len(),
+        written
+    )
+    .unwrap();
+    writeln!(text, "#").unwrap();
+    writeln!(
+        text,
+        "#                TASK-PID      CPU#     TIMESTAMP  FUNCTION"
+    )
+    .unwrap();
+    writeln!(
+        text,
+        "#                   | |          |         |         |"
+    )
+    .unwrap();
+    for entry in entries.iter() {
+        entry.print(&mut text);
+    }
+    text
+}
+
+/// Enable or disable events, `[!][SYSTEM:]EVENT` where either may be `*`
+fn store_event(text: &str) -> Result<()> {
+    for word in text.split_whitespace() {
+        let (enabled, target) = match word.strip_prefix('!') {
+            Some(target) => (false, target),
+            None => (true, word),
+        };
+        let (system, name) = match target.find(':') {
+            Some(pos) => (&target[..pos], &target[(pos + 1)..]),
+            None => ("*", target),
+        };
+        let matched: Vec<_> = events()
+            .into_iter()
+            .filter(|event| system == "*" || event.system == system)
+            .filter(|event| name == "*" || event.name == name)
+            .collect();
+        if matched.is_empty() {
+            return Err(FsError::EntryNotFound);
+        }
+        for event in matched {
+            event.set_enabled(enabled)?;
+        }
+    }
+    Ok(())
+}
+
+/// Event names as `SYSTEM:EVENT` lines
+fn show_events(enabled_only: bool) -> String {
+    let mut text = String::new();
+    for event in events() {
+        if !enabled_only || event.enabled() {
+            writeln!(text, "{}:{}", event.system, event.name).unwrap();
+        }
+    }
+    text
+}
+
+/// Create tracefs, to be mounted at /sys/kernel/tracing
+pub fn tracefs() -> Arc<DevFS> {
+    let fs = DevFS::new();
+    let mut files: Vec<(&str, Arc<dyn INode>)> = Vec::new();
+
+    let trace = ControlFile::writable(
+        Box::new(show_trace),
+        Box::new(|_: &str| Ok(())),
+        Some(Box::new(|| -> Result<()> {
+            buffer::clear();
+            Ok(())
+        })),
+    );
+    files.push(("trace", Arc::new(trace)));
+    let trace_pipe = TracePipe {
+        inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+        pending: Mutex::new(Vec::new()),
+    };
+    files.push(("trace_pipe", Arc::new(trace_pipe)));
+
+    let tracing_on = ControlFile::writable(
+        Box::new(|| format!("{}\n", TRACING_ON.load(Ordering::Relaxed) as u8)),
+        Box::new(|text: &str| -> Result<()> {
+            TRACING_ON.store(parse_bool(text)?, Ordering::Relaxed);
+            Ok(())
+        }),
+        None,
+    );
+    files.push(("tracing_on", Arc::new(tracing_on)));
+
+    let current_tracer = ControlFile::writable(
+        Box::new(|| format!("{}\n", function::current_tracer())),
+        Box::new(|text: &str| function::set_current_tracer(text.trim())),
+        None,
+    );
+    files.push(("current_tracer", Arc::new(current_tracer)));
+    let available_tracers =
+        ControlFile::read_only(Box::new(|| format!("{}\n", function::TRACERS.join(" "))));
+    files.push(("available_tracers", Arc::new(available_tracers)));
+
+    let set_ftrace_filter = ControlFile::writable(
+        Box::new(function::show_filter),
+        Box::new(function::store_filter),
+        Some(Box::new(function::clear_filter)),
+    );
+    files.push(("set_ftrace_filter", Arc::new(set_ftrace_filter)));
+    let available_filter_functions =
+        ControlFile::read_only(Box::new(function::available_functions));
+    files.push((
+        "available_filter_functions",
+        Arc::new(available_filter_functions),
+    ));
+    let set_ftrace_pid = ControlFile::writable(
+        Box::new(|| show_pids(&FTRACE_PIDS)),
+        Box::new(|text: &str| store_pids(&FTRACE_PIDS, text)),
+        Some(Box::new(|| -> Result<()> {
+            FTRACE_PIDS.write().clear();
+            Ok(())
+        })),
+    );
+    files.push(("set_ftrace_pid", Arc::new(set_ftrace_pid)));
+
+    let set_event = ControlFile::writable(
+        Box::new(|| show_events(true)),
+        Box::new(store_event),
+        Some(Box::new(|| -> Result<()> {
+            for event in events() {
+                event.set_enabled(false)?;
+            }
+            Ok(())
+        })),
+    );
+    files.push(("set_event", Arc::new(set_event)));
+    let available_events = ControlFile::read_only(Box::new(|| show_events(false)));
+    files.push(("available_events", Arc::new(available_events)));
+    let set_event_pid = ControlFile::writable(
+        Box::new(|| show_pids(&EVENT_PIDS)),
+        Box::new(|text: &str| store_pids(&EVENT_PIDS, text)),
+        Some(Box::new(|| -> Result<()> {
+            EVENT_PIDS.write().clear();
+            Ok(())
+        })),
+    );
+    files.push(("set_event_pid", Arc::new(set_event_pid)));
+
+    let buffer_size_kb = ControlFile::writable(
+        Box::new(|| format!("{}\n", buffer::size_kb())),
+        Box::new(|text: &str| -> Result<()> {
+            let size_kb = text.trim().parse().map_err(|_| FsError::InvalidParam)?;
+            if size_kb == 0 {
+                return Err(FsError::InvalidParam);
+            }
+            buffer::set_size_kb(size_kb);
+            Ok(())
+        }),
+        None,
+    );
+    files.push(("buffer_size_kb", Arc::new(buffer_size_kb)));
+
+    let kprobe_events = ControlFile::writable(
+        Box::new(kprobe_event::show),
+        Box::new(|text: &str| text.lines().try_for_each(kprobe_event::command)),
+        Some(Box::new(kprobe_event::clear)),
+    );
+    files.push(("kprobe_events", Arc::new(kprobe_events)));
+
+    files.push((
+        "events",
+        Arc::new(TraceDir::Events {
+            fs: Arc::downgrade(&fs),
+        }),
+    ));
+    for (name, file) in files {
+        fs.add(name, file)
+            .expect("failed to create a file in tracefs");
+    }
+    fs
+}
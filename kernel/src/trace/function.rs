@@ -0,0 +1,216 @@
+//! The function tracer, which records the kernel functions called
+//!
+//! Functions are probed with kprobes at their entries, so only those in
+//! `set_ftrace_filter` are traced, at most `MAX_FUNCTIONS` of them. Code
+//! the probes themselves run is never traced.
+
+use super::buffer::{self, Record};
+use super::{current_task, pid_allowed, tracing_on, FTRACE_PIDS};
+use crate::backtrace;
+use crate::kprobes::{
+    get_return_address, register_kprobe, unregister_kprobe, KProbeArgs, TrapFrame,
+};
+use crate::lkm::manager::ModuleManager;
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use rcore_fs::vfs::{FsError, Result};
+
+/// Functions probed at most, each probe patches the kernel text
+const MAX_FUNCTIONS: usize = 512;
+
+/// Modules probes run in, tracing them would recurse
+const NOTRACE: &[&str] = &[
+    "rcore::arch::",
+    "rcore::backtrace::",
+    "rcore::kprobes::",
+    "rcore::lkm::",
+    "rcore::memory::",
+    "rcore::process::",
+    "rcore::sync::",
+    "rcore::trace::",
+    "rcore::trap::",
+];
+
+pub const TRACERS: &[&str] = &["function", "nop"];
+
+struct FunctionTracer {
+    enabled: bool,
+    /// Functions to trace, by address
+    functions: BTreeMap<usize, String>,
+    /// Addresses of the registered probes
+    probes: Vec<usize>,
+}
+
+lazy_static! {
+    static ref TRACER: Mutex<FunctionTracer> = Mutex::new(FunctionTracer {
+        enabled: false,
+        functions: BTreeMap::new(),
+        probes: Vec::new(),
+    });
+}
+
+impl FunctionTracer {
+    fn register(&mut self) {
+        for &addr in self.functions.keys() {
+            let args = KProbeArgs {
+                pre_handler: Arc::new(trace_function),
+                post_handler: None,
+                user_data: addr,
+            };
+            // skip functions probed by others
+            if register_kprobe(addr, args).is_some() {
+                self.probes.push(addr);
+            }
+        }
+    }
+
+    fn unregister(&mut self) {
+        for addr in self.probes.drain(..) {
+            unregister_kprobe(addr);
+        }
+    }
+}
+
+fn trace_function(tf: &mut TrapFrame, ip: usize) -> isize {
+    if !tracing_on() {
+        return 0;
+    }
+    let (pid, comm) = current_task();
+    if pid_allowed(&FTRACE_PIDS, pid) {
+        let parent_ip = get_return_address(tf);
+        buffer::record(pid, comm, Record::Function { ip, parent_ip });
+    }
+    0
+}
+
+/// Demangled kernel functions that can be traced, by address
+fn traceable_functions() -> BTreeMap<usize, String> {
+    ModuleManager::with(|mm| {
+        mm.get_kernel_symbols()
+            .iter()
+            .filter(|(name, _)| {
+                name.starts_with("rcore::")
+                    && !NOTRACE.iter().any(|module| name.starts_with(module))
+            })
+            .map(|(name, addr)| (*addr, name.clone()))
+            .collect()
+    })
+}
+
+/// Match `name` against `pattern`, where `*` matches any characters
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == name,
+        Some(pos) => {
+            let (head, rest) = (&pattern[..pos], &pattern[(pos + 1)..]);
+            if !name.starts_with(head) {
+                return false;
+            }
+            let name = &name[head.len()..];
+            (0..=name.len())
+                .filter(|&i| name.is_char_boundary(i))
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+pub fn current_tracer() -> &'static str {
+    match TRACER.lock().enabled {
+        true => "function",
+        false => "nop",
+    }
+}
+
+pub fn set_current_tracer(name: &str) -> Result<()> {
+    let mut tracer = TRACER.lock();
+    let enabled = match name {
+        "function" => true,
+        "nop" => false,
+        _ => return Err(FsError::InvalidParam),
+    };
+    if tracer.enabled != enabled {
+        if enabled {
+            tracer.register();
+        } else {
+            tracer.unregister();
+        }
+        tracer.enabled = enabled;
+    }
+    Ok(())
+}
+
+pub fn available_functions() -> String {
+    let mut text = String::new();
+    for name in traceable_functions().values() {
+        writeln!(text, "{}", name).unwrap();
+    }
+    text
+}
+
+pub fn show_filter() -> String {
+    let mut text = String::new();
+    for name in TRACER.lock().functions.values() {
+        writeln!(text, "{}", name).unwrap();
+    }
+    text
+}
+
+/// Add the functions matching the patterns in `text`, truncating the file
+/// clears them
+pub fn store_filter(text: &str) -> Result<()> {
+    let mut functions = TRACER.lock().functions.clone();
+    let traceable = traceable_functions();
+    for pattern in text.split_whitespace() {
+        let matched: Vec<_> = traceable
+            .iter()
+            .filter(|(_, name)| glob_match(pattern, name))
+            .collect();
+        if matched.is_empty() {
+            return Err(FsError::InvalidParam);
+        }
+        functions.extend(
+            matched
+                .into_iter()
+                .map(|(&addr, name)| (addr, name.clone())),
+        );
+    }
+    set_filter(functions)
+}
+
+pub fn clear_filter() -> Result<()> {
+    set_filter(BTreeMap::new())
+}
+
+fn set_filter(functions: BTreeMap<usize, String>) -> Result<()> {
+    if functions.len() > MAX_FUNCTIONS {
+        return Err(FsError::InvalidParam);
+    }
+    let mut tracer = TRACER.lock();
+    let enabled = tracer.enabled;
+    if enabled {
+        tracer.unregister();
+    }
+    tracer.functions = functions;
+    if enabled {
+        tracer.register();
+    }
+    Ok(())
+}
+
+/// Print a function entry as `function <-parent`
+pub fn print(ip: usize, parent_ip: usize, out: &mut String) {
+    print_symbol(ip, out);
+    out.push_str(" <-");
+    print_symbol(parent_ip, out);
+}
+
+fn print_symbol(pc: usize, out: &mut String) {
+    match backtrace::symbol(pc) {
+        Some((name, _)) => out.push_str(&name),
+        None => write!(out, "{:#x}", pc).unwrap(),
+    }
+}
@@ -0,0 +1,467 @@
+//! Kprobe events defined by writing to `kprobe_events`, like in Linux:
+//!
+//! ```text
+//! p[:[GRP/]EVENT] SYM[+OFFS]|ADDR [FETCHARGS]    probe an instruction
+//! r[MAXACTIVE][:[GRP/]EVENT] SYM[+0] [FETCHARGS] probe a function return
+//! -:[GRP/]EVENT                                  delete an event
+//! ```
+//!
+//! A fetcharg is `[NAME=]FETCH[:TYPE]`, where FETCH is one of `$argN`
+//! (from 1), `$retval`, `$comm`, `@ADDR`, `@SYM[+OFFS]`, `\IMM` or
+//! `+|-OFFS(FETCH)` to dereference, and TYPE is `u8` to `u64`, `s8` to
+//! `s64`, `x8` to `x64` or `string` for `$comm`. Registers are not
+//! supported as they differ between architectures.
+
+use super::buffer::EVENT_WORDS;
+use super::comm_str;
+use super::event::{add_event, events, find_event, remove_event, EventKind, TraceEvent};
+use crate::consts::{KERNEL_OFFSET, PHYSICAL_MEMORY_OFFSET};
+use crate::kprobes::{
+    get_arg, get_retval, register_kprobe, register_kretprobe, unregister_kprobe,
+    unregister_kretprobe, KProbeArgs, KRetProbeArgs, TrapFrame,
+};
+use crate::lkm::manager::ModuleManager;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt::Write;
+use rcore_fs::vfs::{FsError, Result};
+
+/// Group of events without one
+const DEFAULT_GROUP: &str = "kprobes";
+
+/// Arguments in registers on all architectures
+const MAX_ARG: usize = 6;
+
+enum Fetch {
+    /// `$argN`, from 0 here
+    Arg(usize),
+    Retval,
+    Comm,
+    Imm(u64),
+    /// `@ADDR` or `@SYM[+OFFS]`
+    Memory(usize),
+    /// `+|-OFFS(FETCH)`
+    Deref(isize, Box<Fetch>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Unsigned,
+    Signed,
+    Hex,
+    String,
+}
+
+struct FetchArg {
+    name: String,
+    fetch: Fetch,
+    /// Bytes of the value
+    size: usize,
+    format: Format,
+    /// Where the value is in the data of entries
+    word: usize,
+}
+
+pub struct KProbeEvent {
+    ret: bool,
+    /// Where it probes, as written
+    symbol: String,
+    offset: usize,
+    addr: usize,
+    /// Instances of a kretprobe
+    maxactive: Option<usize>,
+    args: Vec<FetchArg>,
+}
+
+impl KProbeEvent {
+    pub fn register(&self, event: &Arc<TraceEvent>) -> Result<()> {
+        let event = event.clone();
+        let handler = Arc::new(move |tf: &mut TrapFrame, _: usize| -> isize {
+            if let EventKind::KProbe(kprobe) = &event.kind {
+                let (data, faults) = kprobe.fetch(tf);
+                event.record(data, faults);
+            }
+            0
+        });
+        let registered = if self.ret {
+            let args = KRetProbeArgs {
+                exit_handler: handler,
+                entry_handler: None,
+                limit: self.maxactive,
+                user_data: 0,
+            };
+            register_kretprobe(self.addr, args)
+        } else {
+            let args = KProbeArgs {
+                pre_handler: handler,
+                post_handler: None,
+                user_data: 0,
+            };
+            register_kprobe(self.addr, args)
+        };
+        // another probe is there
+        registered.ok_or(FsError::Busy)
+    }
+
+    pub fn unregister(&self) {
+        if self.ret {
+            unregister_kretprobe(self.addr);
+        } else {
+            unregister_kprobe(self.addr);
+        }
+    }
+
+    fn fetch(&self, tf: &TrapFrame) -> ([u64; EVENT_WORDS], u8) {
+        let mut data = [0u64; EVENT_WORDS];
+        let mut faults = 0;
+        for (i, arg) in self.args.iter().enumerate() {
+            if arg.format == Format::String {
+                // only `$comm` is a string
+                let (_, comm) = super::current_task();
+                let (lo, hi) = comm.split_at(8);
+                data[arg.word] = u64::from_le_bytes(lo.try_into().unwrap());
+                data[arg.word + 1] = u64::from_le_bytes(hi.try_into().unwrap());
+                continue;
+            }
+            match fetch(&arg.fetch, tf, arg.size) {
+                Some(value) => data[arg.word] = value,
+                None => faults |= 1 << i,
+            }
+        }
+        (data, faults)
+    }
+
+    pub fn print(&self, data: &[u64; EVENT_WORDS], faults: u8, out: &mut String) {
+        out.push_str(if self.ret { "(<- " } else { "(" });
+        if self.symbol.is_empty() {
+            write!(out, "{:#x})", self.addr).unwrap();
+        } else {
+            write!(out, "{}+{:#x})", self.symbol, self.offset).unwrap();
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            write!(out, " {}=", arg.name).unwrap();
+            if faults & 1 << i != 0 {
+                out.push_str("(fault)");
+                continue;
+            }
+            let value = data[arg.word];
+            let bits = arg.size * 8;
+            let value = if bits < 64 {
+                value & ((1 << bits) - 1)
+            } else {
+                value
+            };
+            match arg.format {
+                Format::Unsigned => write!(out, "{}", value),
+                Format::Hex => write!(out, "{:#x}", value),
+                // sign extend
+                Format::Signed => write!(out, "{}", ((value << (64 - bits)) as i64) >> (64 - bits)),
+                Format::String => {
+                    let mut comm = [0u8; 16];
+                    comm[..8].copy_from_slice(&data[arg.word].to_le_bytes());
+                    comm[8..].copy_from_slice(&data[arg.word + 1].to_le_bytes());
+                    write!(out, "\"{}\"", comm_str(&comm))
+                }
+            }
+            .unwrap();
+        }
+    }
+
+    /// The definition, as listed by `kprobe_events`
+    fn definition(&self, event: &TraceEvent, out: &mut String) {
+        let kind = if self.ret { 'r' } else { 'p' };
+        write!(out, "{}:{}/{} ", kind, event.system, event.name).unwrap();
+        if self.symbol.is_empty() {
+            write!(out, "{:#x}", self.addr).unwrap();
+        } else if self.offset != 0 {
+            write!(out, "{}+{}", self.symbol, self.offset).unwrap();
+        } else {
+            out.push_str(&self.symbol);
+        }
+        for arg in self.args.iter() {
+            write!(out, " {}={}", arg.name, arg.text()).unwrap();
+        }
+        out.push('\n');
+    }
+}
+
+impl FetchArg {
+    fn text(&self) -> String {
+        let mut text = String::new();
+        print_fetch(&self.fetch, &mut text);
+        let format = match self.format {
+            Format::Unsigned => 'u',
+            Format::Signed => 's',
+            Format::Hex => 'x',
+            Format::String => {
+                text.push_str(":string");
+                return text;
+            }
+        };
+        write!(text, ":{}{}", format, self.size * 8).unwrap();
+        text
+    }
+}
+
+fn print_fetch(fetch: &Fetch, out: &mut String) {
+    match fetch {
+        Fetch::Arg(n) => write!(out, "$arg{}", n + 1),
+        Fetch::Retval => write!(out, "$retval"),
+        Fetch::Comm => write!(out, "$comm"),
+        Fetch::Imm(value) => write!(out, "\\{}", value),
+        Fetch::Memory(addr) => write!(out, "@{:#x}", addr),
+        Fetch::Deref(offset, inner) => {
+            write!(out, "{:+}(", offset).unwrap();
+            print_fetch(inner, out);
+            write!(out, ")")
+        }
+    }
+    .unwrap();
+}
+
+/// Whether reading `addr` will not fault, the kernel maps all of its space
+fn is_kernel_address(addr: usize) -> bool {
+    addr >= KERNEL_OFFSET.min(PHYSICAL_MEMORY_OFFSET)
+}
+
+fn read_kernel(addr: usize, size: usize) -> Option<u64> {
+    if !is_kernel_address(addr) || !is_kernel_address(addr + size - 1) {
+        return None;
+    }
+    let value = unsafe {
+        match size {
+            1 => (addr as *const u8).read_unaligned() as u64,
+            2 => (addr as *const u16).read_unaligned() as u64,
+            4 => (addr as *const u32).read_unaligned() as u64,
+            _ => (addr as *const u64).read_unaligned(),
+        }
+    };
+    Some(value)
+}
+
+/// Fetch `size` bytes, pointers followed on the way are 8 bytes
+fn fetch(fetch: &Fetch, tf: &TrapFrame, size: usize) -> Option<u64> {
+    match fetch {
+        Fetch::Arg(n) => Some(get_arg(tf, *n) as u64),
+        Fetch::Retval => Some(get_retval(tf) as u64),
+        Fetch::Comm => None,
+        Fetch::Imm(value) => Some(*value),
+        Fetch::Memory(addr) => read_kernel(*addr, size),
+        Fetch::Deref(offset, inner) => {
+            let base = self::fetch(inner, tf, 8)? as usize;
+            read_kernel(base.wrapping_add(*offset as usize), size)
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Result<u64> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    result.map_err(|_| FsError::InvalidParam)
+}
+
+/// `SYM[+OFFS]` or `ADDR`, with the symbol and offset
+fn parse_location(text: &str) -> Result<(usize, String, usize)> {
+    if text.starts_with("0x") {
+        return Ok((parse_number(text)? as usize, String::new(), 0));
+    }
+    let (symbol, offset) = match text.find('+') {
+        Some(pos) => (&text[..pos], parse_number(&text[(pos + 1)..])? as usize),
+        None => (text, 0),
+    };
+    let addr = ModuleManager::with(|mm| mm.resolve_symbol(symbol)).ok_or(FsError::EntryNotFound)?;
+    Ok((addr + offset, String::from(symbol), offset))
+}
+
+fn parse_fetch(text: &str, ret: bool) -> Result<Fetch> {
+    if let Some(n) = text.strip_prefix("$arg") {
+        let n: usize = n.parse().map_err(|_| FsError::InvalidParam)?;
+        if n == 0 || n > MAX_ARG {
+            return Err(FsError::InvalidParam);
+        }
+        return Ok(Fetch::Arg(n - 1));
+    }
+    match text {
+        "$retval" if ret => return Ok(Fetch::Retval),
+        "$comm" => return Ok(Fetch::Comm),
+        _ => {}
+    }
+    if let Some(imm) = text.strip_prefix('\\') {
+        return Ok(Fetch::Imm(parse_number(imm)?));
+    }
+    if let Some(location) = text.strip_prefix('@') {
+        let (addr, _, _) = parse_location(location)?;
+        return Ok(Fetch::Memory(addr));
+    }
+    if (text.starts_with('+') || text.starts_with('-')) && text.ends_with(')') {
+        let pos = text.find('(').ok_or(FsError::InvalidParam)?;
+        let offset = parse_number(&text[1..pos])? as isize;
+        let offset = if text.starts_with('-') {
+            -offset
+        } else {
+            offset
+        };
+        let inner = parse_fetch(&text[(pos + 1)..(text.len() - 1)], ret)?;
+        return Ok(Fetch::Deref(offset, Box::new(inner)));
+    }
+    Err(FsError::InvalidParam)
+}
+
+/// `[NAME=]FETCH[:TYPE]` named `argN` by default, placed at data `word`
+fn parse_fetch_arg(text: &str, n: usize, word: usize, ret: bool) -> Result<FetchArg> {
+    let (name, text) = match text.find('=') {
+        Some(pos) => (String::from(&text[..pos]), &text[(pos + 1)..]),
+        None => (format!("arg{}", n + 1), text),
+    };
+    // the type comes after the last colon, which is not in an address
+    let (fetch, ty) = match text.rfind(':') {
+        Some(pos) => (&text[..pos], Some(&text[(pos + 1)..])),
+        None => (text, None),
+    };
+    let fetch = parse_fetch(fetch, ret)?;
+    let is_comm = matches!(fetch, Fetch::Comm);
+    let (format, size) = match ty {
+        None if is_comm => (Format::String, 16),
+        None => (Format::Hex, 8),
+        Some("string") if is_comm => (Format::String, 16),
+        Some(ty) if !is_comm && ty.len() > 1 => {
+            let format = match ty.as_bytes()[0] {
+                b'u' => Format::Unsigned,
+                b's' => Format::Signed,
+                b'x' => Format::Hex,
+                _ => return Err(FsError::InvalidParam),
+            };
+            let size = match &ty[1..] {
+                "8" => 1,
+                "16" => 2,
+                "32" => 4,
+                "64" => 8,
+                _ => return Err(FsError::InvalidParam),
+            };
+            (format, size)
+        }
+        _ => return Err(FsError::InvalidParam),
+    };
+    Ok(FetchArg {
+        name,
+        fetch,
+        size,
+        format,
+        word,
+    })
+}
+
+/// `[GRP/]EVENT`, either may be left out
+fn parse_name(text: &str) -> (Option<&str>, Option<&str>) {
+    let (group, event) = match text.find('/') {
+        Some(pos) => (&text[..pos], &text[(pos + 1)..]),
+        None => ("", text),
+    };
+    (
+        Some(group).filter(|s| !s.is_empty()),
+        Some(event).filter(|s| !s.is_empty()),
+    )
+}
+
+/// Run a line written to `kprobe_events`
+pub fn command(line: &str) -> Result<()> {
+    let line = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let mut words = line.split_whitespace();
+    let head = match words.next() {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    let (kind, name) = match head.find(':') {
+        Some(pos) => (&head[..pos], Some(&head[(pos + 1)..])),
+        None => (head, None),
+    };
+    let (group, event) = name.map_or((None, None), parse_name);
+    let group = group.unwrap_or(DEFAULT_GROUP);
+    if kind == "-" {
+        let event = event.ok_or(FsError::InvalidParam)?;
+        let event = find_kprobe_event(group, event).ok_or(FsError::EntryNotFound)?;
+        return remove_event(&event);
+    }
+    let (ret, maxactive) = match kind.as_bytes().first() {
+        Some(b'p') if kind.len() == 1 => (false, None),
+        Some(b'r') if kind.len() == 1 => (true, None),
+        Some(b'r') => (true, Some(parse_number(&kind[1..])? as usize)),
+        _ => return Err(FsError::InvalidParam),
+    };
+    let location = words.next().ok_or(FsError::InvalidParam)?;
+    let (addr, symbol, offset) = parse_location(location)?;
+    if ret && offset != 0 {
+        return Err(FsError::InvalidParam);
+    }
+    let mut args = Vec::new();
+    let mut word = 0;
+    for (n, text) in words.enumerate() {
+        let arg = parse_fetch_arg(text, n, word, ret)?;
+        word += if arg.format == Format::String { 2 } else { 1 };
+        // faults are a bit for each argument in a byte
+        if word > EVENT_WORDS || n >= 8 {
+            return Err(FsError::InvalidParam);
+        }
+        args.push(arg);
+    }
+    let name = match event {
+        Some(event) => String::from(event),
+        None => {
+            let symbol = if symbol.is_empty() {
+                format!("{:x}", addr)
+            } else {
+                symbol.clone()
+            };
+            format!("{}_{}_{}", if ret { 'r' } else { 'p' }, symbol, offset)
+        }
+    };
+    let kprobe = KProbeEvent {
+        ret,
+        symbol,
+        offset,
+        addr,
+        maxactive,
+        args,
+    };
+    add_event(String::from(group), name, EventKind::KProbe(kprobe))?;
+    Ok(())
+}
+
+fn find_kprobe_event(group: &str, name: &str) -> Option<Arc<TraceEvent>> {
+    find_event(&format!("{}:{}", group, name))
+        .filter(|event| matches!(event.kind, EventKind::KProbe(_)))
+}
+
+/// Definitions of all kprobe events
+pub fn show() -> String {
+    let mut text = String::new();
+    for event in events() {
+        if let EventKind::KProbe(kprobe) = &event.kind {
+            kprobe.definition(&event, &mut text);
+        }
+    }
+    text
+}
+
+/// Remove all kprobe events, they must all be disabled
+pub fn clear() -> Result<()> {
+    let kprobe_events: Vec<_> = events()
+        .into_iter()
+        .filter(|event| matches!(event.kind, EventKind::KProbe(_)))
+        .collect();
+    if kprobe_events.iter().any(|event| event.enabled()) {
+        return Err(FsError::Busy);
+    }
+    for event in kprobe_events.iter() {
+        remove_event(event)?;
+    }
+    Ok(())
+}
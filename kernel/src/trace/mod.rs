@@ -0,0 +1,93 @@
+//! A text tracer like ftrace, controlled through tracefs
+//!
+//! Events and the function tracer record entries in per-CPU ring buffers,
+//! which `trace` and `trace_pipe` print as text. Events are the static
+//! tracepoints and the kprobe events defined in `kprobe_events`.
+//! See `fs` for the files at /sys/kernel/tracing.
+
+mod buffer;
+mod event;
+mod filter;
+mod fs;
+mod function;
+mod kprobe_event;
+
+pub use fs::tracefs;
+
+use crate::process::{current_thread, Thread};
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rcore_fs::vfs::{FsError, Result};
+use spin::RwLock;
+
+/// Whether entries are recorded, probes stay registered while it is off
+static TRACING_ON: AtomicBool = AtomicBool::new(true);
+
+lazy_static! {
+    /// Threads events are recorded for, all threads if empty
+    static ref EVENT_PIDS: RwLock<BTreeSet<usize>> = RwLock::new(BTreeSet::new());
+    /// Threads functions are traced for, all threads if empty
+    static ref FTRACE_PIDS: RwLock<BTreeSet<usize>> = RwLock::new(BTreeSet::new());
+}
+
+fn tracing_on() -> bool {
+    TRACING_ON.load(Ordering::Relaxed)
+}
+
+/// Basename of the executable of `thread`, NUL padded. Empty if the
+/// process is locked, probes may fire while it is.
+fn comm(thread: &Thread) -> [u8; 16] {
+    let mut comm = [0u8; 16];
+    if let Some(proc) = thread.proc.try_lock() {
+        let name = proc.exec_path.rsplit('/').next().unwrap_or("").as_bytes();
+        let len = name.len().min(comm.len() - 1);
+        comm[..len].copy_from_slice(&name[..len]);
+    }
+    comm
+}
+
+/// Thread id and comm of the current thread, 0 and `<idle>` between threads
+fn current_task() -> (usize, [u8; 16]) {
+    match current_thread() {
+        Some(thread) => (thread.tid, comm(&thread)),
+        None => {
+            let mut comm = [0u8; 16];
+            comm[..6].copy_from_slice(b"<idle>");
+            (0, comm)
+        }
+    }
+}
+
+fn comm_str(comm: &[u8; 16]) -> &str {
+    let len = comm.iter().position(|&c| c == 0).unwrap_or(comm.len());
+    core::str::from_utf8(&comm[..len]).unwrap_or("")
+}
+
+/// Whether `set` lets `pid` through
+fn pid_allowed(set: &RwLock<BTreeSet<usize>>, pid: usize) -> bool {
+    // a writer may hold it, but probes must not spin on a lock they interrupted
+    match set.try_read() {
+        Some(pids) => pids.is_empty() || pids.contains(&pid),
+        None => false,
+    }
+}
+
+fn show_pids(set: &RwLock<BTreeSet<usize>>) -> String {
+    let mut text = String::new();
+    for pid in set.read().iter() {
+        writeln!(text, "{}", pid).unwrap();
+    }
+    text
+}
+
+/// Add the pids in `text` to `set`, truncating the file clears it
+fn store_pids(set: &RwLock<BTreeSet<usize>>, text: &str) -> Result<()> {
+    let pids = text
+        .split_whitespace()
+        .map(|pid| pid.parse::<usize>().map_err(|_| FsError::InvalidParam))
+        .collect::<Result<BTreeSet<_>>>()?;
+    set.write().extend(pids);
+    Ok(())
+}
@@ -0,0 +1,285 @@
+//! The static tracepoints and their contexts
+//!
+//! Contexts are laid out like the Linux events of the same name,
+//! so offsets in existing programs still hold, except that fields
+//! rCore has no counterpart for are left out.
+
+use super::Tracepoint;
+use crate::arch::cpu;
+use crate::consts::MAX_CPU_NUM;
+use crate::memory::AccessType;
+use crate::process::{current_thread, Thread, THREADS};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Common header of all contexts
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEntry {
+    pub common_type: u16,
+    pub common_flags: u8,
+    pub common_preempt_count: u8,
+    pub common_pid: i32,
+}
+
+impl TraceEntry {
+    fn new(tp: &Tracepoint) -> Self {
+        TraceEntry {
+            common_type: tp.id,
+            common_flags: 0,
+            common_preempt_count: 0,
+            common_pid: current_thread().map_or(0, |thread| thread.tid as i32),
+        }
+    }
+}
+
+/// `raw_syscalls:sys_enter`, and `syscalls:sys_enter_*`
+/// where `id` is the int `__syscall_nr`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SysEnterContext {
+    pub common: TraceEntry,
+    pub id: i64,
+    pub args: [u64; 6],
+}
+
+/// `raw_syscalls:sys_exit`, and `syscalls:sys_exit_*`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SysExitContext {
+    pub common: TraceEntry,
+    pub id: i64,
+    pub ret: i64,
+}
+
+/// `sched:sched_switch`, threads are named after their executable
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SchedSwitchContext {
+    pub common: TraceEntry,
+    pub prev_comm: [u8; 16],
+    pub prev_pid: i32,
+    pub prev_prio: i32,
+    pub prev_state: i64,
+    pub next_comm: [u8; 16],
+    pub next_pid: i32,
+    pub next_prio: i32,
+}
+
+/// `exceptions:page_fault_user` and `exceptions:page_fault_kernel`,
+/// `error_code` has the x86 meaning: 2 for writes, 4 for user mode
+/// and 16 for instruction fetches
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PageFaultContext {
+    pub common: TraceEntry,
+    pub address: u64,
+    pub error_code: u64,
+}
+
+/// `block:block_rq_issue` and `block:block_rq_complete`, sectors are 512 bytes.
+/// `error` is only set on completion, `comm` only on issue.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BlockRqContext {
+    pub common: TraceEntry,
+    pub sector: u64,
+    pub nr_sector: u32,
+    pub error: i32,
+    pub rwbs: [u8; 8],
+    pub comm: [u8; 16],
+}
+
+/// `net:netif_receive_skb` and `net:net_dev_xmit`,
+/// `skbaddr` points to the ethernet frame
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct NetContext {
+    pub common: TraceEntry,
+    pub skbaddr: u64,
+    pub len: u32,
+    pub rc: i32,
+}
+
+pub static SYS_ENTER: Tracepoint =
+    Tracepoint::new("raw_syscalls", "sys_enter", size_of::<SysEnterContext>(), 0);
+pub static SYS_EXIT: Tracepoint =
+    Tracepoint::new("raw_syscalls", "sys_exit", size_of::<SysExitContext>(), 1);
+pub static SCHED_SWITCH: Tracepoint =
+    Tracepoint::new("sched", "sched_switch", size_of::<SchedSwitchContext>(), 2);
+pub static PAGE_FAULT_USER: Tracepoint = Tracepoint::new(
+    "exceptions",
+    "page_fault_user",
+    size_of::<PageFaultContext>(),
+    3,
+);
+pub static PAGE_FAULT_KERNEL: Tracepoint = Tracepoint::new(
+    "exceptions",
+    "page_fault_kernel",
+    size_of::<PageFaultContext>(),
+    4,
+);
+pub static BLOCK_RQ_ISSUE: Tracepoint =
+    Tracepoint::new("block", "block_rq_issue", size_of::<BlockRqContext>(), 5);
+pub static BLOCK_RQ_COMPLETE: Tracepoint =
+    Tracepoint::new("block", "block_rq_complete", size_of::<BlockRqContext>(), 6);
+pub static NETIF_RECEIVE_SKB: Tracepoint =
+    Tracepoint::new("net", "netif_receive_skb", size_of::<NetContext>(), 7);
+pub static NET_DEV_XMIT: Tracepoint =
+    Tracepoint::new("net", "net_dev_xmit", size_of::<NetContext>(), 8);
+
+/// All static tracepoints, indexed by id
+pub static TRACEPOINTS: [&Tracepoint; 9] = [
+    &SYS_ENTER,
+    &SYS_EXIT,
+    &SCHED_SWITCH,
+    &PAGE_FAULT_USER,
+    &PAGE_FAULT_KERNEL,
+    &BLOCK_RQ_ISSUE,
+    &BLOCK_RQ_COMPLETE,
+    &NETIF_RECEIVE_SKB,
+    &NET_DEV_XMIT,
+];
+
+/// Priority of every thread, there is no priority scheduling
+const DEFAULT_PRIO: i32 = 120;
+
+/// Basename of the executable, truncated and NUL terminated.
+/// Empty if the process is locked, callers may hold that lock.
+fn comm(thread: &Thread) -> [u8; 16] {
+    let mut comm = [0u8; 16];
+    if let Some(proc) = thread.proc.try_lock() {
+        let name = proc.exec_path.rsplit('/').next().unwrap_or("").as_bytes();
+        let len = name.len().min(comm.len() - 1);
+        comm[..len].copy_from_slice(&name[..len]);
+    }
+    comm
+}
+
+pub fn trace_sys_enter(id: usize, args: &[usize; 6]) {
+    if !SYS_ENTER.enabled() {
+        return;
+    }
+    let mut ctx = SysEnterContext {
+        common: TraceEntry::new(&SYS_ENTER),
+        id: id as i64,
+        args: [0; 6],
+    };
+    for (arg, &value) in ctx.args.iter_mut().zip(args.iter()) {
+        *arg = value as u64;
+    }
+    SYS_ENTER.fire(id, &ctx);
+}
+
+pub fn trace_sys_exit(id: usize, ret: isize) {
+    if !SYS_EXIT.enabled() {
+        return;
+    }
+    let ctx = SysExitContext {
+        common: TraceEntry::new(&SYS_EXIT),
+        id: id as i64,
+        ret: ret as i64,
+    };
+    SYS_EXIT.fire(id, &ctx);
+}
+
+const NO_THREAD: AtomicUsize = AtomicUsize::new(0);
+/// Thread that last ran on each CPU
+static LAST_TID: [AtomicUsize; MAX_CPU_NUM] = [NO_THREAD; MAX_CPU_NUM];
+
+/// `next` is about to run on this CPU
+pub fn trace_sched_switch(next: &Thread) {
+    let prev_tid = LAST_TID[cpu::id()].swap(next.tid, Ordering::Relaxed);
+    if !SCHED_SWITCH.enabled() {
+        return;
+    }
+    let prev_comm = match THREADS.read().get(&prev_tid) {
+        Some(prev) => comm(prev),
+        None => [0; 16],
+    };
+    let ctx = SchedSwitchContext {
+        common: TraceEntry::new(&SCHED_SWITCH),
+        prev_comm,
+        prev_pid: prev_tid as i32,
+        prev_prio: DEFAULT_PRIO,
+        prev_state: 0,
+        next_comm: comm(next),
+        next_pid: next.tid as i32,
+        next_prio: DEFAULT_PRIO,
+    };
+    SCHED_SWITCH.fire(0, &ctx);
+}
+
+/// A page fault at `addr`, `access` is None if the arch does not tell
+pub fn trace_page_fault(addr: usize, access: Option<AccessType>, user: bool) {
+    let tp = if user {
+        &PAGE_FAULT_USER
+    } else {
+        &PAGE_FAULT_KERNEL
+    };
+    if !tp.enabled() {
+        return;
+    }
+    let mut error_code = if user { 4 } else { 0 };
+    if let Some(access) = access {
+        if access.write {
+            error_code |= 2;
+        }
+        if access.execute {
+            error_code |= 16;
+        }
+    }
+    let ctx = PageFaultContext {
+        common: TraceEntry::new(tp),
+        address: addr as u64,
+        error_code,
+    };
+    tp.fire(0, &ctx);
+}
+
+/// Block I/O of `len` bytes at 512-byte block `sector`,
+/// `error` is None when it is issued
+pub fn trace_block_rq(sector: usize, len: usize, write: bool, error: Option<i32>) {
+    let tp = if error.is_none() {
+        &BLOCK_RQ_ISSUE
+    } else {
+        &BLOCK_RQ_COMPLETE
+    };
+    if !tp.enabled() {
+        return;
+    }
+    let mut rwbs = [0u8; 8];
+    rwbs[0] = if write { b'W' } else { b'R' };
+    let comm = match (error, current_thread()) {
+        (None, Some(thread)) => comm(&thread),
+        _ => [0; 16],
+    };
+    let ctx = BlockRqContext {
+        common: TraceEntry::new(tp),
+        sector: sector as u64,
+        nr_sector: (len / 512) as u32,
+        error: error.unwrap_or(0),
+        rwbs,
+        comm,
+    };
+    tp.fire(0, &ctx);
+}
+
+/// A frame received, or sent with result `rc`
+pub fn trace_net(frame: &[u8], transmit: bool, rc: i32) {
+    let tp = if transmit {
+        &NET_DEV_XMIT
+    } else {
+        &NETIF_RECEIVE_SKB
+    };
+    if !tp.enabled() {
+        return;
+    }
+    let ctx = NetContext {
+        common: TraceEntry::new(tp),
+        skbaddr: frame.as_ptr() as u64,
+        len: frame.len() as u32,
+        rc,
+    };
+    tp.fire(0, &ctx);
+}
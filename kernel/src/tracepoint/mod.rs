@@ -0,0 +1,126 @@
+//! Static tracepoints placed in the kernel
+//!
+//! Every tracepoint hands a typed context (see `events`) to the probes
+//! registered on it. Names follow Linux, e.g. `syscalls:sys_enter_openat`.
+//! A disabled tracepoint costs one atomic load, so they stay in release builds.
+
+mod events;
+
+pub use events::*;
+
+use crate::arch::cpu;
+use crate::consts::MAX_CPU_NUM;
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+include!(concat!(env!("OUT_DIR"), "/syscall_names.rs"));
+
+pub type Handler = dyn Fn(*const u8) + Send + Sync;
+
+struct Probe {
+    id: usize,
+    filter: Option<usize>,
+    handler: Arc<Handler>,
+}
+
+pub struct Tracepoint {
+    pub system: &'static str,
+    pub name: &'static str,
+    /// Bytes of the context passed to probes
+    pub ctx_size: usize,
+    /// Index in `TRACEPOINTS`, the `common_type` of contexts
+    pub id: u16,
+    enabled: AtomicBool,
+    probes: Mutex<Vec<Probe>>,
+}
+
+impl Tracepoint {
+    const fn new(system: &'static str, name: &'static str, ctx_size: usize, id: u16) -> Self {
+        Self {
+            system,
+            name,
+            ctx_size,
+            id,
+            enabled: AtomicBool::new(false),
+            probes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Whether any probe is registered, check it before building a context
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Run the probes on `ctx`. Probes with a filter only run when it equals `key`.
+    pub fn fire<T>(&self, key: usize, ctx: &T) {
+        // probes may trap into tracepoints again, e.g. by faulting on user memory
+        let cpu_id = cpu::id();
+        if FIRING[cpu_id].swap(true, Ordering::Acquire) {
+            return;
+        }
+        let ctx = ctx as *const T as *const u8;
+        for probe in self.probes.lock().iter() {
+            if probe.filter.map_or(true, |filter| filter == key) {
+                (probe.handler)(ctx);
+            }
+        }
+        FIRING[cpu_id].store(false, Ordering::Release);
+    }
+}
+
+const FIRING_INIT: AtomicBool = AtomicBool::new(false);
+static FIRING: [AtomicBool; MAX_CPU_NUM] = [FIRING_INIT; MAX_CPU_NUM];
+
+static PROBE_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Find a tracepoint by `system:name`. Syscall events like
+/// `syscalls:sys_enter_openat` are `raw_syscalls` ones with the
+/// syscall id as filter.
+pub fn find_tracepoint(target: &str) -> Option<(&'static Tracepoint, Option<usize>)> {
+    let pos = target.find(':')?;
+    let (system, name) = (&target[..pos], &target[(pos + 1)..]);
+    if system == "syscalls" {
+        if let Some(syscall) = name.strip_prefix("sys_enter_") {
+            return Some((&SYS_ENTER, Some(syscall_id(syscall)?)));
+        }
+        if let Some(syscall) = name.strip_prefix("sys_exit_") {
+            return Some((&SYS_EXIT, Some(syscall_id(syscall)?)));
+        }
+        return None;
+    }
+    let tp = TRACEPOINTS
+        .iter()
+        .find(|tp| tp.system == system && tp.name == name)?;
+    Some((tp, None))
+}
+
+pub fn syscall_id(name: &str) -> Option<usize> {
+    SYSCALL_NAMES
+        .iter()
+        .find(|(other, _)| *other == name)
+        .map(|&(_, id)| id)
+}
+
+/// Register `handler` on `tp`, returns an id to unregister it
+pub fn register_probe(tp: &Tracepoint, filter: Option<usize>, handler: Arc<Handler>) -> usize {
+    let id = PROBE_ID.fetch_add(1, Ordering::Relaxed);
+    let mut probes = tp.probes.lock();
+    probes.push(Probe {
+        id,
+        filter,
+        handler,
+    });
+    tp.enabled.store(true, Ordering::Relaxed);
+    id
+}
+
+pub fn unregister_probe(tp: &Tracepoint, id: usize) -> Option<()> {
+    let mut probes = tp.probes.lock();
+    let index = probes.iter().position(|probe| probe.id == id)?;
+    probes.remove(index);
+    tp.enabled.store(!probes.is_empty(), Ordering::Relaxed);
+    Some(())
+}